@@ -0,0 +1,486 @@
+//! The command line definition
+//!
+//! Argument and subcommand names are constants here, so a reference to one
+//! that doesn't exist fails to compile. Shell completions are generated from
+//! the same definition.
+
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
+
+pub mod arg {
+    pub const RUN_ENV: &str = "run_env";
+    pub const IMAGE_REFERENCE: &str = "image_reference";
+    pub const RUN_ARGS: &str = "run_args";
+    pub const ENTRYPOINT: &str = "entrypoint";
+    pub const WORKSPACE: &str = "workspace";
+    pub const NOTIFY_SYSTEMD: &str = "notify_systemd";
+    pub const LOG_LEVEL: &str = "log_level";
+    pub const LOG_FILTER: &str = "log_filter";
+    pub const LOG_FILE: &str = "log_file";
+    pub const QUIET: &str = "quiet";
+    pub const FORMAT: &str = "format";
+    pub const EVENT_FD: &str = "event_fd";
+    pub const CACHE_DIR: &str = "cache_dir";
+    pub const EPHEMERAL: &str = "ephemeral";
+    pub const INSTRUCTION_TRACE: &str = "instruction_trace";
+    pub const PULL: &str = "pull";
+    pub const OFFLINE: &str = "offline";
+    pub const VERIFY_CACHE: &str = "verify_cache";
+    pub const PULL_POLICY: &str = "pull_policy";
+    pub const MAX_DOWNLOADS: &str = "max_downloads";
+    pub const PLATFORM: &str = "platform";
+    pub const LOCKFILE: &str = "lockfile";
+    pub const REPOSITORY: &str = "repository";
+    pub const MAX_SIZE: &str = "max_size";
+    pub const OUTPUT: &str = "output";
+    pub const IMAGES: &str = "images";
+    pub const FILE: &str = "file";
+    pub const IMAGE: &str = "image";
+    pub const ID: &str = "id";
+    pub const FAILED: &str = "failed";
+    pub const COUNT: &str = "count";
+    pub const SIGNAL: &str = "signal";
+    pub const SOURCE: &str = "source";
+    pub const DEST: &str = "dest";
+    pub const SHELL: &str = "shell";
+}
+
+pub mod cmd {
+    pub const IMAGE: &str = "image";
+    pub const TAGS: &str = "tags";
+    pub const PRUNE: &str = "prune";
+    pub const LOCK: &str = "lock";
+    pub const UP: &str = "up";
+    pub const HISTORY: &str = "history";
+    pub const PAUSE: &str = "pause";
+    pub const RESUME: &str = "resume";
+    pub const KILL: &str = "kill";
+    pub const LOGS: &str = "logs";
+    pub const ATTACH: &str = "attach";
+    pub const CP: &str = "cp";
+    pub const SELFTEST: &str = "selftest";
+    pub const COMPLETIONS: &str = "completions";
+}
+
+pub const FORMAT_TEXT: &str = "text";
+pub const FORMAT_JSON: &str = "json";
+
+pub const POLICY_ALWAYS: &str = "always";
+pub const POLICY_IF_NOT_PRESENT: &str = "if-not-present";
+pub const POLICY_NEVER: &str = "never";
+
+/// Signals `kill` accepts by name, as well as by number
+const SIGNALS: &[(&str, u32)] = &[
+    ("HUP", 1),
+    ("INT", 2),
+    ("QUIT", 3),
+    ("KILL", 9),
+    ("USR1", 10),
+    ("USR2", 12),
+    ("TERM", 15),
+];
+
+const USAGE: &str = "\
+    bandsocks [options] [REGISTRY/]<IMAGE>[:TAG or @DIGEST] [--] [args...]
+    bandsocks [options] image tags [REGISTRY/]<IMAGE>
+    bandsocks [options] image prune [--max-size SIZE]
+    bandsocks [options] lock [-o FILE] <IMAGE>...
+    bandsocks [options] up [FILE]
+    bandsocks [options] history [--image IMAGE] [--failed] [-n COUNT] [ID]
+    bandsocks [options] pause <ID>
+    bandsocks [options] resume <ID>
+    bandsocks [options] kill [-s SIGNAL] <ID>
+    bandsocks [options] logs <ID>
+    bandsocks [options] attach <ID>
+    bandsocks [options] cp <ID>:<PATH> <FILE>
+    bandsocks [options] cp <FILE> <ID>:<PATH>
+    bandsocks [options] selftest
+    bandsocks completions <SHELL>";
+
+pub fn app() -> App<'static, 'static> {
+    App::new("bandsocks")
+        .version(crate_version!())
+        .about("container runtime 🅱️ 🧦")
+        .usage(USAGE)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&run_args())
+        .args(&global_args())
+        .subcommand(image_command())
+        .subcommand(lock_command())
+        .subcommand(up_command())
+        .subcommand(history_command())
+        .subcommand(control_command(
+            cmd::PAUSE,
+            "freeze every process in a running container until it's resumed",
+        ))
+        .subcommand(control_command(
+            cmd::RESUME,
+            "let the processes in a paused container run again",
+        ))
+        .subcommand(kill_command())
+        .subcommand(control_command(
+            cmd::LOGS,
+            "print the most recent output from a running container",
+        ))
+        .subcommand(control_command(
+            cmd::ATTACH,
+            "print a running container's output as it happens, until the container exits",
+        ))
+        .subcommand(cp_command())
+        .subcommand(SubCommand::with_name(cmd::SELFTEST).about(
+            "run a built-in suite of smoke tests and report which features work on this system",
+        ))
+        .subcommand(completions_command())
+}
+
+fn run_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name(arg::RUN_ENV)
+            .short("e")
+            .long("env")
+            .multiple(true)
+            .value_name("ENV[=VALUE]")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("set environment variables in the container"),
+        Arg::with_name(arg::IMAGE_REFERENCE)
+            .index(1)
+            .required(true)
+            .value_name("IMAGE")
+            .takes_value(true)
+            .help(
+                "image to run, as a registry repository name, with optional REGISTRY/ prefix \
+                 and :TAG or @DIGEST suffix",
+            ),
+        Arg::with_name(arg::RUN_ARGS)
+            .index(2)
+            .multiple(true)
+            .value_name("ARGS")
+            .takes_value(true)
+            .help("arguments passed to the container's entry point"),
+        Arg::with_name(arg::ENTRYPOINT)
+            .long("entrypoint")
+            .multiple(true)
+            .value_name("ENTRY")
+            .takes_value(true)
+            .number_of_values(1)
+            .help("override the container's 'entry point', which is prepended to ARGS if present"),
+        Arg::with_name(arg::WORKSPACE)
+            .long("workspace")
+            .multiple(true)
+            .value_name("[LABEL=]PATH")
+            .takes_value(true)
+            .number_of_values(1)
+            .help(
+                "keep changes under this container directory for the next run with the same \
+                 image and LABEL",
+            ),
+        Arg::with_name(arg::NOTIFY_SYSTEMD).long("sd-notify").help(
+            "tell systemd the service is ready once the container's program has loaded, \
+                 for a Type=notify unit",
+        ),
+    ]
+}
+
+fn global_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name(arg::LOG_LEVEL)
+            .short("l")
+            .long("log-level")
+            .value_name("FILTER")
+            .takes_value(true)
+            .default_value("warn")
+            .help("default log filter, superceded by RUST_LOG environment variable"),
+        Arg::with_name(arg::LOG_FILTER)
+            .long("log-filter")
+            .multiple(true)
+            .value_name("DIRECTIVE")
+            .takes_value(true)
+            .number_of_values(1)
+            .help(
+                "add a log filter directive after the log level or RUST_LOG, taking priority \
+                 over both, like bandsocks::ipcserver=trace",
+            ),
+        Arg::with_name(arg::LOG_FILE)
+            .long("log-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .help(
+                "append runtime logs to this file, or to stderr if FILE is -, keeping up to 3 \
+                 older files as FILE.1 and so on once it grows past 16 MiB; by default logs go \
+                 to bandsocks.log in the cache directory, away from the container's output, \
+                 or to stderr with an ephemeral cache",
+            ),
+        Arg::with_name(arg::QUIET)
+            .short("q")
+            .long("quiet")
+            .help("disable progress indicators, even when the output is a terminal"),
+        Arg::with_name(arg::FORMAT)
+            .long("format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&[FORMAT_TEXT, FORMAT_JSON])
+            .default_value(FORMAT_TEXT)
+            .help(
+                "print results and progress as text, or as one JSON event per line on stderr; \
+                 a container's own output is passed through unchanged",
+            ),
+        Arg::with_name(arg::EVENT_FD)
+            .long("event-fd")
+            .value_name("FD")
+            .takes_value(true)
+            .validator(|fd| match fd.parse::<i32>() {
+                Ok(fd) if fd >= 0 => Ok(()),
+                _ => Err("expected a file descriptor number".to_string()),
+            })
+            .help("write --format json events to this open file descriptor instead of stderr"),
+        Arg::with_name(arg::CACHE_DIR)
+            .short("d")
+            .long("cache")
+            .value_name("DIR")
+            .takes_value(true)
+            .help("specify the cache directory to keep downloaded and decompressed images in"),
+        Arg::with_name(arg::EPHEMERAL)
+            .long("ephemeral")
+            .short("0")
+            .help("set a random, disposable cache directory"),
+        Arg::with_name(arg::INSTRUCTION_TRACE)
+            .long("itrace")
+            .help("instruction trace, single-step execution and instruction logging"),
+        Arg::with_name(arg::PULL)
+            .long("pull")
+            .help("download the image and verify its filesystem but do not run it"),
+        Arg::with_name(arg::OFFLINE)
+            .long("offline")
+            .help("don't download anything, only use images from the cache"),
+        Arg::with_name(arg::VERIFY_CACHE)
+            .long("verify-cache")
+            .help("hash cached image data again before the container uses it"),
+        Arg::with_name(arg::PULL_POLICY)
+            .long("pull-policy")
+            .value_name("POLICY")
+            .takes_value(true)
+            .possible_values(&[POLICY_ALWAYS, POLICY_IF_NOT_PRESENT, POLICY_NEVER])
+            .help("when to check the registry for images that are already in the cache"),
+        Arg::with_name(arg::MAX_DOWNLOADS)
+            .long("max-downloads")
+            .value_name("COUNT")
+            .takes_value(true)
+            .help("download at most this many layers of an image at once, 3 by default"),
+        Arg::with_name(arg::PLATFORM)
+            .long("platform")
+            .value_name("OS/ARCH")
+            .takes_value(true)
+            .help(
+                "platform to pull from multi-platform images, like linux/arm64; defaults to \
+                 this host's",
+            ),
+        Arg::with_name(arg::LOCKFILE)
+            .long("lockfile")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("only run images pinned in this lockfile, as written by the 'lock' subcommand"),
+    ]
+}
+
+fn image_command() -> App<'static, 'static> {
+    SubCommand::with_name(cmd::IMAGE)
+        .about("look up information about images in a registry, or manage the images in the cache")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name(cmd::TAGS)
+                .about("list the tags available in an image repository")
+                .arg(
+                    Arg::with_name(arg::REPOSITORY)
+                        .index(1)
+                        .required(true)
+                        .value_name("IMAGE")
+                        .takes_value(true)
+                        .help("repository name, with optional REGISTRY/ prefix"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(cmd::PRUNE)
+                .about(
+                    "remove images from the cache directory, least recently used first, \
+                     skipping any that are in use",
+                )
+                .arg(
+                    Arg::with_name(arg::MAX_SIZE)
+                        .long("max-size")
+                        .value_name("SIZE")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help(
+                            "stop once the cache fits in this many bytes, with an optional K, \
+                             M, or G suffix; 0 removes every image not in use",
+                        ),
+                ),
+        )
+}
+
+fn lock_command() -> App<'static, 'static> {
+    SubCommand::with_name(cmd::LOCK)
+        .about("pull images and pin each one to its current content digest in a lockfile")
+        .arg(
+            Arg::with_name(arg::OUTPUT)
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .takes_value(true)
+                .default_value("bandsocks.lock")
+                .help("lockfile to create or update"),
+        )
+        .arg(
+            Arg::with_name(arg::IMAGES)
+                .index(1)
+                .required(true)
+                .multiple(true)
+                .value_name("IMAGE")
+                .takes_value(true)
+                .help("images to lock, as they will be named when run"),
+        )
+}
+
+fn up_command() -> App<'static, 'static> {
+    SubCommand::with_name(cmd::UP)
+        .about(
+            "start the services in a compose spec in dependency order, and stop them all when \
+             any one exits",
+        )
+        .arg(
+            Arg::with_name(arg::FILE)
+                .index(1)
+                .value_name("FILE")
+                .takes_value(true)
+                .default_value("bandsocks.json")
+                .help(
+                    "JSON compose spec listing each service, its image, and the services it \
+                     depends on",
+                ),
+        )
+}
+
+fn history_command() -> App<'static, 'static> {
+    SubCommand::with_name(cmd::HISTORY)
+        .about("list recent container runs from the history in the cache directory, with a summary")
+        .arg(
+            Arg::with_name(arg::IMAGE)
+                .long("image")
+                .value_name("IMAGE")
+                .takes_value(true)
+                .help(
+                    "only list runs of this image; a name without a tag or digest matches any \
+                     run from its repository",
+                ),
+        )
+        .arg(
+            Arg::with_name(arg::ID)
+                .index(1)
+                .value_name("ID")
+                .takes_value(true)
+                .conflicts_with_all(&[arg::IMAGE, arg::FAILED, arg::COUNT])
+                .help(
+                    "show the one run of the container with this ID, or any prefix of it that \
+                     no other container shares",
+                ),
+        )
+        .arg(
+            Arg::with_name(arg::FAILED)
+                .long("failed")
+                .help("only list runs that did not exit successfully"),
+        )
+        .arg(
+            Arg::with_name(arg::COUNT)
+                .short("n")
+                .long("count")
+                .value_name("COUNT")
+                .takes_value(true)
+                .help("list at most this many of the most recent matching runs [default: 20]"),
+        )
+}
+
+/// A subcommand for a container that another bandsocks process is running
+fn control_command(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name).about(about).arg(
+        Arg::with_name(arg::ID)
+            .index(1)
+            .required(true)
+            .value_name("ID")
+            .takes_value(true)
+            .help(
+                "ID of the running container, or any prefix of it that no other running \
+                 container shares",
+            ),
+    )
+}
+
+fn kill_command() -> App<'static, 'static> {
+    control_command(
+        cmd::KILL,
+        "send a signal to the init process of a running container",
+    )
+    .arg(
+        Arg::with_name(arg::SIGNAL)
+            .short("s")
+            .long("signal")
+            .value_name("SIGNAL")
+            .takes_value(true)
+            .default_value("KILL")
+            .validator(|signal| match parse_signal(&signal) {
+                Some(_) => Ok(()),
+                None => Err("expected a signal name or number".to_string()),
+            })
+            .help("signal to send, by name like TERM or SIGTERM, or by number"),
+    )
+}
+
+/// Look up a signal by name, with or without its SIG prefix, or by number
+pub fn parse_signal(signal: &str) -> Option<u32> {
+    let name = signal.strip_prefix("SIG").unwrap_or(signal);
+    SIGNALS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|(_, number)| *number)
+        .or_else(|| signal.parse().ok())
+}
+
+fn cp_command() -> App<'static, 'static> {
+    SubCommand::with_name(cmd::CP)
+        .about("copy one file into or out of a running container")
+        .arg(
+            Arg::with_name(arg::SOURCE)
+                .index(1)
+                .required(true)
+                .value_name("SOURCE")
+                .takes_value(true)
+                .help(
+                    "file to copy, either ID:PATH inside a running container or a local file, \
+                     or - for stdin",
+                ),
+        )
+        .arg(
+            Arg::with_name(arg::DEST)
+                .index(2)
+                .required(true)
+                .value_name("DEST")
+                .takes_value(true)
+                .help(
+                    "where to copy it, either ID:PATH inside a running container or a local file \
+                     or directory, or - for stdout",
+                ),
+        )
+}
+
+fn completions_command() -> App<'static, 'static> {
+    SubCommand::with_name(cmd::COMPLETIONS)
+        .about("print a shell completion script for bandsocks to stdout")
+        .arg(
+            Arg::with_name(arg::SHELL)
+                .index(1)
+                .required(true)
+                .value_name("SHELL")
+                .takes_value(true)
+                .possible_values(&Shell::variants())
+                .help("shell to generate completions for"),
+        )
+}
@@ -0,0 +1,335 @@
+//! Control sockets, so one `bandsocks` command can reach a container that
+//! another one is running
+//!
+//! There's no daemon. The process running a container listens on a unix
+//! socket named after the container's full ID, in a directory private to the
+//! user, and takes one command per connection. Commands and replies are single
+//! lines of text. Commands that move data, like `logs` or `read`, follow an
+//! `ok` reply with the data itself, and `write` sends its data after the
+//! command and gets its reply once the data has been written.
+
+use bandsocks::{Container, ContainerHandle, ContainerId, RegistryClient, Signal};
+use std::{
+    collections::VecDeque,
+    env, fs,
+    future::Future,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::DirBuilderExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    runtime::Handle,
+    task::JoinHandle,
+};
+
+pub const PAUSE: &str = "pause";
+pub const RESUME: &str = "resume";
+pub const KILL: &str = "kill";
+pub const LOGS: &str = "logs";
+pub const ATTACH: &str = "attach";
+pub const READ: &str = "read";
+pub const WRITE: &str = "write";
+
+const SUFFIX: &str = ".sock";
+const OK: &str = "ok";
+
+/// How much of the container's most recent output is kept for `logs`
+const LOG_TAIL: usize = 1024 * 1024;
+
+/// Listens for commands to one container until dropped, then removes its
+/// socket
+pub struct ControlSocket {
+    path: PathBuf,
+    log: Arc<Mutex<OutputLog>>,
+}
+
+/// The tail end of the container's output, and the streams attached to it
+#[derive(Default)]
+struct OutputLog {
+    tail: VecDeque<u8>,
+    attached: Vec<UnixStream>,
+}
+
+impl OutputLog {
+    /// Keep output, and copy it to everyone attached
+    ///
+    /// Attached streams never block the container. One that falls behind is
+    /// disconnected.
+    fn write(&mut self, bytes: &[u8]) {
+        self.tail.extend(bytes);
+        let excess = self.tail.len().saturating_sub(LOG_TAIL);
+        self.tail.drain(..excess);
+        self.attached
+            .retain(|mut stream| stream.write_all(bytes).is_ok());
+    }
+}
+
+impl ControlSocket {
+    /// Start listening, from within the async runtime
+    pub fn bind(id: ContainerId, handle: ContainerHandle) -> io::Result<ControlSocket> {
+        let path = socket_dir()?.join(format!("{}{}", id, SUFFIX));
+        // IDs are unique, so anything already here was left by a crash
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let log = Arc::new(Mutex::new(OutputLog::default()));
+        let server = Server {
+            handle,
+            log: log.clone(),
+            runtime: Handle::current(),
+        };
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let server = server.clone();
+                    // Copies can take a while, and shouldn't hold up other commands
+                    let result = stream.and_then(|stream| {
+                        thread::Builder::new()
+                            .name("control".to_string())
+                            .spawn(move || {
+                                if let Err(err) = server.serve(stream) {
+                                    log::warn!("control socket, {}", err);
+                                }
+                            })
+                    });
+                    if let Err(err) = result {
+                        log::warn!("control socket, {}", err);
+                    }
+                }
+            })?;
+        Ok(ControlSocket { path, log })
+    }
+
+    /// Copy the container's output to our own, keeping the most recent
+    /// output for `logs` and `attach`
+    ///
+    /// This takes stdout and stderr from the container, so wait for the
+    /// returned task as well as the container.
+    pub fn forward_output(&self, container: &mut Container) -> JoinHandle<()> {
+        let stdout = container.stdout.take();
+        let stderr = container.stderr.take();
+        let log = self.log.clone();
+        tokio::spawn(async move {
+            let results = tokio::join!(
+                forward(stdout, tokio::io::stdout(), log.clone()),
+                forward(stderr, tokio::io::stderr(), log),
+            );
+            for result in &[results.0, results.1] {
+                if let Err(err) = result {
+                    log::warn!("forwarding container output, {}", err);
+                }
+            }
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        // Attached commands finish when the container does
+        self.log.lock().unwrap().attached.clear();
+    }
+}
+
+async fn forward<R, W>(from: Option<R>, mut to: W, log: Arc<Mutex<OutputLog>>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut from = match from {
+        Some(from) => from,
+        None => return Ok(()),
+    };
+    let mut buf = [0u8; 4096];
+    let mut forwarding = true;
+    loop {
+        let len = from.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        log.lock().unwrap().write(&buf[..len]);
+        if forwarding {
+            match to.write_all(&buf[..len]).await {
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => forwarding = false,
+                other => other?,
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Server {
+    handle: ContainerHandle,
+    log: Arc<Mutex<OutputLog>>,
+    runtime: Handle,
+}
+
+impl Server {
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut command = String::new();
+        reader.read_line(&mut command)?;
+        let mut words = command.trim().splitn(2, ' ');
+        let result = match (words.next().unwrap_or(""), words.next()) {
+            (PAUSE, None) => self.handle.pause().map_err(|err| err.to_string()),
+            (RESUME, None) => self.handle.resume().map_err(|err| err.to_string()),
+            (KILL, Some(signal)) => match signal.parse() {
+                Ok(signal) => self
+                    .handle
+                    .kill(Signal(signal))
+                    .map_err(|err| err.to_string()),
+                Err(_) => Err(format!("bad signal number {:?}", signal)),
+            },
+            (LOGS, None) => {
+                let tail: Vec<u8> = self.log.lock().unwrap().tail.iter().cloned().collect();
+                writeln!(&stream, "{}", OK)?;
+                return (&stream).write_all(&tail);
+            }
+            (ATTACH, None) => {
+                let mut log = self.log.lock().unwrap();
+                writeln!(&stream, "{}", OK)?;
+                let tail: Vec<u8> = log.tail.iter().cloned().collect();
+                (&stream).write_all(&tail)?;
+                stream.set_nonblocking(true)?;
+                log.attached.push(stream.try_clone()?);
+                return Ok(());
+            }
+            (READ, Some(path)) => {
+                let handle = self.handle.clone();
+                let path = PathBuf::from(path);
+                match self.block_on(async move { handle.read_file(path).await }) {
+                    Ok(mut file) => {
+                        writeln!(&stream, "{}", OK)?;
+                        io::copy(&mut file, &mut &stream)?;
+                        return Ok(());
+                    }
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+            (WRITE, Some(path)) => {
+                let handle = self.handle.clone();
+                let path = PathBuf::from(path);
+                match self.block_on(async move { handle.write_file(path).await }) {
+                    Ok(mut file) => io::copy(&mut reader, &mut file)
+                        .map(|_| ())
+                        .map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+            _ => Err(format!("unknown command {:?}", command.trim())),
+        };
+        match result {
+            Ok(()) => writeln!(&stream, "{}", OK),
+            Err(err) => writeln!(&stream, "{}", err),
+        }
+    }
+
+    /// Wait on this thread for a future that runs on the async runtime
+    fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.runtime.spawn(async move {
+            let _ = sender.send(future.await);
+        });
+        receiver.recv().expect("runtime stopped")
+    }
+}
+
+/// Find the running container with this ID, or with an ID that starts with
+/// this prefix and is the only one that does
+///
+/// Returns the container's full ID.
+pub fn resolve(prefix: &str) -> Result<String, String> {
+    let dir = socket_dir().map_err(|err| err.to_string())?;
+    let mut matches = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(|err| err.to_string())? {
+        let name = entry.map_err(|err| err.to_string())?.file_name();
+        if let Some(id) = name.to_str().and_then(|name| name.strip_suffix(SUFFIX)) {
+            if id.starts_with(prefix) {
+                matches.push(id.to_string());
+            }
+        }
+    }
+    match matches.len() {
+        0 => Err(format!(
+            "no running container has an ID starting with {:?}",
+            prefix
+        )),
+        1 => Ok(matches.pop().unwrap()),
+        n => Err(format!(
+            "{} running containers have IDs starting with {:?}",
+            n, prefix
+        )),
+    }
+}
+
+/// Send a command to the running container with this ID, or any prefix of it
+/// that no other running container shares
+///
+/// Returns the container's full ID.
+pub fn send(prefix: &str, command: &str) -> Result<String, String> {
+    request(prefix, command, None).map(|(id, _)| id)
+}
+
+/// Send a command, along with any data it takes, and return the container's
+/// full ID and the rest of the reply after `ok`
+pub fn request(
+    prefix: &str,
+    command: &str,
+    input: Option<&mut dyn Read>,
+) -> Result<(String, BufReader<UnixStream>), String> {
+    let id = resolve(prefix)?;
+    let path = socket_dir()
+        .map_err(|err| err.to_string())?
+        .join(format!("{}{}", id, SUFFIX));
+    let (reply, reader) = connect(&path, command, input)
+        .map_err(|err| format!("container {} is not responding, {}", id, err))?;
+    match reply.trim() {
+        OK => Ok((id, reader)),
+        err => Err(err.to_string()),
+    }
+}
+
+fn connect(
+    path: &Path,
+    command: &str,
+    input: Option<&mut dyn Read>,
+) -> io::Result<(String, BufReader<UnixStream>)> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    if let Some(input) = input {
+        // The container may refuse before reading everything, and its reply
+        // says why
+        let _ = io::copy(input, &mut stream);
+    }
+    stream.shutdown(Shutdown::Write)?;
+    let mut reader = BufReader::new(stream);
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    Ok((reply, reader))
+}
+
+/// The directory for control sockets, only accessible to this user
+fn socket_dir() -> io::Result<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("bandsocks"),
+        None => RegistryClient::default_cache_dir()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?
+            .join("run"),
+    };
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    Ok(dir)
+}
@@ -0,0 +1,241 @@
+//! Runtime log setup
+//!
+//! A container's own output has stdout and stderr to itself, so by default
+//! runtime logs go to a file in the cache directory instead. They can be sent
+//! to another file, or back to stderr with `--log-file -`, and never go to
+//! stdout. Log files are rotated by size so a long-running container can't
+//! fill the disk.
+
+use crate::args::arg;
+use bandsocks::RegistryClient;
+use clap::ArgMatches;
+use env_logger::{Builder, Logger};
+use log::{Log, Metadata, Record};
+use std::{
+    env, fs,
+    fs::{File, OpenOptions},
+    io,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// A log file is rotated once it grows past this size
+const MAX_LOG_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of rotated log files to keep, as `FILE.1` through `FILE.N`
+const ROTATED_LOG_FILES: usize = 3;
+
+/// Name of the default log file, in the cache directory
+const DEFAULT_LOG_FILE: &str = "bandsocks.log";
+
+pub fn init(matches: &ArgMatches<'_>) {
+    let directives: Vec<_> = matches
+        .values_of(arg::LOG_FILTER)
+        .into_iter()
+        .flatten()
+        .collect();
+    let filter = filter_spec(
+        env::var("RUST_LOG").ok().as_deref(),
+        matches.value_of(arg::LOG_LEVEL).unwrap(),
+        &directives,
+    );
+    let mut builder = Builder::new();
+    builder.parse_filters(&filter);
+    if let Ok(style) = env::var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&style);
+    }
+    let file = match matches.value_of(arg::LOG_FILE) {
+        Some("-") => None,
+        Some(path) => Some(RotatingFile::open(Path::new(path)).expect("failed to open log file")),
+        None => match default_log_path(matches).map(|path| RotatingFile::create(&path)) {
+            Some(Ok(file)) => Some(file),
+            Some(Err(err)) => {
+                eprintln!("can't open log file, using stderr, {}", err);
+                None
+            }
+            None => None,
+        },
+    };
+    match file {
+        None => builder.init(),
+        Some(file) => {
+            let logger = builder.build();
+            let max_level = logger.filter();
+            log::set_boxed_logger(Box::new(FileLogger {
+                logger,
+                file: Mutex::new(file),
+            }))
+            .expect("logger already set");
+            log::set_max_level(max_level);
+        }
+    }
+}
+
+/// The log filter to use, from RUST_LOG or else the log level, with the
+/// `--log-filter` directives after it so they win over either
+fn filter_spec(rust_log: Option<&str>, log_level: &str, directives: &[&str]) -> String {
+    // Any regex applies to the whole spec, and has to stay at the end
+    let mut parts = rust_log.unwrap_or(log_level).splitn(2, '/');
+    let mut spec = parts.next().unwrap().to_string();
+    for directive in directives {
+        if !spec.is_empty() {
+            spec.push(',');
+        }
+        spec.push_str(directive);
+    }
+    if let Some(regex) = parts.next() {
+        spec.push('/');
+        spec.push_str(regex);
+    }
+    spec
+}
+
+/// Where logs go without `--log-file`, if anywhere but stderr
+///
+/// An ephemeral cache is deleted on exit, along with anything logged there,
+/// so that case stays on stderr.
+fn default_log_path(matches: &ArgMatches<'_>) -> Option<PathBuf> {
+    if matches.is_present(arg::EPHEMERAL) {
+        return None;
+    }
+    let dir = match matches.value_of(arg::CACHE_DIR) {
+        Some(dir) => PathBuf::from(dir),
+        None => RegistryClient::default_cache_dir().ok()?,
+    };
+    Some(dir.join(DEFAULT_LOG_FILE))
+}
+
+/// Uses env_logger's filters, but writes to a file of our own
+struct FileLogger {
+    logger: Logger,
+    file: Mutex<RotatingFile>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.logger.matches(record) {
+            let line = format!(
+                "[{} {:<5} {}] {}\n",
+                humantime::format_rfc3339_millis(SystemTime::now()),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            // There's nowhere left to report a failure to log
+            let _ = self.file.lock().unwrap().write_line(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_len: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            len,
+            max_len: MAX_LOG_FILE_SIZE,
+        })
+    }
+
+    /// Open the file, creating its directory first if needed
+    fn create(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        RotatingFile::open(path)
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_len {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..ROTATED_LOG_FILES).rev() {
+            match fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        let max_len = self.max_len;
+        *self = RotatingFile::open(&self.path)?;
+        self.max_len = max_len;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger::filter;
+    use log::Level;
+
+    #[test]
+    fn log_filter_wins() {
+        let directives = ["bandsocks::ipcserver=trace"];
+        assert_eq!(
+            filter_spec(None, "warn", &directives),
+            "warn,bandsocks::ipcserver=trace"
+        );
+        let spec = filter_spec(Some("bandsocks=debug/open"), "warn", &["bandsocks=off"]);
+        assert_eq!(spec, "bandsocks=debug,bandsocks=off/open");
+        assert_eq!(filter_spec(Some(""), "warn", &["info"]), "info");
+
+        let filter = filter::Builder::new().parse(&spec).build();
+        let metadata = Metadata::builder()
+            .level(Level::Debug)
+            .target("bandsocks")
+            .build();
+        assert!(!filter.enabled(&metadata));
+    }
+
+    #[test]
+    fn rotating_file() {
+        let dir = env::temp_dir().join(format!("bandsocks-rotating-file-{}", std::process::id()));
+        let path = dir.join("test.log");
+        let mut file = RotatingFile::create(&path).unwrap();
+        file.max_len = 8;
+        for line in &["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        let read = |index: usize| match index {
+            0 => fs::read_to_string(&path).unwrap(),
+            index => fs::read_to_string(file.rotated_path(index)).unwrap(),
+        };
+        assert_eq!(read(0), "six\n");
+        assert_eq!(read(1), "five\n");
+        assert_eq!(read(2), "four\n");
+        assert_eq!(read(3), "three\n");
+        assert!(!file.rotated_path(4).exists());
+        assert_eq!(RotatingFile::open(&path).unwrap().len, 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,51 +1,134 @@
 #[macro_use] extern crate clap;
 
+mod args;
+mod control;
+mod logging;
+mod output;
+mod selftest;
+
+use crate::args::{arg, cmd};
+
 use bandsocks::{
-    Container, Image, ImageError, ProgressEvent, ProgressPhase, ProgressResource, Pull,
-    PullProgress, RegistryClient,
+    ComposeSpec, Container, Image, ImageError, ImageLock, ImageName, ProgressEvent, ProgressPhase,
+    ProgressResource, Pull, PullPolicy, PullProgress, RegistryClient, RunQuery, RunSummary,
 };
-use clap::{App, ArgMatches};
-use env_logger::{from_env, Env};
+use clap::{ArgMatches, Shell};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::{collections::HashMap, path::Path, sync::Arc};
+use serde_json::json;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    fs::File,
+    io,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
 use tokio::task;
 
+/// How many runs `history` lists without `--count`
+const DEFAULT_HISTORY_COUNT: usize = 20;
+
 #[tokio::main]
 async fn main() {
-    let yaml = load_yaml!("cli.yml");
-    let matches = App::from_yaml(yaml).version(crate_version!()).get_matches();
-
-    let log_level = matches.value_of("log_level").unwrap();
-    from_env(Env::default().default_filter_or(log_level)).init();
+    let matches = args::app().get_matches();
+    if let (cmd::COMPLETIONS, Some(completions_matches)) = matches.subcommand() {
+        return completions_command(completions_matches);
+    }
 
-    let run_args = string_values(&matches, "run_args");
-    let run_env = env_values(&matches, "run_env");
-    let image_reference = matches
-        .value_of("image_reference")
-        .unwrap()
-        .parse()
-        .expect("bad image reference");
+    logging::init(&matches);
+    output::init(&matches);
 
     let mut client = RegistryClient::builder();
-    if let Some(dir) = matches.value_of("cache_dir") {
+    if let Some(dir) = matches.value_of(arg::CACHE_DIR) {
         client = client.cache_dir(Path::new(dir));
     }
-    if matches.is_present("ephemeral") {
+    if matches.is_present(arg::EPHEMERAL) {
         client = client.ephemeral_cache();
     }
-    if matches.is_present("offline") {
+    if let Some(policy) = matches.value_of(arg::PULL_POLICY) {
+        client = client.pull_policy(match policy {
+            args::POLICY_ALWAYS => PullPolicy::Always,
+            args::POLICY_NEVER => PullPolicy::Never,
+            args::POLICY_IF_NOT_PRESENT => PullPolicy::IfNotPresent,
+            _ => unreachable!(),
+        });
+    }
+    if matches.is_present(arg::OFFLINE) {
         client = client.offline();
     }
+    if matches.is_present(arg::VERIFY_CACHE) {
+        client = client.verify_cache();
+    }
+    if let Some(count) = matches.value_of(arg::MAX_DOWNLOADS) {
+        client = client.max_concurrent_downloads(count.parse().expect("bad download count"));
+    }
+    if let Some(platform) = matches.value_of(arg::PLATFORM) {
+        client = client.platform(platform);
+    }
     let client = client.build().unwrap();
 
-    let image = (if matches.is_present("quiet") {
-        client.pull(&image_reference).await
-    } else {
-        show_pull_progress(client.pull_progress(&image_reference)).await
-    })
-    .expect("failed to pull container image");
+    match matches.subcommand() {
+        (cmd::IMAGE, Some(image_matches)) => {
+            return image_command(&client, &matches, image_matches).await
+        }
+        (cmd::LOCK, Some(lock_matches)) => {
+            return lock_command(&client, &matches, lock_matches).await
+        }
+        (cmd::UP, Some(up_matches)) => return up_command(&client, &matches, up_matches).await,
+        (cmd::HISTORY, Some(history_matches)) => {
+            return history_command(&client, &matches, history_matches)
+        }
+        (cmd::PAUSE, Some(pause_matches)) => {
+            return control_command(&matches, pause_matches, control::PAUSE, "paused")
+        }
+        (cmd::RESUME, Some(resume_matches)) => {
+            return control_command(&matches, resume_matches, control::RESUME, "resumed")
+        }
+        (cmd::KILL, Some(kill_matches)) => {
+            let signal = kill_matches.value_of(arg::SIGNAL).unwrap();
+            let signal = args::parse_signal(signal).unwrap();
+            let command = format!("{} {}", control::KILL, signal);
+            return control_command(&matches, kill_matches, &command, "killed");
+        }
+        (cmd::LOGS, Some(logs_matches)) => {
+            return output_command(&matches, logs_matches, control::LOGS)
+        }
+        (cmd::ATTACH, Some(attach_matches)) => {
+            return output_command(&matches, attach_matches, control::ATTACH)
+        }
+        (cmd::CP, Some(cp_matches)) => return cp_command(&matches, cp_matches),
+        (cmd::SELFTEST, Some(_)) => return selftest_command(&client, &matches).await,
+        _ => {}
+    }
 
-    if matches.is_present("pull") {
+    let run_args = string_values(&matches, arg::RUN_ARGS);
+    let run_env = env_values(&matches, arg::RUN_ENV);
+    let json = is_json(&matches);
+    let mut image_reference = check(
+        json,
+        "bad image reference",
+        matches.value_of(arg::IMAGE_REFERENCE).unwrap().parse(),
+    );
+    if let Some(lockfile) = matches.value_of(arg::LOCKFILE) {
+        let lock = check(
+            json,
+            "failed to read lockfile",
+            ImageLock::load(Path::new(lockfile)),
+        );
+        image_reference = check(json, "image is not locked", lock.resolve(&image_reference));
+    }
+
+    let image = match pull_image(&client, &matches, &image_reference).await {
+        Ok(image) => image,
+        Err(err) if json => output::fail(err),
+        Err(err) => panic!("failed to pull container image: {:?}", err),
+    };
+    if json {
+        output::emit("pulled", json!({ "image": image.name().to_string() }));
+    }
+
+    if matches.is_present(arg::PULL) {
         if !run_args.is_empty() || !run_env.is_empty() {
             log::warn!("pull-only mode, run arguments are being ignored")
         }
@@ -53,22 +136,68 @@ async fn main() {
         let mut container = Container::new(image)
             .expect("failed to construct container")
             .args(run_args)
-            .envs(run_env);
+            .envs(run_env)
+            .run_history(&client.run_history());
 
-        if matches.is_present("entrypoint") {
-            container = container.entrypoint(string_values(&matches, "entrypoint"));
+        if matches.is_present(arg::ENTRYPOINT) {
+            container = container.entrypoint(string_values(&matches, arg::ENTRYPOINT));
         }
-        if matches.is_present("instruction_trace") {
+        if matches.is_present(arg::INSTRUCTION_TRACE) {
             container = container.instruction_trace();
         }
-        let container = container.spawn().expect("container failed to start");
+        if matches.is_present(arg::NOTIFY_SYSTEMD) {
+            container = container.notify_systemd();
+        }
+        if matches.is_present(arg::WORKSPACE) {
+            let workspaces = client.workspaces();
+            for value in string_values(&matches, arg::WORKSPACE) {
+                let (label, path) = workspace_label(&value);
+                container = container.workspace(path, &workspaces, label);
+            }
+        }
+        let mut container = match container.spawn() {
+            Ok(container) => container,
+            Err(err) if json => output::fail(err),
+            Err(err) => panic!("container failed to start: {:?}", err),
+        };
+        if json {
+            output::emit(
+                "started",
+                json!({
+                    "image": image.name().to_string(),
+                    "id": container.id().to_string(),
+                }),
+            );
+        } else if !matches.is_present(arg::QUIET) {
+            eprintln!("{}", container.id().short());
+        }
 
-        match container.interact().await {
+        let control = match control::ControlSocket::bind(container.id(), container.handle()) {
+            Ok(control) => Some(control),
+            Err(err) => {
+                log::warn!("container can't be controlled from other commands, {}", err);
+                None
+            }
+        };
+        let forwarding = control
+            .as_ref()
+            .map(|control| control.forward_output(&mut container));
+        let result = container.interact().await;
+        if let Some(forwarding) = forwarding {
+            let _ = forwarding.await;
+        }
+        drop(control);
+        match result {
             Ok(status) => {
-                if let Some(code) = status.code() {
-                    std::process::exit(code);
+                if json {
+                    output::emit(
+                        "exited",
+                        json!({ "code": status.code(), "signal": status.signal() }),
+                    );
                 }
+                std::process::exit(status.exit().shell_code());
             }
+            Err(err) if json => output::fail(err),
             Err(err) => {
                 log::error!("{}", err);
                 std::process::exit(0xFF);
@@ -77,6 +206,386 @@ async fn main() {
     }
 }
 
+async fn image_command(
+    client: &RegistryClient,
+    matches: &ArgMatches<'_>,
+    image_matches: &ArgMatches<'_>,
+) {
+    let json = is_json(matches);
+    match image_matches.subcommand() {
+        (cmd::TAGS, Some(tags_matches)) => {
+            let repository = check(
+                json,
+                "bad image reference",
+                tags_matches.value_of(arg::REPOSITORY).unwrap().parse(),
+            );
+            let tags = check(
+                json,
+                "failed to list image tags",
+                client.list_tags(&repository).await,
+            );
+            for tag in tags {
+                if json {
+                    output::emit("tag", json!({ "tag": tag.to_string() }));
+                } else {
+                    println!("{}", tag);
+                }
+            }
+        }
+        (cmd::PRUNE, Some(prune_matches)) => {
+            let max_size = check(
+                json,
+                "bad cache size",
+                parse_size(prune_matches.value_of(arg::MAX_SIZE).unwrap()).ok_or("not a size"),
+            );
+            let report = check(
+                json,
+                "failed to prune image cache",
+                client.image_cache().gc(max_size),
+            );
+            if json {
+                for image in &report.removed {
+                    output::emit("removed", json!({ "image": image.to_string() }));
+                }
+                output::emit(
+                    "pruned",
+                    json!({
+                        "bytes_freed": report.bytes_freed,
+                        "bytes_remaining": report.bytes_remaining,
+                    }),
+                );
+            } else {
+                for image in &report.removed {
+                    println!("removed {}", image);
+                }
+                println!(
+                    "freed {:.1} MiB, {:.1} MiB of image data remaining",
+                    report.bytes_freed as f64 / (1024.0 * 1024.0),
+                    report.bytes_remaining as f64 / (1024.0 * 1024.0)
+                );
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, multiplier) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+async fn lock_command(
+    client: &RegistryClient,
+    matches: &ArgMatches<'_>,
+    lock_matches: &ArgMatches<'_>,
+) {
+    let json = is_json(matches);
+    let path = Path::new(lock_matches.value_of(arg::OUTPUT).unwrap());
+    let mut lock = if path.exists() {
+        check(
+            json,
+            "failed to read existing lockfile",
+            ImageLock::load(path),
+        )
+    } else {
+        ImageLock::new()
+    };
+    for requested in string_values(lock_matches, arg::IMAGES) {
+        let requested = check(json, "bad image reference", requested.parse());
+        let image = check(
+            json,
+            "failed to pull container image",
+            pull_image(client, matches, &requested).await,
+        );
+        if json {
+            output::emit(
+                "locked",
+                json!({
+                    "requested": requested.to_string(),
+                    "image": image.name().to_string(),
+                }),
+            );
+        } else {
+            println!("{} -> {}", requested, image.name());
+        }
+        check(
+            json,
+            "pulled image has no digest",
+            lock.insert(&requested, image.name()),
+        );
+    }
+    check(json, "failed to write lockfile", lock.save(path));
+}
+
+async fn up_command(
+    client: &RegistryClient,
+    matches: &ArgMatches<'_>,
+    up_matches: &ArgMatches<'_>,
+) {
+    let json = is_json(matches);
+    let spec = check(
+        json,
+        "failed to read compose spec",
+        ComposeSpec::load(Path::new(up_matches.value_of(arg::FILE).unwrap())),
+    );
+    if !matches.is_present(arg::QUIET) {
+        let images: BTreeSet<_> = spec.services().map(|(_, service)| &service.image).collect();
+        for image in images {
+            check(
+                json,
+                "failed to pull container image",
+                pull_image(client, matches, image).await,
+            );
+        }
+    }
+    let compose = match spec.up(client).await {
+        Ok(compose) => compose,
+        Err(err) if json => output::fail(err),
+        Err(err) => panic!("services failed to start: {:?}", err),
+    };
+    if json {
+        output::emit(
+            "started",
+            json!({ "services": compose.service_names().collect::<Vec<_>>() }),
+        );
+    }
+    match compose.wait().await {
+        Ok((name, status)) => {
+            log::info!("service {:?} exited, {:?}", name, status);
+            if json {
+                output::emit(
+                    "exited",
+                    json!({
+                        "service": name,
+                        "code": status.code(),
+                        "signal": status.signal(),
+                    }),
+                );
+            }
+            std::process::exit(status.exit().shell_code());
+        }
+        Err(err) if json => output::fail(err),
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(0xFF);
+        }
+    }
+}
+
+fn history_command(
+    client: &RegistryClient,
+    matches: &ArgMatches<'_>,
+    history_matches: &ArgMatches<'_>,
+) {
+    let json = is_json(matches);
+    let count = match history_matches.value_of(arg::COUNT) {
+        Some(count) => check(json, "bad count", count.parse::<usize>()),
+        None => DEFAULT_HISTORY_COUNT,
+    };
+    let mut query = RunQuery::new().last(count);
+    if let Some(image) = history_matches.value_of(arg::IMAGE) {
+        query = query.image(&check(json, "bad image reference", image.parse()));
+    }
+    if history_matches.is_present(arg::FAILED) {
+        query = query.failed();
+    }
+    let history = client.run_history();
+    let records = match history_matches.value_of(arg::ID) {
+        Some(prefix) => vec![check(json, "failed to find run", history.find(prefix))],
+        None => check(json, "failed to read run history", history.query(&query)),
+    };
+    if json {
+        for record in &records {
+            output::emit(
+                "run",
+                json!({
+                    "id": record.id.map(|id| id.to_string()),
+                    "image": record.image.to_string(),
+                    "args": record.args,
+                    "started": record
+                        .started
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs_f64(),
+                    "duration": record.duration.as_secs_f64(),
+                    "code": record.exit_code,
+                    "error": record.error,
+                    "peak_memory": record.peak_memory,
+                }),
+            );
+        }
+        return;
+    }
+    let now = SystemTime::now();
+    for record in &records {
+        let age = now
+            .duration_since(record.started)
+            .unwrap_or_default()
+            .as_secs();
+        let result = match (&record.error, record.exit_code) {
+            (Some(error), _) => format!("error: {}", error.lines().next().unwrap_or("")),
+            (None, Some(code)) => format!("exit {}", code),
+            (None, None) => "unknown".to_string(),
+        };
+        println!(
+            "{:12}  {:>8} ago  {:>9.3}s  {:>7} KiB peak  {}  {}  {}",
+            record.id.map_or_else(|| "-".to_string(), |id| id.short()),
+            format_age(age),
+            record.duration.as_secs_f64(),
+            record.peak_memory / 1024,
+            result,
+            record.image,
+            record.args.join(" ")
+        );
+    }
+    let summary = RunSummary::new(&records);
+    if summary.runs > 0 {
+        println!(
+            "{} runs, {} failed, duration min {:.3}s mean {:.3}s max {:.3}s",
+            summary.runs,
+            summary.failures,
+            summary.min_duration.as_secs_f64(),
+            summary.mean_duration.as_secs_f64(),
+            summary.max_duration.as_secs_f64()
+        );
+    }
+}
+
+/// Send a command to a container that another bandsocks process is running
+fn control_command(
+    matches: &ArgMatches<'_>,
+    control_matches: &ArgMatches<'_>,
+    command: &str,
+    event: &str,
+) {
+    let prefix = control_matches.value_of(arg::ID).unwrap();
+    match control::send(prefix, command) {
+        Ok(id) if is_json(matches) => output::emit(event, json!({ "id": id })),
+        Ok(_) => {}
+        Err(err) => control_failed(matches, err),
+    }
+}
+
+/// Copy output from a container that another bandsocks process is running
+fn output_command(matches: &ArgMatches<'_>, control_matches: &ArgMatches<'_>, command: &str) {
+    let prefix = control_matches.value_of(arg::ID).unwrap();
+    let (_, mut reply) =
+        control::request(prefix, command, None).unwrap_or_else(|err| control_failed(matches, err));
+    if let Err(err) = io::copy(&mut reply, &mut io::stdout()) {
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            control_failed(matches, err.to_string());
+        }
+    }
+}
+
+/// Copy one file between here and a container that another bandsocks process
+/// is running
+fn cp_command(matches: &ArgMatches<'_>, cp_matches: &ArgMatches<'_>) {
+    let source = cp_matches.value_of(arg::SOURCE).unwrap();
+    let dest = cp_matches.value_of(arg::DEST).unwrap();
+    let result = match (container_path(source), container_path(dest)) {
+        (Some((prefix, path)), None) => copy_out(prefix, path, dest),
+        (None, Some((prefix, path))) => copy_in(source, prefix, path),
+        _ => Err("exactly one of SOURCE and DEST must be ID:PATH".to_string()),
+    };
+    match result {
+        Ok(id) if is_json(matches) => output::emit("copied", json!({ "id": id })),
+        Ok(_) => {}
+        Err(err) => control_failed(matches, err),
+    }
+}
+
+/// Split ID:PATH, unless the colon is part of a local path
+fn container_path(arg: &str) -> Option<(&str, &str)> {
+    let index = arg.find(':')?;
+    let (prefix, path) = (&arg[..index], &arg[index + 1..]);
+    if prefix.is_empty() || prefix.contains('/') {
+        None
+    } else {
+        Some((prefix, path))
+    }
+}
+
+fn copy_out(prefix: &str, path: &str, dest: &str) -> Result<String, String> {
+    let command = format!("{} {}", control::READ, path);
+    let (id, mut reply) = control::request(prefix, &command, None)?;
+    let result = if dest == "-" {
+        io::copy(&mut reply, &mut io::stdout())
+    } else {
+        let mut dest = Path::new(dest).to_path_buf();
+        if dest.is_dir() {
+            let name = Path::new(path)
+                .file_name()
+                .ok_or("no file name to copy to")?;
+            dest.push(name);
+        }
+        File::create(&dest).and_then(|mut file| io::copy(&mut reply, &mut file))
+    };
+    result.map_err(|err| err.to_string())?;
+    Ok(id)
+}
+
+fn copy_in(source: &str, prefix: &str, path: &str) -> Result<String, String> {
+    let mut path = path.to_string();
+    if path.ends_with('/') {
+        let name = Path::new(source)
+            .file_name()
+            .filter(|_| source != "-")
+            .ok_or("no file name to copy to")?;
+        path.push_str(&name.to_string_lossy());
+    }
+    let command = format!("{} {}", control::WRITE, path);
+    let (id, _) = if source == "-" {
+        control::request(prefix, &command, Some(&mut io::stdin()))?
+    } else {
+        let mut file = File::open(source).map_err(|err| err.to_string())?;
+        control::request(prefix, &command, Some(&mut file))?
+    };
+    Ok(id)
+}
+
+/// Report a command for another bandsocks process that didn't work, and exit
+fn control_failed(matches: &ArgMatches<'_>, err: String) -> ! {
+    if is_json(matches) {
+        output::fail(err);
+    }
+    eprintln!("{}", err);
+    std::process::exit(1);
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
+
+async fn selftest_command(client: &RegistryClient, matches: &ArgMatches<'_>) {
+    let image_reference = selftest::IMAGE.parse().unwrap();
+    let image = pull_image(client, matches, &image_reference)
+        .await
+        .expect("failed to pull self-test image");
+    if !selftest::run(image).await {
+        std::process::exit(1);
+    }
+}
+
+fn completions_command(matches: &ArgMatches<'_>) {
+    let shell: Shell = matches
+        .value_of(arg::SHELL)
+        .unwrap()
+        .parse()
+        .expect("unknown shell");
+    args::app().gen_completions_to("bandsocks", shell, &mut io::stdout());
+}
+
 fn string_values<S: AsRef<str>>(matches: &ArgMatches, name: S) -> Vec<String> {
     matches
         .values_of(name)
@@ -86,6 +595,13 @@ fn string_values<S: AsRef<str>>(matches: &ArgMatches, name: S) -> Vec<String> {
         .collect()
 }
 
+fn workspace_label(value: &str) -> (&str, &str) {
+    match value.find('=') {
+        Some(index) if !value[..index].contains('/') => (&value[..index], &value[index + 1..]),
+        _ => ("default", value),
+    }
+}
+
 fn env_values<S: AsRef<str>>(matches: &ArgMatches, name: S) -> Vec<(String, String)> {
     string_values(matches, name)
         .iter()
@@ -99,6 +615,34 @@ fn env_values<S: AsRef<str>>(matches: &ArgMatches, name: S) -> Vec<(String, Stri
         .collect()
 }
 
+fn is_json(matches: &ArgMatches<'_>) -> bool {
+    matches.value_of(arg::FORMAT) == Some(args::FORMAT_JSON)
+}
+
+/// Unwrap a result, or end with an `error` event in JSON mode
+fn check<T, E: fmt::Debug + fmt::Display>(json: bool, context: &str, result: Result<T, E>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) if json => output::fail(format!("{}, {}", context, err)),
+        Err(err) => panic!("{}: {:?}", context, err),
+    }
+}
+
+/// Pull an image, showing progress in the output format chosen
+async fn pull_image(
+    client: &RegistryClient,
+    matches: &ArgMatches<'_>,
+    image: &ImageName,
+) -> Result<Arc<Image>, ImageError> {
+    if matches.is_present(arg::QUIET) {
+        client.pull(image).await
+    } else if is_json(matches) {
+        output::pull_progress(client.pull_progress(image)).await
+    } else {
+        show_pull_progress(client.pull_progress(image)).await
+    }
+}
+
 async fn show_pull_progress(mut pull: Pull) -> Result<Arc<Image>, ImageError> {
     const TEMPLATE: &str =
         "{percent:>3}% {prefix:10} {spinner} {wide_msg}  [{bar:25}] {bytes:>9}/{total_bytes:>9}";
@@ -133,11 +677,7 @@ async fn show_pull_progress(mut pull: Pull) -> Result<Arc<Image>, ImageError> {
                     match progress.event {
                         ProgressEvent::Begin | ProgressEvent::BeginSized(_) => {
                             bar.reset();
-                            bar.set_prefix(match progress.phase {
-                                ProgressPhase::Connect => "connect",
-                                ProgressPhase::Download => "download",
-                                ProgressPhase::Decompress => "decompress",
-                            });
+                            bar.set_prefix(output::phase_name(&progress.phase));
                             bar.set_style(
                                 ProgressStyle::default_bar()
                                     .template(TEMPLATE)
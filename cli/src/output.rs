@@ -0,0 +1,84 @@
+//! Line-delimited JSON events for `--format json`, so scripts can follow
+//! along without scraping log messages or progress bars
+//!
+//! Events never go to stdout, which belongs to the container. They go to
+//! stderr, or to the file descriptor given with `--event-fd` to keep them
+//! apart from logs too.
+
+use crate::args::arg;
+use bandsocks::{Image, ImageError, ProgressEvent, ProgressPhase, Pull, PullProgress};
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use std::{
+    fs::File,
+    io::Write,
+    mem::ManuallyDrop,
+    os::unix::io::FromRawFd,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+};
+
+static EVENT_FD: AtomicI32 = AtomicI32::new(2);
+
+pub fn init(matches: &ArgMatches<'_>) {
+    if let Some(fd) = matches.value_of(arg::EVENT_FD) {
+        EVENT_FD.store(fd.parse().unwrap(), Ordering::Relaxed);
+    }
+}
+
+/// Write one event as a single line of JSON
+///
+/// The event name is added to `fields` under the key `event`.
+pub fn emit(event: &str, mut fields: Value) {
+    fields["event"] = event.into();
+    let line = format!("{}\n", fields);
+    // Borrowed, the descriptor stays open for the next event
+    let mut file =
+        ManuallyDrop::new(unsafe { File::from_raw_fd(EVENT_FD.load(Ordering::Relaxed)) });
+    // There's nowhere left to report a failure to write an event
+    let _ = file.write_all(line.as_bytes());
+}
+
+/// Report a failure as the final event, and exit
+pub fn fail<E: ToString>(err: E) -> ! {
+    emit("error", json!({ "message": err.to_string() }));
+    std::process::exit(0xFF);
+}
+
+pub fn phase_name(phase: &ProgressPhase) -> &'static str {
+    match phase {
+        ProgressPhase::Connect => "connect",
+        ProgressPhase::Download => "download",
+        ProgressPhase::Decompress => "decompress",
+    }
+}
+
+/// Emit a `progress` event for each update until the pull finishes
+pub async fn pull_progress(mut pull: Pull) -> Result<Arc<Image>, ImageError> {
+    loop {
+        match pull.progress().await {
+            PullProgress::Done(result) => return result,
+            PullProgress::Update(progress) => {
+                let mut fields = json!({
+                    "resource": progress.resource.to_string(),
+                    "phase": phase_name(&progress.phase),
+                });
+                match progress.event {
+                    ProgressEvent::Begin => fields["state"] = "begin".into(),
+                    ProgressEvent::BeginSized(size) => {
+                        fields["state"] = "begin".into();
+                        fields["size"] = size.into();
+                    }
+                    ProgressEvent::Progress(position) => {
+                        fields["state"] = "progress".into();
+                        fields["position"] = position.into();
+                    }
+                    ProgressEvent::Complete => fields["state"] = "complete".into(),
+                }
+                emit("progress", fields);
+            }
+        }
+    }
+}
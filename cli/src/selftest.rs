@@ -0,0 +1,135 @@
+//! Smoke tests covering the whole path from seccomp and the tracer through
+//! syscall emulation and the virtual filesystem
+
+use bandsocks::{Container, Image, RuntimeError};
+use std::sync::Arc;
+
+/// Pinned by digest, so results from different machines are comparable
+pub const IMAGE: &str =
+    "busybox@sha256:e06f93f59fe842fb490ba992bae19fdd5a05373547b52f8184650c2509908114";
+
+struct Check {
+    feature: &'static str,
+    args: &'static [&'static str],
+    code: i32,
+    stdout: &'static str,
+}
+
+const CHECKS: &[Check] = &[
+    Check {
+        feature: "exec",
+        args: &["true"],
+        code: 0,
+        stdout: "",
+    },
+    Check {
+        feature: "exit status",
+        args: &["false"],
+        code: 1,
+        stdout: "",
+    },
+    Check {
+        feature: "stdout",
+        args: &["echo", "hello"],
+        code: 0,
+        stdout: "hello\n",
+    },
+    Check {
+        feature: "file read",
+        args: &["head", "-n", "1", "/etc/passwd"],
+        code: 0,
+        stdout: "root:x:0:0:root:/root:/bin/sh\n",
+    },
+    Check {
+        feature: "stat",
+        args: &["stat", "-c", "%F %a", "/bin/sh"],
+        code: 0,
+        stdout: "regular file 755\n",
+    },
+    Check {
+        feature: "working directory",
+        args: &["pwd"],
+        code: 0,
+        stdout: "/\n",
+    },
+    Check {
+        feature: "uname",
+        args: &["uname", "-sr"],
+        code: 0,
+        stdout: "Linux 4.0.0-bandsocks\n",
+    },
+    Check {
+        feature: "procfs",
+        args: &["cat", "/proc/sys/kernel/ostype"],
+        code: 0,
+        stdout: "Linux\n",
+    },
+    Check {
+        feature: "sleep",
+        args: &["sleep", "0.01"],
+        code: 0,
+        stdout: "",
+    },
+    Check {
+        feature: "shell",
+        args: &["sh", "-c", "for i in 0 1 2; do echo -n $i; done"],
+        code: 0,
+        stdout: "012",
+    },
+];
+
+/// Run every check in its own container, printing one result line per feature
+///
+/// Returns true if all checks passed.
+pub async fn run(image: Arc<Image>) -> bool {
+    let mut results = check_hardening(&image).await;
+    for check in CHECKS {
+        results.push((check.feature, check_output(&image, check).await));
+    }
+    let mut passed = 0;
+    for (feature, result) in &results {
+        match result {
+            Ok(()) => {
+                passed += 1;
+                println!("  ok  {}", feature);
+            }
+            Err(reason) => println!("FAIL  {}: {}", feature, reason),
+        }
+    }
+    println!("{} of {} checks passed", passed, results.len());
+    passed == results.len()
+}
+
+/// The privileges the tracer gives up before it runs anything, one result
+/// for each part of its hardening report
+async fn check_hardening(image: &Arc<Image>) -> Vec<(&'static str, Result<(), String>)> {
+    const PARTS: [&str; 4] = [
+        "capabilities dropped",
+        "ambient capabilities cleared",
+        "no new privileges",
+        "tracer seccomp policy",
+    ];
+    let output = match Container::new(image.clone()) {
+        Ok(builder) => builder.arg("true").output().await,
+        Err(err) => return vec![("hardening", Err(err.to_string()))],
+    };
+    let in_effect = match output {
+        // Containers don't start at all without every part
+        Ok(_) => [true; 4],
+        Err(RuntimeError::SandWeakHardening { report, .. }) => [
+            report.capabilities_dropped,
+            report.ambient_cleared,
+            report.no_new_privs,
+            report.tracer_policy,
+        ],
+        Err(err) => return vec![("hardening", Err(err.to_string()))],
+    };
+    PARTS
+        .iter()
+        .zip(in_effect.iter())
+        .map(|(part, in_effect)| match in_effect {
+            true => (*part, Ok(())),
+            false => (*part, Err("not in effect".to_string())),
+        })
+        .collect()
+}
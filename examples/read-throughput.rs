@@ -0,0 +1,58 @@
+use std::time::{Duration, Instant};
+
+// Times sequential reads of one file from the image, to compare against the
+// same file read on the host. Usage: read-throughput [path] [iterations]
+//
+// Each run reads the file over and over inside a single container, and an
+// identical run that reads /dev/null instead is subtracted, so container
+// startup and the cost of each exec don't count toward the result.
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let mut args = std::env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| "/bin/busybox".to_string());
+    let iterations: u32 = args.next().map_or(20, |s| s.parse().unwrap());
+    let name = "busybox@sha256:cddb0e8f24f292e9b7baaba4d5f546db08f0a4b900be2048c6bd704bd90c13df";
+    let image = bandsocks::Container::pull(&name.parse().unwrap())
+        .await
+        .unwrap();
+
+    let size = image
+        .clone()
+        .args(&["stat", "-c", "%s", path.as_str()])
+        .output()
+        .await
+        .unwrap()
+        .stdout_str()
+        .trim()
+        .parse::<u64>()
+        .unwrap();
+
+    let time_reads = |path: &str| {
+        let script = format!(
+            "i=0; while [ $i -lt {} ]; do cat {} >/dev/null; i=$((i+1)); done",
+            iterations, path
+        );
+        let container = image.clone().args(&["sh", "-c", script.as_str()]);
+        async move {
+            let started = Instant::now();
+            let output = container.output().await.unwrap();
+            assert!(output.status.success());
+            started.elapsed()
+        }
+    };
+    let overhead = time_reads("/dev/null").await;
+    let elapsed = time_reads(&path).await;
+    let reading = elapsed
+        .checked_sub(overhead)
+        .unwrap_or(Duration::from_nanos(1));
+
+    let total = size * iterations as u64;
+    println!(
+        "read {} bytes in {:?} ({:?} with startup and exec), {:.1} MB/s",
+        total,
+        reading,
+        elapsed,
+        total as f64 / reading.as_secs_f64() / 1e6
+    );
+}
@@ -1,5 +1,6 @@
 //! Definitions that overlap between the kernel ABI and the sand IPC protocol
 
+use crate::types::Errno;
 use core::fmt;
 
 // getdents(2)
@@ -33,6 +34,9 @@ pub const S_IFBLK: u32 = 0o060000;
 pub const S_IFDIR: u32 = 0o040000;
 pub const S_IFCHR: u32 = 0o020000;
 pub const S_IFIFO: u32 = 0o010000;
+pub const S_ISUID: u32 = 0o4000;
+pub const S_ISGID: u32 = 0o2000;
+pub const S_IXGRP: u32 = 0o0010;
 
 #[derive(PartialEq, Eq, Ord, PartialOrd, Clone, Serialize, Deserialize)]
 #[repr(C)]
@@ -46,11 +50,12 @@ pub struct Syscall {
 
 impl fmt::Debug for Syscall {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "SYS_{:?} {:x?} -> {:?} (ip={:x?} sp={:x?})",
-            self.nr, self.args, self.ret, self.ip, self.sp
-        )
+        write!(f, "SYS_{:?} {:x?} -> ", self.nr, self.args)?;
+        match Errno::from_ret(self.ret).and_then(|err| err.name()) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{:?}", self.ret)?,
+        }
+        write!(f, " (ip={:x?} sp={:x?})", self.ip, self.sp)
     }
 }
 
@@ -1,7 +1,7 @@
 //! Double-ended double-queue, for converting between IPC messages and bytes
 //! plus files
 
-use super::{de, ser, SysFd};
+use super::{de, lz4, ser, SysFd};
 use core::{fmt, ops::Range};
 use generic_array::{typenum::*, ArrayLength, GenericArray};
 use serde::{de::DeserializeOwned, Serialize};
@@ -23,9 +23,15 @@ impl fmt::Display for Error {
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
-pub type BytesMax = U4096;
+/// Room for one file sent inline, plus the rest of its message
+pub type BytesMax = U8192;
 pub type FilesMax = U128;
 
+/// Framed messages begin with a little-endian u16 header holding the payload
+/// length, with this bit set if the payload is lz4 compressed
+const FRAME_COMPRESSED: u16 = 0x8000;
+const FRAME_HEADER_LEN: usize = 2;
+
 #[derive(Default)]
 pub struct IPCBuffer {
     bytes: Queue<u8, BytesMax>,
@@ -156,6 +162,68 @@ impl<'a> IPCBuffer {
         result
     }
 
+    /// Append a message with a length header, optionally compressing it
+    ///
+    /// Messages that carry files are never compressed, and neither are
+    /// messages that compression wouldn't make smaller.
+    pub fn push_back_framed<T: Serialize>(&mut self, message: &T, compress: bool) -> Result<()> {
+        let mut raw = IPCBuffer::new();
+        raw.push_back(message)?;
+        let raw = raw.as_slice();
+        let mut compressed = [0u8; BytesMax::USIZE];
+        let compressed_len = if compress && raw.files.is_empty() {
+            lz4::compress(raw.bytes, &mut compressed).filter(|len| *len < raw.bytes.len())
+        } else {
+            None
+        };
+        let (header, payload) = match compressed_len {
+            Some(len) => (FRAME_COMPRESSED | len as u16, &compressed[..len]),
+            None => (raw.bytes.len() as u16, raw.bytes),
+        };
+        self.extend_bytes(&header.to_le_bytes())?;
+        self.extend_bytes(payload)?;
+        self.files.extend(raw.files)
+    }
+
+    /// Remove one message written by [IPCBuffer::push_back_framed()]
+    pub fn pop_front_framed<T: Clone + DeserializeOwned>(&mut self) -> Result<T> {
+        let header = self.front_bytes(FRAME_HEADER_LEN)?;
+        let header = u16::from_le_bytes([header[0], header[1]]);
+        let payload_len = (header & !FRAME_COMPRESSED) as usize;
+        let frame_len = FRAME_HEADER_LEN + payload_len;
+        let frame = self.front_bytes(frame_len)?;
+
+        if header & FRAME_COMPRESSED == 0 {
+            let saved_bytes_range = self.bytes.range.clone();
+            self.pop_front_bytes(FRAME_HEADER_LEN);
+            match self.pop_front() {
+                Ok(message) if self.bytes.range.start == saved_bytes_range.start + frame_len => {
+                    Ok(message)
+                }
+                Ok(_) => {
+                    self.bytes.range = saved_bytes_range;
+                    Err(Error::InvalidValue)
+                }
+                Err(err) => {
+                    self.bytes.range = saved_bytes_range;
+                    Err(err)
+                }
+            }
+        } else {
+            let mut decompressed = IPCBuffer::new();
+            let available = decompressed.begin_fill();
+            let len = lz4::decompress(&frame[FRAME_HEADER_LEN..], available.bytes)
+                .ok_or(Error::InvalidValue)?;
+            decompressed.commit_fill(len, 0);
+            let message = decompressed.pop_front().map_err(|_| Error::InvalidValue)?;
+            if !decompressed.is_empty() {
+                return Err(Error::InvalidValue);
+            }
+            self.pop_front_bytes(frame_len);
+            Ok(message)
+        }
+    }
+
     pub fn extend_bytes(&mut self, data: &[u8]) -> Result<()> {
         self.bytes.extend(data)
     }
@@ -1,21 +1,42 @@
 //! Special purpose de-serialization for IPC messages
 
 use super::{
-    buffer::{Error, IPCBuffer, Result},
-    SysFd,
+    buffer::{BytesMax, Error, IPCBuffer, Result},
+    InlineBytes, SysFd,
 };
 use core::{fmt, fmt::Display, result};
+use generic_array::typenum::Unsigned;
 use serde::{de, de::IntoDeserializer};
 
 const SYSFD: &str = "SysFd@de";
 
+/// Longest string or byte string in a message, the most that could ever fit
+/// in the buffer after its length
+pub const BYTES_LEN_MAX: usize = BytesMax::USIZE - LEN_SIZE;
+
+/// Most entries in one sequence or map
+///
+/// Entries can take up no space at all, so this bounds how many times a
+/// visitor can be called from a message of any size.
+pub const SEQ_LEN_MAX: usize = BytesMax::USIZE;
+
+/// Most files in one message
+pub const MESSAGE_FILES_MAX: usize = 16;
+
+/// Reads messages back out of an [IPCBuffer]
+///
+/// Every length and count is checked against the limits above before any
+/// data is visited, so a message from a compromised peer can't ask for more
+/// than that. Lengths that could never fit are errors right away, instead of
+/// waiting forever for the rest of the message.
 pub struct IPCDeserializer<'d> {
     input: &'d mut IPCBuffer,
+    files: usize,
 }
 
 impl<'a> IPCDeserializer<'a> {
     pub fn new(input: &'a mut IPCBuffer) -> Self {
-        IPCDeserializer { input }
+        IPCDeserializer { input, files: 0 }
     }
 }
 
@@ -37,6 +58,24 @@ impl<'d> de::Deserialize<'d> for SysFd {
     }
 }
 
+impl<'d> de::Deserialize<'d> for InlineBytes {
+    fn deserialize<D: de::Deserializer<'d>>(deserializer: D) -> result::Result<Self, D::Error> {
+        struct InlineBytesVisitor;
+        impl<'d> de::Visitor<'d> for InlineBytesVisitor {
+            type Value = InlineBytes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("short byte string")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> result::Result<InlineBytes, E> {
+                InlineBytes::new(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+            }
+        }
+        deserializer.deserialize_bytes(InlineBytesVisitor)
+    }
+}
+
 impl de::Error for Error {
     fn custom<T: Display>(_msg: T) -> Self {
         Error::Deserialize
@@ -56,42 +95,109 @@ macro_rules! from_le_bytes {
 
 impl<'d> IPCDeserializer<'d> {
     fn deserialize_sysfd<'a, V: de::Visitor<'d>>(&'a mut self, visitor: V) -> Result<V::Value> {
+        if self.files >= MESSAGE_FILES_MAX {
+            return Err(Error::InvalidValue);
+        }
         let file = self.input.pop_front_file()?;
+        self.files += 1;
         visitor.visit_u32(file.0)
     }
+
+    fn length_prefix(&mut self, max: usize) -> Result<usize> {
+        let mut len = [0u8; LEN_SIZE];
+        len.copy_from_slice(self.input.front_bytes(LEN_SIZE)?);
+        let len = u32::from_le_bytes(len) as usize;
+        if len > max {
+            Err(Error::InvalidValue)
+        } else {
+            Ok(len)
+        }
+    }
 }
 
-impl<'d, 'a> de::Deserializer<'d> for &'a mut IPCDeserializer<'d> {
+/// Size of the length that starts each string, byte string, sequence, or map
+const LEN_SIZE: usize = core::mem::size_of::<u32>();
+
+struct SeqAccess<'d, 'a> {
+    deserializer: &'a mut IPCDeserializer<'d>,
+    len: usize,
+}
+
+impl<'d, 'a> de::SeqAccess<'d> for SeqAccess<'d, 'a> {
     type Error = Error;
 
-    fn is_human_readable(&self) -> bool {
-        false
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
     }
 
-    fn deserialize_any<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+    fn next_element_seed<S>(&mut self, seed: S) -> Result<Option<S::Value>>
+    where
+        S: de::DeserializeSeed<'d>,
+    {
+        if self.len > 0 {
+            self.len -= 1;
+            Ok(Some(de::DeserializeSeed::deserialize(
+                seed,
+                &mut *self.deserializer,
+            )?))
+        } else {
+            Ok(None)
+        }
     }
+}
 
-    fn deserialize_byte_buf<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+impl<'d, 'a> de::MapAccess<'d> for SeqAccess<'d, 'a> {
+    type Error = Error;
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
     }
 
-    fn deserialize_bytes<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+    fn next_key_seed<S>(&mut self, seed: S) -> Result<Option<S::Value>>
+    where
+        S: de::DeserializeSeed<'d>,
+    {
+        de::SeqAccess::next_element_seed(self, seed)
     }
 
-    fn deserialize_char<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+    fn next_value_seed<S>(&mut self, seed: S) -> Result<S::Value>
+    where
+        S: de::DeserializeSeed<'d>,
+    {
+        de::DeserializeSeed::deserialize(seed, &mut *self.deserializer)
     }
+}
 
-    fn deserialize_f32<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+impl<'d, 'a> de::Deserializer<'d> for &'a mut IPCDeserializer<'d> {
+    type Error = Error;
+
+    fn is_human_readable(&self) -> bool {
+        false
     }
 
-    fn deserialize_f64<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
+    fn deserialize_any<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
         Err(Error::Unimplemented)
     }
 
+    fn deserialize_byte_buf<V: de::Visitor<'d>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_bytes<V: de::Visitor<'d>>(self, visitor: V) -> Result<V::Value> {
+        let total = LEN_SIZE + self.length_prefix(BYTES_LEN_MAX)?;
+        let value = visitor.visit_bytes(&self.input.front_bytes(total)?[LEN_SIZE..])?;
+        self.input.pop_front_bytes(total);
+        Ok(value)
+    }
+
+    fn deserialize_char<V: de::Visitor<'d>>(self, visitor: V) -> Result<V::Value> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.input.front_bytes(4)?);
+        let c = core::char::from_u32(u32::from_le_bytes(bytes)).ok_or(Error::InvalidValue)?;
+        self.input.pop_front_bytes(4);
+        visitor.visit_char(c)
+    }
+
     fn deserialize_identifier<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
         Err(Error::Unimplemented)
     }
@@ -100,14 +206,21 @@ impl<'d, 'a> de::Deserializer<'d> for &'a mut IPCDeserializer<'d> {
         Err(Error::Unimplemented)
     }
 
-    fn deserialize_str<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+    fn deserialize_str<V: de::Visitor<'d>>(self, visitor: V) -> Result<V::Value> {
+        let total = LEN_SIZE + self.length_prefix(BYTES_LEN_MAX)?;
+        let bytes = &self.input.front_bytes(total)?[LEN_SIZE..];
+        let string = core::str::from_utf8(bytes).map_err(|_| Error::InvalidValue)?;
+        let value = visitor.visit_str(string)?;
+        self.input.pop_front_bytes(total);
+        Ok(value)
     }
 
-    fn deserialize_string<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+    fn deserialize_string<V: de::Visitor<'d>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
     }
 
+    from_le_bytes!(deserialize_f32, visit_f32, f32, 4);
+    from_le_bytes!(deserialize_f64, visit_f64, f64, 8);
     from_le_bytes!(deserialize_u16, visit_u16, u16, 2);
     from_le_bytes!(deserialize_i16, visit_i16, i16, 2);
     from_le_bytes!(deserialize_u32, visit_u32, u32, 4);
@@ -151,43 +264,22 @@ impl<'d, 'a> de::Deserializer<'d> for &'a mut IPCDeserializer<'d> {
         visitor.visit_unit()
     }
 
-    fn deserialize_map<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+    fn deserialize_map<V: de::Visitor<'d>>(self, visitor: V) -> Result<V::Value> {
+        let len = self.length_prefix(SEQ_LEN_MAX)?;
+        self.input.pop_front_bytes(LEN_SIZE);
+        visitor.visit_map(SeqAccess {
+            deserializer: self,
+            len,
+        })
     }
 
-    fn deserialize_seq<V: de::Visitor<'d>>(self, _visitor: V) -> Result<V::Value> {
-        Err(Error::Unimplemented)
+    fn deserialize_seq<V: de::Visitor<'d>>(self, visitor: V) -> Result<V::Value> {
+        let len = self.length_prefix(SEQ_LEN_MAX)?;
+        self.input.pop_front_bytes(LEN_SIZE);
+        self.deserialize_tuple(len, visitor)
     }
 
     fn deserialize_tuple<V: de::Visitor<'d>>(self, len: usize, visitor: V) -> Result<V::Value> {
-        struct SeqAccess<'d, 'a> {
-            deserializer: &'a mut IPCDeserializer<'d>,
-            len: usize,
-        }
-
-        impl<'d, 'a> de::SeqAccess<'d> for SeqAccess<'d, 'a> {
-            type Error = Error;
-
-            fn size_hint(&self) -> Option<usize> {
-                Some(self.len)
-            }
-
-            fn next_element_seed<S>(&mut self, seed: S) -> Result<Option<S::Value>>
-            where
-                S: de::DeserializeSeed<'d>,
-            {
-                if self.len > 0 {
-                    self.len -= 1;
-                    Ok(Some(de::DeserializeSeed::deserialize(
-                        seed,
-                        &mut *self.deserializer,
-                    )?))
-                } else {
-                    Ok(None)
-                }
-            }
-        }
-
         visitor.visit_seq(SeqAccess {
             deserializer: self,
             len,
@@ -7,6 +7,7 @@ compile_error!("bandsocks only works on linux or android");
 compile_error!("bandsocks currently only supports x86_64");
 
 #[macro_use] extern crate serde;
+extern crate alloc;
 
 #[cfg(test)]
 #[macro_use]
@@ -17,6 +18,8 @@ extern crate std;
 pub mod abi;
 pub mod buffer;
 pub mod de;
+pub mod errno;
+pub mod lz4;
 pub mod ser;
 
 mod messages;
@@ -0,0 +1,173 @@
+//! Minimal LZ4 block format codec, without allocation
+//!
+//! Only what's needed to shrink IPC messages: a greedy single-probe compressor
+//! and a bounds-checked decompressor. Reference:
+//! https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md
+
+const MIN_MATCH: usize = 4;
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const HASH_LOG: usize = 12;
+
+/// Compress `input` into `output`, returning the compressed length
+///
+/// Returns None if the result doesn't fit in `output`. Inputs are limited to
+/// 64 kB, so positions fit in the 16-bit match offsets without a window check.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    if input.len() > u16::MAX as usize {
+        return None;
+    }
+    // Hash table entries are input positions plus one, zero is empty
+    let mut table = [0u16; 1 << HASH_LOG];
+    let mut out = Writer {
+        buf: output,
+        len: 0,
+    };
+    let mut anchor = 0;
+    let mut pos = 0;
+    if input.len() > MF_LIMIT {
+        let match_start_limit = input.len() - MF_LIMIT;
+        let match_end_limit = input.len() - LAST_LITERALS;
+        while pos < match_start_limit {
+            let sequence = read_u32(input, pos);
+            let hash = hash(sequence);
+            let candidate = table[hash] as usize;
+            table[hash] = (pos + 1) as u16;
+            if candidate > 0 && read_u32(input, candidate - 1) == sequence {
+                let match_pos = candidate - 1;
+                let mut match_len = MIN_MATCH;
+                while pos + match_len < match_end_limit
+                    && input[match_pos + match_len] == input[pos + match_len]
+                {
+                    match_len += 1;
+                }
+                out.sequence(&input[anchor..pos], pos - match_pos, match_len)?;
+                pos += match_len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+    out.last_literals(&input[anchor..])?;
+    Some(out.len)
+}
+
+/// Decompress one block from `input` into `output`, returning its length
+///
+/// Returns None on any malformed input, including output overflow.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut ip = 0;
+    let mut op = 0;
+    loop {
+        let token = *input.get(ip)?;
+        ip += 1;
+
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len += read_length(input, &mut ip)?;
+        }
+        let literals = input.get(ip..ip + literal_len)?;
+        output
+            .get_mut(op..op + literal_len)?
+            .copy_from_slice(literals);
+        ip += literal_len;
+        op += literal_len;
+        if ip == input.len() {
+            return Some(op);
+        }
+
+        let offset = u16::from_le_bytes([*input.get(ip)?, *input.get(ip + 1)?]) as usize;
+        ip += 2;
+        if offset == 0 || offset > op {
+            return None;
+        }
+        let mut match_len = (token & 15) as usize;
+        if match_len == 15 {
+            match_len += read_length(input, &mut ip)?;
+        }
+        let match_end = op + match_len + MIN_MATCH;
+        if match_end > output.len() {
+            return None;
+        }
+        // Byte at a time, since the match may overlap its own output
+        let mut src = op - offset;
+        while op < match_end {
+            output[op] = output[src];
+            op += 1;
+            src += 1;
+        }
+    }
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn read_length(input: &[u8], ip: &mut usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *input.get(*ip)?;
+        *ip += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn push(&mut self, byte: u8) -> Option<()> {
+        *self.buf.get_mut(self.len)? = byte;
+        self.len += 1;
+        Some(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    fn length(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.push(255)?;
+            len -= 255;
+        }
+        self.push(len as u8)
+    }
+
+    fn literals(&mut self, literals: &[u8], match_nibble: u8) -> Option<()> {
+        let len = literals.len();
+        self.push(((len.min(15) as u8) << 4) | match_nibble)?;
+        if len >= 15 {
+            self.length(len - 15)?;
+        }
+        self.extend(literals)
+    }
+
+    fn sequence(&mut self, literals: &[u8], offset: usize, match_len: usize) -> Option<()> {
+        let match_len = match_len - MIN_MATCH;
+        self.literals(literals, match_len.min(15) as u8)?;
+        self.extend(&(offset as u16).to_le_bytes())?;
+        if match_len >= 15 {
+            self.length(match_len - 15)?;
+        }
+        Some(())
+    }
+
+    fn last_literals(&mut self, literals: &[u8]) -> Option<()> {
+        self.literals(literals, 0)
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{abi, types::*};
+use alloc::boxed::Box;
 
 /// Any message sent from the IPC server to the sand process
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
@@ -11,12 +12,57 @@ pub enum MessageToSand {
         args: SysFd,
         tracer_settings: TracerSettings,
     },
+    /// Stop every task while the container is idle, until the next Resume
+    Suspend,
+    Resume,
+    /// Deliver a signal to the container's init process, as if it came from
+    /// outside the sandbox
+    Kill(Signal),
 }
 
 /// Any message sent from the sand process to the IPC server
+///
+/// Nearly every message is a Task, so that variant stays inline and the rare
+/// large ones are boxed.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub enum MessageFromSand {
-    Task { task: VPid, op: FromTask },
+    Task {
+        task: VPid,
+        op: FromTask,
+        /// The syscall being emulated when the task sent this, if any
+        nr: Option<isize>,
+    },
+    /// Text the tracer would otherwise have printed on its stderr, boxed so
+    /// the other messages stay small
+    Diagnostic(Box<InlineBytes>),
+    /// How the tracer's attempt to give up privileges went, sent once after
+    /// Init, once the loader is forked but before it runs
+    Hardening(HardeningReport),
+}
+
+macro_rules! impl_as_bytes {
+    ($t:ty) => {
+        impl $t {
+            pub fn as_bytes(&self) -> &[u8] {
+                unsafe {
+                    core::slice::from_raw_parts(
+                        self as *const $t as *const u8,
+                        core::mem::size_of_val(self),
+                    )
+                }
+            }
+
+            pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+                unsafe {
+                    core::slice::from_raw_parts_mut(
+                        self as *mut $t as *mut u8,
+                        core::mem::size_of_val(self),
+                    )
+                }
+            }
+        }
+    };
 }
 
 /// Fixed size header for the variable sized initial args data
@@ -31,46 +77,157 @@ pub struct InitArgsHeader {
     pub env_count: usize,
 }
 
-impl InitArgsHeader {
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(
-                self as *const InitArgsHeader as *const u8,
-                core::mem::size_of_val(self),
-            )
-        }
-    }
+/// Fixed size header at the start of an exec snapshot
+///
+/// The header is followed by `region_count` [ExecSnapshotRegion] entries, then
+/// the page-aligned contents of each region.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct ExecSnapshotHeader {
+    pub ip: usize,
+    pub sp: usize,
+    pub brk: usize,
+    pub brk_start: usize,
+    /// Address of the 16 bytes the AT_RANDOM aux vector points to, which are
+    /// refilled on every restore, or zero if there aren't any
+    pub random: usize,
+    pub region_count: usize,
+}
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            core::slice::from_raw_parts_mut(
-                self as *mut InitArgsHeader as *mut u8,
-                core::mem::size_of_val(self),
-            )
-        }
-    }
+/// One memory mapping saved in an exec snapshot
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct ExecSnapshotRegion {
+    pub start: usize,
+    pub end: usize,
+    pub prot: usize,
+    pub data_offset: usize,
 }
 
+impl_as_bytes!(InitArgsHeader);
+impl_as_bytes!(ExecSnapshotHeader);
+impl_as_bytes!(ExecSnapshotRegion);
+
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub enum LogMessage {
     Emulated(abi::Syscall),
     Remote(abi::Syscall),
     Signal(u8, abi::UserRegs),
+    Audit(AuditEvent),
+    /// The same failing syscall repeated many times in a row
+    SyscallStorm(abi::Syscall),
+}
+
+/// Security-relevant decisions made by the sandbox
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub enum AuditEvent {
+    /// Refused to exec a set-user-ID or set-group-ID file whose owner differs
+    /// from the process's virtual credentials
+    SetIdExecDenied {
+        st_mode: u32,
+        st_uid: u32,
+        st_gid: u32,
+    },
+    /// Killed a task whose syscall didn't come from a syscall instruction in
+    /// its executable memory
+    UnverifiedSyscall { nr: isize, ip: VPtr },
+}
+
+/// Privileges the tracer confirmed it had given up, before running anything
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct HardeningReport {
+    /// The effective, permitted, and inheritable capability sets are empty
+    pub capabilities_dropped: bool,
+    /// The ambient capability set is empty
+    pub ambient_cleared: bool,
+    /// No exec can grant privileges, through set-ID files or file capabilities
+    pub no_new_privs: bool,
+    /// The tracer's own seccomp policy refuses exec, fork, and kill, once the
+    /// sandbox has started
+    pub tracer_policy: bool,
+}
+
+impl HardeningReport {
+    pub fn is_complete(&self) -> bool {
+        self.capabilities_dropped && self.ambient_cleared && self.no_new_privs && self.tracer_policy
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct TracerSettings {
     pub max_log_level: LogLevel,
     pub instruction_trace: bool,
+    /// Verify that each seccomp trap comes from a syscall instruction in an
+    /// executable mapping before emulating it
+    pub instruction_pointer_checks: bool,
+    /// Messages from sand use length-prefixed frames, with lz4 compression
+    /// where it helps. See [crate::buffer::IPCBuffer::push_back_framed()].
+    pub compress_messages: bool,
+    pub attach_mode: AttachMode,
+    /// Kill a task caught in a syscall storm, instead of only reporting it
+    pub abort_on_syscall_storm: bool,
+    /// Ask for a snapshot of the first process right after its initial exec,
+    /// restoring it instead of loading the binary when one is available
+    pub exec_snapshots: bool,
+    /// Bytes of recent log messages to keep inside the tracer at every level,
+    /// and write out if it panics, or zero to keep none
+    pub log_ring_size: usize,
+    /// Time each phase of every emulated syscall, reported with
+    /// [FromTask::SyscallLatency]
+    pub syscall_latency: bool,
+    /// Answer the most frequent path lookups through a seccomp user
+    /// notification listener instead of a ptrace stop. Needs Linux 5.19 or
+    /// later.
+    pub user_notif: bool,
+    /// Exit with [crate::exit::EXIT_WEAK_HARDENING] instead of starting the
+    /// loader if the [HardeningReport] is incomplete
+    pub require_hardening: bool,
+    /// Randomize the address space layout. When this is off, the loader puts
+    /// everything at the same addresses every time, and the kernel's own
+    /// randomization is turned off for the sandbox.
+    pub randomize_layout: bool,
+    /// Soft RLIMIT_STACK for the sandbox, in bytes, and the size of the
+    /// region reserved for each initial stack below its guard page
+    pub stack_limit: usize,
+    /// Let the vDSO read the clock without a syscall. When this is off, its
+    /// time functions are replaced with real syscalls, which the tracer sees.
+    pub vdso_time: bool,
+}
+
+/// How the sand process becomes the tracer of each sandboxed process
+///
+/// There is only one way so far. Attaching with PTRACE_ATTACH or
+/// PTRACE_SEIZE instead would never help: yama allows those on a child at
+/// exactly the scopes where it allows PTRACE_TRACEME, so there is nothing to
+/// fall back from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+pub enum AttachMode {
+    /// The new process requests tracing with PTRACE_TRACEME before its first
+    /// exec. Tracees are always direct children of the tracer, so this works
+    /// under a yama ptrace_scope of 0 or 1. The tracer drops CAP_SYS_PTRACE
+    /// with the rest of its capabilities, so scope 2 is out of reach.
+    TraceMe,
+}
+
+/// An opened file, as delivered to the tracer
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub enum FileContents {
+    /// Any file, passed along as a file descriptor
+    Fd(SysFd),
+    /// The complete contents of a small read-only file, boxed so the common
+    /// replies stay small
+    Inline(Box<InlineBytes>),
 }
 
 /// A message delivered to one of the lightweight tasks in the tracer
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub enum ToTask {
     OpenProcessReply(ProcessHandle),
-    FileReply(Result<(VFile, SysFd), Errno>),
+    FileReply(Result<(VFile, FileContents), Errno>),
     FileStatReply(Result<(VFile, FileStat), Errno>),
     BytesReply(Result<(SysFd, usize), Errno>),
+    /// A socket to put in place of the task's placeholder socket
+    SocketReply(Result<SysFd, Errno>),
     Reply(Result<(), Errno>),
 }
 
@@ -94,10 +251,84 @@ pub enum FromTask {
         path: Option<VString>,
         follow_links: FollowLinks,
     },
-    ReadLink(VString),
+    ReadLink {
+        dir: Option<VFile>,
+        path: VString,
+    },
     ProcessKill(VPid, Signal),
-    ChangeWorkingDir(VString),
+    /// Change to the directory at `path`, relative to `file` or the current
+    /// directory, or to `file` itself if there's no path
+    ChangeWorkingDir {
+        file: Option<VFile>,
+        path: Option<VString>,
+    },
     GetWorkingDir,
     Exited(i32),
     Log(LogLevel, LogMessage),
+    /// Request a snapshot of the initial exec, answered with a BytesReply
+    ExecSnapshotOpen,
+    /// Save a snapshot of this task's memory, which has just finished its
+    /// initial exec and stays stopped until the Reply
+    ExecSnapshotCapture {
+        ip: VPtr,
+        sp: VPtr,
+        brk: VPtr,
+        brk_start: VPtr,
+    },
+    /// Nanoseconds spent in each phase of one emulated syscall, not counting
+    /// the runtime's own handling of any messages it sent
+    SyscallLatency {
+        nr: isize,
+        trap: u64,
+        emulate: u64,
+        ipc: u64,
+        resume: u64,
+    },
+    MakeDir {
+        dir: Option<VFile>,
+        path: VString,
+        mode: i32,
+    },
+    /// Remove a directory entry, like rmdir() if `remove_dir` is set or
+    /// unlink() otherwise
+    Unlink {
+        dir: Option<VFile>,
+        path: VString,
+        remove_dir: bool,
+    },
+    Rename {
+        from_dir: Option<VFile>,
+        from: VString,
+        to_dir: Option<VFile>,
+        to: VString,
+    },
+    /// The task was killed by a signal, after which it's gone like Exited
+    Signaled(Signal),
+    /// Like OpenProcess, for a process forked from `parent`
+    OpenChildProcess {
+        sys_pid: SysPid,
+        parent: VPid,
+    },
+    /// The task finished loading a new program from `file`, which
+    /// /proc/self/exe refers to from now on. The `path` it was run by is the
+    /// copy on the new stack, passed to the program as AT_EXECFN.
+    ExecLoaded {
+        file: VFile,
+        path: VString,
+    },
+    /// Bind a stream socket to the inet address at `addr`, answered with a
+    /// SocketReply holding a listening socket
+    NetBind {
+        addr: VPtr,
+        len: usize,
+    },
+    /// Connect a stream socket to the inet address at `addr`, answered with a
+    /// SocketReply holding a connected socket
+    NetConnect {
+        addr: VPtr,
+        len: usize,
+    },
+    /// The task made the same failing syscall too many times in a row, and
+    /// is being killed if [TracerSettings::abort_on_syscall_storm] is set
+    SyscallStorm(abi::Syscall),
 }
@@ -2,7 +2,7 @@
 
 use super::{
     buffer::{Error, IPCBuffer, Result},
-    SysFd,
+    InlineBytes, SysFd,
 };
 use core::{fmt::Display, result};
 use serde::{ser, ser::SerializeTupleStruct};
@@ -21,6 +21,16 @@ impl<'a> IPCSerializer<'a> {
             in_sysfd: false,
         }
     }
+
+    /// Strings, byte strings, sequences, and maps all start with a 32-bit
+    /// length
+    fn length_prefix(&mut self, len: usize) -> Result<()> {
+        assert_eq!(self.in_sysfd, false);
+        if len > u32::MAX as usize {
+            return Err(Error::Serialize);
+        }
+        self.output.extend_bytes(&(len as u32).to_le_bytes())
+    }
 }
 
 impl ser::Serialize for SysFd {
@@ -31,6 +41,12 @@ impl ser::Serialize for SysFd {
     }
 }
 
+impl ser::Serialize for InlineBytes {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.as_slice())
+    }
+}
+
 impl ser::StdError for Error {}
 
 impl ser::Error for Error {
@@ -72,14 +88,8 @@ impl<'a, 'b> ser::Serializer for &'b mut IPCSerializer<'a> {
         self.output.push_back_byte(v as u8)
     }
 
-    fn serialize_f32(self, _v: f32) -> Result<()> {
-        Err(Error::Unimplemented)
-    }
-
-    fn serialize_f64(self, _v: f64) -> Result<()> {
-        Err(Error::Unimplemented)
-    }
-
+    to_le_bytes!(serialize_f32, f32);
+    to_le_bytes!(serialize_f64, f64);
     to_le_bytes!(serialize_u16, u16);
     to_le_bytes!(serialize_i16, i16);
     to_le_bytes!(serialize_i32, i32);
@@ -138,16 +148,18 @@ impl<'a, 'b> ser::Serializer for &'b mut IPCSerializer<'a> {
         }
     }
 
-    fn serialize_char(self, _v: char) -> Result<()> {
-        Err(Error::Unimplemented)
+    fn serialize_char(self, v: char) -> Result<()> {
+        assert_eq!(self.in_sysfd, false);
+        self.output.extend_bytes(&(v as u32).to_le_bytes())
     }
 
-    fn serialize_str(self, _v: &str) -> Result<()> {
-        Err(Error::Unimplemented)
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.serialize_bytes(v.as_bytes())
     }
 
-    fn serialize_bytes(self, _v: &[u8]) -> Result<()> {
-        Err(Error::Unimplemented)
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.length_prefix(v.len())?;
+        self.output.extend_bytes(v)
     }
 
     fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> Result<()>
@@ -179,8 +191,9 @@ impl<'a, 'b> ser::Serializer for &'b mut IPCSerializer<'a> {
         Ok(self)
     }
 
-    fn serialize_seq(self, _len: Option<usize>) -> Result<Self> {
-        assert_eq!(self.in_sysfd, false);
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self> {
+        // The length goes first, so it must be known ahead of time
+        self.length_prefix(len.ok_or(Error::Unimplemented)?)?;
         Ok(self)
     }
 
@@ -189,8 +202,9 @@ impl<'a, 'b> ser::Serializer for &'b mut IPCSerializer<'a> {
         Ok(self)
     }
 
-    fn serialize_map(self, _len: Option<usize>) -> Result<Self> {
-        assert_eq!(self.in_sysfd, false);
+    fn serialize_map(self, len: Option<usize>) -> Result<Self> {
+        // Counted in entries, like a sequence of key-value pairs
+        self.length_prefix(len.ok_or(Error::Unimplemented)?)?;
         Ok(self)
     }
 
@@ -1,4 +1,145 @@
 use crate::*;
+use alloc::boxed::Box;
+use core::{fmt, str};
+use serde::{
+    de, ser,
+    ser::{SerializeMap, SerializeSeq},
+};
+
+/// Strings and sequences need an allocator to deserialize normally, so the
+/// tests use small fixed-size versions
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ShortString(InlineBytes);
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ShortSeq {
+    len: usize,
+    items: [u16; 4],
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ShortMap {
+    len: usize,
+    entries: [(u8, i64); 4],
+}
+
+impl ShortString {
+    fn new(s: &str) -> Self {
+        ShortString(InlineBytes::new(s.as_bytes()).unwrap())
+    }
+}
+
+impl ShortSeq {
+    fn new(items: &[u16]) -> Self {
+        let mut seq = ShortSeq {
+            len: items.len(),
+            items: [0; 4],
+        };
+        seq.items[..items.len()].copy_from_slice(items);
+        seq
+    }
+}
+
+impl ShortMap {
+    fn new(entries: &[(u8, i64)]) -> Self {
+        let mut map = ShortMap {
+            len: entries.len(),
+            entries: [(0, 0); 4],
+        };
+        map.entries[..entries.len()].copy_from_slice(entries);
+        map
+    }
+}
+
+impl ser::Serialize for ShortString {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(str::from_utf8(self.0.as_slice()).unwrap())
+    }
+}
+
+impl<'d> de::Deserialize<'d> for ShortString {
+    fn deserialize<D: de::Deserializer<'d>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'d> de::Visitor<'d> for Visitor {
+            type Value = ShortString;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("short string")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<ShortString, E> {
+                Ok(ShortString::new(v))
+            }
+        }
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+impl ser::Serialize for ShortSeq {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for item in &self.items[..self.len] {
+            seq.serialize_element(item)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'d> de::Deserialize<'d> for ShortSeq {
+    fn deserialize<D: de::Deserializer<'d>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'d> de::Visitor<'d> for Visitor {
+            type Value = ShortSeq;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("short sequence")
+            }
+
+            fn visit_seq<A: de::SeqAccess<'d>>(self, mut access: A) -> Result<ShortSeq, A::Error> {
+                let mut seq = ShortSeq::new(&[]);
+                while let Some(item) = access.next_element()? {
+                    seq.items[seq.len] = item;
+                    seq.len += 1;
+                }
+                Ok(seq)
+            }
+        }
+        deserializer.deserialize_seq(Visitor)
+    }
+}
+
+impl ser::Serialize for ShortMap {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len))?;
+        for (key, value) in &self.entries[..self.len] {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'d> de::Deserialize<'d> for ShortMap {
+    fn deserialize<D: de::Deserializer<'d>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'d> de::Visitor<'d> for Visitor {
+            type Value = ShortMap;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("short map")
+            }
+
+            fn visit_map<A: de::MapAccess<'d>>(self, mut access: A) -> Result<ShortMap, A::Error> {
+                let mut map = ShortMap::new(&[]);
+                while let Some(entry) = access.next_entry()? {
+                    map.entries[map.len] = entry;
+                    map.len += 1;
+                }
+                Ok(map)
+            }
+        }
+        deserializer.deserialize_map(Visitor)
+    }
+}
 
 #[test]
 fn bools() {
@@ -44,7 +185,7 @@ fn messages() {
             VFile {
                 inode: 0x12345678abcdef01,
             },
-            SysFd(5),
+            FileContents::Fd(SysFd(5)),
         ))),
     };
     let msg2 = MessageToSand::Task {
@@ -53,18 +194,18 @@ fn messages() {
     };
     let msg3 = MessageToSand::Task {
         task: VPid(29862),
-        op: ToTask::FileReply(Ok((VFile { inode: 0 }, SysFd(99999)))),
+        op: ToTask::FileReply(Ok((VFile { inode: 0 }, FileContents::Fd(SysFd(99999))))),
     };
     let msg4 = MessageToSand::Task {
         task: VPid(125),
-        op: ToTask::FileReply(Ok((VFile { inode: 777777 }, SysFd(299)))),
+        op: ToTask::FileReply(Ok((VFile { inode: 777777 }, FileContents::Fd(SysFd(299))))),
     };
     let mut buf = buffer::IPCBuffer::new();
     buf.push_back(&msg1).unwrap();
     buf.push_back(&msg2).unwrap();
     buf.push_back(&msg3).unwrap();
     buf.push_back(&msg4).unwrap();
-    assert_eq!(buf.as_slice().bytes.len(), 56);
+    assert_eq!(buf.as_slice().bytes.len(), 59);
     assert_eq!(buf.as_slice().files.len(), 3);
     assert_eq!(buf.pop_front::<MessageToSand>(), Ok(msg1));
     assert_eq!(buf.pop_front::<MessageToSand>(), Ok(msg2));
@@ -132,23 +273,63 @@ macro_rules! check {
     };
 }
 
-macro_rules! nope {
-    ($name: ident, $msg:expr, $t:ty) => {
-        #[test]
-        fn $name() {
-            let mut buf = buffer::IPCBuffer::new();
-            let msg: $t = $msg;
-            assert_eq!(buf.push_back(&msg), Err(buffer::Error::Unimplemented));
-            assert!(buf.is_empty());
-        }
-    };
-}
-
-nope!(no_char, 'n', char);
-nope!(no_str, "blah", &str);
-nope!(no_f32, 1.0, f32);
-nope!(no_f64, 1.0, f64);
+check!(char_1, 'n', char, [0x6e, 0x00, 0x00, 0x00], []);
+check!(char_2, '\u{1f9e6}', char, [0xe6, 0xf9, 0x01, 0x00], []);
+check!(f32_1, 1.0, f32, [0x00, 0x00, 0x80, 0x3f], []);
+check!(f32_2, f32::INFINITY, f32, [0x00, 0x00, 0x80, 0x7f], []);
+check!(
+    f64_1,
+    -2.5,
+    f64,
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xc0],
+    []
+);
 
+check!(
+    str_1,
+    ShortString::new("blah"),
+    ShortString,
+    [0x04, 0x00, 0x00, 0x00, b'b', b'l', b'a', b'h'],
+    []
+);
+check!(
+    str_2,
+    ShortString::new(""),
+    ShortString,
+    [0x00, 0x00, 0x00, 0x00],
+    []
+);
+check!(
+    seq_1,
+    ShortSeq::new(&[0x1234, 0x5678]),
+    ShortSeq,
+    [0x02, 0x00, 0x00, 0x00, 0x34, 0x12, 0x78, 0x56],
+    []
+);
+check!(
+    seq_2,
+    ShortSeq::new(&[]),
+    ShortSeq,
+    [0x00, 0x00, 0x00, 0x00],
+    []
+);
+check!(
+    map_1,
+    ShortMap::new(&[(1, -1), (2, 0x1234)]),
+    ShortMap,
+    [
+        0x02, 0x00, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0x34,
+        0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ],
+    []
+);
+check!(
+    map_2,
+    ShortMap::new(&[]),
+    ShortMap,
+    [0x00, 0x00, 0x00, 0x00],
+    []
+);
 check!(u32_1, 0x12345678, u32, [0x78, 0x56, 0x34, 0x12], []);
 check!(u32_2, 0x00000000, u32, [0x00, 0x00, 0x00, 0x00], []);
 check!(u32_3, 0xffffffff, u32, [0xff, 0xff, 0xff, 0xff], []);
@@ -165,7 +346,32 @@ check!(i8_2, 0, i8, [0x00], []);
 check!(i8_3, -1, i8, [0xff], []);
 check!(u64_1, 0, u64, [0; 8], []);
 check!(u64_2, 0xffffffffffffffff, u64, [0xff; 8], []);
+check!(
+    u64_3,
+    0x0123456789abcdef,
+    u64,
+    [0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01],
+    []
+);
 check!(i64_1, -1, i64, [0xff; 8], []);
+check!(i64_2, i64::MIN, i64, [0, 0, 0, 0, 0, 0, 0, 0x80], []);
+check!(
+    isize_1,
+    -2,
+    isize,
+    [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+    []
+);
+check!(usize_1, 0x1000, usize, [0x00, 0x10, 0, 0, 0, 0, 0, 0], []);
+check!(
+    option_1,
+    Some(-3i64),
+    Option<i64>,
+    [1, 0xfd, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+    []
+);
+check!(option_2, None, Option<SysFd>, [0], []);
+check!(option_3, Some(SysFd(7)), Option<SysFd>, [1], [SysFd(7)]);
 check!(fd_1, SysFd(0x87654321), SysFd, [], [SysFd(0x87654321)]);
 check!(fd_2, SysFd(0), SysFd, [], [SysFd(0)]);
 check!(fd_ok, Ok(SysFd(123)), Result<SysFd, Errno>, [0], [SysFd(123)]);
@@ -216,12 +422,14 @@ check!(
             path: VString(VPtr(0x5544332211009933)),
             mode: 0x55667788,
             flags: 0x34562222
-        }
+        },
+        nr: Some(2)
     },
     MessageFromSand,
     [
         0x00, 0x55, 0x99, 0x34, 0x12, 0x02, 0x00, 0x33, 0x99, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55,
-        0x22, 0x22, 0x56, 0x34, 0x88, 0x77, 0x66, 0x55,
+        0x22, 0x22, 0x56, 0x34, 0x88, 0x77, 0x66, 0x55, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
     ],
     []
 );
@@ -236,13 +444,14 @@ check!(
             path: VString(VPtr(0x3333333333333333)),
             mode: 0x44444444,
             flags: 0x55555555
-        }
+        },
+        nr: None
     },
     MessageFromSand,
     [
         0x00, 0x22, 0x22, 0x22, 0x22, 0x02, 0x01, 0x55, 0x55, 0x66, 0x66, 0x77, 0x77, 0x88, 0x88,
         0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x55, 0x55, 0x55, 0x55, 0x44, 0x44, 0x44,
-        0x44,
+        0x44, 0x00,
     ],
     []
 );
@@ -254,11 +463,14 @@ check!(
             VFile {
                 inode: 0x3333444455556666
             },
-            SysFd(42)
+            FileContents::Fd(SysFd(42))
         ))),
     },
     MessageToSand,
-    [0x00, 0x57, 0x56, 0x55, 0x54, 0x01, 0x00, 0x66, 0x66, 0x55, 0x55, 0x44, 0x44, 0x33, 0x33],
+    [
+        0x00, 0x57, 0x56, 0x55, 0x54, 0x01, 0x00, 0x66, 0x66, 0x55, 0x55, 0x44, 0x44, 0x33, 0x33,
+        0x00
+    ],
     [SysFd(42)]
 );
 check!(
@@ -271,6 +483,23 @@ check!(
     [0x00, 0x44, 0x33, 0x22, 0x11, 0x01, 0x01, 0xf6, 0xff, 0xff, 0xff],
     []
 );
+check!(
+    sys_open_reply_3,
+    MessageToSand::Task {
+        task: VPid(0x11223344),
+        op: ToTask::FileReply(Ok((
+            VFile { inode: 0x12 },
+            FileContents::Inline(Box::new(InlineBytes::new(b"root:x:0:0\n").unwrap()))
+        ))),
+    },
+    MessageToSand,
+    [
+        0x00, 0x44, 0x33, 0x22, 0x11, 0x01, 0x00, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x0b, 0x00, 0x00, 0x00, b'r', b'o', b'o', b't', b':', b'x', b':', b'0', b':', b'0',
+        b'\n'
+    ],
+    []
+);
 check!(
     process_open_reply_1,
     MessageToSand::Task {
@@ -284,3 +513,585 @@ check!(
     [0x00, 0x99, 0x99, 0x66, 0x66, 0],
     [SysFd(10), SysFd(20)]
 );
+check!(
+    exec_snapshot_capture_1,
+    MessageFromSand::Task {
+        task: VPid(1),
+        op: FromTask::ExecSnapshotCapture {
+            ip: VPtr(0x1122),
+            sp: VPtr(0x3344),
+            brk: VPtr(0x5566),
+            brk_start: VPtr(0x5000),
+        },
+        nr: None
+    },
+    MessageFromSand,
+    [
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x0b, 0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44,
+        0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x55, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ],
+    []
+);
+check!(
+    signaled_1,
+    MessageFromSand::Task {
+        task: VPid(2),
+        op: FromTask::Signaled(Signal(9)),
+        nr: None
+    },
+    MessageFromSand,
+    [0x00, 0x02, 0x00, 0x00, 0x00, 0x10, 0x09, 0x00, 0x00, 0x00, 0x00],
+    []
+);
+check!(suspend_1, MessageToSand::Suspend, MessageToSand, [0x02], []);
+check!(resume_1, MessageToSand::Resume, MessageToSand, [0x03], []);
+check!(log_level_1, LogLevel::Trace, LogLevel, [0x05], []);
+check!(
+    kill_1,
+    MessageToSand::Kill(Signal(15)),
+    MessageToSand,
+    [0x04, 0x0f, 0x00, 0x00, 0x00],
+    []
+);
+check!(
+    open_child_process_1,
+    MessageFromSand::Task {
+        task: VPid(3),
+        op: FromTask::OpenChildProcess {
+            sys_pid: SysPid(0x1234),
+            parent: VPid(1),
+        },
+        nr: None
+    },
+    MessageFromSand,
+    [0x00, 0x03, 0x00, 0x00, 0x00, 0x11, 0x34, 0x12, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00],
+    []
+);
+check!(
+    exec_loaded_1,
+    MessageFromSand::Task {
+        task: VPid(1),
+        op: FromTask::ExecLoaded {
+            file: VFile { inode: 0x4321 },
+            path: VString(VPtr(0x7ffe1234)),
+        },
+        nr: None
+    },
+    MessageFromSand,
+    [
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x12, 0x21, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34,
+        0x12, 0xfe, 0x7f, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ],
+    []
+);
+check!(
+    hardening_1,
+    MessageFromSand::Hardening(HardeningReport {
+        capabilities_dropped: true,
+        ambient_cleared: false,
+        no_new_privs: true,
+        tracer_policy: false,
+    }),
+    MessageFromSand,
+    [0x02, 0x01, 0x00, 0x01, 0x00],
+    []
+);
+
+#[test]
+fn bad_chars() {
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&[0x00, 0xd8, 0x00, 0x00]).unwrap();
+    assert_eq!(buf.pop_front::<char>(), Err(buffer::Error::InvalidValue));
+    assert_eq!(buf.as_slice().bytes.len(), 4);
+
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&[0x00, 0x00, 0x11, 0x00]).unwrap();
+    assert_eq!(buf.pop_front::<char>(), Err(buffer::Error::InvalidValue));
+
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&[0x6e, 0x00, 0x00]).unwrap();
+    assert_eq!(buf.pop_front::<char>(), Err(buffer::Error::UnexpectedEnd));
+}
+
+#[test]
+fn bad_strings() {
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&[0x05, 0x00, 0x00, 0x00, b'a', b'b'])
+        .unwrap();
+    assert_eq!(
+        buf.pop_front::<ShortString>(),
+        Err(buffer::Error::UnexpectedEnd)
+    );
+    assert_eq!(buf.as_slice().bytes.len(), 6);
+
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&[0x02, 0x00, 0x00, 0x00, 0xff, 0xfe])
+        .unwrap();
+    assert_eq!(
+        buf.pop_front::<ShortString>(),
+        Err(buffer::Error::InvalidValue)
+    );
+}
+
+#[test]
+fn length_limits() {
+    // Lengths that could never fit fail right away, instead of waiting for
+    // more data
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&[0xff, 0xff, 0xff, 0xff]).unwrap();
+    assert_eq!(
+        buf.pop_front::<ShortString>(),
+        Err(buffer::Error::InvalidValue)
+    );
+    assert_eq!(
+        buf.pop_front::<InlineBytes>(),
+        Err(buffer::Error::InvalidValue)
+    );
+    assert_eq!(
+        buf.pop_front::<ShortSeq>(),
+        Err(buffer::Error::InvalidValue)
+    );
+    assert_eq!(
+        buf.pop_front::<ShortMap>(),
+        Err(buffer::Error::InvalidValue)
+    );
+    assert_eq!(buf.as_slice().bytes.len(), 4);
+
+    let len = (crate::de::BYTES_LEN_MAX + 1) as u32;
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&len.to_le_bytes()).unwrap();
+    assert_eq!(
+        buf.pop_front::<ShortString>(),
+        Err(buffer::Error::InvalidValue)
+    );
+    let len = crate::de::BYTES_LEN_MAX as u32;
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&len.to_le_bytes()).unwrap();
+    assert_eq!(
+        buf.pop_front::<ShortString>(),
+        Err(buffer::Error::UnexpectedEnd)
+    );
+
+    // A sequence of entries that take no space still has a limit
+    let len = (crate::de::SEQ_LEN_MAX + 1) as u32;
+    let mut buf = buffer::IPCBuffer::new();
+    buf.extend_bytes(&len.to_le_bytes()).unwrap();
+    assert_eq!(
+        buf.pop_front::<ShortSeq>(),
+        Err(buffer::Error::InvalidValue)
+    );
+}
+
+#[test]
+fn file_limit() {
+    let mut buf = buffer::IPCBuffer::new();
+    let files = [SysFd(3); crate::de::MESSAGE_FILES_MAX];
+    buf.push_back(&files).unwrap();
+    assert_eq!(
+        buf.pop_front::<[SysFd; crate::de::MESSAGE_FILES_MAX]>(),
+        Ok(files)
+    );
+    assert!(buf.is_empty());
+
+    let files = [SysFd(4); crate::de::MESSAGE_FILES_MAX + 1];
+    buf.push_back(&files).unwrap();
+    assert_eq!(
+        buf.pop_front::<[SysFd; crate::de::MESSAGE_FILES_MAX + 1]>(),
+        Err(buffer::Error::InvalidValue)
+    );
+    assert_eq!(buf.as_slice().files.len(), crate::de::MESSAGE_FILES_MAX + 1);
+}
+
+#[test]
+fn inline_bytes() {
+    let largest = [0x5au8; InlineBytes::CAPACITY];
+    assert!(InlineBytes::new(&largest[..]).is_some());
+    assert!(InlineBytes::new(&[0u8; InlineBytes::CAPACITY + 1][..]).is_none());
+    assert_eq!(InlineBytes::new(b"").unwrap().as_slice(), b"");
+
+    // The largest inline file fits in one message, and arrives only once
+    // it's complete
+    let msg = MessageToSand::Task {
+        task: VPid(1),
+        op: ToTask::FileReply(Ok((
+            VFile { inode: 2 },
+            FileContents::Inline(Box::new(InlineBytes::new(&largest[..]).unwrap())),
+        ))),
+    };
+    let mut buf = buffer::IPCBuffer::new();
+    buf.push_back(&msg).unwrap();
+    let bytes = buf.as_slice().bytes.to_vec();
+    let mut partial = buffer::IPCBuffer::new();
+    partial.extend_bytes(&bytes[..bytes.len() - 1]).unwrap();
+    assert_eq!(
+        partial.pop_front::<MessageToSand>(),
+        Err(buffer::Error::UnexpectedEnd)
+    );
+    partial.extend_bytes(&bytes[bytes.len() - 1..]).unwrap();
+    assert_eq!(partial.pop_front::<MessageToSand>(), Ok(msg));
+    assert!(partial.is_empty());
+}
+
+fn lz4_roundtrip(input: &[u8]) -> usize {
+    let mut compressed = [0u8; 8192];
+    let mut decompressed = [0u8; 8192];
+    let compressed_len = lz4::compress(input, &mut compressed).unwrap();
+    let len = lz4::decompress(&compressed[..compressed_len], &mut decompressed).unwrap();
+    assert_eq!(&decompressed[..len], input);
+    compressed_len
+}
+
+#[test]
+fn lz4_roundtrips() {
+    assert_eq!(lz4_roundtrip(b""), 1);
+    assert_eq!(lz4_roundtrip(b"short"), 6);
+    assert!(lz4_roundtrip(&[0u8; 4096]) < 64);
+    assert!(lz4_roundtrip(&b"abcdefgh".repeat(300)) < 64);
+    let mut noise = [0u8; 4000];
+    let mut state = 0x12345678u32;
+    for byte in noise.iter_mut() {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        *byte = (state >> 24) as u8;
+    }
+    assert!(lz4_roundtrip(&noise) > noise.len());
+    let mut mixed = std::vec::Vec::new();
+    for chunk in noise.chunks(100) {
+        mixed.extend_from_slice(chunk);
+        mixed.extend_from_slice(&[0u8; 37]);
+        mixed.extend_from_slice(&chunk[..20]);
+    }
+    lz4_roundtrip(&mixed);
+}
+
+#[test]
+fn lz4_rejects_bad_input() {
+    let mut out = [0u8; 64];
+    assert_eq!(lz4::decompress(&[], &mut out), None);
+    // match offset pointing before the start of output
+    assert_eq!(lz4::decompress(&[0x10, b'a', 0x02, 0x00], &mut out), None);
+    // literals longer than the input
+    assert_eq!(lz4::decompress(&[0x50, b'a'], &mut out), None);
+    // output too small
+    assert_eq!(
+        lz4::decompress(&[0x1f, b'a', 0x01, 0x00, 0xff, 0x00], &mut out),
+        None
+    );
+}
+
+#[test]
+fn framed_log_messages() {
+    let message = MessageFromSand::Task {
+        task: VPid(1),
+        op: FromTask::Log(
+            LogLevel::Trace,
+            LogMessage::Emulated(abi::Syscall {
+                nr: 0,
+                args: [3, 0x7ffd_0000_1000, 0x1000, 0, 0, 0],
+                ret: 0x1000,
+                ip: 0x4000_1234,
+                sp: 0x7ffd_0000_2000,
+            }),
+        ),
+        nr: Some(0),
+    };
+    let mut raw = buffer::IPCBuffer::new();
+    raw.push_back(&message).unwrap();
+    let raw_len = raw.as_slice().bytes.len();
+
+    let mut buf = buffer::IPCBuffer::new();
+    buf.push_back_framed(&message, false).unwrap();
+    assert_eq!(buf.as_slice().bytes.len(), raw_len + 2);
+    buf.push_back_framed(&message, true).unwrap();
+    let both_len = buf.as_slice().bytes.len();
+    assert!(both_len < 2 * (raw_len + 2));
+
+    // Partial frames wait for more data
+    let mut partial = buffer::IPCBuffer::new();
+    partial
+        .extend_bytes(&buf.as_slice().bytes[..both_len - 1])
+        .unwrap();
+    assert_eq!(
+        partial.pop_front_framed::<MessageFromSand>(),
+        Ok(message.clone())
+    );
+    assert_eq!(
+        partial.pop_front_framed::<MessageFromSand>(),
+        Err(buffer::Error::UnexpectedEnd)
+    );
+
+    assert_eq!(
+        buf.pop_front_framed::<MessageFromSand>(),
+        Ok(message.clone())
+    );
+    assert_eq!(buf.pop_front_framed::<MessageFromSand>(), Ok(message));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn errno_format() {
+    assert_eq!(Errno::new(errno::ENOENT), Errno(-2));
+    assert_eq!(Errno(-2).code(), errno::ENOENT);
+    assert_eq!(format!("{:?}", Errno(-2)), "Errno(ENOENT)");
+    assert_eq!(
+        format!("{}", Errno(-2)),
+        "No such file or directory (ENOENT)"
+    );
+    assert_eq!(format!("{:?}", Errno(-2333)), "Errno(-2333)");
+    assert_eq!(format!("{}", Errno(-2333)), "unknown error 2333");
+    assert_eq!(Errno::from_ret(-13), Some(Errno::new(errno::EACCES)));
+    assert_eq!(Errno::from_ret(-4096), None);
+    assert_eq!(Errno::from_ret(3), None);
+}
+
+#[test]
+fn syscall_format() {
+    let mut call = abi::Syscall {
+        nr: 2,
+        args: [0x1000, 0, 0, 0, 0, 0],
+        ret: -2,
+        ip: 0x4000,
+        sp: 0x7000,
+    };
+    assert_eq!(
+        format!("{:?}", call),
+        "SYS_2 [1000, 0, 0, 0, 0, 0] -> ENOENT (ip=4000 sp=7000)"
+    );
+    call.ret = 3;
+    assert_eq!(
+        format!("{:?}", call),
+        "SYS_2 [1000, 0, 0, 0, 0, 0] -> 3 (ip=4000 sp=7000)"
+    );
+}
+
+#[test]
+fn every_message() {
+    let syscall = abi::Syscall {
+        nr: -1,
+        args: [1, -2, 3, -4, 5, isize::MIN],
+        ret: -38,
+        ip: 0x4000_1234,
+        sp: usize::MAX,
+    };
+    let stat = FileStat {
+        st_dev: u64::MAX,
+        st_nlink: 2,
+        st_mode: 0o40755,
+        st_uid: 1000,
+        st_gid: 1000,
+        st_rdev: 0,
+        st_size: -1,
+        st_atime: 1_600_000_000,
+        st_atime_nsec: 999_999_999,
+        st_mtime: 1_600_000_001,
+        st_mtime_nsec: 0,
+        st_ctime: 1_600_000_002,
+        st_ctime_nsec: 1,
+    };
+    let to_task = [
+        ToTask::OpenProcessReply(ProcessHandle {
+            mem: SysFd(3),
+            maps: SysFd(4),
+        }),
+        ToTask::FileReply(Ok((VFile { inode: 1 }, FileContents::Fd(SysFd(5))))),
+        ToTask::FileReply(Ok((
+            VFile { inode: 2 },
+            FileContents::Inline(Box::new(InlineBytes::new(b"abc").unwrap())),
+        ))),
+        ToTask::FileReply(Err(Errno::new(errno::ENOENT))),
+        ToTask::FileStatReply(Ok((VFile { inode: 3 }, stat))),
+        ToTask::FileStatReply(Err(Errno::new(errno::EACCES))),
+        ToTask::BytesReply(Ok((SysFd(6), 0x1234))),
+        ToTask::BytesReply(Err(Errno::new(errno::ENOMEM))),
+        ToTask::SocketReply(Ok(SysFd(7))),
+        ToTask::SocketReply(Err(Errno::new(errno::EINVAL))),
+        ToTask::Reply(Ok(())),
+        ToTask::Reply(Err(Errno::new(errno::EPERM))),
+    ];
+    let log = [
+        LogMessage::Emulated(syscall.clone()),
+        LogMessage::Remote(syscall.clone()),
+        LogMessage::Signal(
+            11,
+            abi::UserRegs {
+                ip: 0x1234,
+                sp: usize::MAX,
+                ..Default::default()
+            },
+        ),
+        LogMessage::Audit(AuditEvent::SetIdExecDenied {
+            st_mode: 0o104755,
+            st_uid: 0,
+            st_gid: 0,
+        }),
+        LogMessage::Audit(AuditEvent::UnverifiedSyscall {
+            nr: 39,
+            ip: VPtr(0x7f00_0000_1234),
+        }),
+        LogMessage::SyscallStorm(syscall),
+    ];
+    let dir = Some(VFile { inode: usize::MAX });
+    let path = VString(VPtr(0x7ffd_0000_1000));
+    let mut from_task = std::vec![
+        FromTask::OpenProcess(SysPid(100)),
+        FromTask::FileAccess {
+            dir: dir.clone(),
+            path,
+            mode: -1,
+        },
+        FromTask::FileOpen {
+            dir: None,
+            path,
+            flags: i32::MIN,
+            mode: 0o644,
+        },
+        FromTask::FileStat {
+            file: dir.clone(),
+            path: None,
+            follow_links: FollowLinks::NoFollow,
+        },
+        FromTask::FileStat {
+            file: None,
+            path: Some(path),
+            follow_links: FollowLinks::Follow,
+        },
+        FromTask::ReadLink {
+            dir: dir.clone(),
+            path,
+        },
+        FromTask::ProcessKill(VPid(2), Signal(9)),
+        FromTask::ChangeWorkingDir {
+            file: dir,
+            path: Some(path),
+        },
+        FromTask::GetWorkingDir,
+        FromTask::Exited(-1),
+        FromTask::ExecSnapshotOpen,
+        FromTask::ExecSnapshotCapture {
+            ip: VPtr(1),
+            sp: VPtr(2),
+            brk: VPtr(3),
+            brk_start: VPtr(4),
+        },
+        FromTask::SyscallLatency {
+            nr: -1,
+            trap: u64::MAX,
+            emulate: 0,
+            ipc: 1,
+            resume: 2,
+        },
+        FromTask::MakeDir {
+            dir: None,
+            path,
+            mode: 0o755,
+        },
+        FromTask::Unlink {
+            dir: Some(VFile { inode: 9 }),
+            path,
+            remove_dir: true,
+        },
+        FromTask::Rename {
+            from_dir: Some(VFile { inode: 10 }),
+            from: path,
+            to_dir: None,
+            to: VString(VPtr(0)),
+        },
+        FromTask::Signaled(Signal(15)),
+        FromTask::OpenChildProcess {
+            sys_pid: SysPid(101),
+            parent: VPid(1),
+        },
+        FromTask::ExecLoaded {
+            file: VFile { inode: 4 },
+            path,
+        },
+        FromTask::NetBind {
+            addr: VPtr(0x1000),
+            len: 16,
+        },
+        FromTask::NetConnect {
+            addr: VPtr(0x2000),
+            len: 28,
+        },
+        FromTask::SyscallStorm(abi::Syscall {
+            nr: 80,
+            args: [1, 2, 3, 4, 5, 6],
+            ret: -2,
+            ip: 0x1000,
+            sp: 0x2000,
+        }),
+    ];
+    let levels = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+    for (level, message) in levels.iter().zip(log.iter()) {
+        from_task.push(FromTask::Log(*level, message.clone()));
+    }
+
+    let mut to_sand = std::vec![
+        MessageToSand::Init {
+            args: SysFd(8),
+            tracer_settings: TracerSettings {
+                max_log_level: LogLevel::Off,
+                instruction_trace: true,
+                instruction_pointer_checks: false,
+                compress_messages: true,
+                attach_mode: AttachMode::TraceMe,
+                abort_on_syscall_storm: false,
+                exec_snapshots: true,
+                log_ring_size: 0x10000,
+                syscall_latency: false,
+                user_notif: true,
+                require_hardening: false,
+                randomize_layout: true,
+                stack_limit: 8 << 20,
+                vdso_time: false,
+            },
+        },
+        MessageToSand::Suspend,
+        MessageToSand::Resume,
+        MessageToSand::Kill(Signal(2)),
+    ];
+    for op in to_task.iter() {
+        to_sand.push(MessageToSand::Task {
+            task: VPid(u32::MAX),
+            op: op.clone(),
+        });
+    }
+
+    let mut from_sand = std::vec![
+        MessageFromSand::Diagnostic(Box::new(InlineBytes::new(b"panicked\n").unwrap())),
+        MessageFromSand::Hardening(HardeningReport::default()),
+    ];
+    for op in from_task {
+        from_sand.push(MessageFromSand::Task {
+            task: VPid(1),
+            op,
+            nr: None,
+        });
+    }
+
+    let mut buf = buffer::IPCBuffer::new();
+    for message in &to_sand {
+        buf.push_back(message).unwrap();
+        assert_eq!(buf.pop_front::<MessageToSand>(), Ok(message.clone()));
+        assert!(buf.is_empty());
+    }
+    for message in &from_sand {
+        buf.push_back(message).unwrap();
+        assert_eq!(buf.pop_front::<MessageFromSand>(), Ok(message.clone()));
+        assert!(buf.is_empty());
+        for &compress in &[false, true] {
+            buf.push_back_framed(message, compress).unwrap();
+            assert_eq!(
+                buf.pop_front_framed::<MessageFromSand>(),
+                Ok(message.clone())
+            );
+            assert!(buf.is_empty());
+        }
+    }
+}
@@ -1,8 +1,10 @@
+use crate::errno;
 use core::{
     default::Default,
     fmt,
     ops::{Add, Sub},
 };
+use generic_array::{typenum::*, GenericArray};
 
 pub const MEMFD_TEMP_NAME: &[u8] = b"bandsocks-temp\0";
 
@@ -13,6 +15,7 @@ pub mod exit {
     pub const EXIT_DISCONNECTED: usize = 121;
     pub const EXIT_IO_ERROR: usize = 122;
     pub const EXIT_OUT_OF_MEM: usize = 123;
+    pub const EXIT_WEAK_HARDENING: usize = 124;
 }
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Deserialize, Serialize)]
@@ -65,6 +68,42 @@ impl Default for SysFd {
     }
 }
 
+/// Largest file that may be sent inline, see [InlineBytes]
+pub type InlineBytesMax = U4096;
+
+/// A short byte string carried in the message itself, instead of in a file
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct InlineBytes {
+    len: usize,
+    bytes: GenericArray<u8, InlineBytesMax>,
+}
+
+impl InlineBytes {
+    pub const CAPACITY: usize = InlineBytesMax::USIZE;
+
+    /// Returns None if the bytes are larger than [InlineBytes::CAPACITY]
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut result = InlineBytes::default();
+        if bytes.len() > result.bytes.len() {
+            None
+        } else {
+            result.bytes[..bytes.len()].copy_from_slice(bytes);
+            result.len = bytes.len();
+            Some(result)
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl fmt::Debug for InlineBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InlineBytes({} bytes)", self.len)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub struct ProcessHandle {
     pub mem: SysFd,
@@ -79,10 +118,60 @@ pub struct SysPid(pub u32);
 #[repr(C)]
 pub struct Signal(pub u32);
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Hash)]
+/// An error as a syscall returns it, with the kernel's error number negated
+#[derive(PartialEq, Eq, Copy, Clone, Serialize, Deserialize, Hash)]
 #[repr(C)]
 pub struct Errno(pub i32);
 
+impl Errno {
+    /// The error for a positive number from [errno](crate::errno)
+    pub const fn new(code: i32) -> Self {
+        Errno(-code)
+    }
+
+    /// The error a raw syscall return value stands for, if it's one
+    pub fn from_ret(ret: isize) -> Option<Self> {
+        if ret < 0 && ret >= -(errno::MAX_ERRNO as isize) {
+            Some(Errno(ret as i32))
+        } else {
+            None
+        }
+    }
+
+    /// The positive error number, as libc's errno would hold it
+    pub const fn code(&self) -> i32 {
+        -self.0
+    }
+
+    /// The constant's name, like `"ENOENT"`, if it's a known error
+    pub fn name(&self) -> Option<&'static str> {
+        errno::name(self.code())
+    }
+
+    /// What strerror() would say, if it's a known error
+    pub fn description(&self) -> Option<&'static str> {
+        errno::description(self.code())
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "Errno({})", name),
+            None => write!(f, "Errno({})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.name(), self.description()) {
+            (Some(name), Some(description)) => write!(f, "{} ({})", description, name),
+            _ => write!(f, "unknown error {}", self.code()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Ord, PartialOrd, Copy, Clone, Hash, Serialize, Deserialize)]
 #[repr(C)]
 pub struct VPid(pub u32);
@@ -9,17 +9,30 @@ pub const O_ACCMODE: usize = 3;
 pub const O_RDONLY: usize = 0;
 pub const O_WRONLY: usize = 1;
 pub const O_RDWR: usize = 2;
+pub const O_CREAT: usize = 0o100;
+pub const O_TRUNC: usize = 0o1000;
+pub const F_DUPFD: usize = 0;
+pub const F_GETFD: usize = 1;
 pub const F_SETFD: usize = 2;
+pub const F_GETFL: usize = 3;
 pub const F_SETFL: usize = 4;
 pub const F_SETOWN: usize = 8;
 pub const F_CLOEXEC: usize = 1;
 pub const FASYNC: usize = 0o20000;
+pub const O_APPEND: usize = 0o2000;
 pub const O_NONBLOCK: usize = 0o4000;
 pub const O_DIRECTORY: usize = 0o200000;
 pub const O_CLOEXEC: usize = 0o2000000;
 pub const AT_SYMLINK_NOFOLLOW: i32 = 0x100;
 pub const AT_FDCWD: i32 = -100;
+pub const AT_REMOVEDIR: i32 = 0x200;
+pub const AT_NO_AUTOMOUNT: i32 = 0x800;
+pub const AT_EMPTY_PATH: i32 = 0x1000;
+pub const F_DUPFD_CLOEXEC: usize = 1030;
+pub const F_ADD_SEALS: usize = 1033;
 pub const F_GET_SEALS: usize = 1034;
+pub const MFD_CLOEXEC: usize = 1;
+pub const MFD_ALLOW_SEALING: usize = 2;
 pub const F_SEAL_SEAL: usize = 1;
 pub const F_SEAL_SHRINK: usize = 2;
 pub const F_SEAL_GROW: usize = 4;
@@ -79,6 +92,7 @@ pub const PTRACE_SINGLESTEP: usize = 9;
 pub const PTRACE_SYSCALL: usize = 24;
 pub const PTRACE_SETOPTIONS: usize = 0x4200;
 pub const PTRACE_GETEVENTMSG: usize = 0x4201;
+pub const PTRACE_GETSIGINFO: usize = 0x4202;
 pub const PTRACE_GETREGSET: usize = 0x4204;
 pub const PTRACE_SETREGSET: usize = 0x4205;
 pub const PTRACE_GET_SYSCALL_INFO: usize = 0x420e;
@@ -114,9 +128,14 @@ pub const MAP_PRIVATE: isize = 0x02;
 pub const MAP_ANONYMOUS: isize = 0x20;
 pub const MAP_FIXED: isize = 0x10;
 pub const MAP_GROWSDOWN: isize = 0x100;
+pub const MAP_NORESERVE: isize = 0x4000;
 pub const MAP_FIXED_NOREPLACE: isize = 0x100000;
 pub const MREMAP_MAYMOVE: isize = 1;
 
+// personality()
+// linux/include/uapi/linux/personality.h
+pub const ADDR_NO_RANDOMIZE: usize = 0x0040000;
+
 // linux/include/uapi/asm-generic/mman-common.h
 pub const PROT_READ: isize = 1;
 pub const PROT_WRITE: isize = 2;
@@ -182,26 +201,31 @@ pub const SYSCALL_BLOCKED: isize = -1;
 // waitid
 // linux/include/uapi/linux/wait.h
 pub const P_ALL: usize = 0;
+pub const WNOHANG: usize = 1;
 pub const WSTOPPED: usize = 2;
 pub const WEXITED: usize = 4;
 pub const WCONTINUED: usize = 8;
+pub const __WALL: usize = 0x4000_0000;
 pub const SI_MAX_SIZE: usize = 128;
 
-// errno
-// linux/include/uapi/asm-generic/errno-base.h
-pub const EINTR: i32 = 4;
-pub const EIO: i32 = 5;
-pub const E2BIG: i32 = 7;
-pub const ENOEXEC: i32 = 8;
-pub const EBADF: i32 = 9;
-pub const ECHILD: i32 = 10;
-pub const EAGAIN: i32 = 11;
-pub const EFAULT: i32 = 14;
-pub const EEXIST: i32 = 17;
-pub const EINVAL: i32 = 22;
-pub const EROFS: i32 = 30;
-pub const ENOSYS: i32 = 38;
-pub const ECONNRESET: i32 = 104;
+// clone()
+// linux/include/uapi/linux/sched.h
+pub const CSIGNAL: usize = 0xff;
+pub const CLONE_VM: usize = 0x100;
+pub const CLONE_FS: usize = 0x200;
+pub const CLONE_FILES: usize = 0x400;
+pub const CLONE_SIGHAND: usize = 0x800;
+pub const CLONE_VFORK: usize = 0x4000;
+pub const CLONE_THREAD: usize = 0x10000;
+pub const CLONE_SYSVSEM: usize = 0x40000;
+pub const CLONE_SETTLS: usize = 0x80000;
+pub const CLONE_PARENT_SETTID: usize = 0x100000;
+pub const CLONE_CHILD_CLEARTID: usize = 0x200000;
+pub const CLONE_DETACHED: usize = 0x400000;
+pub const CLONE_CHILD_SETTID: usize = 0x1000000;
+
+// errno, shared with the runtime
+pub use crate::protocol::errno::*;
 
 // signo
 // linux/include/uapi/asm-generic/signal.h
@@ -212,10 +236,16 @@ pub const SIGKILL: u8 = 9;
 pub const SIGUSR1: u8 = 10;
 pub const SIGUSR2: u8 = 12;
 pub const SIGSEGV: u8 = 11;
+pub const SIGALRM: u8 = 14;
 pub const SIGCHLD: u8 = 17;
 pub const SIGCONT: u8 = 18;
 pub const SIGSTOP: u8 = 19;
+pub const SIGTSTP: u8 = 20;
+pub const SIGTTIN: u8 = 21;
+pub const SIGTTOU: u8 = 22;
 pub const SIGURG: u8 = 23;
+pub const SIGVTALRM: u8 = 26;
+pub const SIGPROF: u8 = 27;
 pub const SIGIO: u8 = 29;
 pub const SIGSYS: u8 = 31;
 
@@ -244,6 +274,7 @@ pub struct MsgHdr {
 // sendmsg()
 // linux/include/linux/socket.h
 pub const MSG_DONTWAIT: usize = 0x40;
+pub const MSG_CMSG_CLOEXEC: usize = 0x40000000;
 
 // linux/include/linux/socket.h
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
@@ -288,6 +319,8 @@ pub const CLD_DUMPED: u32 = 3;
 pub const CLD_TRAPPED: u32 = 4;
 pub const CLD_STOPPED: u32 = 5;
 pub const CLD_CONTINUED: u32 = 6;
+pub const SI_KERNEL: u32 = 0x80;
+pub const SI_TIMER: u32 = -2i32 as u32;
 
 // sigaction
 // linux/inclide/linux/signal_types.h
@@ -311,14 +344,128 @@ pub struct SigSet {
     pub sig: [u64; 1],
 }
 
+// sigprocmask()
+// linux/include/uapi/asm-generic/signal-defs.h
+pub const SIG_BLOCK: usize = 0;
+
+// poll()
+// linux/include/uapi/asm-generic/poll.h
+pub const POLLIN: i16 = 1;
+
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: i16,
+    pub revents: i16,
+}
+
+// prctl()
+// linux/include/uapi/linux/prctl.h
+pub const PR_GET_NO_NEW_PRIVS: usize = 39;
+pub const PR_CAP_AMBIENT: usize = 47;
+pub const PR_CAP_AMBIENT_IS_SET: usize = 1;
+pub const PR_CAP_AMBIENT_CLEAR_ALL: usize = 4;
+
+// capget(), capset()
+// linux/include/uapi/linux/capability.h
+pub const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+pub const LINUX_CAPABILITY_U32S_3: usize = 2;
+
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct CapUserHeader {
+    pub version: u32,
+    pub pid: i32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct CapUserData {
+    pub effective: u32,
+    pub permitted: u32,
+    pub inheritable: u32,
+}
+
+// prlimit64()
+// linux/include/uapi/asm-generic/resource.h
+pub const RLIMIT_STACK: usize = 3;
+
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct RLimit {
+    pub rlim_cur: u64,
+    pub rlim_max: u64,
+}
+
+// seccomp()
+// linux/include/uapi/linux/seccomp.h
+pub const SECCOMP_SET_MODE_FILTER: usize = 1;
+pub const SECCOMP_FILTER_FLAG_NEW_LISTENER: usize = 1 << 3;
+pub const SECCOMP_FILTER_FLAG_WAIT_KILLABLE_RECV: usize = 1 << 5;
+pub const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+pub const SECCOMP_ADDFD_FLAG_SEND: u32 = 1 << 1;
+pub const SECCOMP_IOCTL_NOTIF_RECV: usize = 0xc050_2100;
+pub const SECCOMP_IOCTL_NOTIF_SEND: usize = 0xc018_2101;
+pub const SECCOMP_IOCTL_NOTIF_ID_VALID: usize = 0x4008_2102;
+pub const SECCOMP_IOCTL_NOTIF_ADDFD: usize = 0x4018_2103;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SeccompData {
+    pub nr: i32,
+    pub arch: u32,
+    pub instruction_pointer: u64,
+    pub args: [u64; 6],
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SeccompNotif {
+    pub id: u64,
+    pub pid: u32,
+    pub flags: u32,
+    pub data: SeccompData,
+}
+
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct SeccompNotifResp {
+    pub id: u64,
+    pub val: i64,
+    pub error: i32,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct SeccompNotifAddFd {
+    pub id: u64,
+    pub flags: u32,
+    pub srcfd: u32,
+    pub newfd: u32,
+    pub newfd_flags: u32,
+}
+
 /// linux/include/uapi/linux/binfmts.h
 pub const BINPRM_BUF_SIZE: usize = 256;
 
 /// linux/include/linux/socket.h
 pub const AF_UNIX: usize = 1;
+pub const AF_INET: usize = 2;
+pub const AF_INET6: usize = 10;
+
+/// Sizes of struct sockaddr_in, sockaddr_in6, and sockaddr_un
+pub const SOCKADDR_IN_LEN: usize = 16;
+pub const SOCKADDR_IN6_LEN: usize = 28;
+pub const SOCKADDR_UN_LEN: usize = 110;
 
 /// linux/include/linux/net.h
 pub const SOCK_STREAM: usize = 1;
+pub const SOCK_TYPE_MASK: usize = 0xf;
+
+/// linux/include/uapi/linux/in.h
+pub const IPPROTO_TCP: usize = 6;
 
 /// linux/arch/x86/include/asm/page_64_types.h
 pub const TASK_SIZE: usize = (1 << 47) - PAGE_SIZE;
@@ -329,6 +476,9 @@ pub const ELF_STACK_ALIGN_MASK: usize = ELF_STACK_ALIGN - 1;
 
 pub const MMAP_RND_BITS: usize = 28;
 
+/// linux/include/uapi/linux/time.h
+pub const CLOCK_MONOTONIC: usize = 1;
+
 /// linux/include/uapi/linux/time.h
 #[derive(Debug, Clone)]
 #[repr(C)]
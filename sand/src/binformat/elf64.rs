@@ -1,25 +1,31 @@
 use crate::{
     abi,
-    binformat::{Exec, ExecFile, FileHeader},
+    binformat::{init_regs, Exec, ExecFile, FileHeader},
     mem::{
         maps::{MappedRange, MemProtect, Segment},
         page::{page_offset, VPage},
         string::VStringRange,
     },
     process::{stack::StackBuilder, task::StoppedTask},
-    protocol::{abi::UserRegs, Errno, VPtr, VString},
+    protocol::{Errno, FromTask, VPtr, VString},
     remote::{
         file::{LoadedSegment, MapLocation, RemoteFd, TempRemoteFd},
         scratchpad::Scratchpad,
         trampoline::Trampoline,
     },
 };
+use alloc::rc::Rc;
 use core::{
+    cell::RefCell,
     mem::{size_of, size_of_val},
     ops::Range,
 };
 use goblin::elf64::{header, header::Header, program_header, program_header::ProgramHeader};
 
+/// Random interpreter locations to try before giving up on randomness and
+/// putting it right after the main program
+const INTERP_PLACEMENT_ATTEMPTS: usize = 8;
+
 pub fn detect(fh: &FileHeader) -> bool {
     let ehdr = elf_header(fh);
     let magic = &ehdr.e_ident[..header::SELFMAG];
@@ -39,6 +45,7 @@ pub async fn load(
     exec: Exec,
     file: ExecFile,
 ) -> Result<(), Errno> {
+    let vfile = file.vfile.clone();
     let mut tr = Trampoline::new(stopped_task);
     let mut pad = Scratchpad::new(&mut tr).await?;
     let elf_file = ElfFile::from_local(&mut pad, file).await;
@@ -52,6 +59,10 @@ pub async fn load(
     elf_cleanup_result?;
 
     entry.init_task(stopped_task);
+    stopped_task.task.msg.send(FromTask::ExecLoaded {
+        file: vfile,
+        path: entry.execfn,
+    });
     Ok(())
 }
 
@@ -63,7 +74,7 @@ fn elf_segment(header: &ProgramHeader) -> Result<Segment, Errno> {
     let vaddr = VPtr(header.p_vaddr as usize);
     let file_start = header.p_offset as usize;
     if page_offset(file_start) != VPage::offset(vaddr) {
-        return Err(Errno(-abi::EINVAL));
+        return Err(Errno::new(abi::EINVAL));
     }
     Ok(Segment {
         mapped_range: MappedRange {
@@ -96,27 +107,31 @@ struct ElfEntry {
     ip: VPtr,
     sp: VPtr,
     brk_base: VPage,
+    /// Copy of the exec filename on the new stack, for AT_EXECFN
+    execfn: VString,
 }
 
 impl ElfEntry {
     fn init_task(&self, stopped_task: &mut StoppedTask) {
-        assert_eq!(self.sp.0 & abi::ELF_STACK_ALIGN_MASK, 0);
-        let prev_regs = stopped_task.regs.clone();
-        stopped_task.regs.clone_from(&UserRegs {
-            sp: self.sp.0,
-            ip: self.ip.0,
-            cs: prev_regs.cs,
-            ss: prev_regs.ss,
-            ds: prev_regs.ds,
-            es: prev_regs.ds,
-            flags: prev_regs.flags,
-            ..Default::default()
-        });
-
-        stopped_task.task.task_data.mm.randomize_brk(self.brk_base);
+        init_regs(stopped_task, self.ip, self.sp);
+        let task_data = &mut stopped_task.task.task_data;
+        let randomize = task_data.tracer_settings.randomize_layout;
+        // The new program's memory is its own, even if the old one's was shared
+        let mut mm = task_data.mm.borrow().clone();
+        mm.init_brk(self.brk_base, randomize);
+        task_data.mm = Rc::new(RefCell::new(mm));
     }
 }
 
+fn offset_pages(pages: &Range<VPage>, offset: VPage) -> Range<VPage> {
+    VPage::round_down(pages.start.ptr() + offset.ptr().0)
+        ..VPage::round_down(pages.end.ptr() + offset.ptr().0)
+}
+
+fn pages_overlap(a: &Range<VPage>, b: &Range<VPage>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
 #[derive(Debug)]
 struct ElfFile {
     local: ExecFile,
@@ -204,6 +219,20 @@ impl ElfFile {
         Ok(VPtr(addr))
     }
 
+    /// Where the program headers end up, before any load offset
+    ///
+    /// A PT_PHDR segment says exactly where they are. Without one, they're
+    /// assumed to be mapped along with the first loadable segment.
+    fn phdr_load_ptr(&self, header_ptr: VPtr) -> Result<VPtr, Errno> {
+        for idx in self.program_header_range() {
+            let phdr = self.program_header(idx)?;
+            if phdr.p_type == program_header::PT_PHDR {
+                return Ok(VPtr(phdr.p_vaddr as usize));
+            }
+        }
+        Ok(header_ptr + self.header().e_phoff as usize)
+    }
+
     fn program_header_range(&self) -> Range<u16> {
         0..self.header().e_phnum
     }
@@ -241,13 +270,23 @@ impl ElfFile {
         interp: &Option<ElfFile>,
         exec: Exec,
     ) -> Result<ElfEntry, Errno> {
-        let offset = self.determine_load_offset(VPage::task_dyn_base());
+        let randomize = trampoline
+            .stopped_task
+            .task
+            .task_data
+            .tracer_settings
+            .randomize_layout;
+        let offset = self.determine_load_offset(VPage::task_dyn_base(), randomize);
         let header = self.header();
         let header_ptr = self.header_load_ptr()?;
+        let phdr_ptr = self.phdr_load_ptr(header_ptr)?;
 
         let interp_offset = match interp {
             None => offset,
-            Some(elf) => elf.determine_load_offset(VPage::task_unmapped_base()),
+            Some(elf) => {
+                let main_pages = offset_pages(&self.load_pages()?, offset);
+                elf.determine_interp_offset(&main_pages, randomize)?
+            }
         };
         let interp_header = match interp {
             None => header,
@@ -259,9 +298,14 @@ impl ElfFile {
         };
 
         let elf_aux = ElfAux {
-            phdr: header_ptr + header.e_phoff as usize + offset.ptr().0,
+            phdr: phdr_ptr + offset.ptr().0,
             phnum: header.e_phnum as usize,
-            base: interp_header_ptr + interp_offset.ptr().0,
+            // Zero without an interpreter, which is how ld.so run as a
+            // program knows it wasn't loaded for someone else
+            base: match interp {
+                None => VPtr::null(),
+                Some(_) => interp_header_ptr + interp_offset.ptr().0,
+            },
             entry: VPtr(header.e_entry as usize) + offset.ptr().0,
             uid: 0,  // todo
             euid: 0, // todo
@@ -269,7 +313,7 @@ impl ElfFile {
             egid: 0, // todo
         };
 
-        let stack = {
+        let (stack, execfn) = {
             let mut pad = Scratchpad::new(trampoline).await?;
             let main_result = self.prepare_stack(&mut pad, exec, elf_aux).await;
             let cleanup_result = pad.free().await;
@@ -291,17 +335,63 @@ impl ElfFile {
             brk_base: segments.end.max(interp_segments.end),
             ip: VPtr(interp_header.e_entry as usize) + interp_offset.ptr().0,
             sp: stack.sp,
+            execfn,
         })
     }
 
-    fn determine_load_offset(&self, dyn_base: VPage) -> VPage {
-        if self.header().e_type == header::ET_DYN {
+    fn determine_load_offset(&self, dyn_base: VPage, randomize: bool) -> VPage {
+        if self.header().e_type != header::ET_DYN {
+            VPage::null()
+        } else if randomize {
             dyn_base.randomize()
         } else {
-            VPage::null()
+            dyn_base
         }
     }
 
+    /// Choose where an interpreter goes, keeping clear of the main program
+    ///
+    /// A main program linked at fixed addresses could sit anywhere, including
+    /// where the interpreter would normally go. If the usual range won't do,
+    /// the interpreter goes just past the end of the main program instead.
+    fn determine_interp_offset(
+        &self,
+        main_pages: &Range<VPage>,
+        randomize: bool,
+    ) -> Result<VPage, Errno> {
+        let pages = self.load_pages()?;
+        let attempts = if randomize {
+            INTERP_PLACEMENT_ATTEMPTS
+        } else {
+            1
+        };
+        for _ in 0..attempts {
+            let offset = self.determine_load_offset(VPage::task_unmapped_base(), randomize);
+            if !pages_overlap(&offset_pages(&pages, offset), main_pages) {
+                return Ok(offset);
+            }
+        }
+        if self.header().e_type != header::ET_DYN || main_pages.end < pages.start {
+            return Err(Errno::new(abi::ENOMEM));
+        }
+        Ok(VPage::round_down(VPtr(
+            main_pages.end.ptr().0 - pages.start.ptr().0,
+        )))
+    }
+
+    /// Pages covered by the loadable segments, before any load offset
+    fn load_pages(&self) -> Result<Range<VPage>, Errno> {
+        let mut range = VPage::max()..VPage::null();
+        for idx in self.program_header_range() {
+            let phdr = self.program_header(idx)?;
+            if phdr.p_type == program_header::PT_LOAD {
+                let mem_pages = elf_segment(&phdr)?.mem_pages();
+                range = range.start.min(mem_pages.start)..range.end.max(mem_pages.end);
+            }
+        }
+        Ok(range)
+    }
+
     async fn load_segments(
         &self,
         trampoline: &mut Trampoline<'_, '_, '_>,
@@ -331,8 +421,25 @@ impl ElfFile {
         scratchpad: &mut Scratchpad<'_, '_, '_, '_>,
         exec: Exec,
         elf_aux: ElfAux,
-    ) -> Result<StackBuilder, Errno> {
+    ) -> Result<(StackBuilder, VString), Errno> {
         let mut stack = StackBuilder::new(scratchpad).await?;
+        match self.fill_stack(&mut stack, scratchpad, exec, elf_aux).await {
+            Ok(execfn) => Ok((stack, execfn)),
+            Err(err) => {
+                // Bad pointers from the caller, or a task that has died
+                let _ = stack.free(&mut scratchpad.trampoline).await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn fill_stack(
+        &self,
+        stack: &mut StackBuilder,
+        scratchpad: &mut Scratchpad<'_, '_, '_, '_>,
+        exec: Exec,
+        elf_aux: ElfAux,
+    ) -> Result<VString, Errno> {
         let mut argc = 0;
 
         let elf_hwcap = raw_cpuid::cpuid!(1).edx as usize;
@@ -377,6 +484,15 @@ impl ElfFile {
             }
         }
 
+        let vdso_ptr = scratchpad
+            .trampoline
+            .kernel_mem()?
+            .vdso
+            .pages
+            .mem_pages()
+            .start
+            .ptr();
+
         // ld.so can show you the aux vectors:
         // cargo run -- -e LD_SHOW_AUXV -- ubuntu /usr/lib/x86_64-linux-gnu/ld-2.31.so
         stack
@@ -385,15 +501,7 @@ impl ElfFile {
                 &[
                     0, // end of envp
                     abi::AT_SYSINFO_EHDR,
-                    scratchpad
-                        .trampoline
-                        .kernel_mem
-                        .vdso
-                        .pages
-                        .mem_pages()
-                        .start
-                        .ptr()
-                        .0,
+                    vdso_ptr.0,
                     abi::AT_HWCAP,
                     elf_hwcap,
                     abi::AT_PAGESZ,
@@ -454,6 +562,6 @@ impl ElfFile {
         stack.store_vectors(scratchpad, &argc_vec).await?;
         stack.push_stored_vectors(scratchpad).await?;
 
-        Ok(stack)
+        Ok(VString(filename_ptr))
     }
 }
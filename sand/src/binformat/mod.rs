@@ -1,14 +1,24 @@
 pub mod elf64;
 pub mod script;
+pub mod snapshot;
 
 use crate::{
     abi,
-    mem::string::VStringArray,
+    mem::{string::VStringArray, vdso},
     nolibc::{File, TempFile},
     process::task::{StoppedTask, Task},
-    protocol::{Errno, FromTask, ToTask, VString},
+    protocol::{
+        abi::{UserRegs, S_ISGID, S_ISUID, S_IXGRP},
+        AuditEvent, Errno, FollowLinks, FromTask, LogLevel, LogMessage, ToTask, VFile, VPtr,
+        VString,
+    },
+    remote::trampoline::Trampoline,
 };
 
+/// Every process in the sandbox runs with these virtual credentials
+const VIRTUAL_UID: u32 = 0;
+const VIRTUAL_GID: u32 = 0;
+
 #[derive(Debug)]
 pub struct Exec {
     pub filename: VString,
@@ -18,17 +28,74 @@ pub struct Exec {
 
 impl Exec {
     pub async fn load(self, stopped_task: &mut StoppedTask<'_, '_>) -> Result<(), Errno> {
+        self.load_image(stopped_task).await?;
+        close_on_exec(stopped_task).await;
+        Ok(())
+    }
+
+    async fn load_image(self, stopped_task: &mut StoppedTask<'_, '_>) -> Result<(), Errno> {
+        let initial = core::mem::replace(&mut stopped_task.task.initial_exec, false);
+        if initial && !stopped_task.task.task_data.tracer_settings.vdso_time {
+            vdso::patch_time_functions(stopped_task)?;
+        }
+        if initial && stopped_task.task.task_data.tracer_settings.exec_snapshots {
+            if let Some(file) = snapshot::open(stopped_task).await? {
+                return snapshot::restore(stopped_task, file).await;
+            }
+            self.load_binary(stopped_task).await?;
+            // A snapshot that can't be saved only costs speed next time, and
+            // the runtime has already logged why
+            let _ = snapshot::capture(stopped_task).await;
+            Ok(())
+        } else {
+            self.load_binary(stopped_task).await
+        }
+    }
+
+    async fn load_binary(self, stopped_task: &mut StoppedTask<'_, '_>) -> Result<(), Errno> {
         let file = ExecFile::new(stopped_task.task, self.filename).await?;
+        check_set_id(stopped_task.task, &file).await?;
         if script::detect(&file.header) {
             script::load(stopped_task, self, file).await
         } else if elf64::detect(&file.header) {
             elf64::load(stopped_task, self, file).await
         } else {
-            Err(Errno(-abi::ENOEXEC))
+            Err(Errno::new(abi::ENOEXEC))
         }
     }
 }
 
+/// Start a freshly loaded program at its entry point, with a clean register
+/// set apart from the segment selectors and flags
+pub fn init_regs(stopped_task: &mut StoppedTask, ip: VPtr, sp: VPtr) {
+    assert_eq!(sp.0 & abi::ELF_STACK_ALIGN_MASK, 0);
+    let prev_regs = stopped_task.regs.clone();
+    stopped_task.regs.clone_from(&UserRegs {
+        sp: sp.0,
+        ip: ip.0,
+        cs: prev_regs.cs,
+        ss: prev_regs.ss,
+        ds: prev_regs.ds,
+        es: prev_regs.ds,
+        flags: prev_regs.flags,
+        ..Default::default()
+    });
+}
+
+/// The new program doesn't inherit close-on-exec descriptors
+///
+/// The kernel never sees this exec, so it can't close them for us. Only the
+/// descriptors in the file table are known here; others stay open. The exec
+/// has already replaced the program, so failures are ignored like the
+/// kernel's own.
+async fn close_on_exec(stopped_task: &mut StoppedTask<'_, '_>) {
+    let fds = stopped_task.task.task_data.file_table.exec();
+    let mut tr = Trampoline::new(stopped_task);
+    for fd in fds {
+        let _ = fd.close(&mut tr).await;
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 #[repr(align(8))]
@@ -47,15 +114,51 @@ impl FileHeader {
     }
 }
 
+/// Set-user-ID and set-group-ID bits are honored when they change nothing
+///
+/// Credentials are virtual and fixed, so a set-ID file owned by root (like
+/// sudo or ping) runs exactly as it would on a real system where the caller
+/// is root. A transition to any other identity can't be emulated yet, and
+/// fails with EPERM and an audit event instead of silently running with the
+/// wrong credentials.
+async fn check_set_id(task: &mut Task<'_>, file: &ExecFile) -> Result<(), Errno> {
+    let (_vfile, stat) = ipc_call!(
+        task,
+        FromTask::FileStat {
+            file: Some(file.vfile.clone()),
+            path: None,
+            follow_links: FollowLinks::Follow,
+        },
+        ToTask::FileStatReply(result),
+        result?
+    );
+    let setuid = stat.st_mode & S_ISUID != 0 && stat.st_uid != VIRTUAL_UID;
+    // Without group execute permission, S_ISGID means mandatory locking
+    let setgid =
+        stat.st_mode & (S_ISGID | S_IXGRP) == (S_ISGID | S_IXGRP) && stat.st_gid != VIRTUAL_GID;
+    if setuid || setgid {
+        let event = AuditEvent::SetIdExecDenied {
+            st_mode: stat.st_mode,
+            st_uid: stat.st_uid,
+            st_gid: stat.st_gid,
+        };
+        task.log(LogLevel::Warn, LogMessage::Audit(event));
+        Err(Errno::new(abi::EPERM))
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct ExecFile {
+    pub vfile: VFile,
     pub inner: TempFile,
     pub header: FileHeader,
 }
 
 impl ExecFile {
     pub async fn new<'q, 's, 't>(task: &'s mut Task<'q>, path: VString) -> Result<Self, Errno> {
-        let (_vfile, sysfd) = ipc_call!(
+        let (vfile, contents) = ipc_call!(
             task,
             FromTask::FileOpen {
                 dir: None,
@@ -66,8 +169,12 @@ impl ExecFile {
             ToTask::FileReply(result),
             result?
         );
-        let inner = TempFile(File::new(sysfd));
+        let inner = TempFile::from_contents(contents)?;
         let header = FileHeader::new(&inner.0)?;
-        Ok(ExecFile { inner, header })
+        Ok(ExecFile {
+            vfile,
+            inner,
+            header,
+        })
     }
 }
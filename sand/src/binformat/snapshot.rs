@@ -0,0 +1,118 @@
+use crate::{
+    abi,
+    binformat::init_regs,
+    mem::{
+        maps::{MappedPages, MappedRange, MemFlags, MemProtect},
+        page::VPage,
+    },
+    nolibc::{File, TempFile},
+    process::task::{StoppedTask, TaskMemManagement},
+    protocol::{Errno, ExecSnapshotHeader, ExecSnapshotRegion, FromTask, ToTask, VPtr},
+    remote::{
+        file::{RemoteFd, TempRemoteFd},
+        scratchpad::Scratchpad,
+        trampoline::Trampoline,
+    },
+};
+use alloc::rc::Rc;
+use core::{cell::RefCell, mem::size_of};
+
+/// Bytes at the address in the AT_RANDOM aux vector
+const AT_RANDOM_LEN: usize = 16;
+
+/// Ask the runtime for a snapshot of this exec, if it has one saved
+pub async fn open(stopped_task: &mut StoppedTask<'_, '_>) -> Result<Option<TempFile>, Errno> {
+    ipc_call!(
+        stopped_task.task,
+        FromTask::ExecSnapshotOpen,
+        ToTask::BytesReply(result),
+        match result {
+            Ok((sys_fd, _len)) => Ok(Some(TempFile(File::new(sys_fd)))),
+            Err(Errno(err)) if err == -abi::ENOENT => Ok(None),
+            Err(err) => Err(err),
+        }
+    )
+}
+
+/// Save the state of a task that has just finished loading its binary
+///
+/// The task stays stopped while the runtime reads its memory. Registers
+/// haven't been written to the process yet, so they travel in the message.
+pub async fn capture(stopped_task: &mut StoppedTask<'_, '_>) -> Result<(), Errno> {
+    let mm = stopped_task.task.task_data.mm.borrow().clone();
+    let op = FromTask::ExecSnapshotCapture {
+        ip: VPtr(stopped_task.regs.ip),
+        sp: VPtr(stopped_task.regs.sp),
+        brk: mm.brk,
+        brk_start: mm.brk_start.ptr(),
+    };
+    ipc_call!(stopped_task.task, op, ToTask::Reply(result), result)
+}
+
+/// Replace the task's memory and registers with a saved snapshot
+///
+/// Each region is mapped privately from the snapshot file, so every run
+/// starts from the same pages without copying them.
+pub async fn restore(stopped_task: &mut StoppedTask<'_, '_>, file: TempFile) -> Result<(), Errno> {
+    let mut header: ExecSnapshotHeader = Default::default();
+    file.0.pread_exact(header.as_bytes_mut(), 0)?;
+    let brk_start = VPage::parse(VPtr(header.brk_start)).map_err(|()| Errno::new(abi::EINVAL))?;
+
+    let mut tr = Trampoline::new(stopped_task);
+    let mut pad = Scratchpad::new(&mut tr).await?;
+    let remote = RemoteFd::from_local(&mut pad, &file.0.fd).await;
+    let pad_cleanup_result = pad.free().await;
+    let remote = TempRemoteFd(remote?);
+    pad_cleanup_result?;
+
+    tr.unmap_all_userspace_mem().await;
+    let result = map_regions(&mut tr, &file, &header, &remote.0).await;
+    let remote_cleanup_result = remote.free(&mut tr).await;
+    result?;
+    remote_cleanup_result?;
+
+    // Every run gets its own stack canary and pointer guard
+    if header.random != 0 {
+        tr.getrandom_exact(VPtr(header.random), AT_RANDOM_LEN, 0)
+            .await?;
+    }
+
+    init_regs(stopped_task, VPtr(header.ip), VPtr(header.sp));
+    // The new program's memory is its own, even if the old one's was shared
+    stopped_task.task.task_data.mm = Rc::new(RefCell::new(TaskMemManagement {
+        brk_start,
+        brk: VPtr(header.brk),
+    }));
+    Ok(())
+}
+
+async fn map_regions(
+    trampoline: &mut Trampoline<'_, '_, '_>,
+    file: &TempFile,
+    header: &ExecSnapshotHeader,
+    remote: &RemoteFd,
+) -> Result<(), Errno> {
+    for index in 0..header.region_count {
+        let mut region: ExecSnapshotRegion = Default::default();
+        let offset = size_of::<ExecSnapshotHeader>() + index * size_of::<ExecSnapshotRegion>();
+        file.0.pread_exact(region.as_bytes_mut(), offset)?;
+        let pages = MappedPages::parse(&MappedRange {
+            mem: VPtr(region.start)..VPtr(region.end),
+            file_start: region.data_offset,
+        })
+        .map_err(|()| Errno::new(abi::EINVAL))?;
+        let prot = region.prot as isize;
+        let flags = MemFlags {
+            protect: MemProtect {
+                read: prot & abi::PROT_READ != 0,
+                write: prot & abi::PROT_WRITE != 0,
+                execute: prot & abi::PROT_EXEC != 0,
+            },
+            mayshare: false,
+        };
+        trampoline
+            .mmap_fixed(&pages, remote, &flags, abi::MAP_FIXED)
+            .await?;
+    }
+    Ok(())
+}
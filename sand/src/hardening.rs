@@ -0,0 +1,68 @@
+use crate::{
+    abi,
+    nolibc::prctl,
+    protocol::{Errno, HardeningReport},
+};
+use sc::syscall;
+
+// The tracer never needs any privileges of its own. Whatever capabilities the
+// runtime was started with are given up here, before seccomp, and then
+// checked rather than trusted, so a failed capset shows up in the report
+// instead of passing silently.
+
+/// Give up every capability, returning a report with the capability fields
+/// filled in
+///
+/// This must run before the tracer's seccomp policy, which doesn't allow
+/// capset or capget.
+pub fn drop_capabilities() -> HardeningReport {
+    // Fails on kernels without ambient capabilities, which have nothing to clear
+    let _ = prctl(abi::PR_CAP_AMBIENT, abi::PR_CAP_AMBIENT_CLEAR_ALL, 0);
+
+    let header = abi::CapUserHeader {
+        version: abi::LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [abi::CapUserData::default(); abi::LINUX_CAPABILITY_U32S_3];
+    let _ = unsafe { syscall!(CAPSET, &header as *const _, data.as_ptr()) };
+
+    HardeningReport {
+        capabilities_dropped: capabilities_empty() == Ok(true),
+        ambient_cleared: ambient_empty() == Ok(true),
+        no_new_privs: false,
+        tracer_policy: false,
+    }
+}
+
+/// Check that no_new_privs is set, which happens when the first seccomp
+/// policy is installed
+pub fn check_no_new_privs(report: &mut HardeningReport) {
+    report.no_new_privs = prctl(abi::PR_GET_NO_NEW_PRIVS, 0, 0) == Ok(1);
+}
+
+fn capabilities_empty() -> Result<bool, Errno> {
+    let mut header = abi::CapUserHeader {
+        version: abi::LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [abi::CapUserData::default(); abi::LINUX_CAPABILITY_U32S_3];
+    match unsafe { syscall!(CAPGET, &mut header as *mut _, data.as_mut_ptr()) } as isize {
+        0 => Ok(data.iter().all(|set| set == &abi::CapUserData::default())),
+        err => Err(Errno(err as i32)),
+    }
+}
+
+fn ambient_empty() -> Result<bool, Errno> {
+    // The kernel has no call to read the whole set, so ask about each
+    // capability until it reports one past the last. Kernels without ambient
+    // capabilities report that right away.
+    let mut cap = 0;
+    loop {
+        match prctl(abi::PR_CAP_AMBIENT, abi::PR_CAP_AMBIENT_IS_SET, cap) {
+            Ok(0) => cap += 1,
+            Ok(_) => return Ok(false),
+            Err(Errno(err)) if err == -abi::EINVAL => return Ok(true),
+            Err(err) => return Err(err),
+        }
+    }
+}
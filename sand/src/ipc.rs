@@ -5,23 +5,31 @@ use crate::{
     protocol::{
         buffer,
         buffer::{FilesMax, IPCBuffer},
-        MessageFromSand, MessageToSand, SysFd,
+        InlineBytes, MessageFromSand, MessageToSand, SysFd,
     },
     EXIT_DISCONNECTED,
 };
+use alloc::boxed::Box;
 use core::{
+    fmt,
     mem::size_of,
     ptr,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
 };
 use sc::syscall;
 use typenum::Unsigned;
 
 static SIGIO_FLAG: AtomicBool = AtomicBool::new(true);
 
+/// The tracer's socket, once initialized, so diagnostics from anywhere can use it
+static DIAGNOSTIC_FD: AtomicU32 = AtomicU32::new(NO_DIAGNOSTIC_FD);
+static DIAGNOSTIC_FRAMED: AtomicBool = AtomicBool::new(false);
+const NO_DIAGNOSTIC_FD: u32 = u32::MAX;
+
 pub struct Socket {
     file: File,
     recv_buffer: IPCBuffer,
+    compress_messages: bool,
 }
 
 #[repr(C)]
@@ -32,13 +40,29 @@ struct CMsgBuffer {
 
 impl Socket {
     pub fn new(file: File) -> Socket {
+        // Nothing the tracer starts may inherit the socket, or sandboxed code
+        // could write messages to the runtime as if it were the tracer
+        file.fcntl(abi::F_SETFD, abi::F_CLOEXEC)
+            .expect("setting socket close-on-exec");
         Socket::setup_sigio(&file);
         Socket {
             file,
             recv_buffer: IPCBuffer::new(),
+            compress_messages: false,
         }
     }
 
+    /// Switch outgoing messages to the framed and compressed format, as
+    /// negotiated by the Init message
+    ///
+    /// Diagnostics start using the socket at this point too. Until the runtime
+    /// has sent Init, it may not be reading.
+    pub fn set_compress_messages(&mut self, compress_messages: bool) {
+        self.compress_messages = compress_messages;
+        DIAGNOSTIC_FRAMED.store(compress_messages, Ordering::SeqCst);
+        DIAGNOSTIC_FD.store(self.file.fd.0, Ordering::SeqCst);
+    }
+
     fn setup_sigio(file: &File) {
         // Note that we want blocking writes and non-blocking reads. See the flags in
         // sendmsg/recvmsg.
@@ -69,7 +93,21 @@ impl Socket {
         }
     }
 
+    /// Wait for the next message, even if no SIGIO has arrived yet
+    pub fn recv_blocking(&mut self) -> MessageToSand {
+        loop {
+            if let Some(message) = self.recv() {
+                return message;
+            }
+            self.recv_to_buffer_with_flags(0);
+        }
+    }
+
     fn recv_to_buffer(&mut self) {
+        self.recv_to_buffer_with_flags(abi::MSG_DONTWAIT);
+    }
+
+    fn recv_to_buffer_with_flags(&mut self, flags: usize) {
         let available = self.recv_buffer.begin_fill();
         let mut iov = IOVec {
             base: available.bytes.as_mut_ptr(),
@@ -85,7 +123,6 @@ impl Socket {
             msg_controllen: size_of::<CMsgBuffer>(),
             msg_flags: 0,
         };
-        let flags = abi::MSG_DONTWAIT;
         let result =
             unsafe { syscall!(RECVMSG, self.file.fd.0, &mut msghdr as *mut MsgHdr, flags) };
         match result as isize {
@@ -112,42 +149,96 @@ impl Socket {
     }
 
     pub fn send(&self, message: &MessageFromSand) {
-        let mut buffer = IPCBuffer::new();
-        buffer.push_back(message).expect("serialize failed");
-        let mut cmsg = CMsgBuffer {
-            hdr: CMsgHdr {
-                cmsg_len: size_of::<CMsgHdr>() + size_of::<u32>() * buffer.as_slice().files.len(),
-                cmsg_level: abi::SOL_SOCKET,
-                cmsg_type: abi::SCM_RIGHTS,
-            },
-            files: unsafe { core::mem::zeroed() },
-        };
-        let slice = buffer.as_slice();
-        for (idx, file) in slice.files.iter().enumerate() {
-            cmsg.files[idx] = file.0;
+        send_to(&self.file.fd, self.compress_messages, message);
+    }
+}
+
+fn send_to(fd: &SysFd, compress_messages: bool, message: &MessageFromSand) {
+    let mut buffer = IPCBuffer::new();
+    let result = if compress_messages {
+        buffer.push_back_framed(message, true)
+    } else {
+        buffer.push_back(message)
+    };
+    result.expect("serialize failed");
+    let mut cmsg = CMsgBuffer {
+        hdr: CMsgHdr {
+            cmsg_len: size_of::<CMsgHdr>() + size_of::<u32>() * buffer.as_slice().files.len(),
+            cmsg_level: abi::SOL_SOCKET,
+            cmsg_type: abi::SCM_RIGHTS,
+        },
+        files: unsafe { core::mem::zeroed() },
+    };
+    let slice = buffer.as_slice();
+    for (idx, file) in slice.files.iter().enumerate() {
+        cmsg.files[idx] = file.0;
+    }
+    let mut iov = IOVec {
+        base: slice.bytes.as_ptr() as *mut u8,
+        len: slice.bytes.len(),
+    };
+    let msghdr = MsgHdr {
+        msg_name: ptr::null_mut(),
+        msg_namelen: 0,
+        msg_iov: &mut iov as *mut IOVec,
+        msg_iovlen: 1,
+        msg_control: &mut cmsg as *mut CMsgBuffer as *mut usize,
+        msg_controllen: cmsg.hdr.cmsg_len,
+        msg_flags: 0,
+    };
+    let flags = 0;
+    let result = unsafe { syscall!(SENDMSG, fd.0, &msghdr as *const abi::MsgHdr, flags) as isize };
+    assert_eq!(result, buffer.as_slice().bytes.len() as isize);
+}
+
+/// Send text to the runtime, which logs it and includes it in any error, or
+/// return false if there's no socket yet
+///
+/// The socket is set aside while sending, so a panic in here falls back on
+/// stderr instead of trying again.
+pub fn write_diagnostic(msg: fmt::Arguments) -> bool {
+    let fd = DIAGNOSTIC_FD.swap(NO_DIAGNOSTIC_FD, Ordering::SeqCst);
+    if fd == NO_DIAGNOSTIC_FD {
+        return false;
+    }
+    let mut writer = DiagnosticWriter {
+        fd: SysFd(fd),
+        framed: DIAGNOSTIC_FRAMED.load(Ordering::SeqCst),
+        buf: [0; InlineBytes::CAPACITY],
+        len: 0,
+    };
+    let _ = fmt::write(&mut writer, msg);
+    writer.flush();
+    DIAGNOSTIC_FD.store(fd, Ordering::SeqCst);
+    true
+}
+
+struct DiagnosticWriter {
+    fd: SysFd,
+    framed: bool,
+    buf: [u8; InlineBytes::CAPACITY],
+    len: usize,
+}
+
+impl DiagnosticWriter {
+    fn flush(&mut self) {
+        if self.len > 0 {
+            let bytes = Box::new(InlineBytes::new(&self.buf[..self.len]).unwrap());
+            send_to(&self.fd, self.framed, &MessageFromSand::Diagnostic(bytes));
+            self.len = 0;
         }
-        let mut iov = IOVec {
-            base: slice.bytes.as_ptr() as *mut u8,
-            len: slice.bytes.len(),
-        };
-        let msghdr = MsgHdr {
-            msg_name: ptr::null_mut(),
-            msg_namelen: 0,
-            msg_iov: &mut iov as *mut IOVec,
-            msg_iovlen: 1,
-            msg_control: &mut cmsg as *mut CMsgBuffer as *mut usize,
-            msg_controllen: cmsg.hdr.cmsg_len,
-            msg_flags: 0,
-        };
-        let flags = 0;
-        let result = unsafe {
-            syscall!(
-                SENDMSG,
-                self.file.fd.0,
-                &msghdr as *const abi::MsgHdr,
-                flags
-            ) as isize
-        };
-        assert_eq!(result, buffer.as_slice().bytes.len() as isize);
+    }
+}
+
+impl fmt::Write for DiagnosticWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len == self.buf.len() {
+                self.flush();
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
     }
 }
@@ -26,8 +26,10 @@ mod process;
 
 mod abi;
 mod binformat;
+mod hardening;
 mod init;
 mod ipc;
+mod logring;
 mod mem;
 mod parser;
 mod ptrace;
@@ -37,6 +39,7 @@ mod syscall;
 mod tracer;
 
 pub use bandsocks_protocol as protocol;
+pub use logring::dump as dump_log_ring;
 pub use nolibc::{c_str_slice, c_strv_slice, c_unwrap_nul, exit, write_stderr, PageAllocator};
 pub use protocol::exit::*;
 
@@ -64,9 +67,16 @@ pub unsafe fn c_main(argv: &[*const u8], envp: &[*const u8]) -> usize {
 
         RunMode::Tracer(socket_file) => {
             stdio_for_tracer(&socket_file);
+            // Kept for one more look just before the loader starts, since
+            // nothing can be opened after seccomp
+            let self_fd = File::open_self_fd().expect("opening proc self fd");
+            let mut hardening = hardening::drop_capabilities();
             seccomp::policy_for_tracer_init();
+            hardening::check_no_new_privs(&mut hardening);
             Box::new(Tracer::new(
                 Socket::new(socket_file),
+                self_fd,
+                hardening,
                 process::task::task_fn,
             ))
             .run();
@@ -147,16 +157,9 @@ unsafe fn parse_envp_to_file(envp: &[*const u8]) -> Option<File> {
     }
 }
 
-fn close_all_except(allowed: &[&File]) {
-    let dir = File::open_self_fd().expect("opening proc self fd");
-    let mut fcount = 0;
-
-    // the directory fd is implicitly included in the allowed list; it's closed
-    // last.
-    let fcount_expected = 1 + allowed.len();
-    let is_allowed = |f: &File| f == &dir || allowed.contains(&f);
-
-    for result in nolibc::DirIterator::<typenum::U512, _, _>::new(&dir, |dirent| {
+/// Every file listed in an open /proc/self/fd directory
+fn open_files(dir: &File) -> impl Iterator<Item = File> + '_ {
+    nolibc::DirIterator::<typenum::U512, _, _>::new(dir, |dirent| {
         assert!(dirent.d_type == protocol::abi::DT_DIR || dirent.d_type == protocol::abi::DT_LNK);
         if dirent.d_type == protocol::abi::DT_LNK {
             Some(File::new(SysFd(
@@ -170,14 +173,24 @@ fn close_all_except(allowed: &[&File]) {
             assert!(dirent.d_name == b".." || dirent.d_name == b".");
             None
         }
-    }) {
-        let result: Option<File> = result.expect("reading proc fd");
-        if let Some(file) = result {
-            if is_allowed(&file) {
-                fcount += 1;
-            } else {
-                file.close().expect("closing fd leak");
-            }
+    })
+    .filter_map(|result| result.expect("reading proc fd"))
+}
+
+fn close_all_except(allowed: &[&File]) {
+    let dir = File::open_self_fd().expect("opening proc self fd");
+    let mut fcount = 0;
+
+    // the directory fd is implicitly included in the allowed list; it's closed
+    // last.
+    let fcount_expected = 1 + allowed.len();
+    let is_allowed = |f: &File| f == &dir || allowed.contains(&f);
+
+    for file in open_files(&dir) {
+        if is_allowed(&file) {
+            fcount += 1;
+        } else {
+            file.close().expect("closing fd leak");
         }
     }
 
@@ -185,6 +198,21 @@ fn close_all_except(allowed: &[&File]) {
     assert!(fcount == fcount_expected);
 }
 
+/// Mark everything besides `allowed` close-on-exec, using a /proc/self/fd
+/// directory that was opened before seccomp and is closed here
+///
+/// Unlike [close_all_except()] this leaves our own files working, for a
+/// process which is about to exec something else.
+fn cloexec_all_except(dir: File, allowed: &[&File]) {
+    for file in open_files(&dir) {
+        if file != dir && !allowed.contains(&&file) {
+            file.fcntl(abi::F_SETFD, abi::F_CLOEXEC)
+                .expect("setting close-on-exec");
+        }
+    }
+    dir.close().expect("proc self fd leak");
+}
+
 fn check_sealed_exe() -> Result<bool, Errno> {
     let exe = File::open_self_exe()?;
     let seals = exe.fcntl(abi::F_GET_SEALS, 0);
@@ -0,0 +1,98 @@
+//! The most recent log messages, kept inside the tracer
+//!
+//! Every message is recorded here regardless of the active log level, so a
+//! panic can still report what led up to it. The ring is written out after
+//! the panic message, and the runtime includes it in the error.
+
+use crate::{
+    nolibc::write_stderr,
+    protocol::{LogLevel, LogMessage, VPid},
+};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    fmt,
+    fmt::Write,
+    ptr, str,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+struct LogRing {
+    buf: Vec<u8>,
+    next: usize,
+    wrapped: bool,
+}
+
+/// The ring, if any, owned by whoever last stored it here
+///
+/// The tracer is single-threaded and signal handlers never log, so nothing
+/// else touches the ring while a reference to it is held.
+static RING: AtomicPtr<LogRing> = AtomicPtr::new(ptr::null_mut());
+
+/// Start keeping up to `size` bytes of recent messages, or stop if it's zero
+pub fn init(size: usize) {
+    let ring = if size == 0 {
+        ptr::null_mut()
+    } else {
+        Box::into_raw(Box::new(LogRing {
+            buf: vec![0; size],
+            next: 0,
+            wrapped: false,
+        }))
+    };
+    let old = RING.swap(ring, Ordering::SeqCst);
+    if !old.is_null() {
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+pub fn is_enabled() -> bool {
+    !RING.load(Ordering::Relaxed).is_null()
+}
+
+pub fn record(vpid: VPid, level: LogLevel, message: &LogMessage) {
+    if let Some(ring) = unsafe { RING.load(Ordering::Relaxed).as_mut() } {
+        let _ = writeln!(ring, "{:?} {:?} {:?}", level, vpid, message);
+    }
+}
+
+/// Write out the ring's contents, oldest first
+///
+/// This doesn't allocate, so it's safe to call while handling a panic or an
+/// allocation failure.
+pub fn dump() {
+    let ring = match unsafe { RING.load(Ordering::Relaxed).as_ref() } {
+        Some(ring) if ring.next > 0 || ring.wrapped => ring,
+        _ => return,
+    };
+    let (newer, older) = ring.buf.split_at(ring.next);
+    let older = if ring.wrapped {
+        // Skip the message that was partly overwritten
+        match older.iter().position(|b| *b == b'\n') {
+            Some(index) => &older[index + 1..],
+            None => &[],
+        }
+    } else {
+        &[]
+    };
+    write_stderr(format_args!("\nrecent sandbox log:\n"));
+    for part in &[older, newer] {
+        write_stderr(format_args!(
+            "{}",
+            str::from_utf8(part).unwrap_or("(not utf8)\n")
+        ));
+    }
+}
+
+impl fmt::Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.buf[self.next] = byte;
+            self.next += 1;
+            if self.next == self.buf.len() {
+                self.next = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
@@ -6,8 +6,8 @@
 #[cfg(not(test))]
 mod main_no_std {
     use bandsocks_sand::{
-        c_main, c_strv_slice, exit, print, println, write_stderr, PageAllocator, EXIT_OUT_OF_MEM,
-        EXIT_PANIC,
+        c_main, c_strv_slice, dump_log_ring, exit, print, println, write_stderr, PageAllocator,
+        EXIT_OUT_OF_MEM, EXIT_PANIC,
     };
     use core::panic::PanicInfo;
 
@@ -22,6 +22,7 @@ mod main_no_std {
     fn out_of_memory(layout: core::alloc::Layout) -> ! {
         container_panic();
         println!(" out of memory allocating {:?}", layout);
+        dump_log_ring();
         exit(EXIT_OUT_OF_MEM);
     }
 
@@ -35,6 +36,7 @@ mod main_no_std {
             print!(" at {}:{}", location.file(), location.line());
         }
         println!();
+        dump_log_ring();
         exit(EXIT_PANIC);
     }
 
@@ -1,8 +1,9 @@
 use crate::{
+    abi,
     mem::{
         maps::{MappedPages, MappedRange, MemFlags, MemProtect},
         page::VPage,
-        rw::find_syscall,
+        rw::{find_syscall, read_bytes},
     },
     nolibc::File,
     parser,
@@ -165,6 +166,10 @@ pub struct KernelMemArea {
 }
 
 impl KernelMemArea {
+    pub fn contains(&self, ptr: VPtr) -> bool {
+        self.pages.mem_range().contains(&ptr)
+    }
+
     pub fn is_overlap(&self, other: &Self) -> bool {
         self.name == other.name
             && self.flags == other.flags
@@ -194,7 +199,11 @@ pub enum KernelMemName {
 }
 
 impl KernelMemAreas {
-    pub fn locate(stopped_task: &mut StoppedTask) -> Self {
+    /// Find the kernel's areas in a task's memory map
+    ///
+    /// Returns None if they're missing, which only happens once the task has
+    /// died and its map is empty.
+    pub fn locate(stopped_task: &mut StoppedTask) -> Option<Self> {
         let mut vdso = None;
         let mut vvar = None;
         let mut vsyscall = None;
@@ -230,17 +239,17 @@ impl KernelMemAreas {
             }
         }
 
-        let vdso = vdso.unwrap();
-        let vvar = vvar.unwrap();
-        let vdso_syscall = find_syscall(stopped_task, vdso.pages.mem_range()).unwrap();
+        let vdso = vdso?;
+        let vvar = vvar?;
+        let vdso_syscall = find_syscall(stopped_task, vdso.pages.mem_range()).ok()?;
 
-        KernelMemAreas {
+        Some(KernelMemAreas {
             vdso,
             vvar,
             vsyscall,
             vdso_syscall,
             task_end,
-        }
+        })
     }
 
     pub fn is_userspace_area(&self, area: &KernelMemArea) -> bool {
@@ -262,6 +271,41 @@ impl KernelMemAreas {
     }
 }
 
+/// Check that a seccomp trap was really taken just after a syscall instruction
+///
+/// The emulator acts on a trapped task's registers with the tracer's authority,
+/// so before trusting them, confirm that the instruction pointer follows the
+/// bytes of a syscall instruction and that those bytes are in memory the task
+/// could actually have been executing.
+pub fn verify_syscall_entry(stopped_task: &mut StoppedTask) -> Result<(), ()> {
+    let insn_len = abi::SYSCALL_INSTRUCTION.len();
+    let insn = match stopped_task.regs.ip.checked_sub(insn_len) {
+        Some(addr) => VPtr(addr)..VPtr(stopped_task.regs.ip),
+        None => return Err(()),
+    };
+
+    let mut bytes = [0u8; 2];
+    read_bytes(stopped_task, insn.start, &mut bytes).map_err(|_| ())?;
+    if bytes != abi::SYSCALL_INSTRUCTION {
+        return Err(());
+    }
+
+    let last_byte = insn.end - 1;
+    let mut found_start = false;
+    let mut found_end = false;
+    for area in KernelMemIterator::new(stopped_task) {
+        if area.flags.protect.execute {
+            found_start |= area.contains(insn.start);
+            found_end |= area.contains(last_byte);
+        }
+    }
+    if found_start && found_end {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
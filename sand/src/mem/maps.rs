@@ -179,6 +179,17 @@ impl fmt::Debug for MemFlags {
 }
 
 impl MemFlags {
+    pub fn none() -> MemFlags {
+        MemFlags {
+            protect: MemProtect {
+                read: false,
+                write: false,
+                execute: false,
+            },
+            mayshare: false,
+        }
+    }
+
     pub fn ro() -> MemFlags {
         MemFlags {
             protect: MemProtect {
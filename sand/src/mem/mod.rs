@@ -3,3 +3,4 @@ pub mod maps;
 pub mod page;
 pub mod rw;
 pub mod string;
+pub mod vdso;
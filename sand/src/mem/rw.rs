@@ -20,7 +20,7 @@ pub fn read_bytes(
     let mem_file = File::new(stopped_task.task.process_handle.mem);
     mem_file
         .pread_exact(bytes, ptr.0)
-        .map_err(|_| Errno(-abi::EFAULT))
+        .map_err(|_| Errno::new(abi::EFAULT))
 }
 
 /// safety: type must be repr(C) and have no invalid bit patterns
@@ -47,10 +47,22 @@ pub fn read_pointer(stopped_task: &mut StoppedTask, remote: VPtr) -> Result<VPtr
 pub fn write_word(stopped_task: &mut StoppedTask, ptr: VPtr, word: usize) -> Result<(), Errno> {
     assert!(0 == (ptr.0 % size_of::<usize>()));
     let result = ptrace::poke(stopped_task.task.task_data.sys_pid, ptr.0, word)
-        .map_err(|()| Errno(-abi::EFAULT));
+        .map_err(|_| Errno::new(abi::EFAULT));
     result
 }
 
+/// Write a 32-bit value without touching the rest of the word it's in
+pub fn write_u32(stopped_task: &mut StoppedTask, ptr: VPtr, value: u32) -> Result<(), Errno> {
+    let offset = ptr.0 % size_of::<usize>();
+    if offset > size_of::<usize>() - size_of::<u32>() {
+        return Err(Errno::new(abi::EFAULT));
+    }
+    let word_ptr = VPtr(ptr.0 - offset);
+    let mut bytes = read_word(stopped_task, word_ptr)?.to_ne_bytes();
+    bytes[offset..offset + size_of::<u32>()].copy_from_slice(&value.to_ne_bytes());
+    write_word(stopped_task, word_ptr, usize::from_ne_bytes(bytes))
+}
+
 pub fn write_padded_bytes(
     stopped_task: &mut StoppedTask,
     mut ptr: VPtr,
@@ -116,7 +128,7 @@ pub fn find_bytes(
 pub fn find_syscall(stopped_task: &mut StoppedTask, area: Range<VPtr>) -> Result<VPtr, Errno> {
     match find_bytes(stopped_task, area, &abi::SYSCALL_INSTRUCTION)? {
         Some(ptr) => Ok(ptr),
-        None => Err(Errno(-abi::ENOSYS)),
+        None => Err(Errno::new(abi::ENOSYS)),
     }
 }
 
@@ -59,7 +59,7 @@ impl VStringRange {
         for _ in 0..alignment {
             match buf.next() {
                 Some(Ok(_byte)) => (),
-                _ => return Err(Errno(-abi::EFAULT)),
+                _ => return Err(Errno::new(abi::EFAULT)),
             }
         }
         let mut len = 0;
@@ -69,6 +69,6 @@ impl VStringRange {
                 return Ok(VStringRange(ptr..(ptr + len)));
             }
         }
-        Err(Errno(-abi::EFAULT))
+        Err(Errno::new(abi::EFAULT))
     }
 }
@@ -0,0 +1,140 @@
+use crate::{
+    abi,
+    mem::{
+        kernel::KernelMemAreas,
+        rw::{read_bytes, read_value, read_word, write_word},
+    },
+    process::task::StoppedTask,
+    protocol::{Errno, VPtr},
+};
+use core::{mem::size_of, ops::Range};
+use goblin::elf64::{
+    header::Header,
+    program_header::{ProgramHeader, PT_LOAD},
+    section_header::{SectionHeader, SHT_DYNSYM},
+    sym::Sym,
+};
+use sc::nr;
+
+/// vDSO functions that read the clock, and the syscall each one stands in for
+///
+/// Both the `__vdso_` names and their unprefixed aliases are listed, but an
+/// alias sits at the same address, so patching it again changes nothing.
+const TIME_FUNCTIONS: &[(&[u8], usize)] = &[
+    (b"__vdso_clock_gettime\0", nr::CLOCK_GETTIME),
+    (b"clock_gettime\0", nr::CLOCK_GETTIME),
+    (b"__vdso_gettimeofday\0", nr::GETTIMEOFDAY),
+    (b"gettimeofday\0", nr::GETTIMEOFDAY),
+    (b"__vdso_time\0", nr::TIME),
+    (b"time\0", nr::TIME),
+];
+
+/// Longest symbol name we need to compare, including its terminator
+const NAME_LIMIT: usize = 24;
+
+/// Replace the vDSO's clock functions with stubs that make the real syscall
+///
+/// The vDSO reads the clock from shared memory without entering the kernel,
+/// where seccomp would never see it. Each patched function becomes
+/// `mov eax, nr; syscall; ret`, which has the same calling convention. The
+/// patched pages are private copies that fork keeps and our emulated exec
+/// never replaces, so this is only needed once, in the first process. The
+/// vDSO still holds syscall instructions for the trampoline to use.
+pub fn patch_time_functions(stopped_task: &mut StoppedTask) -> Result<(), Errno> {
+    let kernel_mem = KernelMemAreas::locate(stopped_task).ok_or(Errno::new(abi::ESRCH))?;
+    let image = kernel_mem.vdso.pages.mem_range();
+    let ehdr: Header = unsafe { read_value(stopped_task, image.start) }?;
+    let load_bias = load_bias(stopped_task, &image, &ehdr)?;
+
+    for idx in 0..ehdr.e_shnum as usize {
+        let shdr_ptr = image.start + ehdr.e_shoff as usize + idx * size_of::<SectionHeader>();
+        let shdr: SectionHeader = unsafe { read_value(stopped_task, shdr_ptr) }?;
+        if shdr.sh_type != SHT_DYNSYM || shdr.sh_entsize as usize != size_of::<Sym>() {
+            continue;
+        }
+        let strtab_ptr = image.start
+            + ehdr.e_shoff as usize
+            + shdr.sh_link as usize * size_of::<SectionHeader>();
+        let strtab: SectionHeader = unsafe { read_value(stopped_task, strtab_ptr) }?;
+
+        for sym_idx in 0..(shdr.sh_size / shdr.sh_entsize) as usize {
+            let sym_ptr = image.start + shdr.sh_offset as usize + sym_idx * size_of::<Sym>();
+            let sym: Sym = unsafe { read_value(stopped_task, sym_ptr) }?;
+            if sym.st_value == 0 || sym.st_name as u64 >= strtab.sh_size {
+                continue;
+            }
+            let mut name = [0u8; NAME_LIMIT];
+            let name_ptr = image.start + strtab.sh_offset as usize + sym.st_name as usize;
+            let name_len = NAME_LIMIT.min(image.end.0 - name_ptr.0);
+            read_bytes(stopped_task, name_ptr, &mut name[..name_len])?;
+
+            for (function, syscall_nr) in TIME_FUNCTIONS {
+                if name.starts_with(function) {
+                    let stub = syscall_stub(*syscall_nr);
+                    let addr = VPtr((sym.st_value as usize).wrapping_add(load_bias));
+                    if sym.st_size < stub.len() as u64
+                        || addr < image.start
+                        || addr + stub.len() > image.end
+                    {
+                        return Err(Errno::new(abi::ENOEXEC));
+                    }
+                    write_unaligned_bytes(stopped_task, addr, &stub)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Difference between the vDSO's link-time addresses and where it's mapped
+fn load_bias(
+    stopped_task: &mut StoppedTask,
+    image: &Range<VPtr>,
+    ehdr: &Header,
+) -> Result<usize, Errno> {
+    for idx in 0..ehdr.e_phnum as usize {
+        let phdr_ptr = image.start + ehdr.e_phoff as usize + idx * size_of::<ProgramHeader>();
+        let phdr: ProgramHeader = unsafe { read_value(stopped_task, phdr_ptr) }?;
+        if phdr.p_type == PT_LOAD {
+            let link_start = (phdr.p_vaddr - phdr.p_offset) as usize;
+            return Ok(image.start.0.wrapping_sub(link_start));
+        }
+    }
+    Err(Errno::new(abi::ENOEXEC))
+}
+
+fn syscall_stub(syscall_nr: usize) -> [u8; 8] {
+    let nr_bytes = (syscall_nr as u32).to_le_bytes();
+    [
+        0xb8,
+        nr_bytes[0],
+        nr_bytes[1],
+        nr_bytes[2],
+        nr_bytes[3],
+        abi::SYSCALL_INSTRUCTION[0],
+        abi::SYSCALL_INSTRUCTION[1],
+        0xc3,
+    ]
+}
+
+/// Overwrite bytes at any alignment, one whole word at a time
+fn write_unaligned_bytes(
+    stopped_task: &mut StoppedTask,
+    ptr: VPtr,
+    bytes: &[u8],
+) -> Result<(), Errno> {
+    let word_size = size_of::<usize>();
+    let mut word_ptr = VPtr(ptr.0 & !(word_size - 1));
+    while word_ptr < ptr + bytes.len() {
+        let mut word = read_word(stopped_task, word_ptr)?.to_ne_bytes();
+        for (i, byte) in word.iter_mut().enumerate() {
+            let addr = word_ptr + i;
+            if addr >= ptr && addr < ptr + bytes.len() {
+                *byte = bytes[addr.0 - ptr.0];
+            }
+        }
+        write_word(stopped_task, word_ptr, usize::from_ne_bytes(word))?;
+        word_ptr = word_ptr + word_size;
+    }
+    Ok(())
+}
@@ -1,12 +1,12 @@
 use crate::{
     abi,
-    protocol::{abi::DirentHeader, Errno, SysFd},
+    protocol::{abi::DirentHeader, Errno, FileContents, SysFd, SysPid, MEMFD_TEMP_NAME},
 };
 use core::{
     alloc::{GlobalAlloc, Layout},
     fmt, mem,
     mem::size_of,
-    slice, str,
+    ptr, slice, str,
 };
 use heapless::{ArrayLength, Vec};
 use plain::Plain;
@@ -41,7 +41,12 @@ macro_rules! dbg {
     });
 }
 
+/// Diagnostics go to the runtime over IPC once the tracer has its socket, and
+/// to the real stderr before then, or in the loader
 pub fn write_stderr(msg: fmt::Arguments) {
+    if crate::ipc::write_diagnostic(msg) {
+        return;
+    }
     if fmt::write(&mut File::stderr(), msg).is_err() {
         exit(crate::EXIT_IO_ERROR);
     }
@@ -59,6 +64,35 @@ impl Drop for TempFile {
     }
 }
 
+impl TempFile {
+    /// Take ownership of a file received from the runtime, copying inline
+    /// contents into a new memfd
+    pub fn from_contents(contents: FileContents) -> Result<TempFile, Errno> {
+        match contents {
+            FileContents::Fd(sys_fd) => Ok(TempFile(File::new(sys_fd))),
+            FileContents::Inline(bytes) => {
+                let file = TempFile(File::memfd_create(
+                    MEMFD_TEMP_NAME,
+                    abi::MFD_CLOEXEC | abi::MFD_ALLOW_SEALING,
+                )?);
+                file.0.pwrite_exact(bytes.as_slice(), 0)?;
+                // The guest opened a read-only file, and gets one
+                file.0.fcntl(
+                    abi::F_ADD_SEALS,
+                    abi::F_SEAL_SEAL | abi::F_SEAL_SHRINK | abi::F_SEAL_GROW | abi::F_SEAL_WRITE,
+                )?;
+                Ok(file)
+            }
+        }
+    }
+}
+
+#[repr(C)]
+struct FileCMsg {
+    hdr: abi::CMsgHdr,
+    fd: u32,
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct File {
     pub fd: SysFd,
@@ -89,6 +123,14 @@ impl File {
         File { fd }
     }
 
+    pub fn memfd_create(name: &[u8], flags: usize) -> Result<File, Errno> {
+        assert_eq!(name.last(), Some(&0));
+        match unsafe { syscall!(MEMFD_CREATE, name.as_ptr(), flags) } as isize {
+            result if result >= 0 => Ok(File::new(SysFd(result as u32))),
+            err => Err(Errno(err as i32)),
+        }
+    }
+
     pub fn open_self_fd() -> Result<File, Errno> {
         let flags = abi::O_RDONLY | abi::O_CLOEXEC | abi::O_DIRECTORY;
         unsafe { File::open(&PROC_SELF_FD, flags, 0) }
@@ -164,6 +206,77 @@ impl File {
         }
     }
 
+    pub fn ioctl(&self, request: usize, arg: usize) -> Result<isize, Errno> {
+        match unsafe { syscall!(IOCTL, self.fd.0, request, arg) } as isize {
+            result if result >= 0 => Ok(result),
+            other => Err(Errno(other as i32)),
+        }
+    }
+
+    /// Send a copy of another file over this socket, along with one byte
+    pub fn send_file(&self, file: &File) -> Result<(), Errno> {
+        let mut cmsg = FileCMsg {
+            hdr: abi::CMsgHdr {
+                cmsg_len: size_of::<abi::CMsgHdr>() + size_of::<u32>(),
+                cmsg_level: abi::SOL_SOCKET,
+                cmsg_type: abi::SCM_RIGHTS,
+            },
+            fd: file.fd.0,
+        };
+        let mut byte = 0u8;
+        let mut iov = abi::IOVec {
+            base: &mut byte as *mut u8,
+            len: 1,
+        };
+        let msghdr = abi::MsgHdr {
+            msg_name: ptr::null_mut(),
+            msg_namelen: 0,
+            msg_iov: &mut iov as *mut abi::IOVec,
+            msg_iovlen: 1,
+            msg_control: &mut cmsg as *mut FileCMsg as *mut usize,
+            msg_controllen: size_of::<FileCMsg>(),
+            msg_flags: 0,
+        };
+        match unsafe { syscall!(SENDMSG, self.fd.0, &msghdr as *const abi::MsgHdr, 0) } as isize {
+            1 => Ok(()),
+            err if err < 0 => Err(Errno(err as i32)),
+            _ => Err(Errno::new(abi::EIO)),
+        }
+    }
+
+    /// Wait for a file sent with [File::send_file()], and receive it with
+    /// close-on-exec set
+    pub fn recv_file(&self) -> Result<File, Errno> {
+        let mut cmsg: FileCMsg = unsafe { mem::zeroed() };
+        let mut byte = 0u8;
+        let mut iov = abi::IOVec {
+            base: &mut byte as *mut u8,
+            len: 1,
+        };
+        let mut msghdr = abi::MsgHdr {
+            msg_name: ptr::null_mut(),
+            msg_namelen: 0,
+            msg_iov: &mut iov as *mut abi::IOVec,
+            msg_iovlen: 1,
+            msg_control: &mut cmsg as *mut FileCMsg as *mut usize,
+            msg_controllen: size_of::<FileCMsg>(),
+            msg_flags: 0,
+        };
+        let flags = abi::MSG_CMSG_CLOEXEC;
+        match unsafe { syscall!(RECVMSG, self.fd.0, &mut msghdr as *mut abi::MsgHdr, flags) }
+            as isize
+        {
+            1 if cmsg.hdr.cmsg_len == size_of::<abi::CMsgHdr>() + size_of::<u32>()
+                && cmsg.hdr.cmsg_level == abi::SOL_SOCKET
+                && cmsg.hdr.cmsg_type == abi::SCM_RIGHTS =>
+            {
+                Ok(File::new(SysFd(cmsg.fd)))
+            }
+            err if err < 0 => Err(Errno(err as i32)),
+            _ => Err(Errno::new(abi::EIO)),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn lseek(&self, pos: usize, whence: isize) -> Result<usize, Errno> {
         let result = unsafe { syscall!(LSEEK, self.fd.0, pos, whence) as isize };
@@ -198,7 +311,25 @@ impl File {
     pub fn pread_exact(&self, bytes: &mut [u8], offset: usize) -> Result<(), Errno> {
         match self.pread(bytes, offset) {
             Ok(len) if len == bytes.len() => Ok(()),
-            Ok(_) => Err(Errno(-abi::EIO)),
+            Ok(_) => Err(Errno::new(abi::EIO)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn pwrite(&self, bytes: &[u8], offset: usize) -> Result<usize, Errno> {
+        let result =
+            unsafe { syscall!(PWRITE64, self.fd.0, bytes.as_ptr(), bytes.len(), offset) as isize };
+        if result >= 0 {
+            Ok(result as usize)
+        } else {
+            Err(Errno(result as i32))
+        }
+    }
+
+    pub fn pwrite_exact(&self, bytes: &[u8], offset: usize) -> Result<(), Errno> {
+        match self.pwrite(bytes, offset) {
+            Ok(len) if len == bytes.len() => Ok(()),
+            Ok(_) => Err(Errno::new(abi::EIO)),
             Err(e) => Err(e),
         }
     }
@@ -208,6 +339,58 @@ pub fn getpid() -> usize {
     unsafe { syscall!(GETPID) }
 }
 
+/// Send a signal to one thread, by its tid alone
+///
+/// Tasks can be threads of a larger process, and the tracer doesn't know its
+/// host tgid, so tgkill with the tid in both places would only reach a
+/// process's first thread.
+pub fn tkill(pid: SysPid, signum: u8) -> Result<(), Errno> {
+    match unsafe { syscall!(TKILL, pid.0, signum) } as isize {
+        0 => Ok(()),
+        other => Err(Errno(other as i32)),
+    }
+}
+
+/// Block signals everywhere except inside [poll_with_signals()]
+pub fn block_signals(signals: &[u8]) -> Result<(), Errno> {
+    let mut set = abi::SigSet { sig: [0] };
+    for signum in signals {
+        set.sig[0] |= 1 << (signum - 1);
+    }
+    match unsafe {
+        syscall!(
+            RT_SIGPROCMASK,
+            abi::SIG_BLOCK,
+            &set as *const abi::SigSet,
+            0,
+            size_of::<abi::SigSet>()
+        )
+    } as isize
+    {
+        0 => Ok(()),
+        other => Err(Errno(other as i32)),
+    }
+}
+
+/// Wait with no timeout until a file is ready or any signal arrives
+pub fn poll_with_signals(fds: &mut [abi::PollFd]) -> Result<usize, Errno> {
+    let unblocked = abi::SigSet { sig: [0] };
+    match unsafe {
+        syscall!(
+            PPOLL,
+            fds.as_mut_ptr(),
+            fds.len(),
+            0,
+            &unblocked as *const abi::SigSet,
+            size_of::<abi::SigSet>()
+        )
+    } as isize
+    {
+        result if result >= 0 => Ok(result as usize),
+        other => Err(Errno(other as i32)),
+    }
+}
+
 pub fn exit(code: usize) -> ! {
     unsafe { syscall!(EXIT, code) };
     unreachable!()
@@ -271,6 +454,50 @@ pub fn signal(signum: u8, handler: extern "C" fn(u32)) -> Result<(), Errno> {
     }
 }
 
+pub fn prctl(option: usize, arg2: usize, arg3: usize) -> Result<usize, Errno> {
+    let result = unsafe { syscall!(PRCTL, option, arg2, arg3, 0, 0) as isize };
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(Errno(result as i32))
+    }
+}
+
+pub fn personality(persona: usize) -> Result<usize, Errno> {
+    let result = unsafe { syscall!(PERSONALITY, persona) as isize };
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(Errno(result as i32))
+    }
+}
+
+/// Get this process's soft and hard limits on a resource
+pub fn get_rlimit(resource: usize) -> Result<abi::RLimit, Errno> {
+    let mut limit = abi::RLimit::default();
+    let result =
+        unsafe { syscall!(PRLIMIT64, 0, resource, 0, (&mut limit) as *mut abi::RLimit) } as isize;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(limit)
+    }
+}
+
+/// Set the soft limit on a resource for this process, no higher than the hard
+/// limit, returning the soft limit that took effect
+pub fn set_soft_rlimit(resource: usize, soft_limit: u64) -> Result<u64, Errno> {
+    let mut limit = get_rlimit(resource)?;
+    limit.rlim_cur = soft_limit.min(limit.rlim_max);
+    let result =
+        unsafe { syscall!(PRLIMIT64, 0, resource, (&limit) as *const abi::RLimit, 0) } as isize;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(limit.rlim_cur)
+    }
+}
+
 pub fn getrandom(bytes: &mut [u8], flags: isize) -> Result<usize, Errno> {
     let result = unsafe { syscall!(GETRANDOM, bytes.as_mut_ptr(), bytes.len(), flags) as isize };
     if result >= 0 {
@@ -1,7 +1,16 @@
 macro_rules! ipc_call {
     ( $task:expr, $op:expr, $reply:pat, $result:expr ) => {{
+        let ipc_begin = $task.latency.ipc_begin();
         $task.msg.send($op);
-        match $task.events.next().await {
+        // The reply comes even if the task dies while waiting for it
+        let event = loop {
+            match $task.events.next().await {
+                event if event.is_exit() => $task.exit = Some(event),
+                event => break event,
+            }
+        };
+        $task.latency.ipc_end(ipc_begin);
+        match event {
             crate::process::Event::Message($reply) => $result,
             other => panic!(
                 "unexpected ipc_call reply, task={:x?} op={:x?}, received: {:x?}",
@@ -16,8 +25,9 @@ pub mod table;
 pub mod task;
 
 use crate::{
-    process::task::TaskData,
-    protocol::{FromTask, SysPid, ToTask},
+    abi,
+    process::task::{ChildTask, TaskData},
+    protocol::{Errno, FromTask, SysFd, SysPid, ToTask, VPid},
 };
 use core::{
     future::Future,
@@ -40,6 +50,8 @@ pub struct Process<'t, F: Future<Output = ()>> {
     event_queue: EventQueue,
     #[pin]
     outbox_queue: OutboxQueue,
+    #[pin]
+    spawn_queue: SpawnQueue,
 }
 
 #[pin_project(project = TaskStateProj)]
@@ -52,7 +64,31 @@ enum TaskState<'t, F: Future<Output = ()>> {
 #[derive(Debug, Eq, PartialEq)]
 pub enum Event {
     Message(ToTask),
-    Signal { sig: u32, code: u32, status: u32 },
+    Signal {
+        sig: u32,
+        code: u32,
+        status: u32,
+    },
+    /// The child from this task's spawn request is in the process table, or
+    /// was killed because the table is full
+    Forked(Result<VPid, Errno>),
+    /// A seccomp user notification, received from this listener
+    Notify(SysFd, abi::SeccompNotif),
+}
+
+impl Event {
+    /// Is this waitid() reporting that the task is gone?
+    pub fn is_exit(&self) -> bool {
+        match self {
+            Event::Signal { sig, code, .. } => {
+                *sig == abi::SIGCHLD as u32
+                    && (*code == abi::CLD_EXITED
+                        || *code == abi::CLD_KILLED
+                        || *code == abi::CLD_DUMPED)
+            }
+            _ => false,
+        }
+    }
 }
 
 type EventQueueSize = U2;
@@ -60,11 +96,18 @@ type EventQueue = Queue<Event, EventQueueSize>;
 type EventConsumer<'q> = Consumer<'q, Event, EventQueueSize>;
 
 type OutboxQueueSize = U4;
-type OutboxQueue = Queue<FromTask, OutboxQueueSize>;
-type OutboxProducer<'q> = Producer<'q, FromTask, OutboxQueueSize>;
+type OutboxQueue = Queue<(FromTask, Option<isize>), OutboxQueueSize>;
+type OutboxProducer<'q> = Producer<'q, (FromTask, Option<isize>), OutboxQueueSize>;
+
+type SpawnQueueSize = U1;
+type SpawnQueue = Queue<ChildTask, SpawnQueueSize>;
+type SpawnProducer<'q> = Producer<'q, ChildTask, SpawnQueueSize>;
 
 pub struct MessageSender<'q> {
     producer: OutboxProducer<'q>,
+    spawner: SpawnProducer<'q>,
+    /// Syscall being emulated, which messages are sent on behalf of
+    nr: Option<isize>,
 }
 
 pub struct EventSource<'q> {
@@ -93,7 +136,20 @@ impl<'q, 's> EventSource<'q> {
 
 impl<'q> MessageSender<'q> {
     pub fn send(&mut self, message: FromTask) {
-        self.producer.enqueue(message).expect("message outbox full");
+        self.producer
+            .enqueue((message, self.nr))
+            .expect("message outbox full");
+    }
+
+    /// Tag messages sent from here on with the syscall they're for, if any
+    pub fn set_syscall(&mut self, nr: Option<isize>) {
+        self.nr = nr;
+    }
+
+    /// Ask the tracer to start tracking a new child process, which it
+    /// acknowledges with an [Event::Forked]
+    pub fn spawn(&mut self, child: ChildTask) {
+        self.spawner.enqueue(child).expect("spawn queue full");
     }
 }
 
@@ -104,6 +160,7 @@ impl<'p, 't: 'p, F: Future<Output = ()>> Process<'t, F> {
             state: TaskState::Initial(task_fn, task_data),
             event_queue: EventQueue::new(),
             outbox_queue: OutboxQueue::new(),
+            spawn_queue: SpawnQueue::new(),
         }
     }
 
@@ -112,11 +169,16 @@ impl<'p, 't: 'p, F: Future<Output = ()>> Process<'t, F> {
         producer.enqueue(event)
     }
 
-    pub fn check_outbox(self: Pin<&mut Self>) -> Option<FromTask> {
+    pub fn check_outbox(self: Pin<&mut Self>) -> Option<(FromTask, Option<isize>)> {
         let mut consumer = unsafe { self.project().outbox_queue.get_unchecked_mut().split().1 };
         consumer.dequeue()
     }
 
+    pub fn check_spawn(self: Pin<&mut Self>) -> Option<ChildTask> {
+        let mut consumer = unsafe { self.project().spawn_queue.get_unchecked_mut().split().1 };
+        consumer.dequeue()
+    }
+
     fn event_source(self: Pin<&'p mut Self>) -> EventSource<'t> {
         let queue = unsafe { self.project().event_queue.get_unchecked_mut() } as *mut EventQueue;
         let queue = unsafe { &mut *queue };
@@ -124,11 +186,19 @@ impl<'p, 't: 'p, F: Future<Output = ()>> Process<'t, F> {
         EventSource { consumer }
     }
 
-    fn message_sender(self: Pin<&'p mut Self>) -> MessageSender<'t> {
-        let queue = unsafe { self.project().outbox_queue.get_unchecked_mut() } as *mut OutboxQueue;
+    fn message_sender(mut self: Pin<&'p mut Self>) -> MessageSender<'t> {
+        let queue =
+            unsafe { self.as_mut().project().outbox_queue.get_unchecked_mut() } as *mut OutboxQueue;
         let queue = unsafe { &mut *queue };
         let producer = queue.split().0;
-        MessageSender { producer }
+        let queue = unsafe { self.project().spawn_queue.get_unchecked_mut() } as *mut SpawnQueue;
+        let queue = unsafe { &mut *queue };
+        let spawner = queue.split().0;
+        MessageSender {
+            producer,
+            spawner,
+            nr: None,
+        }
     }
 
     pub fn poll(mut self: Pin<&'p mut Self>) -> Poll<()> {
@@ -17,12 +17,14 @@ use core::{mem::size_of, ops::Range};
 
 const BUILDER_SIZE_LIMIT: usize = 256 * 1024 * 1024;
 const INITIAL_STACK_FREE: usize = 64 * 1024;
+const STACK_RESERVE_LIMIT: usize = 1024 * 1024 * 1024;
 
 #[derive(Debug)]
 pub struct StackBuilder {
     memfd: TempRemoteFd,
     top: VPage,
     bottom: VPtr,
+    limit: usize,
     num_stored_vectors: usize,
 }
 
@@ -38,27 +40,44 @@ impl InitialStack {
         memfd: &TempRemoteFd,
         top: VPage,
         bottom: VPtr,
+        limit: usize,
     ) -> Result<Self, Errno> {
+        // The whole stack is reserved up front, as much as the limit allows
+        // but always with some room past the initial contents. Pages aren't
+        // committed until they're touched, and the guard page below turns an
+        // overflow into SIGSEGV before it can reach any other mapping.
+        let initial_size = top.ptr().0 - bottom.0;
+        let reserved = limit
+            .min(STACK_RESERVE_LIMIT)
+            .max(initial_size + INITIAL_STACK_FREE);
         let stack = InitialStack {
             sp: bottom,
-            pages: VPage::round_down(VPtr(bottom.0 - INITIAL_STACK_FREE))..top,
+            pages: VPage::round_down(VPtr(top.ptr().0 - reserved))..top,
         };
+        let guard = (stack.pages.start - 1)..stack.pages.start;
 
+        trampoline
+            .mmap_fixed(
+                &MappedPages::anonymous(guard),
+                &RemoteFd::invalid(),
+                &MemFlags::none(),
+                abi::MAP_ANONYMOUS | abi::MAP_FIXED_NOREPLACE | abi::MAP_NORESERVE,
+            )
+            .await?;
         trampoline
             .mmap_fixed(
                 &MappedPages::anonymous(stack.pages.clone()),
                 &RemoteFd::invalid(),
                 &MemFlags::rw(),
-                abi::MAP_ANONYMOUS | abi::MAP_FIXED_NOREPLACE | abi::MAP_GROWSDOWN,
+                abi::MAP_ANONYMOUS | abi::MAP_FIXED_NOREPLACE | abi::MAP_NORESERVE,
             )
             .await?;
 
-        let stack_size = stack.pages.end.ptr().0 - stack.sp.0;
-        let file_offset = BUILDER_SIZE_LIMIT - stack_size;
+        let file_offset = BUILDER_SIZE_LIMIT - initial_size;
 
         memfd
             .0
-            .pread_vptr(trampoline, stack.sp, stack_size, file_offset)
+            .pread_vptr(trampoline, stack.sp, initial_size, file_offset)
             .await?;
 
         Ok(stack)
@@ -75,11 +94,24 @@ impl StackBuilder {
         // sections: growing downward from BUILDER_SIZE_LIMIT is the stack
         // itself, and growing up from there is a temporary location to store
         // vectors that will go to the bottom of the stack later.
-        let top = randomize_stack_top(scratchpad.trampoline.kernel_mem.task_end);
+        let task_end = scratchpad.trampoline.kernel_mem()?.task_end;
+        let settings = &scratchpad
+            .trampoline
+            .stopped_task
+            .task
+            .task_data
+            .tracer_settings;
+        let top = if settings.randomize_layout {
+            randomize_stack_top(task_end)
+        } else {
+            task_end
+        };
+        let limit = settings.stack_limit;
         Ok(StackBuilder {
             memfd: TempRemoteFd::new(scratchpad).await?,
             top,
             bottom: top.ptr(),
+            limit,
             num_stored_vectors: 0,
         })
     }
@@ -89,11 +121,17 @@ impl StackBuilder {
         trampoline: &mut Trampoline<'_, '_, '_>,
     ) -> Result<InitialStack, Errno> {
         assert_eq!(self.num_stored_vectors, 0);
-        let result = InitialStack::load(trampoline, &self.memfd, self.top, self.bottom).await;
+        let result =
+            InitialStack::load(trampoline, &self.memfd, self.top, self.bottom, self.limit).await;
         self.memfd.free(trampoline).await?;
         result
     }
 
+    /// Give up on this stack without loading it
+    pub async fn free(self, trampoline: &mut Trampoline<'_, '_, '_>) -> Result<(), Errno> {
+        self.memfd.free(trampoline).await
+    }
+
     pub fn align(&mut self, alignment: usize) -> VPtr {
         let mask = alignment - 1;
         assert_eq!(alignment & mask, 0);
@@ -106,7 +144,7 @@ impl StackBuilder {
         let ptr = VPtr(self.bottom.0 - length);
         let stack_size = self.top.ptr().0 - ptr.0;
         if stack_size > BUILDER_SIZE_LIMIT {
-            return Err(Errno(-abi::E2BIG));
+            return Err(Errno::new(abi::E2BIG));
         }
         self.bottom = ptr;
         Ok(ptr)
@@ -169,7 +207,7 @@ impl StackBuilder {
         let ptr = VPtr(self.bottom.0 - length);
         let stack_size = self.top.ptr().0 - ptr.0;
         if stack_size > BUILDER_SIZE_LIMIT {
-            return Err(Errno(-abi::E2BIG));
+            return Err(Errno::new(abi::E2BIG));
         }
         let file_offset = BUILDER_SIZE_LIMIT - stack_size;
         fd_copy_exact(
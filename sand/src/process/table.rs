@@ -1,10 +1,10 @@
 use crate::{
     abi,
     process::{
-        task::{TaskData, TaskMemManagement, TaskSocketPair},
+        task::{ChildTask, TaskData},
         Process, TaskFn,
     },
-    protocol::{Errno, SysPid, TracerSettings, VFile, VPid},
+    protocol::{Errno, SysPid, VFile, VPid},
     remote::file::RemoteFd,
 };
 use alloc::{boxed::Box, rc::Rc, vec::Vec};
@@ -49,6 +49,10 @@ impl<'t, F: Future<Output = ()>> ProcessTable<'t, F> {
         self.map_sys_to_v.get(&sys_pid).copied()
     }
 
+    pub fn sys_pids(&self) -> impl Iterator<Item = SysPid> + '_ {
+        self.map_sys_to_v.keys().copied()
+    }
+
     fn allocate_vpid(&mut self) -> Option<VPid> {
         let mut result = None;
         for _ in 0..PID_LIMIT {
@@ -64,39 +68,32 @@ impl<'t, F: Future<Output = ()>> ProcessTable<'t, F> {
         result
     }
 
-    pub fn insert(
-        &mut self,
-        tracer_settings: TracerSettings,
-        sys_pid: SysPid,
-        parent: Option<VPid>,
-        socket_pair: TaskSocketPair,
-        mm: TaskMemManagement,
-        file_table: FileTable,
-    ) -> Option<VPid> {
-        let vpid = self.allocate_vpid();
-        vpid.map(move |vpid| {
-            let task_data = TaskData {
-                file_table,
-                tracer_settings,
-                sys_pid,
-                vpid,
-                parent,
-                socket_pair,
-                mm,
-            };
-            let index = table_index_for_vpid(vpid).unwrap();
-            let min_table_len = index + 1;
-            while self.table.len() < min_table_len {
-                self.table.push(None);
-            }
+    /// Give a new task its VPid, or drop it if there are none left
+    pub fn insert(&mut self, task: ChildTask) -> Option<VPid> {
+        let vpid = self.allocate_vpid()?;
+        let task_data = TaskData {
+            file_table: task.file_table,
+            tracer_settings: task.tracer_settings,
+            sys_pid: task.sys_pid,
+            vpid,
+            parent: task.parent,
+            leader: task.leader,
+            socket_pair: task.socket_pair,
+            mm: task.mm,
+            children: Vec::new(),
+            child_tid: task.child_tid,
+        };
+        let index = table_index_for_vpid(vpid).unwrap();
+        let min_table_len = index + 1;
+        while self.table.len() < min_table_len {
+            self.table.push(None);
+        }
 
-            let process = Box::pin(Process::new(self.task_fn, task_data));
-            assert!(self.table[index].is_none());
-            self.table[index] = Some(process);
-            assert_eq!(self.map_sys_to_v.insert(sys_pid, vpid), None);
-            Some(vpid)
-        })
-        .flatten()
+        let process = Box::pin(Process::new(self.task_fn, task_data));
+        assert!(self.table[index].is_none());
+        self.table[index] = Some(process);
+        assert_eq!(self.map_sys_to_v.insert(task.sys_pid, vpid), None);
+        Some(vpid)
     }
 
     pub fn get(&mut self, vpid: VPid) -> Option<&mut Pin<Box<Process<'t, F>>>> {
@@ -121,9 +118,49 @@ impl<'t, F: Future<Output = ()>> ProcessTable<'t, F> {
     }
 }
 
+/// State shared by every file descriptor that came from the same open()
+///
+/// Like the kernel's open file description, dup() shares this while each new
+/// open() gets its own. The file offset lives in the real description behind
+/// the tracee's descriptor, which is also separate for each open().
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OpenFile {
+    pub vfile: VFile,
+    pub flags: usize,
+}
+
+impl OpenFile {
+    /// Status flags which the real file descriptor must also carry
+    const STATUS_FLAGS: usize = abi::O_APPEND | abi::O_NONBLOCK;
+
+    pub fn new(vfile: VFile, open_flags: i32) -> Self {
+        OpenFile {
+            vfile,
+            flags: open_flags as usize & (abi::O_ACCMODE | OpenFile::STATUS_FLAGS),
+        }
+    }
+
+    pub fn status_flags(&self) -> usize {
+        self.flags & OpenFile::STATUS_FLAGS
+    }
+}
+
+/// One of the process's descriptors, with its close-on-exec flag
+#[derive(Debug, Clone)]
+struct Descriptor {
+    file: Rc<OpenFile>,
+    cloexec: bool,
+}
+
+/// The descriptors that refer to files in the virtual filesystem
+///
+/// This mirrors the part of the tracee's descriptor space which the tracer
+/// created. Descriptors the kernel handed out on its own, like stdio and
+/// pipes, aren't listed, and a dup() onto one of ours replaces it the same
+/// way the real one does.
 #[derive(Debug, Clone)]
 pub struct FileTable {
-    table: Rc<RefCell<HashMap<RemoteFd, VFile>>>,
+    table: Rc<RefCell<HashMap<RemoteFd, Descriptor>>>,
 }
 
 impl FileTable {
@@ -133,25 +170,61 @@ impl FileTable {
         }
     }
 
-    pub fn open(&mut self, fd: RemoteFd, vfile: VFile) {
-        self.table.borrow_mut().insert(fd, vfile);
+    /// A copy for a forked process, which shares each open file but not the
+    /// table itself
+    pub fn fork(&self) -> Self {
+        FileTable {
+            table: Rc::new(RefCell::new(self.table.borrow().clone())),
+        }
+    }
+
+    pub fn open(&mut self, fd: RemoteFd, file: Rc<OpenFile>, cloexec: bool) {
+        self.table
+            .borrow_mut()
+            .insert(fd, Descriptor { file, cloexec });
     }
 
     pub fn close(&mut self, fd: &RemoteFd) {
         self.table.borrow_mut().remove(fd);
     }
 
-    pub fn get(&self, fd: &RemoteFd) -> Result<VFile, Errno> {
+    pub fn get(&self, fd: &RemoteFd) -> Result<Rc<OpenFile>, Errno> {
         self.table
             .borrow()
             .get(fd)
-            .cloned()
-            .ok_or(Errno(-abi::EBADF))
+            .map(|desc| desc.file.clone())
+            .ok_or(Errno::new(abi::EBADF))
     }
 
-    pub fn dup(&mut self, src_fd: &RemoteFd, dest_fd: &RemoteFd) -> Result<(), Errno> {
-        let vfile = self.get(src_fd)?;
-        self.open(dest_fd.clone(), vfile);
-        Ok(())
+    /// Record a dup() the tracee has already made
+    ///
+    /// If the source isn't one of ours, the destination now refers to
+    /// something else and is dropped from the table.
+    pub fn dup(&mut self, src_fd: &RemoteFd, dest_fd: &RemoteFd, cloexec: bool) {
+        match self.get(src_fd) {
+            Ok(file) => self.open(dest_fd.clone(), file, cloexec),
+            Err(_) => self.close(dest_fd),
+        }
+    }
+
+    pub fn set_cloexec(&mut self, fd: &RemoteFd, cloexec: bool) {
+        if let Some(desc) = self.table.borrow_mut().get_mut(fd) {
+            desc.cloexec = cloexec;
+        }
+    }
+
+    /// Remove every close-on-exec descriptor, returning them so the tracee's
+    /// copies can be closed too
+    pub fn exec(&mut self) -> Vec<RemoteFd> {
+        let mut table = self.table.borrow_mut();
+        let fds: Vec<RemoteFd> = table
+            .iter()
+            .filter(|(_, desc)| desc.cloexec)
+            .map(|(fd, _)| fd.clone())
+            .collect();
+        for fd in &fds {
+            table.remove(fd);
+        }
+        fds
     }
 }
@@ -1,17 +1,26 @@
 use crate::{
-    abi,
-    mem::{kernel::KernelMemIterator, page::VPage, rw::print_stack_dump},
+    abi, logring,
+    mem::{
+        kernel::{verify_syscall_entry, KernelMemIterator},
+        page::VPage,
+        rw::{print_stack_dump, write_u32},
+    },
     nolibc::{getrandom_usize, File},
     process::{table::FileTable, Event, EventSource, MessageSender},
     protocol::{
         abi::{Syscall, UserRegs},
-        FromTask, LogLevel, LogMessage, ProcessHandle, SysPid, ToTask, TracerSettings, VPid, VPtr,
+        Errno, FromTask, LogLevel, LogMessage, ProcessHandle, Signal, SysPid, ToTask,
+        TracerSettings, VPid, VPtr,
     },
     ptrace,
     remote::file::RemoteFd,
-    syscall::SyscallEmulator,
+    syscall::{kill_unverified, LatencyTimer, NotifyEmulator, StormDetector, SyscallEmulator},
+};
+use alloc::{rc::Rc, vec::Vec};
+use core::{
+    cell::RefCell,
+    fmt::{self, Debug, Formatter},
 };
-use core::fmt::{self, Debug, Formatter};
 
 #[derive(Debug)]
 pub struct TaskSocketPair {
@@ -27,8 +36,12 @@ pub struct TaskMemManagement {
 }
 
 impl TaskMemManagement {
-    pub fn randomize_brk(&mut self, brk_base: VPage) {
-        let brk = brk_base + (getrandom_usize() & abi::BRK_RND_MASK);
+    pub fn init_brk(&mut self, brk_base: VPage, randomize: bool) {
+        let brk = if randomize {
+            brk_base + (getrandom_usize() & abi::BRK_RND_MASK)
+        } else {
+            brk_base
+        };
         self.brk_start = brk;
         self.brk = brk.ptr();
     }
@@ -39,10 +52,31 @@ pub struct TaskData {
     pub vpid: VPid,
     pub sys_pid: SysPid,
     pub parent: Option<VPid>,
+    /// For a thread, the task its process started with, whose VPid is the
+    /// process ID
+    pub leader: Option<VPid>,
+    pub socket_pair: TaskSocketPair,
+    /// Shared by the threads of a process
+    pub mm: Rc<RefCell<TaskMemManagement>>,
+    pub file_table: FileTable,
+    pub tracer_settings: TracerSettings,
+    /// Children which haven't been waited for yet
+    pub children: Vec<(VPid, SysPid)>,
+    /// Where the task's VPid goes once it first stops, for CLONE_CHILD_SETTID
+    pub child_tid: Option<VPtr>,
+}
+
+/// A new process or thread, waiting for the tracer to give it a VPid
+#[derive(Debug)]
+pub struct ChildTask {
+    pub sys_pid: SysPid,
+    pub parent: Option<VPid>,
+    pub leader: Option<VPid>,
     pub socket_pair: TaskSocketPair,
-    pub mm: TaskMemManagement,
+    pub mm: Rc<RefCell<TaskMemManagement>>,
     pub file_table: FileTable,
     pub tracer_settings: TracerSettings,
+    pub child_tid: Option<VPtr>,
 }
 
 pub async fn task_fn(events: EventSource<'_>, msg: MessageSender<'_>, task_data: TaskData) {
@@ -54,6 +88,13 @@ pub struct Task<'q> {
     pub process_handle: ProcessHandle,
     pub msg: MessageSender<'q>,
     pub events: EventSource<'q>,
+    pub storm: StormDetector,
+    pub latency: LatencyTimer,
+    /// Set until the first process makes its first exec, the one that loads
+    /// the container's entry point
+    pub initial_exec: bool,
+    /// The task's exit, if it arrived while waiting for something else
+    pub exit: Option<Event>,
 }
 
 #[derive(Debug)]
@@ -76,6 +117,12 @@ impl TaskSocketPair {
     }
 }
 
+impl Drop for TaskSocketPair {
+    fn drop(&mut self) {
+        let _ = self.tracer.close();
+    }
+}
+
 impl<'q> Debug for Task<'q> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_tuple("Task")
@@ -91,40 +138,80 @@ impl<'q> Task<'q> {
         mut msg: MessageSender<'q>,
         task_data: TaskData,
     ) -> Task<'q> {
-        ptrace::setoptions(task_data.sys_pid);
-
-        // Wait for ptrace attach breakpoint
-        expect_event_or_panic(
-            &mut events,
-            task_data.sys_pid,
-            Event::Signal {
-                sig: abi::SIGCHLD as u32,
-                code: abi::CLD_TRAPPED,
-                status: abi::SIGTRAP as u32,
-            },
-        )
-        .await;
-
-        // Wait for exec of the loader process
-        ptrace::cont(task_data.sys_pid);
-        expect_event_or_panic(
-            &mut events,
-            task_data.sys_pid,
-            Event::Signal {
-                sig: abi::SIGCHLD as u32,
-                code: abi::CLD_TRAPPED,
-                status: abi::PTRACE_SIG_EXEC,
-            },
-        )
-        .await;
+        // A task can be killed at any point while it starts, and its exit
+        // waits for the main loop once the runtime knows about the process
+        let mut exit = match task_data.leader.or(task_data.parent) {
+            None => {
+                ptrace::unless_exited(ptrace::setoptions(task_data.sys_pid));
+
+                // Wait for ptrace attach breakpoint
+                let mut exit = expect_stop_or_exit(
+                    &mut events,
+                    task_data.sys_pid,
+                    Event::Signal {
+                        sig: abi::SIGCHLD as u32,
+                        code: abi::CLD_TRAPPED,
+                        status: abi::SIGTRAP as u32,
+                    },
+                )
+                .await;
+
+                // Wait for exec of the loader process
+                if exit.is_none() {
+                    ptrace::unless_exited(ptrace::cont(task_data.sys_pid));
+                    exit = expect_stop_or_exit(
+                        &mut events,
+                        task_data.sys_pid,
+                        Event::Signal {
+                            sig: abi::SIGCHLD as u32,
+                            code: abi::CLD_TRAPPED,
+                            status: abi::PTRACE_SIG_EXEC,
+                        },
+                    )
+                    .await;
+                }
 
-        msg.send(FromTask::OpenProcess(task_data.sys_pid));
-        match events.next().await {
+                msg.send(FromTask::OpenProcess(task_data.sys_pid));
+                exit
+            }
+            Some(parent) => {
+                // Forked children and threads are traced from the start,
+                // with their parent's ptrace options, and stop first with a
+                // SIGSTOP. A thread starts with its process's status.
+                let exit = expect_stop_or_exit(
+                    &mut events,
+                    task_data.sys_pid,
+                    Event::Signal {
+                        sig: abi::SIGCHLD as u32,
+                        code: abi::CLD_TRAPPED,
+                        status: abi::SIGSTOP as u32,
+                    },
+                )
+                .await;
+
+                msg.send(FromTask::OpenChildProcess {
+                    sys_pid: task_data.sys_pid,
+                    parent,
+                });
+                exit
+            }
+        };
+        let event = loop {
+            match events.next().await {
+                event if exit.is_none() && event.is_exit() => exit = Some(event),
+                event => break event,
+            }
+        };
+        match event {
             Event::Message(ToTask::OpenProcessReply(process_handle)) => Task {
                 events,
                 msg,
                 process_handle,
+                initial_exec: task_data.parent.is_none(),
+                latency: LatencyTimer::new(task_data.tracer_settings.syscall_latency),
                 task_data,
+                storm: Default::default(),
+                exit,
             },
             event => {
                 unexpected_event_panic(task_data.sys_pid, None, event, ExpectedEvent::OpenProcess)
@@ -133,29 +220,30 @@ impl<'q> Task<'q> {
         }
     }
 
+    /// Is a message at this level worth building? Everything is, while the
+    /// log ring is keeping recent messages.
     pub fn log_enabled(&self, level: LogLevel) -> bool {
-        level <= self.task_data.tracer_settings.max_log_level
+        level <= self.task_data.tracer_settings.max_log_level || logring::is_enabled()
     }
 
     pub fn log(&mut self, level: LogLevel, message: LogMessage) {
-        if self.log_enabled(level) {
+        logring::record(self.task_data.vpid, level, &message);
+        if level <= self.task_data.tracer_settings.max_log_level {
             self.msg.send(FromTask::Log(level, message));
         }
     }
 
     async fn run(&mut self) {
+        if let Some(ptr) = self.task_data.child_tid.take() {
+            self.set_child_tid(ptr);
+        }
         self.cont();
         loop {
-            let event = self.events.next().await;
+            let event = match self.exit.take() {
+                Some(event) => event,
+                None => self.events.next().await,
+            };
             match event {
-                Event::Signal { sig, code, status }
-                    if sig == abi::SIGCHLD as u32
-                        && code == abi::CLD_TRAPPED
-                        && status == abi::PTRACE_SIG_FORK =>
-                {
-                    let child_pid = ptrace::geteventmsg(self.task_data.sys_pid) as u32;
-                    self.handle_fork(child_pid).await
-                }
                 Event::Signal { sig, code, status }
                     if sig == abi::SIGCHLD as u32
                         && code == abi::CLD_TRAPPED
@@ -173,13 +261,22 @@ impl<'q> Task<'q> {
                 {
                     return self.handle_exited(status).await
                 }
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32
+                        && (code == abi::CLD_KILLED || code == abi::CLD_DUMPED) =>
+                {
+                    return self.handle_killed(status).await
+                }
+                Event::Notify(listener, notif) => {
+                    NotifyEmulator::new(self, &listener, notif).dispatch().await
+                }
                 event => {
                     let mut regs: UserRegs = Default::default();
                     let sys_pid = self.task_data.sys_pid;
                     let mut stopped_task = self.as_stopped_task(&mut regs);
                     unexpected_event_panic(
                         sys_pid,
-                        Some(&mut stopped_task),
+                        stopped_task.as_mut(),
                         event,
                         ExpectedEvent::MainLoop,
                     )
@@ -189,83 +286,220 @@ impl<'q> Task<'q> {
         }
     }
 
+    /// Resume the task, unless it was killed while stopped, in which case its
+    /// exit is the next event
     fn cont(&self) {
-        if self.task_data.tracer_settings.instruction_trace {
-            ptrace::single_step(self.task_data.sys_pid);
+        let result = if self.task_data.tracer_settings.instruction_trace {
+            ptrace::single_step(self.task_data.sys_pid)
         } else {
-            ptrace::cont(self.task_data.sys_pid);
+            ptrace::cont(self.task_data.sys_pid)
+        };
+        ptrace::unless_exited(result);
+    }
+
+    fn cont_with_signal(&self, signal: u8) {
+        let result = if self.task_data.tracer_settings.instruction_trace {
+            ptrace::single_step_with_signal(self.task_data.sys_pid, signal)
+        } else {
+            ptrace::cont_with_signal(self.task_data.sys_pid, signal)
+        };
+        ptrace::unless_exited(result);
+    }
+
+    /// Did this signal come from one of the task's own timers?
+    ///
+    /// alarm() and setitimer() raise their signals from the kernel, and
+    /// timer_create() timers report themselves as such. Stop signals would
+    /// upset the tracer, so those are never passed on.
+    fn is_timer_signal(&self, signal: u8) -> bool {
+        let mut siginfo: abi::SigInfo = Default::default();
+        let result = ptrace::getsiginfo(self.task_data.sys_pid, &mut siginfo);
+        if ptrace::unless_exited(result).is_none() {
+            return false;
+        }
+        match siginfo.si_code {
+            abi::SI_KERNEL => {
+                signal == abi::SIGALRM || signal == abi::SIGVTALRM || signal == abi::SIGPROF
+            }
+            abi::SI_TIMER => !matches!(
+                signal,
+                abi::SIGSTOP | abi::SIGTSTP | abi::SIGTTIN | abi::SIGTTOU
+            ),
+            _ => false,
+        }
+    }
+
+    /// Store this task's VPid over the host's thread ID, which the kernel
+    /// already put there
+    fn set_child_tid(&mut self, ptr: VPtr) {
+        let vpid = self.task_data.vpid;
+        let mut regs: UserRegs = Default::default();
+        if let Some(mut stopped_task) = self.as_stopped_task(&mut regs) {
+            // Like the kernel, ignore a bad address
+            let _ = write_u32(&mut stopped_task, ptr, vpid.0);
         }
     }
 
-    fn as_stopped_task<'s>(&'s mut self, regs: &'s mut UserRegs) -> StoppedTask<'q, 's> {
-        ptrace::get_regs(self.task_data.sys_pid, regs);
-        StoppedTask { task: self, regs }
+    /// Read the registers of a stopped task, or None if it has since died
+    fn as_stopped_task<'s>(&'s mut self, regs: &'s mut UserRegs) -> Option<StoppedTask<'q, 's>> {
+        ptrace::unless_exited(ptrace::get_regs(self.task_data.sys_pid, regs))?;
+        Some(StoppedTask { task: self, regs })
     }
 
     async fn handle_signal(&mut self, signal: u8) {
+        // Timers are the kernel's, like clocks, so their signals go through
+        if self.is_timer_signal(signal) {
+            return self.cont_with_signal(signal);
+        }
+
         let mut regs: UserRegs = Default::default();
-        let mut stopped_task = self.as_stopped_task(&mut regs);
-        let mut log_level = LogLevel::Trace;
+        let mut stopped_task = match self.as_stopped_task(&mut regs) {
+            Some(stopped_task) => stopped_task,
+            None => return,
+        };
+        let max_log_level = stopped_task.task.task_data.tracer_settings.max_log_level;
 
+        // Faults and syscalls outside the seccomp policy belong to the task.
+        // It may handle them, or die of them and report that like any other
+        // death by signal.
         if signal == abi::SIGSEGV || signal == abi::SIGSYS {
-            println!("task state:\n{:x?}", stopped_task.regs);
-            KernelMemIterator::print_maps(&mut stopped_task);
-            print_stack_dump(&mut stopped_task);
-            panic!("*** signal {} inside sandbox ***", signal);
-        }
-
-        if signal == abi::SIGTRAP {
-            log_level = stopped_task.task.task_data.tracer_settings.max_log_level;
+            if max_log_level >= LogLevel::Debug {
+                println!("task state:\n{:x?}", stopped_task.regs);
+                KernelMemIterator::print_maps(&mut stopped_task);
+                print_stack_dump(&mut stopped_task);
+            }
+            let msg = LogMessage::Signal(signal, stopped_task.regs.clone());
+            self.log(LogLevel::Warn, msg);
+            return self.cont_with_signal(signal);
         }
 
+        let log_level = if signal == abi::SIGTRAP {
+            max_log_level
+        } else {
+            LogLevel::Trace
+        };
         let msg = LogMessage::Signal(signal, stopped_task.regs.clone());
         self.log(log_level, msg);
         self.cont();
     }
 
-    async fn handle_fork(&mut self, child_pid: u32) {
-        panic!("fork not handled yet, pid {}", child_pid);
+    /// Start tracking a new child of this task, and return its VPid
+    ///
+    /// The child takes over `socket_pair`. Its clone flags decide whether
+    /// it's a thread in this task's process, and whether it shares this
+    /// task's memory layout and file table or starts with copies. If there's
+    /// no VPid left for it, the child is killed and this returns EAGAIN.
+    pub async fn handle_fork(
+        &mut self,
+        sys_pid: SysPid,
+        socket_pair: TaskSocketPair,
+        flags: usize,
+        child_tid: Option<VPtr>,
+    ) -> Result<VPid, Errno> {
+        let task_data = &self.task_data;
+        let thread = flags & abi::CLONE_THREAD != 0;
+        let (parent, leader) = if thread {
+            let leader = task_data.leader.unwrap_or(task_data.vpid);
+            (task_data.parent, Some(leader))
+        } else {
+            (Some(task_data.vpid), None)
+        };
+        let mm = if flags & abi::CLONE_VM != 0 {
+            task_data.mm.clone()
+        } else {
+            Rc::new(RefCell::new(task_data.mm.borrow().clone()))
+        };
+        let file_table = if flags & abi::CLONE_FILES != 0 {
+            task_data.file_table.clone()
+        } else {
+            task_data.file_table.fork()
+        };
+        self.msg.spawn(ChildTask {
+            sys_pid,
+            parent,
+            leader,
+            socket_pair,
+            mm,
+            file_table,
+            tracer_settings: task_data.tracer_settings.clone(),
+            child_tid,
+        });
+        // This task may die before the child is tracked, but it still is
+        let event = loop {
+            match self.events.next().await {
+                event if event.is_exit() => self.exit = Some(event),
+                event => break event,
+            }
+        };
+        match event {
+            Event::Forked(Ok(vpid)) => {
+                if !thread {
+                    self.task_data.children.push((vpid, sys_pid));
+                }
+                Ok(vpid)
+            }
+            Event::Forked(Err(err)) => Err(err),
+            event => {
+                let sys_pid = self.task_data.sys_pid;
+                unexpected_event_panic(sys_pid, None, event, ExpectedEvent::Forked).await
+            }
+        }
     }
 
     async fn handle_exited(&mut self, exit_code: u32) {
         self.msg.send(FromTask::Exited(exit_code as i32));
     }
 
+    async fn handle_killed(&mut self, signal: u32) {
+        self.msg.send(FromTask::Signaled(Signal(signal)));
+    }
+
     async fn handle_seccomp_trap(&mut self) {
         let sys_pid = self.task_data.sys_pid;
+        let checks = self.task_data.tracer_settings.instruction_pointer_checks;
+        self.latency.trapped();
         let mut regs: UserRegs = Default::default();
-        let mut stopped_task = self.as_stopped_task(&mut regs);
+        let mut stopped_task = match self.as_stopped_task(&mut regs) {
+            Some(stopped_task) => stopped_task,
+            None => return,
+        };
+        let nr = Syscall::from_regs(stopped_task.regs).nr;
+        stopped_task.task.msg.set_syscall(Some(nr));
+        stopped_task.task.latency.dispatched();
+        if checks && verify_syscall_entry(&mut stopped_task).is_err() {
+            // Left stopped in the trap until the SIGKILL takes it
+            let ip = VPtr(stopped_task.regs.ip);
+            kill_unverified(stopped_task.task, nr, ip);
+            stopped_task.task.msg.set_syscall(None);
+            return;
+        }
         SyscallEmulator::new(&mut stopped_task).dispatch().await;
+        stopped_task.task.msg.set_syscall(None);
+        stopped_task.task.latency.emulated();
         Syscall::orig_nr_to_regs(abi::SYSCALL_BLOCKED, &mut stopped_task.regs);
-        ptrace::set_regs(sys_pid, &stopped_task.regs);
-        self.cont();
+        if ptrace::unless_exited(ptrace::set_regs(sys_pid, &stopped_task.regs)).is_some() {
+            self.cont();
+        }
+        if let Some(report) = self.latency.resumed(nr) {
+            self.msg.send(report);
+        }
     }
 }
 
-async fn expect_event_or_panic<'q, 's, 't>(
+/// Wait for a stop while the task starts, or for the task's exit if it dies
+/// first, which is returned
+async fn expect_stop_or_exit<'q, 's>(
     events: &'s mut EventSource<'q>,
     sys_pid: SysPid,
     expected: Event,
-) {
+) -> Option<Event> {
     let received = events.next().await;
-    if received != expected {
-        unexpected_event_panic(sys_pid, None, received, ExpectedEvent::Matching(expected)).await;
-    }
-}
-
-impl<'q, 's> StoppedTask<'q, 's> {
-    pub async fn expect_event_or_panic(&mut self, expected: Event) {
-        let sys_pid = self.task.task_data.sys_pid;
-        let received = self.task.events.next().await;
-        if received != expected {
-            unexpected_event_panic(
-                sys_pid,
-                Some(self),
-                received,
-                ExpectedEvent::Matching(expected),
-            )
-            .await;
-        }
+    if received.is_exit() {
+        Some(received)
+    } else if received != expected {
+        unexpected_event_panic(sys_pid, None, received, ExpectedEvent::Matching(expected)).await
+    } else {
+        None
     }
 }
 
@@ -274,6 +508,7 @@ enum ExpectedEvent {
     Matching(Event),
     MainLoop,
     OpenProcess,
+    Forked,
 }
 
 async fn unexpected_event_panic<'q, 's, 't>(
@@ -283,7 +518,7 @@ async fn unexpected_event_panic<'q, 's, 't>(
     expected: ExpectedEvent,
 ) -> ! {
     let mut regs: UserRegs = Default::default();
-    ptrace::get_regs(sys_pid, &mut regs);
+    let _ = ptrace::get_regs(sys_pid, &mut regs);
     println!(
         concat!("task: {:?}\n", "stopped: {:x?}\n", "current: {:x?}",),
         sys_pid, stopped_task, regs
@@ -1,8 +1,8 @@
 use crate::{
     abi,
-    protocol::{abi::UserRegs, SysPid},
+    protocol::{abi::UserRegs, Errno, SysPid},
 };
-use core::{mem, ptr::null};
+use core::{fmt, mem, ptr::null};
 use sc::syscall;
 
 pub struct RawExecArgs<'a> {
@@ -21,7 +21,10 @@ pub unsafe fn be_the_child_process(args: &RawExecArgs) -> ! {
     // Make attachable, but doesn't wait for the tracer
     match syscall!(PTRACE, abi::PTRACE_TRACEME, 0, 0, 0) as isize {
         0 => {}
-        result => panic!("ptrace error, {}", result),
+        result => panic!(
+            "PTRACE_TRACEME failed ({}), check /proc/sys/kernel/yama/ptrace_scope",
+            result
+        ),
     }
 
     // Let the tracer attach before we exec.
@@ -36,25 +39,92 @@ pub unsafe fn be_the_child_process(args: &RawExecArgs) -> ! {
     panic!("exec failed, {}", result);
 }
 
-pub fn cont(pid: SysPid) {
-    unsafe {
-        syscall!(PTRACE, abi::PTRACE_CONT, pid.0, 0, 0);
+/// A ptrace request that failed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PtraceError {
+    /// ESRCH, the tracee is gone
+    ///
+    /// Every request here is made while the tracee is stopped, so this means
+    /// something killed it in the meantime. Its exit still arrives through
+    /// waitid() like any other.
+    Exited,
+    /// Any other failure, with the name of the request
+    Failed(&'static str, Errno),
+}
+
+pub type PtraceResult<T> = Result<T, PtraceError>;
+
+impl fmt::Display for PtraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PtraceError::Exited => write!(f, "tracee exited"),
+            PtraceError::Failed(request, err) => write!(f, "ptrace {} failed, {}", request, err),
+        }
+    }
+}
+
+impl From<PtraceError> for Errno {
+    fn from(err: PtraceError) -> Self {
+        match err {
+            PtraceError::Exited => Errno::new(abi::ESRCH),
+            PtraceError::Failed(_, err) => err,
+        }
     }
 }
 
-pub fn single_step(pid: SysPid) {
-    unsafe {
-        syscall!(PTRACE, abi::PTRACE_SINGLESTEP, pid.0, 0, 0);
+/// The value, or None if the tracee died first
+///
+/// Any other failure means the tracer lost track of its tracee, and panics.
+pub fn unless_exited<T>(result: PtraceResult<T>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(PtraceError::Exited) => None,
+        Err(err) => panic!("{}", err),
     }
 }
 
-pub fn trace_syscall(pid: SysPid) {
-    unsafe {
-        syscall!(PTRACE, abi::PTRACE_SYSCALL, pid.0, 0, 0);
+fn request(
+    name: &'static str,
+    request: usize,
+    pid: SysPid,
+    addr: usize,
+    data: usize,
+) -> PtraceResult<()> {
+    match unsafe { syscall!(PTRACE, request, pid.0, addr, data) as isize } {
+        0 => Ok(()),
+        result if result == -(abi::ESRCH as isize) => Err(PtraceError::Exited),
+        result => Err(PtraceError::Failed(name, Errno(result as i32))),
     }
 }
 
-pub fn setoptions(pid: SysPid) {
+pub fn cont(pid: SysPid) -> PtraceResult<()> {
+    request("cont", abi::PTRACE_CONT, pid, 0, 0)
+}
+
+pub fn single_step(pid: SysPid) -> PtraceResult<()> {
+    request("singlestep", abi::PTRACE_SINGLESTEP, pid, 0, 0)
+}
+
+/// Continue from a signal-delivery-stop, letting the task have the signal
+pub fn cont_with_signal(pid: SysPid, signal: u8) -> PtraceResult<()> {
+    request("cont", abi::PTRACE_CONT, pid, 0, signal as usize)
+}
+
+pub fn single_step_with_signal(pid: SysPid, signal: u8) -> PtraceResult<()> {
+    request(
+        "singlestep",
+        abi::PTRACE_SINGLESTEP,
+        pid,
+        0,
+        signal as usize,
+    )
+}
+
+pub fn trace_syscall(pid: SysPid) -> PtraceResult<()> {
+    request("syscall", abi::PTRACE_SYSCALL, pid, 0, 0)
+}
+
+pub fn setoptions(pid: SysPid) -> PtraceResult<()> {
     let options = abi::PTRACE_O_EXITKILL
         | abi::PTRACE_O_TRACECLONE
         | abi::PTRACE_O_TRACEEXEC
@@ -63,80 +133,78 @@ pub fn setoptions(pid: SysPid) {
         | abi::PTRACE_O_TRACEVFORK
         | abi::PTRACE_O_TRACEVFORK_DONE
         | abi::PTRACE_O_TRACESECCOMP;
-    unsafe {
-        syscall!(PTRACE, abi::PTRACE_SETOPTIONS, pid.0, 0, options);
-    }
+    request("setoptions", abi::PTRACE_SETOPTIONS, pid, 0, options)
 }
 
-pub fn get_regs(pid: SysPid, regs: &mut UserRegs) {
+pub fn get_regs(pid: SysPid, regs: &mut UserRegs) -> PtraceResult<()> {
     let mut iovec = abi::IOVec {
         base: regs as *mut UserRegs as *mut u8,
         len: mem::size_of_val(regs),
     };
-    match unsafe {
-        syscall!(
-            PTRACE,
-            abi::PTRACE_GETREGSET,
-            pid.0,
-            abi::NT_PRSTATUS,
-            &mut iovec as *mut abi::IOVec
-        ) as isize
-    } {
-        0 => (),
-        err => panic!("ptrace getregset failed ({})", err),
-    }
+    let iovec_ptr = &mut iovec as *mut abi::IOVec as usize;
+    request(
+        "getregset",
+        abi::PTRACE_GETREGSET,
+        pid,
+        abi::NT_PRSTATUS,
+        iovec_ptr,
+    )?;
     assert_eq!(iovec.len, mem::size_of_val(regs));
+    Ok(())
 }
 
-pub fn set_regs(pid: SysPid, regs: &UserRegs) {
+pub fn set_regs(pid: SysPid, regs: &UserRegs) -> PtraceResult<()> {
     let mut iovec = abi::IOVec {
         base: regs as *const UserRegs as *mut u8,
         len: mem::size_of_val(regs),
     };
-    match unsafe {
-        syscall!(
-            PTRACE,
-            abi::PTRACE_SETREGSET,
-            pid.0,
-            abi::NT_PRSTATUS,
-            &mut iovec as *mut abi::IOVec
-        ) as isize
-    } {
-        0 => (),
-        err => panic!("ptrace getregset failed ({})", err),
-    }
+    let iovec_ptr = &mut iovec as *mut abi::IOVec as usize;
+    request(
+        "setregset",
+        abi::PTRACE_SETREGSET,
+        pid,
+        abi::NT_PRSTATUS,
+        iovec_ptr,
+    )?;
     assert_eq!(iovec.len, mem::size_of_val(regs));
+    Ok(())
 }
 
-pub fn geteventmsg(pid: SysPid) -> usize {
+pub fn geteventmsg(pid: SysPid) -> PtraceResult<usize> {
     let mut result = usize::MAX;
-    match unsafe {
-        syscall!(
-            PTRACE,
-            abi::PTRACE_GETEVENTMSG,
-            pid.0,
-            0,
-            &mut result as *mut usize
-        ) as isize
-    } {
-        0 => result,
-        err => panic!("ptrace geteventmsg failed ({})", err),
-    }
+    let result_ptr = &mut result as *mut usize as usize;
+    request("geteventmsg", abi::PTRACE_GETEVENTMSG, pid, 0, result_ptr)?;
+    Ok(result)
 }
 
-pub fn poke(pid: SysPid, addr: usize, data: usize) -> Result<(), ()> {
-    match unsafe { syscall!(PTRACE, abi::PTRACE_POKEDATA, pid.0, addr, data) as isize } {
-        0 => Ok(()),
-        _ => Err(()),
-    }
+/// Details of the signal a task is stopped for, in signal-delivery-stop
+pub fn getsiginfo(pid: SysPid, info: &mut abi::SigInfo) -> PtraceResult<()> {
+    let info_ptr = info as *mut abi::SigInfo as usize;
+    request("getsiginfo", abi::PTRACE_GETSIGINFO, pid, 0, info_ptr)
+}
+
+pub fn poke(pid: SysPid, addr: usize, data: usize) -> PtraceResult<()> {
+    request("pokedata", abi::PTRACE_POKEDATA, pid, addr, data)
 }
 
 pub fn wait(info: &mut abi::SigInfo) -> isize {
+    wait_with_options(info, 0)
+}
+
+/// Like [wait()], but leaves `si_pid` zero instead of blocking when no
+/// child is waiting
+pub fn wait_nohang(info: &mut abi::SigInfo) -> isize {
+    info.si_pid = 0;
+    wait_with_options(info, abi::WNOHANG)
+}
+
+fn wait_with_options(info: &mut abi::SigInfo, options: usize) -> isize {
     let info_ptr = info as *mut abi::SigInfo as usize;
     assert_eq!(mem::size_of_val(info), abi::SI_MAX_SIZE);
     let which = abi::P_ALL;
     let pid = usize::MAX;
-    let options = abi::WEXITED | abi::WSTOPPED | abi::WCONTINUED;
+    // Threads don't signal their exit with SIGCHLD, so they need __WALL
+    let options = options | abi::WEXITED | abi::WSTOPPED | abi::WCONTINUED | abi::__WALL;
     let rusage = null::<usize>() as usize;
     unsafe { syscall!(WAITID, which, pid, info_ptr, options, rusage) as isize }
 }
@@ -29,7 +29,7 @@ impl RemoteFd {
         let name_start = scratchpad.mem_range.start.ptr();
         let name_end = name_start + name.len();
         if name_end >= scratchpad.mem_range.end.ptr() {
-            return Err(Errno(-abi::EINVAL));
+            return Err(Errno::new(abi::EINVAL));
         }
         write_padded_bytes(scratchpad.trampoline.stopped_task, name_start, name)?;
         let result = scratchpad
@@ -155,7 +155,7 @@ impl RemoteFd {
         if remote_cmsg.hdr == BASE_LAYOUT.cmsg.hdr {
             Ok(RemoteFd(remote_cmsg.fd))
         } else {
-            Err(Errno(-abi::EIO))
+            Err(Errno::new(abi::EIO))
         }
     }
 
@@ -168,6 +168,22 @@ impl RemoteFd {
         }
     }
 
+    pub async fn fcntl(
+        &self,
+        tr: &mut Trampoline<'_, '_, '_>,
+        op: usize,
+        arg: usize,
+    ) -> Result<isize, Errno> {
+        let result = tr
+            .syscall(sc::nr::FCNTL, &[self.0 as isize, op as isize, arg as isize])
+            .await;
+        if result >= 0 {
+            Ok(result)
+        } else {
+            Err(Errno(result as i32))
+        }
+    }
+
     pub async fn pread_vptr(
         &self,
         tr: &mut Trampoline<'_, '_, '_>,
@@ -202,7 +218,7 @@ impl RemoteFd {
     ) -> Result<(), Errno> {
         match self.pread_vptr(tr, addr, length, offset).await {
             Ok(actual) if actual == length => Ok(()),
-            Ok(_) => Err(Errno(-abi::EIO)),
+            Ok(_) => Err(Errno::new(abi::EIO)),
             Err(e) => Err(e),
         }
     }
@@ -214,7 +230,7 @@ impl RemoteFd {
         offset: usize,
     ) -> Result<(), Errno> {
         if bytes.len() > scratchpad.len() - size_of::<usize>() {
-            return Err(Errno(-abi::EINVAL));
+            return Err(Errno::new(abi::EINVAL));
         }
         write_padded_bytes(scratchpad.trampoline.stopped_task, scratchpad.ptr(), bytes)?;
         self.pwrite_vptr_exact(scratchpad.trampoline, scratchpad.ptr(), bytes.len(), offset)
@@ -255,7 +271,7 @@ impl RemoteFd {
     ) -> Result<(), Errno> {
         match self.pwrite_vptr(tr, addr, length, offset).await {
             Ok(actual) if actual == length => Ok(()),
-            Ok(_) => Err(Errno(-abi::EIO)),
+            Ok(_) => Err(Errno::new(abi::EIO)),
             Err(e) => Err(e),
         }
     }
@@ -286,10 +302,12 @@ impl TempRemoteFd {
         Ok(EmptyTempRemoteFd::new(scratchpad).await?.0)
     }
 
+    /// Close the remote fd, which counts as freed even if that fails, as it
+    /// does once the task has died
     pub async fn free(self, trampoline: &mut Trampoline<'_, '_, '_>) -> Result<(), Errno> {
-        self.0.close(trampoline).await?;
+        let result = self.0.close(trampoline).await;
         core::mem::forget(self);
-        Ok(())
+        result
     }
 
     /// use this temp memfd to perform a remote memmove() operation
@@ -314,7 +332,7 @@ impl TempRemoteFd {
         bytes: &[u8],
     ) -> Result<(), Errno> {
         if bytes.len() > scratchpad.len() - size_of::<usize>() {
-            return Err(Errno(-abi::EINVAL));
+            return Err(Errno::new(abi::EINVAL));
         }
         write_padded_bytes(scratchpad.trampoline.stopped_task, scratchpad.ptr(), bytes)?;
         self.memmove(scratchpad.trampoline, addr, scratchpad.ptr(), bytes.len())
@@ -431,7 +449,7 @@ pub async fn fd_copy_exact(
     {
         Ok(())
     } else {
-        Err(Errno(-abi::EIO))
+        Err(Errno::new(abi::EIO))
     }
 }
 
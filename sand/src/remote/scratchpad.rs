@@ -48,9 +48,14 @@ impl<'q, 's, 't, 'r> Scratchpad<'q, 's, 't, 'r> {
         self.mem_range.start.ptr()
     }
 
+    /// Unmap the scratchpad, which must happen before it's dropped
+    ///
+    /// Errors are returned, but the scratchpad counts as freed either way.
+    /// The usual reason for one is that the task has died, taking its memory
+    /// with it.
     pub async fn free(self) -> Result<(), Errno> {
-        self.trampoline.munmap(&self.mem_range).await?;
+        let result = self.trampoline.munmap(&self.mem_range).await;
         core::mem::forget(self);
-        Ok(())
+        result
     }
 }
@@ -6,8 +6,8 @@ use crate::{
         page::VPage,
     },
     process::{task::StoppedTask, Event},
-    protocol::{abi::Syscall, Errno, LogLevel, LogMessage, VPtr},
-    ptrace,
+    protocol::{abi::Syscall, Errno, LogLevel, LogMessage, SysPid, VPtr},
+    ptrace::{self, PtraceError, PtraceResult},
     remote::file::RemoteFd,
 };
 use core::ops::Range;
@@ -15,7 +15,7 @@ use core::ops::Range;
 #[derive(Debug)]
 pub struct Trampoline<'q, 's, 't> {
     pub stopped_task: &'t mut StoppedTask<'q, 's>,
-    pub kernel_mem: KernelMemAreas,
+    kernel_mem: Option<KernelMemAreas>,
 }
 
 impl<'q, 's, 't> Trampoline<'q, 's, 't> {
@@ -27,26 +27,121 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         }
     }
 
+    /// Find the kernel's areas in the task's memory, or ESRCH if the task
+    /// died before this trampoline could look
+    pub fn kernel_mem(&self) -> Result<&KernelMemAreas, Errno> {
+        self.kernel_mem.as_ref().ok_or(Errno::new(abi::ESRCH))
+    }
+
     pub async fn unmap_all_userspace_mem(&mut self) {
         loop {
+            let kernel_mem = match &self.kernel_mem {
+                Some(kernel_mem) => kernel_mem,
+                None => return,
+            };
             let mut to_unmap = None;
             for area in KernelMemIterator::new(self.stopped_task) {
-                if self.kernel_mem.is_userspace_area(&area) {
+                if kernel_mem.is_userspace_area(&area) {
                     to_unmap = Some(area);
                     break;
                 }
             }
             match to_unmap {
-                Some(area) => self
-                    .munmap(&area.pages.mem_pages())
-                    .await
-                    .expect("unmap userspace"),
+                Some(area) => match self.munmap(&area.pages.mem_pages()).await {
+                    Ok(()) => {}
+                    // The task died, and its memory went with it
+                    Err(err) if err == Errno::new(abi::ESRCH) => return,
+                    Err(err) => panic!("unmap userspace, {:?}", err),
+                },
                 None => return,
             }
         }
     }
 
+    /// Run a syscall in the task, returning its result
+    ///
+    /// If the task is killed partway through, this returns ESRCH, and the
+    /// task handles its exit once the emulated syscall is done.
     pub async fn syscall(&mut self, nr: usize, args: &[isize]) -> isize {
+        match or_esrch(self.remote_syscall(nr, args).await) {
+            Ok(result) => result,
+            Err(err) => err.0 as isize,
+        }
+    }
+
+    async fn remote_syscall(&mut self, nr: usize, args: &[isize]) -> PtraceResult<isize> {
+        self.start_syscall(nr, args)?;
+        self.expect_event(abi::PTRACE_SIG_TRACESYSGOOD, ptrace::trace_syscall)
+            .await?;
+        self.finish_syscall().await
+    }
+
+    /// Start a syscall which may create a new process or thread, like fork()
+    /// or clone()
+    ///
+    /// On success the new task exists and is traced, but nothing is tracking
+    /// it yet. It stays in its initial stop until it's added to the process
+    /// table. This task stays stopped in the syscall until
+    /// [finish_fork()](Self::finish_fork).
+    pub async fn start_fork(&mut self, nr: usize, args: &[isize]) -> Result<SysPid, Errno> {
+        let pid = self.stopped_task.task.task_data.sys_pid;
+        or_esrch(self.start_syscall(nr, args))?;
+        loop {
+            match self.stopped_task.task.events.next().await {
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32
+                        && code == abi::CLD_TRAPPED
+                        && (status == abi::PTRACE_SIG_FORK
+                            || status == abi::PTRACE_SIG_VFORK
+                            || status == abi::PTRACE_SIG_CLONE) =>
+                {
+                    return Ok(SysPid(or_esrch(ptrace::geteventmsg(pid))? as u32));
+                }
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32
+                        && code == abi::CLD_TRAPPED
+                        && status == abi::PTRACE_SIG_TRACESYSGOOD =>
+                {
+                    // No fork event, so there's no new process
+                    let result = or_esrch(self.finish_syscall().await)?;
+                    return Err(Errno(result as i32));
+                }
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32 && code == abi::CLD_TRAPPED && status < 0x80 =>
+                {
+                    or_esrch(ptrace::trace_syscall(pid))?
+                }
+                event if event.is_exit() => {
+                    self.stopped_task.task.exit = Some(event);
+                    return Err(Errno::new(abi::ESRCH));
+                }
+                event => panic!("unexpected event during fork, {:x?}", event),
+            }
+        }
+    }
+
+    /// Let a syscall from [start_fork()](Self::start_fork) return
+    ///
+    /// After a vfork, this waits until the child has exec'd or exited.
+    pub async fn finish_fork(&mut self, vfork: bool) -> Result<(), Errno> {
+        let pid = self.stopped_task.task.task_data.sys_pid;
+        or_esrch(ptrace::trace_syscall(pid))?;
+        if vfork {
+            let done = self
+                .expect_event(abi::PTRACE_SIG_VFORK_DONE, ptrace::trace_syscall)
+                .await;
+            or_esrch(done)?;
+            or_esrch(ptrace::trace_syscall(pid))?;
+        }
+        let traced = self
+            .expect_event(abi::PTRACE_SIG_TRACESYSGOOD, ptrace::trace_syscall)
+            .await;
+        or_esrch(traced)?;
+        or_esrch(self.finish_syscall().await)?;
+        Ok(())
+    }
+
+    fn start_syscall(&mut self, nr: usize, args: &[isize]) -> PtraceResult<()> {
         let pid = self.stopped_task.task.task_data.sys_pid;
         let mut local_regs = self.stopped_task.regs.clone();
 
@@ -54,16 +149,55 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         Syscall::args_to_regs(args, &mut local_regs);
 
         // Run the syscall until completion, trapping again on the way out
-        ptrace::set_regs(pid, &local_regs);
-        ptrace::trace_syscall(pid);
-        self.stopped_task
-            .expect_event_or_panic(Event::Signal {
-                sig: abi::SIGCHLD as u32,
-                code: abi::CLD_TRAPPED,
-                status: abi::PTRACE_SIG_TRACESYSGOOD,
-            })
-            .await;
-        ptrace::get_regs(pid, &mut local_regs);
+        ptrace::set_regs(pid, &local_regs)?;
+        ptrace::trace_syscall(pid)
+    }
+
+    /// Wait for a ptrace stop, resuming the same way after any signals
+    ///
+    /// Signals are discarded here just as they are outside the trampoline.
+    /// Without that, a child exiting during a remote syscall would interrupt
+    /// its parent with a SIGCHLD.
+    ///
+    /// If the task exits instead, its exit is saved for the task's main loop.
+    async fn expect_event(
+        &mut self,
+        expected: u32,
+        resume: fn(SysPid) -> PtraceResult<()>,
+    ) -> PtraceResult<()> {
+        let pid = self.stopped_task.task.task_data.sys_pid;
+        loop {
+            match self.stopped_task.task.events.next().await {
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32
+                        && code == abi::CLD_TRAPPED
+                        && status == expected =>
+                {
+                    return Ok(())
+                }
+                Event::Signal { sig, code, status }
+                    if sig == abi::SIGCHLD as u32 && code == abi::CLD_TRAPPED && status < 0x80 =>
+                {
+                    resume(pid)?
+                }
+                event if event.is_exit() => {
+                    self.stopped_task.task.exit = Some(event);
+                    return Err(PtraceError::Exited);
+                }
+                event => panic!(
+                    "unexpected event in trampoline, expected status {:x}, received {:x?}",
+                    expected, event
+                ),
+            }
+        }
+    }
+
+    /// Collect the result of a syscall trapped on the way out, and return to
+    /// the original seccomp stop
+    async fn finish_syscall(&mut self) -> PtraceResult<isize> {
+        let pid = self.stopped_task.task.task_data.sys_pid;
+        let mut local_regs = self.stopped_task.regs.clone();
+        ptrace::get_regs(pid, &mut local_regs)?;
 
         // Save the results from the remote call
         let result = Syscall::ret_from_regs(&local_regs);
@@ -83,27 +217,25 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         // using the VDSO as a trampoline.
         let fake_syscall_nr = sc::nr::OPEN as isize;
         let fake_syscall_arg = 0xffff_ffff_dddd_dddd_u64 as isize;
-        local_regs.ip = self.kernel_mem.vdso_syscall.0;
+        local_regs.ip = match &self.kernel_mem {
+            Some(kernel_mem) => kernel_mem.vdso_syscall.0,
+            None => return Err(PtraceError::Exited),
+        };
         local_regs.sp = 0;
         Syscall::nr_to_regs(fake_syscall_nr, &mut local_regs);
         Syscall::args_to_regs(&[fake_syscall_arg; 6], &mut local_regs);
 
-        ptrace::set_regs(pid, &local_regs);
-        ptrace::single_step(pid);
-        self.stopped_task
-            .expect_event_or_panic(Event::Signal {
-                sig: abi::SIGCHLD as u32,
-                code: abi::CLD_TRAPPED,
-                status: abi::PTRACE_SIG_SECCOMP,
-            })
-            .await;
-        ptrace::get_regs(pid, &mut local_regs);
+        ptrace::set_regs(pid, &local_regs)?;
+        ptrace::single_step(pid)?;
+        self.expect_event(abi::PTRACE_SIG_SECCOMP, ptrace::single_step)
+            .await?;
+        ptrace::get_regs(pid, &mut local_regs)?;
         let info = Syscall::from_regs(&local_regs);
         assert_eq!(info.nr, fake_syscall_nr);
         assert_eq!(info.args, [fake_syscall_arg; 6]);
 
-        ptrace::set_regs(pid, &self.stopped_task.regs);
-        result
+        ptrace::set_regs(pid, &self.stopped_task.regs)?;
+        Ok(result)
     }
 
     pub async fn mmap(
@@ -131,7 +263,7 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
             Err(Errno(result as i32))
         } else {
             let result = VPtr(result as usize);
-            VPage::parse_range(&(result..(result + len))).map_err(|()| Errno(-abi::EINVAL))
+            VPage::parse_range(&(result..(result + len))).map_err(|()| Errno::new(abi::EINVAL))
         }
     }
 
@@ -149,7 +281,7 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         } else {
             // Unexpected location, unmap the unwanted mapping before failing.
             self.munmap(&result).await?;
-            Err(Errno(-abi::EINVAL))
+            Err(Errno::new(abi::EINVAL))
         }
     }
 
@@ -191,7 +323,7 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         } else {
             let new_addr = VPtr(result as usize);
             VPage::parse_range(&(new_addr..(new_addr + new_length)))
-                .map_err(|()| Errno(-abi::EINVAL))
+                .map_err(|()| Errno::new(abi::EINVAL))
         }
     }
 
@@ -223,7 +355,13 @@ impl<'q, 's, 't> Trampoline<'q, 's, 't> {
         if self.getrandom(addr, length, flags).await? == length {
             Ok(())
         } else {
-            Err(Errno(-abi::EIO))
+            Err(Errno::new(abi::EIO))
         }
     }
 }
+
+/// Remote syscalls in a task that has died fail with ESRCH, like any other
+/// syscall naming a process that's gone
+fn or_esrch<T>(result: PtraceResult<T>) -> Result<T, Errno> {
+    ptrace::unless_exited(result).ok_or_else(|| Errno::new(abi::ESRCH))
+}
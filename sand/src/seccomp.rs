@@ -1,11 +1,18 @@
-use crate::abi;
-use sc::nr;
+use crate::{
+    abi,
+    nolibc::File,
+    protocol::{Errno, SysFd},
+};
+use core::fmt;
+use sc::{nr, syscall};
 use seccomp_tiny::{abi::*, bpf::*, ProgramBuffer};
 
 // This file has two policies; the "tracer" policy is applied very early, and
 // covers this process for its entire lifetime. The "loader" policy is applied
 // during stage 2, and it applies additional ruless which the sandbox contents
-// use but not the tracer.
+// use but not the tracer. When user notifications are enabled, a third
+// "notify" policy sits between the two, and so does a "clock" policy when
+// the vDSO's clock is turned off.
 //
 // For comparison, the container we might be running in likely has a policy like
 // this one: https://github.com/moby/moby/blob/master/profiles/seccomp/default.json
@@ -29,6 +36,7 @@ fn base_rules_for_all_policies() -> ProgramBuffer {
             nr::FUTEX,
             nr::GETRANDOM,
             nr::GETRLIMIT,
+            nr::LISTEN,
             nr::LSEEK,
             nr::MEMFD_CREATE,
             nr::MMAP,
@@ -42,18 +50,29 @@ fn base_rules_for_all_policies() -> ProgramBuffer {
             nr::PWRITE64,
             nr::READ,
             nr::READV,
+            nr::RECVFROM,
             nr::RT_SIGACTION,
             nr::RT_SIGPROCMASK,
             nr::RT_SIGRETURN,
             nr::SELECT,
             nr::SENDFILE,
+            nr::SENDTO,
             nr::SET_ROBUST_LIST,
             nr::SIGALTSTACK,
+            nr::CLOCK_GETRES,
+            nr::CLOCK_GETTIME,
+            nr::GETTIMEOFDAY,
             nr::TIME,
+            nr::ALARM,
+            nr::GETITIMER,
+            nr::SETITIMER,
+            nr::TIMER_CREATE,
+            nr::TIMER_DELETE,
+            nr::TIMER_GETOVERRUN,
+            nr::TIMER_GETTIME,
+            nr::TIMER_SETTIME,
             nr::WRITE,
             nr::WRITEV,
-            // fixme: only allow some operations
-            nr::FCNTL,
             nr::ARCH_PRCTL,
             nr::PRCTL,
             nr::FADVISE64,
@@ -76,53 +95,266 @@ pub fn base_rules_for_tracer() -> ProgramBuffer {
             nr::SENDMSG,
             nr::RECVMSG,
             nr::CLOSE,
+            nr::FCNTL,
             nr::WAITID,
             nr::PTRACE,
             nr::GETPID,
             nr::SOCKETPAIR,
+            nr::TKILL,
+            nr::PPOLL,
+            nr::PROCESS_VM_WRITEV,
         ],
         &[ret(SECCOMP_RET_ALLOW)],
     );
 
+    // The tracer only uses ioctl on a user notification listener
+    p.if_any_eq(
+        &[nr::IOCTL],
+        &[
+            load(arg_offset(1)),
+            jump_if_eq(abi::SECCOMP_IOCTL_NOTIF_RECV as u32, 3, 0),
+            jump_if_eq(abi::SECCOMP_IOCTL_NOTIF_SEND as u32, 2, 0),
+            jump_if_eq(abi::SECCOMP_IOCTL_NOTIF_ID_VALID as u32, 1, 0),
+            jump_if_eq(abi::SECCOMP_IOCTL_NOTIF_ADDFD as u32, 0, 1),
+            ret(SECCOMP_RET_ALLOW),
+            load(offset_of!(SeccompData, nr)),
+        ],
+    );
+
     p
 }
 
+/// Offset of a syscall argument's low 32 bits
+fn arg_offset(index: usize) -> usize {
+    offset_of!(SeccompData, args) + index * 8
+}
+
+fn jump_if_eq(k: u32, jt: u8, jf: u8) -> SockFilter {
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    SockFilter {
+        code: BPF_JMP_JEQ_K,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn jump_if_set(k: u32, jt: u8, jf: u8) -> SockFilter {
+    const BPF_JMP_JSET_K: u16 = 0x45;
+    SockFilter {
+        code: BPF_JMP_JSET_K,
+        jt,
+        jf,
+        k,
+    }
+}
+
 pub fn policy_for_tracer_init() {
+    rules_for_tracer_init().activate();
+}
+
+pub fn policy_for_tracer_after_init() -> Result<(), PolicyNotInEffect> {
+    rules_for_tracer_after_init().activate();
+    check_tracer_after_init()
+}
+
+/// Send every clock read to the tracer, for the loader and everything it
+/// starts
+///
+/// The other policies allow these calls. Stacked on them, the trace here wins.
+pub fn policy_for_traced_clock() {
+    rules_for_traced_clock().activate();
+}
+
+pub fn policy_for_loader() {
+    rules_for_loader().activate();
+}
+
+fn rules_for_tracer_init() -> ProgramBuffer {
     let mut p = base_rules_for_tracer();
 
     // During init, we need the tracer to make one real non-emulated fork and exec,
-    // which will subsequently be disallowed/emulated.
-    p.if_any_eq(&[nr::FORK, nr::EXECVE], &[ret(SECCOMP_RET_ALLOW)]);
+    // which will subsequently be disallowed/emulated. The child may also need
+    // to install the notify policy or turn off address randomization before
+    // its exec. Just before the fork, the tracer lists its own fds to find any
+    // that shouldn't be inherited.
+    p.if_any_eq(
+        &[
+            nr::FORK,
+            nr::EXECVE,
+            nr::SECCOMP,
+            nr::GETDENTS64,
+            nr::PERSONALITY,
+        ],
+        &[ret(SECCOMP_RET_ALLOW)],
+    );
 
     // There is no tracer yet, but we want to allow tracing later.
     // With no tracer attached this blocks the syscall with ENOSYS.
     p.inst(ret(SECCOMP_RET_TRACE));
-
-    p.activate();
+    p
 }
 
-pub fn policy_for_tracer_after_init() {
+fn rules_for_tracer_after_init() -> ProgramBuffer {
     let mut p = base_rules_for_tracer();
 
+    // This is stacked on the init policy, so the stricter result wins. Process
+    // creation and signals to other processes are refused outright rather than
+    // left to the default.
+    p.if_any_eq(
+        &[
+            nr::CLONE,
+            nr::EXECVE,
+            nr::EXECVEAT,
+            nr::FORK,
+            nr::KILL,
+            nr::SECCOMP,
+            nr::VFORK,
+        ],
+        &[ret(SECCOMP_RET_ERRNO | abi::EPERM as u32)],
+    );
+
     p.inst(ret(SECCOMP_RET_TRACE));
+    p
+}
 
-    p.activate();
+/// The tracer's stacked policy didn't refuse the calls it should have, with
+/// what they returned instead
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PolicyNotInEffect {
+    pub execve: isize,
+    pub kill: isize,
 }
 
-pub fn policy_for_loader() {
+impl fmt::Display for PolicyNotInEffect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tracer seccomp policy not in effect (execve {}, kill {})",
+            self.execve, self.kill
+        )
+    }
+}
+
+/// Make sure the stacked policy is in effect, using calls which are harmless
+/// even if they were allowed
+fn check_tracer_after_init() -> Result<(), PolicyNotInEffect> {
+    let execve = unsafe { syscall!(EXECVE, 0, 0, 0) } as isize;
+    let kill = unsafe { syscall!(KILL, 0, 0) } as isize;
+    if execve == -abi::EPERM as isize && kill == -abi::EPERM as isize {
+        Ok(())
+    } else {
+        Err(PolicyNotInEffect { execve, kill })
+    }
+}
+
+/// Install the notify policy, returning its listener
+///
+/// This runs in the tracer's child just before its exec, so the loader and
+/// everything it starts inherit the policy. Once the tracer receives a
+/// notification, the process waits for the reply even if signals arrive.
+pub fn notify_listener() -> Result<File, Errno> {
+    #[repr(C)]
+    struct FilterProg {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    let p = rules_for_notify();
+    let prog = FilterProg {
+        len: p.instructions().len() as u16,
+        filter: p.instructions().as_ptr(),
+    };
+    let flags = abi::SECCOMP_FILTER_FLAG_NEW_LISTENER | abi::SECCOMP_FILTER_FLAG_WAIT_KILLABLE_RECV;
+    match unsafe {
+        syscall!(
+            SECCOMP,
+            abi::SECCOMP_SET_MODE_FILTER,
+            flags,
+            &prog as *const FilterProg
+        )
+    } as isize
+    {
+        fd if fd >= 0 => Ok(File::new(SysFd(fd as u32))),
+        err => Err(Errno(err as i32)),
+    }
+}
+
+fn rules_for_notify() -> ProgramBuffer {
+    let mut p = ProgramBuffer::new();
+    p.inst(load(offset_of!(SeccompData, nr)));
+
+    // Path lookups the tracer answers without a ptrace stop, see
+    // syscall::notify. Anything else is left to the loader policy.
+    p.if_any_eq(
+        &[nr::ACCESS, nr::LSTAT, nr::OPEN, nr::STAT],
+        &[ret(abi::SECCOMP_RET_USER_NOTIF)],
+    );
+
+    // Only the working directory is answered here, other directory fds
+    // need the tracer's file table
+    p.if_any_eq(
+        &[nr::OPENAT],
+        &[
+            load(arg_offset(0)),
+            jump_if_eq(abi::AT_FDCWD as u32, 0, 1),
+            ret(abi::SECCOMP_RET_USER_NOTIF),
+            ret(SECCOMP_RET_ALLOW),
+        ],
+    );
+    p.if_any_eq(
+        &[nr::NEWFSTATAT],
+        &[
+            load(arg_offset(0)),
+            jump_if_eq(abi::AT_FDCWD as u32, 0, 3),
+            load(arg_offset(3)),
+            jump_if_set(abi::AT_EMPTY_PATH as u32, 1, 0),
+            ret(abi::SECCOMP_RET_USER_NOTIF),
+            ret(SECCOMP_RET_ALLOW),
+        ],
+    );
+
+    p.inst(ret(SECCOMP_RET_ALLOW));
+    p
+}
+
+fn rules_for_traced_clock() -> ProgramBuffer {
+    let mut p = ProgramBuffer::new();
+    p.inst(load(offset_of!(SeccompData, nr)));
+    p.if_any_eq(
+        &[
+            nr::CLOCK_GETRES,
+            nr::CLOCK_GETTIME,
+            nr::GETTIMEOFDAY,
+            nr::TIME,
+        ],
+        &[ret(SECCOMP_RET_TRACE)],
+    );
+    p.inst(ret(SECCOMP_RET_ALLOW));
+    p
+}
+
+fn rules_for_loader() -> ProgramBuffer {
     let mut p = base_rules_for_all_policies();
 
     // Calls to emulate / calls to allow the emulator to remotely issue
     p.if_any_eq(
         &[
+            nr::ACCEPT,
+            nr::ACCEPT4,
             nr::ACCESS,
+            nr::BIND,
             nr::BRK,
             nr::CHDIR,
             nr::CLONE,
             nr::CLOSE,
+            nr::CONNECT,
+            nr::CREAT,
             nr::DUP,
             nr::DUP2,
+            nr::DUP3,
             nr::EXECVE,
+            nr::FACCESSAT,
             nr::FCHDIR,
             nr::FORK,
             nr::FSTAT,
@@ -132,69 +364,442 @@ pub fn policy_for_loader() {
             nr::GETEGID,
             nr::GETEUID,
             nr::GETGID,
+            nr::GETPEERNAME,
             nr::GETPGID,
             nr::GETPGRP,
             nr::GETPID,
             nr::GETPPID,
+            nr::GETSOCKNAME,
             nr::GETTID,
             nr::GETUID,
             nr::IOCTL,
             nr::LSTAT,
+            nr::MKDIR,
+            nr::MKDIRAT,
             nr::NEWFSTATAT,
             nr::OPEN,
             nr::OPENAT,
             nr::READLINK,
+            nr::READLINKAT,
             nr::RECVMSG,
+            nr::RENAME,
+            nr::RENAMEAT,
+            nr::RMDIR,
             nr::SENDMSG,
+            nr::MSGCTL,
+            nr::MSGGET,
+            nr::MSGRCV,
+            nr::MSGSND,
+            nr::SEMCTL,
+            nr::SEMGET,
+            nr::SEMOP,
+            nr::SEMTIMEDOP,
             nr::SETPGID,
+            nr::SETSOCKOPT,
             nr::SET_TID_ADDRESS,
+            nr::SHMAT,
+            nr::SHMCTL,
+            nr::SHMDT,
+            nr::SHMGET,
+            nr::SHUTDOWN,
+            nr::SOCKET,
             nr::STAT,
             nr::STATFS,
             nr::SYSINFO,
             nr::UNAME,
+            nr::UNLINK,
+            nr::UNLINKAT,
+            nr::VFORK,
             nr::WAIT4,
         ],
         &[ret(SECCOMP_RET_TRACE)],
     );
 
-    // Reject network subsystem
+    // fcntl() commands that change the descriptor table are emulated, so
+    // the tracer's file table stays in sync; the rest work on any fd.
+    // fixme: only allow some of the rest
     p.if_any_eq(
+        &[nr::FCNTL],
         &[
-            nr::SOCKET,
-            nr::BIND,
-            nr::LISTEN,
-            nr::CONNECT,
-            nr::ACCEPT,
-            nr::SHUTDOWN,
-            nr::GETSOCKNAME,
-            nr::GETPEERNAME,
-            nr::SOCKETPAIR,
-            nr::SETSOCKOPT,
-            nr::GETSOCKOPT,
+            load(arg_offset(1)),
+            jump_if_eq(abi::F_DUPFD as u32, 3, 0),
+            jump_if_eq(abi::F_DUPFD_CLOEXEC as u32, 2, 0),
+            jump_if_eq(abi::F_SETFD as u32, 1, 0),
+            ret(SECCOMP_RET_ALLOW),
+            ret(SECCOMP_RET_TRACE),
         ],
-        &[ret(SECCOMP_RET_ERRNO | -abi::ENOSYS as u16 as u32)],
+    );
+
+    // Reject the parts of the network subsystem that aren't emulated
+    p.if_any_eq(
+        &[nr::SOCKETPAIR, nr::GETSOCKOPT],
+        &[ret(SECCOMP_RET_ERRNO | abi::ENOSYS as u32)],
     );
 
     // Reject filesystem modification
     p.if_any_eq(
         &[
-            nr::MKDIR,
-            nr::RMDIR,
             nr::CHMOD,
-            nr::CREAT,
             nr::LINK,
-            nr::UNLINK,
             nr::SYMLINK,
             nr::CHMOD,
             nr::FCHMOD,
             nr::CHOWN,
             nr::FCHOWN,
         ],
-        &[ret(SECCOMP_RET_ERRNO | -abi::EROFS as u16 as u32)],
+        &[ret(SECCOMP_RET_ERRNO | abi::EROFS as u32)],
     );
 
-    // All other syscalls panic via SIGSYS
+    // All other syscalls raise SIGSYS in the task
     p.inst(ret(SECCOMP_RET_TRAP));
+    p
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::mem::size_of;
+
+    const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    enum Action {
+        Allow,
+        Notify,
+        Trace,
+        Trap,
+        Errno(i32),
+        Kill,
+    }
 
-    p.activate();
+    fn classify(result: u32) -> Action {
+        let data = result & 0xffff;
+        match result & 0xffff_0000 {
+            SECCOMP_RET_ALLOW => Action::Allow,
+            abi::SECCOMP_RET_USER_NOTIF => Action::Notify,
+            SECCOMP_RET_TRACE => Action::Trace,
+            SECCOMP_RET_TRAP => Action::Trap,
+            SECCOMP_RET_ERRNO => Action::Errno(data as i32),
+            _ => Action::Kill,
+        }
+    }
+
+    fn seccomp_data(nr: usize, args: &[u64; 6]) -> [u8; size_of::<SeccompData>()] {
+        let mut data = [0u8; size_of::<SeccompData>()];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(offset_of!(SeccompData, nr), &(nr as i32).to_le_bytes());
+        put(
+            offset_of!(SeccompData, arch),
+            &AUDIT_ARCH_X86_64.to_le_bytes(),
+        );
+        put(
+            offset_of!(SeccompData, instruction_pointer),
+            &0x4000_1000u64.to_le_bytes(),
+        );
+        for (i, arg) in args.iter().enumerate() {
+            put(offset_of!(SeccompData, args) + 8 * i, &arg.to_le_bytes());
+        }
+        data
+    }
+
+    /// Classic BPF, the subset the kernel accepts for seccomp filters
+    fn run(program: &[SockFilter], data: &[u8]) -> u32 {
+        let load = |k: u32| {
+            let k = k as usize;
+            assert!(k % 4 == 0 && k + 4 <= data.len(), "bad load at {}", k);
+            u32::from_le_bytes([data[k], data[k + 1], data[k + 2], data[k + 3]])
+        };
+        let (mut a, mut x, mut mem) = (0u32, 0u32, [0u32; 16]);
+        let mut pc = 0;
+        loop {
+            let inst = &program[pc];
+            pc += 1;
+            let src = if inst.code & 0x08 == 0 { inst.k } else { x };
+            match inst.code & 0x07 {
+                // ld, ldx
+                0x00 | 0x01 => {
+                    let value = match inst.code & 0xe0 {
+                        0x00 => inst.k,
+                        0x20 => load(inst.k),
+                        0x60 => mem[inst.k as usize],
+                        0x80 => data.len() as u32,
+                        _ => panic!("unsupported load {:#x}", inst.code),
+                    };
+                    assert_eq!(inst.code & 0x18, 0, "only word loads are allowed");
+                    if inst.code & 0x07 == 0 {
+                        a = value;
+                    } else {
+                        x = value;
+                    }
+                }
+                // st, stx
+                0x02 => mem[inst.k as usize] = a,
+                0x03 => mem[inst.k as usize] = x,
+                // alu
+                0x04 => {
+                    a = match inst.code & 0xf0 {
+                        0x00 => a.wrapping_add(src),
+                        0x10 => a.wrapping_sub(src),
+                        0x20 => a.wrapping_mul(src),
+                        0x30 => a / src,
+                        0x40 => a | src,
+                        0x50 => a & src,
+                        0x60 => a.wrapping_shl(src),
+                        0x70 => a.wrapping_shr(src),
+                        0x80 => a.wrapping_neg(),
+                        0x90 => a % src,
+                        0xa0 => a ^ src,
+                        _ => panic!("unsupported alu {:#x}", inst.code),
+                    }
+                }
+                // jmp
+                0x05 => {
+                    let taken = match inst.code & 0xf0 {
+                        0x00 => {
+                            pc += inst.k as usize;
+                            continue;
+                        }
+                        0x10 => a == src,
+                        0x20 => a > src,
+                        0x30 => a >= src,
+                        0x40 => a & src != 0,
+                        _ => panic!("unsupported jump {:#x}", inst.code),
+                    };
+                    let offset = if taken { inst.jt } else { inst.jf };
+                    pc += offset as usize;
+                }
+                // ret
+                0x06 => {
+                    return match inst.code & 0x18 {
+                        0x00 => inst.k,
+                        0x10 => a,
+                        _ => panic!("unsupported ret {:#x}", inst.code),
+                    }
+                }
+                // misc: tax, txa
+                _ => {
+                    if inst.code & 0xf8 == 0 {
+                        x = a;
+                    } else {
+                        a = x;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stacked filters all run, and the lowest action value takes precedence
+    fn run_stacked(programs: &[ProgramBuffer], data: &[u8]) -> u32 {
+        programs
+            .iter()
+            .map(|program| run(program.instructions(), data))
+            .min_by_key(|result| (result & 0xffff_0000) as i32)
+            .unwrap()
+    }
+
+    fn check(programs: &[ProgramBuffer], expected: &[(usize, Action)]) {
+        let arg_patterns = [
+            [0; 6],
+            [!0; 6],
+            [3, 0x7fff_0000_1000, 0o644, 1, 0x8000_0000, 0],
+        ];
+        for (nr, action) in expected {
+            for args in &arg_patterns {
+                check_args(programs, *nr, args, *action);
+            }
+        }
+    }
+
+    fn check_args(programs: &[ProgramBuffer], nr: usize, args: &[u64; 6], action: Action) {
+        let result = run_stacked(programs, &seccomp_data(nr, args));
+        assert_eq!(classify(result), action, "nr={} args={:x?}", nr, args);
+    }
+
+    #[test]
+    fn tracer_init() {
+        check(
+            &[rules_for_tracer_init()],
+            &[
+                (nr::READ, Action::Allow),
+                (nr::MMAP, Action::Allow),
+                (nr::PTRACE, Action::Allow),
+                (nr::SOCKETPAIR, Action::Allow),
+                (nr::FORK, Action::Allow),
+                (nr::EXECVE, Action::Allow),
+                (nr::GETDENTS64, Action::Allow),
+                (nr::PERSONALITY, Action::Allow),
+                (nr::OPENAT, Action::Trace),
+                (nr::KILL, Action::Trace),
+            ],
+        );
+    }
+
+    #[test]
+    fn tracer_after_init() {
+        check(
+            &[rules_for_tracer_init(), rules_for_tracer_after_init()],
+            &[
+                (nr::WRITE, Action::Allow),
+                (nr::WAITID, Action::Allow),
+                (nr::TKILL, Action::Allow),
+                (nr::FORK, Action::Errno(abi::EPERM)),
+                (nr::VFORK, Action::Errno(abi::EPERM)),
+                (nr::EXECVE, Action::Errno(abi::EPERM)),
+                (nr::KILL, Action::Errno(abi::EPERM)),
+                (nr::SECCOMP, Action::Errno(abi::EPERM)),
+                (nr::PPOLL, Action::Allow),
+                (nr::OPEN, Action::Trace),
+                (nr::GETDENTS64, Action::Trace),
+                (999, Action::Trace),
+            ],
+        );
+    }
+
+    #[test]
+    fn tracer_ioctl() {
+        let programs = [rules_for_tracer_init(), rules_for_tracer_after_init()];
+        for request in &[
+            abi::SECCOMP_IOCTL_NOTIF_RECV,
+            abi::SECCOMP_IOCTL_NOTIF_SEND,
+            abi::SECCOMP_IOCTL_NOTIF_ID_VALID,
+            abi::SECCOMP_IOCTL_NOTIF_ADDFD,
+        ] {
+            let args = [5, *request as u64, 0x7fff_0000_1000, 0, 0, 0];
+            check_args(&programs, nr::IOCTL, &args, Action::Allow);
+        }
+        let tcgets = [1, 0x5401, 0x7fff_0000_1000, 0, 0, 0];
+        check_args(&programs, nr::IOCTL, &tcgets, Action::Trace);
+    }
+
+    #[test]
+    fn loader() {
+        check(
+            &[rules_for_tracer_init(), rules_for_loader()],
+            &[
+                (nr::READ, Action::Allow),
+                (nr::EXIT_GROUP, Action::Allow),
+                (nr::DUP3, Action::Trace),
+                (nr::OPENAT, Action::Trace),
+                (nr::FACCESSAT, Action::Trace),
+                (nr::EXECVE, Action::Trace),
+                (nr::GETPID, Action::Trace),
+                (nr::GETDENTS64, Action::Trace),
+                (nr::READLINK, Action::Trace),
+                (nr::READLINKAT, Action::Trace),
+                (nr::CLOCK_GETTIME, Action::Allow),
+                (nr::GETTIMEOFDAY, Action::Allow),
+                (nr::TIME, Action::Allow),
+                (nr::ALARM, Action::Allow),
+                (nr::SETITIMER, Action::Allow),
+                (nr::TIMER_CREATE, Action::Allow),
+                (nr::TIMER_SETTIME, Action::Allow),
+                (nr::SENDMSG, Action::Trace),
+                (nr::RECVMSG, Action::Trace),
+                (nr::SOCKET, Action::Trace),
+                (nr::CONNECT, Action::Trace),
+                (nr::LISTEN, Action::Allow),
+                (nr::ACCEPT, Action::Trace),
+                (nr::ACCEPT4, Action::Trace),
+                (nr::GETSOCKNAME, Action::Trace),
+                (nr::GETPEERNAME, Action::Trace),
+                (nr::SOCKETPAIR, Action::Errno(abi::ENOSYS)),
+                (nr::GETSOCKOPT, Action::Errno(abi::ENOSYS)),
+                (nr::CHMOD, Action::Errno(abi::EROFS)),
+                (nr::SYMLINK, Action::Errno(abi::EROFS)),
+                (nr::PTRACE, Action::Trap),
+                (nr::WAITID, Action::Trap),
+                (999, Action::Trap),
+            ],
+        );
+    }
+
+    #[test]
+    fn traced_clock() {
+        check(
+            &[
+                rules_for_tracer_init(),
+                rules_for_traced_clock(),
+                rules_for_loader(),
+            ],
+            &[
+                (nr::CLOCK_GETRES, Action::Trace),
+                (nr::CLOCK_GETTIME, Action::Trace),
+                (nr::GETTIMEOFDAY, Action::Trace),
+                (nr::TIME, Action::Trace),
+                (nr::TIMER_GETTIME, Action::Allow),
+                (nr::READ, Action::Allow),
+                (nr::OPENAT, Action::Trace),
+                (nr::PTRACE, Action::Trap),
+            ],
+        );
+    }
+
+    #[test]
+    fn loader_fcntl() {
+        let programs = [rules_for_tracer_init(), rules_for_loader()];
+        for op in &[abi::F_GETFD, abi::F_GETFL, abi::F_SETFL, abi::F_GET_SEALS] {
+            let args = [3, *op as u64, 0, 0, 0, 0];
+            check_args(&programs, nr::FCNTL, &args, Action::Allow);
+        }
+        for op in &[abi::F_DUPFD, abi::F_DUPFD_CLOEXEC, abi::F_SETFD] {
+            let args = [3, *op as u64, 0, 0, 0, 0];
+            check_args(&programs, nr::FCNTL, &args, Action::Trace);
+        }
+        let tracer = [rules_for_tracer_init(), rules_for_tracer_after_init()];
+        let setfd = [3, abi::F_SETFD as u64, abi::F_CLOEXEC as u64, 0, 0, 0];
+        check_args(&tracer, nr::FCNTL, &setfd, Action::Allow);
+    }
+
+    #[test]
+    fn notify() {
+        let programs = [
+            rules_for_tracer_init(),
+            rules_for_notify(),
+            rules_for_loader(),
+        ];
+        check(
+            &programs,
+            &[
+                (nr::OPEN, Action::Notify),
+                (nr::STAT, Action::Notify),
+                (nr::LSTAT, Action::Notify),
+                (nr::ACCESS, Action::Notify),
+                (nr::READ, Action::Allow),
+                (nr::EXECVE, Action::Trace),
+                (nr::SOCKET, Action::Trace),
+                (nr::PTRACE, Action::Trap),
+            ],
+        );
+        let cwd = abi::AT_FDCWD as u64;
+        let path = 0x7fff_0000_1000;
+        let nofollow = abi::AT_SYMLINK_NOFOLLOW as u64;
+        let empty_path = abi::AT_EMPTY_PATH as u64;
+        check_args(
+            &programs,
+            nr::OPENAT,
+            &[cwd, path, 0, 0, 0, 0],
+            Action::Notify,
+        );
+        check_args(&programs, nr::OPENAT, &[3, path, 0, 0, 0, 0], Action::Trace);
+        check_args(
+            &programs,
+            nr::NEWFSTATAT,
+            &[cwd, path, path, nofollow, 0, 0],
+            Action::Notify,
+        );
+        check_args(
+            &programs,
+            nr::NEWFSTATAT,
+            &[cwd, path, path, empty_path, 0, 0],
+            Action::Trace,
+        );
+        check_args(
+            &programs,
+            nr::NEWFSTATAT,
+            &[3, path, path, 0, 0, 0],
+            Action::Trace,
+        );
+    }
 }
@@ -2,20 +2,25 @@ use crate::{
     abi,
     binformat::Exec,
     mem::string::VStringArray,
-    process::task::StoppedTask,
+    nolibc::{tkill, TempFile},
+    process::{
+        table::OpenFile,
+        task::{StoppedTask, Task},
+    },
     protocol::{
-        abi::Syscall, Errno, FileStat, FollowLinks, FromTask, LogLevel, LogMessage, SysFd, ToTask,
-        VFile, VPtr, VString,
+        abi::Syscall, AuditEvent, Errno, FileContents, FileStat, FollowLinks, FromTask, LogLevel,
+        LogMessage, SysFd, ToTask, VFile, VPtr, VString,
     },
     remote::{file::RemoteFd, trampoline::Trampoline},
     syscall,
     syscall::result::SyscallResult,
 };
+use alloc::rc::Rc;
 use plain::Plain;
 use sc::nr;
 
 #[repr(C)]
-struct UserStat(abi::Stat);
+pub struct UserStat(abi::Stat);
 
 #[repr(C)]
 struct UserStatFs(abi::StatFs);
@@ -23,6 +28,59 @@ struct UserStatFs(abi::StatFs);
 unsafe impl Plain for UserStat {}
 unsafe impl Plain for UserStatFs {}
 
+impl UserStat {
+    pub fn new(vfile: VFile, file_stat: &FileStat) -> Self {
+        UserStat(abi::Stat {
+            st_dev: file_stat.st_dev,
+            st_ino: vfile.inode as u64,
+            st_nlink: file_stat.st_nlink,
+            st_mode: file_stat.st_mode,
+            st_uid: file_stat.st_uid,
+            st_gid: file_stat.st_gid,
+            pad0: 0,
+            st_rdev: file_stat.st_rdev,
+            st_size: file_stat.st_size,
+            st_blksize: 4096,
+            st_blocks: (file_stat.st_size + 511) / 512,
+            st_atime: file_stat.st_atime,
+            st_atime_nsec: file_stat.st_atime_nsec,
+            st_mtime: file_stat.st_mtime,
+            st_mtime_nsec: file_stat.st_mtime_nsec,
+            st_ctime: file_stat.st_ctime,
+            st_ctime_nsec: file_stat.st_ctime_nsec,
+            unused: [0; 3],
+        })
+    }
+}
+
+/// Log a syscall that has been emulated, and watch for storms of them
+pub fn log_emulated(task: &mut Task<'_>, log_level: LogLevel, call: &Syscall) {
+    if task.log_enabled(log_level) {
+        task.log(log_level, LogMessage::Emulated(call.clone()))
+    }
+
+    // A storm is the guest's own doing, so it only ever costs the guest
+    if task.storm.observe(call) {
+        task.log(LogLevel::Warn, LogMessage::SyscallStorm(call.clone()));
+        task.msg.send(FromTask::SyscallStorm(call.clone()));
+        if task.task_data.tracer_settings.abort_on_syscall_storm {
+            // Its exit is reported like any other death by signal
+            let _ = tkill(task.task_data.sys_pid, abi::SIGKILL);
+        }
+    }
+}
+
+/// Kill a task whose syscall failed the instruction pointer check
+///
+/// Nothing it asked for can be trusted, but it can only have fooled itself,
+/// so the tracer carries on with the rest of the sandbox.
+pub fn kill_unverified(task: &mut Task<'_>, nr: isize, ip: VPtr) {
+    let event = AuditEvent::UnverifiedSyscall { nr, ip };
+    task.log(LogLevel::Warn, LogMessage::Audit(event));
+    // Its exit is reported like any other death by signal
+    let _ = tkill(task.task_data.sys_pid, abi::SIGKILL);
+}
+
 #[derive(Debug)]
 pub struct SyscallEmulator<'q, 's, 't> {
     stopped_task: &'t mut StoppedTask<'q, 's>,
@@ -35,17 +93,33 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
         SyscallEmulator { stopped_task, call }
     }
 
-    async fn return_file(&mut self, vfile: VFile, sys_fd: &SysFd) -> Result<RemoteFd, Errno> {
+    async fn return_file(
+        &mut self,
+        vfile: VFile,
+        open_flags: i32,
+        sys_fd: &SysFd,
+    ) -> Result<RemoteFd, Errno> {
+        let file = OpenFile::new(vfile, open_flags);
         let mut tr = Trampoline::new(self.stopped_task);
-        let result = syscall::result::file(&mut tr, sys_fd).await;
-        if let Ok(fd) = &result {
-            self.stopped_task
-                .task
-                .task_data
-                .file_table
-                .open(fd.clone(), vfile);
+        let fd = syscall::result::file(&mut tr, sys_fd).await?;
+        let mut result = Ok(0);
+        if file.status_flags() != 0 {
+            result = fd.fcntl(&mut tr, abi::F_SETFL, file.status_flags()).await;
+        }
+        let cloexec = open_flags as usize & abi::O_CLOEXEC != 0;
+        if result.is_ok() && cloexec {
+            result = fd.fcntl(&mut tr, abi::F_SETFD, abi::F_CLOEXEC).await;
         }
-        result
+        if let Err(err) = result {
+            fd.close(&mut tr).await?;
+            return Err(err);
+        }
+        self.stopped_task
+            .task
+            .task_data
+            .file_table
+            .open(fd.clone(), Rc::new(file), cloexec);
+        Ok(fd)
     }
 
     async fn return_local_bytes(&mut self, bytes: &[u8], to_ptr: VPtr) -> Result<(), Errno> {
@@ -69,26 +143,7 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
         vfile: VFile,
         file_stat: &FileStat,
     ) -> Result<(), Errno> {
-        let result = UserStat(abi::Stat {
-            st_dev: file_stat.st_dev,
-            st_ino: vfile.inode as u64,
-            st_nlink: file_stat.st_nlink,
-            st_mode: file_stat.st_mode,
-            st_uid: file_stat.st_uid,
-            st_gid: file_stat.st_gid,
-            pad0: 0,
-            st_rdev: file_stat.st_rdev,
-            st_size: file_stat.st_size,
-            st_blksize: 4096,
-            st_blocks: (file_stat.st_size + 511) / 512,
-            st_atime: file_stat.st_atime,
-            st_atime_nsec: file_stat.st_atime_nsec,
-            st_mtime: file_stat.st_mtime,
-            st_mtime_nsec: file_stat.st_mtime_nsec,
-            st_ctime: file_stat.st_ctime,
-            st_ctime_nsec: file_stat.st_ctime_nsec,
-            unused: [0; 3],
-        });
+        let result = UserStat::new(vfile, file_stat);
         self.return_local_bytes(unsafe { plain::as_bytes(&result) }, out_ptr)
             .await
     }
@@ -114,12 +169,12 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
 
     async fn return_file_result(
         &mut self,
-        result: Result<(VFile, SysFd), Errno>,
+        result: Result<(VFile, FileContents), Errno>,
+        open_flags: i32,
     ) -> Result<RemoteFd, Errno> {
-        match result {
-            Err(err) => Err(err),
-            Ok((vfile, sys_fd)) => self.return_file(vfile, &sys_fd).await,
-        }
+        let (vfile, contents) = result?;
+        let file = TempFile::from_contents(contents)?;
+        self.return_file(vfile, open_flags, &file.0.fd).await
     }
 
     async fn return_stat_result(
@@ -131,6 +186,213 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
         self.return_stat(out_ptr, vfile, &file_stat).await
     }
 
+    /// Stat an open file, leaving descriptors outside the virtual filesystem
+    /// to the kernel
+    async fn return_fstat(&mut self, fd: RemoteFd, out_ptr: VPtr) -> Result<(), Errno> {
+        let table = &self.stopped_task.task.task_data.file_table;
+        if table.get(&fd).is_err() {
+            return syscall::fs::kernel_fstat(self.stopped_task, fd, out_ptr).await;
+        }
+        let result = syscall::fs::fstat(self.stopped_task, fd).await;
+        self.return_stat_result(out_ptr, result).await
+    }
+
+    async fn return_fstatat(
+        &mut self,
+        dir_fd: i32,
+        path: VString,
+        out_ptr: VPtr,
+        flags: i32,
+    ) -> Result<(), Errno> {
+        let known_flags = abi::AT_SYMLINK_NOFOLLOW | abi::AT_NO_AUTOMOUNT | abi::AT_EMPTY_PATH;
+        if flags & !known_flags != 0 {
+            return Err(Errno::new(abi::EINVAL));
+        }
+        let path = if (flags & abi::AT_EMPTY_PATH) != 0
+            && syscall::fs::is_empty_path(self.stopped_task, &path)?
+        {
+            None
+        } else {
+            Some(path)
+        };
+        let file = match (dir_fd, &path) {
+            (abi::AT_FDCWD, _) => None,
+            (_, None) => return self.return_fstat(RemoteFd(dir_fd as u32), out_ptr).await,
+            (_, Some(path)) => self.dir_file(dir_fd, path)?,
+        };
+        let result = ipc_call!(
+            self.stopped_task.task,
+            FromTask::FileStat {
+                file: file.clone(),
+                path,
+                follow_links: if (flags & abi::AT_SYMLINK_NOFOLLOW) != 0 {
+                    FollowLinks::NoFollow
+                } else {
+                    FollowLinks::Follow
+                }
+            },
+            ToTask::FileStatReply(result),
+            result
+        );
+        self.return_stat_result(out_ptr, result).await
+    }
+
+    /// Look up the directory an `*at` syscall's path is relative to, or
+    /// `None` for the working directory
+    ///
+    /// Absolute paths ignore the directory, which doesn't even need to be an
+    /// open file.
+    fn dir_file(&mut self, dir_fd: i32, path: &VString) -> Result<Option<VFile>, Errno> {
+        if dir_fd == abi::AT_FDCWD || syscall::fs::is_absolute_path(self.stopped_task, path)? {
+            return Ok(None);
+        }
+        let table = &self.stopped_task.task.task_data.file_table;
+        Ok(Some(table.get(&RemoteFd(dir_fd as u32))?.vfile.clone()))
+    }
+
+    async fn return_mkdirat(&mut self, dir_fd: i32, path: VString, mode: i32) -> Result<(), Errno> {
+        let dir = self.dir_file(dir_fd, &path)?;
+        ipc_call!(
+            self.stopped_task.task,
+            FromTask::MakeDir { dir, path, mode },
+            ToTask::Reply(result),
+            result
+        )
+    }
+
+    async fn return_unlinkat(
+        &mut self,
+        dir_fd: i32,
+        path: VString,
+        flags: i32,
+    ) -> Result<(), Errno> {
+        let dir = self.dir_file(dir_fd, &path)?;
+        ipc_call!(
+            self.stopped_task.task,
+            FromTask::Unlink {
+                dir,
+                path,
+                remove_dir: (flags & abi::AT_REMOVEDIR) != 0,
+            },
+            ToTask::Reply(result),
+            result
+        )
+    }
+
+    async fn return_renameat(
+        &mut self,
+        from_dir_fd: i32,
+        from: VString,
+        to_dir_fd: i32,
+        to: VString,
+    ) -> Result<(), Errno> {
+        let from_dir = self.dir_file(from_dir_fd, &from)?;
+        let to_dir = self.dir_file(to_dir_fd, &to)?;
+        ipc_call!(
+            self.stopped_task.task,
+            FromTask::Rename {
+                from_dir,
+                from,
+                to_dir,
+                to,
+            },
+            ToTask::Reply(result),
+            result
+        )
+    }
+
+    async fn return_openat(
+        &mut self,
+        dir_fd: i32,
+        path: VString,
+        flags: i32,
+        mode: i32,
+    ) -> Result<RemoteFd, Errno> {
+        let dir = self.dir_file(dir_fd, &path)?;
+        let result = ipc_call!(
+            self.stopped_task.task,
+            FromTask::FileOpen {
+                dir: dir.clone(),
+                path,
+                flags,
+                mode,
+            },
+            ToTask::FileReply(result),
+            result
+        );
+        self.return_file_result(result, flags).await
+    }
+
+    async fn return_faccessat(
+        &mut self,
+        dir_fd: i32,
+        path: VString,
+        mode: i32,
+    ) -> Result<(), Errno> {
+        let dir = self.dir_file(dir_fd, &path)?;
+        ipc_call!(
+            self.stopped_task.task,
+            FromTask::FileAccess {
+                dir: dir.clone(),
+                path,
+                mode,
+            },
+            ToTask::Reply(result),
+            result
+        )
+    }
+
+    async fn return_readlinkat(
+        &mut self,
+        dir_fd: i32,
+        path: VString,
+        buffer: VPtr,
+        buffer_len: usize,
+    ) -> Result<usize, Errno> {
+        let dir = self.dir_file(dir_fd, &path)?;
+        let result = ipc_call!(
+            self.stopped_task.task,
+            FromTask::ReadLink {
+                dir: dir.clone(),
+                path
+            },
+            ToTask::BytesReply(result),
+            result
+        );
+        self.return_bytes_result(result, buffer, buffer_len).await
+    }
+
+    async fn return_getcwd(&mut self, buffer: VPtr, buffer_len: usize) -> Result<usize, Errno> {
+        let result = ipc_call!(
+            self.stopped_task.task,
+            FromTask::GetWorkingDir,
+            ToTask::BytesReply(result),
+            result
+        );
+        // Unlike readlink, getcwd never returns a partial path
+        if let Ok((_, result_len)) = &result {
+            if *result_len > buffer_len {
+                return Err(Errno::new(abi::ERANGE));
+            }
+        }
+        self.return_bytes_result(result, buffer, buffer_len).await
+    }
+
+    async fn return_fchdir(&mut self, fd: RemoteFd) -> Result<(), Errno> {
+        // Directories are only ever opened through the virtual filesystem
+        let table = &self.stopped_task.task.task_data.file_table;
+        let file = table.get(&fd)?.vfile.clone();
+        ipc_call!(
+            self.stopped_task.task,
+            FromTask::ChangeWorkingDir {
+                file: Some(file.clone()),
+                path: None,
+            },
+            ToTask::Reply(result),
+            result
+        )
+    }
+
     async fn return_bytes_result(
         &mut self,
         result: Result<(SysFd, usize), Errno>,
@@ -158,7 +420,21 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 .await
                 .into(),
 
-            nr::FORK => syscall::user::fork(self.stopped_task).await.into(),
+            nr::FORK => syscall::user::clone(self.stopped_task, abi::SIGCHLD as usize, &[0; 6])
+                .await
+                .into(),
+
+            nr::VFORK => syscall::user::clone(
+                self.stopped_task,
+                abi::CLONE_VM | abi::CLONE_VFORK | abi::SIGCHLD as usize,
+                &[0; 6],
+            )
+            .await
+            .into(),
+
+            nr::CLONE => syscall::user::clone(self.stopped_task, arg_usize(0), &args)
+                .await
+                .into(),
 
             nr::EXECVE => Exec {
                 filename: arg_string(0),
@@ -173,16 +449,99 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 .await
                 .into(),
 
+            nr::CLOCK_GETTIME | nr::CLOCK_GETRES | nr::GETTIMEOFDAY | nr::TIME => {
+                syscall::user::clock(self.stopped_task, self.call.nr as usize, &args)
+                    .await
+                    .into()
+            }
+
             nr::DUP => syscall::fs::dup(self.stopped_task, arg_fd(0)).await.into(),
 
             nr::DUP2 => syscall::fs::dup2(self.stopped_task, arg_fd(0), arg_fd(1))
                 .await
                 .into(),
 
-            nr::GETPID => self.stopped_task.task.task_data.vpid.into(),
+            nr::DUP3 => syscall::fs::dup3(self.stopped_task, arg_fd(0), arg_fd(1), arg_usize(2))
+                .await
+                .into(),
+
+            nr::FCNTL => {
+                syscall::fs::fcntl(self.stopped_task, arg_fd(0), arg_usize(1), arg_usize(2))
+                    .await
+                    .into()
+            }
+
+            nr::SOCKET => {
+                syscall::net::socket(self.stopped_task, arg_usize(0), arg_usize(1), arg_usize(2))
+                    .await
+                    .into()
+            }
+
+            nr::BIND => syscall::net::bind(self.stopped_task, arg_fd(0), arg_ptr(1), arg_usize(2))
+                .await
+                .into(),
+
+            nr::CONNECT => {
+                syscall::net::connect(self.stopped_task, arg_fd(0), arg_ptr(1), arg_usize(2))
+                    .await
+                    .into()
+            }
+
+            nr::SETSOCKOPT => {
+                syscall::net::setsockopt(self.stopped_task, arg_fd(0), arg_i32(1), &args)
+                    .await
+                    .into()
+            }
+
+            nr::SHUTDOWN => syscall::net::shutdown(self.stopped_task, arg_fd(0), &args[..2])
+                .await
+                .into(),
+
+            nr::ACCEPT => {
+                syscall::net::accept(self.stopped_task, arg_fd(0), arg_ptr(1), arg_ptr(2), 0)
+                    .await
+                    .into()
+            }
+
+            nr::ACCEPT4 => syscall::net::accept(
+                self.stopped_task,
+                arg_fd(0),
+                arg_ptr(1),
+                arg_ptr(2),
+                arg_usize(3),
+            )
+            .await
+            .into(),
+
+            nr::GETSOCKNAME | nr::GETPEERNAME => syscall::net::sockname(
+                self.stopped_task,
+                self.call.nr as usize,
+                arg_fd(0),
+                arg_ptr(1),
+                arg_ptr(2),
+            )
+            .await
+            .into(),
+
+            nr::SENDMSG | nr::RECVMSG => syscall::net::message(
+                self.stopped_task,
+                self.call.nr as usize,
+                arg_fd(0),
+                &args[..3],
+            )
+            .await
+            .into(),
+
+            nr::GETPID => {
+                let task_data = &self.stopped_task.task.task_data;
+                task_data.leader.unwrap_or(task_data.vpid).into()
+            }
             nr::GETTID => self.stopped_task.task.task_data.vpid.into(),
 
-            nr::GETPPID => SyscallResult(1),
+            nr::GETPPID => match self.stopped_task.task.task_data.parent {
+                Some(parent) => parent.into(),
+                None => SyscallResult(1),
+            },
             nr::GETUID => SyscallResult(0),
             nr::GETGID => SyscallResult(0),
             nr::GETEUID => SyscallResult(0),
@@ -193,11 +552,36 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
 
             nr::SYSINFO => SyscallResult(0),
 
-            nr::SET_TID_ADDRESS => SyscallResult(0),
+            // Returns the caller's thread ID, which libc keeps for itself
+            nr::SET_TID_ADDRESS => self.stopped_task.task.task_data.vpid.into(),
 
-            nr::WAIT4 => Errno(-abi::ECHILD).into(),
+            nr::WAIT4 => syscall::user::wait4(
+                self.stopped_task,
+                arg_i32(0),
+                arg_ptr(1),
+                arg_usize(2),
+                arg_ptr(3),
+            )
+            .await
+            .into(),
 
-            nr::CLONE => panic!("clone"),
+            // System V IPC is not available in the sandbox. Fail predictably, and
+            // log each attempt so it's visible why a program gave up.
+            nr::SHMGET
+            | nr::SHMAT
+            | nr::SHMCTL
+            | nr::SHMDT
+            | nr::SEMGET
+            | nr::SEMOP
+            | nr::SEMCTL
+            | nr::SEMTIMEDOP
+            | nr::MSGGET
+            | nr::MSGSND
+            | nr::MSGRCV
+            | nr::MSGCTL => {
+                log_level = LogLevel::Warn;
+                Errno::new(abi::ENOSYS).into()
+            }
 
             nr::IOCTL => {
                 let _fd = arg_fd(0);
@@ -217,10 +601,7 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 self.return_stat_result(arg_ptr(1), result).await.into()
             ),
 
-            nr::FSTAT => {
-                let result = syscall::fs::fstat(self.stopped_task, arg_fd(0)).await;
-                self.return_stat_result(arg_ptr(1), result).await.into()
-            }
+            nr::FSTAT => self.return_fstat(arg_fd(0), arg_ptr(1)).await.into(),
 
             nr::LSTAT => ipc_call!(
                 self.stopped_task.task,
@@ -233,29 +614,10 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 self.return_stat_result(arg_ptr(1), result).await.into()
             ),
 
-            nr::NEWFSTATAT => {
-                log_level = LogLevel::Warn;
-                let flags = arg_i32(3);
-                let fd = arg_i32(0);
-                if fd != abi::AT_FDCWD {
-                    unimplemented!();
-                }
-                let result = ipc_call!(
-                    self.stopped_task.task,
-                    FromTask::FileStat {
-                        file: None,
-                        path: Some(arg_string(1)),
-                        follow_links: if (flags & abi::AT_SYMLINK_NOFOLLOW) != 0 {
-                            FollowLinks::NoFollow
-                        } else {
-                            FollowLinks::Follow
-                        }
-                    },
-                    ToTask::FileStatReply(result),
-                    result
-                );
-                self.return_stat_result(arg_ptr(2), result).await.into()
-            }
+            nr::NEWFSTATAT => self
+                .return_fstatat(arg_i32(0), arg_string(1), arg_ptr(2), arg_i32(3))
+                .await
+                .into(),
 
             nr::STATFS => self.return_statfs(arg_ptr(1)).await.into(),
             nr::FSTATFS => self.return_statfs(arg_ptr(1)).await.into(),
@@ -271,24 +633,30 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                 result.into()
             ),
 
-            nr::GETCWD => ipc_call!(
-                self.stopped_task.task,
-                FromTask::GetWorkingDir,
-                ToTask::BytesReply(result),
-                self.return_bytes_result(result, arg_ptr(0), arg_usize(1))
-                    .await
-                    .into()
-            ),
+            nr::FACCESSAT => self
+                .return_faccessat(arg_i32(0), arg_string(1), arg_i32(2))
+                .await
+                .into(),
+
+            nr::GETCWD => self.return_getcwd(arg_ptr(0), arg_usize(1)).await.into(),
 
             nr::READLINK => ipc_call!(
                 self.stopped_task.task,
-                FromTask::ReadLink(arg_string(0)),
+                FromTask::ReadLink {
+                    dir: None,
+                    path: arg_string(0),
+                },
                 ToTask::BytesReply(result),
                 self.return_bytes_result(result, arg_ptr(1), arg_usize(2))
                     .await
                     .into()
             ),
 
+            nr::READLINKAT => self
+                .return_readlinkat(arg_i32(0), arg_string(1), arg_ptr(2), arg_usize(3))
+                .await
+                .into(),
+
             nr::GETDENTS64 => {
                 syscall::fs::getdents(self.stopped_task, arg_fd(0), arg_ptr(1), arg_usize(2))
                     .await
@@ -296,12 +664,15 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
 
             nr::CHDIR => ipc_call!(
                 self.stopped_task.task,
-                FromTask::ChangeWorkingDir(arg_string(0)),
+                FromTask::ChangeWorkingDir {
+                    file: None,
+                    path: Some(arg_string(0)),
+                },
                 ToTask::Reply(result),
                 result.into()
             ),
 
-            nr::FCHDIR => SyscallResult(0),
+            nr::FCHDIR => self.return_fchdir(arg_fd(0)).await.into(),
 
             nr::OPEN => ipc_call!(
                 self.stopped_task.task,
@@ -312,41 +683,101 @@ impl<'q, 's, 't> SyscallEmulator<'q, 's, 't> {
                     mode: arg_i32(2),
                 },
                 ToTask::FileReply(result),
-                self.return_file_result(result).await.into()
+                self.return_file_result(result, arg_i32(1)).await.into()
             ),
 
-            nr::CLOSE => syscall::fs::close(self.stopped_task, arg_fd(0))
-                .await
-                .into(),
-
-            nr::OPENAT if arg_i32(0) == abi::AT_FDCWD => {
-                let fd = arg_i32(0);
-                if fd != abi::AT_FDCWD {
-                    log_level = LogLevel::Error;
-                }
-                let result = ipc_call!(
+            nr::CREAT => {
+                let flags = (abi::O_CREAT | abi::O_WRONLY | abi::O_TRUNC) as i32;
+                ipc_call!(
                     self.stopped_task.task,
                     FromTask::FileOpen {
                         dir: None,
-                        path: arg_string(1),
-                        flags: arg_i32(2),
-                        mode: arg_i32(3),
+                        path: arg_string(0),
+                        flags,
+                        mode: arg_i32(1),
                     },
                     ToTask::FileReply(result),
-                    result
-                );
-                self.return_file_result(result).await.into()
+                    self.return_file_result(result, flags).await.into()
+                )
             }
 
-            _ => panic!("unexpected {:?}", self.call),
+            nr::MKDIR => ipc_call!(
+                self.stopped_task.task,
+                FromTask::MakeDir {
+                    dir: None,
+                    path: arg_string(0),
+                    mode: arg_i32(1),
+                },
+                ToTask::Reply(result),
+                result.into()
+            ),
+
+            nr::MKDIRAT => self
+                .return_mkdirat(arg_i32(0), arg_string(1), arg_i32(2))
+                .await
+                .into(),
+
+            nr::UNLINK => ipc_call!(
+                self.stopped_task.task,
+                FromTask::Unlink {
+                    dir: None,
+                    path: arg_string(0),
+                    remove_dir: false,
+                },
+                ToTask::Reply(result),
+                result.into()
+            ),
+
+            nr::RMDIR => ipc_call!(
+                self.stopped_task.task,
+                FromTask::Unlink {
+                    dir: None,
+                    path: arg_string(0),
+                    remove_dir: true,
+                },
+                ToTask::Reply(result),
+                result.into()
+            ),
+
+            nr::UNLINKAT => self
+                .return_unlinkat(arg_i32(0), arg_string(1), arg_i32(2))
+                .await
+                .into(),
+
+            nr::RENAME => ipc_call!(
+                self.stopped_task.task,
+                FromTask::Rename {
+                    from_dir: None,
+                    from: arg_string(0),
+                    to_dir: None,
+                    to: arg_string(1),
+                },
+                ToTask::Reply(result),
+                result.into()
+            ),
+
+            nr::RENAMEAT => self
+                .return_renameat(arg_i32(0), arg_string(1), arg_i32(2), arg_string(3))
+                .await
+                .into(),
+
+            nr::CLOSE => syscall::fs::close(self.stopped_task, arg_fd(0))
+                .await
+                .into(),
+
+            nr::OPENAT => self
+                .return_openat(arg_i32(0), arg_string(1), arg_i32(2), arg_i32(3))
+                .await
+                .into(),
+
+            // Only reachable if the seccomp filter and this table disagree
+            _ => {
+                log_level = LogLevel::Warn;
+                Errno::new(abi::ENOSYS).into()
+            }
         };
         self.call.ret = result.0;
         Syscall::ret_to_regs(self.call.ret, self.stopped_task.regs);
-
-        if self.stopped_task.task.log_enabled(log_level) {
-            self.stopped_task
-                .task
-                .log(log_level, LogMessage::Emulated(self.call.clone()))
-        }
+        log_emulated(self.stopped_task.task, log_level, &self.call);
     }
 }
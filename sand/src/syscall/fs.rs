@@ -1,6 +1,8 @@
 use crate::{
+    abi,
+    mem::rw::read_bytes,
     process::task::StoppedTask,
-    protocol::{Errno, FileStat, FollowLinks, FromTask, ToTask, VFile, VPtr},
+    protocol::{Errno, FileStat, FollowLinks, FromTask, ToTask, VFile, VPtr, VString},
     remote::{file::RemoteFd, trampoline::Trampoline},
     syscall::result::SyscallResult,
 };
@@ -23,7 +25,19 @@ pub async fn getdents(
     )
 }
 
+/// The task socket is inherited by the sandboxed process so the tracer can
+/// pass it files, but to the process it looks like a descriptor that isn't
+/// open
+fn check_not_task_socket(stopped_task: &StoppedTask<'_, '_>, fd: &RemoteFd) -> Result<(), Errno> {
+    if fd == &stopped_task.task.task_data.socket_pair.remote {
+        Err(Errno::new(abi::EBADF))
+    } else {
+        Ok(())
+    }
+}
+
 pub async fn dup(stopped_task: &mut StoppedTask<'_, '_>, src_fd: RemoteFd) -> Result<RemoteFd, Errno> {
+    check_not_task_socket(stopped_task, &src_fd)?;
     let mut tr = Trampoline::new(stopped_task);
     let result = tr.syscall(sc::nr::DUP, &[src_fd.0 as isize]).await;
     if result < 0 {
@@ -31,24 +45,77 @@ pub async fn dup(stopped_task: &mut StoppedTask<'_, '_>, src_fd: RemoteFd) -> Re
     } else {
         let table = &mut stopped_task.task.task_data.file_table;
         let dest_fd = RemoteFd(result as u32);
-        table.dup(&src_fd, &dest_fd)?;
+        table.dup(&src_fd, &dest_fd, false);
         Ok(dest_fd)
     }
 }
 
 pub async fn dup2(stopped_task: &mut StoppedTask<'_, '_>, src_fd: RemoteFd, dest_fd: RemoteFd) -> Result<RemoteFd, Errno> {
+    check_not_task_socket(stopped_task, &src_fd)?;
+    check_not_task_socket(stopped_task, &dest_fd)?;
     let mut tr = Trampoline::new(stopped_task);
     let result = tr.syscall(sc::nr::DUP2, &[src_fd.0 as isize, dest_fd.0 as isize]).await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        assert_eq!(result, dest_fd.0 as isize);
+        // Duplicating a descriptor onto itself leaves its flags alone
+        if src_fd != dest_fd {
+            let table = &mut stopped_task.task.task_data.file_table;
+            table.dup(&src_fd, &dest_fd, false);
+        }
+        Ok(dest_fd)
+    }
+}
+
+pub async fn dup3(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    src_fd: RemoteFd,
+    dest_fd: RemoteFd,
+    flags: usize,
+) -> Result<RemoteFd, Errno> {
+    check_not_task_socket(stopped_task, &src_fd)?;
+    check_not_task_socket(stopped_task, &dest_fd)?;
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr
+        .syscall(
+            sc::nr::DUP3,
+            &[src_fd.0 as isize, dest_fd.0 as isize, flags as isize],
+        )
+        .await;
     if result < 0 {
         Err(Errno(result as i32))
     } else {
         assert_eq!(result, dest_fd.0 as isize);
         let table = &mut stopped_task.task.task_data.file_table;
-        table.dup(&src_fd, &dest_fd)?;
+        table.dup(&src_fd, &dest_fd, flags & abi::O_CLOEXEC != 0);
         Ok(dest_fd)
     }
 }
 
+/// The fcntl() commands which change the descriptor table; the rest are
+/// allowed by the seccomp policy without a stop
+pub async fn fcntl(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    op: usize,
+    arg: usize,
+) -> Result<usize, Errno> {
+    check_not_task_socket(stopped_task, &fd)?;
+    let mut tr = Trampoline::new(stopped_task);
+    let result = fd.fcntl(&mut tr, op, arg).await? as usize;
+    let table = &mut stopped_task.task.task_data.file_table;
+    match op {
+        abi::F_DUPFD | abi::F_DUPFD_CLOEXEC => {
+            let cloexec = op == abi::F_DUPFD_CLOEXEC;
+            table.dup(&fd, &RemoteFd(result as u32), cloexec)
+        }
+        abi::F_SETFD => table.set_cloexec(&fd, arg & abi::F_CLOEXEC != 0),
+        _ => {}
+    }
+    Ok(result)
+}
+
 pub async fn fstat(
     stopped_task: &mut StoppedTask<'_, '_>,
     fd: RemoteFd,
@@ -57,7 +124,7 @@ pub async fn fstat(
     ipc_call!(
         stopped_task.task,
         FromTask::FileStat {
-            file: Some(file.clone()),
+            file: Some(file.vfile.clone()),
             path: None,
             follow_links: FollowLinks::Follow,
         },
@@ -66,7 +133,47 @@ pub async fn fstat(
     )
 }
 
+/// Let the kernel stat a descriptor the virtual filesystem doesn't know,
+/// like stdio or a pipe, writing straight into the task's buffer
+pub async fn kernel_fstat(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    out_ptr: VPtr,
+) -> Result<(), Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr
+        .syscall(sc::nr::FSTAT, &[fd.0 as isize, out_ptr.0 as isize])
+        .await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check for the empty path which AT_EMPTY_PATH allows
+pub fn is_empty_path(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    path: &VString,
+) -> Result<bool, Errno> {
+    let mut first = [0u8];
+    read_bytes(stopped_task, path.0, &mut first)?;
+    Ok(first[0] == 0)
+}
+
+/// Check for an absolute path, which `*at` syscalls resolve without looking
+/// at their directory fd
+pub fn is_absolute_path(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    path: &VString,
+) -> Result<bool, Errno> {
+    let mut first = [0u8];
+    read_bytes(stopped_task, path.0, &mut first)?;
+    Ok(first[0] == b'/')
+}
+
 pub async fn close(stopped_task: &mut StoppedTask<'_, '_>, fd: RemoteFd) -> Result<(), Errno> {
+    check_not_task_socket(stopped_task, &fd)?;
     // Note that the fd will be closed even if close() also reports an error
     let table = &mut stopped_task.task.task_data.file_table;
     table.close(&fd);
@@ -0,0 +1,94 @@
+use crate::{abi, protocol::FromTask};
+use sc::syscall;
+
+/// Nanoseconds on the monotonic clock, which the runtime shares
+pub fn now() -> u64 {
+    let mut time = abi::TimeSpec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let result = unsafe {
+        syscall!(
+            CLOCK_GETTIME,
+            abi::CLOCK_MONOTONIC,
+            &mut time as *mut abi::TimeSpec
+        )
+    };
+    assert_eq!(result, 0);
+    time.tv_sec * 1_000_000_000 + time.tv_nsec
+}
+
+/// Timestamps for one emulated syscall, as it passes through each phase
+///
+/// The runtime's share of each IPC round trip is measured on the other side,
+/// and subtracted from the total time spent waiting here.
+#[derive(Debug, Default)]
+pub struct LatencyTimer {
+    enabled: bool,
+    trapped: u64,
+    dispatched: u64,
+    emulated: u64,
+    ipc: u64,
+}
+
+impl LatencyTimer {
+    pub fn new(enabled: bool) -> Self {
+        LatencyTimer {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    /// The tracer has woken up for a seccomp trap
+    pub fn trapped(&mut self) {
+        if self.enabled {
+            self.trapped = now();
+            self.ipc = 0;
+        }
+    }
+
+    /// Registers are loaded, and emulation starts
+    pub fn dispatched(&mut self) {
+        if self.enabled {
+            self.dispatched = now();
+        }
+    }
+
+    /// Emulation is done, and the task is about to resume
+    pub fn emulated(&mut self) {
+        if self.enabled {
+            self.emulated = now();
+        }
+    }
+
+    /// Start of a round trip to the runtime
+    pub fn ipc_begin(&self) -> u64 {
+        if self.enabled {
+            now()
+        } else {
+            0
+        }
+    }
+
+    /// End of a round trip which started at `begin`
+    pub fn ipc_end(&mut self, begin: u64) {
+        if self.enabled {
+            self.ipc += now() - begin;
+        }
+    }
+
+    /// The task has resumed, report the whole trip
+    pub fn resumed(&self, nr: isize) -> Option<FromTask> {
+        if !self.enabled {
+            return None;
+        }
+        let resumed = now();
+        Some(FromTask::SyscallLatency {
+            nr,
+            trap: self.dispatched - self.trapped,
+            emulate: (self.emulated - self.dispatched).saturating_sub(self.ipc),
+            ipc: self.ipc,
+            resume: resumed - self.emulated,
+        })
+    }
+}
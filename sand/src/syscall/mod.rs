@@ -1,6 +1,13 @@
 mod dispatch;
 mod fs;
+mod latency;
+mod net;
+mod notify;
 mod result;
+mod storm;
 mod user;
 
-pub use dispatch::SyscallEmulator;
+pub use dispatch::{kill_unverified, SyscallEmulator};
+pub use latency::LatencyTimer;
+pub use notify::{respond_to_notification, NotifyEmulator};
+pub use storm::StormDetector;
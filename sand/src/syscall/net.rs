@@ -0,0 +1,384 @@
+use crate::{
+    abi,
+    mem::rw::{read_bytes, read_value, write_padded_value, write_u32, write_word},
+    nolibc::{File, TempFile},
+    process::task::StoppedTask,
+    protocol::{Errno, FromTask, SysFd, ToTask, VPtr},
+    remote::{file::RemoteFd, scratchpad::Scratchpad, trampoline::Trampoline},
+    syscall::result,
+};
+use core::cmp::min;
+
+/// Room for a struct sockaddr_un, rounded up to a whole word
+const UNIX_NAME_SPACE: usize = (abi::SOCKADDR_UN_LEN + 7) & !7;
+
+/// Stream sockets on the loopback network the runtime provides
+///
+/// A new socket is a placeholder until it's bound or connected. At that
+/// point the runtime hands back a real socket from the container's network
+/// group, which takes over the placeholder's descriptor. Listening,
+/// accepting, and data transfer then happen on that socket with the tracer
+/// only translating addresses.
+pub async fn socket(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    domain: usize,
+    sock_type: usize,
+    protocol: usize,
+) -> Result<RemoteFd, Errno> {
+    if domain != abi::AF_INET && domain != abi::AF_INET6 {
+        return Err(Errno::new(abi::EAFNOSUPPORT));
+    }
+    if sock_type & abi::SOCK_TYPE_MASK != abi::SOCK_STREAM {
+        return Err(Errno::new(abi::ESOCKTNOSUPPORT));
+    }
+    if sock_type & !(abi::SOCK_TYPE_MASK | abi::O_NONBLOCK | abi::O_CLOEXEC) != 0 {
+        return Err(Errno::new(abi::EINVAL));
+    }
+    if protocol != 0 && protocol != abi::IPPROTO_TCP {
+        return Err(Errno::new(abi::EPROTONOSUPPORT));
+    }
+
+    // Nothing will ever arrive on the placeholder, but it takes socket
+    // options like any other socket
+    let (local, remote) = File::socketpair(abi::AF_UNIX, abi::SOCK_STREAM, 0)?;
+    let mut tr = Trampoline::new(stopped_task);
+    let passed = result::file(&mut tr, &remote.fd).await;
+    let _ = local.close();
+    let _ = remote.close();
+    let fd = passed?;
+
+    let mut result = Ok(0);
+    if sock_type & abi::O_NONBLOCK != 0 {
+        result = fd.fcntl(&mut tr, abi::F_SETFL, abi::O_NONBLOCK).await;
+    }
+    if result.is_ok() && sock_type & abi::O_CLOEXEC != 0 {
+        result = fd.fcntl(&mut tr, abi::F_SETFD, abi::F_CLOEXEC).await;
+    }
+    if let Err(err) = result {
+        fd.close(&mut tr).await?;
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+pub async fn bind(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    addr: VPtr,
+    len: usize,
+) -> Result<(), Errno> {
+    check_socket(stopped_task, &fd)?;
+    let sys_fd = ipc_call!(
+        stopped_task.task,
+        FromTask::NetBind { addr, len },
+        ToTask::SocketReply(result),
+        result
+    )?;
+    replace_socket(stopped_task, &fd, sys_fd).await
+}
+
+pub async fn connect(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    addr: VPtr,
+    len: usize,
+) -> Result<(), Errno> {
+    check_socket(stopped_task, &fd)?;
+    let sys_fd = ipc_call!(
+        stopped_task.task,
+        FromTask::NetConnect { addr, len },
+        ToTask::SocketReply(result),
+        result
+    )?;
+    replace_socket(stopped_task, &fd, sys_fd).await
+}
+
+/// Socket-level options go to the real socket. Protocol options like
+/// TCP_NODELAY have no meaning on the loopback network, and are ignored.
+pub async fn setsockopt(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    level: i32,
+    args: &[isize],
+) -> Result<(), Errno> {
+    check_socket(stopped_task, &fd)?;
+    if level != abi::SOL_SOCKET {
+        return Ok(());
+    }
+    passthrough(stopped_task, sc::nr::SETSOCKOPT, args)
+        .await
+        .map(|_| ())
+}
+
+pub async fn shutdown(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    args: &[isize],
+) -> Result<(), Errno> {
+    check_socket(stopped_task, &fd)?;
+    passthrough(stopped_task, sc::nr::SHUTDOWN, args)
+        .await
+        .map(|_| ())
+}
+
+pub async fn accept(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: RemoteFd,
+    addr: VPtr,
+    addr_len: VPtr,
+    flags: usize,
+) -> Result<RemoteFd, Errno> {
+    check_socket(stopped_task, &fd)?;
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr
+        .syscall(sc::nr::ACCEPT4, &[fd.0 as isize, 0, 0, flags as isize])
+        .await;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    }
+    let new_fd = RemoteFd(result as u32);
+    if addr.0 != 0 {
+        let result = name(stopped_task, sc::nr::GETPEERNAME, &new_fd, addr, addr_len).await;
+        if let Err(err) = result {
+            let mut tr = Trampoline::new(stopped_task);
+            new_fd.close(&mut tr).await?;
+            return Err(err);
+        }
+    }
+    Ok(new_fd)
+}
+
+/// getsockname() or getpeername(), with `nr` saying which
+pub async fn sockname(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    nr: usize,
+    fd: RemoteFd,
+    addr: VPtr,
+    addr_len: VPtr,
+) -> Result<(), Errno> {
+    check_socket(stopped_task, &fd)?;
+    name(stopped_task, nr, &fd, addr, addr_len).await
+}
+
+/// sendmsg() and recvmsg() work on the real socket, without control messages
+///
+/// Control messages could pass host descriptors or credentials to another
+/// container in the network group, so sending any fails with EPERM, and
+/// recvmsg() is given no room to receive them. The kernel sees a copy of the
+/// message header that the tracer made, so other threads can't slip a
+/// control buffer back in after it's checked.
+pub async fn message(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    nr: usize,
+    fd: RemoteFd,
+    args: &[isize],
+) -> Result<usize, Errno> {
+    check_socket(stopped_task, &fd)?;
+    let msg_ptr = VPtr(args[1] as usize);
+    let mut msg: abi::MsgHdr = unsafe { read_value(stopped_task, msg_ptr) }?;
+    if nr == sc::nr::SENDMSG && msg.msg_controllen != 0 {
+        return Err(Errno::new(abi::EPERM));
+    }
+    msg.msg_control = core::ptr::null_mut();
+    msg.msg_controllen = 0;
+
+    let mut tr = Trampoline::new(stopped_task);
+    let mut pad = Scratchpad::new(&mut tr).await?;
+    let result = copied_message(&mut pad, nr, args, &msg).await;
+    let cleanup_result = pad.free().await;
+    let (len, msg) = result?;
+    cleanup_result?;
+
+    if nr == sc::nr::RECVMSG {
+        let field = |offset| msg_ptr + offset;
+        write_u32(
+            stopped_task,
+            field(offset_of!(abi::MsgHdr, msg_namelen)),
+            msg.msg_namelen as u32,
+        )?;
+        write_word(
+            stopped_task,
+            field(offset_of!(abi::MsgHdr, msg_controllen)),
+            0,
+        )?;
+        write_u32(
+            stopped_task,
+            field(offset_of!(abi::MsgHdr, msg_flags)),
+            msg.msg_flags,
+        )?;
+    }
+    Ok(len)
+}
+
+/// Run sendmsg() or recvmsg() with the header in a scratchpad, returning
+/// the result and the header as the kernel left it
+async fn copied_message(
+    pad: &mut Scratchpad<'_, '_, '_, '_>,
+    nr: usize,
+    args: &[isize],
+    msg: &abi::MsgHdr,
+) -> Result<(usize, abi::MsgHdr), Errno> {
+    unsafe { write_padded_value(pad.trampoline.stopped_task, pad.ptr(), msg) }?;
+    let result = pad
+        .trampoline
+        .syscall(nr, &[args[0], pad.ptr().0 as isize, args[2]])
+        .await;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    }
+    let msg = unsafe { read_value(pad.trampoline.stopped_task, pad.ptr()) }?;
+    Ok((result as usize, msg))
+}
+
+/// Options and shutdown would reach the tracer's end of the task socket,
+/// and files from the virtual filesystem are never sockets
+fn check_socket(stopped_task: &StoppedTask<'_, '_>, fd: &RemoteFd) -> Result<(), Errno> {
+    let task_data = &stopped_task.task.task_data;
+    if fd == &task_data.socket_pair.remote {
+        Err(Errno::new(abi::EBADF))
+    } else if task_data.file_table.get(fd).is_ok() {
+        Err(Errno::new(abi::ENOTSOCK))
+    } else {
+        Ok(())
+    }
+}
+
+async fn passthrough(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    nr: usize,
+    args: &[isize],
+) -> Result<usize, Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr.syscall(nr, args).await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// Write a socket's address to the task, as `nr` from the kernel would
+///
+/// Every real socket the runtime hands out is a unix socket, bound to a file
+/// named after the address it stands for in hex. A socket without one is
+/// still a placeholder: unbound, and not connected to any peer.
+async fn name(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    nr: usize,
+    fd: &RemoteFd,
+    addr: VPtr,
+    addr_len: VPtr,
+) -> Result<(), Errno> {
+    let mut unix_name = [0u8; UNIX_NAME_SPACE];
+    let unix_len = {
+        let mut tr = Trampoline::new(stopped_task);
+        let mut pad = Scratchpad::new(&mut tr).await?;
+        let result = kernel_name(&mut pad, nr, fd, &mut unix_name).await;
+        let cleanup_result = pad.free().await;
+        let len = result?;
+        cleanup_result?;
+        len
+    };
+    let mut inet_name = [0u8; abi::SOCKADDR_IN6_LEN];
+    let inet_len = match decode_name(&unix_name[..unix_len], &mut inet_name) {
+        Some(len) => len,
+        None if nr == sc::nr::GETPEERNAME => return Err(Errno::new(abi::ENOTCONN)),
+        None => {
+            inet_name = [0; abi::SOCKADDR_IN6_LEN];
+            inet_name[..2].copy_from_slice(&(abi::AF_INET as u16).to_ne_bytes());
+            abi::SOCKADDR_IN_LEN
+        }
+    };
+
+    let space: i32 = unsafe { read_value(stopped_task, addr_len) }?;
+    if space < 0 {
+        return Err(Errno::new(abi::EINVAL));
+    }
+    let mut tr = Trampoline::new(stopped_task);
+    let copied = min(space as usize, inet_len);
+    if copied > 0 {
+        result::local_bytes(&mut tr, &inet_name[..copied], addr).await?;
+    }
+    result::local_bytes(&mut tr, &(inet_len as u32).to_ne_bytes(), addr_len).await
+}
+
+async fn kernel_name(
+    pad: &mut Scratchpad<'_, '_, '_, '_>,
+    nr: usize,
+    fd: &RemoteFd,
+    unix_name: &mut [u8],
+) -> Result<usize, Errno> {
+    let len_ptr = pad.ptr() + UNIX_NAME_SPACE;
+    write_word(pad.trampoline.stopped_task, len_ptr, UNIX_NAME_SPACE)?;
+    let result = pad
+        .trampoline
+        .syscall(
+            nr,
+            &[fd.0 as isize, pad.ptr().0 as isize, len_ptr.0 as isize],
+        )
+        .await;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    }
+    let len: u32 = unsafe { read_value(pad.trampoline.stopped_task, len_ptr) }?;
+    let len = min(len as usize, unix_name.len());
+    read_bytes(
+        pad.trampoline.stopped_task,
+        pad.ptr(),
+        &mut unix_name[..len],
+    )?;
+    Ok(len)
+}
+
+/// Recover the address a runtime socket's name stands for, returning its
+/// length
+fn decode_name(unix_name: &[u8], inet_name: &mut [u8; abi::SOCKADDR_IN6_LEN]) -> Option<usize> {
+    let path = unix_name.get(2..).unwrap_or(&[]);
+    let path = path.split(|b| *b == 0).next().unwrap_or(&[]);
+    let hex = path.rsplit(|b| *b == b'/').next().unwrap_or(&[]);
+    let len = hex.len() / 2;
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let decoded = hex.len() % 2 == 0
+        && len <= inet_name.len()
+        && hex.chunks(2).zip(inet_name.iter_mut()).all(|(pair, byte)| {
+            match (digit(pair[0]), digit(pair[1])) {
+                (Some(high), Some(low)) => {
+                    *byte = (high << 4) | low;
+                    true
+                }
+                _ => false,
+            }
+        });
+    let family = u16::from_ne_bytes([inet_name[0], inet_name[1]]) as usize;
+    match (decoded, family, len) {
+        (true, abi::AF_INET, abi::SOCKADDR_IN_LEN) => Some(len),
+        (true, abi::AF_INET6, abi::SOCKADDR_IN6_LEN) => Some(len),
+        _ => None,
+    }
+}
+
+/// Put a socket from the runtime in place of the process's socket at `fd`,
+/// keeping its blocking mode and close-on-exec flag
+async fn replace_socket(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    fd: &RemoteFd,
+    sys_fd: SysFd,
+) -> Result<(), Errno> {
+    let file = TempFile(File::new(sys_fd));
+    let mut tr = Trampoline::new(stopped_task);
+    let status_flags = fd.fcntl(&mut tr, abi::F_GETFL, 0).await? as usize;
+    let fd_flags = fd.fcntl(&mut tr, abi::F_GETFD, 0).await? as usize;
+    let new_fd = result::file(&mut tr, &file.0.fd).await?;
+    let result = tr
+        .syscall(sc::nr::DUP2, &[new_fd.0 as isize, fd.0 as isize])
+        .await;
+    new_fd.close(&mut tr).await?;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    }
+    fd.fcntl(&mut tr, abi::F_SETFL, status_flags & abi::O_NONBLOCK)
+        .await?;
+    fd.fcntl(&mut tr, abi::F_SETFD, fd_flags & abi::F_CLOEXEC)
+        .await?;
+    Ok(())
+}
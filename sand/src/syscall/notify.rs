@@ -0,0 +1,299 @@
+use crate::{
+    abi,
+    mem::kernel::verify_syscall_entry,
+    nolibc::{File, TempFile},
+    process::{
+        table::OpenFile,
+        task::{StoppedTask, Task},
+    },
+    protocol::{
+        abi::{Syscall, UserRegs},
+        Errno, FileContents, FileStat, FollowLinks, FromTask, LogLevel, SysFd, ToTask, VFile, VPtr,
+        VString,
+    },
+    remote::file::RemoteFd,
+    syscall::dispatch::{kill_unverified, log_emulated, UserStat},
+};
+use alloc::rc::Rc;
+use sc::{nr, syscall};
+
+/// Answers a seccomp user notification, for the calls the notify policy in
+/// [crate::seccomp] sends us
+///
+/// The process is waiting inside its syscall rather than stopped, so nothing
+/// here can run code in it. Results are written with process_vm_writev, and
+/// new files are installed by the kernel on our behalf.
+#[derive(Debug)]
+pub struct NotifyEmulator<'q, 't> {
+    task: &'t mut Task<'q>,
+    listener: File,
+    notif: abi::SeccompNotif,
+}
+
+impl<'q, 't> NotifyEmulator<'q, 't> {
+    pub fn new(task: &'t mut Task<'q>, listener: &SysFd, notif: abi::SeccompNotif) -> Self {
+        NotifyEmulator {
+            task,
+            listener: File::new(*listener),
+            notif,
+        }
+    }
+
+    fn return_file_result(
+        &mut self,
+        result: Result<(VFile, FileContents), Errno>,
+        open_flags: i32,
+    ) -> Result<isize, Errno> {
+        let (vfile, contents) = result?;
+        let file = TempFile::from_contents(contents)?;
+        let open_file = OpenFile::new(vfile, open_flags);
+        let cloexec = open_flags as usize & abi::O_CLOEXEC != 0;
+        // The new descriptor shares this open file, and its status flags
+        if open_file.status_flags() != 0 {
+            file.0.fcntl(abi::F_SETFL, open_file.status_flags())?;
+        }
+        let addfd = abi::SeccompNotifAddFd {
+            id: self.notif.id,
+            flags: 0,
+            srcfd: file.0.fd.0,
+            newfd: 0,
+            newfd_flags: if cloexec { abi::O_CLOEXEC as u32 } else { 0 },
+        };
+        let fd = self.listener.ioctl(
+            abi::SECCOMP_IOCTL_NOTIF_ADDFD,
+            &addfd as *const abi::SeccompNotifAddFd as usize,
+        )?;
+        self.task
+            .task_data
+            .file_table
+            .open(RemoteFd(fd as u32), Rc::new(open_file), cloexec);
+        Ok(fd)
+    }
+
+    fn return_stat_result(
+        &mut self,
+        out_ptr: VPtr,
+        result: Result<(VFile, FileStat), Errno>,
+    ) -> Result<isize, Errno> {
+        let (vfile, file_stat) = result?;
+        let stat = UserStat::new(vfile, &file_stat);
+        self.return_local_bytes(unsafe { plain::as_bytes(&stat) }, out_ptr)?;
+        Ok(0)
+    }
+
+    fn return_local_bytes(&mut self, bytes: &[u8], to_ptr: VPtr) -> Result<(), Errno> {
+        // The process may have been killed and its pid reused since the
+        // notification was received
+        let id = self.notif.id;
+        self.listener.ioctl(
+            abi::SECCOMP_IOCTL_NOTIF_ID_VALID,
+            &id as *const u64 as usize,
+        )?;
+        let local = abi::IOVec {
+            base: bytes.as_ptr() as *mut u8,
+            len: bytes.len(),
+        };
+        let remote = abi::IOVec {
+            base: to_ptr.0 as *mut u8,
+            len: bytes.len(),
+        };
+        match unsafe {
+            syscall!(
+                PROCESS_VM_WRITEV,
+                self.notif.pid,
+                &local as *const abi::IOVec,
+                1,
+                &remote as *const abi::IOVec,
+                1,
+                0
+            )
+        } as isize
+        {
+            len if len == bytes.len() as isize => Ok(()),
+            len if len >= 0 => Err(Errno::new(abi::EFAULT)),
+            err => Err(Errno(err as i32)),
+        }
+    }
+
+    fn respond(&self, result: &Result<isize, Errno>) {
+        if let Err(err) = respond_to_notification(&self.listener, self.notif.id, result) {
+            panic!("seccomp notification reply failed, {:?}", err);
+        }
+    }
+
+    /// The same instruction pointer check as for a seccomp trap
+    ///
+    /// The process isn't stopped, but it's blocked inside the syscall, so its
+    /// maps and memory can be read just the same. Only the registers are out
+    /// of reach, and the kernel reports the instruction pointer for us.
+    fn verify_syscall_entry(&mut self) -> Result<(), ()> {
+        let mut regs: UserRegs = Default::default();
+        regs.ip = self.notif.data.instruction_pointer as usize;
+        let mut stopped_task = StoppedTask {
+            task: &mut *self.task,
+            regs: &mut regs,
+        };
+        verify_syscall_entry(&mut stopped_task)
+    }
+
+    pub async fn dispatch(&mut self) {
+        let checks = self
+            .task
+            .task_data
+            .tracer_settings
+            .instruction_pointer_checks;
+        self.task.latency.trapped();
+        self.task.msg.set_syscall(Some(self.notif.data.nr as isize));
+        if checks && self.verify_syscall_entry().is_err() {
+            let nr = self.notif.data.nr as isize;
+            let ip = VPtr(self.notif.data.instruction_pointer as usize);
+            kill_unverified(self.task, nr, ip);
+            // The kernel still waits on an answer, even for a dying process
+            self.respond(&Err(Errno::new(abi::EPERM)));
+            self.task.msg.set_syscall(None);
+            return;
+        }
+        self.task.latency.dispatched();
+
+        let args = self.notif.data.args;
+        let arg_i32 = |idx: usize| args[idx] as i32;
+        let arg_ptr = |idx: usize| VPtr(args[idx] as usize);
+        let arg_string = |idx: usize| VString(arg_ptr(idx));
+        let follow_links = |flags: i32| {
+            if (flags & abi::AT_SYMLINK_NOFOLLOW) != 0 {
+                FollowLinks::NoFollow
+            } else {
+                FollowLinks::Follow
+            }
+        };
+
+        let mut log_level = LogLevel::Debug;
+        let result: Result<isize, Errno> = match self.notif.data.nr as usize {
+            nr::OPEN => ipc_call!(
+                self.task,
+                FromTask::FileOpen {
+                    dir: None,
+                    path: arg_string(0),
+                    flags: arg_i32(1),
+                    mode: arg_i32(2),
+                },
+                ToTask::FileReply(result),
+                self.return_file_result(result, arg_i32(1))
+            ),
+
+            nr::OPENAT => ipc_call!(
+                self.task,
+                FromTask::FileOpen {
+                    dir: None,
+                    path: arg_string(1),
+                    flags: arg_i32(2),
+                    mode: arg_i32(3),
+                },
+                ToTask::FileReply(result),
+                self.return_file_result(result, arg_i32(2))
+            ),
+
+            nr::STAT | nr::LSTAT => {
+                let flags = if self.notif.data.nr as usize == nr::LSTAT {
+                    abi::AT_SYMLINK_NOFOLLOW
+                } else {
+                    0
+                };
+                ipc_call!(
+                    self.task,
+                    FromTask::FileStat {
+                        file: None,
+                        path: Some(arg_string(0)),
+                        follow_links: follow_links(flags),
+                    },
+                    ToTask::FileStatReply(result),
+                    self.return_stat_result(arg_ptr(1), result)
+                )
+            }
+
+            nr::NEWFSTATAT => {
+                let known_flags = abi::AT_SYMLINK_NOFOLLOW | abi::AT_NO_AUTOMOUNT;
+                if arg_i32(3) & !known_flags != 0 {
+                    Err(Errno::new(abi::EINVAL))
+                } else {
+                    ipc_call!(
+                        self.task,
+                        FromTask::FileStat {
+                            file: None,
+                            path: Some(arg_string(1)),
+                            follow_links: follow_links(arg_i32(3)),
+                        },
+                        ToTask::FileStatReply(result),
+                        self.return_stat_result(arg_ptr(2), result)
+                    )
+                }
+            }
+
+            nr::ACCESS => ipc_call!(
+                self.task,
+                FromTask::FileAccess {
+                    dir: None,
+                    path: arg_string(0),
+                    mode: arg_i32(1),
+                },
+                ToTask::Reply(result),
+                result.map(|()| 0)
+            ),
+
+            // Not something the notify policy sends
+            _ => {
+                log_level = LogLevel::Warn;
+                Err(Errno::new(abi::ENOSYS))
+            }
+        };
+        self.respond(&result);
+        self.task.msg.set_syscall(None);
+        self.task.latency.emulated();
+
+        let mut call_args = [0; 6];
+        for (arg, value) in call_args.iter_mut().zip(args.iter()) {
+            *arg = *value as isize;
+        }
+        let call = Syscall {
+            nr: self.notif.data.nr as isize,
+            args: call_args,
+            ret: match result {
+                Ok(val) => val,
+                Err(Errno(err)) => err as isize,
+            },
+            ip: self.notif.data.instruction_pointer as usize,
+            sp: 0,
+        };
+        log_emulated(self.task, log_level, &call);
+        if let Some(report) = self.task.latency.resumed(call.nr) {
+            self.task.msg.send(report);
+        }
+    }
+}
+
+/// Answer a notification, which may be for a process that's already gone
+pub fn respond_to_notification(
+    listener: &File,
+    id: u64,
+    result: &Result<isize, Errno>,
+) -> Result<(), Errno> {
+    let (val, error) = match result {
+        Ok(val) => (*val as i64, 0),
+        Err(Errno(err)) => (0, *err),
+    };
+    let resp = abi::SeccompNotifResp {
+        id,
+        val,
+        error,
+        flags: 0,
+    };
+    match listener.ioctl(
+        abi::SECCOMP_IOCTL_NOTIF_SEND,
+        &resp as *const abi::SeccompNotifResp as usize,
+    ) {
+        Ok(_) => Ok(()),
+        // Killed while we were answering
+        Err(err) if err == Errno::new(abi::ENOENT) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
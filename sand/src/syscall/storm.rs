@@ -0,0 +1,78 @@
+use crate::protocol::abi::Syscall;
+
+/// Number of identical failing calls in a row that counts as a storm
+pub const STORM_THRESHOLD: u32 = 10000;
+
+/// Notices when a task keeps retrying the same failing syscall
+///
+/// Some programs retry an unsupported syscall forever, which keeps the tracer
+/// busy emulating it at full speed. A run of calls with the same number,
+/// arguments, and error result is almost always one of these loops.
+#[derive(Debug, Default)]
+pub struct StormDetector {
+    last: Option<Syscall>,
+    repeats: u32,
+}
+
+impl StormDetector {
+    /// Record one emulated call, returning true once per STORM_THRESHOLD
+    /// identical failing calls in a row
+    pub fn observe(&mut self, call: &Syscall) -> bool {
+        if call.ret >= 0 {
+            self.last = None;
+            self.repeats = 0;
+            return false;
+        }
+        match &self.last {
+            Some(last) if last.nr == call.nr && last.args == call.args && last.ret == call.ret => {
+                self.repeats += 1;
+            }
+            _ => {
+                self.last = Some(call.clone());
+                self.repeats = 1;
+            }
+        }
+        if self.repeats >= STORM_THRESHOLD {
+            self.repeats = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(nr: isize, ret: isize) -> Syscall {
+        Syscall {
+            nr,
+            args: [1, 2, 3, 4, 5, 6],
+            ret,
+            ip: 0,
+            sp: 0,
+        }
+    }
+
+    #[test]
+    fn storm_detection() {
+        let mut detector: StormDetector = Default::default();
+        for _ in 1..STORM_THRESHOLD {
+            assert!(!detector.observe(&call(29, -38)));
+        }
+        assert!(detector.observe(&call(29, -38)));
+        assert!(!detector.observe(&call(29, -38)));
+    }
+
+    #[test]
+    fn storm_interrupted() {
+        let mut detector: StormDetector = Default::default();
+        for i in 0..(STORM_THRESHOLD * 2) {
+            assert!(!detector.observe(&call(29, -38)));
+            if i % 100 == 0 {
+                assert!(!detector.observe(&call(29, 0)));
+            }
+        }
+    }
+}
@@ -3,16 +3,19 @@ use crate::{
     mem::{
         maps::{MappedPages, MemFlags},
         page::VPage,
+        rw::{read_value, write_u32},
     },
-    process::task::StoppedTask,
-    protocol::{Errno, VPtr},
+    nolibc::{tkill, File},
+    process::task::{StoppedTask, TaskSocketPair},
+    protocol::{Errno, VPid, VPtr},
     remote::{
         file::{RemoteFd, TempRemoteFd},
         scratchpad::Scratchpad,
         trampoline::Trampoline,
     },
-    syscall::result::SyscallResult,
+    syscall::result,
 };
+use core::mem;
 
 pub async fn uname<'q, 's, 't>(
     stopped_task: &'t mut StoppedTask<'q, 's>,
@@ -79,15 +82,34 @@ pub async fn uname<'q, 's, 't>(
     Ok(())
 }
 
+/// Clock reads, which only reach the tracer while the vDSO's time functions
+/// are turned off. There is no virtual clock, so the same call is made again
+/// from inside the task, and its result returned as is. That result is the
+/// time itself for `time()`.
+pub async fn clock(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    nr: usize,
+    args: &[isize],
+) -> Result<usize, Errno> {
+    let mut tr = Trampoline::new(stopped_task);
+    let result = tr.syscall(nr, &args[..2]).await;
+    if result < 0 {
+        Err(Errno(result as i32))
+    } else {
+        Ok(result as usize)
+    }
+}
+
 /// brk() is emulated using mmap because we can't change the host kernel's per
 /// process brk pointer from our loader without extra privileges.
 pub async fn brk<'q, 's, 't>(
     stopped_task: &'t mut StoppedTask<'q, 's>,
     new_brk: VPtr,
 ) -> Result<VPtr, Errno> {
+    // Threads share this, and may run while the trampoline waits
+    let mm = stopped_task.task.task_data.mm.clone();
     if new_brk.0 != 0 {
-        let old_brk = stopped_task.task.task_data.mm.brk;
-        let brk_start = stopped_task.task.task_data.mm.brk_start;
+        let (old_brk, brk_start) = (mm.borrow().brk, mm.borrow().brk_start);
         let old_brk_page = VPage::round_up(brk_start.ptr().max(old_brk));
         let new_brk_page = VPage::round_up(brk_start.ptr().max(new_brk));
 
@@ -112,19 +134,181 @@ pub async fn brk<'q, 's, 't>(
                 .await?;
             }
         }
-        stopped_task.task.task_data.mm.brk = brk_start.ptr().max(new_brk);
+        mm.borrow_mut().brk = brk_start.ptr().max(new_brk);
+    }
+    let brk = mm.borrow().brk;
+    Ok(brk)
+}
+
+/// fork(), vfork(), and clone(), for new processes and threads
+///
+/// The child keeps this task's socket pair, since it already has the remote
+/// end, and the parent switches to a new one. A vfork() child gets its own
+/// copy of memory, which is allowed, but its parent still waits for it to
+/// exec or exit. Threads share their brk and file table with the task that
+/// made them.
+///
+/// Thread IDs are VPids everywhere the sandbox sees them, including the ones
+/// stored for CLONE_PARENT_SETTID and CLONE_CHILD_SETTID.
+pub async fn clone(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    flags: usize,
+    args: &[isize],
+) -> Result<VPid, Errno> {
+    const SUPPORTED: usize = abi::CSIGNAL
+        | abi::CLONE_VM
+        | abi::CLONE_FS
+        | abi::CLONE_FILES
+        | abi::CLONE_SIGHAND
+        | abi::CLONE_VFORK
+        | abi::CLONE_THREAD
+        | abi::CLONE_SYSVSEM
+        | abi::CLONE_SETTLS
+        | abi::CLONE_PARENT_SETTID
+        | abi::CLONE_CHILD_CLEARTID
+        | abi::CLONE_DETACHED
+        | abi::CLONE_CHILD_SETTID;
+    if flags & !SUPPORTED != 0 {
+        return Err(Errno::new(abi::ENOSYS));
+    }
+    let vfork = flags & abi::CLONE_VFORK != 0;
+    let flags = if vfork { flags & !abi::CLONE_VM } else { flags };
+    let child_tid = if flags & abi::CLONE_CHILD_SETTID != 0 {
+        Some(VPtr(args[3] as usize))
+    } else {
+        None
+    };
+    let host_args = [flags as isize, args[1], args[2], args[3], args[4]];
+
+    let mut tr = Trampoline::new(stopped_task);
+    let (child, socket_pair) = if vfork {
+        // The parent doesn't return until the child is done, and the child
+        // needs this task's socket pair before then. So the parent's new pair
+        // comes first, closed on exec so the child's program doesn't get it.
+        let socket_pair = replacement_socket_pair(&mut tr).await?;
+        let started = match socket_pair
+            .remote
+            .fcntl(&mut tr, abi::F_SETFD, abi::F_CLOEXEC)
+            .await
+        {
+            Ok(_) => tr.start_fork(sc::nr::CLONE, &host_args).await,
+            Err(err) => Err(err),
+        };
+        match started {
+            Ok(child) => (child, socket_pair),
+            Err(err) => {
+                let _ = socket_pair.remote.close(&mut tr).await;
+                return Err(err);
+            }
+        }
+    } else {
+        let child = tr.start_fork(sc::nr::CLONE, &host_args).await?;
+        let replaced = match tr.finish_fork(false).await {
+            Ok(()) => replacement_socket_pair(&mut tr).await,
+            Err(err) => Err(err),
+        };
+        match replaced {
+            Ok(socket_pair) => (child, socket_pair),
+            Err(err) => {
+                let _ = tkill(child, abi::SIGKILL);
+                return Err(err);
+            }
+        }
+    };
+
+    let task_data = &mut tr.stopped_task.task.task_data;
+    let inherited = mem::replace(&mut task_data.socket_pair, socket_pair);
+    let inherited_remote = inherited.remote.clone();
+    let result = tr
+        .stopped_task
+        .task
+        .handle_fork(child, inherited, flags, child_tid)
+        .await;
+    if vfork {
+        tr.finish_fork(true).await?;
+        let remote = tr.stopped_task.task.task_data.socket_pair.remote.clone();
+        remote.fcntl(&mut tr, abi::F_SETFD, 0).await?;
+    }
+    // A shared file table still needs the child's socket
+    if flags & abi::CLONE_FILES == 0 || result.is_err() {
+        let _ = inherited_remote.close(&mut tr).await;
+    }
+
+    let vpid = result?;
+    if flags & abi::CLONE_PARENT_SETTID != 0 {
+        // The kernel stored the host's ID, and like it, this ignores faults
+        let _ = write_u32(tr.stopped_task, VPtr(args[2] as usize), vpid.0);
     }
-    Ok(stopped_task.task.task_data.mm.brk)
+    Ok(vpid)
 }
 
-pub async fn fork(stopped_task: &mut StoppedTask<'_, '_>) -> SyscallResult {
+/// A new socket pair for this task, with the remote end passed over the old one
+async fn replacement_socket_pair(tr: &mut Trampoline<'_, '_, '_>) -> Result<TaskSocketPair, Errno> {
+    let (tracer, remote) = File::socketpair(abi::AF_UNIX, abi::SOCK_STREAM, 0)?;
+    let passed = result::file(tr, &remote.fd).await;
+    let _ = remote.close();
+    match passed {
+        Ok(remote) => Ok(TaskSocketPair { tracer, remote }),
+        Err(err) => {
+            let _ = tracer.close();
+            Err(err)
+        }
+    }
+}
+
+/// wait4(), translating process IDs between the container and the host
+///
+/// Process groups aren't emulated, so waiting on any group waits on any
+/// child.
+pub async fn wait4(
+    stopped_task: &mut StoppedTask<'_, '_>,
+    pid: i32,
+    status: VPtr,
+    options: usize,
+    rusage: VPtr,
+) -> Result<VPid, Errno> {
+    let children = &stopped_task.task.task_data.children;
+    let sys_pid = if pid > 0 {
+        match children.iter().find(|(vpid, _)| vpid.0 == pid as u32) {
+            Some((_, sys_pid)) => sys_pid.0 as isize,
+            None => return Err(Errno::new(abi::ECHILD)),
+        }
+    } else {
+        -1
+    };
     let mut tr = Trampoline::new(stopped_task);
-    // to do:
-    //   pid translate, allocate task
-    //   expect ptrace fork event
-    //     probably requires lower-level trampoline remote syscall interface
-    //
-    // current test case:
-    // $ cargo run --release busybox:musl -- sh -c "true&"
-    SyscallResult(tr.syscall(sc::nr::FORK, &[]).await)
+    let result = tr
+        .syscall(
+            sc::nr::WAIT4,
+            &[
+                sys_pid,
+                status.0 as isize,
+                options as isize,
+                rusage.0 as isize,
+            ],
+        )
+        .await;
+    if result < 0 {
+        return Err(Errno(result as i32));
+    } else if result == 0 {
+        // WNOHANG, and no child has changed state
+        return Ok(VPid(0));
+    }
+
+    // The child is gone unless this only reports a stop or continue
+    let reaped = if status.0 == 0 {
+        options & (abi::WSTOPPED | abi::WCONTINUED) == 0
+    } else {
+        let status: i32 = unsafe { read_value(tr.stopped_task, status) }?;
+        status & 0xff != 0x7f && status != 0xffff
+    };
+    let children = &mut tr.stopped_task.task.task_data.children;
+    match children
+        .iter()
+        .position(|(_, sys_pid)| sys_pid.0 == result as u32)
+    {
+        None => Err(Errno::new(abi::ECHILD)),
+        Some(index) if reaped => Ok(children.remove(index).0),
+        Some(index) => Ok(children[index].0),
+    }
 }
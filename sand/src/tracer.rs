@@ -1,21 +1,28 @@
 use crate::{
     abi,
     ipc::Socket,
+    logring,
     mem::page::VPage,
-    nolibc::PROC_SELF_EXE,
+    nolibc::{
+        block_signals, exit, get_rlimit, personality, poll_with_signals, set_soft_rlimit, signal,
+        tkill, File, PROC_SELF_EXE,
+    },
     process::{
         table::{FileTable, ProcessTable},
-        task::{TaskMemManagement, TaskSocketPair},
+        task::{ChildTask, TaskMemManagement, TaskSocketPair},
         Event, TaskFn,
     },
     protocol::{
-        LogLevel, MessageFromSand, MessageToSand, SysFd, SysPid, TracerSettings, VPid, VPtr,
+        exit::EXIT_WEAK_HARDENING, AttachMode, Errno, HardeningReport, LogLevel, MessageFromSand,
+        MessageToSand, SysFd, SysPid, TracerSettings, VPid, VPtr,
     },
     ptrace,
     ptrace::RawExecArgs,
     seccomp,
+    syscall::respond_to_notification,
 };
-use core::{future::Future, ptr::null, task::Poll};
+use alloc::{rc::Rc, vec::Vec};
+use core::{cell::RefCell, future::Future, ptr::null, task::Poll};
 use heapless::{consts::*, String};
 use sc::syscall;
 
@@ -23,21 +30,83 @@ pub struct Tracer<'t, F: Future<Output = ()>> {
     ipc: Socket,
     settings: TracerSettings,
     process_table: ProcessTable<'t, F>,
+    suspended: bool,
+    /// Tasks sent a SIGSTOP for Suspend, not yet seen in its
+    /// signal-delivery-stop
+    stopping: Vec<SysPid>,
+    /// New tasks to stop once they report their first stop, if still
+    /// suspended then
+    attaching: Vec<SysPid>,
+    /// Tasks held in the signal-delivery-stop of a SIGSTOP until Resume
+    parked: Vec<SysPid>,
+    /// Signals sent on the runtime's behalf, not yet seen in a
+    /// signal-delivery-stop
+    forwarded: Vec<(SysPid, u8)>,
+    unclaimed: Vec<(SysPid, Event)>,
+    /// Listener for the notify seccomp policy, if it's in use
+    notify: Option<File>,
+    /// Our /proc/self/fd, until the loader starts
+    self_fd: Option<File>,
+    /// Privileges given up at startup, reported when Init arrives
+    hardening: HardeningReport,
 }
 
 impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
-    pub fn new(ipc: Socket, task_fn: TaskFn<'t, F>) -> Self {
+    pub fn new(
+        ipc: Socket,
+        self_fd: File,
+        hardening: HardeningReport,
+        task_fn: TaskFn<'t, F>,
+    ) -> Self {
         Tracer {
             settings: TracerSettings {
                 max_log_level: LogLevel::Off,
                 instruction_trace: false,
+                instruction_pointer_checks: true,
+                compress_messages: false,
+                attach_mode: AttachMode::TraceMe,
+                abort_on_syscall_storm: false,
+                exec_snapshots: false,
+                log_ring_size: 0,
+                syscall_latency: false,
+                user_notif: false,
+                require_hardening: true,
+                randomize_layout: true,
+                stack_limit: 8 * 1024 * 1024,
+                vdso_time: true,
             },
             process_table: ProcessTable::new(task_fn),
+            suspended: false,
+            stopping: Vec::new(),
+            attaching: Vec::new(),
+            parked: Vec::new(),
+            forwarded: Vec::new(),
+            unclaimed: Vec::new(),
+            notify: None,
+            self_fd: Some(self_fd),
+            hardening,
             ipc,
         }
     }
 
     pub fn run(mut self) {
+        // Until Init arrives there are no tasks to wait for. A tracer may be
+        // started ahead of time and left idle here, with its seccomp policy
+        // already in place, until the runtime has a container for it.
+        let init = self.ipc.recv_blocking();
+        self.message_event(init);
+        if self.notify.is_some() {
+            match self.run_with_notify() {
+                Ok(()) => return,
+                Err(err) => {
+                    // Without a listener, calls the policy would have sent us
+                    // fail with ENOSYS, including any still waiting
+                    println!("seccomp listener failed, {:?}", err);
+                    let _ = self.notify.take().unwrap().close();
+                }
+            }
+        }
+
         let mut siginfo: abi::SigInfo = Default::default();
         loop {
             while let Some(message) = self.ipc.recv() {
@@ -52,6 +121,67 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
         }
     }
 
+    /// The main loop, also waiting on the notify policy's listener
+    ///
+    /// Signals stay blocked except while polling, so a SIGCHLD or SIGIO can't
+    /// slip in between checking for events and going to sleep. Returns once
+    /// no tasks are left, or early if the listener stops working.
+    fn run_with_notify(&mut self) -> Result<(), Errno> {
+        block_signals(&[abi::SIGCHLD, abi::SIGIO]).expect("blocking signals");
+        signal(abi::SIGCHLD, handle_sigchld).expect("setting up sigchld handler");
+
+        let mut siginfo: abi::SigInfo = Default::default();
+        loop {
+            while let Some(message) = self.ipc.recv() {
+                self.message_event(message);
+            }
+            loop {
+                match ptrace::wait_nohang(&mut siginfo) {
+                    err if err == -abi::ECHILD as isize => return Ok(()),
+                    err if err == 0 && siginfo.si_pid == 0 => break,
+                    err if err == 0 => self.siginfo_event(&siginfo),
+                    err => panic!("unexpected waitid response ({})", err),
+                }
+            }
+            let listener = self.notify.as_ref().unwrap().fd;
+            let mut fds = [abi::PollFd {
+                fd: listener.0 as i32,
+                events: abi::POLLIN,
+                revents: 0,
+            }];
+            match poll_with_signals(&mut fds) {
+                Ok(_) if fds[0].revents & abi::POLLIN != 0 => self.notify_event(listener)?,
+                Ok(_) => {}
+                Err(err) if err == Errno::new(abi::EINTR) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn notify_event(&mut self, listener: SysFd) -> Result<(), Errno> {
+        let mut notif: abi::SeccompNotif = Default::default();
+        let result = File::new(listener).ioctl(
+            abi::SECCOMP_IOCTL_NOTIF_RECV,
+            &mut notif as *mut abi::SeccompNotif as usize,
+        );
+        match result {
+            Ok(_) => {}
+            // The call was interrupted before we could receive it
+            Err(err) if err == Errno::new(abi::ENOENT) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        match self.process_table.syspid_to_v(SysPid(notif.pid)) {
+            Some(vpid) => self.task_event(vpid, Event::Notify(listener, notif)),
+            None => {
+                // Nothing to emulate it for, but it mustn't be left waiting
+                println!("seccomp notification from unrecognized task, {:x?}", notif);
+                let result = Err(Errno::new(abi::ENOSYS));
+                respond_to_notification(&File::new(listener), notif.id, &result)?;
+            }
+        }
+        Ok(())
+    }
+
     fn init_loader(&mut self, args_fd: &SysFd) {
         let mut fd_str = String::<U16>::from("FD=");
         fd_str.push_str(&String::<U16>::from(args_fd.0)).unwrap();
@@ -60,23 +190,91 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
         let loader_env = [fd_str.as_ptr(), null()];
         let exec_args = unsafe { RawExecArgs::new(PROC_SELF_EXE, &loader_argv, &loader_env) };
         let socket_pair = TaskSocketPair::new_inheritable();
-        let settings = self.settings.clone();
+        let mut settings = self.settings.clone();
+        // The loader reserves stack for the limit the sandbox really gets,
+        // which the child clamps to the hard limit it inherits from us
+        let hard_limit = get_rlimit(abi::RLIMIT_STACK)
+            .expect("reading stack limit")
+            .rlim_max;
+        settings.stack_limit = (settings.stack_limit as u64).min(hard_limit) as usize;
+
+        // The loader inherits stdio, its args, and its task socket. Whatever
+        // else is open here, including anything the runtime handed us by
+        // mistake, must not reach the sandbox.
+        crate::cloexec_all_except(
+            self.self_fd.take().expect("loader already started"),
+            &[
+                &File::stdin(),
+                &File::stdout(),
+                &File::stderr(),
+                &File::new(*args_fd),
+                &File::new(SysFd(socket_pair.remote.0)),
+            ],
+        );
+
         match unsafe { syscall!(FORK) } as isize {
-            result if result == 0 => unsafe { ptrace::be_the_child_process(&exec_args) },
+            result if result == 0 => {
+                if !settings.randomize_layout {
+                    // Kept across exec, for the mappings the loader doesn't place
+                    personality(abi::ADDR_NO_RANDOMIZE).expect("disabling address randomization");
+                }
+                // Inherited by everything in the sandbox, and visible to it
+                set_soft_rlimit(abi::RLIMIT_STACK, settings.stack_limit as u64)
+                    .expect("setting stack limit");
+                if !settings.vdso_time {
+                    seccomp::policy_for_traced_clock();
+                }
+                if settings.user_notif {
+                    // Hand the listener to the tracer, keeping no copy
+                    let listener = seccomp::notify_listener().expect("seccomp notify policy");
+                    let socket = File::new(SysFd(socket_pair.remote.0));
+                    socket
+                        .send_file(&listener)
+                        .expect("sending seccomp listener");
+                    listener.close().expect("closing seccomp listener");
+                }
+                match settings.attach_mode {
+                    AttachMode::TraceMe => unsafe { ptrace::be_the_child_process(&exec_args) },
+                }
+            }
             result if result < 0 => panic!("fork error"),
             result => {
-                seccomp::policy_for_tracer_after_init();
-
                 let sys_pid = SysPid(result as u32);
-                let parent = None;
+                match seccomp::policy_for_tracer_after_init() {
+                    Ok(()) => self.hardening.tracer_policy = true,
+                    Err(err) => println!("{}", err),
+                }
+                self.ipc
+                    .send(&MessageFromSand::Hardening(self.hardening.clone()));
+                if settings.require_hardening && !self.hardening.is_complete() {
+                    // It hasn't run anything yet, and mustn't once untraced
+                    let _ = tkill(sys_pid, abi::SIGKILL);
+                    exit(EXIT_WEAK_HARDENING);
+                }
+                if settings.user_notif {
+                    let listener = socket_pair
+                        .tracer
+                        .recv_file()
+                        .expect("receiving seccomp listener");
+                    self.notify = Some(listener);
+                }
+
                 let mm = TaskMemManagement {
                     brk: VPtr::null(),
                     brk_start: VPage::null(),
                 };
-                let file_table = FileTable::new();
-                self.process_table
-                    .insert(settings, sys_pid, parent, socket_pair, mm, file_table)
-                    .expect("virtual process limit exceeded");
+                let task = ChildTask {
+                    sys_pid,
+                    parent: None,
+                    leader: None,
+                    socket_pair,
+                    mm: Rc::new(RefCell::new(mm)),
+                    file_table: FileTable::new(),
+                    tracer_settings: settings,
+                    child_tid: None,
+                };
+                // The table starts out empty
+                self.process_table.insert(task).unwrap();
             }
         }
     }
@@ -88,27 +286,133 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
                 args,
                 tracer_settings,
             } => {
+                self.ipc
+                    .set_compress_messages(tracer_settings.compress_messages);
+                logring::init(tracer_settings.log_ring_size);
                 self.settings = tracer_settings;
                 self.init_loader(&args);
             }
+            MessageToSand::Suspend => self.suspend(),
+            MessageToSand::Resume => self.resume(),
+            MessageToSand::Kill(signal) => self.kill(signal.0 as u8),
+        }
+    }
+
+    /// Send a signal to the init process, which is let through when it stops
+    ///
+    /// Signals a task gets while traced are normally discarded in its
+    /// signal-delivery-stop, so this one is remembered until then. SIGKILL
+    /// needs no help, and stopping is left to Suspend and Resume.
+    fn kill(&mut self, signal: u8) {
+        if signal == abi::SIGSTOP {
+            return;
+        }
+        let sys_pid = match self.process_table.get(VPid(1)) {
+            None => return,
+            Some(process) => process.sys_pid,
+        };
+        if tkill(sys_pid, signal).is_ok() && signal != abi::SIGKILL {
+            self.forwarded.push((sys_pid, signal));
+        }
+    }
+
+    fn suspend(&mut self) {
+        if !self.suspended {
+            self.suspended = true;
+            for sys_pid in self.process_table.sys_pids() {
+                self.stop(sys_pid);
+            }
+        }
+    }
+
+    /// Send a task the SIGSTOP that parks it, unless one is still on its way
+    ///
+    /// A task that already exited is skipped; its exit is reported as usual.
+    fn stop(&mut self, sys_pid: SysPid) {
+        if self.stopping.contains(&sys_pid) || self.parked.contains(&sys_pid) {
+            return;
+        }
+        match tkill(sys_pid, abi::SIGSTOP) {
+            Ok(()) => self.stopping.push(sys_pid),
+            Err(err) if err == Errno::new(abi::ESRCH) => {}
+            Err(err) => panic!("stopping task, {:?}", err),
+        }
+    }
+
+    fn resume(&mut self) {
+        self.suspended = false;
+        self.attaching.clear();
+        // The SIGSTOP is discarded here, so the tasks never see it. A SIGSTOP
+        // still on its way is discarded the same way when it arrives.
+        for sys_pid in self.parked.drain(..) {
+            let result = if self.settings.instruction_trace {
+                ptrace::single_step(sys_pid)
+            } else {
+                ptrace::cont(sys_pid)
+            };
+            ptrace::unless_exited(result);
         }
     }
 
     fn siginfo_event(&mut self, siginfo: &abi::SigInfo) {
         let sys_pid = SysPid(siginfo.si_pid);
-        let vpid = self.process_table.syspid_to_v(sys_pid);
-        match vpid {
-            None => panic!("signal for unrecognized task, {:x?}", sys_pid),
+        let sigstop =
+            siginfo.si_code == abi::CLD_TRAPPED && siginfo.si_status == abi::SIGSTOP as u32;
+        if sigstop {
+            if let Some(index) = self.stopping.iter().position(|pid| *pid == sys_pid) {
+                self.stopping.swap_remove(index);
+                if self.suspended {
+                    // Parked in signal-delivery-stop, not visible to the task
+                    self.parked.push(sys_pid);
+                } else {
+                    // Resumed before this arrived, so the task never sees it
+                    let result = if self.settings.instruction_trace {
+                        ptrace::single_step(sys_pid)
+                    } else {
+                        ptrace::cont(sys_pid)
+                    };
+                    ptrace::unless_exited(result);
+                }
+                return;
+            }
+        }
+        if siginfo.si_code == abi::CLD_TRAPPED && siginfo.si_status < 0x100 {
+            let forwarded = (sys_pid, siginfo.si_status as u8);
+            if let Some(index) = self.forwarded.iter().position(|item| *item == forwarded) {
+                self.forwarded.swap_remove(index);
+                let result = if self.settings.instruction_trace {
+                    ptrace::single_step_with_signal(sys_pid, forwarded.1)
+                } else {
+                    ptrace::cont_with_signal(sys_pid, forwarded.1)
+                };
+                ptrace::unless_exited(result);
+                return;
+            }
+        }
+        let event = Event::Signal {
+            sig: siginfo.si_signo,
+            code: siginfo.si_code,
+            status: siginfo.si_status,
+        };
+        match self.process_table.syspid_to_v(sys_pid) {
             Some(vpid) => {
-                self.task_event(
-                    vpid,
-                    Event::Signal {
-                        sig: siginfo.si_signo,
-                        code: siginfo.si_code,
-                        status: siginfo.si_status,
-                    },
-                );
+                self.task_event(vpid, event);
+                if sigstop {
+                    self.attached(sys_pid);
+                }
             }
+            // A new child can report its first stop before the parent has
+            // finished handling the fork, so keep it until the child is added
+            None => self.unclaimed.push((sys_pid, event)),
+        }
+    }
+
+    /// A new task has had its first stop, and a SIGSTOP sent now won't be
+    /// merged into that one
+    fn attached(&mut self, sys_pid: SysPid) {
+        if let Some(index) = self.attaching.iter().position(|pid| *pid == sys_pid) {
+            self.attaching.swap_remove(index);
+            self.stop(sys_pid);
         }
     }
 
@@ -128,17 +432,65 @@ impl<'t, F: Future<Output = ()>> Tracer<'t, F> {
             let outbox = process.unwrap().as_mut().check_outbox();
             match outbox {
                 None => break,
-                Some(op) => {
-                    self.ipc.send(&MessageFromSand::Task { task, op });
+                Some((op, nr)) => {
+                    self.ipc.send(&MessageFromSand::Task { task, op, nr });
                 }
             }
         }
+        let spawned = self.process_table.get(task).unwrap().as_mut().check_spawn();
+        if let Some(child) = spawned {
+            return self.spawn_event(task, child);
+        }
         match result {
             Poll::Pending => {}
             Poll::Ready(()) => {
                 // task exited normally, remove it from the process table
-                assert!(self.process_table.remove(task).is_some());
+                let sys_pid = self.process_table.remove(task);
+                assert!(sys_pid.is_some());
+                self.forwarded.retain(|(pid, _)| Some(*pid) != sys_pid);
+                self.stopping.retain(|pid| Some(*pid) != sys_pid);
+                self.attaching.retain(|pid| Some(*pid) != sys_pid);
+                self.parked.retain(|pid| Some(*pid) != sys_pid);
+                if task == VPid(1) {
+                    // Like init in a pid namespace, take everything else with it
+                    for sys_pid in self.process_table.sys_pids() {
+                        let _ = tkill(sys_pid, abi::SIGKILL);
+                    }
+                }
             }
         }
     }
+
+    fn spawn_event(&mut self, parent: VPid, child: ChildTask) {
+        let sys_pid = child.sys_pid;
+        let vpid = match self.process_table.insert(child) {
+            Some(vpid) => vpid,
+            None => {
+                // Out of VPids, so fork() fails the way it would at a limit
+                let _ = tkill(sys_pid, abi::SIGKILL);
+                self.unclaimed.retain(|(pid, _)| *pid != sys_pid);
+                return self.task_event(parent, Event::Forked(Err(Errno::new(abi::EAGAIN))));
+            }
+        };
+        self.task_event(parent, Event::Forked(Ok(vpid)));
+        if self.suspended {
+            // Children forked during a pause are stopped too, once attached
+            self.attaching.push(sys_pid);
+        }
+        let mut index = 0;
+        while index < self.unclaimed.len() {
+            if self.unclaimed[index].0 == sys_pid {
+                let (_, event) = self.unclaimed.remove(index);
+                self.task_event(vpid, event);
+                self.attached(sys_pid);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+extern "C" fn handle_sigchld(num: u32) {
+    // Only here to interrupt poll_with_signals()
+    assert_eq!(num, abi::SIGCHLD as u32);
 }
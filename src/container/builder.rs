@@ -1,21 +1,49 @@
 use crate::{
-    container::{Container, ExitStatus, Output},
+    container::{
+        events::EventCallback,
+        hooks::{HookCallback, Hooks},
+        notify,
+        pool::TracerPool,
+        secrets::SecretAudit,
+        snapshot::ExecSnapshots,
+        Container, ContainerEvent, ContainerId, ExitStatus, HookContext, HookFuture, HookStage,
+        NetworkGroup, Output, RunHistory, RunRecord, SessionRecording, Stdio, StreamId,
+        SystemdScope, TaggedOutput,
+    },
     errors::{ImageError, RuntimeError, VFSError},
-    filesystem::{mount::Mount, socket::SharedStream, storage::FileStorage, vfs::Filesystem},
+    filesystem::{
+        bind::BindMount, devices, hostfiles::HostFiles, mount::Mount, procfs, remap::PathRemap,
+        storage::FileStorage, tmpfs::Tmpfs, vfs::Filesystem, workspace::Workspaces,
+    },
+    image::ImageName,
+    ipcserver::AutoSuspend,
     manifest::ImageConfig,
-    sand,
-    sand::protocol::{FollowLinks, TracerSettings},
+    rt, sand,
+    sand::protocol::{abi, AttachMode, FileStat, FollowLinks, LogLevel, TracerSettings},
 };
+use bytes::Bytes;
 use std::{
+    collections::HashMap,
     ffi::{CString, NulError, OsStr},
+    mem,
     os::unix::{ffi::OsStrExt, net::UnixStream},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
+/// The usual default RLIMIT_STACK, see [ContainerBuilder::stack_limit()]
+const DEFAULT_STACK_LIMIT: usize = 8 * 1024 * 1024;
+
+/// Quota of the tmpfs mounts every container starts with at /tmp and /run,
+/// see [ContainerBuilder::tmpfs()]
+const DEFAULT_TMPFS_QUOTA: u64 = 64 * 1024 * 1024;
+
 /// Setup for containers, starting at [Container::new()] and ending with
 /// [ContainerBuilder::spawn()]
 #[derive(Clone)]
 pub struct ContainerBuilder {
+    image: ImageName,
     filesystem: Filesystem,
     storage: FileStorage,
     working_dir: CString,
@@ -25,26 +53,73 @@ pub struct ContainerBuilder {
     env: Vec<CString>,
     arg_error: Result<(), NulError>,
     mount_error: Result<(), VFSError>,
-    stdio: [Option<SharedStream>; 3],
+    read_only: bool,
+    stdio: [Stdio; 3],
+    record_session: bool,
+    tag_output: bool,
+    auto_suspend: Option<Duration>,
     tracer_settings: TracerSettings,
+    tracer_pool: Option<Arc<TracerPool>>,
+    exec_snapshots: Option<Arc<ExecSnapshots>>,
+    memory_limit: Option<u64>,
+    scope: Option<SystemdScope>,
+    path_remap: PathRemap,
+    secret_env: Vec<Vec<u8>>,
+    secret_files: Vec<(String, PathBuf)>,
+    network: Option<Arc<NetworkGroup>>,
+    history: Option<RunHistory>,
+    workspaces: Vec<(PathBuf, Workspaces, String)>,
+    events: Option<EventCallback>,
+    hooks: Vec<(HookStage, HookCallback)>,
 }
 
 impl ContainerBuilder {
     pub(crate) fn new(
+        image: ImageName,
         config: &ImageConfig,
         filesystem: Filesystem,
         storage: FileStorage,
     ) -> Result<Self, ImageError> {
-        Ok(ContainerBuilder {
+        let builder = ContainerBuilder {
+            image,
             filesystem,
             storage,
             tracer_settings: TracerSettings {
                 max_log_level: sand::max_log_level(),
                 instruction_trace: false,
+                instruction_pointer_checks: true,
+                // Only worth the cpu time when logs are heavy enough to matter
+                compress_messages: sand::max_log_level() >= LogLevel::Debug,
+                attach_mode: AttachMode::TraceMe,
+                abort_on_syscall_storm: false,
+                exec_snapshots: false,
+                log_ring_size: 0,
+                syscall_latency: false,
+                user_notif: false,
+                require_hardening: true,
+                randomize_layout: true,
+                stack_limit: DEFAULT_STACK_LIMIT,
+                vdso_time: true,
             },
             arg_error: Ok(()),
             mount_error: Ok(()),
-            stdio: [None, None, None],
+            read_only: false,
+            stdio: Default::default(),
+            record_session: false,
+            tag_output: false,
+            auto_suspend: None,
+            tracer_pool: None,
+            exec_snapshots: None,
+            memory_limit: None,
+            scope: None,
+            path_remap: PathRemap::default(),
+            secret_env: Vec::new(),
+            secret_files: Vec::new(),
+            network: None,
+            history: None,
+            workspaces: Vec::new(),
+            events: None,
+            hooks: Vec::new(),
             working_dir: CString::new(config.working_dir.as_bytes())?,
             entrypoint: match &config.entrypoint {
                 None => Vec::new(),
@@ -71,7 +146,10 @@ impl ContainerBuilder {
                 }
                 result
             },
-        })
+        };
+        Ok(builder
+            .tmpfs("/tmp", DEFAULT_TMPFS_QUOTA)
+            .mount("/run", &Tmpfs::new(DEFAULT_TMPFS_QUOTA).mode(0o755)))
     }
 
     /// Start a new [Container] using these settings, and wait for it to exit,
@@ -102,26 +180,101 @@ impl ContainerBuilder {
     }
 
     /// Start a new [Container] using the settings in this builder
-    pub fn spawn(mut self) -> Result<Container, RuntimeError> {
+    ///
+    /// This doesn't need to be called from a tokio runtime. The container
+    /// itself runs on a private runtime that bandsocks starts the first time
+    /// it's needed, on a thread of its own.
+    pub fn spawn(self) -> Result<Container, RuntimeError> {
+        rt::enter(|| self.spawn_entered())
+    }
+
+    fn spawn_entered(mut self) -> Result<Container, RuntimeError> {
         self.arg_error?;
         self.mount_error?;
+        let mut workspaces = Vec::new();
+        for (path, store, label) in &self.workspaces {
+            workspaces.push(store.restore(&self.image, label, &mut self.filesystem, path)?);
+        }
+        self.tracer_settings.attach_mode = sand::attach_mode()?;
+        self.tracer_settings.exec_snapshots = self.exec_snapshots.is_some();
+        log::debug!("attach mode {:?}", self.tracer_settings.attach_mode);
+        procfs::populate(&mut self.filesystem, self.memory_limit)?;
+        devices::populate(&mut self.filesystem)?;
+
+        // Like docker, create a working directory the image doesn't have
+        let working_dir = Path::new(OsStr::from_bytes(self.working_dir.as_bytes()));
+        if let Err(VFSError::NotFound) =
+            self.filesystem
+                .lookup(&Filesystem::root(), working_dir, &FollowLinks::Follow)
+        {
+            self.filesystem.writer().write_directory_metadata(
+                working_dir,
+                FileStat {
+                    st_mode: abi::S_IFDIR | 0o755,
+                    ..Default::default()
+                },
+            )?;
+        }
+
+        let mut secret_files = HashMap::new();
+        for (name, path) in &self.secret_files {
+            // Later mounts may have covered the file up
+            if let Ok(file) =
+                self.filesystem
+                    .lookup(&Filesystem::root(), path, &FollowLinks::NoFollow)
+            {
+                self.filesystem.set_private(&file);
+                secret_files.insert(file.inode, name.clone());
+            }
+        }
+        let secrets = Arc::new(SecretAudit::new(self.secret_env, secret_files));
 
         let mut local_stdio: [Option<UnixStream>; 3] = [None, None, None];
+        let mut auto_suspend = None;
         for fd in 0..3 {
-            let remote_stream = match self.stdio[fd].take() {
-                Some(stream) => stream,
-                None => {
-                    let (local, remote) = SharedStream::pair()?;
-                    local_stdio[fd] = Some(local);
-                    remote
-                }
-            };
+            let (remote_stream, local) = mem::take(&mut self.stdio[fd]).into_remote(fd)?;
+            local_stdio[fd] = local;
             remote_stream.mount(
                 &mut self.filesystem,
                 &Path::new(&format!("/proc/1/fd/{}", fd)),
             )?;
+            if fd == 0 {
+                auto_suspend = self.auto_suspend.map(|idle| AutoSuspend {
+                    idle,
+                    wake: remote_stream,
+                });
+            }
         }
 
+        self.filesystem.set_read_only(self.read_only);
+
+        let tagged_output = if self.tag_output {
+            let output = TaggedOutput::new();
+            for (local, stream) in local_stdio[1..]
+                .iter_mut()
+                .zip(&[StreamId::Stdout, StreamId::Stderr])
+            {
+                if let Some(source) = local.take() {
+                    *local = Some(output.tap(*stream, source)?);
+                }
+            }
+            Some(output)
+        } else {
+            None
+        };
+
+        let recording = if self.record_session {
+            let recording = SessionRecording::new();
+            for local in local_stdio[1..].iter_mut() {
+                if let Some(stream) = local.take() {
+                    *local = Some(recording.tap(stream)?);
+                }
+            }
+            Some(recording)
+        } else {
+            None
+        };
+
         let mut argv = self.entrypoint;
         match self.cmd_override {
             None => argv.extend(self.cmd_default),
@@ -148,7 +301,16 @@ impl ContainerBuilder {
             }
         }
 
-        Container::exec(
+        let args: Vec<String> = argv
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let started = (SystemTime::now(), Instant::now());
+        let id = ContainerId::new(&self.image);
+        log::info!("starting container {} from {}", id, self.image);
+
+        let mut container = Container::exec(
+            id,
             self.filesystem,
             self.storage,
             filename,
@@ -157,7 +319,65 @@ impl ContainerBuilder {
             self.env,
             local_stdio,
             self.tracer_settings,
-        )
+            auto_suspend,
+            self.tracer_pool.as_ref().and_then(TracerPool::take),
+            self.exec_snapshots,
+            self.memory_limit,
+            tagged_output,
+            self.path_remap,
+            secrets,
+            self.network,
+            workspaces,
+            self.events,
+            self.scope,
+            Hooks::new(id, self.hooks),
+        )?;
+        container.recording = recording;
+
+        Ok(match self.history {
+            None => container,
+            Some(history) => {
+                let image = self.image;
+                let memory = container.memory.clone();
+                let join = container.join;
+                Container {
+                    // Kept on the private runtime like the task it waits on
+                    join: rt::spawn_private(async move {
+                        let result = join.await?;
+                        let record = RunRecord {
+                            id: Some(id),
+                            image,
+                            args,
+                            started: started.0,
+                            duration: started.1.elapsed(),
+                            exit_code: result.as_ref().ok().and_then(ExitStatus::code),
+                            error: result.as_ref().err().map(ToString::to_string),
+                            peak_memory: memory.usage().peak,
+                        };
+                        // Recording waits on a file lock
+                        match rt::spawn_blocking(move || history.record(&record)).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => log::warn!("failed to record run history, {}", err),
+                            Err(err) => log::warn!("failed to record run history, {}", err),
+                        }
+                        result
+                    }),
+                    ..container
+                }
+            }
+        })
+    }
+
+    /// Claim a warm tracer from this pool when the container is spawned
+    pub(crate) fn tracer_pool(mut self, pool: Arc<TracerPool>) -> Self {
+        self.tracer_pool = Some(pool);
+        self
+    }
+
+    /// Share exec snapshots with other containers running the same image
+    pub(crate) fn exec_snapshots(mut self, snapshots: Arc<ExecSnapshots>) -> Self {
+        self.exec_snapshots = Some(snapshots);
+        self
     }
 
     /// Mount an overlay on the container's filesystem
@@ -169,27 +389,148 @@ impl ContainerBuilder {
         P: AsRef<Path>,
         T: Mount,
     {
+        // A mount could replace the entry point or its libraries
+        self.exec_snapshots = None;
         self.mount_error = self
             .mount_error
             .and(mount.mount(&mut self.filesystem, path.as_ref()));
         self
     }
 
-    /// Attach stdin to a specific shared stream
-    pub fn stdin(mut self, stream: SharedStream) -> Self {
-        self.stdio[0] = Some(stream);
+    /// Share a directory from the host at `path` in the container
+    ///
+    /// This is a shortcut for mounting a [BindMount]. The container sees the
+    /// host directory live, and with `read_only` unset, it can change files
+    /// there too.
+    pub fn bind_mount<P: AsRef<Path>, Q: AsRef<Path>>(
+        mut self,
+        host_path: P,
+        path: Q,
+        read_only: bool,
+    ) -> Self {
+        match BindMount::new(host_path.as_ref(), read_only) {
+            Ok(mount) => self.mount(path, &mount),
+            Err(err) => {
+                log::warn!("opening bind mount {:?}, {}", host_path.as_ref(), err);
+                self.mount_error = self.mount_error.and(Err(VFSError::IO));
+                self
+            }
+        }
+    }
+
+    /// Mount an empty in-memory directory at `path`, with a quota of
+    /// `quota` bytes
+    ///
+    /// This is a shortcut for mounting a [Tmpfs] that everyone can write to.
+    /// Every container starts with one at /tmp and another at /run, each
+    /// with a 64 MiB quota, and calling this again for either path replaces
+    /// it.
+    pub fn tmpfs<P: AsRef<Path>>(self, path: P, quota: u64) -> Self {
+        self.mount(path, &Tmpfs::new(quota))
+    }
+
+    /// Keep the container from changing files outside its tmpfs mounts
+    ///
+    /// Creating, removing, renaming, or writing files anywhere else fails in
+    /// the guest with `EROFS`, like running with a read-only root. Writable
+    /// bind mounts are still writable.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Keep a directory from one run to the next, in the workspace for this
+    /// image and `label`
+    ///
+    /// The directory starts out as the last container with the same image and
+    /// label left it, or as the image has it the first time, and it's saved
+    /// again when this container exits. This lets iterative builds reuse
+    /// their output, like a `target` directory, instead of starting cold.
+    pub fn workspace<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        workspaces: &Workspaces,
+        label: &str,
+    ) -> Self {
+        // The workspace could replace the entry point or its libraries
+        self.exec_snapshots = None;
+        self.workspaces.push((
+            path.as_ref().to_path_buf(),
+            workspaces.clone(),
+            label.to_string(),
+        ));
         self
     }
 
-    /// Attach stdout to a specific shared stream
-    pub fn stdout(mut self, stream: SharedStream) -> Self {
-        self.stdio[1] = Some(stream);
+    /// Copy the host's CA certificates and name service config into the
+    /// container, read-only
+    ///
+    /// This lets TLS clients in minimal images verify servers the same way
+    /// the host would. Only the paths listed in [HostFiles::network()] are
+    /// copied, replacing any files of the same name in the image.
+    pub fn host_network_config(mut self) -> Self {
+        match HostFiles::network() {
+            Ok(files) => self.mount("/", &files),
+            Err(err) => {
+                log::warn!("reading host network config, {}", err);
+                self.mount_error = self.mount_error.and(Err(VFSError::IO));
+                self
+            }
+        }
+    }
+
+    /// Join a loopback network shared with other containers
+    ///
+    /// TCP listeners on 127.0.0.1 or ::1 in any container of the group can be
+    /// reached from the others. Without a group, each container has a
+    /// loopback network of its own.
+    pub fn network_group(mut self, group: &Arc<NetworkGroup>) -> Self {
+        self.network = Some(group.clone());
+        self
+    }
+
+    /// Rewrite paths the container opens or stats which start with `from`,
+    /// so they start with `to` instead
+    ///
+    /// Rules are tried in the order they were added, and the first one whose
+    /// prefix matches by whole components wins. Paths are matched as the
+    /// process wrote them, before any symlinks or `..` are resolved, so only
+    /// absolute paths are affected. Combine with [ContainerBuilder::mount()]
+    /// to expose host files at a path of your choosing.
+    pub fn remap_path<P: AsRef<Path>, Q: AsRef<Path>>(mut self, from: P, to: Q) -> Self {
+        // A remapped path could change how the entry point loads
+        self.exec_snapshots = None;
+        self.path_remap.push(from.as_ref(), to.as_ref());
         self
     }
 
-    /// Attach stderr to a specific shared stream
-    pub fn stderr(mut self, stream: SharedStream) -> Self {
-        self.stdio[2] = Some(stream);
+    /// Choose where stdin comes from, [piped](Stdio::piped()) by default
+    ///
+    /// This takes a [Stdio] or a specific [SharedStream](crate::SharedStream).
+    /// Any tokio [AsyncRead](tokio::io::AsyncRead) can be used here via
+    /// [SharedStream::from_async_read()](crate::SharedStream::from_async_read).
+    pub fn stdin<T: Into<Stdio>>(mut self, cfg: T) -> Self {
+        self.stdio[0] = cfg.into();
+        self
+    }
+
+    /// Choose where stdout goes, [piped](Stdio::piped()) by default
+    ///
+    /// This takes a [Stdio] or a specific [SharedStream](crate::SharedStream).
+    /// Any tokio [AsyncWrite](tokio::io::AsyncWrite) can be used here via
+    /// [SharedStream::from_async_write()](crate::SharedStream::from_async_write).
+    pub fn stdout<T: Into<Stdio>>(mut self, cfg: T) -> Self {
+        self.stdio[1] = cfg.into();
+        self
+    }
+
+    /// Choose where stderr goes, [piped](Stdio::piped()) by default
+    ///
+    /// This takes a [Stdio] or a specific [SharedStream](crate::SharedStream).
+    /// Any tokio [AsyncWrite](tokio::io::AsyncWrite) can be used here via
+    /// [SharedStream::from_async_write()](crate::SharedStream::from_async_write).
+    pub fn stderr<T: Into<Stdio>>(mut self, cfg: T) -> Self {
+        self.stdio[2] = cfg.into();
         self
     }
 
@@ -273,6 +614,51 @@ impl ContainerBuilder {
         self
     }
 
+    /// Add or replace an environment variable holding a secret
+    ///
+    /// The value is left out of the runtime's logs. Environment variables
+    /// are copied into every process the container starts, so prefer
+    /// [ContainerBuilder::secret_file()] for programs that can read a file.
+    pub fn secret<K, V>(mut self, key: K, val: V) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.secret_env.push(key.as_ref().as_bytes().to_vec());
+        self.env(key, val)
+    }
+
+    /// Place a secret in a read-only file at `path`
+    ///
+    /// The file lives only in the container's memory, alongside anything the
+    /// container writes, and never in image storage or the cache directory.
+    /// Under a [workspace](ContainerBuilder::workspace()), it's left out of
+    /// the saved tree.
+    /// The first time any process opens it, the read is logged and recorded
+    /// under `name` in [Container::secret_accesses()].
+    pub fn secret_file<P, V>(mut self, name: &str, path: P, val: V) -> Self
+    where
+        P: AsRef<Path>,
+        V: AsRef<[u8]>,
+    {
+        // Snapshots are shared between containers, and must not hold secrets
+        self.exec_snapshots = None;
+        let contents = Bytes::copy_from_slice(val.as_ref());
+        let stat = FileStat {
+            st_mode: abi::S_IFREG | 0o400,
+            st_size: contents.len() as i64,
+            ..Default::default()
+        };
+        let result = self
+            .filesystem
+            .writer()
+            .write_file_bytes(path.as_ref(), stat, contents);
+        self.mount_error = self.mount_error.and(result);
+        self.secret_files
+            .push((name.to_string(), path.as_ref().to_path_buf()));
+        self
+    }
+
     /// Add or replace many environment variables
     pub fn envs<I, K, V>(mut self, vars: I) -> Self
     where
@@ -299,6 +685,236 @@ impl ContainerBuilder {
         self
     }
 
+    /// Add a [RunRecord] to a [RunHistory] when the container exits
+    ///
+    /// The record is written once the container finishes, whether or not
+    /// anything waits for it.
+    pub fn run_history(mut self, history: &RunHistory) -> Self {
+        self.history = Some(history.clone());
+        self
+    }
+
+    /// Record the container's stdout and stderr with timing information
+    ///
+    /// The recording is available from [Container::recording()] and can be
+    /// saved in asciicast format for replay. Streams attached with
+    /// [ContainerBuilder::stdout()] or [ContainerBuilder::stderr()] are not
+    /// part of the recording.
+    pub fn record_session(mut self) -> Self {
+        self.record_session = true;
+        self
+    }
+
+    /// Split the container's stdout and stderr into lines tagged with the
+    /// process that wrote them
+    ///
+    /// The tagged lines are available from [Container::tagged_output()], as
+    /// one merged stream or one stream per process. The original output is
+    /// still available as usual. Streams attached with
+    /// [ContainerBuilder::stdout()] or [ContainerBuilder::stderr()] are not
+    /// tagged.
+    pub fn tag_output(mut self) -> Self {
+        self.tag_output = true;
+        self
+    }
+
+    /// Suspend the container whenever it sits idle for this long
+    ///
+    /// A container is idle when every process is blocked reading and no input
+    /// is waiting on its stdin. Suspended processes are stopped and cost
+    /// nothing until new input arrives on stdin, which resumes them
+    /// transparently. Containers that only wait on other streams are never
+    /// suspended, and neither is anything on a kernel without
+    /// `/proc/<pid>/syscall` to tell a blocked read.
+    pub fn auto_suspend(mut self, idle: Duration) -> Self {
+        self.auto_suspend = Some(idle);
+        self
+    }
+
+    /// Cap the host memory held for this container, in bytes
+    ///
+    /// This covers files passed to the sandbox by descriptor and the resident
+    /// size of guest mappings, as reported by [Container::memory_usage()].
+    /// Once the cap is reached, opening a file that would need a descriptor
+    /// fails in the guest with `ENOMEM`. Mappings the guest makes on its own
+    /// can't be refused, but they count toward the cap.
+    pub fn memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Run the container in a transient systemd scope with these resource
+    /// limits
+    ///
+    /// The limits are applied by the user's systemd manager, so the runtime
+    /// needs no extra privileges. See [SystemdScope] for the requirements.
+    pub fn systemd_scope(mut self, scope: SystemdScope) -> Self {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Set the most verbose log level the sandbox runtime will send
+    ///
+    /// By default this follows whichever levels are enabled for this crate in
+    /// the [log] facade. Messages still pass through [log] on arrival, so this
+    /// can only reduce what gets logged, and the cost of producing it.
+    pub fn sandbox_log_level(mut self, level: LogLevel) -> Self {
+        self.tracer_settings.max_log_level = level;
+        self.tracer_settings.compress_messages = level >= LogLevel::Debug;
+        self
+    }
+
+    /// Keep this many bytes of recent sandbox log messages, none by default
+    ///
+    /// The sandbox runtime records every message it could log, even those
+    /// below [ContainerBuilder::sandbox_log_level()], in a ring buffer. If it
+    /// panics, the ring's contents follow the panic message in
+    /// [RuntimeError::SandPanic], so failures can be understood without
+    /// running again at trace level. That means formatting every syscall,
+    /// which is why it's off unless asked for; 16 KiB is plenty.
+    pub fn sandbox_log_ring(mut self, bytes: usize) -> Self {
+        self.tracer_settings.log_ring_size = bytes;
+        self
+    }
+
+    /// Call this for each [ContainerEvent] while the container runs
+    ///
+    /// Events arrive in order, one per process start and exit and one for
+    /// each request a process makes of the runtime, tagged with its VPid.
+    /// The callback runs on the container's runtime task, which waits for
+    /// it, so anything slow belongs on a channel instead.
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ContainerEvent) + Send + Sync + 'static,
+    {
+        self.events = Some(Arc::new(callback));
+        self
+    }
+
+    /// Run `callback` at one [HookStage] of the container's life
+    ///
+    /// Hooks reach the container through a [HookContext], which can add
+    /// files before it starts or collect them before its filesystem goes
+    /// away. They run on the container's runtime task in the order they were
+    /// added, and the container waits for each one. An error from a hook
+    /// ends the container with that error.
+    pub fn hook<F>(mut self, stage: HookStage, callback: F) -> Self
+    where
+        F: for<'a, 'b> Fn(&'a mut HookContext<'b>) -> HookFuture<'a> + Send + Sync + 'static,
+    {
+        self.hooks.push((stage, Arc::new(callback)));
+        self
+    }
+
+    /// Tell systemd the service is ready once the container's first process
+    /// has loaded its program
+    ///
+    /// This sends READY=1 to the socket in NOTIFY_SOCKET, for a `Type=notify`
+    /// unit whose workload is this container. Readiness is only reported
+    /// once per process, however many containers ask, and nothing is sent
+    /// unless systemd provided a socket.
+    pub fn notify_systemd(self) -> Self {
+        self.hook(HookStage::PostExec, |_| {
+            notify::ready();
+            Box::pin(async { Ok(()) })
+        })
+    }
+
+    /// Time each phase of every emulated syscall
+    ///
+    /// The results are available from [Container::syscall_latency()]. This
+    /// adds a few clock reads and one extra message to each syscall.
+    pub fn syscall_latency_stats(mut self) -> Self {
+        self.tracer_settings.syscall_latency = true;
+        self
+    }
+
+    /// Answer open, stat, and access without stopping the process
+    ///
+    /// These calls are normally intercepted with a ptrace stop, and emulated
+    /// by running code inside the stopped process. With this option, calls
+    /// relative to the working directory are delivered to the sandbox runtime
+    /// as seccomp user notifications instead, and opened files are installed
+    /// directly. Other calls are still emulated the usual way. This needs
+    /// Linux 5.19 or later.
+    pub fn seccomp_user_notif(mut self) -> Self {
+        self.tracer_settings.user_notif = true;
+        self
+    }
+
+    /// Run even if the sandbox can't fully give up its privileges
+    ///
+    /// Before starting anything, the sandbox runtime drops every capability
+    /// and confirms that no_new_privs is set. By default a container fails
+    /// with [RuntimeError::SandWeakHardening] if any of that didn't take
+    /// effect. With this option, the container runs anyway and the problem is
+    /// only logged as a warning.
+    pub fn allow_weak_hardening(mut self) -> Self {
+        self.tracer_settings.require_hardening = false;
+        self
+    }
+
+    /// Load programs at the same addresses on every run
+    ///
+    /// Normally the stack, heap, and position-independent programs and
+    /// interpreters are placed at random, like the kernel does for ordinary
+    /// processes. This turns that off, along with the kernel's randomization
+    /// of everything else inside the sandbox, so that runs can be reproduced
+    /// exactly. Memory addresses become predictable to the sandboxed code too.
+    pub fn deterministic_layout(mut self) -> Self {
+        self.tracer_settings.randomize_layout = false;
+        self
+    }
+
+    /// Set the stack size limit, in bytes, 8 MiB by default
+    ///
+    /// This becomes the soft `RLIMIT_STACK` inside the container, lowered to
+    /// the hard limit the sandbox runtime inherited if it's higher than that.
+    /// Each program's initial stack gets this much room reserved, up to 1 GiB,
+    /// with an inaccessible guard page below so that a stack overflow stops
+    /// the program with `SIGSEGV` instead of running into other mappings.
+    pub fn stack_limit(mut self, bytes: usize) -> Self {
+        self.tracer_settings.stack_limit = bytes;
+        self
+    }
+
+    /// Let programs read the clock through the vDSO, on by default
+    ///
+    /// The kernel's vDSO answers `clock_gettime()`, `gettimeofday()`, and
+    /// `time()` from shared memory, without a syscall the sandbox could see,
+    /// and the same calls made directly go straight to the kernel. Turning
+    /// this off patches those vDSO functions into ordinary syscalls and traces
+    /// all of them, so every clock read reaches the sandbox no matter how a
+    /// program makes it, and shows up in [Container::syscall_latency()]. The
+    /// clock is still the host's, and each read costs a trip through the
+    /// tracer.
+    pub fn vdso_time(mut self, enabled: bool) -> Self {
+        self.tracer_settings.vdso_time = enabled;
+        self
+    }
+
+    /// Verify where each intercepted syscall came from, on by default
+    ///
+    /// Before emulating a syscall, the sandbox checks that it was made by a
+    /// syscall instruction in an executable mapping. This costs a memory
+    /// read per syscall, and turning it off trades that check for speed.
+    pub fn instruction_pointer_checks(mut self, enabled: bool) -> Self {
+        self.tracer_settings.instruction_pointer_checks = enabled;
+        self
+    }
+
+    /// Kill a process that gets stuck retrying a failing syscall
+    ///
+    /// Thousands of identical failing calls in a row are always logged as a
+    /// warning and reported as a [ContainerEvent::SyscallStorm]. With this
+    /// option, the process is also killed with SIGKILL instead of spinning at
+    /// full CPU. The rest of the container keeps running, unless that process
+    /// was its init.
+    pub fn abort_on_syscall_storm(mut self) -> Self {
+        self.tracer_settings.abort_on_syscall_storm = true;
+        self
+    }
+
     /// Run the container in single-step mode
     ///
     /// This is extremely verbose, and intended only for debugging or reporting
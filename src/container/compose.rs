@@ -0,0 +1,477 @@
+use crate::{
+    container::{Container, ExitStatus, NetworkGroup, Stdio},
+    errors::{ImageError, RuntimeError},
+    image::ImageName,
+    registry::RegistryClient,
+    rt,
+};
+use futures_util::future::select_all;
+use std::{
+    collections::BTreeMap, fs, net::Shutdown, os::unix::net::UnixStream, path::Path, sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    task::JoinHandle,
+    time::Duration,
+};
+
+/// Several containers which start in order and stop together
+///
+/// A compose spec is a JSON file naming each service, the image it runs, and
+/// the other services it depends on. For example:
+///
+/// ```json
+/// {
+///   "services": {
+///     "server": {
+///       "image": "busybox",
+///       "command": ["httpd", "-f", "-p", "8080"],
+///       "healthcheck": { "test": ["nc", "-z", "127.0.0.1", "8080"] }
+///     },
+///     "client": {
+///       "image": "busybox",
+///       "command": ["wget", "-O-", "http://127.0.0.1:8080/"],
+///       "depends_on": ["server"]
+///     }
+///   }
+/// }
+/// ```
+///
+/// All services share one [NetworkGroup], so they can reach each other's
+/// listening ports on the loopback network.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ComposeSpec {
+    services: BTreeMap<String, ServiceSpec>,
+    order: Vec<String>,
+}
+
+/// One container in a [ComposeSpec]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServiceSpec {
+    /// Image to run
+    pub image: ImageName,
+    /// Replaces the image's entry point
+    pub entrypoint: Option<Vec<String>>,
+    /// Replaces the image's default arguments
+    pub command: Option<Vec<String>>,
+    /// Environment variables added to the image's own
+    pub environment: BTreeMap<String, String>,
+    /// Services which must be started, and healthy, before this one
+    pub depends_on: Vec<String>,
+    /// Command which decides whether the service is ready
+    pub healthcheck: Option<HealthCheck>,
+}
+
+/// A command to run until a service is ready
+///
+/// Each attempt runs the command in a new container, from the service's own
+/// image and on the same network. The service is healthy once the command
+/// exits successfully.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthCheck {
+    /// Full command line for the check
+    pub test: Vec<String>,
+    /// Time to wait between attempts
+    pub interval: Duration,
+    /// Attempts to make before giving up
+    pub retries: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComposeFile {
+    services: BTreeMap<String, ServiceFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServiceFile {
+    image: String,
+    #[serde(default)]
+    entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    command: Option<Vec<String>>,
+    #[serde(default)]
+    environment: BTreeMap<String, String>,
+    #[serde(default)]
+    depends_on: Vec<String>,
+    #[serde(default)]
+    healthcheck: Option<HealthCheckFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckFile {
+    test: Vec<String>,
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+    #[serde(default = "default_retries")]
+    retries: u32,
+}
+
+fn default_interval_ms() -> u64 {
+    500
+}
+
+fn default_retries() -> u32 {
+    60
+}
+
+impl ComposeSpec {
+    /// Check a set of services, and work out the order they start in
+    pub fn new(services: BTreeMap<String, ServiceSpec>) -> Result<Self, RuntimeError> {
+        if services.is_empty() {
+            return Err(RuntimeError::InvalidComposeSpec(
+                "no services defined".to_string(),
+            ));
+        }
+        for (name, service) in &services {
+            for dependency in &service.depends_on {
+                if !services.contains_key(dependency) {
+                    return Err(RuntimeError::InvalidComposeSpec(format!(
+                        "service {:?} depends on {:?}, which is not defined",
+                        name, dependency
+                    )));
+                }
+            }
+            if let Some(check) = &service.healthcheck {
+                if check.test.is_empty() {
+                    return Err(RuntimeError::InvalidComposeSpec(format!(
+                        "service {:?} has an empty health check",
+                        name
+                    )));
+                }
+            }
+        }
+
+        // Repeatedly take every service whose dependencies have all started,
+        // in name order so the result doesn't depend on anything else
+        let mut order: Vec<String> = Vec::new();
+        while order.len() < services.len() {
+            let ready: Vec<String> = services
+                .iter()
+                .filter(|(name, service)| {
+                    !order.contains(name)
+                        && service.depends_on.iter().all(|dep| order.contains(dep))
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            if ready.is_empty() {
+                let stuck: Vec<&str> = services
+                    .keys()
+                    .filter(|name| !order.contains(name))
+                    .map(String::as_str)
+                    .collect();
+                return Err(RuntimeError::InvalidComposeSpec(format!(
+                    "dependency cycle among {}",
+                    stuck.join(", ")
+                )));
+            }
+            order.extend(ready);
+        }
+        Ok(ComposeSpec { services, order })
+    }
+
+    /// Parse a spec from its JSON representation
+    pub fn parse(json: &[u8]) -> Result<Self, RuntimeError> {
+        let file: ComposeFile = serde_json::from_slice(json).map_err(ImageError::from)?;
+        let mut services = BTreeMap::new();
+        for (name, service) in file.services {
+            services.insert(
+                name,
+                ServiceSpec {
+                    image: service.image.parse()?,
+                    entrypoint: service.entrypoint,
+                    command: service.command,
+                    environment: service.environment,
+                    depends_on: service.depends_on,
+                    healthcheck: service.healthcheck.map(|check| HealthCheck {
+                        test: check.test,
+                        interval: Duration::from_millis(check.interval_ms),
+                        retries: check.retries,
+                    }),
+                },
+            );
+        }
+        ComposeSpec::new(services)
+    }
+
+    /// Read a spec file from disk
+    pub fn load(path: &Path) -> Result<Self, RuntimeError> {
+        ComposeSpec::parse(&fs::read(path)?)
+    }
+
+    /// Iterate over each service by name, in the order they start
+    pub fn services(&self) -> impl Iterator<Item = (&str, &ServiceSpec)> {
+        self.order
+            .iter()
+            .map(move |name| (name.as_str(), &self.services[name]))
+    }
+
+    /// Pull every image and start each service in order
+    ///
+    /// Services start one at a time, and a service with a health check must
+    /// pass it before the next one starts. If any service fails to start or
+    /// to become healthy, the ones already running are stopped.
+    ///
+    /// Services get no stdin. Each line of their output goes to the same
+    /// stream of the current process, with the service name in front.
+    pub async fn up(&self, client: &RegistryClient) -> Result<Compose, RuntimeError> {
+        let mut compose = Compose {
+            network: NetworkGroup::new("compose")?,
+            services: Vec::new(),
+        };
+        for (name, spec) in self.services() {
+            if let Err(err) = compose.start(client, name, spec).await {
+                compose.down().await;
+                return Err(err);
+            }
+        }
+        Ok(compose)
+    }
+}
+
+/// Services started from a [ComposeSpec]
+#[derive(Debug)]
+pub struct Compose {
+    network: Arc<NetworkGroup>,
+    services: Vec<RunningService>,
+}
+
+#[derive(Debug)]
+struct RunningService {
+    name: String,
+    control: UnixStream,
+    join: JoinHandle<Result<ExitStatus, RuntimeError>>,
+}
+
+impl Compose {
+    /// Get the network all services share
+    pub fn network_group(&self) -> &Arc<NetworkGroup> {
+        &self.network
+    }
+
+    /// Iterate over the names of running services, in the order they started
+    pub fn service_names(&self) -> impl Iterator<Item = &str> {
+        self.services.iter().map(|service| service.name.as_str())
+    }
+
+    /// Wait until any service exits, then stop the others
+    ///
+    /// Returns the name and exit status of the service that finished first.
+    pub async fn wait(mut self) -> Result<(String, ExitStatus), RuntimeError> {
+        let joins = self.services.iter_mut().map(|service| &mut service.join);
+        let (result, index, _) = select_all(joins).await;
+        let finished = self.services.remove(index);
+        self.down().await;
+        Ok((finished.name, result??))
+    }
+
+    /// Stop every service, most recently started first
+    pub async fn down(mut self) {
+        while let Some(service) = self.services.pop() {
+            log::debug!("stopping service {:?}", service.name);
+            let _ = service.control.shutdown(Shutdown::Both);
+            let _ = service.join.await;
+        }
+    }
+
+    async fn start(
+        &mut self,
+        client: &RegistryClient,
+        name: &str,
+        spec: &ServiceSpec,
+    ) -> Result<(), RuntimeError> {
+        log::info!("starting service {:?} from {}", name, spec.image);
+        let image = client.pull(&spec.image).await?;
+        let mut builder = Container::new(image.clone())?
+            .network_group(&self.network)
+            .stdin(Stdio::null())
+            .envs(&spec.environment);
+        if let Some(entrypoint) = &spec.entrypoint {
+            builder = builder.entrypoint(entrypoint);
+        }
+        if let Some(command) = &spec.command {
+            builder = builder.args(command);
+        }
+        let mut container = builder.spawn()?;
+        let control = container.control.try_clone()?;
+        let stdout = forward_lines(name, container.stdout.take(), tokio::io::stdout());
+        let stderr = forward_lines(name, container.stderr.take(), tokio::io::stderr());
+        let mut join = rt::spawn(async move {
+            let status = container.wait().await;
+            let _ = tokio::join!(stdout, stderr);
+            status
+        });
+
+        if let Some(check) = &spec.healthcheck {
+            let healthy = async {
+                for _ in 0..check.retries {
+                    let output = Container::new(image.clone())?
+                        .network_group(&self.network)
+                        .entrypoint(Vec::<String>::new())
+                        .args(&check.test)
+                        .output()
+                        .await?;
+                    if output.status.success() {
+                        return Ok(());
+                    }
+                    rt::delay_for(check.interval).await;
+                }
+                Err(RuntimeError::HealthCheckFailed(name.to_string()))
+            };
+            let result: Result<(), RuntimeError> = tokio::select! {
+                result = healthy => result,
+                status = &mut join => Err(RuntimeError::ServiceExited {
+                    service: name.to_string(),
+                    status: status??,
+                }),
+            };
+            if let Err(err) = result {
+                let _ = control.shutdown(Shutdown::Both);
+                let _ = join.await;
+                return Err(err);
+            }
+            log::info!("service {:?} is healthy", name);
+        }
+
+        self.services.push(RunningService {
+            name: name.to_string(),
+            control,
+            join,
+        });
+        Ok(())
+    }
+}
+
+/// Copy lines from a service's output stream, with its name in front
+fn forward_lines<R, W>(
+    name: &str,
+    stream: Option<R>,
+    mut dest: W,
+) -> JoinHandle<tokio::io::Result<()>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let prefix = format!("{} | ", name).into_bytes();
+    rt::spawn(async move {
+        if let Some(stream) = stream {
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                line.extend_from_slice(&prefix);
+                if reader.read_until(b'\n', &mut line).await? == 0 {
+                    break;
+                }
+                if line.last() != Some(&b'\n') {
+                    line.push(b'\n');
+                }
+                dest.write_all(&line).await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(depends_on: &[&str]) -> ServiceSpec {
+        ServiceSpec {
+            image: "busybox".parse().unwrap(),
+            entrypoint: None,
+            command: None,
+            environment: BTreeMap::new(),
+            depends_on: depends_on.iter().map(|dep| dep.to_string()).collect(),
+            healthcheck: None,
+        }
+    }
+
+    fn order(spec: &ComposeSpec) -> Vec<&str> {
+        spec.services().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let spec = ComposeSpec::parse(
+            br#"{
+                "services": {
+                    "web": {
+                        "image": "busybox",
+                        "command": ["httpd", "-f"],
+                        "environment": { "PORT": "80" },
+                        "depends_on": ["db"]
+                    },
+                    "db": {
+                        "image": "redis:6",
+                        "healthcheck": { "test": ["redis-cli", "ping"], "retries": 3 }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(order(&spec), vec!["db", "web"]);
+        let (_, db) = spec.services().next().unwrap();
+        assert_eq!(db.image, "redis:6".parse().unwrap());
+        assert_eq!(
+            db.healthcheck,
+            Some(HealthCheck {
+                test: vec!["redis-cli".to_string(), "ping".to_string()],
+                interval: Duration::from_millis(500),
+                retries: 3,
+            })
+        );
+        let (_, web) = spec.services().nth(1).unwrap();
+        assert_eq!(
+            web.command,
+            Some(vec!["httpd".to_string(), "-f".to_string()])
+        );
+        assert_eq!(web.environment.get("PORT").map(String::as_str), Some("80"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(ComposeSpec::parse(b"{}").is_err());
+        assert!(ComposeSpec::parse(br#"{"services": {}}"#).is_err());
+        assert!(
+            ComposeSpec::parse(br#"{"services": {"a": {"image": "x", "ports": []}}}"#).is_err()
+        );
+        assert!(ComposeSpec::parse(br#"{"services": {"a": {"image": "Not Valid"}}}"#).is_err());
+        assert!(ComposeSpec::parse(
+            br#"{"services": {"a": {"image": "x", "healthcheck": {"test": []}}}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn startup_order() {
+        let mut services = BTreeMap::new();
+        services.insert("app".to_string(), service(&["cache", "db"]));
+        services.insert("cache".to_string(), service(&[]));
+        services.insert("db".to_string(), service(&[]));
+        services.insert("proxy".to_string(), service(&["app"]));
+        let spec = ComposeSpec::new(services).unwrap();
+        assert_eq!(order(&spec), vec!["cache", "db", "app", "proxy"]);
+    }
+
+    #[test]
+    fn dependency_errors() {
+        let mut services = BTreeMap::new();
+        services.insert("app".to_string(), service(&["db"]));
+        match ComposeSpec::new(services.clone()) {
+            Err(RuntimeError::InvalidComposeSpec(reason)) => assert!(reason.contains("\"db\"")),
+            other => panic!("unexpected {:?}", other),
+        }
+        services.insert("db".to_string(), service(&["app"]));
+        services.insert("other".to_string(), service(&[]));
+        match ComposeSpec::new(services) {
+            Err(RuntimeError::InvalidComposeSpec(reason)) => {
+                assert_eq!(reason, "dependency cycle among app, db")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
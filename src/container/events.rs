@@ -0,0 +1,199 @@
+use crate::sand::protocol::{FromTask, VPid};
+use std::{sync::Arc, time::Duration};
+
+/// Something that happened inside a running container, see
+/// [ContainerBuilder::on_event()](crate::ContainerBuilder::on_event)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ContainerEvent {
+    /// A process started, either as init or as a fork of `parent`
+    ProcessStarted { vpid: VPid, parent: Option<VPid> },
+    /// A process asked the runtime for something it can't do on its own, and
+    /// was answered
+    Request {
+        vpid: VPid,
+        /// The syscall the process made, if the request was on behalf of one
+        nr: Option<isize>,
+        /// Name of the request, like `FileOpen` or `NetConnect`
+        op: &'static str,
+        /// Time the runtime spent handling it
+        elapsed: Duration,
+    },
+    /// A process exited with this code
+    ProcessExited { vpid: VPid, code: i32 },
+    /// A process was ended by a signal
+    ProcessSignaled { vpid: VPid, signal: i32 },
+    /// A process keeps making the same syscall, which keeps failing
+    ///
+    /// This is reported each time thousands of identical calls have failed
+    /// in a row. With
+    /// [ContainerBuilder::abort_on_syscall_storm()](crate::ContainerBuilder::abort_on_syscall_storm)
+    /// the process is killed as well.
+    SyscallStorm {
+        vpid: VPid,
+        /// The syscall number
+        nr: isize,
+        /// The error it returns, as a negative errno
+        ret: isize,
+    },
+}
+
+pub(crate) type EventCallback = Arc<dyn Fn(&ContainerEvent) + Send + Sync>;
+
+impl ContainerEvent {
+    /// The event for a message one task sent to the runtime, if it's worth
+    /// telling anyone about
+    pub(crate) fn from_task(
+        vpid: VPid,
+        nr: Option<isize>,
+        op: &FromTask,
+        elapsed: Duration,
+    ) -> Option<Self> {
+        match op {
+            FromTask::Log(..) | FromTask::SyscallLatency { .. } => None,
+            FromTask::OpenProcess(_) => Some(ContainerEvent::ProcessStarted { vpid, parent: None }),
+            FromTask::OpenChildProcess { parent, .. } => Some(ContainerEvent::ProcessStarted {
+                vpid,
+                parent: Some(*parent),
+            }),
+            FromTask::Exited(code) => Some(ContainerEvent::ProcessExited { vpid, code: *code }),
+            FromTask::Signaled(signal) => Some(ContainerEvent::ProcessSignaled {
+                vpid,
+                signal: signal.0 as i32,
+            }),
+            FromTask::SyscallStorm(call) => Some(ContainerEvent::SyscallStorm {
+                vpid,
+                nr: call.nr,
+                ret: call.ret,
+            }),
+            op => Some(ContainerEvent::Request {
+                vpid,
+                nr,
+                op: op_name(op),
+                elapsed,
+            }),
+        }
+    }
+}
+
+/// The name of a message from a task, for logs and events
+pub(crate) fn op_name(op: &FromTask) -> &'static str {
+    match op {
+        FromTask::Log(..) => "Log",
+        FromTask::SyscallLatency { .. } => "SyscallLatency",
+        FromTask::SyscallStorm(_) => "SyscallStorm",
+        FromTask::OpenProcess(_) => "OpenProcess",
+        FromTask::OpenChildProcess { .. } => "OpenChildProcess",
+        FromTask::Exited(_) => "Exited",
+        FromTask::Signaled(_) => "Signaled",
+        FromTask::FileAccess { .. } => "FileAccess",
+        FromTask::FileOpen { .. } => "FileOpen",
+        FromTask::FileStat { .. } => "FileStat",
+        FromTask::ReadLink { .. } => "ReadLink",
+        FromTask::ProcessKill(..) => "ProcessKill",
+        FromTask::ChangeWorkingDir { .. } => "ChangeWorkingDir",
+        FromTask::GetWorkingDir => "GetWorkingDir",
+        FromTask::ExecSnapshotOpen => "ExecSnapshotOpen",
+        FromTask::ExecSnapshotCapture { .. } => "ExecSnapshotCapture",
+        FromTask::MakeDir { .. } => "MakeDir",
+        FromTask::Unlink { .. } => "Unlink",
+        FromTask::Rename { .. } => "Rename",
+        FromTask::ExecLoaded { .. } => "ExecLoaded",
+        FromTask::NetBind { .. } => "NetBind",
+        FromTask::NetConnect { .. } => "NetConnect",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sand::protocol::{abi, Signal, SysPid, VPtr, VString};
+
+    #[test]
+    fn task_messages() {
+        let elapsed = Duration::from_micros(5);
+        assert_eq!(
+            ContainerEvent::from_task(VPid(1), None, &FromTask::OpenProcess(SysPid(100)), elapsed),
+            Some(ContainerEvent::ProcessStarted {
+                vpid: VPid(1),
+                parent: None
+            })
+        );
+        assert_eq!(
+            ContainerEvent::from_task(
+                VPid(2),
+                None,
+                &FromTask::OpenChildProcess {
+                    sys_pid: SysPid(101),
+                    parent: VPid(1)
+                },
+                elapsed
+            ),
+            Some(ContainerEvent::ProcessStarted {
+                vpid: VPid(2),
+                parent: Some(VPid(1))
+            })
+        );
+        assert_eq!(
+            ContainerEvent::from_task(
+                VPid(2),
+                Some(83),
+                &FromTask::MakeDir {
+                    dir: None,
+                    path: VString(VPtr(0x1000)),
+                    mode: 0o755
+                },
+                elapsed
+            ),
+            Some(ContainerEvent::Request {
+                vpid: VPid(2),
+                nr: Some(83),
+                op: "MakeDir",
+                elapsed
+            })
+        );
+        assert_eq!(
+            ContainerEvent::from_task(VPid(2), None, &FromTask::Signaled(Signal(15)), elapsed),
+            Some(ContainerEvent::ProcessSignaled {
+                vpid: VPid(2),
+                signal: 15
+            })
+        );
+        assert_eq!(
+            ContainerEvent::from_task(VPid(1), None, &FromTask::Exited(3), elapsed),
+            Some(ContainerEvent::ProcessExited {
+                vpid: VPid(1),
+                code: 3
+            })
+        );
+        assert_eq!(
+            ContainerEvent::from_task(
+                VPid(1),
+                None,
+                &FromTask::SyscallLatency {
+                    nr: 39,
+                    trap: 1,
+                    emulate: 2,
+                    ipc: 0,
+                    resume: 1
+                },
+                elapsed
+            ),
+            None
+        );
+        let call = abi::Syscall {
+            nr: 80,
+            args: [0x1000, 0, 0, 0, 0, 0],
+            ret: -2,
+            ip: 0,
+            sp: 0,
+        };
+        assert_eq!(
+            ContainerEvent::from_task(VPid(3), None, &FromTask::SyscallStorm(call), elapsed),
+            Some(ContainerEvent::SyscallStorm {
+                vpid: VPid(3),
+                nr: 80,
+                ret: -2
+            })
+        );
+    }
+}
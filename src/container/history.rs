@@ -0,0 +1,507 @@
+use crate::{
+    container::ContainerId,
+    errors::ImageError,
+    filesystem::storage::{FileStorage, StorageKey},
+    image::ImageName,
+};
+use std::{
+    ffi::OsString,
+    fs,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Default size limit for a history file, enough for tens of thousands of runs
+const MAX_HISTORY_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// A log of finished container runs, kept on disk
+///
+/// Containers built with
+/// [ContainerBuilder::run_history()](crate::ContainerBuilder::run_history)
+/// add a [RunRecord] here when they exit. The history is a file of JSON
+/// lines, one per run, which any number of processes can append to at once.
+/// The usual place for it is the cache directory, see
+/// [RegistryClient::run_history()](crate::RegistryClient::run_history).
+///
+/// A file that would grow past its [size limit](RunHistory::max_size) is
+/// renamed with `.1` added, replacing the one before, and a new file starts.
+/// Queries read both, so the history keeps the most recent runs.
+#[derive(Clone, Debug)]
+pub struct RunHistory {
+    path: PathBuf,
+    max_size: u64,
+    // Keeps an ephemeral cache directory around for as long as its history
+    _storage: Option<FileStorage>,
+}
+
+/// One finished container run
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunRecord {
+    /// ID of the container, or `None` for runs recorded before containers
+    /// had IDs
+    pub id: Option<ContainerId>,
+    /// The image that ran, including its content digest
+    pub image: ImageName,
+    /// Full command line of the container's first process
+    pub args: Vec<String>,
+    /// When the container was spawned
+    pub started: SystemTime,
+    /// Time from spawning the container until it exited
+    pub duration: Duration,
+    /// Exit code, as in [ExitStatus::code()](crate::ExitStatus::code), or
+    /// `None` if the runtime failed
+    pub exit_code: Option<i32>,
+    /// Runtime error that ended the container, if any
+    pub error: Option<String>,
+    /// Most host memory held for the container at once, as in
+    /// [MemoryUsage::peak](crate::MemoryUsage::peak)
+    pub peak_memory: u64,
+}
+
+/// Which runs to return from [RunHistory::query()]
+///
+/// The default query matches every run.
+#[derive(Clone, Debug, Default)]
+pub struct RunQuery {
+    id: Option<String>,
+    image: Option<ImageName>,
+    failed: bool,
+    since: Option<SystemTime>,
+    last: Option<usize>,
+}
+
+/// Totals over a set of runs, from [RunSummary::new()]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RunSummary {
+    /// Number of runs
+    pub runs: usize,
+    /// Runs that didn't exit successfully
+    pub failures: usize,
+    /// Shortest run
+    pub min_duration: Duration,
+    /// Average run
+    pub mean_duration: Duration,
+    /// Longest run
+    pub max_duration: Duration,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RecordLine {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    image: String,
+    args: Vec<String>,
+    started_ms: u64,
+    duration_ms: u64,
+    exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    peak_memory: u64,
+}
+
+impl RunHistory {
+    /// Use a history file at any path, which is created on the first record
+    pub fn open(path: &Path) -> Self {
+        RunHistory {
+            path: path.to_path_buf(),
+            max_size: MAX_HISTORY_FILE_SIZE,
+            _storage: None,
+        }
+    }
+
+    pub(crate) fn in_storage(storage: &FileStorage) -> Self {
+        RunHistory {
+            path: storage.path_of(&StorageKey::History),
+            max_size: MAX_HISTORY_FILE_SIZE,
+            _storage: Some(storage.clone()),
+        }
+    }
+
+    /// Start a new file once the current one would grow past this many
+    /// bytes, 8 MiB by default
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Get the path of the history file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add a run to the end of the history
+    ///
+    /// This blocks while another process holds the history's file lock, so
+    /// async code should call it from a blocking thread.
+    pub fn record(&self, record: &RunRecord) -> Result<(), ImageError> {
+        let line = RecordLine {
+            id: record.id.as_ref().map(ToString::to_string),
+            image: record.image.to_string(),
+            args: record.args.clone(),
+            started_ms: millis(
+                record
+                    .started
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default(),
+            ),
+            duration_ms: millis(record.duration),
+            exit_code: record.exit_code,
+            error: record.error.clone(),
+            peak_memory: record.peak_memory,
+        };
+        let mut bytes = serde_json::to_vec(&line)?;
+        bytes.push(b'\n');
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = self.open_locked()?;
+        let len = file.metadata()?.len();
+        if len > 0 && len + bytes.len() as u64 > self.max_size {
+            fs::rename(&self.path, self.rotated_path())?;
+            file = self.open_locked()?;
+        }
+        // One write per line, so appends from other processes can't land in
+        // the middle of it
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Open the current file for appending, holding a lock that keeps other
+    /// processes from rotating it
+    ///
+    /// The lock goes away when the file is closed. If another process
+    /// rotated the file while this one waited for it, the new file is opened
+    /// instead.
+    fn open_locked(&self) -> Result<File, ImageError> {
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let opened = file.metadata()?.ino();
+            match fs::metadata(&self.path) {
+                Ok(current) if current.ino() == opened => return Ok(file),
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(".1");
+        name.into()
+    }
+
+    fn read_lines(path: &Path) -> Result<Vec<u8>, ImageError> {
+        match fs::read(path) {
+            Ok(contents) => Ok(contents),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Find runs matching a query, oldest first
+    ///
+    /// Lines that can't be read, like one cut short by a crash, are skipped.
+    pub fn query(&self, query: &RunQuery) -> Result<Vec<RunRecord>, ImageError> {
+        let mut contents = RunHistory::read_lines(&self.rotated_path())?;
+        if contents.last().map_or(false, |byte| *byte != b'\n') {
+            contents.push(b'\n');
+        }
+        contents.extend(RunHistory::read_lines(&self.path)?);
+        let mut records: Vec<RunRecord> = contents
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match parse_line(line) {
+                Ok(record) => Some(record),
+                Err(err) => {
+                    log::warn!("skipping unreadable run history line, {}", err);
+                    None
+                }
+            })
+            .filter(|record| query.matches(record))
+            .collect();
+        if let Some(last) = query.last {
+            let skip = records.len().saturating_sub(last);
+            records.drain(..skip);
+        }
+        Ok(records)
+    }
+
+    /// Find the run of the container whose ID starts with `prefix`
+    ///
+    /// The prefix must match exactly one container, see
+    /// [ContainerId::resolve()].
+    pub fn find(&self, prefix: &str) -> Result<RunRecord, ImageError> {
+        let records = self.query(&RunQuery::new().id(prefix))?;
+        let id = ContainerId::resolve(prefix, records.iter().filter_map(|r| r.id.as_ref()))?;
+        Ok(records
+            .into_iter()
+            .find(|record| record.id == Some(id))
+            .unwrap())
+    }
+}
+
+impl RunRecord {
+    /// Did the container exit successfully?
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+impl RunQuery {
+    /// Start a query that matches every run
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Only match runs of containers whose ID starts with this prefix
+    pub fn id(mut self, prefix: &str) -> Self {
+        self.id = Some(prefix.to_string());
+        self
+    }
+
+    /// Only match runs of this image
+    ///
+    /// Runs are recorded under a name with a content digest, and match if
+    /// they agree with every part of the name given here. A name without a
+    /// tag or digest matches any run from the repository.
+    pub fn image(mut self, image: &ImageName) -> Self {
+        self.image = Some(image.clone());
+        self
+    }
+
+    /// Only match runs that didn't exit successfully
+    pub fn failed(mut self) -> Self {
+        self.failed = true;
+        self
+    }
+
+    /// Only match runs started at or after this time
+    pub fn since(mut self, time: SystemTime) -> Self {
+        self.since = Some(time);
+        self
+    }
+
+    /// Keep only the most recent matches, up to this many
+    pub fn last(mut self, count: usize) -> Self {
+        self.last = Some(count);
+        self
+    }
+
+    fn matches(&self, record: &RunRecord) -> bool {
+        let image_matches = match &self.image {
+            None => true,
+            Some(image) => {
+                image.registry_str() == record.image.registry_str()
+                    && image.repository_str() == record.image.repository_str()
+                    && (image.tag_str().is_none() || image.tag_str() == record.image.tag_str())
+                    && (image.content_digest_str().is_none()
+                        || image.content_digest_str() == record.image.content_digest_str())
+            }
+        };
+        let id_matches = match &self.id {
+            None => true,
+            Some(prefix) => record.id.map_or(false, |id| id.starts_with(prefix)),
+        };
+        id_matches
+            && image_matches
+            && !(self.failed && record.success())
+            && self.since.map_or(true, |since| record.started >= since)
+    }
+}
+
+impl RunSummary {
+    /// Add up a set of runs
+    pub fn new(records: &[RunRecord]) -> Self {
+        if records.is_empty() {
+            return Default::default();
+        }
+        let total: Duration = records.iter().map(|record| record.duration).sum();
+        RunSummary {
+            runs: records.len(),
+            failures: records.iter().filter(|record| !record.success()).count(),
+            min_duration: records.iter().map(|record| record.duration).min().unwrap(),
+            mean_duration: total / records.len() as u32,
+            max_duration: records.iter().map(|record| record.duration).max().unwrap(),
+        }
+    }
+}
+
+fn parse_line(line: &[u8]) -> Result<RunRecord, ImageError> {
+    let line: RecordLine = serde_json::from_slice(line)?;
+    Ok(RunRecord {
+        id: line.id.as_deref().map(str::parse).transpose()?,
+        image: line.image.parse()?,
+        args: line.args,
+        started: UNIX_EPOCH + Duration::from_millis(line.started_ms),
+        duration: Duration::from_millis(line.duration_ms),
+        exit_code: line.exit_code,
+        error: line.error,
+        peak_memory: line.peak_memory,
+    })
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn record(image: &str, exit_code: Option<i32>, secs: u64) -> RunRecord {
+        RunRecord {
+            id: Some(ContainerId::new(&image.parse().unwrap())),
+            image: image.parse().unwrap(),
+            args: vec!["sh".to_string(), "-c".to_string(), "true".to_string()],
+            started: UNIX_EPOCH + Duration::from_secs(1_600_000_000 + secs),
+            duration: Duration::from_millis(100 * secs),
+            exit_code,
+            error: None,
+            peak_memory: 4096,
+        }
+    }
+
+    const BUSYBOX: &str =
+        "busybox:latest@sha256:e06f93f59fe842fb490ba992bae19fdd5a05373547b52f8184650c2509908114";
+    const ALPINE: &str =
+        "alpine:3@sha256:a15790640a6690aa1730c38cf0a440e2aa44aaca9b0e8931a9f2b0d7cc90fd65";
+
+    #[test]
+    fn record_and_query() {
+        let dir = TempDir::new().unwrap();
+        let history = RunHistory::open(&dir.path().join("new").join("runs.jsonl"));
+        assert!(history.query(&RunQuery::new()).unwrap().is_empty());
+
+        let runs = vec![
+            record(BUSYBOX, Some(0), 1),
+            record(ALPINE, Some(1), 2),
+            record(BUSYBOX, Some(137), 3),
+            record(BUSYBOX, Some(0), 4),
+        ];
+        for run in &runs {
+            history.record(run).unwrap();
+        }
+        assert_eq!(history.query(&RunQuery::new()).unwrap(), runs);
+        assert_eq!(
+            history.query(&RunQuery::new().failed()).unwrap(),
+            vec![runs[1].clone(), runs[2].clone()]
+        );
+        assert_eq!(
+            history
+                .query(&RunQuery::new().image(&"busybox".parse().unwrap()).last(2))
+                .unwrap(),
+            vec![runs[2].clone(), runs[3].clone()]
+        );
+        assert_eq!(
+            history
+                .query(&RunQuery::new().image(&"busybox:1.32".parse().unwrap()))
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            history
+                .query(&RunQuery::new().since(runs[2].started))
+                .unwrap(),
+            vec![runs[2].clone(), runs[3].clone()]
+        );
+    }
+
+    #[test]
+    fn find_by_id_prefix() {
+        let dir = TempDir::new().unwrap();
+        let history = RunHistory::open(&dir.path().join("runs.jsonl"));
+        let runs = vec![
+            record(BUSYBOX, Some(0), 1),
+            record(ALPINE, Some(0), 2),
+            RunRecord {
+                id: None,
+                ..record(BUSYBOX, Some(0), 3)
+            },
+        ];
+        for run in &runs {
+            history.record(run).unwrap();
+        }
+        let id = runs[0].id.unwrap().to_string();
+        assert_eq!(history.find(&id).unwrap(), runs[0]);
+        assert_eq!(history.find(&id[..12].to_uppercase()).unwrap(), runs[0]);
+        assert!(matches!(
+            history.find(""),
+            Err(ImageError::AmbiguousContainerId(_, 2))
+        ));
+        assert!(matches!(
+            history.find("not hex"),
+            Err(ImageError::ContainerIdNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn skips_damaged_lines() {
+        let dir = TempDir::new().unwrap();
+        let history = RunHistory::open(&dir.path().join("runs.jsonl"));
+        let run = record(BUSYBOX, None, 1);
+        history.record(&run).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(history.path())
+            .unwrap()
+            .write_all(b"{\"image\": \"busy")
+            .unwrap();
+        assert_eq!(history.query(&RunQuery::new()).unwrap(), vec![run]);
+    }
+
+    #[test]
+    fn rotates_at_size_limit() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("runs.jsonl");
+        let line_len = {
+            let history = RunHistory::open(&dir.path().join("measure.jsonl"));
+            history.record(&record(BUSYBOX, Some(0), 1)).unwrap();
+            fs::metadata(history.path()).unwrap().len()
+        };
+        let history = RunHistory::open(&path).max_size(line_len * 2);
+        let runs: Vec<RunRecord> = (1..=5).map(|secs| record(BUSYBOX, Some(0), secs)).collect();
+        for run in &runs {
+            history.record(run).unwrap();
+        }
+        let rotated = dir.path().join("runs.jsonl.1");
+        assert_eq!(fs::metadata(&path).unwrap().len(), line_len);
+        assert_eq!(fs::metadata(&rotated).unwrap().len(), line_len * 2);
+        assert!(!dir.path().join("runs.jsonl.2").exists());
+        assert_eq!(history.query(&RunQuery::new()).unwrap(), runs[2..].to_vec());
+        assert_eq!(
+            history.query(&RunQuery::new().last(2)).unwrap(),
+            runs[3..].to_vec()
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let runs = vec![
+            record(BUSYBOX, Some(0), 1),
+            record(BUSYBOX, None, 2),
+            record(BUSYBOX, Some(0), 6),
+        ];
+        assert_eq!(
+            RunSummary::new(&runs),
+            RunSummary {
+                runs: 3,
+                failures: 1,
+                min_duration: Duration::from_millis(100),
+                mean_duration: Duration::from_millis(300),
+                max_duration: Duration::from_millis(600),
+            }
+        );
+        assert_eq!(RunSummary::new(&[]), RunSummary::default());
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    container::ContainerId,
+    errors::{RuntimeError, VFSError},
+    filesystem::{storage::FileStorage, vfs::Filesystem},
+    sand::protocol::{abi, FileStat, FollowLinks},
+};
+use bytes::Bytes;
+use std::{future::Future, mem, path::Path, pin::Pin, sync::Arc};
+
+/// A point in a container's life where hooks run, see
+/// [ContainerBuilder::hook()](crate::ContainerBuilder::hook)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HookStage {
+    /// With every mount in place, before the first process starts
+    PreSpawn,
+    /// Once the first process has loaded its program
+    PostExec,
+    /// After the container's processes have exited, before its workspaces
+    /// are saved and its filesystem is dropped
+    PreExit,
+}
+
+/// The future returned by a hook, which may borrow its [HookContext]
+pub type HookFuture<'a> = Pin<Box<dyn Future<Output = Result<(), RuntimeError>> + Send + 'a>>;
+
+pub(crate) type HookCallback =
+    Arc<dyn for<'a, 'b> Fn(&'a mut HookContext<'b>) -> HookFuture<'a> + Send + Sync>;
+
+/// What a hook can reach of its container
+pub struct HookContext<'a> {
+    stage: HookStage,
+    id: ContainerId,
+    filesystem: &'a mut Filesystem,
+    storage: &'a FileStorage,
+}
+
+impl<'a> HookContext<'a> {
+    pub fn stage(&self) -> HookStage {
+        self.stage
+    }
+
+    pub fn id(&self) -> ContainerId {
+        self.id
+    }
+
+    /// The container's filesystem, as the runtime sees it
+    ///
+    /// Changes here take effect immediately, even while processes are
+    /// running, and like the container's own changes they never reach the
+    /// image.
+    pub fn filesystem(&mut self) -> &mut Filesystem {
+        self.filesystem
+    }
+
+    /// Put a file with these contents at `path`, replacing anything there
+    pub fn write_file<P: AsRef<Path>, B: Into<Bytes>>(
+        &mut self,
+        path: P,
+        contents: B,
+        mode: u32,
+    ) -> Result<(), VFSError> {
+        let contents = contents.into();
+        let stat = FileStat {
+            st_mode: abi::S_IFREG | (mode & 0o7777),
+            st_size: contents.len() as i64,
+            ..Default::default()
+        };
+        self.filesystem
+            .writer()
+            .write_file_bytes(path.as_ref(), stat, contents)
+    }
+
+    /// Read the file at `path`, or None if it's larger than `limit` bytes or
+    /// isn't a regular file
+    pub async fn read_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>, VFSError> {
+        let vfile =
+            self.filesystem
+                .lookup(&Filesystem::root(), path.as_ref(), &FollowLinks::Follow)?;
+        self.filesystem
+            .read_small_file(self.storage, &vfile, limit)
+            .await
+    }
+}
+
+/// Hooks registered for one container
+#[derive(Clone)]
+pub(crate) struct Hooks {
+    id: ContainerId,
+    callbacks: Vec<(HookStage, HookCallback)>,
+}
+
+impl Hooks {
+    pub(crate) fn new(id: ContainerId, callbacks: Vec<(HookStage, HookCallback)>) -> Self {
+        Hooks { id, callbacks }
+    }
+
+    /// Run every hook for `stage`, in the order they were added
+    pub(crate) async fn run(
+        &mut self,
+        stage: HookStage,
+        filesystem: &mut Filesystem,
+        storage: &FileStorage,
+    ) -> Result<(), RuntimeError> {
+        // Each stage only happens once, so its hooks are done with afterward
+        let (now, later): (Vec<_>, Vec<_>) = mem::take(&mut self.callbacks)
+            .into_iter()
+            .partition(|(hook_stage, _)| *hook_stage == stage);
+        self.callbacks = later;
+        let mut context = HookContext {
+            stage,
+            id: self.id,
+            filesystem,
+            storage,
+        };
+        for (_, callback) in now {
+            log::debug!("running {:?} hook for {}", stage, self.id);
+            callback(&mut context).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageName;
+    use std::sync::Mutex;
+    use tokio::runtime::Runtime;
+
+    fn hook<F>(callback: F) -> HookCallback
+    where
+        F: for<'a, 'b> Fn(&'a mut HookContext<'b>) -> HookFuture<'a> + Send + Sync + 'static,
+    {
+        Arc::new(callback)
+    }
+
+    #[test]
+    fn stages_run_once_in_order() {
+        let image: ImageName = "busybox".parse().unwrap();
+        let id = ContainerId::new(&image);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_exit = seen.clone();
+        let mut hooks = Hooks::new(
+            id,
+            vec![
+                (
+                    HookStage::PreExit,
+                    hook(move |ctx| {
+                        let seen = seen_exit.clone();
+                        Box::pin(async move {
+                            let contents = ctx.read_file("/etc/motd", 64).await?;
+                            seen.lock().unwrap().push(contents.unwrap());
+                            Ok(())
+                        })
+                    }),
+                ),
+                (
+                    HookStage::PreSpawn,
+                    hook(|ctx| {
+                        Box::pin(async move {
+                            assert_eq!(ctx.stage(), HookStage::PreSpawn);
+                            ctx.write_file("/etc/motd", &b"first"[..], 0o644)?;
+                            Ok(())
+                        })
+                    }),
+                ),
+                (
+                    HookStage::PreSpawn,
+                    hook(|ctx| {
+                        Box::pin(async move {
+                            ctx.write_file("/etc/motd", &b"second"[..], 0o644)?;
+                            Ok(())
+                        })
+                    }),
+                ),
+            ],
+        );
+
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = Filesystem::new();
+        Runtime::new().unwrap().block_on(async {
+            hooks
+                .run(HookStage::PreSpawn, &mut fs, &storage)
+                .await
+                .unwrap();
+            hooks
+                .run(HookStage::PostExec, &mut fs, &storage)
+                .await
+                .unwrap();
+            hooks
+                .run(HookStage::PreExit, &mut fs, &storage)
+                .await
+                .unwrap();
+            hooks
+                .run(HookStage::PreExit, &mut fs, &storage)
+                .await
+                .unwrap();
+        });
+        assert_eq!(*seen.lock().unwrap(), vec![b"second".to_vec()]);
+    }
+
+    #[test]
+    fn errors_stop_the_stage() {
+        let image: ImageName = "busybox".parse().unwrap();
+        let ran = Arc::new(Mutex::new(false));
+        let ran_later = ran.clone();
+        let mut hooks = Hooks::new(
+            ContainerId::new(&image),
+            vec![
+                (
+                    HookStage::PostExec,
+                    hook(|_| Box::pin(async { Err(RuntimeError::Disconnected) })),
+                ),
+                (
+                    HookStage::PostExec,
+                    hook(move |_| {
+                        *ran_later.lock().unwrap() = true;
+                        Box::pin(async { Ok(()) })
+                    }),
+                ),
+            ],
+        );
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = Filesystem::new();
+        let result =
+            Runtime::new()
+                .unwrap()
+                .block_on(hooks.run(HookStage::PostExec, &mut fs, &storage));
+        assert!(matches!(result, Err(RuntimeError::Disconnected)));
+        assert!(!*ran.lock().unwrap());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{errors::ImageError, image::ImageName};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+/// Number of hex digits in [ContainerId::short()]
+const SHORT_LEN: usize = 12;
+
+/// Identifies one container run
+///
+/// IDs are 64 hex digits, the sha256 of the image's content digest and a
+/// random nonce, so every run of an image gets its own ID. Any prefix that
+/// no other ID shares can stand in for the whole thing, and the 12 digit
+/// [ContainerId::short()] form usually does.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct ContainerId([u8; 32]);
+
+impl ContainerId {
+    /// Make a new ID for a run of this image
+    pub(crate) fn new(image: &ImageName) -> Self {
+        let nonce: [u8; 16] = rand::random();
+        let mut hasher = Sha256::new();
+        hasher.update(image.content_digest_str().unwrap_or(image.as_str()));
+        hasher.update(&nonce);
+        ContainerId(hasher.finalize().into())
+    }
+
+    /// Return the first 12 hex digits of the ID
+    pub fn short(&self) -> String {
+        let mut s = self.to_string();
+        s.truncate(SHORT_LEN);
+        s
+    }
+
+    /// Does the hex form of this ID start with `prefix`?
+    pub fn starts_with(&self, prefix: &str) -> bool {
+        self.to_string().starts_with(&prefix.to_ascii_lowercase())
+    }
+
+    /// Find the one ID starting with `prefix`
+    ///
+    /// IDs may repeat. It's an error if no ID matches, or if the prefix
+    /// matches more than one distinct ID.
+    ///
+    /// ```
+    /// # use bandsocks::ContainerId;
+    /// let ids: Vec<ContainerId> = vec![
+    ///     "3f2a".repeat(16).parse().unwrap(),
+    ///     "3f9b".repeat(16).parse().unwrap(),
+    /// ];
+    /// assert_eq!(ContainerId::resolve("3F2", &ids).unwrap(), ids[0]);
+    /// assert!(ContainerId::resolve("3f", &ids).is_err());
+    /// assert!(ContainerId::resolve("40", &ids).is_err());
+    /// ```
+    pub fn resolve<'a, I>(prefix: &str, ids: I) -> Result<ContainerId, ImageError>
+    where
+        I: IntoIterator<Item = &'a ContainerId>,
+    {
+        let matches: BTreeSet<ContainerId> = ids
+            .into_iter()
+            .filter(|id| id.starts_with(prefix))
+            .copied()
+            .collect();
+        let mut iter = matches.iter();
+        match (iter.next(), iter.next()) {
+            (None, _) => Err(ImageError::ContainerIdNotFound(prefix.to_string())),
+            (Some(id), None) => Ok(*id),
+            (Some(_), Some(_)) => Err(ImageError::AmbiguousContainerId(
+                prefix.to_string(),
+                matches.len(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ContainerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ContainerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ContainerId({})", self.short())
+    }
+}
+
+impl FromStr for ContainerId {
+    type Err = ImageError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ImageError::InvalidContainerId(s.to_string()));
+        }
+        let mut bytes = [0u8; 32];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[index * 2..index * 2 + 2], 16).unwrap();
+        }
+        Ok(ContainerId(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_per_run() {
+        let image: ImageName =
+            "busybox@sha256:e06f93f59fe842fb490ba992bae19fdd5a05373547b52f8184650c2509908114"
+                .parse()
+                .unwrap();
+        let first = ContainerId::new(&image);
+        let second = ContainerId::new(&image);
+        assert_ne!(first, second);
+        assert_eq!(first.to_string().len(), 64);
+        assert_eq!(first.short().len(), 12);
+        assert!(first.starts_with(&first.short()));
+        assert_eq!(first.to_string().parse::<ContainerId>().unwrap(), first);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("".parse::<ContainerId>().is_err());
+        assert!("3f2a".parse::<ContainerId>().is_err());
+        assert!("+f".repeat(32).parse::<ContainerId>().is_err());
+        assert!("é".repeat(32).parse::<ContainerId>().is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::sand::protocol::VPid;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+/// One bucket for zero, and one for each bit of a duration in nanoseconds
+const BUCKETS: usize = 65;
+
+/// A histogram of durations, with one bucket per power of two nanoseconds
+#[derive(Clone, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total_ns: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            total_ns: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, ns: u64) {
+        self.buckets[(64 - ns.leading_zeros()) as usize] += 1;
+        self.count += 1;
+        self.total_ns = self.total_ns.saturating_add(ns);
+    }
+
+    /// Number of durations recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of all durations recorded
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.total_ns)
+    }
+
+    /// Average duration, or zero if nothing was recorded
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::from_nanos(0),
+            count => Duration::from_nanos(self.total_ns / count),
+        }
+    }
+
+    /// An upper bound on the duration below which `fraction` of the recorded
+    /// durations fall, accurate to within a factor of two
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let target = (self.count as f64 * fraction).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return Duration::from_nanos(((1u128 << bucket) - 1) as u64);
+            }
+        }
+        Duration::from_nanos(0)
+    }
+}
+
+impl std::fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LatencyHistogram")
+            .field("count", &self.count)
+            .field("mean", &self.mean())
+            .field("p50", &self.percentile(0.5))
+            .field("p99", &self.percentile(0.99))
+            .finish()
+    }
+}
+
+/// Where the time goes while emulating one kind of syscall
+///
+/// Each call is split into phases. `trap` is the tracer's work after waking
+/// for the seccomp trap, `emulate` is the rest of its work in the sandbox,
+/// including any syscalls it makes on the process's behalf, `ipc` is time
+/// spent waiting on messages to and from the runtime, `taskcall` is the
+/// runtime's own handling of those messages, and `resume` is the time to
+/// restart the process afterward.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SyscallLatency {
+    pub trap: LatencyHistogram,
+    pub emulate: LatencyHistogram,
+    pub ipc: LatencyHistogram,
+    pub taskcall: LatencyHistogram,
+    pub resume: LatencyHistogram,
+}
+
+/// Latency histograms for one container, keyed by syscall number
+#[derive(Debug, Default)]
+pub(crate) struct LatencyStats {
+    by_syscall: Mutex<BTreeMap<isize, SyscallLatency>>,
+    taskcall_ns: Mutex<HashMap<VPid, u64>>,
+}
+
+impl LatencyStats {
+    pub fn snapshot(&self) -> BTreeMap<isize, SyscallLatency> {
+        self.by_syscall.lock().unwrap().clone()
+    }
+
+    /// Count time the runtime spent handling a message from this task, toward
+    /// the task's next syscall report
+    pub fn taskcall(&self, task: VPid, elapsed: Duration) {
+        *self.taskcall_ns.lock().unwrap().entry(task).or_default() += elapsed.as_nanos() as u64;
+    }
+
+    /// Record a syscall as reported by the sandbox
+    pub fn syscall(&self, task: VPid, nr: isize, trap: u64, emulate: u64, ipc: u64, resume: u64) {
+        let taskcall = self.taskcall_ns.lock().unwrap().remove(&task).unwrap_or(0);
+        let mut by_syscall = self.by_syscall.lock().unwrap();
+        let latency = by_syscall.entry(nr).or_default();
+        latency.trap.record(trap);
+        latency.emulate.record(emulate);
+        latency.ipc.record(ipc.saturating_sub(taskcall));
+        latency.taskcall.record(taskcall);
+        latency.resume.record(resume);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), Duration::from_nanos(0));
+        for ns in &[0, 1, 100, 1000, 1000, 1000, 1_000_000] {
+            histogram.record(*ns);
+        }
+        assert_eq!(histogram.count(), 7);
+        assert_eq!(histogram.total(), Duration::from_nanos(1_003_101));
+        assert_eq!(histogram.percentile(0.5), Duration::from_nanos(1023));
+        assert_eq!(
+            histogram.percentile(1.0),
+            Duration::from_nanos((1 << 20) - 1)
+        );
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.percentile(1.0), Duration::from_nanos(u64::MAX));
+    }
+
+    #[test]
+    fn subtract_taskcall_from_ipc() {
+        let stats = LatencyStats::default();
+        stats.taskcall(VPid(1), Duration::from_nanos(3000));
+        stats.syscall(VPid(1), 2, 10, 20, 5000, 30);
+        stats.syscall(VPid(1), 2, 10, 20, 5000, 30);
+        let latency = &stats.snapshot()[&2];
+        assert_eq!(latency.ipc.total(), Duration::from_nanos(7000));
+        assert_eq!(latency.taskcall.total(), Duration::from_nanos(3000));
+        assert_eq!(latency.trap.count(), 2);
+    }
+}
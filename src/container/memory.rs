@@ -0,0 +1,199 @@
+use crate::process::Process;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Host memory held on behalf of one container
+///
+/// The sandbox's own rlimit only covers memory the guest allocates itself.
+/// This also counts what the runtime hands over: every file passed to the
+/// guest as a descriptor, and the resident size of all guest mappings.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MemoryUsage {
+    /// Bytes in files passed to the guest by descriptor
+    ///
+    /// Files stop counting once no guest process has them open, as found
+    /// by the next sample. Memory-backed files stay resident until they're
+    /// closed, while image files share the host page cache.
+    pub passed_fds: u64,
+    /// Resident bytes in anonymous guest mappings, including the stack and
+    /// heap
+    pub mapped_anon: u64,
+    /// Resident bytes in file-backed guest mappings
+    pub mapped_file: u64,
+    /// The most memory in use at any one time, by the sum of the above
+    pub peak: u64,
+    /// The cap set with
+    /// [ContainerBuilder::memory_limit()](crate::ContainerBuilder::memory_limit),
+    /// if any
+    pub limit: Option<u64>,
+}
+
+impl MemoryUsage {
+    /// Total bytes currently counted against the container
+    pub fn total(&self) -> u64 {
+        self.passed_fds + self.mapped_anon + self.mapped_file
+    }
+}
+
+/// A file by its device and inode numbers
+pub(crate) type FileId = (u64, u64);
+
+#[derive(Debug, Default)]
+struct PassedFiles {
+    /// Length of each file, and the sample count when it was passed
+    files: HashMap<FileId, (u64, u64)>,
+    samples: u64,
+}
+
+/// Running totals shared between a [Container](crate::Container) and the
+/// server handling its sandbox
+#[derive(Debug, Default)]
+pub(crate) struct MemoryAccounting {
+    limit: Option<u64>,
+    passed_fds: AtomicU64,
+    passed: Mutex<PassedFiles>,
+    mapped: Mutex<(u64, u64)>,
+    peak: AtomicU64,
+}
+
+impl MemoryAccounting {
+    pub fn new(limit: Option<u64>) -> Self {
+        MemoryAccounting {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        let (mapped_anon, mapped_file) = *self.mapped.lock().unwrap();
+        MemoryUsage {
+            passed_fds: self.passed_fds.load(Ordering::Relaxed),
+            mapped_anon,
+            mapped_file,
+            peak: self.peak.load(Ordering::Relaxed),
+            limit: self.limit,
+        }
+    }
+
+    /// Re-measure the resident size of every guest process, and stop
+    /// counting passed files they've all closed
+    pub fn sample<'a, I: IntoIterator<Item = &'a Process>>(&self, processes: I) {
+        let mut mapped = (0, 0);
+        let mut open = Some(HashSet::new());
+        for process in processes {
+            if let Some((anon, file)) = process.smaps.resident() {
+                mapped.0 += anon;
+                mapped.1 += file;
+            }
+            match (process.fds.open_files(), &mut open) {
+                (Some(files), Some(open)) => open.extend(files),
+                // Without a full list, nothing can be known closed
+                _ => open = None,
+            }
+        }
+        self.update(mapped, open.as_ref());
+    }
+
+    fn update(&self, mapped: (u64, u64), open: Option<&HashSet<FileId>>) {
+        *self.mapped.lock().unwrap() = mapped;
+        let mut passed = self.passed.lock().unwrap();
+        passed.samples += 1;
+        // A file passed since the last sample may not be installed in the
+        // guest yet
+        let recent = passed.samples - 1;
+        if let Some(open) = open {
+            passed
+                .files
+                .retain(|id, (_, sample)| *sample >= recent || open.contains(id));
+        }
+        let total = passed.files.values().map(|(len, _)| len).sum();
+        self.passed_fds.store(total, Ordering::Relaxed);
+        drop(passed);
+        self.update_peak();
+    }
+
+    /// Count a file about to be passed to the guest, unless it would go over
+    /// the limit
+    ///
+    /// A file the guest already has open from before is only counted once.
+    pub fn pass_fd(&self, id: FileId, len: u64) -> bool {
+        let mut passed = self.passed.lock().unwrap();
+        let samples = passed.samples;
+        if let Some((_, sample)) = passed.files.get_mut(&id) {
+            *sample = samples;
+            return true;
+        }
+        if let Some(limit) = self.limit {
+            if self.usage().total() + len > limit {
+                log::warn!("refusing a {} byte file, over the memory limit", len);
+                return false;
+            }
+        }
+        passed.files.insert(id, (len, samples));
+        self.passed_fds.fetch_add(len, Ordering::Relaxed);
+        drop(passed);
+        self.update_peak();
+        true
+    }
+
+    fn update_peak(&self) {
+        self.peak.fetch_max(self.usage().total(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_passed_fds() {
+        let accounting = MemoryAccounting::new(Some(1000));
+        assert!(accounting.pass_fd((1, 1), 600));
+        assert!(!accounting.pass_fd((1, 2), 600));
+        assert!(accounting.pass_fd((1, 3), 400));
+        assert!(accounting.pass_fd((1, 1), 600));
+        let usage = accounting.usage();
+        assert_eq!(usage.passed_fds, 1000);
+        assert_eq!(usage.total(), 1000);
+        assert_eq!(usage.peak, 1000);
+        assert_eq!(usage.limit, Some(1000));
+
+        let unlimited = MemoryAccounting::new(None);
+        assert!(unlimited.pass_fd((1, 1), u64::MAX / 2));
+    }
+
+    #[test]
+    fn closed_files_stop_counting() {
+        let accounting = MemoryAccounting::new(Some(1000));
+        assert!(accounting.pass_fd((1, 1), 600));
+        assert!(accounting.pass_fd((1, 2), 300));
+        let open: HashSet<FileId> = [(1, 1)].iter().cloned().collect();
+
+        // Until a whole interval has passed, files may still be in flight
+        accounting.update((50, 0), Some(&open));
+        assert_eq!(accounting.usage().passed_fds, 900);
+        accounting.update((50, 0), Some(&open));
+        let usage = accounting.usage();
+        assert_eq!(usage.passed_fds, 600);
+        assert_eq!(usage.total(), 650);
+        assert_eq!(usage.peak, 950);
+        assert!(accounting.pass_fd((1, 3), 300));
+
+        // Processes that can't be listed keep everything counted
+        accounting.update((0, 0), None);
+        accounting.update((0, 0), None);
+        assert_eq!(accounting.usage().passed_fds, 900);
+
+        // Exited processes have nothing open
+        accounting.update((0, 0), Some(&HashSet::new()));
+        let usage = accounting.usage();
+        assert_eq!(usage.passed_fds, 0);
+        assert_eq!(usage.total(), 0);
+        assert!(accounting.pass_fd((1, 4), 1000));
+    }
+}
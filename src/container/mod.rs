@@ -1,21 +1,73 @@
 //! Sandboxed subprocesses with a virtual filesystem
 
 mod builder;
+mod compose;
+pub(crate) mod events;
+pub(crate) mod hooks;
+mod history;
+mod id;
+pub(crate) mod latency;
+pub(crate) mod memory;
+pub(crate) mod network;
+pub(crate) mod notify;
+mod output;
+mod pool;
+mod recording;
+mod scope;
+pub(crate) mod secrets;
+pub(crate) mod snapshot;
+mod stdio;
 
 pub use builder::ContainerBuilder;
+pub use compose::{Compose, ComposeSpec, HealthCheck, ServiceSpec};
+pub use events::ContainerEvent;
+pub use hooks::{HookContext, HookFuture, HookStage};
+pub use history::{RunHistory, RunQuery, RunRecord, RunSummary};
+pub use id::ContainerId;
+pub use latency::{LatencyHistogram, SyscallLatency};
+pub use memory::MemoryUsage;
+pub use network::NetworkGroup;
+pub use output::{OutputChunk, OutputStream, StreamId, TaggedOutput};
+pub use pool::ContainerPool;
+pub use recording::SessionRecording;
+pub use scope::SystemdScope;
+pub use secrets::SecretAccess;
+pub use stdio::{ChildStderr, ChildStdin, ChildStdout, Stdio};
 
 use crate::{
     errors::{ImageError, RuntimeError},
-    filesystem::{storage::FileStorage, vfs::Filesystem},
-    image::{Image, ImageName},
-    ipcserver::IPCServer,
-    registry::RegistryClient,
-    sand::protocol::{InitArgsHeader, TracerSettings},
+    filesystem::{
+        remap::PathRemap, storage::FileStorage, vfs::Filesystem, workspace::WorkspaceMount,
+    },
+    image::{Image, ImageLock, ImageName},
+    ipcserver::{AutoSuspend, ControlRequest, IPCServer, TracerProcess},
+    registry::{Pull, RegistryClient},
+    rt,
+    sand::protocol::{InitArgsHeader, Signal, TracerSettings, VPid},
+};
+use events::EventCallback;
+use hooks::Hooks;
+use latency::LatencyStats;
+use memory::MemoryAccounting;
+use secrets::SecretAudit;
+use snapshot::{ExecSnapshotSlot, ExecSnapshots};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    ffi::CString,
+    fmt,
+    fs::File,
+    io, mem,
+    os::unix::net::UnixStream,
+    path::Path,
+    ptr,
+    sync::{Arc, Mutex},
+    thread,
 };
-use std::{borrow::Cow, ffi::CString, fmt, io, os::unix::net::UnixStream, sync::Arc, thread};
 use tokio::{
-    io::{AsyncWriteExt, BufWriter},
-    task,
+    io::{AsyncRead, AsyncWriteExt},
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
     task::JoinHandle,
 };
 
@@ -24,28 +76,168 @@ use tokio::{
 /// Roughly analogous to [std::process::Child], but for a sandbox container.
 #[derive(Debug)]
 pub struct Container {
-    pub stdin: Option<UnixStream>,
-    pub stdout: Option<UnixStream>,
-    pub stderr: Option<UnixStream>,
+    pub stdin: Option<ChildStdin>,
+    pub stdout: Option<ChildStdout>,
+    pub stderr: Option<ChildStderr>,
+    id: ContainerId,
+    recording: Option<SessionRecording>,
+    tagged_output: Option<TaggedOutput>,
+    memory: Arc<MemoryAccounting>,
+    latency: Arc<LatencyStats>,
+    secrets: Arc<SecretAudit>,
+    control: UnixStream,
+    requests: mpsc::UnboundedSender<ControlRequest>,
     join: JoinHandle<Result<ExitStatus, RuntimeError>>,
 }
 
+/// A way to control a [Container] from elsewhere while it runs
+///
+/// Unlike the [Container], a handle can be cloned and kept after the
+/// [Container] is consumed by [Container::wait()] or [Container::interact()].
+/// Once the container has finished, requests fail with
+/// [RuntimeError::Disconnected].
+#[derive(Debug, Clone)]
+pub struct ContainerHandle {
+    requests: mpsc::UnboundedSender<ControlRequest>,
+}
+
+impl ContainerHandle {
+    /// Send a signal to the container's init process, as [Container::kill()]
+    pub fn kill(&self, signal: Signal) -> Result<(), RuntimeError> {
+        if signal.0 == 0 || signal.0 > 64 {
+            Err(RuntimeError::InvalidSignal(signal.0))?
+        }
+        self.send(ControlRequest::Kill(signal))
+    }
+
+    /// Freeze every process in the container, as [Container::pause()]
+    pub fn pause(&self) -> Result<(), RuntimeError> {
+        self.send(ControlRequest::Pause(true))
+    }
+
+    /// Let the container run again, as [Container::resume()]
+    pub fn resume(&self) -> Result<(), RuntimeError> {
+        self.send(ControlRequest::Pause(false))
+    }
+
+    /// Open a file inside the container for reading, as
+    /// [Container::read_file()]
+    pub async fn read_file<P: AsRef<Path>>(&self, path: P) -> Result<File, RuntimeError> {
+        self.open_file(path.as_ref(), false).await
+    }
+
+    /// Create or replace a file inside the container, as
+    /// [Container::write_file()]
+    pub async fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<File, RuntimeError> {
+        self.open_file(path.as_ref(), true).await
+    }
+
+    async fn open_file(&self, path: &Path, write: bool) -> Result<File, RuntimeError> {
+        let (reply, result) = oneshot::channel();
+        self.send(ControlRequest::OpenFile {
+            path: path.to_path_buf(),
+            write,
+            reply,
+        })?;
+        result.await.map_err(|_| RuntimeError::Disconnected)?
+    }
+
+    fn send(&self, request: ControlRequest) -> Result<(), RuntimeError> {
+        self.requests
+            .send(request)
+            .map_err(|_| RuntimeError::Disconnected)
+    }
+}
+
 /// Status of an exited container
 ///
-/// Much like [std::process::ExitStatus]
+/// Much like [std::process::ExitStatus], but the code follows the shell's
+/// rules. The container's status is always that of its init process, the
+/// first one started. Other processes may exit in any way without ending the
+/// container, and any left running when init exits are killed.
+///
+/// If init exits normally, the code is its exit code. If it's killed by
+/// signal N, the code is 128+N. A shell running as init will itself exit with
+/// 128+N after its foreground command is killed by signal N, so that case
+/// also counts as a death by signal if the most recent process killed by a
+/// signal was killed by that one.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ExitStatus {
     pub(crate) code: i32,
+    pub(crate) signal: Option<(VPid, i32)>,
 }
 
 impl ExitStatus {
+    /// Init exited normally, and `last_signal` is the most recent process
+    /// killed by a signal, if any
+    pub(crate) fn exited(code: i32, last_signal: Option<(VPid, i32)>) -> Self {
+        ExitStatus {
+            code,
+            signal: last_signal.filter(|(_, signal)| code == 128 + signal),
+        }
+    }
+
+    /// Init was killed by a signal
+    pub(crate) fn signaled(signal: i32) -> Self {
+        ExitStatus {
+            code: 128 + signal,
+            signal: Some((VPid(1), signal)),
+        }
+    }
+
     pub fn success(&self) -> bool {
         self.code == 0
     }
 
+    /// The exit code, as a shell would report it
     pub fn code(&self) -> Option<i32> {
         Some(self.code)
     }
+
+    /// The signal responsible, if the container ended with a death by signal
+    pub fn signal(&self) -> Option<i32> {
+        self.signal.map(|(_, signal)| signal)
+    }
+
+    /// Whether the container ended with an exit code or a death by signal
+    ///
+    /// A death by signal follows the same rules as [ExitStatus::signal()].
+    /// This includes faults in init, and syscalls the sandbox won't allow,
+    /// which end a process with SIGSYS.
+    pub fn exit(&self) -> ContainerExit {
+        match self.signal() {
+            Some(signal) => ContainerExit::Signal(signal),
+            None => ContainerExit::Code(self.code),
+        }
+    }
+
+    /// The virtual process killed by [ExitStatus::signal()]
+    ///
+    /// This is init itself if it was killed, or one of its descendants if
+    /// init passed along the status of a process killed by a signal.
+    pub fn signaled_process(&self) -> Option<VPid> {
+        self.signal.map(|(vpid, _)| vpid)
+    }
+}
+
+/// How a container ended, from [ExitStatus::exit()]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ContainerExit {
+    /// Init exited normally with this code
+    Code(i32),
+    /// Init was killed by this signal, or passed along the status of a
+    /// process that was
+    Signal(i32),
+}
+
+impl ContainerExit {
+    /// The exit status a shell would report, 128+N for signal N
+    pub fn shell_code(&self) -> i32 {
+        match self {
+            ContainerExit::Code(code) => *code,
+            ContainerExit::Signal(signal) => 128 + signal,
+        }
+    }
 }
 
 /// Output from an exited container
@@ -84,6 +276,114 @@ impl fmt::Debug for Output {
     }
 }
 
+/// Signals that [Container::interact()] passes on to the container
+const FORWARDED_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+lazy_static! {
+    static ref FORWARDING: Mutex<ForwardingState> = Mutex::new(ForwardingState {
+        count: 0,
+        tokio: None,
+        prior: Vec::new(),
+    });
+}
+
+struct ForwardingState {
+    /// How many containers are forwarding signals
+    count: usize,
+    /// The handlers tokio installed, set aside when the last container
+    /// finished
+    tokio: Option<Vec<libc::sigaction>>,
+    /// The handlers from before the first container began, put back when the
+    /// last one finishes
+    prior: Vec<libc::sigaction>,
+}
+
+/// Pass SIGINT and SIGTERM from the host on to a container until `done`
+///
+/// A second signal of either kind gets its default handling instead, which
+/// ends the host process even if the container ignored the first.
+async fn forward_signals(
+    requests: mpsc::UnboundedSender<ControlRequest>,
+    mut done: oneshot::Receiver<()>,
+) -> io::Result<()> {
+    // Before tokio installs its handlers, so the ones they replace are saved
+    let _forwarding = Forwarding::begin()?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut forwarded = Vec::new();
+    loop {
+        let number = tokio::select! {
+            _ = &mut done => return Ok(()),
+            Some(()) = interrupt.recv() => libc::SIGINT,
+            Some(()) = terminate.recv() => libc::SIGTERM,
+        };
+        if forwarded.contains(&number) {
+            unsafe {
+                libc::signal(number, libc::SIG_DFL);
+                libc::raise(number);
+            }
+        }
+        forwarded.push(number);
+        let _ = requests.send(ControlRequest::Kill(Signal(number as u32)));
+    }
+}
+
+/// Catches the forwarded signals while any container is interacting
+///
+/// The handlers tokio installs stay for the life of the process, and would
+/// keep SIGINT and SIGTERM from ever ending it. The last one to finish puts
+/// back whatever handling the process had before the first one began, and
+/// the next to begin reinstalls tokio's handlers.
+struct Forwarding;
+
+impl Forwarding {
+    fn begin() -> io::Result<Forwarding> {
+        let mut state = FORWARDING.lock().unwrap();
+        if state.count == 0 {
+            state.prior = current_actions();
+            if let Some(actions) = state.tokio.take() {
+                set_actions(&actions)?;
+            }
+        }
+        state.count += 1;
+        Ok(Forwarding)
+    }
+}
+
+impl Drop for Forwarding {
+    fn drop(&mut self) {
+        let mut state = FORWARDING.lock().unwrap();
+        state.count -= 1;
+        if state.count == 0 {
+            state.tokio = Some(current_actions());
+            if let Err(err) = set_actions(&state.prior) {
+                log::warn!("failed to restore signal handlers, {}", err);
+            }
+        }
+    }
+}
+
+/// The handling of each of the [FORWARDED_SIGNALS]
+fn current_actions() -> Vec<libc::sigaction> {
+    FORWARDED_SIGNALS
+        .iter()
+        .map(|number| {
+            let mut action: libc::sigaction = unsafe { mem::zeroed() };
+            unsafe { libc::sigaction(*number, ptr::null(), &mut action) };
+            action
+        })
+        .collect()
+}
+
+fn set_actions(actions: &[libc::sigaction]) -> io::Result<()> {
+    for (number, action) in FORWARDED_SIGNALS.iter().zip(actions) {
+        if unsafe { libc::sigaction(*number, action, ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 fn expect_broken_pipe(result: io::Result<()>) -> io::Result<()> {
     match result {
         Ok(()) => Ok(()),
@@ -98,10 +398,14 @@ impl Container {
         // Take over the image if nobody else is using it, otherwise
         // make a shallow copy of the filesystem.
         match Arc::try_unwrap(image) {
-            Ok(owned) => {
-                ContainerBuilder::new(&owned.config.config, owned.filesystem, owned.storage)
-            }
+            Ok(owned) => ContainerBuilder::new(
+                owned.name,
+                &owned.config.config,
+                owned.filesystem,
+                owned.storage,
+            ),
             Err(shared) => ContainerBuilder::new(
+                shared.name.clone(),
                 &shared.config.config,
                 shared.filesystem.clone(),
                 shared.storage.clone(),
@@ -119,8 +423,147 @@ impl Container {
         Container::new(RegistryClient::new()?.pull(name).await?)
     }
 
+    /// Start pulling an image from a repository server, with progress updates
+    ///
+    /// This is equivalent to using [RegistryClient::pull_progress()] with
+    /// default settings. Once the [Pull] finishes, pass its image to
+    /// [Container::new()].
+    pub fn pull_with_progress(name: &ImageName) -> Result<Pull, ImageError> {
+        Ok(RegistryClient::new()?.pull_progress(name))
+    }
+
+    /// Prepare to run a new container from an OCI image layout directory,
+    /// without any registry access
+    ///
+    /// This is equivalent to [Image::from_oci_dir()] followed by
+    /// [Container::new()].
+    pub async fn load_oci_dir<P: AsRef<Path>>(path: P) -> Result<ContainerBuilder, ImageError> {
+        Container::new(Image::from_oci_dir(path.as_ref()).await?)
+    }
+
+    /// Prepare to run a new container from an image archive written by
+    /// `docker save`, without any registry access
+    ///
+    /// This is equivalent to [Image::from_docker_archive()] followed by
+    /// [Container::new()].
+    pub async fn load_tar<P: AsRef<Path>>(path: P) -> Result<ContainerBuilder, ImageError> {
+        Container::new(Image::from_docker_archive(path.as_ref()).await?)
+    }
+
+    /// Prepare to run a new container, pulling the version of an image that
+    /// was pinned in an [ImageLock]
+    ///
+    /// The name is resolved with [ImageLock::resolve()], and the image is
+    /// pulled by its locked content digest. Names missing from the lock are
+    /// refused, so a tag can't silently drift to new content.
+    pub async fn pull_locked(
+        lock: &ImageLock,
+        name: &ImageName,
+    ) -> Result<ContainerBuilder, ImageError> {
+        Container::pull(&lock.resolve(name)?).await
+    }
+
+    /// Return the ID assigned to this container when it was spawned
+    pub fn id(&self) -> ContainerId {
+        self.id
+    }
+
+    /// Return a handle for controlling this container while it runs
+    pub fn handle(&self) -> ContainerHandle {
+        ContainerHandle {
+            requests: self.requests.clone(),
+        }
+    }
+
+    /// Return the session recording, if one was requested with
+    /// [ContainerBuilder::record_session()]
+    pub fn recording(&self) -> Option<SessionRecording> {
+        self.recording.clone()
+    }
+
+    /// Return the container's output tagged by process and stream, if that
+    /// was requested with [ContainerBuilder::tag_output()]
+    pub fn tagged_output(&self) -> Option<TaggedOutput> {
+        self.tagged_output.clone()
+    }
+
+    /// Return the host memory currently held for this container
+    ///
+    /// Mapped sizes and open files are measured twice a second, so these may
+    /// lag a little behind the container.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory.usage()
+    }
+
+    /// Return latency histograms for each emulated syscall, by number
+    ///
+    /// This stays empty unless timing was turned on with
+    /// [ContainerBuilder::syscall_latency_stats()].
+    pub fn syscall_latency(&self) -> BTreeMap<isize, SyscallLatency> {
+        self.latency.snapshot()
+    }
+
+    /// Return the first read of each secret file so far, in order
+    ///
+    /// Only secrets added with [ContainerBuilder::secret_file()] are audited,
+    /// since reads from the environment can't be seen.
+    pub fn secret_accesses(&self) -> Vec<SecretAccess> {
+        self.secrets.snapshot()
+    }
+
+    /// Send a signal to the container's init process
+    ///
+    /// The signal arrives as one from outside the sandbox would, and the
+    /// process may catch or ignore it. `Signal(libc::SIGKILL as u32)` always
+    /// ends the container, and its [ExitStatus] says so. Stopping and
+    /// continuing are up to [Container::pause()] and [Container::resume()],
+    /// so SIGSTOP is ignored here.
+    pub fn kill(&self, signal: Signal) -> Result<(), RuntimeError> {
+        self.handle().kill(signal)
+    }
+
+    /// Freeze every process in the container until [Container::resume()]
+    ///
+    /// Processes are stopped with a SIGSTOP that they never see, the same way
+    /// [ContainerBuilder::auto_suspend()] stops idle containers. Processes
+    /// forked while the container is paused are stopped too. A syscall the
+    /// runtime is already emulating still finishes, and its process stops on
+    /// the way back. Time keeps passing for the paused processes, so sleeps
+    /// and timeouts may end as soon as they resume.
+    pub fn pause(&self) -> Result<(), RuntimeError> {
+        self.handle().pause()
+    }
+
+    /// Let the processes in a container run again after [Container::pause()]
+    pub fn resume(&self) -> Result<(), RuntimeError> {
+        self.handle().resume()
+    }
+
+    /// Open a file inside the running container for reading
+    ///
+    /// The path is absolute within the container's filesystem, and symbolic
+    /// links are followed the way the container would follow them. The file
+    /// is a snapshot only if the container never writes to it again.
+    pub async fn read_file<P: AsRef<Path>>(&self, path: P) -> Result<File, RuntimeError> {
+        self.handle().read_file(path).await
+    }
+
+    /// Create a file inside the running container, or empty an existing one,
+    /// and open it for writing
+    ///
+    /// Files from the image are copied up first, as they would be for a write
+    /// from inside the container, so the image itself is never modified. On a
+    /// read-only container this only works inside its tmpfs mounts.
+    pub async fn write_file<P: AsRef<Path>>(&self, path: P) -> Result<File, RuntimeError> {
+        self.handle().write_file(path).await
+    }
+
     /// Wait for the container to finish running, if necessary, and return its
     /// exit status.
+    ///
+    /// The container finishes when its init process does. See [ExitStatus]
+    /// for how the exit code is chosen, and [ExitStatus::exit()] to tell an
+    /// exit code from a death by signal.
     pub async fn wait(self) -> Result<ExitStatus, RuntimeError> {
         log::trace!("wait starting");
         let result = self.join.await?;
@@ -128,6 +571,17 @@ impl Container {
         result
     }
 
+    /// Block the current thread until the container finishes running, and
+    /// return its exit status
+    ///
+    /// The container runs on bandsocks' private runtime thread, so this works
+    /// even if the runtime the container was spawned from isn't running.
+    /// Don't call this from async code; use [Container::wait()] there
+    /// instead.
+    pub fn wait_blocking(self) -> Result<ExitStatus, RuntimeError> {
+        rt::private_handle().block_on(self.wait())
+    }
+
     /// Wait for the container to finish running, while connecting it to stdio
     ///
     /// Any stdio streams which haven't been taken from the [Container] or
@@ -138,34 +592,55 @@ impl Container {
     /// the blocking stdin reads. This thread may continue running after
     /// the container itself exits, since `std`'s stdin reads cannot be
     /// cancelled.
+    ///
+    /// SIGINT and SIGTERM are caught while the container runs and passed on
+    /// to it with [Container::kill()], so a Ctrl-C reaches the sandboxed
+    /// program instead of stranding it. A second one of the same kind is
+    /// handled as usual, in case the program ignores the first, and both go
+    /// back to their usual handling when this returns.
     pub async fn interact(self) -> Result<ExitStatus, RuntimeError> {
         log::trace!("interact starting");
         if let Some(mut stream) = self.stdin {
+            // Blocking reads on a thread of their own, forwarded from there
+            let (mut local, remote) = UnixStream::pair()?;
             let _ = thread::Builder::new()
                 .name("stdin".to_string())
                 .spawn(move || {
-                    let _ = io::copy(&mut io::stdin(), &mut stream);
+                    let _ = io::copy(&mut io::stdin(), &mut local);
                 });
+            rt::spawn(async move {
+                let mut remote = tokio::net::UnixStream::from_std(remote)?;
+                tokio::io::copy(&mut remote, &mut stream).await
+            });
         }
 
         let stdout = self.stdout;
-        let stdout = tokio::spawn(async move {
-            if let Some(stream) = stdout {
-                let mut stream = tokio::net::UnixStream::from_std(stream)?;
+        let stdout = rt::spawn(async move {
+            if let Some(mut stream) = stdout {
                 tokio::io::copy(&mut stream, &mut tokio::io::stdout()).await?;
             }
             Ok::<(), tokio::io::Error>(())
         });
         let stderr = self.stderr;
-        let stderr = tokio::spawn(async move {
-            if let Some(stream) = stderr {
-                let mut stream = tokio::net::UnixStream::from_std(stream)?;
+        let stderr = rt::spawn(async move {
+            if let Some(mut stream) = stderr {
                 tokio::io::copy(&mut stream, &mut tokio::io::stderr()).await?;
             }
             Ok::<(), tokio::io::Error>(())
         });
 
-        let status = self.join.await??;
+        let (done, signals_done) = oneshot::channel();
+        let signals = rt::spawn(forward_signals(self.requests, signals_done));
+
+        let status = self.join.await;
+        let _ = done.send(());
+        // The container's exit matters more than a problem with signals
+        match signals.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::warn!("forwarding signals, {}", err),
+            Err(err) => log::warn!("forwarding signals, {}", err),
+        }
+        let status = status??;
         log::trace!("interact waiting for stdout/stderr");
         let (stdout, stderr) = tokio::join!(stdout, stderr);
         expect_broken_pipe(stdout?)?;
@@ -183,11 +658,13 @@ impl Container {
     pub async fn output(self) -> Result<Output, RuntimeError> {
         drop(self.stdin);
 
-        fn output_task(stream: Option<UnixStream>) -> JoinHandle<tokio::io::Result<Vec<u8>>> {
-            task::spawn(async move {
+        fn output_task<R>(stream: Option<R>) -> JoinHandle<tokio::io::Result<Vec<u8>>>
+        where
+            R: AsyncRead + Unpin + Send + 'static,
+        {
+            rt::spawn(async move {
                 let mut buf = Vec::<u8>::new();
-                if let Some(stream) = stream {
-                    let mut stream = tokio::net::UnixStream::from_std(stream)?;
+                if let Some(mut stream) = stream {
                     tokio::io::copy(&mut stream, &mut buf).await?;
                 }
                 Ok(buf)
@@ -211,6 +688,7 @@ impl Container {
     }
 
     pub(crate) fn exec(
+        id: ContainerId,
         filesystem: Filesystem,
         storage: FileStorage,
         filename: CString,
@@ -219,13 +697,25 @@ impl Container {
         env: Vec<CString>,
         stdio: [Option<UnixStream>; 3],
         tracer_settings: TracerSettings,
+        auto_suspend: Option<AutoSuspend>,
+        tracer: Option<TracerProcess>,
+        exec_snapshots: Option<Arc<ExecSnapshots>>,
+        memory_limit: Option<u64>,
+        tagged_output: Option<TaggedOutput>,
+        path_remap: PathRemap,
+        secrets: Arc<SecretAudit>,
+        network: Option<Arc<NetworkGroup>>,
+        workspaces: Vec<WorkspaceMount>,
+        events: Option<EventCallback>,
+        scope: Option<SystemdScope>,
+        mut hooks: Hooks,
     ) -> Result<Container, RuntimeError> {
         log::debug!(
             "exec file={:?} dir={:?} argv={:?} env={:?}",
             filename,
             dir,
             argv,
-            env
+            secrets.redact_env(&env)
         );
 
         let filename = filename.into_bytes_with_nul();
@@ -242,36 +732,84 @@ impl Container {
             env_count: env.len(),
         };
 
+        let mut args = args_header.as_bytes().to_vec();
+        args.extend_from_slice(&dir);
+        args.extend_from_slice(&filename);
+        for bytes in argv {
+            args.extend_from_slice(&bytes);
+        }
+        args.push(0);
+        for bytes in env {
+            args.extend_from_slice(&bytes);
+        }
+        args.push(0);
+
+        let exec_snapshot = match exec_snapshots {
+            Some(snapshots) => Some(ExecSnapshotSlot::new(snapshots, &tracer_settings, &args)?),
+            None => None,
+        };
+
         let [stdin, stdout, stderr] = stdio;
+        let memory = Arc::new(MemoryAccounting::new(memory_limit));
+        let server_memory = memory.clone();
+        let latency = Arc::new(LatencyStats::default());
+        let server_latency = latency.clone();
+        let server_output = tagged_output.clone();
+        let server_secrets = secrets.clone();
+        let tracer = match tracer {
+            Some(tracer) => tracer,
+            None => TracerProcess::spawn()?,
+        };
+        let tracer_pid = tracer.id();
+        let control = tracer.control()?;
+        let (requests, server_requests) = mpsc::unbounded_channel();
 
         Ok(Container {
-            stdin,
-            stdout,
-            stderr,
-            join: tokio::spawn(async move {
+            stdin: stdin.map(ChildStdin::from_std).transpose()?,
+            stdout: stdout.map(ChildStdout::from_std).transpose()?,
+            stderr: stderr.map(ChildStderr::from_std).transpose()?,
+            id,
+            recording: None,
+            tagged_output,
+            memory,
+            latency,
+            secrets,
+            control,
+            requests,
+            join: rt::spawn_private(async move {
+                if let Some(scope) = scope {
+                    rt::spawn_blocking(move || scope.enter(&id, tracer_pid)).await??;
+                }
                 let ipc_task = {
-                    let (args_local, args_remote) = fd_queue::tokio::UnixStream::pair()?;
-                    let mut args_buf = BufWriter::new(args_local);
-                    let ipc_task =
-                        IPCServer::new(filesystem, storage, &args_remote, tracer_settings)
-                            .await?
-                            .task();
-
-                    args_buf.write_all(args_header.as_bytes()).await?;
-                    args_buf.write_all(&dir).await?;
-                    args_buf.write_all(&filename).await?;
-
-                    for bytes in argv {
-                        args_buf.write_all(&bytes).await?;
-                    }
-                    args_buf.write_all(b"\0").await?;
-
-                    for bytes in env {
-                        args_buf.write_all(&bytes).await?;
-                    }
-                    args_buf.write_all(b"\0").await?;
-
-                    args_buf.flush().await?;
+                    let mut filesystem = filesystem;
+                    hooks
+                        .run(HookStage::PreSpawn, &mut filesystem, &storage)
+                        .await?;
+                    let (mut args_local, args_remote) = fd_queue::tokio::UnixStream::pair()?;
+                    let ipc_task = IPCServer::new(
+                        filesystem,
+                        storage,
+                        &args_remote,
+                        tracer_settings,
+                        auto_suspend,
+                        server_requests,
+                        tracer,
+                        exec_snapshot,
+                        server_memory,
+                        server_latency,
+                        server_output,
+                        path_remap,
+                        server_secrets,
+                        network,
+                        workspaces,
+                        events,
+                        hooks,
+                    )
+                    .await?
+                    .task();
+
+                    args_local.write_all(&args).await?;
+                    args_local.flush().await?;
                     ipc_task
                 };
                 Ok(ipc_task.await??)
@@ -279,3 +817,44 @@ impl Container {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn forwarding_restores_prior_handlers() {
+        let ignored = || current_actions()[1].sa_sigaction == libc::SIG_IGN;
+        unsafe { libc::signal(libc::SIGTERM, libc::SIG_IGN) };
+        Runtime::new().unwrap().block_on(async {
+            for _ in 0..2 {
+                let forwarding = Forwarding::begin().unwrap();
+                let _terminate = signal(SignalKind::terminate()).unwrap();
+                assert!(!ignored());
+                drop(forwarding);
+                assert!(ignored());
+            }
+        });
+        unsafe { libc::signal(libc::SIGTERM, libc::SIG_DFL) };
+    }
+
+    #[test]
+    fn exit_status_rules() {
+        let status = ExitStatus::exited(3, Some((VPid(2), 9)));
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(status.signal(), None);
+        assert_eq!(status.signaled_process(), None);
+
+        let status = ExitStatus::exited(137, Some((VPid(4), 9)));
+        assert_eq!(status.code(), Some(137));
+        assert_eq!(status.signal(), Some(9));
+        assert_eq!(status.signaled_process(), Some(VPid(4)));
+
+        let status = ExitStatus::signaled(15);
+        assert!(!status.success());
+        assert_eq!(status.code(), Some(143));
+        assert_eq!(status.signal(), Some(15));
+        assert_eq!(status.signaled_process(), Some(VPid(1)));
+    }
+}
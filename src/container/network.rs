@@ -0,0 +1,445 @@
+use crate::{errors::RuntimeError, sand::protocol::Errno};
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::symlink,
+        io::FromRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tempfile::TempDir;
+
+/// Ports handed out when a socket is bound to port zero, the same range
+/// Linux uses by default
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 32768..=60999;
+
+/// Flag in /proc/net/unix for sockets that are listening
+const UNIX_ACCEPTCON: u32 = 0x10000;
+
+/// A loopback network shared by containers
+///
+/// Every container has a loopback network of its own unless it joins a
+/// group with
+/// [ContainerBuilder::network_group()](crate::ContainerBuilder::network_group).
+/// Containers in the same group can reach each other's TCP listeners at
+/// 127.0.0.1 or ::1, so a client in one container can talk to a server in
+/// another. There is no other network.
+///
+/// The runtime models the network itself, with a unix socket standing in for
+/// each TCP socket. IPv4 and IPv6 share one set of ports, and only stream
+/// sockets are supported.
+///
+/// Each unix socket is bound to a file named after the address it stands
+/// for, a struct sockaddr_in or sockaddr_in6 in hex, so the sandbox can
+/// recover socket and peer names from the kernel. Listening ports also get a
+/// symlink named after the port, which is what clients connect to.
+pub struct NetworkGroup {
+    name: String,
+    dir: TempDir,
+    bind_lock: Mutex<()>,
+    next_client_port: AtomicUsize,
+}
+
+impl NetworkGroup {
+    /// Create a new, empty network group
+    pub fn new(name: &str) -> Result<Arc<NetworkGroup>, RuntimeError> {
+        Ok(Arc::new(NetworkGroup {
+            name: name.to_string(),
+            dir: TempDir::new()?,
+            bind_lock: Mutex::new(()),
+            next_client_port: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Get the name this group was created with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Start listening on a local address, or any free port if its port is
+    /// zero
+    pub(crate) fn bind(&self, addr: &SocketAddr) -> Result<UnixListener, Errno> {
+        if !is_local(&addr.ip()) {
+            return Err(Errno::new(libc::EADDRNOTAVAIL));
+        }
+        let _guard = self.bind_lock.lock().unwrap();
+        if addr.port() != 0 {
+            return self.bind_port(addr);
+        }
+        for port in EPHEMERAL_PORTS {
+            match self.bind_port(&SocketAddr::new(addr.ip(), port)) {
+                Err(err) if err.code() == libc::EADDRINUSE => continue,
+                result => return result,
+            }
+        }
+        Err(Errno::new(libc::EADDRINUSE))
+    }
+
+    /// Connect to a port some container in this group is listening on
+    ///
+    /// This never waits. Like a full TCP backlog, a full listen queue
+    /// refuses the connection.
+    pub(crate) fn connect(&self, addr: &SocketAddr) -> Result<UnixStream, Errno> {
+        if !is_local(&addr.ip()) {
+            return Err(Errno::new(libc::ENETUNREACH));
+        }
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(last_errno());
+        }
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+        // The client's own name only needs to last until the listener has
+        // seen it, sockets keep the name they were bound with
+        let client_path = self.bind_client(fd, &addr.ip())?;
+        let sockaddr = sockaddr_un(&self.port_path(addr.port()));
+        let result = sockaddr.and_then(|sockaddr| {
+            let result = unsafe {
+                libc::connect(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_un as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+                )
+            };
+            if result == 0 {
+                Ok(())
+            } else {
+                Err(last_errno())
+            }
+        });
+        let _ = fs::remove_file(&client_path);
+        match result {
+            Ok(()) => Ok(stream),
+            Err(err) if err.code() == libc::ENOENT || err.code() == libc::EAGAIN => {
+                Err(Errno::new(libc::ECONNREFUSED))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Name a client socket after an ephemeral port on the loopback address
+    /// in the same family as `ip`
+    fn bind_client(&self, fd: libc::c_int, ip: &IpAddr) -> Result<PathBuf, Errno> {
+        let ip: IpAddr = match ip {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        };
+        let first_port = *EPHEMERAL_PORTS.start() as usize;
+        let num_ports = EPHEMERAL_PORTS.len();
+        for _ in 0..num_ports {
+            let counter = self.next_client_port.fetch_add(1, Ordering::Relaxed);
+            let port = (first_port + counter % num_ports) as u16;
+            let path = self.socket_path(&SocketAddr::new(ip, port));
+            let sockaddr = sockaddr_un(&path)?;
+            let result = unsafe {
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_un as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+                )
+            };
+            if result == 0 {
+                return Ok(path);
+            }
+            match last_errno() {
+                err if err.code() == libc::EADDRINUSE => continue,
+                err => return Err(err),
+            }
+        }
+        Err(Errno::new(libc::EADDRNOTAVAIL))
+    }
+
+    fn bind_port(&self, addr: &SocketAddr) -> Result<UnixListener, Errno> {
+        let link = self.port_path(addr.port());
+        // The socket file outlives its listener, so a port is only in use
+        // while something is still listening there
+        if let Ok(target) = fs::read_link(&link) {
+            let target = self.dir.path().join(target);
+            if is_listening(&target) {
+                return Err(Errno::new(libc::EADDRINUSE));
+            }
+            let _ = fs::remove_file(&link);
+            let _ = fs::remove_file(&target);
+        }
+        let path = self.socket_path(addr);
+        let listener = UnixListener::bind(&path).map_err(io_errno)?;
+        if let Err(err) = symlink(path.file_name().unwrap(), &link) {
+            let _ = fs::remove_file(&path);
+            return Err(io_errno(err));
+        }
+        Ok(listener)
+    }
+
+    fn port_path(&self, port: u16) -> PathBuf {
+        self.dir.path().join(port.to_string())
+    }
+
+    fn socket_path(&self, addr: &SocketAddr) -> PathBuf {
+        let hex: String = sockaddr_bytes(addr)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.dir.path().join(hex)
+    }
+}
+
+impl fmt::Debug for NetworkGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NetworkGroup({})", self.name)
+    }
+}
+
+/// Decode a struct sockaddr_in or sockaddr_in6 from the sandbox
+pub(crate) fn parse_sockaddr(bytes: &[u8]) -> Result<SocketAddr, Errno> {
+    if bytes.len() < 2 {
+        return Err(Errno::new(libc::EINVAL));
+    }
+    let port = || u16::from_be_bytes([bytes[2], bytes[3]]);
+    match u16::from_ne_bytes([bytes[0], bytes[1]]) as i32 {
+        libc::AF_INET if bytes.len() >= mem::size_of::<libc::sockaddr_in>() => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&bytes[4..8]);
+            Ok(SocketAddr::new(Ipv4Addr::from(octets).into(), port()))
+        }
+        libc::AF_INET6 if bytes.len() >= mem::size_of::<libc::sockaddr_in6>() => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&bytes[8..24]);
+            Ok(SocketAddr::new(Ipv6Addr::from(octets).into(), port()))
+        }
+        libc::AF_INET | libc::AF_INET6 => Err(Errno::new(libc::EINVAL)),
+        _ => Err(Errno::new(libc::EAFNOSUPPORT)),
+    }
+}
+
+/// Encode a struct sockaddr_in or sockaddr_in6 as the sandbox would
+pub(crate) fn sockaddr_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    match addr {
+        SocketAddr::V4(addr) => {
+            bytes.extend_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.resize(mem::size_of::<libc::sockaddr_in>(), 0);
+        }
+        SocketAddr::V6(addr) => {
+            bytes.extend_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+            bytes.extend_from_slice(&addr.port().to_be_bytes());
+            bytes.extend_from_slice(&addr.flowinfo().to_be_bytes());
+            bytes.extend_from_slice(&addr.ip().octets());
+            bytes.extend_from_slice(&addr.scope_id().to_ne_bytes());
+        }
+    }
+    bytes
+}
+
+fn sockaddr_un(path: &Path) -> Result<libc::sockaddr_un, Errno> {
+    let mut sockaddr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    sockaddr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path_bytes = path.as_os_str().as_bytes();
+    if path_bytes.len() >= sockaddr.sun_path.len() {
+        return Err(Errno::new(libc::ENAMETOOLONG));
+    }
+    for (dest, src) in sockaddr.sun_path.iter_mut().zip(path_bytes) {
+        *dest = *src as libc::c_char;
+    }
+    Ok(sockaddr)
+}
+
+/// Addresses that mean this host, which is all a network group has
+fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_unspecified(),
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.to_ipv4().map_or(false, |ip| ip.is_loopback())
+        }
+    }
+}
+
+/// Is any socket listening at this path?
+fn is_listening(path: &Path) -> bool {
+    let table = match fs::read_to_string("/proc/net/unix") {
+        Ok(table) => table,
+        // Without the table, assume the port is taken rather than steal it
+        Err(_) => return true,
+    };
+    let path = path.to_string_lossy();
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() >= 8
+            && fields[7..].join(" ") == path
+            && u32::from_str_radix(fields[3], 16).map_or(false, |flags| flags & UNIX_ACCEPTCON != 0)
+    })
+}
+
+fn io_errno(err: std::io::Error) -> Errno {
+    match err.kind() {
+        ErrorKind::AddrInUse => Errno::new(libc::EADDRINUSE),
+        _ => Errno::new(err.raw_os_error().unwrap_or(libc::EIO)),
+    }
+}
+
+fn last_errno() -> Errno {
+    Errno::new(
+        std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn sockaddr_in(ip: [u8; 4], port: u16) -> Vec<u8> {
+        sockaddr_bytes(&SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+    }
+
+    fn sockaddr_in6(ip: Ipv6Addr, port: u16) -> Vec<u8> {
+        sockaddr_bytes(&SocketAddr::new(ip.into(), port))
+    }
+
+    /// Decode a unix socket name the way the sandbox does
+    fn tcp_name(addr: std::os::unix::net::SocketAddr) -> SocketAddr {
+        let hex = addr.as_pathname().unwrap().file_name().unwrap();
+        let hex = hex.to_str().unwrap();
+        let bytes: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        parse_sockaddr(&bytes).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_sockaddr(&sockaddr_in([127, 0, 0, 1], 8080)),
+            Ok("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_sockaddr(&sockaddr_in6(Ipv6Addr::LOCALHOST, 443)),
+            Ok("[::1]:443".parse().unwrap())
+        );
+        assert_eq!(sockaddr_in([127, 0, 0, 1], 80).len(), 16);
+        assert_eq!(sockaddr_in6(Ipv6Addr::LOCALHOST, 80).len(), 28);
+        assert_eq!(
+            parse_sockaddr(&sockaddr_in([127, 0, 0, 1], 80)[..8]),
+            Err(Errno::new(libc::EINVAL))
+        );
+        assert_eq!(
+            parse_sockaddr(&(libc::AF_UNIX as u16).to_ne_bytes()),
+            Err(Errno::new(libc::EAFNOSUPPORT))
+        );
+    }
+
+    #[test]
+    fn local_addresses() {
+        for ip in &[
+            "127.0.0.1",
+            "127.1.2.3",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_local(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["10.0.0.1", "8.8.8.8", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(!is_local(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn connect_to_listener() {
+        let group = NetworkGroup::new("test").unwrap();
+        let listener = group.bind(&"0.0.0.0:8080".parse().unwrap()).unwrap();
+        let mut client = group.connect(&"[::1]:8080".parse().unwrap()).unwrap();
+        client.set_nonblocking(false).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.write_all(b"hello").unwrap();
+        drop(server);
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "hello");
+    }
+
+    #[test]
+    fn socket_names() {
+        let group = NetworkGroup::new("test").unwrap();
+        let listener = group.bind(&"0.0.0.0:0".parse().unwrap()).unwrap();
+        let listen_addr = tcp_name(listener.local_addr().unwrap());
+        assert!(listen_addr.ip().is_unspecified());
+        assert!(EPHEMERAL_PORTS.contains(&listen_addr.port()));
+
+        let connect_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen_addr.port());
+        let client = group.connect(&connect_addr).unwrap();
+        let client_addr = tcp_name(client.local_addr().unwrap());
+        assert_eq!(client_addr.ip(), Ipv6Addr::LOCALHOST);
+        assert!(EPHEMERAL_PORTS.contains(&client_addr.port()));
+        assert_eq!(tcp_name(client.peer_addr().unwrap()), listen_addr);
+
+        let (server, _) = listener.accept().unwrap();
+        assert_eq!(tcp_name(server.peer_addr().unwrap()), client_addr);
+        assert_eq!(tcp_name(server.local_addr().unwrap()), listen_addr);
+
+        let ipv4_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen_addr.port());
+        let second = group.connect(&ipv4_addr).unwrap();
+        let second_addr = tcp_name(second.local_addr().unwrap());
+        assert_eq!(second_addr.ip(), Ipv4Addr::LOCALHOST);
+        assert_ne!(second_addr.port(), client_addr.port());
+    }
+
+    #[test]
+    fn ports() {
+        let group = NetworkGroup::new("test").unwrap();
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        assert_eq!(
+            group.connect(&addr).err(),
+            Some(Errno::new(libc::ECONNREFUSED))
+        );
+        let listener = group.bind(&addr).unwrap();
+        assert_eq!(group.bind(&addr).err(), Some(Errno::new(libc::EADDRINUSE)));
+        drop(listener);
+        assert_eq!(
+            group.connect(&addr).err(),
+            Some(Errno::new(libc::ECONNREFUSED))
+        );
+        let _listener = group.bind(&addr).unwrap();
+        assert!(group.connect(&addr).is_ok());
+        assert!(group.bind(&"127.0.0.1:0".parse().unwrap()).is_ok());
+        assert_eq!(
+            group.bind(&"10.0.0.1:1234".parse().unwrap()).err(),
+            Some(Errno::new(libc::EADDRNOTAVAIL))
+        );
+    }
+
+    #[test]
+    fn groups_are_separate() {
+        let first = NetworkGroup::new("first").unwrap();
+        let second = NetworkGroup::new("second").unwrap();
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let _listener = first.bind(&addr).unwrap();
+        assert!(first.connect(&addr).is_ok());
+        assert_eq!(
+            second.connect(&addr).err(),
+            Some(Errno::new(libc::ECONNREFUSED))
+        );
+    }
+}
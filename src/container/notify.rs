@@ -0,0 +1,88 @@
+use std::{
+    env, io, mem,
+    os::unix::ffi::OsStrExt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Readiness only needs to be reported once for the whole process
+static READY_SENT: AtomicBool = AtomicBool::new(false);
+
+/// Tell systemd the service is ready, unless it already was
+///
+/// This does nothing unless systemd started us with a notification socket,
+/// as it does for a `Type=notify` service.
+pub(crate) fn ready() {
+    if READY_SENT.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(socket) = env::var_os("NOTIFY_SOCKET") {
+        log::debug!("notifying systemd at {:?}", socket);
+        if let Err(err) = send(socket.as_bytes(), b"READY=1") {
+            log::warn!("failed to notify systemd, {}", err);
+        }
+    }
+}
+
+/// Send one datagram to the notification socket at `socket`
+///
+/// A leading `@` names a socket in the abstract namespace.
+fn send(socket: &[u8], message: &[u8]) -> io::Result<()> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if socket.is_empty() || socket.len() >= addr.sun_path.len() {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dest, src) in addr.sun_path.iter_mut().zip(socket) {
+        *dest = *src as libc::c_char;
+    }
+    if socket[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let addr_len = mem::size_of::<libc::sa_family_t>() + socket.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = unsafe {
+        libc::sendto(
+            fd,
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            addr_len as libc::socklen_t,
+        )
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if result < 0 {
+        Err(err)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn send_datagram() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str().as_bytes(), b"READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn bad_socket_names() {
+        assert!(send(b"", b"READY=1").is_err());
+        assert!(send(&[b'a'; 200], b"READY=1").is_err());
+        assert!(send(b"@bandsocks-test-no-listener", b"READY=1").is_err());
+    }
+}
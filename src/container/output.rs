@@ -0,0 +1,360 @@
+use crate::sand::protocol::{SysPid, VPid};
+use futures_util::stream::Stream;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    io::Write,
+    mem,
+    os::unix::{io::AsRawFd, net::UnixStream},
+    pin::Pin,
+    ptr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+};
+use tokio::sync::mpsc;
+
+/// Longest partial line held back before it's sent as a chunk of its own
+const LINE_MAX: usize = 64 * 1024;
+
+/// How much of the most recent output is kept for replay
+const REPLAY_MAX: usize = 1024 * 1024;
+
+/// Which output stream a chunk was written to
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum StreamId {
+    Stdout,
+    Stderr,
+}
+
+/// Whole lines written by one process to one stream
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutputChunk {
+    /// Position of this chunk in the merged output, starting at zero
+    pub seq: u64,
+    /// The process that wrote this chunk, if it could be identified
+    pub vpid: Option<VPid>,
+    pub stream: StreamId,
+    /// One or more lines, each ending in a newline, except for a final
+    /// partial line written before the stream closed or a very long line
+    pub data: Vec<u8>,
+}
+
+/// Output from every process in a container, split into lines and tagged
+/// with the process and stream it came from
+///
+/// Enabled with [crate::ContainerBuilder::tag_output()], and retrieved from
+/// [crate::Container::tagged_output()]. All processes share the container's
+/// stdout and stderr, so each write is attributed using the credentials the
+/// kernel attaches to it. Each process gets its own line buffer per stream, so
+/// lines from different processes never interleave.
+///
+/// The most recent chunks, up to 1 MiB of output, are kept for replay. Each
+/// new [OutputStream] starts with those, so a stream opened late misses only
+/// what has since been dropped. The merged order is the
+/// order lines were completed in, which follows the order of writes to each
+/// stream but can only approximate the order between stdout and stderr.
+#[derive(Clone)]
+pub struct TaggedOutput {
+    inner: Arc<Mutex<Multiplexer>>,
+}
+
+/// An async stream of [OutputChunk]s, which ends after the container closes
+/// its output
+pub struct OutputStream {
+    receiver: mpsc::UnboundedReceiver<OutputChunk>,
+}
+
+struct Multiplexer {
+    pids: HashMap<SysPid, VPid>,
+    partial: HashMap<(Option<SysPid>, StreamId), Vec<u8>>,
+    chunks: VecDeque<OutputChunk>,
+    /// Bytes of output in `chunks`
+    chunk_bytes: usize,
+    next_seq: u64,
+    subscribers: Vec<(Option<VPid>, mpsc::UnboundedSender<OutputChunk>)>,
+    open_streams: usize,
+}
+
+impl fmt::Debug for TaggedOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TaggedOutput")
+    }
+}
+
+impl TaggedOutput {
+    pub(crate) fn new() -> Self {
+        TaggedOutput {
+            inner: Arc::new(Mutex::new(Multiplexer {
+                pids: HashMap::new(),
+                partial: HashMap::new(),
+                chunks: VecDeque::new(),
+                chunk_bytes: 0,
+                next_seq: 0,
+                subscribers: Vec::new(),
+                open_streams: 0,
+            })),
+        }
+    }
+
+    /// All output from the container, in one stream
+    pub fn merged(&self) -> OutputStream {
+        self.subscribe(None)
+    }
+
+    /// Output from one process only
+    ///
+    /// Virtual process IDs are never reused within a container, so this can
+    /// be called before the process starts.
+    pub fn process(&self, vpid: VPid) -> OutputStream {
+        self.subscribe(Some(vpid))
+    }
+
+    /// The chunks still kept for replay, in merged order
+    pub fn chunks(&self) -> Vec<OutputChunk> {
+        self.inner.lock().unwrap().chunks.iter().cloned().collect()
+    }
+
+    fn subscribe(&self, filter: Option<VPid>) -> OutputStream {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut mux = self.inner.lock().unwrap();
+        for chunk in &mux.chunks {
+            if filter.is_none() || filter == chunk.vpid {
+                let _ = sender.send(chunk.clone());
+            }
+        }
+        if mux.open_streams > 0 {
+            mux.subscribers.push((filter, sender));
+        }
+        OutputStream { receiver }
+    }
+
+    /// Remember which virtual process a host process belongs to
+    pub(crate) fn process_started(&self, sys_pid: SysPid, vpid: VPid) {
+        self.inner.lock().unwrap().pids.insert(sys_pid, vpid);
+    }
+
+    /// Tag everything arriving on a stream, and return a new stream that
+    /// receives the same data
+    ///
+    /// This must happen before the container can write to the stream, since
+    /// writes are only attributed once the socket asks for credentials.
+    /// Tagging continues even if the returned stream is dropped.
+    pub(crate) fn tap(&self, stream: StreamId, source: UnixStream) -> io::Result<UnixStream> {
+        pass_credentials(&source)?;
+        let (mut forward, user) = UnixStream::pair()?;
+        self.inner.lock().unwrap().open_streams += 1;
+        let output = self.clone();
+        thread::Builder::new()
+            .name(format!("{:?}", stream).to_lowercase())
+            .spawn(move || {
+                let mut buf = [0u8; 4096];
+                let mut forwarding = true;
+                loop {
+                    match recv_with_pid(&source, &mut buf) {
+                        Ok((0, _)) => break,
+                        Ok((len, sys_pid)) => {
+                            output.received(stream, sys_pid, &buf[..len]);
+                            if forwarding && forward.write_all(&buf[..len]).is_err() {
+                                forwarding = false;
+                            }
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                        Err(err) => {
+                            log::warn!("reading container {:?}, {}", stream, err);
+                            break;
+                        }
+                    }
+                }
+                output.closed(stream);
+            })?;
+        Ok(user)
+    }
+
+    fn received(&self, stream: StreamId, sys_pid: Option<SysPid>, bytes: &[u8]) {
+        let mut mux = self.inner.lock().unwrap();
+        let mut pending = mux.partial.remove(&(sys_pid, stream)).unwrap_or_default();
+        pending.extend_from_slice(bytes);
+        let complete = match pending.iter().rposition(|b| *b == b'\n') {
+            Some(index) => index + 1,
+            None if pending.len() >= LINE_MAX => pending.len(),
+            None => 0,
+        };
+        let rest = pending.split_off(complete);
+        if !pending.is_empty() {
+            mux.emit(sys_pid, stream, pending);
+        }
+        if !rest.is_empty() {
+            mux.partial.insert((sys_pid, stream), rest);
+        }
+    }
+
+    fn closed(&self, stream: StreamId) {
+        let mut mux = self.inner.lock().unwrap();
+        let mut partial: Vec<_> = mux
+            .partial
+            .iter()
+            .filter(|((_, id), _)| *id == stream)
+            .map(|(key, _)| *key)
+            .collect();
+        partial.sort_by_key(|(sys_pid, _)| sys_pid.map(|pid| pid.0));
+        for key in partial {
+            let data = mux.partial.remove(&key).unwrap();
+            mux.emit(key.0, stream, data);
+        }
+        mux.open_streams -= 1;
+        if mux.open_streams == 0 {
+            mux.subscribers.clear();
+        }
+    }
+}
+
+impl Multiplexer {
+    fn emit(&mut self, sys_pid: Option<SysPid>, stream: StreamId, data: Vec<u8>) {
+        let chunk = OutputChunk {
+            seq: self.next_seq,
+            vpid: sys_pid.and_then(|pid| self.pids.get(&pid).copied()),
+            stream,
+            data,
+        };
+        self.subscribers.retain(|(filter, sender)| {
+            (filter.is_some() && *filter != chunk.vpid) || sender.send(chunk.clone()).is_ok()
+        });
+        self.next_seq += 1;
+        self.chunk_bytes += chunk.data.len();
+        self.chunks.push_back(chunk);
+        while self.chunk_bytes > REPLAY_MAX {
+            let oldest = self.chunks.pop_front().unwrap();
+            self.chunk_bytes -= oldest.data.len();
+        }
+    }
+}
+
+impl OutputStream {
+    /// Wait for the next chunk, or None once the output has closed
+    pub async fn next(&mut self) -> Option<OutputChunk> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for OutputStream {
+    type Item = OutputChunk;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<OutputChunk>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl fmt::Debug for OutputStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OutputStream")
+    }
+}
+
+fn pass_credentials(socket: &UnixStream) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PASSCRED,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Read from a socket with credentials enabled, returning the sending process
+///
+/// The kernel never combines writes from different processes into one read
+/// when credentials are requested, so the whole buffer has one sender.
+fn recv_with_pid(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Option<SysPid>)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut sys_pid = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_CREDENTIALS {
+            let cred: libc::ucred =
+                unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::ucred) };
+            sys_pid = Some(SysPid(cred.pid as u32));
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok((len as usize, sys_pid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_from_each_process() {
+        let output = TaggedOutput::new();
+        output.process_started(SysPid(100), VPid(1));
+        output.process_started(SysPid(200), VPid(2));
+        output.inner.lock().unwrap().open_streams = 2;
+        let mut second = output.process(VPid(2));
+
+        output.received(StreamId::Stdout, Some(SysPid(100)), b"one ");
+        output.received(StreamId::Stdout, Some(SysPid(200)), b"two\nthr");
+        output.received(StreamId::Stdout, Some(SysPid(100)), b"done\n");
+        output.received(StreamId::Stderr, Some(SysPid(200)), b"oops\n");
+        output.closed(StreamId::Stdout);
+        output.closed(StreamId::Stderr);
+
+        let tagged: Vec<_> = output
+            .chunks()
+            .into_iter()
+            .map(|chunk| (chunk.seq, chunk.vpid, chunk.stream, chunk.data))
+            .collect();
+        assert_eq!(
+            tagged,
+            vec![
+                (0, Some(VPid(2)), StreamId::Stdout, b"two\n".to_vec()),
+                (1, Some(VPid(1)), StreamId::Stdout, b"one done\n".to_vec()),
+                (2, Some(VPid(2)), StreamId::Stderr, b"oops\n".to_vec()),
+                (3, Some(VPid(2)), StreamId::Stdout, b"thr".to_vec()),
+            ]
+        );
+
+        let mut received = Vec::new();
+        while let Ok(chunk) = second.receiver.try_recv() {
+            received.push(chunk.seq);
+        }
+        assert_eq!(received, vec![0, 2, 3]);
+        assert_eq!(output.merged().receiver.try_recv().unwrap().seq, 0);
+    }
+
+    #[test]
+    fn replay_is_bounded() {
+        let output = TaggedOutput::new();
+        let line = vec![b'x'; LINE_MAX];
+        let count = REPLAY_MAX / LINE_MAX + 4;
+        for _ in 0..count {
+            output.received(StreamId::Stdout, None, &line);
+        }
+        let chunks = output.chunks();
+        assert_eq!(chunks.len(), REPLAY_MAX / LINE_MAX);
+        assert_eq!(chunks.last().unwrap().seq, count as u64 - 1);
+        assert_eq!(output.merged().receiver.try_recv().unwrap().seq, 4);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    container::{snapshot::ExecSnapshots, Container, ContainerBuilder},
+    errors::ImageError,
+    image::{Image, ImageName},
+    ipcserver::TracerProcess,
+    registry::RegistryClient,
+    rt,
+};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// A source of quick-starting containers which all run the same image
+///
+/// The pool keeps an [Image] loaded, along with a number of sandbox tracer
+/// processes that have already started and loaded their seccomp policy. Each
+/// container spawned from the pool claims one of those tracers, and a
+/// replacement is started in the background. If the pool runs dry, containers
+/// still start normally, just without the head start.
+///
+/// Pools are cheap to clone, and clones share the same tracers.
+#[derive(Clone)]
+pub struct ContainerPool {
+    image: Arc<Image>,
+    tracers: Arc<TracerPool>,
+    snapshots: Option<Arc<ExecSnapshots>>,
+}
+
+impl ContainerPool {
+    /// Start a pool for an image that's already loaded, keeping `size` tracer
+    /// processes ready
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn new(image: Arc<Image>, size: usize) -> Self {
+        let tracers = Arc::new(TracerPool::new(size));
+        tracers.fill();
+        ContainerPool {
+            image,
+            tracers,
+            snapshots: None,
+        }
+    }
+
+    /// Pull an image with the default [RegistryClient] and start a pool for it
+    ///
+    /// This is equivalent to using [RegistryClient::pull()] followed by
+    /// [ContainerPool::new()].
+    pub async fn pull(name: &ImageName, size: usize) -> Result<Self, ImageError> {
+        Ok(ContainerPool::new(
+            RegistryClient::new()?.pull(name).await?,
+            size,
+        ))
+    }
+
+    /// Skip loading the entry point on repeated runs, by restoring a snapshot
+    ///
+    /// The first container to run each distinct command line saves the memory
+    /// of its first process right after the entry binary and its dynamic
+    /// loader are mapped. Later containers with the same arguments and
+    /// environment restore that snapshot instead of loading ELF files.
+    /// Containers with extra mounts never use snapshots.
+    ///
+    /// Restored processes start with the same memory layout and the same
+    /// auxiliary random bytes as the original, so address space layout
+    /// randomization is shared by every run of the same command. The mappings
+    /// are also no longer backed by the original files.
+    pub fn exec_snapshots(mut self) -> Self {
+        self.snapshots = Some(Default::default());
+        self
+    }
+
+    /// Get the image this pool runs
+    pub fn image(&self) -> &Arc<Image> {
+        &self.image
+    }
+
+    /// Prepare to run a new container using the pool
+    ///
+    /// This is like [Container::new()] with the pool's image, and the
+    /// [ContainerBuilder] can be customized in all the same ways. A warm
+    /// tracer is claimed when the container is spawned.
+    pub fn container(&self) -> Result<ContainerBuilder, ImageError> {
+        let builder = Container::new(self.image.clone())?.tracer_pool(self.tracers.clone());
+        Ok(match &self.snapshots {
+            Some(snapshots) => builder.exec_snapshots(snapshots.clone()),
+            None => builder,
+        })
+    }
+}
+
+impl fmt::Debug for ContainerPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ContainerPool({}, {})",
+            self.image.name(),
+            self.tracers.size
+        )
+    }
+}
+
+/// Idle tracer processes, waiting for a container
+pub(crate) struct TracerPool {
+    size: usize,
+    idle: Mutex<Vec<TracerProcess>>,
+    /// Only one refill runs at a time, so the pool never grows past its size
+    filling: AtomicBool,
+}
+
+impl TracerPool {
+    fn new(size: usize) -> Self {
+        TracerPool {
+            size,
+            idle: Mutex::new(Vec::with_capacity(size)),
+            filling: AtomicBool::new(false),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.idle.lock().unwrap().len() >= self.size
+    }
+
+    /// Start tracers until the pool is full, returning false if one failed
+    fn fill(&self) -> bool {
+        while !self.is_full() {
+            match TracerProcess::spawn() {
+                Ok(tracer) => self.idle.lock().unwrap().push(tracer),
+                Err(err) => {
+                    log::warn!("failed to start a tracer for the pool, {}", err);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Fill the pool on a blocking thread, unless that's already happening
+    fn refill(self: &Arc<Self>) {
+        if self.filling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let pool = self.clone();
+        rt::spawn_blocking(move || {
+            let filled = pool.fill();
+            pool.filling.store(false, Ordering::Release);
+            // A tracer taken after the last check would otherwise not be
+            // replaced until the next one is taken
+            if filled && !pool.is_full() {
+                pool.refill();
+            }
+        });
+    }
+
+    /// Take an idle tracer if there is one, and refill the pool in the
+    /// background
+    pub(crate) fn take(self: &Arc<Self>) -> Option<TracerProcess> {
+        let tracer = self.idle.lock().unwrap().pop();
+        self.refill();
+        tracer
+    }
+}
@@ -0,0 +1,145 @@
+use crate::rt;
+use std::{
+    fmt, io,
+    io::Write,
+    os::unix::net::UnixStream,
+    str,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Terminal size reported in recordings, since containers don't have a tty
+const WIDTH: u32 = 80;
+const HEIGHT: u32 = 24;
+
+/// A timed record of everything a container wrote to stdout and stderr
+///
+/// Enabled with [crate::ContainerBuilder::record_session()], and retrieved
+/// from [crate::Container::recording()]. The recording is shared, so it can be
+/// taken from the container before waiting for it to exit, and will keep
+/// growing until the container closes its output streams.
+#[derive(Clone)]
+pub struct SessionRecording {
+    inner: Arc<Mutex<Recorder>>,
+}
+
+impl fmt::Debug for SessionRecording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SessionRecording")
+    }
+}
+
+struct Recorder {
+    start: Instant,
+    timestamp: u64,
+    events: Vec<(f64, String)>,
+    partial_char: Vec<u8>,
+}
+
+impl SessionRecording {
+    pub(crate) fn new() -> Self {
+        SessionRecording {
+            inner: Arc::new(Mutex::new(Recorder {
+                start: Instant::now(),
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0),
+                events: Vec::new(),
+                partial_char: Vec::new(),
+            })),
+        }
+    }
+
+    /// Write the recording in asciicast v2 format
+    ///
+    /// Reference: https://github.com/asciinema/asciinema/blob/develop/doc/asciicast-v2.md
+    pub fn write_asciicast<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let recorder = self.inner.lock().unwrap();
+        let header = serde_json::json!({
+            "version": 2,
+            "width": WIDTH,
+            "height": HEIGHT,
+            "timestamp": recorder.timestamp,
+        });
+        writeln!(out, "{}", header)?;
+        for (time, data) in &recorder.events {
+            writeln!(out, "{}", serde_json::json!([time, "o", data]))?;
+        }
+        Ok(())
+    }
+
+    /// Return the recording as an asciicast v2 string
+    pub fn to_asciicast(&self) -> String {
+        let mut buf = Vec::new();
+        self.write_asciicast(&mut buf).expect("writing to memory");
+        String::from_utf8(buf).expect("json is utf8")
+    }
+
+    fn output(&self, bytes: &[u8]) {
+        let mut recorder = self.inner.lock().unwrap();
+        let time = recorder.start.elapsed().as_secs_f64();
+        let mut pending = std::mem::take(&mut recorder.partial_char);
+        pending.extend_from_slice(bytes);
+
+        // Hold back a multi-byte character that was split between reads, but
+        // replace bytes that can never be valid
+        let mut data = String::new();
+        let mut remaining = &pending[..];
+        loop {
+            match str::from_utf8(remaining) {
+                Ok(valid) => {
+                    data.push_str(valid);
+                    remaining = &[];
+                    break;
+                }
+                Err(err) => {
+                    let (valid, rest) = remaining.split_at(err.valid_up_to());
+                    data.push_str(str::from_utf8(valid).unwrap());
+                    match err.error_len() {
+                        None => {
+                            remaining = rest;
+                            break;
+                        }
+                        Some(len) => {
+                            data.push(char::REPLACEMENT_CHARACTER);
+                            remaining = &rest[len..];
+                        }
+                    }
+                }
+            }
+        }
+        recorder.partial_char = remaining.to_vec();
+        if !data.is_empty() {
+            recorder.events.push((time, data));
+        }
+    }
+
+    /// Record everything arriving on a stream, and return a new stream that
+    /// receives the same data
+    ///
+    /// Recording continues even if the returned stream is dropped.
+    pub(crate) fn tap(&self, source: UnixStream) -> io::Result<UnixStream> {
+        let (forward, user) = UnixStream::pair()?;
+        let recording = self.clone();
+        rt::spawn(async move {
+            let mut source = tokio::net::UnixStream::from_std(source)?;
+            let mut forward = Some(tokio::net::UnixStream::from_std(forward)?);
+            let mut buf = [0u8; 4096];
+            loop {
+                let len = source.read(&mut buf).await?;
+                if len == 0 {
+                    return Ok::<(), io::Error>(());
+                }
+                recording.output(&buf[..len]);
+                if let Some(stream) = &mut forward {
+                    if stream.write_all(&buf[..len]).await.is_err() {
+                        forward = None;
+                    }
+                }
+            }
+        });
+        Ok(user)
+    }
+}
@@ -0,0 +1,283 @@
+use crate::{container::ContainerId, errors::RuntimeError};
+use std::{
+    ffi::{OsStr, OsString},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const BUSCTL: &str = "busctl";
+const DESTINATION: &str = "org.freedesktop.systemd1";
+const MANAGER_PATH: &str = "/org/freedesktop/systemd1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.systemd1.Manager";
+const JOB_TIMEOUT: Duration = Duration::from_secs(10);
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Resource limits for a container, enforced by the host's systemd
+///
+/// Cgroup limits would normally need privileges the runtime doesn't have.
+/// Instead, the runtime asks the user's own systemd manager, over D-Bus with
+/// `busctl`, to move the tracer into a transient scope unit. The scope also
+/// gives the container its own line in systemd's accounting, and it goes away
+/// by itself once the tracer exits. The user manager
+/// already owns a delegated cgroup subtree, so nothing here is setuid and the
+/// runtime process stays unprivileged. Sandboxed processes are all children of
+/// the tracer, and they end up in the same scope.
+///
+/// This needs a systemd user session with the relevant cgroup controllers
+/// delegated to it. If the scope can't be created, the container fails before
+/// starting any sandboxed processes rather than running without the limits.
+#[derive(Debug, Clone, Default)]
+pub struct SystemdScope {
+    system: bool,
+    properties: Vec<(&'static str, u64)>,
+}
+
+impl SystemdScope {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Hard limit on memory use, in bytes, as systemd's MemoryMax
+    ///
+    /// Unlike [ContainerBuilder::memory_limit()](crate::ContainerBuilder::memory_limit()),
+    /// this is enforced by the kernel and counts everything the processes
+    /// use, including page cache.
+    pub fn memory_max(self, bytes: u64) -> Self {
+        self.property("MemoryMax", bytes)
+    }
+
+    /// Limit cpu time to `percent` of one cpu, as systemd's CPUQuota
+    pub fn cpu_quota(self, percent: u32) -> Self {
+        self.property("CPUQuotaPerSecUSec", percent as u64 * 10_000)
+    }
+
+    /// Relative share of block I/O, from 1 to 10000 with 100 as the default,
+    /// as systemd's IOWeight
+    pub fn io_weight(self, weight: u16) -> Self {
+        self.property("IOWeight", weight.max(1).min(10000) as u64)
+    }
+
+    /// Ask the system's service manager instead of the user's
+    ///
+    /// This suits a runtime which is itself a system service. Creating units
+    /// on the system bus needs root, or a polkit rule allowing it.
+    pub fn system(mut self) -> Self {
+        self.system = true;
+        self
+    }
+
+    /// Limit on the number of tasks in the scope, as systemd's TasksMax
+    pub fn tasks_max(self, tasks: u64) -> Self {
+        self.property("TasksMax", tasks)
+    }
+
+    fn property(mut self, name: &'static str, value: u64) -> Self {
+        self.properties.retain(|(existing, _)| *existing != name);
+        self.properties.push((name, value));
+        self
+    }
+
+    fn unit_name(id: &ContainerId) -> String {
+        format!("bandsocks-{}.scope", id)
+    }
+
+    fn bus(&self) -> &'static str {
+        if self.system {
+            "--system"
+        } else {
+            "--user"
+        }
+    }
+
+    fn busctl_args(&self, id: &ContainerId, pid: u32) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![
+            self.bus().into(),
+            "call".into(),
+            DESTINATION.into(),
+            MANAGER_PATH.into(),
+            MANAGER_INTERFACE.into(),
+            "StartTransientUnit".into(),
+            "ssa(sv)a(sa(sv))".into(),
+            SystemdScope::unit_name(id).into(),
+            "fail".into(),
+            (2 + self.properties.len()).to_string().into(),
+            "PIDs".into(),
+            "au".into(),
+            "1".into(),
+            pid.to_string().into(),
+            // Once the tracer exits, the unit goes away even if it failed
+            "CollectMode".into(),
+            "s".into(),
+            "inactive-or-failed".into(),
+        ];
+        for (name, value) in &self.properties {
+            args.push((*name).into());
+            args.push("t".into());
+            args.push(value.to_string().into());
+        }
+        // No auxiliary units
+        args.push("0".into());
+        args
+    }
+
+    /// Move the tracer process into a new scope for this container
+    ///
+    /// This has to happen before the tracer starts any sandboxed processes,
+    /// so that they inherit the scope. Like `systemd-run --scope`, it waits
+    /// for systemd to finish the start job and checks that the unit came up,
+    /// since `StartTransientUnit` returns as soon as the job is queued. It
+    /// blocks on `busctl` subprocesses, so run it on a blocking thread.
+    pub(crate) fn enter(&self, id: &ContainerId, pid: u32) -> Result<(), RuntimeError> {
+        let unit = SystemdScope::unit_name(id);
+        log::debug!("moving tracer {} into {}", pid, unit);
+        let reply = self.busctl(self.busctl_args(id, pid))?;
+        self.wait_for_job(reply_string(&reply)?)?;
+
+        let reply = self.busctl(&[
+            self.bus(),
+            "call",
+            DESTINATION,
+            MANAGER_PATH,
+            MANAGER_INTERFACE,
+            "GetUnit",
+            "s",
+            unit.as_str(),
+        ])?;
+        let reply = self.busctl(&[
+            self.bus(),
+            "get-property",
+            DESTINATION,
+            reply_string(&reply)?,
+            "org.freedesktop.systemd1.Unit",
+            "ActiveState",
+        ])?;
+        match reply_string(&reply)? {
+            "active" => Ok(()),
+            state => Err(RuntimeError::SystemdScopeError(format!(
+                "{} is {} after starting",
+                unit, state
+            ))),
+        }
+    }
+
+    /// Wait for a job to leave systemd's queue
+    ///
+    /// The job's object goes away once systemd has finished with it, which is
+    /// also when it would send `JobRemoved`.
+    fn wait_for_job(&self, job: &str) -> Result<(), RuntimeError> {
+        let deadline = Instant::now() + JOB_TIMEOUT;
+        while self
+            .busctl(&[
+                self.bus(),
+                "get-property",
+                DESTINATION,
+                job,
+                "org.freedesktop.systemd1.Job",
+                "State",
+            ])
+            .is_ok()
+        {
+            if Instant::now() >= deadline {
+                return Err(RuntimeError::SystemdScopeError(format!(
+                    "timed out waiting for {}",
+                    job
+                )));
+            }
+            thread::sleep(JOB_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn busctl<I, S>(&self, args: I) -> Result<String, RuntimeError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = Command::new(BUSCTL)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(RuntimeError::SystemdScopeError(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ))
+        }
+    }
+}
+
+/// The value from a `busctl` reply with a single string or object path, like
+/// `o "/org/freedesktop/systemd1/job/42"`
+fn reply_string(reply: &str) -> Result<&str, RuntimeError> {
+    let reply = reply.trim();
+    match (reply.find('"'), reply.rfind('"')) {
+        (Some(start), Some(end)) if start < end => Ok(&reply[start + 1..end]),
+        _ => Err(RuntimeError::SystemdScopeError(format!(
+            "unexpected reply from busctl, {:?}",
+            reply
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageName;
+
+    #[test]
+    fn busctl_args() {
+        let image: ImageName = "busybox".parse().unwrap();
+        let id = ContainerId::new(&image);
+        let scope = SystemdScope::new()
+            .memory_max(1 << 30)
+            .cpu_quota(50)
+            .io_weight(0)
+            .memory_max(1 << 20);
+        let args: Vec<String> = scope
+            .busctl_args(&id, 1234)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect();
+        assert_eq!(args[0], "--user");
+        assert_eq!(args[7], format!("bandsocks-{}.scope", id));
+        assert_eq!(
+            &args[9..],
+            &[
+                "5",
+                "PIDs",
+                "au",
+                "1",
+                "1234",
+                "CollectMode",
+                "s",
+                "inactive-or-failed",
+                "CPUQuotaPerSecUSec",
+                "t",
+                "500000",
+                "IOWeight",
+                "t",
+                "1",
+                "MemoryMax",
+                "t",
+                "1048576",
+                "0"
+            ]
+        );
+        let args = SystemdScope::new().system().busctl_args(&id, 1234);
+        assert_eq!(args[0], "--system");
+        assert_eq!(args[9], "2");
+    }
+
+    #[test]
+    fn reply_strings() {
+        assert_eq!(
+            reply_string("o \"/org/freedesktop/systemd1/job/42\"\n").unwrap(),
+            "/org/freedesktop/systemd1/job/42"
+        );
+        assert_eq!(reply_string("s \"active\"").unwrap(), "active");
+        assert!(reply_string("").is_err());
+        assert!(reply_string("u 3").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::sand::protocol::{INodeNum, VFile, VPid};
+use std::{collections::HashMap, ffi::CString, sync::Mutex};
+
+/// The first time a process opened a secret file, see
+/// [ContainerBuilder::secret_file()](crate::ContainerBuilder::secret_file)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SecretAccess {
+    /// Name the secret was given to the builder with
+    pub name: String,
+    /// The process which opened it
+    pub vpid: VPid,
+}
+
+/// Secrets given to one container, and which of its files have been read
+#[derive(Debug, Default)]
+pub(crate) struct SecretAudit {
+    env_keys: Vec<Vec<u8>>,
+    files: HashMap<INodeNum, String>,
+    accesses: Mutex<Vec<SecretAccess>>,
+}
+
+impl SecretAudit {
+    pub fn new(env_keys: Vec<Vec<u8>>, files: HashMap<INodeNum, String>) -> Self {
+        SecretAudit {
+            env_keys,
+            files,
+            accesses: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<SecretAccess> {
+        self.accesses.lock().unwrap().clone()
+    }
+
+    /// Note a file the guest opened, recording it if it's the first read of
+    /// a secret
+    pub fn opened(&self, vpid: VPid, file: &VFile) {
+        if let Some(name) = self.files.get(&file.inode) {
+            let mut accesses = self.accesses.lock().unwrap();
+            if accesses.iter().all(|access| &access.name != name) {
+                log::info!("secret {:?} first read by {:?}", name, vpid);
+                accesses.push(SecretAccess {
+                    name: name.clone(),
+                    vpid,
+                });
+            }
+        }
+    }
+
+    /// A copy of the environment that's safe to log
+    pub fn redact_env(&self, env: &[CString]) -> Vec<CString> {
+        env.iter()
+            .map(|var| {
+                let bytes = var.as_bytes();
+                let key = bytes.split(|b| *b == b'=').next().unwrap_or(bytes);
+                if bytes.len() > key.len() && self.env_keys.iter().any(|k| k == key) {
+                    let mut redacted = key.to_vec();
+                    redacted.extend_from_slice(b"=(secret)");
+                    CString::new(redacted).unwrap()
+                } else {
+                    var.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_read_and_redaction() {
+        let mut files = HashMap::new();
+        files.insert(5, "token".to_string());
+        let audit = SecretAudit::new(vec![b"API_KEY".to_vec()], files);
+        audit.opened(VPid(2), &VFile { inode: 4 });
+        audit.opened(VPid(3), &VFile { inode: 5 });
+        audit.opened(VPid(1), &VFile { inode: 5 });
+        assert_eq!(
+            audit.snapshot(),
+            vec![SecretAccess {
+                name: "token".to_string(),
+                vpid: VPid(3)
+            }]
+        );
+
+        let env = vec![
+            CString::new("API_KEY=hunter2").unwrap(),
+            CString::new("API_KEY_ID=7").unwrap(),
+        ];
+        assert_eq!(
+            audit.redact_env(&env),
+            vec![
+                CString::new("API_KEY=(secret)").unwrap(),
+                CString::new("API_KEY_ID=7").unwrap(),
+            ]
+        );
+    }
+}
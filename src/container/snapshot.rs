@@ -0,0 +1,166 @@
+use crate::{
+    errors::RuntimeError,
+    process::{Executable, Process},
+    sand::protocol::{
+        buffer::IPCBuffer, ExecSnapshotHeader, ExecSnapshotRegion, TracerSettings, VPtr,
+    },
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    mem::size_of,
+    sync::{Arc, Mutex},
+};
+
+/// Mappings that belong to the kernel, and exist in every process already
+const KERNEL_REGIONS: &[&str] = &["[vdso]", "[vvar]", "[vvar_vclock]", "[vsyscall]"];
+
+/// Saved memory images of a container's first process, taken right after it
+/// loaded its entry point
+///
+/// Snapshots are keyed on the exact initial arguments and tracer settings, and
+/// only shared between containers that run the same image with no extra
+/// mounts.
+#[derive(Debug, Default)]
+pub(crate) struct ExecSnapshots {
+    saved: Mutex<HashMap<Vec<u8>, SavedExec>>,
+}
+
+/// One snapshot, and the program its process had loaded
+#[derive(Debug, Clone)]
+pub(crate) struct SavedExec {
+    pub file: Arc<File>,
+    pub exe: Option<Executable>,
+}
+
+/// The place in an [ExecSnapshots] for one particular container's exec
+#[derive(Debug)]
+pub(crate) struct ExecSnapshotSlot {
+    pub snapshots: Arc<ExecSnapshots>,
+    pub key: Vec<u8>,
+}
+
+impl ExecSnapshotSlot {
+    /// The same initial args always load the same way, as long as the tracer
+    /// lays out memory the same way too
+    pub fn new(
+        snapshots: Arc<ExecSnapshots>,
+        settings: &TracerSettings,
+        args: &[u8],
+    ) -> Result<Self, RuntimeError> {
+        let mut buffer = IPCBuffer::new();
+        buffer.push_back(settings)?;
+        let mut key = buffer.as_slice().bytes.to_vec();
+        key.extend_from_slice(args);
+        Ok(ExecSnapshotSlot { snapshots, key })
+    }
+
+    /// The saved snapshot, in a sealed memfd, if there is one yet
+    pub fn get(&self) -> Option<SavedExec> {
+        self.snapshots.saved.lock().unwrap().get(&self.key).cloned()
+    }
+
+    /// Save the memory of a stopped process along with its entry registers
+    pub fn capture(
+        &self,
+        process: &Process,
+        header: ExecSnapshotHeader,
+    ) -> Result<(), RuntimeError> {
+        let saved = SavedExec {
+            file: Arc::new(write_snapshot(process, header)?),
+            exe: process.status.exe.clone(),
+        };
+        self.snapshots
+            .saved
+            .lock()
+            .unwrap()
+            .insert(self.key.clone(), saved);
+        Ok(())
+    }
+}
+
+fn write_snapshot(process: &Process, mut header: ExecSnapshotHeader) -> Result<File, RuntimeError> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    let page_align = |offset: usize| (offset + page_size - 1) & !(page_size - 1);
+
+    let maps = process.maps.regions()?;
+    let maps: Vec<_> = maps
+        .iter()
+        .filter(|region| match &region.name {
+            Some(name) => !KERNEL_REGIONS.contains(&name.as_str()),
+            None => true,
+        })
+        .collect();
+
+    header.random = find_at_random(process, header.sp)?;
+    header.region_count = maps.len();
+    let table_len = size_of::<ExecSnapshotHeader>() + maps.len() * size_of::<ExecSnapshotRegion>();
+    let mut data_offset = page_align(table_len);
+    let mut regions = Vec::with_capacity(maps.len());
+    for region in &maps {
+        regions.push(ExecSnapshotRegion {
+            start: region.start.0,
+            end: region.end.0,
+            prot: region.prot,
+            data_offset,
+        });
+        data_offset += region.end.0 - region.start.0;
+    }
+
+    let memfd = memfd::MemfdOptions::default()
+        .allow_sealing(true)
+        .create("bandsocks-exec-snapshot")?;
+    let mut file = memfd.as_file();
+    file.write_all(header.as_bytes())?;
+    for region in &regions {
+        file.write_all(region.as_bytes())?;
+    }
+    let mut buffer = Vec::new();
+    for (map, region) in maps.iter().zip(regions.iter()) {
+        buffer.resize(region.end - region.start, 0u8);
+        process.mem.read_bytes(map.start, &mut buffer)?;
+        file.seek(SeekFrom::Start(region.data_offset as u64))?;
+        file.write_all(&buffer)?;
+    }
+
+    // Every container maps the same pages privately, so nobody may change them
+    memfd.add_seals(
+        &[
+            memfd::FileSeal::SealWrite,
+            memfd::FileSeal::SealShrink,
+            memfd::FileSeal::SealGrow,
+            memfd::FileSeal::SealSeal,
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+    )?;
+    Ok(memfd.into_file())
+}
+
+/// Find the AT_RANDOM bytes from a new process's initial stack
+///
+/// Below the stack pointer are argc, the argv and envp arrays each ending in
+/// a null pointer, and then the aux vectors in pairs.
+fn find_at_random(process: &Process, sp: usize) -> Result<usize, RuntimeError> {
+    let word = |index: usize| -> Result<usize, RuntimeError> {
+        let mut bytes = [0u8; size_of::<usize>()];
+        process
+            .mem
+            .read_bytes(VPtr(sp + index * size_of::<usize>()), &mut bytes)?;
+        Ok(usize::from_ne_bytes(bytes))
+    };
+    let mut index = 1 + word(0)? + 1;
+    while word(index)? != 0 {
+        index += 1;
+    }
+    index += 1;
+    loop {
+        match word(index)? as libc::c_ulong {
+            libc::AT_NULL => return Ok(0),
+            libc::AT_RANDOM => return word(index + 1),
+            _ => index += 2,
+        }
+    }
+}
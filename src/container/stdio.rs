@@ -0,0 +1,191 @@
+use crate::filesystem::socket::SharedStream;
+use std::{
+    io,
+    io::Write,
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// What to connect to one of a container's stdio streams
+///
+/// Like [std::process::Stdio], for use with
+/// [ContainerBuilder::stdin()](crate::ContainerBuilder::stdin) and friends.
+/// Any [SharedStream] can also be used where a [Stdio] is expected.
+#[derive(Debug, Clone)]
+pub struct Stdio(StdioKind);
+
+#[derive(Debug, Clone)]
+enum StdioKind {
+    Piped,
+    Inherit,
+    Null,
+    Stream(SharedStream),
+}
+
+impl Stdio {
+    /// Connect the stream to the [Container](crate::Container), the default
+    ///
+    /// The other end is available as [Container::stdin](crate::Container),
+    /// [Container::stdout](crate::Container), or
+    /// [Container::stderr](crate::Container).
+    pub fn piped() -> Self {
+        Stdio(StdioKind::Piped)
+    }
+
+    /// Connect the stream to the same stream of the current process
+    ///
+    /// Stdin is read using a separate thread, which may keep running after
+    /// the container exits, since `std`'s stdin reads cannot be cancelled.
+    pub fn inherit() -> Self {
+        Stdio(StdioKind::Inherit)
+    }
+
+    /// Read nothing, or discard everything written
+    pub fn null() -> Self {
+        Stdio(StdioKind::Null)
+    }
+
+    /// The stream for the container to use, and the local end if it's piped
+    pub(crate) fn into_remote(self, fd: usize) -> io::Result<(SharedStream, Option<UnixStream>)> {
+        Ok(match (self.0, fd) {
+            (StdioKind::Piped, fd) => {
+                let (local, remote) = SharedStream::pair()?;
+                if fd != 0 {
+                    // Nothing is written back to an output, and a container
+                    // reading one shouldn't wait for it
+                    local.shutdown(Shutdown::Write)?;
+                }
+                (remote, Some(local))
+            }
+            (StdioKind::Stream(stream), _) => (stream, None),
+            (StdioKind::Null, 0) => (SharedStream::from_async_read(tokio::io::empty())?, None),
+            (StdioKind::Null, _) => (SharedStream::from_async_write(tokio::io::sink())?, None),
+            (StdioKind::Inherit, 0) => (inherit_stdin()?, None),
+            (StdioKind::Inherit, 1) => (SharedStream::from_async_write(tokio::io::stdout())?, None),
+            (StdioKind::Inherit, _) => (SharedStream::from_async_write(tokio::io::stderr())?, None),
+        })
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Stdio::piped()
+    }
+}
+
+impl From<SharedStream> for Stdio {
+    fn from(stream: SharedStream) -> Self {
+        Stdio(StdioKind::Stream(stream))
+    }
+}
+
+fn inherit_stdin() -> io::Result<SharedStream> {
+    let (mut local, remote) = SharedStream::pair()?;
+    thread::Builder::new()
+        .name("stdin".to_string())
+        .spawn(move || {
+            let _ = io::copy(&mut io::stdin(), &mut local);
+            let _ = local.flush();
+            let _ = local.shutdown(Shutdown::Write);
+        })?;
+    Ok(remote)
+}
+
+macro_rules! child_stream {
+    ($name:ident, $doc:expr) => {
+        #[doc = $doc]
+        #[derive(Debug)]
+        pub struct $name {
+            inner: tokio::net::UnixStream,
+        }
+
+        impl $name {
+            pub(crate) fn from_std(stream: UnixStream) -> io::Result<Self> {
+                Ok($name {
+                    inner: tokio::net::UnixStream::from_std(stream)?,
+                })
+            }
+        }
+    };
+}
+
+macro_rules! impl_async_read {
+    ($name:ident) => {
+        impl AsyncRead for $name {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.inner).poll_read(cx, buf)
+            }
+        }
+
+        /// For executors other than tokio
+        impl futures_util::io::AsyncRead for $name {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.inner).poll_read(cx, buf)
+            }
+        }
+    };
+}
+
+child_stream!(
+    ChildStdin,
+    "The container's stdin, when it's [piped](Stdio::piped())"
+);
+child_stream!(
+    ChildStdout,
+    "The container's stdout, when it's [piped](Stdio::piped())"
+);
+child_stream!(
+    ChildStderr,
+    "The container's stderr, when it's [piped](Stdio::piped())"
+);
+impl_async_read!(ChildStdout);
+impl_async_read!(ChildStderr);
+
+impl AsyncWrite for ChildStdin {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// For executors other than tokio
+impl futures_util::io::AsyncWrite for ChildStdin {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,6 +1,6 @@
 //! Error types you might see while setting up or running a container
 
-use crate::sand::protocol::Errno;
+use crate::sand::protocol::{Errno, HardeningReport};
 use thiserror::Error;
 
 /// Errors during container image preparation
@@ -49,6 +49,18 @@ pub enum ImageError {
     #[error("tar file format error")]
     TARFileError,
 
+    /// tar link target leads outside the image root
+    #[error("tar link target leads outside the image root: {0:?}")]
+    TARLinkEscapesRoot(std::path::PathBuf),
+
+    /// tar entry metadata is larger than allowed
+    #[error("tar entry metadata is larger than allowed")]
+    TARMetadataTooLarge,
+
+    /// decompressed layer is larger than allowed
+    #[error("decompressed layer is larger than the limit of {0} bytes")]
+    DecompressedSizeLimit(u64),
+
     /// virtual filesystem error while preparing image
     #[error("virtual filesystem error while preparing image: {0}")]
     ImageVFSError(#[from] VFSError),
@@ -57,6 +69,10 @@ pub enum ImageError {
     #[error("data just written to the cache is missing")]
     StorageMissingAfterInsert,
 
+    /// cached data failed verification, and the image must be pulled again
+    #[error("cached data for {0} failed verification, and the image must be pulled again")]
+    StorageCorrupted(crate::image::ContentDigest),
+
     /// i/o errors occurred, the content digest is not valid
     #[error("i/o errors occurred, the content digest is not valid")]
     ContentDigestIOError,
@@ -70,8 +86,22 @@ pub enum ImageError {
     NoDefaultCacheDir,
 
     /// only v2 image manifests are supported
-    #[error("only v2 image manifests are supported")]
-    UnsupportedManifestType,
+    #[error("only v2 image manifests are supported, found media type {0:?}")]
+    UnsupportedManifestType(String),
+
+    /// manifest lists are not supported
+    #[error(
+        "manifest lists are not supported, found media type {0:?}; try a platform-specific digest"
+    )]
+    UnsupportedManifestList(String),
+
+    /// a field in a JSON document from the registry is malformed
+    #[error("malformed {document}, field {field:?}: {reason}")]
+    InvalidJSONField {
+        document: &'static str,
+        field: String,
+        reason: String,
+    },
 
     /// unsupported type for runtime config
     #[error("unsupported type for runtime config, {0:?}")]
@@ -102,16 +132,61 @@ pub enum ImageError {
     #[error("insecure configuration; refusing to run a manifest downloaded over HTTP with no content digest")]
     InsecureManifest,
 
+    /// image is not in the lockfile
+    #[error("image is not in the lockfile: {0}")]
+    ImageNotLocked(crate::image::ImageName),
+
+    /// image name has no content digest, so it can't be locked
+    #[error("image name has no content digest, so it can't be locked: {0}")]
+    ImageNotPinned(crate::image::ImageName),
+
+    /// requested image digest disagrees with the lockfile
+    #[error("requested image {requested} disagrees with the lockfile, which has {locked}")]
+    LockMismatch {
+        requested: crate::image::ImageName,
+        locked: crate::image::ImageName,
+    },
+
+    /// registry server is rate limiting our requests
+    #[error("registry server is rate limiting our requests, retry after {retry_after:?}")]
+    RateLimited {
+        retry_after: Option<std::time::Duration>,
+    },
+
     /// registry server requested an unsupported type of authentication
     #[error("registry server requested an unsupported type of authentication: {0:?}")]
     UnsupportedAuthentication(String),
 
+    /// no image in the index is for this platform
+    #[error("no image in the index is for {0}")]
+    NoMatchingPlatform(String),
+
+    /// platform is not in `os/architecture[/variant]` form
+    #[error("invalid platform, expected os/architecture[/variant]: {0:?}")]
+    InvalidPlatform(String),
+
+    /// a file named by an image archive's manifest is missing from the archive
+    #[error("file missing from image archive: {0:?}")]
+    ArchiveFileMissing(String),
+
     /// calculated digest of downloaded content is not what we asked for
     #[error("calculated digest of downloaded content is not what we asked for, expected {expected}, found {found}")]
     ContentDigestMismatch {
         expected: crate::image::ContentDigest,
         found: crate::image::ContentDigest,
     },
+
+    /// container ID is not 64 hexadecimal digits
+    #[error("invalid container ID: {0:?}")]
+    InvalidContainerId(String),
+
+    /// no container ID starts with this prefix
+    #[error("no container ID starts with {0:?}")]
+    ContainerIdNotFound(String),
+
+    /// more than one container ID starts with this prefix
+    #[error("container ID prefix {0:?} is ambiguous, it matches {1} containers")]
+    AmbiguousContainerId(String, usize),
 }
 
 /// Errors that occur while a container is running
@@ -153,6 +228,10 @@ pub enum RuntimeError {
     #[error("invalid process ID")]
     InvalidPid,
 
+    /// signal number out of range
+    #[error("invalid signal number {0}")]
+    InvalidSignal(u32),
+
     /// incorrect ipc process state
     #[error("incorrect ipc process state")]
     WrongProcessState,
@@ -169,6 +248,14 @@ pub enum RuntimeError {
     #[error("memory access error")]
     MemAccess,
 
+    /// unexpected format in a process memory map
+    #[error("unexpected format in a process memory map")]
+    MapsFormat,
+
+    /// ptrace is restricted by the yama security module
+    #[error("ptrace restricted by yama ptrace_scope {0}, needs a scope of 1 or lower")]
+    PtraceRestricted(u32),
+
     /// error in memory-backed file
     #[error("error in memory-backed file: {0}")]
     MemfdError(#[from] memfd::Error),
@@ -199,6 +286,32 @@ pub enum RuntimeError {
     /// out of memory in sandbox runtime
     #[error("out of memory in sandbox runtime\n{stderr}")]
     SandOutOfMem { stderr: String },
+
+    /// sandbox runtime could not give up its privileges
+    #[error("sandbox runtime could not give up its privileges, {report:?}\n{stderr}")]
+    SandWeakHardening {
+        report: HardeningReport,
+        stderr: String,
+    },
+
+    /// systemd could not create a scope for the container
+    #[error("failed to create systemd scope: {0}")]
+    SystemdScopeError(String),
+
+    /// compose spec is not usable
+    #[error("invalid compose spec: {0}")]
+    InvalidComposeSpec(String),
+
+    /// a service never passed its health check
+    #[error("service {0:?} did not pass its health check")]
+    HealthCheckFailed(String),
+
+    /// a service exited while others depended on it
+    #[error("service {service:?} exited before it was healthy, {status:?}")]
+    ServiceExited {
+        service: String,
+        status: crate::container::ExitStatus,
+    },
 }
 
 /// Errors from the virtual filesystem layer, convertible to an errno code
@@ -207,6 +320,9 @@ pub enum VFSError {
     #[error("unexpected filesystem image storage error")]
     ImageStorageError,
 
+    #[error("filesystem image storage failed verification")]
+    ImageStorageCorrupted,
+
     #[error("generic I/O error")]
     IO,
 
@@ -237,6 +353,36 @@ pub enum VFSError {
     #[error("name too long")]
     NameTooLong,
 
+    #[error("file already exists")]
+    AlreadyExists,
+
+    #[error("directory not empty")]
+    DirectoryNotEmpty,
+
+    #[error("can't move a directory inside itself")]
+    InvalidRename,
+
+    #[error("path has no final component to operate on")]
+    ReservedName,
+
+    #[error("no such device")]
+    NoDevice,
+
+    #[error("read-only filesystem")]
+    ReadOnly,
+
+    #[error("mount point is busy")]
+    Busy,
+
+    #[error("can't rename across mounts")]
+    CrossDevice,
+
+    #[error("no space left in tmpfs mount")]
+    NoSpace,
+
+    #[error("host filesystem error, errno {0}")]
+    Host(libc::c_int),
+
     #[error("utf8 path conversion error")]
     Utf8Error(#[from] std::str::Utf8Error),
 }
@@ -246,6 +392,7 @@ impl VFSError {
     pub fn to_errno(&self) -> libc::c_int {
         match self {
             VFSError::ImageStorageError => libc::EIO,
+            VFSError::ImageStorageCorrupted => libc::EIO,
             VFSError::Utf8Error(_) => libc::EINVAL,
             VFSError::IO => libc::EIO,
             VFSError::DirectoryExpected => libc::ENOTDIR,
@@ -257,12 +404,22 @@ impl VFSError {
             VFSError::SymbolicLinkLimitExceeded => libc::ELOOP,
             VFSError::INodeRefCountError => libc::ENOMEM,
             VFSError::NameTooLong => libc::ENAMETOOLONG,
+            VFSError::AlreadyExists => libc::EEXIST,
+            VFSError::DirectoryNotEmpty => libc::ENOTEMPTY,
+            VFSError::InvalidRename => libc::EINVAL,
+            VFSError::NoDevice => libc::ENXIO,
+            VFSError::ReservedName => libc::EINVAL,
+            VFSError::ReadOnly => libc::EROFS,
+            VFSError::Busy => libc::EBUSY,
+            VFSError::CrossDevice => libc::EXDEV,
+            VFSError::NoSpace => libc::ENOSPC,
+            VFSError::Host(code) => *code,
         }
     }
 }
 
 impl From<VFSError> for Errno {
     fn from(err: VFSError) -> Self {
-        Errno(-err.to_errno())
+        Errno::new(err.to_errno())
     }
 }
@@ -0,0 +1,469 @@
+use crate::{
+    errors::VFSError,
+    filesystem::{mount::Mount, vfs::Filesystem},
+    sand::protocol::FileStat,
+};
+use std::{
+    ffi::{CString, OsStr, OsString},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    mem::MaybeUninit,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+/// A directory on the host, shared live with containers
+///
+/// Unlike a [Volume](crate::Volume), nothing is copied. Containers see the
+/// host directory as it is at each access, and unless the mount is read-only
+/// their changes go straight to the host. This is the way to hand input files
+/// to a sandboxed tool and collect its output.
+///
+/// The directory is opened once, when the mount is created, and every later
+/// access starts from that descriptor. Paths below it are walked one name at
+/// a time without following links on the host. The container's own
+/// filesystem handles `..` and symbolic links instead, as it would for a
+/// kernel bind mount, so neither can reach host files outside the directory.
+#[derive(Clone)]
+pub struct BindMount {
+    dir: Arc<HostDir>,
+}
+
+/// The host side of a [BindMount], with paths relative to its directory
+pub(crate) struct HostDir {
+    path: PathBuf,
+    fd: File,
+    read_only: bool,
+}
+
+impl BindMount {
+    /// Open a host directory to share, read-only or writable
+    pub fn new<P: AsRef<Path>>(host_path: P, read_only: bool) -> io::Result<BindMount> {
+        let path = host_path.as_ref().to_path_buf();
+        let fd = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(&path)?;
+        Ok(BindMount {
+            dir: Arc::new(HostDir {
+                path,
+                fd,
+                read_only,
+            }),
+        })
+    }
+
+    /// Get the host path this mount was opened with
+    pub fn host_path(&self) -> &Path {
+        &self.dir.path
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.dir.read_only
+    }
+}
+
+impl Mount for BindMount {
+    fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError> {
+        fs.writer().mount_host_directory(path, self.dir.clone())
+    }
+}
+
+impl fmt::Debug for BindMount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.dir, f)
+    }
+}
+
+impl fmt::Debug for HostDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BindMount({:?}{})",
+            self.path,
+            if self.read_only { ", ro" } else { "" }
+        )
+    }
+}
+
+impl HostDir {
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Metadata for a file or directory, without following a final link
+    pub fn stat(&self, path: &Path) -> Result<FileStat, VFSError> {
+        let mut stat = MaybeUninit::<libc::stat>::zeroed();
+        let result = match split(path)? {
+            None => unsafe { libc::fstat(self.fd.as_raw_fd(), stat.as_mut_ptr()) },
+            Some((parent, name)) => {
+                let dir = self.open_dir(parent)?;
+                let name = c_name(name)?;
+                unsafe {
+                    libc::fstatat(
+                        dir.as_raw_fd(),
+                        name.as_ptr(),
+                        stat.as_mut_ptr(),
+                        libc::AT_SYMLINK_NOFOLLOW,
+                    )
+                }
+            }
+        };
+        check(result)?;
+        let stat = unsafe { stat.assume_init() };
+        // Files belong to the container's root user, like files it creates
+        Ok(FileStat {
+            st_nlink: stat.st_nlink as u64,
+            st_mode: stat.st_mode,
+            st_rdev: stat.st_rdev,
+            st_size: stat.st_size,
+            st_atime: stat.st_atime as u64,
+            st_atime_nsec: stat.st_atime_nsec as u64,
+            st_mtime: stat.st_mtime as u64,
+            st_mtime_nsec: stat.st_mtime_nsec as u64,
+            st_ctime: stat.st_ctime as u64,
+            st_ctime_nsec: stat.st_ctime_nsec as u64,
+            ..Default::default()
+        })
+    }
+
+    /// The target of a symbolic link, or None for anything else
+    pub fn read_link(&self, path: &Path) -> Result<Option<CString>, VFSError> {
+        let (parent, name) = match split(path)? {
+            None => return Ok(None),
+            Some(parts) => parts,
+        };
+        let dir = self.open_dir(parent)?;
+        let name = c_name(name)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return match io::Error::last_os_error().raw_os_error() {
+                Some(libc::EINVAL) => Ok(None),
+                Some(code) => Err(host_error(code)),
+                None => Err(VFSError::IO),
+            };
+        }
+        buf.truncate(len as usize);
+        CString::new(buf).map(Some).map_err(|_| VFSError::IO)
+    }
+
+    /// Open a regular file for the guest, with the access mode in `flags`
+    ///
+    /// Other kinds of file are refused, since opening a FIFO or a device here
+    /// could block the runtime or reach host hardware.
+    pub fn open(&self, path: &Path, flags: i32) -> Result<File, VFSError> {
+        let access = flags & libc::O_ACCMODE;
+        let truncate = flags & libc::O_TRUNC != 0;
+        if self.read_only && (truncate || access != libc::O_RDONLY) {
+            return Err(VFSError::ReadOnly);
+        }
+        let (parent, name) = split(path)?.ok_or(VFSError::FileExpected)?;
+        let dir = self.open_dir(parent)?;
+        let file = openat(&dir, name, libc::O_PATH | libc::O_NOFOLLOW)?;
+        let metadata = file.metadata().map_err(io_error)?;
+        if !metadata.is_file() {
+            return Err(if metadata.is_dir() {
+                VFSError::FileExpected
+            } else {
+                VFSError::NoDevice
+            });
+        }
+        // Reopening the same inode through its descriptor can't be redirected
+        OpenOptions::new()
+            .read(access != libc::O_WRONLY)
+            .write(access != libc::O_RDONLY)
+            .append(flags & libc::O_APPEND != 0)
+            .truncate(truncate)
+            .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            .map_err(io_error)
+    }
+
+    /// Names of the entries in a directory, besides `.` and `..`
+    pub fn list(&self, path: &Path) -> Result<Vec<OsString>, VFSError> {
+        let dir = self.open_dir(path)?;
+        let mut names = Vec::new();
+        for entry in fs::read_dir(format!("/proc/self/fd/{}", dir.as_raw_fd())).map_err(io_error)? {
+            names.push(entry.map_err(io_error)?.file_name());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn create_file(&self, path: &Path, mode: u32) -> Result<(), VFSError> {
+        let (dir, name) = self.writable_parent(path)?;
+        let flags = libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY | libc::O_NOFOLLOW;
+        let fd = unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                flags | libc::O_CLOEXEC,
+                mode & 0o7777,
+            )
+        };
+        check(fd)?;
+        drop(unsafe { File::from_raw_fd(fd) });
+        Ok(())
+    }
+
+    pub fn create_dir(&self, path: &Path, mode: u32) -> Result<(), VFSError> {
+        let (dir, name) = self.writable_parent(path)?;
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode & 0o7777) })
+    }
+
+    pub fn unlink(&self, path: &Path, remove_dir: bool) -> Result<(), VFSError> {
+        let (dir, name) = self.writable_parent(path)?;
+        let flags = if remove_dir { libc::AT_REMOVEDIR } else { 0 };
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })
+    }
+
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<(), VFSError> {
+        let (from_dir, from_name) = self.writable_parent(from)?;
+        let (to_dir, to_name) = self.writable_parent(to)?;
+        check(unsafe {
+            libc::renameat(
+                from_dir.as_raw_fd(),
+                from_name.as_ptr(),
+                to_dir.as_raw_fd(),
+                to_name.as_ptr(),
+            )
+        })
+    }
+
+    fn writable_parent(&self, path: &Path) -> Result<(File, CString), VFSError> {
+        if self.read_only {
+            return Err(VFSError::ReadOnly);
+        }
+        // The mount's own directory can't be created or removed from inside
+        let (parent, name) = split(path)?.ok_or(VFSError::Busy)?;
+        Ok((self.open_dir(parent)?, c_name(name)?))
+    }
+
+    /// Open a directory below this one, one name at a time
+    fn open_dir(&self, path: &Path) -> Result<File, VFSError> {
+        let mut dir = openat(&self.fd, OsStr::new("."), libc::O_PATH | libc::O_DIRECTORY)?;
+        for part in path.iter() {
+            dir = openat(
+                &dir,
+                part,
+                libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+            )?;
+        }
+        Ok(dir)
+    }
+}
+
+/// Separate the last name from a relative path, or None for the directory
+/// itself
+///
+/// Paths below a mount come from the container's filesystem, which only ever
+/// builds them from plain names. Anything else is refused outright.
+fn split(path: &Path) -> Result<Option<(&Path, &OsStr)>, VFSError> {
+    if !path
+        .components()
+        .all(|part| matches!(part, Component::Normal(_)))
+    {
+        return Err(VFSError::NotFound);
+    }
+    Ok(match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Some((parent, name)),
+        _ => None,
+    })
+}
+
+fn c_name(name: &OsStr) -> Result<CString, VFSError> {
+    CString::new(name.as_bytes()).map_err(|_| VFSError::NotFound)
+}
+
+fn openat(dir: &File, name: &OsStr, flags: i32) -> Result<File, VFSError> {
+    let name = c_name(name)?;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC) };
+    check(fd)?;
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn check(result: libc::c_int) -> Result<(), VFSError> {
+    if result < 0 {
+        Err(io_error(io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+fn io_error(err: io::Error) -> VFSError {
+    match err.raw_os_error() {
+        Some(code) => host_error(code),
+        None => VFSError::IO,
+    }
+}
+
+/// Turn an error from the host into the error the container should see
+fn host_error(code: libc::c_int) -> VFSError {
+    match code {
+        libc::ENOENT => VFSError::NotFound,
+        libc::ENOTDIR => VFSError::DirectoryExpected,
+        libc::EISDIR => VFSError::FileExpected,
+        libc::EEXIST => VFSError::AlreadyExists,
+        libc::ENOTEMPTY => VFSError::DirectoryNotEmpty,
+        libc::ENAMETOOLONG => VFSError::NameTooLong,
+        libc::EROFS => VFSError::ReadOnly,
+        libc::EXDEV => VFSError::CrossDevice,
+        libc::ELOOP => VFSError::SymbolicLinkLimitExceeded,
+        code => VFSError::Host(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::storage::FileStorage,
+        sand::protocol::{abi, FollowLinks},
+    };
+    use std::{io::Read, os::unix::fs::symlink};
+    use tokio::runtime::Runtime;
+
+    fn lookup(fs: &Filesystem, path: &str) -> Result<crate::sand::protocol::VFile, VFSError> {
+        fs.lookup(&Filesystem::root(), Path::new(path), &FollowLinks::Follow)
+    }
+
+    #[test]
+    fn links_stay_inside() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), b"host only\n").unwrap();
+        let host = tempfile::tempdir().unwrap();
+        fs::create_dir(host.path().join("in")).unwrap();
+        fs::write(host.path().join("in/data.txt"), b"1,2,3\n").unwrap();
+        symlink(outside.path().join("secret"), host.path().join("abs")).unwrap();
+        symlink("../../etc/passwd", host.path().join("in/up")).unwrap();
+        symlink("data.txt", host.path().join("in/rel")).unwrap();
+
+        let mut fs = Filesystem::new();
+        fs.writer()
+            .write_file_bytes(
+                Path::new("/etc/passwd"),
+                FileStat {
+                    st_mode: abi::S_IFREG | 0o644,
+                    st_size: 5,
+                    ..Default::default()
+                },
+                bytes::Bytes::from_static(b"root\n"),
+            )
+            .unwrap();
+        let mount = BindMount::new(host.path(), true).unwrap();
+        mount.mount(&mut fs, Path::new("/mnt")).unwrap();
+
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        Runtime::new().unwrap().block_on(async {
+            let data = lookup(&fs, "/mnt/in/rel").unwrap();
+            assert_eq!(data, lookup(&fs, "/mnt/in/data.txt").unwrap());
+            let contents = fs.read_small_file(&storage, &data, 4096).await.unwrap();
+            assert_eq!(contents.unwrap(), b"1,2,3\n");
+
+            // Links resolve in the container, not on the host
+            let up = lookup(&fs, "/mnt/in/up").unwrap();
+            assert_eq!(up, lookup(&fs, "/etc/passwd").unwrap());
+            assert!(matches!(lookup(&fs, "/mnt/abs"), Err(VFSError::NotFound)));
+            assert_eq!(lookup(&fs, "/mnt/in/../..").unwrap(), Filesystem::root());
+            assert_eq!(
+                fs.canonicalize(Path::new("/mnt/in/rel")).unwrap(),
+                Path::new("/mnt/in/data.txt")
+            );
+
+            let file = fs
+                .open_storage(&storage, &data, libc::O_RDONLY)
+                .await
+                .unwrap();
+            let mut file = File::open(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+            let mut text = String::new();
+            file.read_to_string(&mut text).unwrap();
+            assert_eq!(text, "1,2,3\n");
+            assert!(matches!(
+                fs.copy_up(&storage, &data, false).await,
+                Err(VFSError::ReadOnly)
+            ));
+        });
+    }
+
+    #[test]
+    fn writes_reach_host() {
+        let host = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = Filesystem::new();
+        let mount = BindMount::new(host.path(), false).unwrap();
+        mount.mount(&mut fs, Path::new("/out")).unwrap();
+
+        let mut writer = fs.writer();
+        let stat = FileStat {
+            st_mode: abi::S_IFREG | 0o644,
+            ..Default::default()
+        };
+        let created = writer
+            .create_file(&storage, Path::new("/out/result.txt"), stat.clone())
+            .unwrap();
+        writer
+            .create_directory(Path::new("/out/logs"), stat.clone())
+            .unwrap();
+        writer
+            .rename(
+                Path::new("/out/result.txt"),
+                Path::new("/out/logs/done.txt"),
+            )
+            .unwrap();
+        assert!(matches!(
+            writer.unlink(Path::new("/out"), true),
+            Err(VFSError::Busy)
+        ));
+        assert!(matches!(
+            writer.rename(Path::new("/out/logs"), Path::new("/logs")),
+            Err(VFSError::CrossDevice)
+        ));
+        assert!(host.path().join("logs/done.txt").is_file());
+        assert_eq!(
+            lookup(&fs, "/out/logs/done.txt").unwrap(),
+            lookup(&fs, "/out/logs/../logs/done.txt").unwrap()
+        );
+        assert_ne!(created, lookup(&fs, "/out/logs").unwrap());
+
+        fs::write(host.path().join("late"), b"").unwrap();
+        assert!(lookup(&fs, "/out/late").is_ok());
+    }
+
+    #[test]
+    fn relative_misses_inside_mount_expire() {
+        let host = tempfile::tempdir().unwrap();
+        fs::create_dir(host.path().join("logs")).unwrap();
+        let mut fs = Filesystem::new();
+        let mount = BindMount::new(host.path(), true).unwrap();
+        mount.mount(&mut fs, Path::new("/out")).unwrap();
+
+        for dir in &["/out", "/out/logs"] {
+            let dir = lookup(&fs, dir).unwrap();
+            let late = Path::new("late.txt");
+            assert!(matches!(
+                fs.lookup(&dir, late, &FollowLinks::Follow),
+                Err(VFSError::NotFound)
+            ));
+            fs::write(host.path().join("late.txt"), b"").unwrap();
+            fs::write(host.path().join("logs/late.txt"), b"").unwrap();
+            assert!(fs.lookup(&dir, late, &FollowLinks::Follow).is_ok());
+            fs::remove_file(host.path().join("late.txt")).unwrap();
+            fs::remove_file(host.path().join("logs/late.txt")).unwrap();
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Character devices under /dev, which open the same way in every container
+//! no matter what device nodes its image includes
+
+use crate::{
+    errors::VFSError,
+    filesystem::vfs::Filesystem,
+    sand::protocol::{abi, FileStat},
+};
+use std::{
+    fs::{File, OpenOptions},
+    path::Path,
+};
+
+/// Paths and device numbers written into every container's /dev
+const DEVICES: &[(&str, u32, u32)] = &[
+    ("/dev/null", 1, 3),
+    ("/dev/zero", 1, 5),
+    ("/dev/random", 1, 8),
+    ("/dev/urandom", 1, 9),
+    ("/dev/tty", 5, 0),
+];
+
+/// What opening a character device node actually opens
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CharDevice {
+    /// The same device on the host, for devices with no state worth hiding
+    Host(&'static str),
+    /// The controlling terminal, which is whatever the container's stdio is
+    /// connected to
+    Tty,
+}
+
+impl CharDevice {
+    /// Look up a device by its major and minor numbers, like the kernel would
+    pub fn from_numbers(major: u32, minor: u32) -> Option<CharDevice> {
+        match (major, minor) {
+            (1, 3) => Some(CharDevice::Host("/dev/null")),
+            (1, 5) => Some(CharDevice::Host("/dev/zero")),
+            (1, 8) => Some(CharDevice::Host("/dev/random")),
+            (1, 9) => Some(CharDevice::Host("/dev/urandom")),
+            (5, 0) => Some(CharDevice::Tty),
+            _ => None,
+        }
+    }
+}
+
+/// Write the standard device nodes into a container's filesystem, replacing
+/// any the image had at the same paths
+pub fn populate(fs: &mut Filesystem) -> Result<(), VFSError> {
+    for (path, major, minor) in DEVICES {
+        let stat = FileStat {
+            st_mode: abi::S_IFCHR | 0o666,
+            st_rdev: unsafe { libc::makedev(*major, *minor) } as u64,
+            ..Default::default()
+        };
+        fs.writer()
+            .write_char_device(Path::new(path), stat, *major, *minor)?;
+    }
+    Ok(())
+}
+
+/// Open a host device with the access mode in `flags`
+pub fn open_host(path: &str, flags: i32) -> Result<File, VFSError> {
+    let access = flags & libc::O_ACCMODE;
+    OpenOptions::new()
+        .read(access != libc::O_WRONLY)
+        .write(access != libc::O_RDONLY)
+        .open(path)
+        .map_err(|_| VFSError::IO)
+}
+
+/// The stdio stream that stands in for the terminal, for the access mode in
+/// `flags`
+///
+/// Reading alone gets stdin. Anything that writes gets stderr, where prompts
+/// and other messages for whoever is at the terminal belong, and which isn't
+/// redirected along with stdout. A stream only goes one way, so reading from
+/// a terminal opened for both sees end-of-file.
+pub fn tty_stream(flags: i32) -> &'static Path {
+    match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => Path::new("/proc/1/fd/0"),
+        _ => Path::new("/proc/1/fd/2"),
+    }
+}
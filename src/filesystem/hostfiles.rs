@@ -0,0 +1,163 @@
+use crate::{
+    errors::VFSError,
+    filesystem::{mount::Mount, vfs::Filesystem},
+    sand::protocol::{abi, FileStat},
+};
+use bytes::Bytes;
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Host paths passed through by [HostFiles::network()]. Directories are read
+/// one level deep.
+const NETWORK_PATHS: &[&str] = &["etc/ssl/certs", "etc/nsswitch.conf"];
+
+/// Host files larger than this are left out
+const FILE_MAX: u64 = 4 * 1024 * 1024;
+
+/// A small, fixed set of host files copied into containers read-only
+///
+/// Minimal images often lack the CA certificates and name service config
+/// that network clients expect. This copies only those paths from the host,
+/// rather than granting access to all of `/etc`. Contents are read once, when
+/// the set is created, and later changes on the host have no effect.
+#[derive(Debug, Clone)]
+pub struct HostFiles {
+    files: Vec<(PathBuf, HostFile)>,
+}
+
+#[derive(Debug, Clone)]
+enum HostFile {
+    Bytes(Bytes),
+    /// A link to another file in the same directory, like the hashed names
+    /// OpenSSL looks certificates up by
+    Symlink(CString),
+}
+
+impl HostFiles {
+    /// The host's CA certificates from `/etc/ssl/certs`, and its
+    /// `/etc/nsswitch.conf`, skipping any that are missing
+    pub fn network() -> io::Result<HostFiles> {
+        HostFiles::read(Path::new("/"), NETWORK_PATHS)
+    }
+
+    fn read(root: &Path, paths: &[&str]) -> io::Result<HostFiles> {
+        let mut files = Vec::new();
+        for path in paths {
+            let host_path = root.join(path);
+            match fs::metadata(&host_path) {
+                Ok(meta) if meta.is_dir() => {
+                    for entry in fs::read_dir(&host_path)? {
+                        let entry = entry?;
+                        let guest_path = Path::new(path).join(entry.file_name());
+                        if let Some(file) = HostFile::read(&entry.path())? {
+                            files.push((guest_path, file));
+                        }
+                    }
+                }
+                Ok(_) => {
+                    if let Some(file) = HostFile::read(&host_path)? {
+                        files.push((PathBuf::from(path), file));
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(HostFiles { files })
+    }
+}
+
+impl HostFile {
+    fn read(path: &Path) -> io::Result<Option<HostFile>> {
+        if let Ok(target) = fs::read_link(path) {
+            if target.components().count() == 1 && target.is_relative() {
+                let target = CString::new(target.as_os_str().as_bytes())?;
+                return Ok(Some(HostFile::Symlink(target)));
+            }
+        }
+        match fs::metadata(path) {
+            Ok(meta) if meta.is_file() && meta.len() <= FILE_MAX => {
+                Ok(Some(HostFile::Bytes(Bytes::from(fs::read(path)?))))
+            }
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Mount for HostFiles {
+    fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError> {
+        let mut writer = fs.writer();
+        for (file_path, file) in &self.files {
+            let file_path = path.join(file_path);
+            match file {
+                HostFile::Bytes(contents) => {
+                    let stat = FileStat {
+                        st_mode: abi::S_IFREG | 0o444,
+                        st_size: contents.len() as i64,
+                        ..Default::default()
+                    };
+                    writer.write_file_bytes(&file_path, stat, contents.clone())?
+                }
+                HostFile::Symlink(target) => {
+                    let stat = FileStat {
+                        st_mode: abi::S_IFLNK | 0o777,
+                        st_size: target.as_bytes().len() as i64,
+                        ..Default::default()
+                    };
+                    writer.write_symlink(&file_path, stat, target.clone())?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filesystem::storage::FileStorage, sand::protocol::FollowLinks};
+    use std::os::unix::fs::symlink;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn certificates_and_links() {
+        let host = tempfile::tempdir().unwrap();
+        let certs = host.path().join("etc/ssl/certs");
+        fs::create_dir_all(&certs).unwrap();
+        fs::create_dir(certs.join("java")).unwrap();
+        fs::write(host.path().join("ca.pem"), b"CERT\n").unwrap();
+        symlink(host.path().join("ca.pem"), certs.join("ca.pem")).unwrap();
+        symlink("ca.pem", certs.join("1a2b3c4d.0")).unwrap();
+        let files = HostFiles::read(host.path(), NETWORK_PATHS).unwrap();
+
+        let mut fs = Filesystem::new();
+        files.mount(&mut fs, Path::new("/")).unwrap();
+        let root = Filesystem::root();
+        let cert = fs
+            .lookup(
+                &root,
+                Path::new("/etc/ssl/certs/1a2b3c4d.0"),
+                &FollowLinks::Follow,
+            )
+            .unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let contents = Runtime::new()
+            .unwrap()
+            .block_on(fs.read_small_file(&storage, &cert, 4096))
+            .unwrap();
+        assert_eq!(contents.unwrap(), b"CERT\n");
+        for missing in &["/etc/ssl/certs/java", "/etc/nsswitch.conf"] {
+            assert!(fs
+                .lookup(&root, Path::new(missing), &FollowLinks::Follow)
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{
+    errors::ImageError,
+    filesystem::{
+        storage::{FileStorage, StorageKey},
+        vfs::Filesystem,
+    },
+    image::ContentDigest,
+    sand::protocol::{abi, FileStat},
+};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs,
+    fs::File,
+    io,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+/// Copy a directory tree from the host into a filesystem
+///
+/// Regular file contents are copied into `storage`, and everything else is
+/// recreated in the virtual filesystem, keeping modes, owners, and hard links.
+/// Symbolic links are stored without following them, so absolute targets
+/// refer to the image rather than the host. Sockets are skipped.
+///
+/// Returns a digest which identifies the tree's metadata and file contents.
+pub fn import(
+    fs: &mut Filesystem,
+    storage: &FileStorage,
+    host_root: &Path,
+) -> Result<ContentDigest, ImageError> {
+    let mut import = Import {
+        fs,
+        storage,
+        host_root,
+        hard_links: HashMap::new(),
+        summary: Vec::new(),
+    };
+    import.directory(Path::new("/"))?;
+    Ok(ContentDigest::from_content(&import.summary))
+}
+
+struct Import<'a> {
+    fs: &'a mut Filesystem,
+    storage: &'a FileStorage,
+    host_root: &'a Path,
+    hard_links: HashMap<(u64, u64), PathBuf>,
+    summary: Vec<u8>,
+}
+
+impl<'a> Import<'a> {
+    fn directory(&mut self, path: &Path) -> Result<(), ImageError> {
+        let host_path = self.host_path(path);
+        let metadata = fs::symlink_metadata(&host_path)?;
+        self.summarize(path, &metadata, b"");
+        self.fs
+            .writer()
+            .write_directory_metadata(path, file_stat(&metadata))?;
+
+        let mut names = fs::read_dir(&host_path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        names.sort();
+        for name in names {
+            self.entry(&path.join(name))?;
+        }
+        Ok(())
+    }
+
+    fn entry(&mut self, path: &Path) -> Result<(), ImageError> {
+        let host_path = self.host_path(path);
+        let metadata = fs::symlink_metadata(&host_path)?;
+        let stat = file_stat(&metadata);
+
+        if !metadata.is_dir() && metadata.nlink() > 1 {
+            let inode = (metadata.dev(), metadata.ino());
+            if let Some(link_to) = self.hard_links.get(&inode) {
+                self.summarize(path, &metadata, link_to.as_os_str().as_bytes());
+                self.fs.writer().write_hardlink(path, link_to)?;
+                return Ok(());
+            }
+            self.hard_links.insert(inode, path.to_path_buf());
+        }
+
+        match stat.st_mode & abi::S_IFMT {
+            abi::S_IFDIR => return self.directory(path),
+            abi::S_IFREG => {
+                let data = self.file_data(&host_path)?;
+                let digest = match &data {
+                    Some(StorageKey::Blob(digest)) => digest.as_str().as_bytes(),
+                    _ => b"",
+                };
+                self.summarize(path, &metadata, digest);
+                self.fs.writer().write_storage_file(path, stat, data)?;
+            }
+            abi::S_IFLNK => {
+                let link_to = fs::read_link(&host_path)?;
+                let link_to = link_to.as_os_str().as_bytes();
+                self.summarize(path, &metadata, link_to);
+                self.fs
+                    .writer()
+                    .write_symlink(path, stat, CString::new(link_to)?)?;
+            }
+            abi::S_IFCHR => {
+                self.summarize(path, &metadata, b"");
+                let (major, minor) = device_numbers(&metadata);
+                self.fs
+                    .writer()
+                    .write_char_device(path, stat, major, minor)?;
+            }
+            abi::S_IFBLK => {
+                self.summarize(path, &metadata, b"");
+                let (major, minor) = device_numbers(&metadata);
+                self.fs
+                    .writer()
+                    .write_block_device(path, stat, major, minor)?;
+            }
+            abi::S_IFIFO => {
+                self.summarize(path, &metadata, b"");
+                self.fs.writer().write_fifo(path, stat)?;
+            }
+            _ => log::warn!("skipping unsupported file type at {:?}", host_path),
+        }
+        Ok(())
+    }
+
+    /// Copy one file's contents into storage, keyed on its own digest
+    fn file_data(&self, host_path: &Path) -> Result<Option<StorageKey>, ImageError> {
+        let mut file = File::open(host_path)?;
+        let mut writer = self.storage.begin_write()?;
+        if io::copy(&mut file, &mut writer)? == 0 {
+            writer.remove_temp()?;
+            return Ok(None);
+        }
+        let key = StorageKey::Blob(writer.finalize()?);
+        self.storage.commit_write(writer, &key)?;
+        Ok(Some(key))
+    }
+
+    fn host_path(&self, path: &Path) -> PathBuf {
+        self.host_root
+            .join(path.strip_prefix("/").expect("import paths are absolute"))
+    }
+
+    fn summarize(&mut self, path: &Path, metadata: &fs::Metadata, data: &[u8]) {
+        let _ = write!(
+            &mut self.summary,
+            "{:o} {} {} {} {} ",
+            metadata.mode(),
+            metadata.uid(),
+            metadata.gid(),
+            metadata.size(),
+            metadata.mtime(),
+        );
+        self.summary.extend_from_slice(path.as_os_str().as_bytes());
+        self.summary.push(0);
+        self.summary.extend_from_slice(data);
+        self.summary.push(b'\n');
+    }
+}
+
+fn file_stat(metadata: &fs::Metadata) -> FileStat {
+    FileStat {
+        st_mode: metadata.mode(),
+        st_uid: metadata.uid(),
+        st_gid: metadata.gid(),
+        st_mtime: metadata.mtime(),
+        st_size: metadata.size() as i64,
+        ..Default::default()
+    }
+}
+
+fn device_numbers(metadata: &fs::Metadata) -> (u32, u32) {
+    let rdev = metadata.rdev();
+    unsafe { (libc::major(rdev), libc::minor(rdev)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sand::protocol::FollowLinks;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn import_tree() {
+        let host = tempfile::tempdir().unwrap();
+        fs::create_dir_all(host.path().join("usr/bin")).unwrap();
+        fs::write(host.path().join("usr/bin/busybox"), b"binary").unwrap();
+        fs::write(host.path().join("empty"), b"").unwrap();
+        fs::hard_link(
+            host.path().join("usr/bin/busybox"),
+            host.path().join("usr/bin/sh"),
+        )
+        .unwrap();
+        symlink("/usr/bin/busybox", host.path().join("usr/bin/ls")).unwrap();
+
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = Filesystem::new();
+        let digest = import(&mut fs, &storage, host.path()).unwrap();
+
+        let root = Filesystem::root();
+        let lookup =
+            |path: &str, follow: FollowLinks| fs.lookup(&root, Path::new(path), &follow).unwrap();
+        let busybox = lookup("/usr/bin/busybox", FollowLinks::NoFollow);
+        assert_eq!(fs.stat(&busybox).unwrap().st_size, 6);
+        assert_eq!(lookup("/usr/bin/sh", FollowLinks::NoFollow), busybox);
+        assert_eq!(lookup("/usr/bin/ls", FollowLinks::Follow), busybox);
+        assert_eq!(
+            fs.readlink(&lookup("usr/bin/ls", FollowLinks::NoFollow))
+                .unwrap()
+                .to_bytes(),
+            b"/usr/bin/busybox"
+        );
+        assert_eq!(
+            fs.stat(&lookup("empty", FollowLinks::NoFollow))
+                .unwrap()
+                .st_size,
+            0
+        );
+
+        let mut again = Filesystem::new();
+        assert_eq!(import(&mut again, &storage, host.path()).unwrap(), digest);
+        fs::write(host.path().join("empty"), b"full").unwrap();
+        assert_ne!(import(&mut again, &storage, host.path()).unwrap(), digest);
+    }
+}
@@ -1,5 +1,14 @@
+pub mod bind;
+pub mod devices;
+pub mod hostfiles;
+pub mod import;
 pub mod mount;
+pub mod procfs;
+pub mod remap;
 pub mod socket;
 pub mod storage;
 pub mod tar;
+pub mod tmpfs;
 pub mod vfs;
+pub mod volume;
+pub mod workspace;
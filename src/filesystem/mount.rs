@@ -1,7 +1,13 @@
 use crate::{errors::VFSError, filesystem::vfs::Filesystem};
-use std::path::Path;
+use std::{path::Path, sync::Arc};
 
 /// A trait for the ability to mount into a container's filesystem
 pub trait Mount {
     fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError>;
 }
+
+impl<M: Mount + ?Sized> Mount for Arc<M> {
+    fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError> {
+        M::mount(self, fs, path)
+    }
+}
@@ -0,0 +1,211 @@
+//! Synthetic files under /proc, describing the virtual kernel to the container
+
+use crate::{
+    container::memory::MemoryUsage,
+    errors::VFSError,
+    filesystem::vfs::Filesystem,
+    process::{MapsRegion, Process},
+    sand::protocol::{abi, FileStat},
+};
+use bytes::Bytes;
+use std::{fmt::Display, fmt::Write, path::Path};
+
+/// System V IPC objects are never available inside the sandbox, so these
+/// tables only ever contain their header line. The formats match
+/// linux/ipc/shm.c, linux/ipc/sem.c, and linux/ipc/msg.c
+const SYSVIPC_SHM: &str = "       key      shmid perms                  size  cpid  lpid nattch   uid   gid  cuid  cgid      atime      dtime      ctime                   rss                  swap\n";
+const SYSVIPC_SEM: &str =
+    "       key      semid perms      nsems   uid   gid  cuid  cgid      otime      ctime\n";
+const SYSVIPC_MSG: &str = "       key      msqid perms      cbytes       qnum lspid lrpid   uid   gid  cuid  cgid      stime      rtime      ctime\n";
+
+/// Files whose contents depend on the process reading them, or on the
+/// container's state at the time, so they're generated on every open
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProcNode {
+    /// Link to the program the process last loaded with exec
+    SelfExe,
+    /// Memory mappings of the process
+    SelfMaps,
+    /// The host's CPUs, which the guest runs on directly
+    CpuInfo,
+    /// Memory totals, scaled to the container's memory limit if it has one
+    MemInfo,
+}
+
+/// Write all synthetic /proc files into a container's filesystem
+///
+/// Limits under /proc/sys are scaled to `memory_limit` if the container has
+/// one, the same way /proc/meminfo is.
+pub fn populate(fs: &mut Filesystem, memory_limit: Option<u64>) -> Result<(), VFSError> {
+    write_static(fs, "/proc/sysvipc/shm", SYSVIPC_SHM)?;
+    write_static(fs, "/proc/sysvipc/sem", SYSVIPC_SEM)?;
+    write_static(fs, "/proc/sysvipc/msg", SYSVIPC_MSG)?;
+    write_node(fs, "/proc/self/exe", ProcNode::SelfExe)?;
+    write_node(fs, "/proc/self/maps", ProcNode::SelfMaps)?;
+    write_node(fs, "/proc/cpuinfo", ProcNode::CpuInfo)?;
+    write_node(fs, "/proc/meminfo", ProcNode::MemInfo)?;
+    populate_sys(fs, memory_limit.unwrap_or_else(host_memory_total))
+}
+
+/// Contents of a generated file as `process` would read it right now
+///
+/// [ProcNode::SelfExe] has no contents of its own; opening it opens the
+/// program instead.
+pub fn generate(
+    node: ProcNode,
+    process: &Process,
+    memory: &MemoryUsage,
+) -> Result<Vec<u8>, VFSError> {
+    match node {
+        ProcNode::SelfExe => Err(VFSError::FileExpected),
+        ProcNode::SelfMaps => {
+            let regions = process.maps.regions().map_err(|_| VFSError::IO)?;
+            Ok(format_maps(&regions).into_bytes())
+        }
+        ProcNode::CpuInfo => std::fs::read("/proc/cpuinfo").map_err(|_| VFSError::IO),
+        ProcNode::MemInfo => Ok(format_meminfo(memory).into_bytes()),
+    }
+}
+
+/// Mappings in the layout of linux/fs/proc/task_mmu.c
+///
+/// Only pseudo-paths like `[stack]` are kept. Mapped files are backed by
+/// runtime storage on the host, and those names mean nothing in the
+/// container, so file mappings are listed the same as anonymous ones.
+fn format_maps(regions: &[MapsRegion]) -> String {
+    let mut text = String::new();
+    for region in regions {
+        let bit = |prot: i32, flag: char| {
+            if region.prot & prot as usize != 0 {
+                flag
+            } else {
+                '-'
+            }
+        };
+        let line = format!(
+            "{:08x}-{:08x} {}{}{}p 00000000 00:00 0",
+            region.start.0,
+            region.end.0,
+            bit(libc::PROT_READ, 'r'),
+            bit(libc::PROT_WRITE, 'w'),
+            bit(libc::PROT_EXEC, 'x'),
+        );
+        match region.name.as_deref().filter(|name| name.starts_with('[')) {
+            Some(name) => writeln!(text, "{:<72} {}", line, name).unwrap(),
+            None => writeln!(text, "{}", line).unwrap(),
+        }
+    }
+    text
+}
+
+/// The first lines of linux/fs/proc/meminfo.c, which is as far as most
+/// programs read
+///
+/// The container has no page cache or swap of its own. Everything the
+/// runtime counts against it is reported as used.
+fn format_meminfo(memory: &MemoryUsage) -> String {
+    let total = memory.limit.unwrap_or_else(host_memory_total);
+    let available = total.saturating_sub(memory.total());
+    let mut text = String::new();
+    for (name, bytes) in &[
+        ("MemTotal:", total),
+        ("MemFree:", available),
+        ("MemAvailable:", available),
+        ("Buffers:", 0),
+        ("Cached:", 0),
+        ("SwapCached:", 0),
+        ("SwapTotal:", 0),
+        ("SwapFree:", 0),
+    ] {
+        writeln!(text, "{:<16}{:>8} kB", name, bytes / 1024).unwrap();
+    }
+    text
+}
+
+fn host_memory_total() -> u64 {
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    match unsafe { libc::sysinfo(&mut info) } {
+        0 => info.totalram as u64 * info.mem_unit as u64,
+        _ => 0,
+    }
+}
+
+/// Tunables under /proc/sys that programs commonly read to size their own
+/// resource usage. Limits are worked out from the container's memory the way
+/// the kernel works them out from the host's; the rest mirror what the
+/// emulated kernel reports elsewhere, for example via uname().
+fn populate_sys(fs: &mut Filesystem, memory_total: u64) -> Result<(), VFSError> {
+    write_line(fs, "/proc/sys/fs/file-max", file_max(memory_total))?;
+    write_line(fs, "/proc/sys/fs/nr_open", NR_OPEN)?;
+    write_line(fs, "/proc/sys/kernel/pid_max", PID_MAX)?;
+    write_line(fs, "/proc/sys/kernel/ostype", "Linux")?;
+    write_line(fs, "/proc/sys/kernel/osrelease", "4.0.0-bandsocks")?;
+    write_line(fs, "/proc/sys/kernel/hostname", "host")?;
+    write_line(fs, "/proc/sys/kernel/random/poolsize", POOL_SIZE)?;
+    write_line(fs, "/proc/sys/kernel/random/entropy_avail", POOL_SIZE)?;
+    write_line(fs, "/proc/sys/kernel/random/boot_id", random_uuid())?;
+    write_line(fs, "/proc/sys/vm/overcommit_memory", 0)?;
+    Ok(())
+}
+
+/// Matches the size of the virtual process ID space in the sand runtime
+const PID_MAX: u32 = 1024 * 1024;
+
+/// Entropy pool size reported by kernels since 5.18; getrandom() is passed
+/// through to the host, so the pool is always reported as full.
+const POOL_SIZE: u32 = 256;
+
+/// The kernel's default limit on descriptors per process, from linux/fs.h
+const NR_OPEN: u64 = 1024 * 1024;
+
+/// Open files allowed for this much memory, following files_maxfiles_init()
+/// in linux/fs/file_table.c
+fn file_max(memory_total: u64) -> u64 {
+    const NR_FILE: u64 = 8192;
+    (memory_total / 1024 / 10).max(NR_FILE)
+}
+
+/// A random version 4 UUID, unique to each container
+fn random_uuid() -> String {
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex: Vec<String> = b.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
+
+fn write_line<T: Display>(fs: &mut Filesystem, path: &str, value: T) -> Result<(), VFSError> {
+    write_bytes(fs, path, Bytes::from(format!("{}\n", value)))
+}
+
+fn write_static(fs: &mut Filesystem, path: &str, contents: &'static str) -> Result<(), VFSError> {
+    write_bytes(fs, path, Bytes::from_static(contents.as_bytes()))
+}
+
+fn write_node(fs: &mut Filesystem, path: &str, node: ProcNode) -> Result<(), VFSError> {
+    let stat = FileStat {
+        st_mode: match node {
+            ProcNode::SelfExe => abi::S_IFLNK | 0o777,
+            _ => abi::S_IFREG | 0o444,
+        },
+        ..Default::default()
+    };
+    fs.writer().write_proc_node(Path::new(path), stat, node)
+}
+
+fn write_bytes(fs: &mut Filesystem, path: &str, contents: Bytes) -> Result<(), VFSError> {
+    let stat = FileStat {
+        st_mode: abi::S_IFREG | 0o444,
+        st_size: contents.len() as i64,
+        ..Default::default()
+    };
+    fs.writer()
+        .write_file_bytes(Path::new(path), stat, contents)
+}
@@ -0,0 +1,63 @@
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+/// An ordered list of rules which rewrite paths from the guest before
+/// they're looked up, see
+/// [ContainerBuilder::remap_path()](crate::ContainerBuilder::remap_path)
+#[derive(Debug, Clone, Default)]
+pub struct PathRemap {
+    rules: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PathRemap {
+    /// Add a rule, tried after all the existing ones
+    pub fn push(&mut self, from: &Path, to: &Path) {
+        self.rules.push((from.to_owned(), to.to_owned()));
+    }
+
+    /// Rewrite a path using the first rule whose prefix matches it, comparing
+    /// whole components
+    pub fn apply<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        for (from, to) in &self.rules {
+            if let Ok(rest) = path.strip_prefix(from) {
+                let remapped = if rest.as_os_str().is_empty() {
+                    to.clone()
+                } else {
+                    to.join(rest)
+                };
+                log::debug!("remapped {:?} -> {:?}", path, remapped);
+                return Cow::Owned(remapped);
+            }
+        }
+        Cow::Borrowed(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule() {
+        let mut remap = PathRemap::default();
+        remap.push(Path::new("/etc/ssl/certs"), Path::new("/host/certs"));
+        remap.push(Path::new("/etc/ssl"), Path::new("/host/ssl"));
+        remap.push(Path::new("/etc/ssl/private"), Path::new("/never"));
+
+        let apply = |path: &str| remap.apply(Path::new(path)).into_owned();
+        assert_eq!(
+            apply("/etc/ssl/certs/ca.pem"),
+            Path::new("/host/certs/ca.pem")
+        );
+        assert_eq!(
+            apply("/etc/ssl/private/key"),
+            Path::new("/host/ssl/private/key")
+        );
+        assert_eq!(apply("/etc/ssl"), Path::new("/host/ssl"));
+        assert_eq!(apply("/etc/ssl2"), Path::new("/etc/ssl2"));
+        assert_eq!(apply("etc/ssl"), Path::new("etc/ssl"));
+        assert_eq!(apply("/etc/hosts"), Path::new("/etc/hosts"));
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
     errors::VFSError,
     filesystem::{mount::Mount, vfs::Filesystem},
+    rt,
     sand::protocol::{abi, FileStat},
 };
 use std::{
@@ -12,6 +13,7 @@ use std::{
     path::Path,
     sync::Arc,
 };
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 /// A single UnixStream which has been shared with a container
 #[derive(Clone)]
@@ -31,6 +33,58 @@ impl SharedStream {
         }
     }
 
+    /// Share a stream whose input comes from any [AsyncRead]
+    ///
+    /// Data is copied from the reader into a socket that the container can
+    /// read from, by a background task. The container sees
+    /// end-of-file once the reader does. This is useful for feeding a
+    /// container's stdin from something that isn't a file, like a websocket.
+    pub fn from_async_read<R>(mut reader: R) -> io::Result<SharedStream>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let (local, remote) = SharedStream::pair()?;
+        rt::spawn(async move {
+            let mut local = tokio::net::UnixStream::from_std(local)?;
+            tokio::io::copy(&mut reader, &mut local).await?;
+            local.shutdown(std::net::Shutdown::Write)
+        });
+        Ok(remote)
+    }
+
+    /// Share a stream whose output goes to any [AsyncWrite]
+    ///
+    /// Everything the container writes is copied to the writer by a
+    /// background task, which flushes and shuts down the writer when the
+    /// container closes its end. Reads from the container's end see
+    /// end-of-file.
+    pub fn from_async_write<W>(mut writer: W) -> io::Result<SharedStream>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (local, remote) = SharedStream::pair()?;
+        local.shutdown(std::net::Shutdown::Write)?;
+        rt::spawn(async move {
+            let mut local = tokio::net::UnixStream::from_std(local)?;
+            tokio::io::copy(&mut local, &mut writer).await?;
+            writer.shutdown().await
+        });
+        Ok(remote)
+    }
+
+    /// Would a read from the container's side return right away, with data or
+    /// end-of-file?
+    pub(crate) fn has_pending_input(&self) -> bool {
+        let mut fds = [libc::pollfd {
+            fd: self.inner.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout = 0;
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) };
+        result > 0 && fds[0].revents != 0
+    }
+
     pub(crate) fn vfile_open(&self) -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
         Ok(self.inner.clone())
     }
@@ -10,7 +10,14 @@ pub enum StorageKey {
     Temp(u32, u64),
     Blob(ContentDigest),
     BlobPart(ContentDigest, Range<usize>),
+    SparsePart(ContentDigest, Range<usize>),
     Manifest(Registry, Repository, ImageVersion),
+    Lease(ContentDigest),
+    CacheLock,
+    Image(ContentDigest),
+    Partial(ContentDigest),
+    History,
+    Workspaces,
 }
 
 impl StorageKey {
@@ -53,6 +60,14 @@ impl StorageKey {
                 path.set_extension("part");
                 path
             }
+            StorageKey::SparsePart(content_digest, range) => {
+                let mut path = base_dir.to_path_buf();
+                path.push("parts");
+                path.push(path_encode(content_digest.as_str()));
+                path.push(format!("{:x}-{:x}", range.start, range.end));
+                path.set_extension("sparse");
+                path
+            }
             StorageKey::Manifest(registry, repository, version) => {
                 let mut path = base_dir.to_path_buf();
                 path.push("manifest");
@@ -62,6 +77,44 @@ impl StorageKey {
                 path.set_extension("json");
                 path
             }
+            StorageKey::Lease(content_digest) => {
+                let mut path = base_dir.to_path_buf();
+                path.push("leases");
+                path.push(path_encode(content_digest.as_str()));
+                path.set_extension("lease");
+                path
+            }
+            StorageKey::CacheLock => {
+                let mut path = base_dir.to_path_buf();
+                path.push("leases");
+                path.push("cache.lock");
+                path
+            }
+            StorageKey::Image(content_digest) => {
+                let mut path = base_dir.to_path_buf();
+                path.push("images");
+                path.push(path_encode(content_digest.as_str()));
+                path.set_extension("json");
+                path
+            }
+            StorageKey::Partial(content_digest) => {
+                let mut path = base_dir.to_path_buf();
+                path.push("partial");
+                path.push(path_encode(content_digest.as_str()));
+                path.set_extension("partial");
+                path
+            }
+            StorageKey::History => {
+                let mut path = base_dir.to_path_buf();
+                path.push("history");
+                path.push("runs.jsonl");
+                path
+            }
+            StorageKey::Workspaces => {
+                let mut path = base_dir.to_path_buf();
+                path.push("workspaces");
+                path
+            }
         }
     }
 }
@@ -263,6 +316,48 @@ mod test {
                 .unwrap(),
             "root/blobs/sha256-00112233445566778899aabbccddeeff-cm2.blob"
         );
+        assert_eq!(
+            StorageKey::Lease("sha256:00112233445566778899aabbccddeeff".parse().unwrap())
+                .to_path(Path::new("root"))
+                .to_str()
+                .unwrap(),
+            "root/leases/sha256-00112233445566778899aabbccddeeff-cm2.lease"
+        );
+        assert_eq!(
+            StorageKey::CacheLock
+                .to_path(Path::new("root"))
+                .to_str()
+                .unwrap(),
+            "root/leases/cache.lock"
+        );
+        assert_eq!(
+            StorageKey::Image("sha256:00112233445566778899aabbccddeeff".parse().unwrap())
+                .to_path(Path::new("root"))
+                .to_str()
+                .unwrap(),
+            "root/images/sha256-00112233445566778899aabbccddeeff-cm2.json"
+        );
+        assert_eq!(
+            StorageKey::Partial("sha256:00112233445566778899aabbccddeeff".parse().unwrap())
+                .to_path(Path::new("root"))
+                .to_str()
+                .unwrap(),
+            "root/partial/sha256-00112233445566778899aabbccddeeff-cm2.partial"
+        );
+        assert_eq!(
+            StorageKey::History
+                .to_path(Path::new("root"))
+                .to_str()
+                .unwrap(),
+            "root/history/runs.jsonl"
+        );
+        assert_eq!(
+            StorageKey::Workspaces
+                .to_path(Path::new("root"))
+                .to_str()
+                .unwrap(),
+            "root/workspaces"
+        );
         assert_eq!(
             StorageKey::BlobPart(
                 "bla-a1-a2-a3:00112233445566778899aabbccddeeff"
@@ -287,6 +382,16 @@ mod test {
             .unwrap(),
             "root/parts/bla-a1-a2-a3-00112233445566778899aabbccddeeff-6r14r14r14m2/0-0.part"
         );
+        assert_eq!(
+            StorageKey::SparsePart(
+                "sha256:00112233445566778899aabbccddeeff".parse().unwrap(),
+                0x400..0x1000
+            )
+            .to_path(Path::new("root"))
+            .to_str()
+            .unwrap(),
+            "root/parts/sha256-00112233445566778899aabbccddeeff-cm2/400-1000.sparse"
+        );
         assert_eq!(
             StorageKey::Manifest(
                 "taco-extreme.example.org".parse().unwrap(),
@@ -0,0 +1,89 @@
+use std::{
+    fs::File,
+    io,
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    sync::Arc,
+};
+
+/// Take a shared advisory lock, waiting for any exclusive holder
+///
+/// Locks belong to the open file, so they last as long as any duplicate of
+/// it, including copies sent into a running container.
+pub fn lock_shared(file: &File) -> io::Result<()> {
+    flock(file, libc::LOCK_SH)
+}
+
+/// Take an exclusive advisory lock, waiting for every other holder
+pub fn lock_exclusive(file: &File) -> io::Result<()> {
+    flock(file, libc::LOCK_EX)
+}
+
+/// Take an exclusive advisory lock if nobody else holds one at all
+///
+/// Returns false if the file is in use.
+pub fn try_lock_exclusive(file: &File) -> io::Result<bool> {
+    match flock(file, libc::LOCK_EX | libc::LOCK_NB) {
+        Ok(()) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Was this file unlinked since we opened it?
+///
+/// A file can be removed after it's opened but before the lock is granted;
+/// the lock holder must check this and open the path again.
+pub fn is_removed(file: &File) -> io::Result<bool> {
+    Ok(file.metadata()?.nlink() == 0)
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// A shared lock which keeps one image's storage from being collected
+///
+/// Every process using an image holds a shared lock on the image's lease file,
+/// so the number of lock holders is the image's reference count. The lease
+/// ends when the last clone is dropped.
+#[derive(Clone, Debug)]
+pub struct StorageLease {
+    _file: Arc<File>,
+}
+
+impl StorageLease {
+    pub fn new(file: File) -> Self {
+        StorageLease {
+            _file: Arc::new(file),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn shared_lock_blocks_exclusive() {
+        let mut temp = tempfile::NamedTempFile::new().unwrap();
+        temp.write_all(b"data").unwrap();
+        let reader = File::open(temp.path()).unwrap();
+        let collector = File::open(temp.path()).unwrap();
+        lock_shared(&reader).unwrap();
+        assert!(!try_lock_exclusive(&collector).unwrap());
+        drop(reader);
+        assert!(try_lock_exclusive(&collector).unwrap());
+        assert!(!is_removed(&collector).unwrap());
+        temp.close().unwrap();
+        assert!(is_removed(&collector).unwrap());
+    }
+}
@@ -1,21 +1,27 @@
 mod key;
+mod lock;
+mod sparse;
 mod writer;
 
 pub use key::StorageKey;
+pub use lock::StorageLease;
+pub use sparse::{SparseExtent, SparseMap};
 pub use writer::StorageWriter;
 
-use crate::errors::ImageError;
+use crate::{errors::ImageError, image::ContentDigest, rt};
 use memmap::{Mmap, MmapOptions};
 use std::{
+    collections::HashSet,
     env, fs,
     fs::{File, OpenOptions},
     io,
-    os::unix::fs::OpenOptionsExt,
+    ops::Range,
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::SystemTime,
 };
 use tempfile::TempDir;
-use tokio::task;
 
 pub fn default_cache_dir() -> Result<PathBuf, ImageError> {
     match env::var("BANDSOCKS_CACHE") {
@@ -46,38 +52,277 @@ fn create_parent_dirs(path: &Path) {
     }
 }
 
+/// One file found by [FileStorage::list_data()]
+#[derive(Clone, Debug)]
+pub struct StoredFile {
+    pub path: PathBuf,
+    /// Space used on disk, which is less than the length for sparse files
+    pub bytes: u64,
+    pub modified: SystemTime,
+}
+
+fn disk_usage(metadata: &fs::Metadata) -> u64 {
+    metadata.blocks() * 512
+}
+
 #[derive(Clone, Debug)]
 pub struct FileStorage {
     path: PathBuf,
     temp_dir: Option<Arc<TempDir>>,
+    _lease: Option<StorageLease>,
+    verified: Option<Arc<Mutex<HashSet<StorageKey>>>>,
 }
 
 impl FileStorage {
     pub fn new(path: PathBuf, temp_dir: Option<Arc<TempDir>>) -> Self {
-        FileStorage { path, temp_dir }
+        FileStorage {
+            path,
+            temp_dir,
+            _lease: None,
+            verified: None,
+        }
+    }
+
+    /// Return a copy of this storage which checks parts before opening them
+    ///
+    /// The first time each part is opened in this process, the blob it came
+    /// from is hashed and compared with its content digest, and the part is
+    /// compared with its range of the blob. A damaged part is removed and
+    /// rebuilt from the blob. A damaged blob is removed, so the next pull
+    /// downloads it again, and the open fails with
+    /// [ImageError::StorageCorrupted].
+    pub fn verifying(&self) -> FileStorage {
+        let mut storage = self.clone();
+        storage.verified = Some(Default::default());
+        storage
+    }
+
+    /// Where one object lives in local storage
+    pub fn path_of(&self, key: &StorageKey) -> PathBuf {
+        key.to_path(&self.path)
     }
 
     /// Open one object from local storage, as a File
+    ///
+    /// The file is returned with a shared lock held, which protects it from
+    /// [FileStorage::try_remove()] in any process for as long as the file or
+    /// any duplicate of it stays open.
     pub fn open(&self, key: &StorageKey) -> Result<Option<File>, ImageError> {
         let path = key.to_path(&self.path);
-        match File::open(path) {
+        loop {
+            let file = match File::open(&path) {
+                Err(e) => match e.kind() {
+                    io::ErrorKind::NotFound => return Ok(None),
+                    _ => return Err(e.into()),
+                },
+                Ok(f) => f,
+            };
+            lock::lock_shared(&file)?;
+            if !lock::is_removed(&file)? {
+                return Ok(Some(file));
+            }
+        }
+    }
+
+    /// Remove an object from local storage, unless it's in use
+    ///
+    /// This is the building block for garbage collecting the cache while other
+    /// processes might be using it. Returns true if the object was removed,
+    /// or false if it's missing or anyone holds a lock on it.
+    pub fn try_remove(&self, key: &StorageKey) -> Result<bool, ImageError> {
+        self.try_remove_path(&key.to_path(&self.path))
+    }
+
+    /// Remove a file found by [FileStorage::list_data()], unless it's in use
+    pub fn try_remove_path(&self, path: &Path) -> Result<bool, ImageError> {
+        let file = match File::open(path) {
             Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => Ok(None),
-                _ => Err(e.into()),
+                io::ErrorKind::NotFound => return Ok(false),
+                _ => return Err(e.into()),
             },
-            Ok(f) => Ok(Some(f)),
+            Ok(f) => f,
+        };
+        if lock::try_lock_exclusive(&file)? && !lock::is_removed(&file)? {
+            fs::remove_file(path)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Remove every part cut from one blob, except those in use
+    ///
+    /// Returns the number of bytes freed on disk.
+    pub fn try_remove_parts(&self, blob: &ContentDigest) -> Result<u64, ImageError> {
+        let dir = match StorageKey::BlobPart(blob.clone(), 0..0)
+            .to_path(&self.path)
+            .parent()
+        {
+            Some(dir) => dir.to_path_buf(),
+            None => return Ok(0),
+        };
+        let entries = match fs::read_dir(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            result => result?,
+        };
+        let mut freed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let bytes = disk_usage(&entry.metadata()?);
+            if self.try_remove_path(&entry.path())? {
+                freed += bytes;
+            }
+        }
+        // Only succeeds once the directory is empty
+        let _ = fs::remove_dir(&dir);
+        Ok(freed)
+    }
+
+    /// List every blob, part, partial download, and temporary file in local
+    /// storage
+    ///
+    /// This is the data a garbage collector can reclaim. Files which
+    /// disappear while listing are left out.
+    pub fn list_data(&self) -> Result<Vec<StoredFile>, ImageError> {
+        let mut files = Vec::new();
+        let mut dirs: Vec<PathBuf> = ["blobs", "parts", "partial", "tmp"]
+            .iter()
+            .map(|name| self.path.join(name))
+            .collect();
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                result => result?,
+            };
+            for entry in entries {
+                let entry = entry?;
+                let metadata = match entry.metadata() {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    result => result?,
+                };
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                } else if metadata.is_file() {
+                    files.push(StoredFile {
+                        path: entry.path(),
+                        bytes: disk_usage(&metadata),
+                        modified: metadata.modified()?,
+                    });
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// List the paths of every [StorageKey::Image] record
+    pub fn list_images(&self) -> Result<Vec<PathBuf>, ImageError> {
+        let entries = match fs::read_dir(self.path.join("images")) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            result => result?,
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            paths.push(entry?.path());
+        }
+        Ok(paths)
+    }
+
+    /// Run a garbage collection step for one image, if nobody is using it
+    ///
+    /// This holds an exclusive lock on the image's [StorageKey::Lease] while
+    /// `collect` runs, then removes the lease. Anyone calling
+    /// [FileStorage::leased()] meanwhile waits for this to finish and then
+    /// starts a fresh lease, so a pull never sees an image half removed.
+    /// Returns `None` without calling `collect` if the image is leased.
+    pub fn collect_unleased<T, F>(
+        &self,
+        image: &ContentDigest,
+        collect: F,
+    ) -> Result<Option<T>, ImageError>
+    where
+        F: FnOnce() -> Result<T, ImageError>,
+    {
+        let path = StorageKey::Lease(image.clone()).to_path(&self.path);
+        create_parent_dirs(&path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o640)
+            .open(&path)?;
+        if !lock::try_lock_exclusive(&file)? || lock::is_removed(&file)? {
+            return Ok(None);
+        }
+        let result = collect();
+        fs::remove_file(&path)?;
+        drop(file);
+        result.map(Some)
+    }
+
+    /// Run `f` while holding the lock shared by everything that reads or
+    /// writes [StorageKey::Image] records
+    ///
+    /// Pulls hold it shared while they record which blobs an image uses. The
+    /// garbage collector holds it exclusively from reading those records until
+    /// it's done removing what they don't mention, so a blob is never removed
+    /// after a pull has claimed it.
+    pub fn with_cache_lock<T, F>(&self, exclusive: bool, f: F) -> Result<T, ImageError>
+    where
+        F: FnOnce() -> Result<T, ImageError>,
+    {
+        let path = StorageKey::CacheLock.to_path(&self.path);
+        create_parent_dirs(&path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o640)
+            .open(&path)?;
+        if exclusive {
+            lock::lock_exclusive(&file)?;
+        } else {
+            lock::lock_shared(&file)?;
+        }
+        let result = f();
+        drop(file);
+        result
+    }
+
+    /// Return a copy of this storage which holds a lease on an image
+    ///
+    /// While any copy is alive, [FileStorage::try_remove()] refuses to remove
+    /// the image's [StorageKey::Lease] in this or any other process. A garbage
+    /// collector must remove the lease first, and leave the image's blobs
+    /// alone if that fails.
+    pub fn leased(&self, image: &ContentDigest) -> Result<FileStorage, ImageError> {
+        let path = StorageKey::Lease(image.clone()).to_path(&self.path);
+        create_parent_dirs(&path);
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .mode(0o640)
+                .open(&path)?;
+            lock::lock_shared(&file)?;
+            if !lock::is_removed(&file)? {
+                let mut storage = self.clone();
+                storage._lease = Some(StorageLease::new(file));
+                return Ok(storage);
+            }
         }
     }
 
     /// Open an object, creating requested BlobParts on demand
     pub async fn open_part(&self, key: &StorageKey) -> Result<Option<File>, ImageError> {
+        self.verify_async(key, None).await?;
         match self.open(key)? {
             Some(f) => Ok(Some(f)),
             None => match key.clone() {
                 StorageKey::BlobPart(digest, range) => {
                     let task_storage = self.clone();
                     let task_key = key.clone();
-                    task::spawn_blocking(move || {
+                    rt::spawn_blocking(move || {
                         match task_storage.mmap(&StorageKey::Blob(digest))? {
                             None => Ok(None),
                             Some(part_of) => {
@@ -96,6 +341,125 @@ impl FileStorage {
         }
     }
 
+    /// Open a sparse file, expanding it from packed data on demand
+    ///
+    /// The `data` key must be a BlobPart holding the packed extents. The
+    /// expanded file is cached under the matching SparsePart key.
+    pub async fn open_sparse(
+        &self,
+        data: &StorageKey,
+        map: &SparseMap,
+    ) -> Result<Option<File>, ImageError> {
+        let (digest, range) = match data {
+            StorageKey::BlobPart(digest, range) => (digest.clone(), range.clone()),
+            _ => return Ok(None),
+        };
+        let key = StorageKey::SparsePart(digest.clone(), range.clone());
+        self.verify_async(&key, Some(map)).await?;
+        if let Some(f) = self.open(&key)? {
+            return Ok(Some(f));
+        }
+        let task_storage = self.clone();
+        let map = map.clone();
+        rt::spawn_blocking(move || task_storage.expand_sparse(digest, range, &map, &key)).await?
+    }
+
+    async fn verify_async(
+        &self,
+        key: &StorageKey,
+        map: Option<&SparseMap>,
+    ) -> Result<(), ImageError> {
+        match &self.verified {
+            Some(verified) if !verified.lock().unwrap().contains(key) => {
+                let storage = self.clone();
+                let key = key.clone();
+                let map = map.cloned();
+                rt::spawn_blocking(move || storage.verify(&key, map.as_ref())).await?
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check one part and the blob it came from, if that hasn't happened yet
+    ///
+    /// Sparse parts need the map they were expanded with. Missing data is not
+    /// an error here, it will be created or reported when opening.
+    fn verify(&self, key: &StorageKey, map: Option<&SparseMap>) -> Result<(), ImageError> {
+        let verified = match &self.verified {
+            Some(verified) => verified,
+            None => return Ok(()),
+        };
+        let (digest, range) = match key {
+            StorageKey::BlobPart(digest, range) | StorageKey::SparsePart(digest, range) => {
+                (digest, range)
+            }
+            _ => return Ok(()),
+        };
+        let blob_key = StorageKey::Blob(digest.clone());
+        let blob = match self.mmap(&blob_key)? {
+            Some(blob) => blob,
+            None => return Ok(()),
+        };
+        if !verified.lock().unwrap().contains(&blob_key) {
+            // Only sha256 digests can be checked, same as when downloading
+            if digest.format_str() == "sha256" && &ContentDigest::from_content(&blob) != digest {
+                drop(blob);
+                log::warn!("cached blob {} is corrupted, removing it", digest);
+                self.try_remove(&blob_key)?;
+                return Err(ImageError::StorageCorrupted(digest.clone()));
+            }
+            verified.lock().unwrap().insert(blob_key);
+        }
+        let data = blob
+            .get(range.clone())
+            .ok_or_else(|| ImageError::StorageCorrupted(digest.clone()))?;
+        let part_ok = match self.mmap(key)? {
+            None => return Ok(()),
+            Some(part) => match (key, map) {
+                (StorageKey::SparsePart(..), Some(map)) => map.matches(data, &part),
+                _ => data == &part[..],
+            },
+        };
+        if !part_ok {
+            log::warn!("cached part {:?} is corrupted, removing it", key);
+            if !self.try_remove(key)? && self.exists(key) {
+                return Err(ImageError::StorageCorrupted(digest.clone()));
+            }
+            return Ok(());
+        }
+        verified.lock().unwrap().insert(key.clone());
+        Ok(())
+    }
+
+    fn expand_sparse(
+        &self,
+        digest: ContentDigest,
+        range: Range<usize>,
+        map: &SparseMap,
+        key: &StorageKey,
+    ) -> Result<Option<File>, ImageError> {
+        let blob = match self.mmap(&StorageKey::Blob(digest))? {
+            None => return Ok(None),
+            Some(blob) => blob,
+        };
+        let temp_path = StorageKey::temp().to_path(&self.path);
+        create_parent_dirs(&temp_path);
+        let temp_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o440)
+            .open(&temp_path)?;
+        if let Err(e) = map.expand(&blob[range], &temp_file) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        let dest_path = key.to_path(&self.path);
+        create_parent_dirs(&dest_path);
+        fs::rename(&temp_path, &dest_path)?;
+        log::debug!("storage expanded sparse file, {:?}", dest_path);
+        self.open(key)
+    }
+
     /// Check whether a stored file exists without actually opening it
     ///
     /// Returns true if and only if the storage exists as a regular file. Any
@@ -117,7 +481,7 @@ impl FileStorage {
         let storage = self.clone();
         let from_key = from_key.clone();
         let to_key = to_key.clone();
-        task::spawn_blocking(move || {
+        rt::spawn_blocking(move || {
             let source = storage.mmap(&from_key)?;
             if let Some(source) = source {
                 let mut slice = &source[..];
@@ -147,6 +511,7 @@ impl FileStorage {
         create_parent_dirs(&temp_path);
 
         let temp_file = OpenOptions::new()
+            .read(true)
             .write(true)
             .create_new(true)
             .mode(0o440)
@@ -155,6 +520,46 @@ impl FileStorage {
         Ok(StorageWriter::new(key, temp_file, temp_path))
     }
 
+    /// Create a temporary file with no name, which goes away when it's closed
+    ///
+    /// This holds data too large to keep in memory that never needs to be
+    /// found again by name. Since the file is unlinked right away, nothing is
+    /// left behind even if the process exits without cleaning up.
+    pub fn anonymous_file(&self) -> Result<File, ImageError> {
+        let path = StorageKey::temp().to_path(&self.path);
+        create_parent_dirs(&path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(file)
+    }
+
+    /// Begin writing a blob, continuing from any earlier interrupted attempt
+    ///
+    /// The data goes in the blob's [StorageKey::Partial] file, which is kept
+    /// when the writer is discarded so the next attempt can pick up where
+    /// this one left off. If another writer in any process is already using
+    /// the partial file, this starts an ordinary temporary file instead.
+    pub fn resume_write(&self, blob: &ContentDigest) -> Result<StorageWriter, ImageError> {
+        let key = StorageKey::Partial(blob.clone());
+        let path = key.to_path(&self.path);
+        create_parent_dirs(&path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(0o640)
+            .open(&path)?;
+        if !lock::try_lock_exclusive(&file)? || lock::is_removed(&file)? {
+            return self.begin_write();
+        }
+        StorageWriter::resume(key, file, path)
+    }
+
     /// Promote a temporary file into a StorageKey
     pub fn commit_write(
         &self,
@@ -169,3 +574,89 @@ impl FileStorage {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn store(storage: &FileStorage, key: &StorageKey, data: &[u8]) {
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(data).unwrap();
+        storage.commit_write(writer, key).unwrap();
+    }
+
+    #[test]
+    fn verify_parts() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None).verifying();
+        let digest = ContentDigest::from_content(b"layer data");
+        let good_part = StorageKey::BlobPart(digest.clone(), 0..5);
+        let bad_part = StorageKey::BlobPart(digest.clone(), 6..10);
+        store(&storage, &StorageKey::Blob(digest), b"layer data");
+        store(&storage, &good_part, b"layer");
+        store(&storage, &bad_part, b"dada");
+
+        storage.verify(&good_part, None).unwrap();
+        assert!(storage.exists(&good_part));
+        storage.verify(&bad_part, None).unwrap();
+        assert!(!storage.exists(&bad_part));
+    }
+
+    #[test]
+    fn verify_corrupted_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None).verifying();
+        let digest = ContentDigest::from_content(b"layer data");
+        let blob = StorageKey::Blob(digest.clone());
+        let part = StorageKey::BlobPart(digest, 0..5);
+        store(&storage, &blob, b"layer dada");
+        store(&storage, &part, b"layer");
+
+        assert!(matches!(
+            storage.verify(&part, None),
+            Err(ImageError::StorageCorrupted(_))
+        ));
+        assert!(!storage.exists(&blob));
+    }
+
+    #[test]
+    fn resume_partial_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None);
+        let digest = ContentDigest::from_content(b"layer data");
+
+        let mut writer = storage.resume_write(&digest).unwrap();
+        assert_eq!(writer.position(), 0);
+        writer.write_all(b"layer").unwrap();
+        // A second download of the same blob can't share the partial file
+        let mut other = storage.resume_write(&digest).unwrap();
+        assert_eq!(other.position(), 0);
+        other.discard().unwrap();
+        writer.discard().unwrap();
+        drop(writer);
+
+        let mut writer = storage.resume_write(&digest).unwrap();
+        assert_eq!(writer.position(), 5);
+        writer.write_all(b" data").unwrap();
+        assert_eq!(writer.current_digest(), Some(digest.clone()));
+        storage
+            .commit_write(writer, &StorageKey::Blob(digest.clone()))
+            .unwrap();
+        assert!(!storage.exists(&StorageKey::Partial(digest.clone())));
+        assert_eq!(
+            &storage
+                .mmap(&StorageKey::Blob(digest.clone()))
+                .unwrap()
+                .unwrap()[..],
+            b"layer data"
+        );
+
+        let mut writer = storage.resume_write(&digest).unwrap();
+        writer.write_all(b"stale").unwrap();
+        writer.truncate().unwrap();
+        writer.write_all(b"layer data").unwrap();
+        assert_eq!(&writer.mmap().unwrap()[..], b"layer data");
+        assert_eq!(writer.finalize().unwrap(), digest);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+/// One run of real data within a sparse file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SparseExtent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Layout of a sparse file whose data extents are stored back to back
+///
+/// Everything outside the extents reads as zero. Expanded files keep those
+/// ranges as filesystem holes, so they cost no more cache space than the data.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SparseMap {
+    pub extents: Vec<SparseExtent>,
+    pub size: u64,
+}
+
+impl SparseMap {
+    /// Total length of the packed data for all extents
+    pub fn data_len(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.len).sum()
+    }
+
+    /// Write packed extent data out to a file at its sparse offsets
+    pub fn expand(&self, data: &[u8], file: &File) -> io::Result<()> {
+        if data.len() as u64 != self.data_len() {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let mut data_offset = 0;
+        for extent in &self.extents {
+            let data_end = data_offset + extent.len as usize;
+            file.write_all_at(&data[data_offset..data_end], extent.offset)?;
+            data_offset = data_end;
+        }
+        file.set_len(self.size)
+    }
+
+    /// Check that an expanded file has exactly the contents of the packed data
+    pub fn matches(&self, data: &[u8], expanded: &[u8]) -> bool {
+        if data.len() as u64 != self.data_len() || expanded.len() as u64 != self.size {
+            return false;
+        }
+        let mut data_offset = 0;
+        let mut expanded_offset = 0;
+        for extent in &self.extents {
+            let start = extent.offset as usize;
+            let end = start + extent.len as usize;
+            let data_end = data_offset + extent.len as usize;
+            if start < expanded_offset
+                || end > expanded.len()
+                || expanded[expanded_offset..start].iter().any(|b| *b != 0)
+                || expanded[start..end] != data[data_offset..data_end]
+            {
+                return false;
+            }
+            data_offset = data_end;
+            expanded_offset = end;
+        }
+        expanded[expanded_offset..].iter().all(|b| *b == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn expand_with_holes() {
+        let size = 3 * 1024 * 1024 * 1024;
+        let map = SparseMap {
+            extents: vec![
+                SparseExtent { offset: 0, len: 4 },
+                SparseExtent {
+                    offset: size - 1024 * 1024,
+                    len: 3,
+                },
+            ],
+            size,
+        };
+        let file = tempfile::tempfile().unwrap();
+        map.expand(b"headend", &file).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), size);
+
+        let mut buf = [0u8; 4];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"head");
+        file.read_exact_at(&mut buf[..3], size - 1024 * 1024)
+            .unwrap();
+        assert_eq!(&buf[..3], b"end");
+        file.read_exact_at(&mut buf, 1024 * 1024 * 1024).unwrap();
+        assert_eq!(buf, [0; 4]);
+
+        // Filesystems without hole support report the whole file as data
+        let fd = file.as_raw_fd();
+        let hole = unsafe { libc::lseek(fd, 0, libc::SEEK_HOLE) };
+        assert!(hole > 0 && hole as u64 <= size);
+    }
+
+    #[test]
+    fn matches_expanded() {
+        let map = SparseMap {
+            extents: vec![
+                SparseExtent { offset: 2, len: 3 },
+                SparseExtent { offset: 8, len: 2 },
+            ],
+            size: 12,
+        };
+        assert!(map.matches(b"abcde", b"\0\0abc\0\0\0de\0\0"));
+        assert!(!map.matches(b"abcde", b"\0\0abc\0\0\0dx\0\0"));
+        assert!(!map.matches(b"abcde", b"\0\0abc\0x\0de\0\0"));
+        assert!(!map.matches(b"abcde", b"\0\0abc\0\0\0de\0"));
+        assert!(!map.matches(b"abcd", b"\0\0abc\0\0\0de\0\0"));
+    }
+
+    #[test]
+    fn expand_wrong_length() {
+        let map = SparseMap {
+            extents: vec![SparseExtent { offset: 10, len: 4 }],
+            size: 20,
+        };
+        let file = tempfile::tempfile().unwrap();
+        assert!(map.expand(b"abc", &file).is_err());
+    }
+}
@@ -1,11 +1,13 @@
 use crate::{errors::ImageError, filesystem::storage::StorageKey, image::ContentDigest};
+use memmap::{Mmap, MmapOptions};
 use pin_project::pin_project;
 use sha2::{Digest, Sha256};
 use std::{
     fs,
-    fs::File,
+    fs::{File, Permissions},
     io,
-    io::Write,
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
 };
 
@@ -17,6 +19,8 @@ pub struct StorageWriter {
     hasher: Option<Sha256>,
     temp_path: Option<PathBuf>,
     content_digest: Option<Result<ContentDigest, ()>>,
+    position: u64,
+    resumable: bool,
     pub key: StorageKey,
 }
 
@@ -28,9 +32,80 @@ impl StorageWriter {
             temp_path: Some(temp_path),
             hasher: Some(Sha256::new()),
             content_digest: None,
+            position: 0,
+            resumable: false,
         }
     }
 
+    /// Continue writing to a file left by an earlier writer
+    ///
+    /// The existing contents are hashed, and new writes are appended. The
+    /// caller must hold an exclusive lock on the file. If this writer is
+    /// discarded the file stays, so a later writer can resume it again.
+    pub fn resume(key: StorageKey, file: File, path: PathBuf) -> Result<StorageWriter, ImageError> {
+        let mut writer = StorageWriter::new(key, file, path);
+        writer.resumable = true;
+        let mut file = writer.temp_file.as_ref().expect("storage writer open");
+        let hasher = writer
+            .hasher
+            .as_mut()
+            .expect("storage writer not finalized");
+        let mut buffer = vec![0; 0x10000];
+        loop {
+            match file.read(&mut buffer)? {
+                0 => break,
+                len => {
+                    hasher.update(&buffer[..len]);
+                    writer.position += len as u64;
+                }
+            }
+        }
+        Ok(writer)
+    }
+
+    /// Number of bytes in the file so far, including any resumed data
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Digest of the data written so far, without finalizing
+    pub fn current_digest(&self) -> Option<ContentDigest> {
+        self.hasher.as_ref().map(|hasher| {
+            ContentDigest::from_parts("sha256", &hasher.clone().finalize())
+                .expect("always parseable")
+        })
+    }
+
+    /// Throw away everything written so far and start again from empty
+    pub fn truncate(&mut self) -> Result<(), ImageError> {
+        let mut file = self.temp_file.as_ref().expect("storage writer open");
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        self.hasher = Some(Sha256::new());
+        self.content_digest = None;
+        self.position = 0;
+        Ok(())
+    }
+
+    /// Give up on this write
+    ///
+    /// Temporary files are deleted, but a resumable file is kept for later.
+    pub fn discard(&mut self) -> Result<(), ImageError> {
+        if self.resumable {
+            self.temp_path.take();
+            Ok(())
+        } else {
+            self.remove_temp()
+        }
+    }
+
+    /// Memory map everything written so far
+    pub fn mmap(&mut self) -> Result<Mmap, ImageError> {
+        self.flush()?;
+        let file = self.temp_file.as_ref().expect("storage writer open");
+        Ok(unsafe { MmapOptions::new().map(file) }?)
+    }
+
     /// Delete the temporary file backing this writer
     pub fn remove_temp(&mut self) -> Result<(), ImageError> {
         if let Some(path) = self.temp_path.take() {
@@ -45,6 +120,9 @@ impl StorageWriter {
             .temp_path
             .take()
             .expect("storage writer temp can only be taken once");
+        // Committed files are read-only, including resumed ones which had to
+        // stay writable until now
+        fs::set_permissions(&temp_path, Permissions::from_mode(0o440))?;
         fs::rename(&temp_path, &dest_path)?;
         Ok(())
     }
@@ -83,6 +161,7 @@ impl Write for StorageWriter {
                 if let Some(hasher) = &mut self.hasher {
                     hasher.update(&buf[..actual_size]);
                 }
+                self.position += actual_size as u64;
                 Ok(actual_size)
             }
         }
@@ -1,18 +1,34 @@
 use crate::{
     errors::ImageError,
     filesystem::{
-        storage::{FileStorage, StorageKey},
+        storage::{FileStorage, SparseExtent, SparseMap, StorageKey},
         vfs::Filesystem,
     },
     sand::protocol::{abi, FileStat},
 };
 use std::{
     convert::TryInto,
-    ffi::CString,
+    ffi::{CString, OsStr},
     io::{Cursor, Read},
-    path::Path,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path},
 };
-use tar::{Archive, Entry, EntryType};
+use tar::{Archive, Entry, EntryType, GnuExtSparseHeader, GnuSparseHeader, Header};
+
+const BLOCK_LEN: usize = 512;
+
+/// Longest path or link name accepted from an archive entry
+const MAX_PATH_LEN: usize = 4096;
+
+/// Most extents a single sparse entry may describe
+const MAX_SPARSE_EXTENTS: usize = 65536;
+
+/// Layer entries named with this prefix delete a file from lower layers
+/// instead of adding one
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// Layer entry that empties its directory of everything from lower layers
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
 
 pub fn extract(
     fs: &mut Filesystem,
@@ -24,15 +40,27 @@ pub fn extract(
         Some(map) => map,
         None => return Err(ImageError::TARFileError),
     };
+    apply_whiteouts(fs, &archive_map[..])?;
     while let Some(entry) = Archive::new(Cursor::new(&archive_map[offset..]))
         .entries()?
         .next()
     {
         let entry = entry?;
-        let entry_size = entry.size() as usize;
-        let file_begin = offset + (entry.raw_file_position() as usize);
+        // The archive holds entry_size bytes of data, which is less than the
+        // file's size when it's sparse
+        let entry_size = entry.header().entry_size()? as usize;
+        let mut file_begin = offset + (entry.raw_file_position() as usize);
+        let sparse_map = if entry.header().entry_type().is_gnu_sparse() {
+            let (map, ext_blocks) = gnu_sparse_map(entry.header(), &archive_map[file_begin..])?;
+            file_begin += ext_blocks * BLOCK_LEN;
+            Some(map)
+        } else {
+            None
+        };
         let file_range = file_begin..(file_begin + entry_size);
-        let file_key = if entry_size == 0 {
+        // A sparse file that's all hole still needs a key for its map, even
+        // though the range it names is empty
+        let file_key = if entry_size == 0 && sparse_map.is_none() {
             None
         } else {
             Some(
@@ -43,13 +71,49 @@ pub fn extract(
             )
         };
         offset = pad_to_block_multiple(file_begin + entry_size);
-        extract_file_metadata(fs, entry, file_key)?;
+        extract_file_metadata(fs, entry, file_key, sparse_map)?;
     }
     Ok(())
 }
 
+/// Delete files from lower layers, as this layer's whiteouts say to
+///
+/// Whiteouts never affect files from their own layer, even ones earlier in
+/// the same archive, so they are all applied before anything is extracted.
+fn apply_whiteouts(fs: &mut Filesystem, archive: &[u8]) -> Result<(), ImageError> {
+    let mut fsw = fs.writer();
+    for entry in Archive::new(Cursor::new(archive)).entries()? {
+        let entry = entry?;
+        let path = entry.path()?;
+        if path.as_os_str().len() > MAX_PATH_LEN {
+            return Err(ImageError::TARMetadataTooLarge);
+        }
+        let name = match path.file_name() {
+            Some(name) => name.as_bytes(),
+            None => continue,
+        };
+        if name == OPAQUE_WHITEOUT {
+            fsw.write_opaque_directory(path.parent().unwrap_or_else(|| Path::new("")))?;
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            match hidden {
+                b"" | b"." | b".." => return Err(ImageError::TARFileError),
+                // Other aufs metadata, like its hard link directory
+                hidden if hidden.starts_with(WHITEOUT_PREFIX) => {}
+                hidden => fsw.write_whiteout(&path.with_file_name(OsStr::from_bytes(hidden)))?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_whiteout(path: &Path) -> bool {
+    match path.file_name() {
+        Some(name) => name.as_bytes().starts_with(WHITEOUT_PREFIX),
+        None => false,
+    }
+}
+
 fn pad_to_block_multiple(size: usize) -> usize {
-    const BLOCK_LEN: usize = 512;
     let rem = size % BLOCK_LEN;
     if rem == 0 {
         size
@@ -58,15 +122,71 @@ fn pad_to_block_multiple(size: usize) -> usize {
     }
 }
 
+/// Read the extent list for a GNU sparse entry
+///
+/// Up to four extents live in the header itself. More are stored in extension
+/// blocks between the header and the data, which are found at the start of
+/// `following`. Returns the map and the number of extension blocks.
+fn gnu_sparse_map(header: &Header, following: &[u8]) -> Result<(SparseMap, usize), ImageError> {
+    let gnu = header.as_gnu().ok_or(ImageError::TARFileError)?;
+    let mut extents = Vec::new();
+    push_sparse_extents(&mut extents, &gnu.sparse)?;
+    let mut ext_blocks = 0;
+    let mut is_extended = gnu.is_extended();
+    while is_extended {
+        if extents.len() > MAX_SPARSE_EXTENTS {
+            return Err(ImageError::TARMetadataTooLarge);
+        }
+        let block = following
+            .get(ext_blocks * BLOCK_LEN..(ext_blocks + 1) * BLOCK_LEN)
+            .ok_or(ImageError::TARFileError)?;
+        let mut ext = GnuExtSparseHeader::new();
+        ext.as_mut_bytes().copy_from_slice(block);
+        push_sparse_extents(&mut extents, ext.sparse())?;
+        is_extended = ext.is_extended();
+        ext_blocks += 1;
+    }
+    let map = SparseMap {
+        extents,
+        size: gnu.real_size()?,
+    };
+    if map.data_len() != header.entry_size()? {
+        return Err(ImageError::TARFileError);
+    }
+    Ok((map, ext_blocks))
+}
+
+fn push_sparse_extents(
+    extents: &mut Vec<SparseExtent>,
+    headers: &[GnuSparseHeader],
+) -> Result<(), ImageError> {
+    for header in headers.iter().filter(|header| !header.is_empty()) {
+        extents.push(SparseExtent {
+            offset: header.offset()?,
+            len: header.length()?,
+        });
+    }
+    Ok(())
+}
+
 fn extract_file_metadata<'a, R: Read>(
     fs: &mut Filesystem,
     entry: Entry<'a, R>,
     data: Option<StorageKey>,
+    sparse_map: Option<SparseMap>,
 ) -> Result<(), ImageError> {
     let mut fsw = fs.writer();
     let kind = entry.header().entry_type();
     let path = entry.path()?;
     let link_name = entry.link_name_bytes();
+    if path.as_os_str().len() > MAX_PATH_LEN
+        || link_name.as_ref().map(|name| name.len()).unwrap_or(0) > MAX_PATH_LEN
+    {
+        return Err(ImageError::TARMetadataTooLarge);
+    }
+    if is_whiteout(&path) {
+        return Ok(());
+    }
     let device = (
         entry.header().device_major()?,
         entry.header().device_minor()?,
@@ -102,14 +222,29 @@ fn extract_file_metadata<'a, R: Read>(
     match kind {
         EntryType::Fifo => fsw.write_fifo(&path, stat)?,
         EntryType::Regular => fsw.write_storage_file(&path, stat, data)?,
+        EntryType::GNUSparse => match (data, sparse_map) {
+            (Some(data), Some(map)) => fsw.write_sparse_file(&path, stat, data, map)?,
+            _ => Err(ImageError::TARFileError)?,
+        },
         EntryType::Directory => fsw.write_directory_metadata(&path, stat)?,
         EntryType::Symlink => match link_name {
-            Some(link_name) => fsw.write_symlink(&path, stat, CString::new(link_name)?)?,
+            Some(link_name) => {
+                let target = Path::new(OsStr::from_bytes(&link_name));
+                let base = if target.is_absolute() {
+                    Path::new("")
+                } else {
+                    path.parent().unwrap_or_else(|| Path::new(""))
+                };
+                check_link_target(base, target)?;
+                fsw.write_symlink(&path, stat, CString::new(link_name)?)?
+            }
             None => Err(ImageError::TARFileError)?,
         },
         EntryType::Link => match link_name {
             Some(link_name) => {
-                fsw.write_hardlink(&path, &Path::new(std::str::from_utf8(&link_name)?))?
+                let target = Path::new(OsStr::from_bytes(&link_name));
+                check_link_target(Path::new(""), target)?;
+                fsw.write_hardlink(&path, target)?
             }
             None => Err(ImageError::TARFileError)?,
         },
@@ -129,3 +264,313 @@ fn extract_file_metadata<'a, R: Read>(
     }
     Ok(())
 }
+
+/// Reject a link whose target, followed from `base`, climbs above the root
+///
+/// Both paths are taken relative to the root of the image. The VFS clamps
+/// `..` at its root anyway, so a target like this is never legitimate.
+fn check_link_target(base: &Path, target: &Path) -> Result<(), ImageError> {
+    let mut depth: usize = 0;
+    for component in base.components().chain(target.components()) {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return Err(ImageError::TARLinkEscapesRoot(target.to_path_buf())),
+            },
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errors::VFSError, sand::protocol::FollowLinks};
+    use std::io::Write;
+
+    fn extract_archive<F: FnOnce(&mut tar::Builder<Vec<u8>>)>(
+        build: F,
+    ) -> Result<Filesystem, ImageError> {
+        let mut fs = Filesystem::new();
+        extract_layer(&mut fs, build)?;
+        Ok(fs)
+    }
+
+    fn extract_layer<F: FnOnce(&mut tar::Builder<Vec<u8>>)>(
+        fs: &mut Filesystem,
+        build: F,
+    ) -> Result<(), ImageError> {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None);
+        extract_stored(fs, &storage, build)
+    }
+
+    fn extract_stored<F: FnOnce(&mut tar::Builder<Vec<u8>>)>(
+        fs: &mut Filesystem,
+        storage: &FileStorage,
+        build: F,
+    ) -> Result<(), ImageError> {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder);
+        let archive = builder.into_inner().unwrap();
+
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(&archive).unwrap();
+        let key = StorageKey::Blob(writer.finalize().unwrap());
+        storage.commit_write(writer, &key).unwrap();
+        extract(fs, storage, &key)
+    }
+
+    fn append_link(builder: &mut tar::Builder<Vec<u8>>, kind: EntryType, path: &str, target: &str) {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_link_name(target).unwrap();
+        builder.append_data(&mut header, path, &[][..]).unwrap();
+    }
+
+    fn append_file(builder: &mut tar::Builder<Vec<u8>>, path: &str) {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(4);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, path, &b"data"[..])
+            .unwrap();
+    }
+
+    fn octal_field(field: &mut [u8; 12], value: u64) {
+        field.copy_from_slice(format!("{:011o}\0", value).as_bytes());
+    }
+
+    /// Add a GNU sparse file with its extents given as (offset, data)
+    ///
+    /// Like GNU tar, the last extent should be an empty one at the end of the
+    /// file, and the others should hold whole blocks.
+    fn append_sparse(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        size: u64,
+        extents: &[(u64, &[u8])],
+    ) {
+        let mut header = Header::new_gnu();
+        header.set_path(path).unwrap();
+        header.set_entry_type(EntryType::GNUSparse);
+        header.set_mode(0o644);
+        let mut data = Vec::new();
+        let gnu = header.as_gnu_mut().unwrap();
+        octal_field(&mut gnu.realsize, size);
+        for (sparse, (offset, bytes)) in gnu.sparse.iter_mut().zip(extents) {
+            octal_field(&mut sparse.offset, *offset);
+            octal_field(&mut sparse.numbytes, bytes.len() as u64);
+            data.extend_from_slice(bytes);
+        }
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        builder.append(&header, &data[..]).unwrap();
+    }
+
+    fn read_sparse(extents: &[(u64, &[u8])], size: u64) -> (FileStat, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None);
+        let mut fs = Filesystem::new();
+        extract_stored(&mut fs, &storage, |builder| {
+            append_sparse(builder, "sparse", size, extents);
+            append_file(builder, "after");
+        })
+        .unwrap();
+        let root = Filesystem::root();
+        let after = fs
+            .lookup(&root, Path::new("/after"), &FollowLinks::Follow)
+            .unwrap();
+        assert_eq!(fs.stat(&after).unwrap().st_size, 4);
+        let file = fs
+            .lookup(&root, Path::new("/sparse"), &FollowLinks::Follow)
+            .unwrap();
+        let stat = fs.stat(&file).unwrap();
+        let contents = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(fs.read_small_file(&storage, &file, 1 << 20))
+            .unwrap()
+            .unwrap();
+        (stat, contents)
+    }
+
+    #[test]
+    fn sparse_file() {
+        let data = [b'x'; 512];
+        let (stat, contents) = read_sparse(&[(4096, &data[..]), (12288, b"")], 12288);
+        assert_eq!(stat.st_size, 12288);
+        assert_eq!(stat.st_mode, abi::S_IFREG | 0o644);
+        let mut expected = vec![0; 12288];
+        expected[4096..4608].copy_from_slice(&data);
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn sparse_file_of_only_holes() {
+        let (stat, contents) = read_sparse(&[(8192, b"")], 8192);
+        assert_eq!(stat.st_size, 8192);
+        assert_eq!(contents, vec![0; 8192]);
+    }
+
+    #[test]
+    fn symlinks_within_root() {
+        let fs = extract_archive(|builder| {
+            append_file(builder, "lib/libc.so");
+            append_link(
+                builder,
+                EntryType::Symlink,
+                "usr/lib/libc.so",
+                "../../lib/libc.so",
+            );
+            append_link(builder, EntryType::Symlink, "bin/sh", "/bin/busybox");
+        })
+        .unwrap();
+        let root = Filesystem::root();
+        fs.lookup(&root, Path::new("/usr/lib/libc.so"), &FollowLinks::Follow)
+            .unwrap();
+    }
+
+    #[test]
+    fn relative_symlink_escaping_root() {
+        match extract_archive(|builder| {
+            append_link(
+                builder,
+                EntryType::Symlink,
+                "etc/passwd",
+                "../../etc/passwd",
+            );
+        }) {
+            Err(ImageError::TARLinkEscapesRoot(path)) => {
+                assert_eq!(path, Path::new("../../etc/passwd"))
+            }
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn absolute_symlink_escaping_root() {
+        match extract_archive(|builder| {
+            append_link(
+                builder,
+                EntryType::Symlink,
+                "escape",
+                "/../../proc/self/root",
+            );
+        }) {
+            Err(ImageError::TARLinkEscapesRoot(_)) => (),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn hardlink_escaping_root() {
+        match extract_archive(|builder| {
+            append_file(builder, "file");
+            append_link(builder, EntryType::Link, "link", "../file");
+        }) {
+            Err(ImageError::TARLinkEscapesRoot(_)) => (),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn whiteouts() {
+        let mut fs = extract_archive(|builder| {
+            append_file(builder, "etc/hidden");
+            append_file(builder, "etc/kept");
+            append_file(builder, "var/cache/old");
+            append_file(builder, "var/cache/dir/old");
+        })
+        .unwrap();
+        extract_layer(&mut fs, |builder| {
+            append_file(builder, "var/cache/new");
+            append_file(builder, "etc/.wh.hidden");
+            append_file(builder, "etc/.wh.missing");
+            append_file(builder, "var/cache/.wh..wh..opq");
+            append_file(builder, "usr/.wh..wh.plnk");
+        })
+        .unwrap();
+        let lookup = |path: &str| {
+            fs.lookup(&Filesystem::root(), Path::new(path), &FollowLinks::Follow)
+                .map(|_| ())
+        };
+        lookup("/etc/kept").unwrap();
+        lookup("/var/cache/new").unwrap();
+        for path in &[
+            "/etc/hidden",
+            "/etc/.wh.hidden",
+            "/etc/missing",
+            "/var/cache/old",
+            "/var/cache/dir",
+            "/var/cache/.wh..wh..opq",
+            "/usr/.wh..wh.plnk",
+        ] {
+            assert!(matches!(lookup(path), Err(VFSError::NotFound)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn opaque_directory_replaces_non_directories() {
+        let mut fs = extract_archive(|builder| {
+            append_file(builder, "etc/passwd");
+            append_file(builder, "data");
+            append_link(builder, EntryType::Symlink, "config", "/etc");
+        })
+        .unwrap();
+        extract_layer(&mut fs, |builder| {
+            append_file(builder, "config/.wh..wh..opq");
+            append_file(builder, "config/new");
+            append_file(builder, "data/.wh..wh..opq");
+        })
+        .unwrap();
+        let lookup =
+            |path: &str| fs.lookup(&Filesystem::root(), Path::new(path), &FollowLinks::NoFollow);
+        lookup("/etc/passwd").unwrap();
+        assert!(matches!(lookup("/etc/new"), Err(VFSError::NotFound)));
+        lookup("/config/new").unwrap();
+        assert!(fs.is_directory(&lookup("/config").unwrap()).unwrap());
+        let data = lookup("/data").unwrap();
+        assert!(fs.is_directory(&data).unwrap());
+        assert_eq!(fs.read_dir(&data).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn whiteout_of_reserved_name() {
+        match extract_archive(|builder| append_file(builder, "etc/.wh..")) {
+            Err(ImageError::TARFileError) => (),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn oversized_path() {
+        let long_path = "a/".repeat(MAX_PATH_LEN) + "file";
+        match extract_archive(|builder| append_file(builder, &long_path)) {
+            Err(ImageError::TARMetadataTooLarge) => (),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn oversized_link_name() {
+        // Link names this long need a GNU long link entry ahead of the symlink
+        let long_target = "a/".repeat(MAX_PATH_LEN) + "file\0";
+        match extract_archive(|builder| {
+            let mut long_link = Header::new_gnu();
+            long_link.as_gnu_mut().unwrap().name[..13].copy_from_slice(b"././@LongLink");
+            long_link.set_entry_type(EntryType::GNULongLink);
+            long_link.set_size(long_target.len() as u64);
+            long_link.set_cksum();
+            builder.append(&long_link, long_target.as_bytes()).unwrap();
+            append_link(builder, EntryType::Symlink, "link", "a");
+        }) {
+            Err(ImageError::TARMetadataTooLarge) => (),
+            other => panic!("unexpected result, {:?}", other.map(|_| ())),
+        }
+    }
+}
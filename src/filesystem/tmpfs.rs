@@ -0,0 +1,203 @@
+use crate::{
+    errors::VFSError,
+    filesystem::{mount::Mount, vfs::Filesystem},
+};
+use std::path::Path;
+
+/// An empty directory whose contents live in memory, like a kernel tmpfs
+///
+/// Anything at the mount point in the image is hidden, and every container
+/// starts with the directory empty. Files the container creates inside it
+/// belong to that container alone, and nothing can be renamed into or out of
+/// it.
+///
+/// Unlike a kernel tmpfs, there is no hard size limit. The container writes
+/// through its own file descriptors, which the runtime never sees, so the
+/// mount has a quota instead. It counts each file at the size it had when it
+/// was last opened for writing, and once that total reaches the quota,
+/// creating something or opening a file for writing fails with ENOSPC. A
+/// file that is already open can grow without limit.
+#[derive(Debug, Clone)]
+pub struct Tmpfs {
+    quota: u64,
+    mode: u32,
+}
+
+impl Tmpfs {
+    /// A tmpfs with a quota of `quota` bytes, writable by everyone like /tmp
+    pub fn new(quota: u64) -> Self {
+        Tmpfs {
+            quota,
+            mode: 0o1777,
+        }
+    }
+
+    /// Change the permission bits of the mount's root directory
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode & 0o7777;
+        self
+    }
+
+    pub fn quota(&self) -> u64 {
+        self.quota
+    }
+}
+
+impl Mount for Tmpfs {
+    fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError> {
+        fs.writer().mount_tmpfs(path, self.quota, self.mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::storage::FileStorage,
+        sand::protocol::{abi, FileStat, FollowLinks, VFile},
+    };
+    use std::{fs::OpenOptions, io::Write, os::unix::io::AsRawFd};
+    use tokio::runtime::Runtime;
+
+    fn lookup(fs: &Filesystem, path: &str) -> Result<VFile, VFSError> {
+        fs.lookup(&Filesystem::root(), Path::new(path), &FollowLinks::Follow)
+    }
+
+    fn file_stat() -> FileStat {
+        FileStat {
+            st_mode: abi::S_IFREG | 0o644,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn hides_image_contents() {
+        let mut fs = Filesystem::new();
+        fs.writer()
+            .write_file_bytes(
+                Path::new("/tmp/leftover"),
+                file_stat(),
+                bytes::Bytes::from_static(b"old\n"),
+            )
+            .unwrap();
+        Tmpfs::new(4096).mount(&mut fs, Path::new("/tmp")).unwrap();
+        Tmpfs::new(4096)
+            .mode(0o755)
+            .mount(&mut fs, Path::new("/run"))
+            .unwrap();
+
+        assert!(matches!(
+            lookup(&fs, "/tmp/leftover"),
+            Err(VFSError::NotFound)
+        ));
+        let tmp = lookup(&fs, "/tmp").unwrap();
+        assert_eq!(fs.stat(&tmp).unwrap().st_mode, abi::S_IFDIR | 0o1777);
+        let run = lookup(&fs, "/run").unwrap();
+        assert_eq!(fs.stat(&run).unwrap().st_mode, abi::S_IFDIR | 0o755);
+        assert_eq!(lookup(&fs, "/tmp/..").unwrap(), Filesystem::root());
+        assert_eq!(fs.read_dir(&tmp).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn quota() {
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = Filesystem::new();
+        Tmpfs::new(100).mount(&mut fs, Path::new("/tmp")).unwrap();
+        let mut writer = fs.writer();
+        writer
+            .create_directory(
+                Path::new("/tmp/dir"),
+                FileStat {
+                    st_mode: abi::S_IFDIR | 0o755,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let small = writer
+            .create_file(&storage, Path::new("/tmp/dir/small"), file_stat())
+            .unwrap();
+        writer
+            .create_file(&storage, Path::new("/outside"), file_stat())
+            .unwrap();
+        Runtime::new().unwrap().block_on(async {
+            let fd = fs
+                .open_storage(&storage, &small, libc::O_WRONLY)
+                .await
+                .unwrap();
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+                .unwrap();
+            file.write_all(&[0x55; 100]).unwrap();
+            assert_eq!(fs.stat(&small).unwrap().st_size, 100);
+
+            // Growth through an open file only counts once the file is
+            // opened again
+            fs.writer()
+                .create_file(&storage, Path::new("/tmp/second"), file_stat())
+                .unwrap();
+            assert!(matches!(
+                fs.copy_up(&storage, &small, false).await,
+                Err(VFSError::NoSpace)
+            ));
+            assert!(matches!(
+                fs.writer()
+                    .create_file(&storage, Path::new("/tmp/third"), file_stat()),
+                Err(VFSError::NoSpace)
+            ));
+
+            // Other files don't count, and truncating makes room again
+            let outside = lookup(&fs, "/outside").unwrap();
+            fs.copy_up(&storage, &outside, false).await.unwrap();
+            fs.copy_up(&storage, &small, true).await.unwrap();
+            fs.writer()
+                .create_file(&storage, Path::new("/tmp/third"), file_stat())
+                .unwrap();
+
+            // So does removing a file
+            file.write_all(&[0x55; 100]).unwrap();
+            assert!(matches!(
+                fs.copy_up(&storage, &small, false).await,
+                Err(VFSError::NoSpace)
+            ));
+            fs.writer()
+                .unlink(Path::new("/tmp/dir/small"), false)
+                .unwrap();
+            fs.writer()
+                .create_file(&storage, Path::new("/tmp/fourth"), file_stat())
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn separate_device() {
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = Filesystem::new();
+        Tmpfs::new(4096).mount(&mut fs, Path::new("/tmp")).unwrap();
+        let mut writer = fs.writer();
+        writer
+            .create_file(&storage, Path::new("/tmp/a"), file_stat())
+            .unwrap();
+        writer
+            .create_file(&storage, Path::new("/b"), file_stat())
+            .unwrap();
+        writer
+            .rename(Path::new("/tmp/a"), Path::new("/tmp/c"))
+            .unwrap();
+        assert!(matches!(
+            writer.rename(Path::new("/tmp/c"), Path::new("/c")),
+            Err(VFSError::CrossDevice)
+        ));
+        assert!(matches!(
+            writer.rename(Path::new("/b"), Path::new("/tmp/b")),
+            Err(VFSError::CrossDevice)
+        ));
+        assert!(matches!(
+            writer.unlink(Path::new("/tmp"), true),
+            Err(VFSError::Busy)
+        ));
+        assert!(fs.is_mount_point(&lookup(&fs, "/tmp").unwrap()).unwrap());
+    }
+}
@@ -1,26 +1,71 @@
 use crate::{
-    errors::VFSError,
+    errors::{ImageError, VFSError},
     filesystem::{
+        bind::HostDir,
+        devices,
+        devices::CharDevice,
+        procfs::ProcNode,
         socket::SharedStream,
-        storage::{FileStorage, StorageKey},
+        storage::{FileStorage, SparseMap, StorageKey},
+        volume::VolumeFiles,
     },
     sand::protocol::{abi, abi::DirentHeader, FileStat, FollowLinks, INodeNum, VFile},
 };
+use bytes::Bytes;
 use plain::Plain;
 use std::{
-    collections::BTreeMap,
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap},
     convert::TryInto,
-    ffi::{CStr, CString, OsStr, OsString},
+    ffi::{CString, OsStr, OsString},
     fs::File,
-    io::{BufWriter, Seek, SeekFrom, Write},
+    fs::OpenOptions,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    iter, mem,
     os::unix::{ffi::OsStrExt, io::AsRawFd},
-    path::Path,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
+/// Upper limit on remembered failed lookups, after which the cache starts over
+const NEGATIVE_LOOKUPS_MAX: usize = 8192;
+
+/// First inode number given to files below bind mounts, far past any image
+const HOST_INODE_BASE: INodeNum = 1 << 48;
+
+/// First inode number given to volumes, each of which gets a range of
+/// [VOLUME_INODE_SPAN] numbers to itself, below [HOST_INODE_BASE]
+const VOLUME_INODE_BASE: INodeNum = 1 << 40;
+const VOLUME_INODE_SPAN: INodeNum = 1 << 28;
+
+/// Volumes prepared so far in this process, to give each its own range
+static VOLUME_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Image files larger than this are copied to disk instead of memory when
+/// the guest first writes to them
+const MEMFILE_SPILL_SIZE: i64 = 16 * 1024 * 1024;
+
+/// Once the guest's files in memory add up to this much, new files and
+/// copies go to disk as well
+const MEMFILE_SPILL_TOTAL: u64 = 64 * 1024 * 1024;
+
 #[derive(Clone)]
 pub struct Filesystem {
     inodes: Vec<Option<Arc<INode>>>,
+    /// Volumes mounted here, by the inode number of their root
+    volumes: BTreeMap<INodeNum, MountedVolume>,
+    negative_lookups: Arc<NegativeLookups>,
+    host_entries: Arc<HostEntries>,
+    /// Quota and usage of each tmpfs mount, by the inode number of its root
+    tmpfs: BTreeMap<INodeNum, TmpfsUsage>,
+    /// Sizes of the guest's files held in memory
+    memfiles: MemFileUsage,
+    /// Refuse changes outside tmpfs and bind mounts
+    read_only: bool,
+    /// Files that must never leave the container's memory, like secrets
+    private: BTreeSet<INodeNum>,
 }
 
 pub struct VFSWriter<'f> {
@@ -28,6 +73,56 @@ pub struct VFSWriter<'f> {
     fs: &'f mut Filesystem,
 }
 
+/// The inodes of a [Volume](crate::Volume), numbered from a range that no
+/// other volume uses, so every filesystem can share them without copying
+pub(crate) struct VolumeTree {
+    base: INodeNum,
+    inodes: Vec<Option<Arc<INode>>>,
+}
+
+/// A volume in one filesystem, with a root of its own whose parent is the
+/// directory it was mounted in
+#[derive(Clone)]
+struct MountedVolume {
+    tree: Arc<VolumeTree>,
+    root: Arc<INode>,
+}
+
+/// Sizes of a set of files, as of the last time the runtime saw each one
+#[derive(Clone, Debug, Default)]
+struct FileSizes {
+    total: u64,
+    files: BTreeMap<INodeNum, u64>,
+}
+
+impl FileSizes {
+    /// Record a file's size, returning true if it changed
+    fn set_size(&mut self, num: INodeNum, size: u64) -> bool {
+        let previous = self.files.insert(num, size);
+        self.total = self.total - previous.unwrap_or(0) + size;
+        previous != Some(size)
+    }
+
+    fn remove(&mut self, num: INodeNum) {
+        self.total -= self.files.remove(&num).unwrap_or(0);
+    }
+}
+
+/// Space used in one tmpfs mount
+#[derive(Clone, Debug, Default)]
+struct TmpfsUsage {
+    quota: u64,
+    sizes: FileSizes,
+}
+
+/// Space used by the guest's files in memory
+#[derive(Clone, Debug, Default)]
+struct MemFileUsage {
+    sizes: FileSizes,
+    /// Files opened for writing which may still be growing
+    changing: BTreeSet<INodeNum>,
+}
+
 #[derive(Clone)]
 struct INode {
     stat: FileStat,
@@ -44,12 +139,52 @@ struct DirEntryRef {
 enum Node {
     NormalDirectory(BTreeMap<OsString, INodeNum>),
     FileStorage(StorageKey),
+    SparseFile(StorageKey, Arc<SparseMap>),
+    VolumeFile(Arc<VolumeFiles>, StorageKey, Option<Arc<SparseMap>>),
     SharedStream(SharedStream),
+    Bytes(Bytes),
+    /// Contents written by the guest, held by this container only, with the
+    /// root of the tmpfs mount it was created in if any. These live in
+    /// memory, except for large copies of image files and anything made
+    /// while the guest already has too much in memory, which are unlinked
+    /// files in the cache directory.
+    MemFile(Arc<File>, Option<INodeNum>),
     EmptyFile,
     SymbolicLink(CString),
     Char(u32, u32),
     Block(u32, u32),
     Fifo,
+    /// Generated from the state of whichever process opens it
+    Proc(ProcNode),
+    /// Root of a bind mount, with the directory it was mounted in
+    HostDirectory(Arc<HostDir>, INodeNum),
+}
+
+/// The kind of file a directory entry refers to
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum FileType {
+    Directory,
+    File,
+    SymbolicLink,
+    Socket,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Unknown,
+}
+
+/// One entry listed by [Filesystem::read_dir()]
+#[derive(Debug, Clone)]
+pub struct DirEntry<'f> {
+    name: &'f OsStr,
+    file: VFile,
+    stat: &'f FileStat,
+}
+
+/// Iterator over the entries in one directory, in sorted order
+pub struct ReadDir<'f> {
+    fs: &'f Filesystem,
+    iter: btree_map::Iter<'f, OsString, INodeNum>,
 }
 
 #[repr(C)]
@@ -61,6 +196,47 @@ unsafe impl Plain for PlainDirentHeader {}
 struct Limits {
     path_segment: usize,
     symbolic_link: usize,
+    /// Every inode whose contents affected the lookup so far
+    visited: Vec<INodeNum>,
+}
+
+/// Lookups which failed with NotFound, shared by every copy of a filesystem
+///
+/// Images are read-only, so a path missing from one stays missing in every
+/// container that runs it. Containers do add their own mounts though. Each
+/// entry keeps the inodes its lookup visited, and copies of a filesystem share
+/// those inodes until a write replaces them, so an entry only applies while
+/// every inode it depends on is still the same one.
+#[derive(Default)]
+struct NegativeLookups {
+    entries: Mutex<HashMap<NegativeLookupKey, Vec<(INodeNum, Arc<INode>)>>>,
+}
+
+type NegativeLookupKey = (INodeNum, PathBuf, bool);
+
+/// Inode numbers for files below bind mounts, assigned as they're looked up
+///
+/// Host files aren't copied into the inode table. Each path below a mount
+/// gets a number the first time it's found, and keeps it for as long as the
+/// filesystem exists, so the guest sees stable numbers even though the files
+/// themselves can change underneath it at any time.
+#[derive(Default)]
+struct HostEntries {
+    entries: Mutex<HostEntryTable>,
+}
+
+#[derive(Default, Clone)]
+struct HostEntryTable {
+    list: Vec<HostEntry>,
+    numbers: HashMap<(INodeNum, PathBuf), INodeNum>,
+}
+
+/// A file or directory at `path` within the bind mount at inode `mount`
+#[derive(Clone)]
+struct HostEntry {
+    mount: INodeNum,
+    dir: Arc<HostDir>,
+    path: PathBuf,
 }
 
 impl DirEntryRef {
@@ -77,6 +253,7 @@ impl Limits {
         Limits {
             path_segment: 1000,
             symbolic_link: 50,
+            visited: Vec::new(),
         }
     }
 
@@ -99,9 +276,24 @@ impl Limits {
     }
 }
 
+impl Default for Filesystem {
+    fn default() -> Self {
+        Filesystem::new()
+    }
+}
+
 impl<'s> Filesystem {
     pub fn new() -> Self {
-        let mut fs = Filesystem { inodes: vec![None] };
+        let mut fs = Filesystem {
+            inodes: vec![None],
+            volumes: BTreeMap::new(),
+            negative_lookups: Default::default(),
+            host_entries: Default::default(),
+            tmpfs: BTreeMap::new(),
+            memfiles: Default::default(),
+            read_only: false,
+            private: BTreeSet::new(),
+        };
         let root = Filesystem::root().inode;
         fs.writer().put_directory(root);
         fs
@@ -112,12 +304,103 @@ impl<'s> Filesystem {
         VFSWriter { workdir, fs: self }
     }
 
+    /// Get a writer which resolves relative paths starting at `workdir`
+    pub fn writer_at<'f>(&'f mut self, workdir: &VFile) -> VFSWriter<'f> {
+        let workdir = workdir.clone();
+        VFSWriter { workdir, fs: self }
+    }
+
     fn get_inode(&self, num: INodeNum) -> Result<&INode, VFSError> {
-        match self.inodes.get(num) {
+        match self.shared_inode(num) {
             None => Err(VFSError::UnallocNode),
-            Some(slice) => match slice {
+            Some(node) => Ok(node),
+        }
+    }
+
+    /// The inode with this number, as it may be shared with other filesystems
+    fn shared_inode(&self, num: INodeNum) -> Option<&Arc<INode>> {
+        if is_volume_inode(num) {
+            let (base, volume) = self.volumes.range(..=num).next_back()?;
+            return if num == *base {
+                Some(&volume.root)
+            } else {
+                volume.tree.inodes.get(num - base)?.as_ref()
+            };
+        }
+        self.inodes.get(num)?.as_ref()
+    }
+
+    /// Number this filesystem's inodes from a range of their own, so it can
+    /// be mounted anywhere as a read-only volume
+    pub(crate) fn into_volume_tree(self) -> Result<VolumeTree, VFSError> {
+        let index = VOLUME_COUNT.fetch_add(1, Ordering::Relaxed);
+        let base = VOLUME_INODE_BASE + index * VOLUME_INODE_SPAN;
+        if base >= HOST_INODE_BASE || self.inodes.len() > VOLUME_INODE_SPAN {
+            return Err(VFSError::NoSpace);
+        }
+        let inodes = self
+            .inodes
+            .into_iter()
+            .map(|inode| {
+                inode.map(|mut inode| {
+                    if let Node::NormalDirectory(map) = &mut Arc::make_mut(&mut inode).data {
+                        for num in map.values_mut() {
+                            *num += base;
+                        }
+                    }
+                    inode
+                })
+            })
+            .collect();
+        Ok(VolumeTree { base, inodes })
+    }
+
+    /// Find the bind mount behind an inode, if it belongs to one
+    fn host_entry(&self, num: INodeNum) -> Result<Option<HostEntry>, VFSError> {
+        if num >= HOST_INODE_BASE {
+            let table = self.host_entries.entries.lock().unwrap();
+            return match table.list.get(num - HOST_INODE_BASE) {
+                Some(entry) => Ok(Some(entry.clone())),
                 None => Err(VFSError::UnallocNode),
-                Some(node) => Ok(node),
+            };
+        }
+        match &self.get_inode(num)?.data {
+            Node::HostDirectory(dir, _) => Ok(Some(HostEntry {
+                mount: num,
+                dir: dir.clone(),
+                path: PathBuf::new(),
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// Get the inode number for another path in the same bind mount
+    fn host_inode(&self, entry: &HostEntry, path: PathBuf) -> INodeNum {
+        if path.as_os_str().is_empty() {
+            return entry.mount;
+        }
+        let mut table = self.host_entries.entries.lock().unwrap();
+        let key = (entry.mount, path);
+        if let Some(num) = table.numbers.get(&key) {
+            return *num;
+        }
+        let num = HOST_INODE_BASE + table.list.len();
+        table.list.push(HostEntry {
+            mount: entry.mount,
+            dir: entry.dir.clone(),
+            path: key.1.clone(),
+        });
+        table.numbers.insert(key, num);
+        num
+    }
+
+    /// Where a symbolic link points, or None if this isn't a link
+    fn link_target(&self, num: INodeNum) -> Result<Option<CString>, VFSError> {
+        match self.host_entry(num)? {
+            Some(entry) => entry.dir.read_link(&entry.path),
+            None => match &self.get_inode(num)?.data {
+                Node::SymbolicLink(cstr) => Ok(Some(cstr.clone())),
+                _ => Ok(None),
             },
         }
     }
@@ -127,14 +410,16 @@ impl<'s> Filesystem {
         mut limits: &mut Limits,
         mut entry: DirEntryRef,
     ) -> Result<DirEntryRef, VFSError> {
-        while let Node::SymbolicLink(cstr) = &self.get_inode(entry.child)?.data {
+        limits.visited.push(entry.child);
+        while let Some(cstr) = self.link_target(entry.child)? {
             log::trace!("following symlink, {:?} -> {:?}", entry, cstr);
             limits.take_symbolic_link()?;
             entry = self.resolve_path(
                 &mut limits,
                 entry.parent,
-                Path::new(cstr.as_c_str().to_str()?),
+                Path::new(OsStr::from_bytes(cstr.as_bytes())),
             )?;
+            limits.visited.push(entry.child);
         }
         Ok(entry)
     }
@@ -148,7 +433,27 @@ impl<'s> Filesystem {
         limits.take_path_segment()?;
         if part == "/" {
             Ok(DirEntryRef::root())
+        } else if let Some(host) = self.host_entry(parent)? {
+            // Links and `..` are resolved here, never by the host, so the
+            // same path means the same thing as it would in a kernel mount
+            let child = if part == "." {
+                parent
+            } else if part == ".." {
+                match host.path.parent() {
+                    Some(path) => self.host_inode(&host, path.to_path_buf()),
+                    None => match &self.get_inode(host.mount)?.data {
+                        Node::HostDirectory(_, mounted_in) => *mounted_in,
+                        _ => return Err(VFSError::UnallocNode),
+                    },
+                }
+            } else {
+                let path = host.path.join(part);
+                host.dir.stat(&path)?;
+                self.host_inode(&host, path)
+            };
+            Ok(DirEntryRef { parent, child })
         } else {
+            limits.visited.push(parent);
             match &self.get_inode(parent)?.data {
                 Node::NormalDirectory(map) => match map.get(part) {
                     None => Err(VFSError::NotFound),
@@ -205,11 +510,33 @@ impl<'s> Filesystem {
         path: &Path,
         follow_links: &FollowLinks,
     ) -> Result<VFile, VFSError> {
+        let key = (
+            dir.inode,
+            path.to_path_buf(),
+            follow_links == &FollowLinks::Follow,
+        );
+        if self.negative_lookups.contains(self, &key) {
+            log::debug!(
+                "open({:?}, {:?}, {:?}) -> cached NotFound",
+                dir,
+                path,
+                follow_links
+            );
+            return Err(VFSError::NotFound);
+        }
         let mut limits = Limits::reset();
-        let entry = self.resolve_path(&mut limits, dir.inode, path)?;
-        let entry = match follow_links {
-            FollowLinks::NoFollow => entry,
-            FollowLinks::Follow => self.resolve_symlinks(&mut limits, entry)?,
+        let entry = self
+            .resolve_path(&mut limits, dir.inode, path)
+            .and_then(|entry| match follow_links {
+                FollowLinks::NoFollow => Ok(entry),
+                FollowLinks::Follow => self.resolve_symlinks(&mut limits, entry),
+            });
+        let entry = match entry {
+            Err(VFSError::NotFound) => {
+                self.negative_lookups.insert(self, key, &limits.visited);
+                return Err(VFSError::NotFound);
+            }
+            other => other?,
         };
         log::debug!(
             "open({:?}, {:?}, {:?}) -> {:?}",
@@ -221,37 +548,437 @@ impl<'s> Filesystem {
         Ok(VFile { inode: entry.child })
     }
 
-    pub fn stat(&self, f: &VFile) -> Result<&FileStat, VFSError> {
-        let stat = &self.get_inode(f.inode)?.stat;
+    /// Find the absolute path of a file with every symbolic link resolved
+    ///
+    /// Relative paths start at the root directory. The result names the same
+    /// file that [Filesystem::lookup()] would find when following links, but
+    /// using only normal directories on the way there. If the file has more
+    /// than one hard link in the same directory, any of those names may be
+    /// used.
+    pub fn canonicalize(&self, path: &Path) -> Result<PathBuf, VFSError> {
+        let mut limits = Limits::reset();
+        let entry = self.resolve_path(&mut limits, Filesystem::root().inode, path)?;
+        let entry = self.resolve_symlinks(&mut limits, entry)?;
+        let mut names = Vec::new();
+        let dir = if self.is_directory(&VFile { inode: entry.child })? {
+            entry.child
+        } else {
+            names.push(self.name_in_directory(entry.parent, entry.child)?);
+            entry.parent
+        };
+        let result = self.path_from_root(&mut limits, dir, names)?;
+        log::debug!("canonicalize({:?}) -> {:?}", path, result);
+        Ok(result)
+    }
+
+    /// Find the absolute path of a directory, by following `..` entries up to
+    /// the root
+    ///
+    /// A directory that has been removed no longer has a path, and gives
+    /// [VFSError::NotFound].
+    pub fn directory_path(&self, dir: &VFile) -> Result<PathBuf, VFSError> {
+        if !self.is_directory(dir)? {
+            return Err(VFSError::DirectoryExpected);
+        }
+        self.path_from_root(&mut Limits::reset(), dir.inode, Vec::new())
+    }
+
+    /// Finish a path whose `names` were collected in reverse, starting below
+    /// the directory `dir`
+    fn path_from_root(
+        &self,
+        limits: &mut Limits,
+        mut dir: INodeNum,
+        mut names: Vec<OsString>,
+    ) -> Result<PathBuf, VFSError> {
+        while dir != Filesystem::root().inode {
+            let parent = self.resolve_path_segment(limits, dir, OsStr::new(".."))?;
+            names.push(self.name_in_directory(parent.child, dir)?);
+            dir = parent.child;
+        }
+        let mut result = PathBuf::from("/");
+        result.extend(names.iter().rev());
+        Ok(result)
+    }
+
+    fn name_in_directory(&self, dir: INodeNum, child: INodeNum) -> Result<OsString, VFSError> {
+        if let Some(host) = self.host_entry(child)? {
+            if let Some(name) = host.path.file_name() {
+                return Ok(name.to_os_string());
+            }
+        }
+        match &self.get_inode(dir)?.data {
+            Node::NormalDirectory(map) => map
+                .iter()
+                .find(|(name, num)| **num == child && *name != "." && *name != "..")
+                .map(|(name, _)| name.clone())
+                .ok_or(VFSError::NotFound),
+            _ => Err(VFSError::DirectoryExpected),
+        }
+    }
+
+    pub fn stat(&self, f: &VFile) -> Result<FileStat, VFSError> {
+        let stat = match self.host_entry(f.inode)? {
+            Some(host) => host.dir.stat(&host.path)?,
+            None => current_stat(self.get_inode(f.inode)?)?,
+        };
         log::debug!("stat({:?}) -> {:?}", f, stat);
         Ok(stat)
     }
 
-    pub fn readlink(&self, f: &VFile) -> Result<&CStr, VFSError> {
-        let cstr = match &self.get_inode(f.inode)?.data {
-            Node::SymbolicLink(path) => path.as_c_str(),
-            _ => return Err(VFSError::LinkExpected),
-        };
+    pub fn readlink(&self, f: &VFile) -> Result<CString, VFSError> {
+        let cstr = self.link_target(f.inode)?.ok_or(VFSError::LinkExpected)?;
         log::debug!("readlink({:?}) -> {:?}", f, cstr);
         Ok(cstr)
     }
 
+    /// Is this the root directory of a [BindMount](crate::BindMount),
+    /// [Tmpfs](crate::Tmpfs) or [Volume](crate::Volume)
+    pub(crate) fn is_mount_point(&self, f: &VFile) -> Result<bool, VFSError> {
+        if f.inode >= HOST_INODE_BASE {
+            return Ok(false);
+        }
+        if self.volumes.contains_key(&f.inode) {
+            return Ok(true);
+        }
+        match &self.get_inode(f.inode)?.data {
+            Node::HostDirectory(_, _) => Ok(true),
+            _ => Ok(self.tmpfs.contains_key(&f.inode)),
+        }
+    }
+
+    /// Keep a file out of anything saved to disk, under any of its names
+    pub(crate) fn set_private(&mut self, f: &VFile) {
+        self.private.insert(f.inode);
+    }
+
+    /// Was this file marked with [Filesystem::set_private()]
+    pub(crate) fn is_private(&self, f: &VFile) -> bool {
+        self.private.contains(&f.inode)
+    }
+
+    /// Is this the same unchanged file as one in another filesystem
+    ///
+    /// Files shared by [VFSWriter::graft()] stay the same until either copy
+    /// is changed in any way, including its metadata.
+    pub(crate) fn is_same_inode(&self, f: &VFile, other: &Filesystem, other_f: &VFile) -> bool {
+        match (
+            self.shared_inode(f.inode),
+            other.shared_inode(other_f.inode),
+        ) {
+            (Some(inode), Some(other_inode)) => Arc::ptr_eq(inode, other_inode),
+            _ => false,
+        }
+    }
+
+    /// Find the tmpfs mount holding a directory, by the inode of its root
+    fn tmpfs_containing(&self, dir: INodeNum) -> Result<Option<INodeNum>, VFSError> {
+        if dir >= HOST_INODE_BASE {
+            return Ok(None);
+        }
+        let mut num = dir;
+        loop {
+            if self.tmpfs.contains_key(&num) {
+                return Ok(Some(num));
+            }
+            let parent = match &self.get_inode(num)?.data {
+                Node::NormalDirectory(map) => map.get(OsStr::new("..")).copied(),
+                _ => None,
+            };
+            match parent {
+                Some(parent) if parent != num => num = parent,
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Make the image's files, and everything else outside tmpfs and bind
+    /// mounts, read-only from here on
+    ///
+    /// This only affects the guest's own changes, so anything mounted before
+    /// it was set stays in place. Writes to a bind mount still follow that
+    /// mount's own setting.
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Fail with ReadOnly if the guest can't change entries in this directory
+    fn check_writable(&self, dir: INodeNum) -> Result<(), VFSError> {
+        if is_volume_inode(dir) || self.read_only && self.tmpfs_containing(dir)?.is_none() {
+            Err(VFSError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fail with NoSpace if a tmpfs mount's files already fill its quota
+    fn check_tmpfs_space(&self, root: INodeNum) -> Result<(), VFSError> {
+        let usage = self.tmpfs.get(&root).ok_or(VFSError::UnallocNode)?;
+        if usage.sizes.total >= usage.quota {
+            Err(VFSError::NoSpace)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn tmpfs_usage_mut(&mut self, root: INodeNum) -> Result<&mut TmpfsUsage, VFSError> {
+        self.tmpfs.get_mut(&root).ok_or(VFSError::UnallocNode)
+    }
+
+    /// Make an empty file for the guest to write to, on disk if it's going
+    /// to be `large` or the guest already has too much in memory
+    fn new_guest_file(
+        &mut self,
+        storage: &FileStorage,
+        num: INodeNum,
+        large: bool,
+    ) -> Result<File, VFSError> {
+        if large || self.memfiles_full()? {
+            storage.anonymous_file().map_err(|_| VFSError::IO)
+        } else {
+            let file = new_memfile()?;
+            self.memfile_written(num, 0);
+            Ok(file)
+        }
+    }
+
+    /// Are the guest's files in memory over [MEMFILE_SPILL_TOTAL]
+    ///
+    /// Files opened for writing are measured again each time this is
+    /// checked, until one stops changing size.
+    fn memfiles_full(&mut self) -> Result<bool, VFSError> {
+        for num in mem::take(&mut self.memfiles.changing) {
+            let size = match &self.get_inode(num)?.data {
+                Node::MemFile(file, _) => file.metadata().map_err(|_| VFSError::IO)?.len(),
+                _ => continue,
+            };
+            if self.memfiles.sizes.set_size(num, size) {
+                self.memfiles.changing.insert(num);
+            }
+        }
+        Ok(self.memfiles.sizes.total >= MEMFILE_SPILL_TOTAL)
+    }
+
+    fn memfile_written(&mut self, num: INodeNum, size: u64) {
+        self.memfiles.sizes.set_size(num, size);
+        self.memfiles.changing.insert(num);
+    }
+
+    /// Which synthetic /proc file this is, if any
+    pub fn proc_node(&self, f: &VFile) -> Result<Option<ProcNode>, VFSError> {
+        if f.inode >= HOST_INODE_BASE {
+            return Ok(None);
+        }
+        match &self.get_inode(f.inode)?.data {
+            Node::Proc(node) => Ok(Some(*node)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether this file's contents can't change while the guest has it open
+    ///
+    /// Only files from an image qualify. A copy of one of these is as good as
+    /// the file itself, but anything the container or host can write to has
+    /// to be shared through a file descriptor.
+    pub fn has_fixed_contents(&self, f: &VFile) -> Result<bool, VFSError> {
+        if f.inode >= HOST_INODE_BASE {
+            return Ok(false);
+        }
+        Ok(matches!(
+            &self.get_inode(f.inode)?.data,
+            Node::FileStorage(_) | Node::SparseFile(_, _) | Node::Bytes(_) | Node::EmptyFile
+        ))
+    }
+
+    /// Open a file for the guest, with an access mode matching `flags`
+    ///
+    /// Only files the guest has written to can be opened for writing, so
+    /// anything else should go through [Filesystem::copy_up()] first. Files
+    /// opened for reading start reading ahead right away.
     pub async fn open_storage(
         &self,
         storage: &FileStorage,
         f: &VFile,
+        flags: i32,
     ) -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
+        let reading = flags & libc::O_ACCMODE == libc::O_RDONLY;
+        if let Some(host) = self.host_entry(f.inode)? {
+            return if self.is_directory(f)? {
+                self.open_host_directory(f.inode, &host)
+            } else {
+                let file = host.dir.open(&host.path, flags)?;
+                if reading {
+                    advise_sequential(&file);
+                }
+                Ok(Arc::new(file))
+            };
+        }
         let node = self.get_inode(f.inode)?;
-        match &node.data {
-            Node::EmptyFile => open_null(),
-            Node::NormalDirectory(dir) => self.open_directory(dir),
-            Node::SharedStream(stream) => stream.vfile_open(),
-            Node::FileStorage(key) => open_storage_part(storage, key).await,
+        let file = match &node.data {
+            Node::MemFile(file, _) => reopen_memfile(file, flags)?,
+            Node::FileStorage(key) => open_storage_part(storage, key).await?,
+            Node::SparseFile(key, map) => open_sparse_part(storage, key, map).await?,
+            Node::VolumeFile(files, key, map) => files.open(key, map.as_deref()).await?,
+            Node::EmptyFile => return open_null(),
+            Node::NormalDirectory(dir) => return self.open_directory(dir),
+            Node::SharedStream(stream) => return stream.vfile_open(),
+            Node::Bytes(bytes) => return open_bytes(bytes),
+            Node::Char(major, minor) => {
+                return match CharDevice::from_numbers(*major, *minor) {
+                    Some(CharDevice::Host(path)) => Ok(Arc::new(devices::open_host(path, flags)?)),
+                    Some(CharDevice::Tty) => self.open_tty(flags),
+                    None => Err(VFSError::NoDevice),
+                }
+            }
             _ => return Err(VFSError::FileExpected),
+        };
+        if reading {
+            advise_sequential(&file);
+        }
+        Ok(Arc::new(file))
+    }
+
+    fn open_tty(&self, flags: i32) -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
+        let path = devices::tty_stream(flags);
+        let stream = self.lookup(&Filesystem::root(), path, &FollowLinks::Follow)?;
+        match &self.get_inode(stream.inode)?.data {
+            Node::SharedStream(stream) => stream.vfile_open(),
+            _ => Err(VFSError::NoDevice),
+        }
+    }
+
+    /// Read the complete contents of a small image file
+    ///
+    /// Returns None for files larger than `limit`, and for anything besides
+    /// plain file data, like directories and streams, which must be opened.
+    pub async fn read_small_file(
+        &self,
+        storage: &FileStorage,
+        f: &VFile,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>, VFSError> {
+        if let Some(host) = self.host_entry(f.inode)? {
+            let file = match host.dir.open(&host.path, libc::O_RDONLY) {
+                Err(VFSError::FileExpected) | Err(VFSError::NoDevice) => return Ok(None),
+                other => other?,
+            };
+            return read_up_to(file, 0, limit).map_err(|_| VFSError::IO);
+        }
+        let node = self.get_inode(f.inode)?;
+        let size = current_stat(node)?.st_size;
+        if size < 0 || size as usize > limit {
+            return Ok(None);
+        }
+        let file = match &node.data {
+            Node::EmptyFile => return Ok(Some(Vec::new())),
+            Node::Bytes(bytes) if bytes.len() <= limit => return Ok(Some(bytes.to_vec())),
+            Node::Bytes(_) => return Ok(None),
+            data => match open_contents(storage, data).await? {
+                Some(file) => file,
+                None => return Ok(None),
+            },
+        };
+        read_up_to(file, size as usize, limit).map_err(|_| VFSError::ImageStorageError)
+    }
+
+    /// Give a regular file its own writable contents in this filesystem
+    ///
+    /// Images are never modified. The first time a file is opened
+    /// for writing, its current contents are copied into memory which belongs
+    /// to this container alone, and every hard link to the same inode sees the
+    /// copy afterward. With `truncate` the copy starts out empty instead.
+    /// Copies larger than [MEMFILE_SPILL_SIZE] go to an unlinked file in the
+    /// cache directory rather than memory, and so does every new copy once
+    /// the guest's files in memory add up to [MEMFILE_SPILL_TOTAL]. The guest
+    /// writes through its own descriptors, so a file stays wherever it
+    /// started out no matter how much it grows later.
+    /// Devices and streams are left alone, since writes to those already go
+    /// somewhere else, and so are files in writable bind mounts. Files in a
+    /// full tmpfs mount can only be opened for writing to truncate them, and
+    /// on a read-only filesystem only files in tmpfs mounts can be written.
+    /// Files in volumes can never be written.
+    pub async fn copy_up(
+        &mut self,
+        storage: &FileStorage,
+        f: &VFile,
+        truncate: bool,
+    ) -> Result<(), VFSError> {
+        if is_volume_inode(f.inode) {
+            return Err(VFSError::ReadOnly);
+        }
+        if let Some(host) = self.host_entry(f.inode)? {
+            return if self.is_directory(f)? {
+                Err(VFSError::FileExpected)
+            } else if host.dir.is_read_only() {
+                Err(VFSError::ReadOnly)
+            } else {
+                Ok(())
+            };
+        }
+        let node = self.get_inode(f.inode)?;
+        let data = node.data.clone();
+        let large = !truncate && current_stat(node)?.st_size > MEMFILE_SPILL_SIZE;
+        let mut file = match &data {
+            Node::MemFile(file, tmpfs) => {
+                if self.read_only && tmpfs.is_none() {
+                    return Err(VFSError::ReadOnly);
+                }
+                let size = if truncate {
+                    file.set_len(0).map_err(|_| VFSError::IO)?;
+                    0
+                } else {
+                    file.metadata().map_err(|_| VFSError::IO)?.len()
+                };
+                if self.memfiles.sizes.files.contains_key(&f.inode) {
+                    self.memfile_written(f.inode, size);
+                }
+                if let Some(root) = *tmpfs {
+                    self.tmpfs_usage_mut(root)?.sizes.set_size(f.inode, size);
+                    if !truncate {
+                        self.check_tmpfs_space(root)?;
+                    }
+                }
+                return Ok(());
+            }
+            Node::NormalDirectory(_) => return Err(VFSError::FileExpected),
+            Node::EmptyFile
+            | Node::Bytes(_)
+            | Node::FileStorage(_)
+            | Node::SparseFile(_, _)
+            | Node::VolumeFile(_, _, _) => {
+                if self.read_only {
+                    return Err(VFSError::ReadOnly);
+                }
+                self.new_guest_file(storage, f.inode, large)?
+            }
+            _ => return Ok(()),
+        };
+        if !truncate {
+            match &data {
+                Node::Bytes(bytes) => file.write_all(bytes).map_err(|_| VFSError::IO)?,
+                data => {
+                    if let Some(mut contents) = open_contents(storage, data).await? {
+                        io::copy(&mut contents, &mut file).map_err(|_| VFSError::IO)?;
+                    }
+                }
+            }
         }
+        let in_memory = self.memfiles.sizes.files.contains_key(&f.inode);
+        log::debug!(
+            "copy_up({:?}, truncate={}, in_memory={})",
+            f,
+            truncate,
+            in_memory
+        );
+        self.writer().get_inode_mut(f.inode)?.data = Node::MemFile(Arc::new(file), None);
+        Ok(())
     }
 
     pub fn is_directory(&self, f: &VFile) -> Result<bool, VFSError> {
+        if let Some(host) = self.host_entry(f.inode)? {
+            let mode = host.dir.stat(&host.path)?.st_mode;
+            return Ok(mode & abi::S_IFMT == abi::S_IFDIR);
+        }
         let node = self.get_inode(f.inode)?;
         match &node.data {
             Node::NormalDirectory(_) => Ok(true),
@@ -259,18 +986,25 @@ impl<'s> Filesystem {
         }
     }
 
+    /// List the contents of a directory, including its `.` and `..` entries
+    ///
+    /// Bind mounts can only be listed by the guest, through
+    /// [Filesystem::open_storage()].
+    pub fn read_dir(&self, dir: &VFile) -> Result<ReadDir<'_>, VFSError> {
+        if dir.inode >= HOST_INODE_BASE {
+            return Err(VFSError::DirectoryExpected);
+        }
+        match &self.get_inode(dir.inode)?.data {
+            Node::NormalDirectory(map) => Ok(ReadDir {
+                fs: self,
+                iter: map.iter(),
+            }),
+            _ => Err(VFSError::DirectoryExpected),
+        }
+    }
+
     fn dir_entry_type(&self, inode: INodeNum) -> Result<u8, VFSError> {
-        let stat = &self.get_inode(inode)?.stat;
-        Ok(match stat.st_mode & abi::S_IFMT {
-            abi::S_IFSOCK => abi::DT_SOCK,
-            abi::S_IFLNK => abi::DT_LNK,
-            abi::S_IFREG => abi::DT_REG,
-            abi::S_IFBLK => abi::DT_BLK,
-            abi::S_IFDIR => abi::DT_DIR,
-            abi::S_IFCHR => abi::DT_CHR,
-            abi::S_IFIFO => abi::DT_FIFO,
-            _ => abi::DT_UNKNOWN,
-        })
+        Ok(dirent_type(self.get_inode(inode)?.stat.st_mode))
     }
 
     fn open_directory(
@@ -284,6 +1018,131 @@ impl<'s> Filesystem {
         }
         builder.finish()
     }
+
+    fn open_host_directory(
+        &self,
+        num: INodeNum,
+        host: &HostEntry,
+    ) -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
+        let parent = self.resolve_path_segment(&mut Limits::reset(), num, OsStr::new(".."))?;
+        let mut builder = DirectoryFileBuilder::new()?;
+        builder.append(b".", num as u64, abi::DT_DIR)?;
+        builder.append(b"..", parent.child as u64, abi::DT_DIR)?;
+        for name in host.dir.list(&host.path)? {
+            let path = host.path.join(&name);
+            // Entries can vanish between listing and stat, like on any live
+            // directory
+            let file_type = match host.dir.stat(&path) {
+                Ok(stat) => dirent_type(stat.st_mode),
+                Err(VFSError::NotFound) => continue,
+                Err(err) => return Err(err),
+            };
+            let child = self.host_inode(host, path);
+            builder.append(name.as_bytes(), child as u64, file_type)?;
+        }
+        builder.finish()
+    }
+}
+
+impl FileType {
+    fn from_mode(mode: u32) -> Self {
+        match mode & abi::S_IFMT {
+            abi::S_IFSOCK => FileType::Socket,
+            abi::S_IFLNK => FileType::SymbolicLink,
+            abi::S_IFREG => FileType::File,
+            abi::S_IFBLK => FileType::BlockDevice,
+            abi::S_IFDIR => FileType::Directory,
+            abi::S_IFCHR => FileType::CharDevice,
+            abi::S_IFIFO => FileType::Fifo,
+            _ => FileType::Unknown,
+        }
+    }
+}
+
+impl<'f> DirEntry<'f> {
+    /// Name of this entry within its directory
+    pub fn name(&self) -> &'f OsStr {
+        self.name
+    }
+
+    /// The file this entry refers to, for use with other [Filesystem] calls
+    pub fn file(&self) -> &VFile {
+        &self.file
+    }
+
+    /// Metadata for the file this entry refers to
+    pub fn stat(&self) -> &'f FileStat {
+        self.stat
+    }
+
+    /// The kind of file this entry refers to, according to its mode
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.stat.st_mode)
+    }
+}
+
+impl<'f> Iterator for ReadDir<'f> {
+    type Item = Result<DirEntry<'f>, VFSError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (name, inode) = self.iter.next()?;
+        Some(self.fs.get_inode(*inode).map(|node| DirEntry {
+            name: name.as_os_str(),
+            file: VFile { inode: *inode },
+            stat: &node.stat,
+        }))
+    }
+}
+
+fn is_volume_inode(num: INodeNum) -> bool {
+    (VOLUME_INODE_BASE..HOST_INODE_BASE).contains(&num)
+}
+
+fn dirent_type(mode: u32) -> u8 {
+    match mode & abi::S_IFMT {
+        abi::S_IFSOCK => abi::DT_SOCK,
+        abi::S_IFLNK => abi::DT_LNK,
+        abi::S_IFREG => abi::DT_REG,
+        abi::S_IFBLK => abi::DT_BLK,
+        abi::S_IFDIR => abi::DT_DIR,
+        abi::S_IFCHR => abi::DT_CHR,
+        abi::S_IFIFO => abi::DT_FIFO,
+        _ => abi::DT_UNKNOWN,
+    }
+}
+
+impl NegativeLookups {
+    fn contains(&self, fs: &Filesystem, key: &NegativeLookupKey) -> bool {
+        match self.entries.lock().unwrap().get(key) {
+            None => false,
+            Some(visited) => visited
+                .iter()
+                .all(|(num, inode)| match fs.shared_inode(*num) {
+                    Some(current) => Arc::ptr_eq(current, inode),
+                    None => false,
+                }),
+        }
+    }
+
+    fn insert(&self, fs: &Filesystem, key: NegativeLookupKey, visited: &[INodeNum]) {
+        // Segments resolved by the host aren't visited, so the starting
+        // directory needs checking too
+        let mut inodes = Vec::with_capacity(visited.len() + 1);
+        for num in iter::once(&key.0).chain(visited) {
+            match fs.shared_inode(*num) {
+                // Files can appear in a bind mount at any time
+                _ if *num >= HOST_INODE_BASE => return,
+                Some(inode) if matches!(inode.data, Node::HostDirectory(_, _)) => return,
+                Some(inode) => inodes.push((*num, inode.clone())),
+                None => return,
+            }
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= NEGATIVE_LOOKUPS_MAX {
+            entries.clear();
+        }
+        entries.insert(key, inodes);
+    }
 }
 
 impl<'f> VFSWriter<'f> {
@@ -294,6 +1153,9 @@ impl<'f> VFSWriter<'f> {
     }
 
     fn get_inode_mut(&mut self, num: INodeNum) -> Result<&mut INode, VFSError> {
+        if is_volume_inode(num) {
+            return Err(VFSError::ReadOnly);
+        }
         match self.fs.inodes.get_mut(num) {
             None => Err(VFSError::UnallocNode),
             Some(slice) => match slice {
@@ -326,6 +1188,10 @@ impl<'f> VFSWriter<'f> {
     }
 
     fn inode_incref(&mut self, num: INodeNum) -> Result<(), VFSError> {
+        // Volumes can't change, so their link counts stay as they are
+        if is_volume_inode(num) {
+            return Ok(());
+        }
         let mut stat = &mut self.get_inode_mut(num)?.stat;
         match stat.st_nlink.checked_add(1) {
             None => Err(VFSError::INodeRefCountError),
@@ -337,11 +1203,25 @@ impl<'f> VFSWriter<'f> {
     }
 
     fn inode_decref(&mut self, num: INodeNum) -> Result<(), VFSError> {
+        if is_volume_inode(num) {
+            return Ok(());
+        }
         let mut stat = &mut self.get_inode_mut(num)?.stat;
         match stat.st_nlink.checked_sub(1) {
             None => Err(VFSError::INodeRefCountError),
             Some(count) => {
                 stat.st_nlink = count;
+                let tmpfs = match &self.fs.get_inode(num)?.data {
+                    Node::MemFile(_, tmpfs) if count == 0 => *tmpfs,
+                    _ => None,
+                };
+                if let Some(root) = tmpfs {
+                    self.fs.tmpfs_usage_mut(root)?.sizes.remove(num);
+                }
+                if count == 0 {
+                    self.fs.memfiles.sizes.remove(num);
+                    self.fs.memfiles.changing.remove(&num);
+                }
                 Ok(())
             }
         }
@@ -436,36 +1316,196 @@ impl<'f> VFSWriter<'f> {
         )
     }
 
-    pub fn write_shared_stream(
+    pub fn write_sparse_file(
         &mut self,
         path: &Path,
         stat: FileStat,
-        stream: SharedStream,
+        data: StorageKey,
+        map: SparseMap,
     ) -> Result<(), VFSError> {
-        self.write_node_file(path, stat, Node::SharedStream(stream))
+        self.write_node_file(path, stat, Node::SparseFile(data, Arc::new(map)))
     }
 
-    pub fn write_symlink(
+    /// Point file contents at a volume's storage, before the volume is shared
+    pub(crate) fn attach_volume_files(&mut self, files: Arc<VolumeFiles>) {
+        for inode in self.fs.inodes.iter_mut().flatten() {
+            let data = match &inode.data {
+                Node::FileStorage(key) => Node::VolumeFile(files.clone(), key.clone(), None),
+                Node::SparseFile(key, map) => {
+                    Node::VolumeFile(files.clone(), key.clone(), Some(map.clone()))
+                }
+                _ => continue,
+            };
+            Arc::make_mut(inode).data = data;
+        }
+    }
+
+    /// Place an empty tmpfs mount at `path`, replacing anything there
+    pub(crate) fn mount_tmpfs(
         &mut self,
         path: &Path,
-        stat: FileStat,
-        link_to: CString,
+        quota: u64,
+        mode: u32,
     ) -> Result<(), VFSError> {
-        self.write_node_file(path, stat, Node::SymbolicLink(link_to))
+        let mut limits = Limits::reset();
+        let (parent, name) = self.resolve_or_create_parent(&mut limits, path)?;
+        let num = self.alloc_child_directory(parent, name)?;
+        self.get_inode_mut(num)?.stat.st_mode = abi::S_IFDIR | mode;
+        self.fs.tmpfs.insert(
+            num,
+            TmpfsUsage {
+                quota,
+                ..Default::default()
+            },
+        );
+        Ok(())
     }
 
-    pub fn write_hardlink(&mut self, path: &Path, link_to: &Path) -> Result<(), VFSError> {
+    /// Place a copy of another filesystem's whole tree at `path`
+    ///
+    /// Directories are copied with their inode numbers moved past the end of
+    /// this filesystem, and everything else is shared with the source. Any
+    /// existing file or directory at `path` is replaced. The source must not
+    /// refer to image storage, which belongs to only one filesystem.
+    pub(crate) fn graft(&mut self, path: &Path, source: &Filesystem) -> Result<(), VFSError> {
         let mut limits = Limits::reset();
-        let link_to_node = self
-            .fs
-            .resolve_path(&mut limits, self.workdir.inode, link_to)?
-            .child;
         let (dir, name) = self.resolve_or_create_parent(&mut limits, path)?;
-        self.add_child_to_directory(dir, name, link_to_node)?;
+        let base = self.fs.inodes.len();
+        for inode in &source.inodes {
+            self.fs.inodes.push(inode.as_ref().map(|inode| {
+                match &inode.data {
+                    Node::NormalDirectory(map) => Arc::new(INode {
+                        stat: inode.stat.clone(),
+                        data: Node::NormalDirectory(
+                            map.iter()
+                                .map(|(name, num)| (name.clone(), num + base))
+                                .collect(),
+                        ),
+                    }),
+                    _ => inode.clone(),
+                }
+            }));
+        }
+        let root = base + Filesystem::root().inode;
+        self.add_child_to_directory(root, OsStr::new(".."), dir)?;
+        self.add_child_to_directory(dir, name, root)?;
         Ok(())
     }
 
-    pub fn write_fifo(&mut self, path: &Path, stat: FileStat) -> Result<(), VFSError> {
+    /// Place a volume at `path`, replacing anything there
+    ///
+    /// The volume's inodes are shared rather than copied, so this takes as
+    /// long for a large volume as for a small one. Nothing in the volume can
+    /// be changed, and each filesystem can only mount a volume once.
+    pub(crate) fn mount_volume(
+        &mut self,
+        path: &Path,
+        tree: &Arc<VolumeTree>,
+    ) -> Result<(), VFSError> {
+        if self.fs.volumes.contains_key(&tree.base) {
+            return Err(VFSError::Busy);
+        }
+        let mut limits = Limits::reset();
+        let (dir, name) = self.resolve_or_create_parent(&mut limits, path)?;
+        let mut root = tree
+            .inodes
+            .get(Filesystem::root().inode)
+            .cloned()
+            .flatten()
+            .ok_or(VFSError::UnallocNode)?;
+        match &mut Arc::make_mut(&mut root).data {
+            Node::NormalDirectory(map) => map.insert(OsString::from(".."), dir),
+            _ => Err(VFSError::DirectoryExpected)?,
+        };
+        let volume = MountedVolume {
+            tree: tree.clone(),
+            root,
+        };
+        self.fs.volumes.insert(tree.base, volume);
+        self.inode_incref(dir)?;
+        self.add_child_to_directory(dir, name, tree.base)
+    }
+
+    /// Place the root of a bind mount at `path`, replacing anything there
+    pub(crate) fn mount_host_directory(
+        &mut self,
+        path: &Path,
+        dir: Arc<HostDir>,
+    ) -> Result<(), VFSError> {
+        let stat = dir.stat(Path::new(""))?;
+        if stat.st_mode & abi::S_IFMT != abi::S_IFDIR {
+            return Err(VFSError::DirectoryExpected);
+        }
+        let mut limits = Limits::reset();
+        let (parent, name) = self.resolve_or_create_parent(&mut limits, path)?;
+        let num = self.alloc_inode_number();
+        self.put_inode(
+            num,
+            INode {
+                stat: FileStat {
+                    st_nlink: 0,
+                    ..stat
+                },
+                data: Node::HostDirectory(dir, parent),
+            },
+        );
+        self.add_child_to_directory(parent, name, num)?;
+        // Copies made before this mount may number their own mounts the same
+        let table = self.fs.host_entries.entries.lock().unwrap().clone();
+        self.fs.host_entries = Arc::new(HostEntries {
+            entries: Mutex::new(table),
+        });
+        Ok(())
+    }
+
+    pub fn write_shared_stream(
+        &mut self,
+        path: &Path,
+        stat: FileStat,
+        stream: SharedStream,
+    ) -> Result<(), VFSError> {
+        self.write_node_file(path, stat, Node::SharedStream(stream))
+    }
+
+    pub fn write_file_bytes(
+        &mut self,
+        path: &Path,
+        stat: FileStat,
+        data: Bytes,
+    ) -> Result<(), VFSError> {
+        self.write_node_file(path, stat, Node::Bytes(data))
+    }
+
+    pub fn write_symlink(
+        &mut self,
+        path: &Path,
+        stat: FileStat,
+        link_to: CString,
+    ) -> Result<(), VFSError> {
+        self.write_node_file(path, stat, Node::SymbolicLink(link_to))
+    }
+
+    pub fn write_hardlink(&mut self, path: &Path, link_to: &Path) -> Result<(), VFSError> {
+        let mut limits = Limits::reset();
+        let link_to_node = self
+            .fs
+            .resolve_path(&mut limits, self.workdir.inode, link_to)?
+            .child;
+        let (dir, name) = self.resolve_or_create_parent(&mut limits, path)?;
+        self.add_child_to_directory(dir, name, link_to_node)?;
+        Ok(())
+    }
+
+    pub fn write_proc_node(
+        &mut self,
+        path: &Path,
+        stat: FileStat,
+        node: ProcNode,
+    ) -> Result<(), VFSError> {
+        self.write_node_file(path, stat, Node::Proc(node))
+    }
+
+    pub fn write_fifo(&mut self, path: &Path, stat: FileStat) -> Result<(), VFSError> {
         self.write_node_file(path, stat, Node::Fifo)
     }
 
@@ -489,6 +1529,305 @@ impl<'f> VFSWriter<'f> {
         self.write_node_file(path, stat, Node::Block(major, minor))
     }
 
+    /// Remove whatever an earlier layer left at `path`, if anything
+    pub fn write_whiteout(&mut self, path: &Path) -> Result<(), VFSError> {
+        let mut limits = Limits::reset();
+        let (dir, name) = match self.resolve_existing_parent(&mut limits, path) {
+            Err(VFSError::NotFound) => return Ok(()),
+            result => result?,
+        };
+        if self.directory_entry(dir, name)?.is_some() {
+            self.remove_entry(dir, name)?;
+        }
+        Ok(())
+    }
+
+    /// Remove everything earlier layers left inside the directory at `path`
+    ///
+    /// Like overlayfs, this never follows a symbolic link at `path`. A link
+    /// or anything else that isn't a directory is replaced by an empty one.
+    pub fn write_opaque_directory(&mut self, path: &Path) -> Result<(), VFSError> {
+        let dir = if path.file_name().is_none() {
+            self.workdir.inode
+        } else {
+            let mut limits = Limits::reset();
+            let (parent, name) = match self.resolve_existing_parent(&mut limits, path) {
+                Err(VFSError::NotFound) => return Ok(()),
+                result => result?,
+            };
+            match self.directory_entry(parent, name)? {
+                None => return Ok(()),
+                Some(child) if self.is_empty_directory(child)?.is_some() => child,
+                Some(_) => {
+                    self.remove_entry(parent, name)?;
+                    return self.alloc_child_directory(parent, name).map(|_| ());
+                }
+            }
+        };
+        let names: Vec<OsString> = match &self.fs.get_inode(dir)?.data {
+            Node::NormalDirectory(map) => map
+                .keys()
+                .filter(|name| *name != "." && *name != "..")
+                .cloned()
+                .collect(),
+            _ => return Err(VFSError::DirectoryExpected),
+        };
+        for name in names {
+            self.remove_entry(dir, &name)?;
+        }
+        Ok(())
+    }
+
+    fn remove_entry(&mut self, dir: INodeNum, name: &OsStr) -> Result<(), VFSError> {
+        let child = self.remove_child_from_directory(dir, name)?;
+        if self.is_empty_directory(child)?.is_some() {
+            self.remove_child_from_directory(child, OsStr::new(".."))?;
+        }
+        Ok(())
+    }
+
+    /// Find the directory that would hold `path`, without creating anything
+    fn resolve_existing_parent<'b>(
+        &self,
+        limits: &mut Limits,
+        path: &'b Path,
+    ) -> Result<(INodeNum, &'b OsStr), VFSError> {
+        self.resolve_existing_parent_at(limits, self.workdir.inode, path)
+    }
+
+    fn resolve_existing_parent_at<'b>(
+        &self,
+        mut limits: &mut Limits,
+        workdir: INodeNum,
+        path: &'b Path,
+    ) -> Result<(INodeNum, &'b OsStr), VFSError> {
+        let dir = match path.parent() {
+            Some(parent) => {
+                let entry = self.fs.resolve_path(&mut limits, workdir, parent)?;
+                self.fs.resolve_symlinks(&mut limits, entry)?.child
+            }
+            None => workdir,
+        };
+        let name = path.file_name().ok_or(VFSError::ReservedName)?;
+        if self.fs.host_entry(dir)?.is_none() {
+            self.directory_entry(dir, name)?;
+        }
+        Ok((dir, name))
+    }
+
+    fn directory_entry(&self, dir: INodeNum, name: &OsStr) -> Result<Option<INodeNum>, VFSError> {
+        match &self.fs.get_inode(dir)?.data {
+            Node::NormalDirectory(map) => Ok(map.get(name).copied()),
+            _ => Err(VFSError::DirectoryExpected),
+        }
+    }
+
+    fn is_empty_directory(&self, num: INodeNum) -> Result<Option<bool>, VFSError> {
+        match &self.fs.get_inode(num)?.data {
+            Node::NormalDirectory(map) => {
+                Ok(Some(map.keys().all(|name| name == "." || name == "..")))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn remove_child_from_directory(
+        &mut self,
+        parent: INodeNum,
+        child_name: &OsStr,
+    ) -> Result<INodeNum, VFSError> {
+        let removed = match &mut self.get_inode_mut(parent)?.data {
+            Node::NormalDirectory(map) => map.remove(child_name),
+            _ => Err(VFSError::DirectoryExpected)?,
+        };
+        let child = removed.ok_or(VFSError::NotFound)?;
+        self.inode_decref(child)?;
+        Ok(child)
+    }
+
+    // The calls below carry out changes made by the guest. Together with the
+    // image's own inodes, which each container shares until it writes to them,
+    // they act as the upper layer of an overlay: changes never reach the image
+    // or other containers. Removing an entry hides the image's copy the way an
+    // overlayfs whiteout would, and a directory created in its place starts
+    // out empty, like an opaque directory. Unlike the image writer, these
+    // never create missing parent directories or replace existing entries.
+
+    // Inside a bind mount, each call goes straight to the host instead.
+
+    /// Create a new empty directory, failing if anything is already there
+    pub fn create_directory(&mut self, path: &Path, stat: FileStat) -> Result<VFile, VFSError> {
+        let mut limits = Limits::reset();
+        let (dir, name) = self.resolve_existing_parent(&mut limits, path)?;
+        if let Some(host) = self.fs.host_entry(dir)? {
+            let path = host.path.join(name);
+            host.dir.create_dir(&path, stat.st_mode)?;
+            let inode = self.fs.host_inode(&host, path);
+            return Ok(VFile { inode });
+        }
+        if self.directory_entry(dir, name)?.is_some() {
+            return Err(VFSError::AlreadyExists);
+        }
+        self.fs.check_writable(dir)?;
+        if let Some(root) = self.fs.tmpfs_containing(dir)? {
+            self.fs.check_tmpfs_space(root)?;
+        }
+        let num = self.alloc_child_directory(dir, name)?;
+        let inode = self.get_inode_mut(num)?;
+        inode.stat = FileStat {
+            st_nlink: inode.stat.st_nlink,
+            ..stat
+        };
+        Ok(VFile { inode: num })
+    }
+
+    /// Create a new empty regular file, failing if anything is already there
+    ///
+    /// The file is kept in memory unless the guest already has too much
+    /// there, in which case it goes to an unlinked file in the cache directory.
+    pub fn create_file(
+        &mut self,
+        storage: &FileStorage,
+        path: &Path,
+        stat: FileStat,
+    ) -> Result<VFile, VFSError> {
+        let mut limits = Limits::reset();
+        let (dir, name) = self.resolve_existing_parent(&mut limits, path)?;
+        if let Some(host) = self.fs.host_entry(dir)? {
+            let path = host.path.join(name);
+            host.dir.create_file(&path, stat.st_mode)?;
+            let inode = self.fs.host_inode(&host, path);
+            return Ok(VFile { inode });
+        }
+        if self.directory_entry(dir, name)?.is_some() {
+            return Err(VFSError::AlreadyExists);
+        }
+        self.fs.check_writable(dir)?;
+        let tmpfs = self.fs.tmpfs_containing(dir)?;
+        if let Some(root) = tmpfs {
+            self.fs.check_tmpfs_space(root)?;
+        }
+        let num = self.alloc_inode_number();
+        let file = self.fs.new_guest_file(storage, num, false)?;
+        let data = Node::MemFile(Arc::new(file), tmpfs);
+        self.put_inode(num, INode { stat, data });
+        self.add_child_to_directory(dir, name, num)?;
+        Ok(VFile { inode: num })
+    }
+
+    /// Remove a directory entry, as unlink() or, with `remove_dir`, rmdir()
+    ///
+    /// The inode itself stays allocated, so files the guest already has open
+    /// keep working.
+    pub fn unlink(&mut self, path: &Path, remove_dir: bool) -> Result<(), VFSError> {
+        let mut limits = Limits::reset();
+        let (dir, name) = self.resolve_existing_parent(&mut limits, path)?;
+        if let Some(host) = self.fs.host_entry(dir)? {
+            return host.dir.unlink(&host.path.join(name), remove_dir);
+        }
+        let child = self.directory_entry(dir, name)?.ok_or(VFSError::NotFound)?;
+        if self.fs.is_mount_point(&VFile { inode: child })? {
+            return Err(VFSError::Busy);
+        }
+        self.fs.check_writable(dir)?;
+        match (self.is_empty_directory(child)?, remove_dir) {
+            (Some(true), true) => {
+                self.remove_child_from_directory(child, OsStr::new(".."))?;
+            }
+            (Some(false), true) => return Err(VFSError::DirectoryNotEmpty),
+            (None, true) => return Err(VFSError::DirectoryExpected),
+            (Some(_), false) => return Err(VFSError::FileExpected),
+            (None, false) => {}
+        }
+        self.remove_child_from_directory(dir, name)?;
+        Ok(())
+    }
+
+    /// Move a directory entry, replacing whatever was at the destination
+    ///
+    /// Follows the rules of rename(): a directory can only replace an empty
+    /// directory, anything else can only replace a non-directory, and a
+    /// directory can't move inside itself. Nothing moves into or out of a
+    /// bind mount or tmpfs mount, or replaces one.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<(), VFSError> {
+        let to_workdir = self.workdir.clone();
+        self.rename_to(from, &to_workdir, to)
+    }
+
+    /// Like [rename()](VFSWriter::rename), with a relative `to` starting at
+    /// `to_workdir` instead of this writer's working directory, as renameat()
+    pub fn rename_to(
+        &mut self,
+        from: &Path,
+        to_workdir: &VFile,
+        to: &Path,
+    ) -> Result<(), VFSError> {
+        let mut limits = Limits::reset();
+        let (from_dir, from_name) = self.resolve_existing_parent(&mut limits, from)?;
+        let mut limits = Limits::reset();
+        let (to_dir, to_name) =
+            self.resolve_existing_parent_at(&mut limits, to_workdir.inode, to)?;
+        match (self.fs.host_entry(from_dir)?, self.fs.host_entry(to_dir)?) {
+            (None, None) => {}
+            (Some(from_host), Some(to_host)) if from_host.mount == to_host.mount => {
+                return from_host
+                    .dir
+                    .rename(&from_host.path.join(from_name), &to_host.path.join(to_name));
+            }
+            _ => return Err(VFSError::CrossDevice),
+        }
+        if self.fs.tmpfs_containing(from_dir)? != self.fs.tmpfs_containing(to_dir)? {
+            return Err(VFSError::CrossDevice);
+        }
+        self.fs.check_writable(from_dir)?;
+        self.fs.check_writable(to_dir)?;
+        let child = self
+            .directory_entry(from_dir, from_name)?
+            .ok_or(VFSError::NotFound)?;
+        if self.fs.is_mount_point(&VFile { inode: child })? {
+            return Err(VFSError::Busy);
+        }
+        let moving_dir = self.is_empty_directory(child)?.is_some();
+
+        if moving_dir {
+            let mut ancestor = Some(to_dir);
+            while let Some(dir) = ancestor {
+                if dir == child {
+                    return Err(VFSError::InvalidRename);
+                }
+                ancestor = match self.directory_entry(dir, OsStr::new(".."))? {
+                    Some(parent) if parent != dir => Some(parent),
+                    _ => None,
+                };
+            }
+        }
+
+        if let Some(existing) = self.directory_entry(to_dir, to_name)? {
+            if existing == child {
+                return Ok(());
+            }
+            if self.fs.is_mount_point(&VFile { inode: existing })? {
+                return Err(VFSError::Busy);
+            }
+            match (moving_dir, self.is_empty_directory(existing)?) {
+                (true, Some(true)) => {
+                    self.remove_child_from_directory(existing, OsStr::new(".."))?;
+                }
+                (true, Some(false)) => return Err(VFSError::DirectoryNotEmpty),
+                (true, None) => return Err(VFSError::DirectoryExpected),
+                (false, Some(_)) => return Err(VFSError::FileExpected),
+                (false, None) => {}
+            }
+        }
+
+        self.add_child_to_directory(to_dir, to_name, child)?;
+        self.remove_child_from_directory(from_dir, from_name)?;
+        if moving_dir && from_dir != to_dir {
+            self.add_child_to_directory(child, OsStr::new(".."), to_dir)?;
+        }
+        Ok(())
+    }
+
     fn resolve_or_create_path_segment(
         &mut self,
         mut limits: &mut Limits,
@@ -529,24 +1868,140 @@ impl<'f> VFSWriter<'f> {
     }
 }
 
+/// Metadata for an inode, with the size of guest-written files kept current
+fn current_stat(node: &INode) -> Result<FileStat, VFSError> {
+    let mut stat = node.stat.clone();
+    if let Node::MemFile(file, _) = &node.data {
+        stat.st_size = file.metadata().map_err(|_| VFSError::IO)?.len() as i64;
+    }
+    Ok(stat)
+}
+
+/// Read a whole file, or give None if it's longer than `limit`
+fn read_up_to(file: File, size: usize, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut contents = Vec::with_capacity(size.min(limit));
+    file.take(limit as u64 + 1).read_to_end(&mut contents)?;
+    if contents.len() > limit {
+        Ok(None)
+    } else {
+        Ok(Some(contents))
+    }
+}
+
+/// Open a new description of the file contents behind a node, if it has any
+async fn open_contents(storage: &FileStorage, data: &Node) -> Result<Option<File>, VFSError> {
+    let file = match data {
+        Node::FileStorage(key) => storage.open_part(key).await,
+        Node::SparseFile(key, map) => storage.open_sparse(key, map).await,
+        Node::VolumeFile(files, key, map) => {
+            return Ok(Some(files.open(key, map.as_deref()).await?))
+        }
+        Node::MemFile(file, _) => return Ok(Some(reopen_memfile(file, libc::O_RDONLY)?)),
+        _ => return Ok(None),
+    };
+    Ok(Some(
+        file.map_err(storage_error)?
+            .ok_or(VFSError::ImageStorageError)?,
+    ))
+}
+
+fn new_memfile() -> Result<File, VFSError> {
+    let memfd = memfd::MemfdOptions::default()
+        .create("bandsocks-file")
+        .map_err(|_| VFSError::IO)?;
+    Ok(memfd.into_file())
+}
+
+/// Open a guest-written file again, with its own offset and access mode
+fn reopen_memfile(file: &File, flags: i32) -> Result<File, VFSError> {
+    let access = flags & libc::O_ACCMODE;
+    OpenOptions::new()
+        .read(access != libc::O_WRONLY)
+        .write(access != libc::O_RDONLY)
+        .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .map_err(|_| VFSError::IO)
+}
+
 fn open_null() -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
     Ok(Arc::new(
         File::open("/dev/null").map_err(|_| VFSError::ImageStorageError)?,
     ))
 }
 
-async fn open_storage_part(
+fn open_bytes(data: &[u8]) -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
+    let memfd = memfd::MemfdOptions::default()
+        .allow_sealing(true)
+        .create("bandsocks-bytes")
+        .map_err(|_| VFSError::ImageStorageError)?;
+    memfd
+        .as_file()
+        .write_all(data)
+        .map_err(|_| VFSError::ImageStorageError)?;
+    Ok(Arc::new(seal_memfd(memfd)?))
+}
+
+fn seal_memfd(memfd: memfd::Memfd) -> Result<File, VFSError> {
+    memfd
+        .add_seals(
+            &[
+                memfd::FileSeal::SealWrite,
+                memfd::FileSeal::SealShrink,
+                memfd::FileSeal::SealGrow,
+                memfd::FileSeal::SealSeal,
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        )
+        .map_err(|_| VFSError::ImageStorageError)?;
+    let mut file = memfd.into_file();
+    file.seek(SeekFrom::Start(0))
+        .map_err(|_| VFSError::ImageStorageError)?;
+    Ok(file)
+}
+
+/// How much of a file to start reading ahead as soon as a guest opens it
+const READAHEAD_INITIAL: i64 = 8 * 1024 * 1024;
+
+async fn open_storage_part(storage: &FileStorage, key: &StorageKey) -> Result<File, VFSError> {
+    storage
+        .open_part(key)
+        .await
+        .map_err(storage_error)?
+        .ok_or(VFSError::ImageStorageError)
+}
+
+async fn open_sparse_part(
     storage: &FileStorage,
     key: &StorageKey,
-) -> Result<Arc<dyn AsRawFd + Sync + Send>, VFSError> {
-    Ok(Arc::new(
-        storage
-            .open_part(key)
-            .await
-            .ok()
-            .flatten()
-            .ok_or(VFSError::ImageStorageError)?,
-    ))
+    map: &SparseMap,
+) -> Result<File, VFSError> {
+    storage
+        .open_sparse(key, map)
+        .await
+        .map_err(storage_error)?
+        .ok_or(VFSError::ImageStorageError)
+}
+
+pub(crate) fn storage_error(err: ImageError) -> VFSError {
+    match err {
+        ImageError::StorageCorrupted(_) => VFSError::ImageStorageCorrupted,
+        _ => VFSError::ImageStorageError,
+    }
+}
+
+/// Ask the kernel for aggressive readahead on a file the guest will stream
+///
+/// This is the open file the guest gets, passed along as-is, so the advice
+/// applies to its own reads. Starting readahead now overlaps the first disk
+/// reads with the rest of exec or open. Both calls are only hints, and errors
+/// are not worth reporting, including from files that can't be advised.
+fn advise_sequential(file: &File) {
+    let fd = file.as_raw_fd();
+    unsafe {
+        libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+        libc::posix_fadvise(fd, 0, READAHEAD_INITIAL, libc::POSIX_FADV_WILLNEED);
+    }
 }
 
 struct DirectoryFileBuilder {
@@ -570,24 +2025,7 @@ impl DirectoryFileBuilder {
             .into_inner()
             .map_err(|_| VFSError::ImageStorageError)?;
         let memfd = memfd::Memfd::try_from_file(memfd).left().unwrap();
-        memfd
-            .add_seals(
-                &[
-                    memfd::FileSeal::SealWrite,
-                    memfd::FileSeal::SealShrink,
-                    memfd::FileSeal::SealGrow,
-                    memfd::FileSeal::SealSeal,
-                ]
-                .iter()
-                .cloned()
-                .collect(),
-            )
-            .map_err(|_| VFSError::ImageStorageError)?;
-        let mut memfd = memfd.into_file();
-        memfd
-            .seek(SeekFrom::Start(0))
-            .map_err(|_| VFSError::ImageStorageError)?;
-        Ok(Arc::new(memfd))
+        Ok(Arc::new(seal_memfd(memfd)?))
     }
 
     fn append(&mut self, name: &[u8], d_ino: u64, d_type: u8) -> Result<(), VFSError> {
@@ -626,3 +2064,466 @@ impl DirectoryFileBuilder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::mount::Mount;
+    use std::os::unix::fs::MetadataExt;
+    use tokio::runtime::Runtime;
+
+    fn file_stat() -> FileStat {
+        FileStat {
+            st_mode: abi::S_IFREG | 0o644,
+            ..Default::default()
+        }
+    }
+
+    fn link_stat() -> FileStat {
+        FileStat {
+            st_mode: abi::S_IFLNK | 0o777,
+            ..Default::default()
+        }
+    }
+
+    fn image() -> Filesystem {
+        let mut fs = Filesystem::new();
+        fs.writer()
+            .write_file_bytes(
+                Path::new("/usr/lib/python3/os.py"),
+                file_stat(),
+                Bytes::from_static(b"import sys\n"),
+            )
+            .unwrap();
+        fs
+    }
+
+    fn lookup(fs: &Filesystem, path: &str) -> Result<VFile, VFSError> {
+        fs.lookup(&Filesystem::root(), Path::new(path), &FollowLinks::Follow)
+    }
+
+    #[test]
+    fn canonicalize_through_symlinks() {
+        let mut fs = image();
+        let mut writer = fs.writer();
+        writer
+            .write_symlink(
+                Path::new("/usr/lib/python"),
+                link_stat(),
+                CString::new("python3").unwrap(),
+            )
+            .unwrap();
+        writer
+            .write_symlink(
+                Path::new("/lib"),
+                link_stat(),
+                CString::new("usr/lib").unwrap(),
+            )
+            .unwrap();
+        writer
+            .write_symlink(
+                Path::new("/usr/lib/python3/link.py"),
+                link_stat(),
+                CString::new("../python/os.py").unwrap(),
+            )
+            .unwrap();
+        let canonical = |path: &str| fs.canonicalize(Path::new(path)).unwrap();
+        assert_eq!(canonical("/"), Path::new("/"));
+        assert_eq!(canonical("lib"), Path::new("/usr/lib"));
+        assert_eq!(canonical("/lib/python/"), Path::new("/usr/lib/python3"));
+        assert_eq!(
+            canonical("/lib/python/../python3/./link.py"),
+            Path::new("/usr/lib/python3/os.py")
+        );
+        assert!(matches!(
+            fs.canonicalize(Path::new("/lib/python/missing.py")),
+            Err(VFSError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn directory_path_of_removed_dir() {
+        let mut fs = image();
+        let python = lookup(&fs, "/usr/lib/python3").unwrap();
+        assert_eq!(
+            fs.directory_path(&Filesystem::root()).unwrap(),
+            Path::new("/")
+        );
+        assert_eq!(
+            fs.directory_path(&python).unwrap(),
+            Path::new("/usr/lib/python3")
+        );
+        assert!(matches!(
+            fs.directory_path(&lookup(&fs, "/usr/lib/python3/os.py").unwrap()),
+            Err(VFSError::DirectoryExpected)
+        ));
+        let mut writer = fs.writer();
+        writer
+            .unlink(Path::new("/usr/lib/python3/os.py"), false)
+            .unwrap();
+        writer.unlink(Path::new("/usr/lib/python3"), true).unwrap();
+        assert!(matches!(
+            fs.directory_path(&python),
+            Err(VFSError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn read_dir_lists_entries() {
+        let mut fs = image();
+        fs.writer()
+            .write_symlink(
+                Path::new("/usr/lib/python3/link.py"),
+                link_stat(),
+                CString::new("os.py").unwrap(),
+            )
+            .unwrap();
+        let dir = lookup(&fs, "/usr/lib/python3").unwrap();
+        let entries: Vec<_> = fs
+            .read_dir(&dir)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.name().to_os_string(), entry.file_type())
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (OsString::from("."), FileType::Directory),
+                (OsString::from(".."), FileType::Directory),
+                (OsString::from("link.py"), FileType::SymbolicLink),
+                (OsString::from("os.py"), FileType::File),
+            ]
+        );
+        let file = lookup(&fs, "/usr/lib/python3/os.py").unwrap();
+        assert!(matches!(
+            fs.read_dir(&file),
+            Err(VFSError::DirectoryExpected)
+        ));
+    }
+
+    #[test]
+    fn missing_path_is_cached() {
+        let fs = image();
+        let container = fs.clone();
+        assert!(matches!(
+            lookup(&fs, "/usr/lib/python3/missing.py"),
+            Err(VFSError::NotFound)
+        ));
+        assert!(container.negative_lookups.contains(
+            &container,
+            &(
+                Filesystem::root().inode,
+                PathBuf::from("/usr/lib/python3/missing.py"),
+                true
+            )
+        ));
+        assert!(matches!(
+            lookup(&container, "/usr/lib/python3/missing.py"),
+            Err(VFSError::NotFound)
+        ));
+        lookup(&container, "/usr/lib/python3/os.py").unwrap();
+    }
+
+    #[test]
+    fn mount_shadows_cached_path() {
+        let fs = image();
+        let mut container = fs.clone();
+        assert!(matches!(
+            lookup(&fs, "/usr/lib/python3/site.py"),
+            Err(VFSError::NotFound)
+        ));
+        container
+            .writer()
+            .write_file_bytes(
+                Path::new("/usr/lib/python3/site.py"),
+                file_stat(),
+                Bytes::from_static(b"\n"),
+            )
+            .unwrap();
+        lookup(&container, "/usr/lib/python3/site.py").unwrap();
+        assert!(matches!(
+            lookup(&fs, "/usr/lib/python3/site.py"),
+            Err(VFSError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn mount_shadows_cached_parent() {
+        let fs = image();
+        let mut container = fs.clone();
+        assert!(matches!(
+            lookup(&fs, "/opt/app/main.py"),
+            Err(VFSError::NotFound)
+        ));
+        container
+            .writer()
+            .write_file_bytes(
+                Path::new("/opt/app/main.py"),
+                file_stat(),
+                Bytes::from_static(b"\n"),
+            )
+            .unwrap();
+        lookup(&container, "/opt/app/main.py").unwrap();
+    }
+
+    #[test]
+    fn guest_changes_stay_in_container() {
+        let temp = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(temp.path().to_path_buf(), None);
+        let fs = image();
+        let mut container = fs.clone();
+        let python = lookup(&container, "/usr/lib/python3").unwrap();
+        let mut writer = container.writer_at(&python);
+        let cache = Path::new("__pycache__");
+        writer.create_directory(cache, Default::default()).unwrap();
+        assert!(matches!(
+            writer.create_directory(cache, Default::default()),
+            Err(VFSError::AlreadyExists)
+        ));
+        writer
+            .create_file(&storage, Path::new("__pycache__/os.pyc"), file_stat())
+            .unwrap();
+        assert!(matches!(
+            writer.create_file(&storage, Path::new("missing/os.pyc"), file_stat()),
+            Err(VFSError::NotFound)
+        ));
+        assert!(matches!(
+            writer.unlink(cache, true),
+            Err(VFSError::DirectoryNotEmpty)
+        ));
+        assert!(matches!(
+            writer.unlink(cache, false),
+            Err(VFSError::FileExpected)
+        ));
+        writer
+            .rename(Path::new("os.py"), Path::new("__pycache__/os.py"))
+            .unwrap();
+        assert!(matches!(
+            writer.rename(cache, Path::new("__pycache__/inner")),
+            Err(VFSError::InvalidRename)
+        ));
+        writer.rename(cache, Path::new("/cache")).unwrap();
+        writer.unlink(Path::new("/cache/os.pyc"), false).unwrap();
+        let root = Filesystem::root();
+        writer
+            .rename_to(Path::new("/cache/os.py"), &root, Path::new("cache/os2.py"))
+            .unwrap();
+        writer
+            .rename_to(Path::new("/cache/os2.py"), &root, Path::new("cache/os.py"))
+            .unwrap();
+
+        lookup(&container, "/cache/os.py").unwrap();
+        assert_eq!(
+            container.canonicalize(Path::new("/cache/..")).unwrap(),
+            Path::new("/")
+        );
+        assert!(matches!(
+            lookup(&container, "/usr/lib/python3/os.py"),
+            Err(VFSError::NotFound)
+        ));
+        lookup(&fs, "/usr/lib/python3/os.py").unwrap();
+        assert!(matches!(lookup(&fs, "/cache"), Err(VFSError::NotFound)));
+    }
+
+    #[test]
+    fn copy_up_leaves_image_alone() {
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let fs = image();
+        let mut container = fs.clone();
+        let os = lookup(&container, "/usr/lib/python3/os.py").unwrap();
+
+        assert!(container.has_fixed_contents(&os).unwrap());
+        Runtime::new().unwrap().block_on(async {
+            container.copy_up(&storage, &os, false).await.unwrap();
+            assert!(!container.has_fixed_contents(&os).unwrap());
+            assert!(fs.has_fixed_contents(&os).unwrap());
+            let file = container
+                .open_storage(&storage, &os, libc::O_WRONLY)
+                .await
+                .unwrap();
+            OpenOptions::new()
+                .append(true)
+                .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+                .unwrap()
+                .write_all(b"import os\n")
+                .unwrap();
+            let contents = container.read_small_file(&storage, &os, 4096).await;
+            assert_eq!(contents.unwrap().unwrap(), b"import sys\nimport os\n");
+            assert_eq!(container.stat(&os).unwrap().st_size, 21);
+            let contents = fs.read_small_file(&storage, &os, 4096).await;
+            assert_eq!(contents.unwrap().unwrap(), b"import sys\n");
+
+            container.copy_up(&storage, &os, true).await.unwrap();
+            let contents = container.read_small_file(&storage, &os, 4096).await;
+            assert_eq!(contents.unwrap().unwrap(), b"");
+        });
+    }
+
+    #[test]
+    fn copy_up_spills_large_files() {
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = image();
+        let size = MEMFILE_SPILL_SIZE as usize + 1;
+        fs.writer()
+            .write_file_bytes(
+                Path::new("/var/lib/big.db"),
+                FileStat {
+                    st_size: size as i64,
+                    ..file_stat()
+                },
+                Bytes::from(vec![0x5a; size]),
+            )
+            .unwrap();
+        let big = lookup(&fs, "/var/lib/big.db").unwrap();
+        let small = lookup(&fs, "/usr/lib/python3/os.py").unwrap();
+
+        fn backing(fs: &Filesystem, f: &VFile) -> PathBuf {
+            match &fs.get_inode(f.inode).unwrap().data {
+                Node::MemFile(file, _) => {
+                    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap()
+                }
+                _ => panic!("not copied up"),
+            }
+        }
+
+        Runtime::new().unwrap().block_on(async {
+            fs.copy_up(&storage, &big, false).await.unwrap();
+            fs.copy_up(&storage, &small, false).await.unwrap();
+            assert!(backing(&fs, &big).starts_with(cache.path()));
+            assert!(!backing(&fs, &small).starts_with(cache.path()));
+            assert_eq!(fs.stat(&big).unwrap().st_size, size as i64);
+            assert!(std::fs::read_dir(cache.path().join("tmp"))
+                .unwrap()
+                .next()
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn new_files_spill_once_memory_is_full() {
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = image();
+        let on_disk = |fs: &Filesystem, path: &str| {
+            let f = lookup(fs, path).unwrap();
+            match &fs.get_inode(f.inode).unwrap().data {
+                Node::MemFile(file, _) => {
+                    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+                        .unwrap()
+                        .starts_with(cache.path())
+                }
+                _ => panic!("not a guest file"),
+            }
+        };
+
+        // Growth through a descriptor the guest already has still counts
+        let first = fs
+            .writer()
+            .create_file(&storage, Path::new("/first"), file_stat())
+            .unwrap();
+        let fd = Runtime::new()
+            .unwrap()
+            .block_on(fs.open_storage(&storage, &first, libc::O_WRONLY))
+            .unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+            .unwrap()
+            .set_len(MEMFILE_SPILL_TOTAL)
+            .unwrap();
+        fs.writer()
+            .create_file(&storage, Path::new("/second"), file_stat())
+            .unwrap();
+        assert!(!on_disk(&fs, "/first"));
+        assert!(on_disk(&fs, "/second"));
+
+        // Removing it makes room again
+        fs.writer().unlink(Path::new("/first"), false).unwrap();
+        fs.writer()
+            .create_file(&storage, Path::new("/third"), file_stat())
+            .unwrap();
+        assert!(!on_disk(&fs, "/third"));
+    }
+
+    #[test]
+    fn read_only_outside_tmpfs() {
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = image();
+        fs.writer()
+            .mount_tmpfs(Path::new("/tmp"), 4096, 0o1777)
+            .unwrap();
+        fs.set_read_only(true);
+        let os_py = lookup(&fs, "/usr/lib/python3/os.py").unwrap();
+
+        let mut writer = fs.writer();
+        assert!(matches!(
+            writer.create_file(&storage, Path::new("/usr/lib/new.py"), file_stat()),
+            Err(VFSError::ReadOnly)
+        ));
+        assert!(matches!(
+            writer.unlink(Path::new("/usr/lib/python3/os.py"), false),
+            Err(VFSError::ReadOnly)
+        ));
+        assert!(matches!(
+            writer.rename(Path::new("/usr/lib/python3"), Path::new("/usr/lib/py")),
+            Err(VFSError::ReadOnly)
+        ));
+        let scratch = writer
+            .create_file(&storage, Path::new("/tmp/scratch"), file_stat())
+            .unwrap();
+        writer
+            .rename(Path::new("/tmp/scratch"), Path::new("/tmp/moved"))
+            .unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            assert!(matches!(
+                fs.copy_up(&storage, &os_py, true).await,
+                Err(VFSError::ReadOnly)
+            ));
+            fs.copy_up(&storage, &scratch, false).await.unwrap();
+        });
+        assert_eq!(lookup(&fs, "/tmp/moved").unwrap(), scratch);
+    }
+
+    #[test]
+    fn open_char_devices() {
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let mut fs = image();
+        devices::populate(&mut fs).unwrap();
+        let null = lookup(&fs, "/dev/null").unwrap();
+        let tty = lookup(&fs, "/dev/tty").unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            let file = fs
+                .open_storage(&storage, &null, libc::O_RDWR)
+                .await
+                .unwrap();
+            let mut contents = Vec::new();
+            File::open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            assert!(contents.is_empty());
+        });
+
+        let (_local, stderr) = SharedStream::pair().unwrap();
+        stderr.mount(&mut fs, Path::new("/proc/1/fd/2")).unwrap();
+        let inode = |fd| {
+            std::fs::metadata(format!("/proc/self/fd/{}", fd))
+                .unwrap()
+                .ino()
+        };
+        Runtime::new().unwrap().block_on(async {
+            for flags in &[libc::O_WRONLY, libc::O_RDWR] {
+                let file = fs.open_storage(&storage, &tty, *flags).await.unwrap();
+                assert_eq!(inode(file.as_raw_fd()), inode(stderr.as_raw_fd()));
+            }
+        });
+    }
+}
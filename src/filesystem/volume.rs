@@ -0,0 +1,292 @@
+use crate::{
+    errors::{ImageError, VFSError},
+    filesystem::{
+        import,
+        mount::Mount,
+        storage::{FileStorage, SparseMap, StorageKey},
+        tar,
+        vfs::{storage_error, Filesystem, VolumeTree},
+    },
+    rt,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tempfile::TempDir;
+
+/// A read-only directory tree which can be mounted into many containers
+///
+/// Volumes are prepared once, from a directory or tar archive on the host,
+/// and their data is copied into a private cache that lasts as long as the
+/// volume. Each mount shares the volume's file metadata and its open cache
+/// files with every other container using the same volume, so mounting a
+/// large dataset into thousands of containers costs little more than
+/// mounting it into one, however many files it holds.
+///
+/// Containers can't change a volume, and get `EROFS` if they try. Later
+/// changes on the host have no effect on it either.
+pub struct Volume {
+    name: String,
+    tree: Arc<VolumeTree>,
+}
+
+/// Cache files a volume keeps open at once, shared by all of its mounts
+const MAX_OPEN_FILES: usize = 256;
+
+/// Storage for one volume's file contents, along with files it has opened
+pub(crate) struct VolumeFiles {
+    storage: FileStorage,
+    open: Mutex<OpenFiles>,
+}
+
+/// Open cache files, closing the least recently used past a limit
+struct OpenFiles {
+    limit: usize,
+    files: HashMap<StorageKey, (Arc<File>, u64)>,
+    clock: u64,
+}
+
+impl Volume {
+    /// Prepare a volume holding a copy of a host directory
+    pub async fn from_directory(name: &str, path: &Path) -> Result<Arc<Volume>, ImageError> {
+        let path = path.to_path_buf();
+        Volume::prepare(name, move |filesystem, storage| {
+            import::import(filesystem, storage, &path)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Prepare a volume holding the contents of an uncompressed tar archive
+    pub async fn from_tar(name: &str, path: &Path) -> Result<Arc<Volume>, ImageError> {
+        let path = path.to_path_buf();
+        Volume::prepare(name, move |filesystem, storage| {
+            let mut writer = storage.begin_write()?;
+            io::copy(&mut File::open(&path)?, &mut writer)?;
+            let key = StorageKey::Blob(writer.finalize()?);
+            storage.commit_write(writer, &key)?;
+            tar::extract(filesystem, storage, &key)
+        })
+        .await
+    }
+
+    /// Get the name this volume was prepared with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn prepare<F>(name: &str, populate: F) -> Result<Arc<Volume>, ImageError>
+    where
+        F: FnOnce(&mut Filesystem, &FileStorage) -> Result<(), ImageError> + Send + 'static,
+    {
+        let temp_dir = TempDir::new()?;
+        let cache_dir = temp_dir.path().join("bandsocks-volume");
+        let storage = FileStorage::new(cache_dir, Some(Arc::new(temp_dir)));
+        let tree = rt::spawn_blocking(move || {
+            let mut filesystem = Filesystem::new();
+            populate(&mut filesystem, &storage)?;
+            filesystem
+                .writer()
+                .attach_volume_files(Arc::new(VolumeFiles::new(storage)));
+            Ok::<_, ImageError>(filesystem.into_volume_tree()?)
+        })
+        .await??;
+        Ok(Arc::new(Volume {
+            name: name.to_string(),
+            tree: Arc::new(tree),
+        }))
+    }
+}
+
+impl Mount for Volume {
+    fn mount(&self, fs: &mut Filesystem, path: &Path) -> Result<(), VFSError> {
+        fs.writer().mount_volume(path, &self.tree)
+    }
+}
+
+impl fmt::Debug for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Volume({})", self.name)
+    }
+}
+
+impl VolumeFiles {
+    pub(crate) fn new(storage: FileStorage) -> Self {
+        VolumeFiles {
+            storage,
+            open: Mutex::new(OpenFiles::new(MAX_OPEN_FILES)),
+        }
+    }
+
+    /// Open a new file description for one stored part
+    ///
+    /// The first open of each part keeps its cache file open, until enough
+    /// other parts have been opened since. Later opens reopen that same file,
+    /// which avoids searching the cache directory again, while still giving
+    /// each open its own offset.
+    pub(crate) async fn open(
+        &self,
+        key: &StorageKey,
+        map: Option<&SparseMap>,
+    ) -> Result<File, VFSError> {
+        let shared = self.open.lock().unwrap().get(key);
+        let shared = match shared {
+            Some(file) => file,
+            None => {
+                let file = match map {
+                    None => self.storage.open_part(key).await,
+                    Some(map) => self.storage.open_sparse(key, map).await,
+                };
+                let file = file
+                    .map_err(storage_error)?
+                    .ok_or(VFSError::ImageStorageError)?;
+                self.open.lock().unwrap().insert(key, Arc::new(file))
+            }
+        };
+        let path = PathBuf::from(format!("/proc/self/fd/{}", shared.as_raw_fd()));
+        File::open(path).map_err(|err| VFSError::Host(err.raw_os_error().unwrap_or(libc::EIO)))
+    }
+}
+
+impl OpenFiles {
+    fn new(limit: usize) -> Self {
+        OpenFiles {
+            limit,
+            files: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: &StorageKey) -> Option<Arc<File>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.files.get_mut(key).map(|(file, used)| {
+            *used = clock;
+            file.clone()
+        })
+    }
+
+    /// Keep a file open, or return the one another task opened first
+    fn insert(&mut self, key: &StorageKey, file: Arc<File>) -> Arc<File> {
+        if let Some(file) = self.get(key) {
+            return file;
+        }
+        if self.files.len() >= self.limit {
+            let oldest = self
+                .files
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.files.remove(&oldest);
+            }
+        }
+        self.files.insert(key.clone(), (file.clone(), self.clock));
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image::ContentDigest, sand::protocol::FollowLinks};
+    use std::fs;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn mount_directory_volume() {
+        let host = tempfile::tempdir().unwrap();
+        fs::create_dir(host.path().join("set")).unwrap();
+        fs::write(host.path().join("set/rows.csv"), b"1,2,3\n").unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+
+        Runtime::new().unwrap().block_on(async {
+            let volume = Volume::from_directory("dataset", host.path())
+                .await
+                .unwrap();
+            let mut first = Filesystem::new();
+            first
+                .writer()
+                .write_directory_metadata(Path::new("/data/old"), Default::default())
+                .unwrap();
+            let mut second = first.clone();
+            volume.mount(&mut first, Path::new("/data")).unwrap();
+            volume.mount(&mut second, Path::new("/data")).unwrap();
+
+            for fs in &[&first, &second] {
+                let root = Filesystem::root();
+                let rows = fs
+                    .lookup(&root, Path::new("/data/set/rows.csv"), &FollowLinks::Follow)
+                    .unwrap();
+                let contents = fs.read_small_file(&storage, &rows, 4096).await.unwrap();
+                assert_eq!(contents.unwrap(), b"1,2,3\n");
+                let parent = fs
+                    .lookup(&root, Path::new("/data/set/../.."), &FollowLinks::Follow)
+                    .unwrap();
+                assert_eq!(parent, root);
+                assert!(fs
+                    .lookup(&root, Path::new("/data/old"), &FollowLinks::Follow)
+                    .is_err());
+            }
+        });
+    }
+
+    #[test]
+    fn open_files_are_bounded() {
+        let key = |name: &[u8]| StorageKey::Blob(ContentDigest::from_content(name));
+        let null = || Arc::new(File::open("/dev/null").unwrap());
+        let mut open = OpenFiles::new(2);
+        open.insert(&key(b"a"), null());
+        open.insert(&key(b"b"), null());
+        assert!(open.get(&key(b"a")).is_some());
+        open.insert(&key(b"c"), null());
+        assert_eq!(open.files.len(), 2);
+        assert!(open.get(&key(b"b")).is_none());
+        assert!(open.get(&key(b"a")).is_some());
+        assert!(open.get(&key(b"c")).is_some());
+    }
+
+    #[test]
+    fn volume_is_read_only() {
+        let host = tempfile::tempdir().unwrap();
+        fs::write(host.path().join("rows.csv"), b"1,2,3\n").unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            let volume = Volume::from_directory("dataset", host.path())
+                .await
+                .unwrap();
+            let mut fs = Filesystem::new();
+            volume.mount(&mut fs, Path::new("/data")).unwrap();
+            let root = Filesystem::root();
+            let rows = fs
+                .lookup(&root, Path::new("/data/rows.csv"), &FollowLinks::Follow)
+                .unwrap();
+            let storage = FileStorage::new(host.path().join("cache"), None);
+
+            assert!(matches!(
+                fs.copy_up(&storage, &rows, true).await,
+                Err(VFSError::ReadOnly)
+            ));
+            let mut writer = fs.writer();
+            assert!(matches!(
+                writer.create_file(&storage, Path::new("/data/new"), Default::default()),
+                Err(VFSError::ReadOnly)
+            ));
+            assert!(matches!(
+                writer.unlink(Path::new("/data"), true),
+                Err(VFSError::Busy)
+            ));
+            assert!(matches!(
+                volume.mount(&mut fs, Path::new("/other")),
+                Err(VFSError::Busy)
+            ));
+        });
+    }
+}
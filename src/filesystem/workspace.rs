@@ -0,0 +1,835 @@
+use crate::{
+    errors::ImageError,
+    filesystem::{
+        storage::{FileStorage, StorageKey},
+        tar,
+        vfs::{FileType, Filesystem},
+        volume::VolumeFiles,
+    },
+    image::{ContentDigest, ImageName},
+    rt,
+    sand::protocol::{abi, FileStat, FollowLinks, VFile},
+};
+use ::tar::{Builder, EntryType, Header};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs,
+    fs::File,
+    io,
+    io::Read,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// A tree is saved in full again, instead of as one more layer of changes,
+/// once it has this many layers
+const MAX_LAYERS: usize = 8;
+
+/// Directory trees which outlive their containers, kept on disk
+///
+/// Each workspace belongs to one image and a label of your choosing. A
+/// container using it with
+/// [ContainerBuilder::workspace()](crate::ContainerBuilder::workspace)
+/// starts with the directory as the last such container left it, so a
+/// compile and test loop can keep its build output rather than starting cold
+/// every time. The usual place for workspaces is the cache directory, see
+/// [RegistryClient::workspaces()](crate::RegistryClient::workspaces).
+///
+/// The tree is saved when the container exits. Like an image, it's stored as
+/// a stack of layers, and each save only adds a layer with what the container
+/// changed. Files it left alone are neither read nor copied. Once there are
+/// enough layers, the next save writes the whole tree again and drops the
+/// old ones. Containers which use the same workspace at once don't see each
+/// other's changes, and the last one to exit decides what's kept.
+///
+/// Workspaces in the cache directory are removed along with their image by
+/// [ImageCache::gc()](crate::ImageCache::gc).
+#[derive(Clone, Debug)]
+pub struct Workspaces {
+    path: PathBuf,
+    // Keeps an ephemeral cache directory around for as long as its workspaces
+    _storage: Option<FileStorage>,
+}
+
+/// One container's use of a workspace, which saves the tree on exit
+pub(crate) struct WorkspaceMount {
+    path: PathBuf,
+    dir: PathBuf,
+    key: ContentDigest,
+    storage: FileStorage,
+    lease: FileStorage,
+    /// The layers restored, bottom first
+    layers: Vec<ContentDigest>,
+    /// The tree those layers made, sharing its files with the container's
+    /// until either one changes
+    base: Filesystem,
+}
+
+impl Workspaces {
+    /// Keep workspaces in any directory, which is created as needed
+    pub fn open(path: &Path) -> Self {
+        Workspaces {
+            path: path.to_path_buf(),
+            _storage: None,
+        }
+    }
+
+    pub(crate) fn in_storage(storage: &FileStorage) -> Self {
+        Workspaces {
+            path: storage.path_of(&StorageKey::Workspaces),
+            _storage: Some(storage.clone()),
+        }
+    }
+
+    /// Get the path of the directory holding every workspace
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Put the saved tree for this image and label at `path`
+    ///
+    /// With nothing saved yet, the image's own directory is left as it is,
+    /// or an empty one is created.
+    pub(crate) fn restore(
+        &self,
+        image: &ImageName,
+        label: &str,
+        fs: &mut Filesystem,
+        path: &Path,
+    ) -> Result<WorkspaceMount, ImageError> {
+        let image = image_key(image);
+        let mut key = image.as_bytes().to_vec();
+        key.push(0);
+        key.extend_from_slice(label.as_bytes());
+        let key = ContentDigest::from_content(&key);
+        let dir = self.path.join(key.hex_str());
+        let storage = FileStorage::new(dir.clone(), None);
+
+        // The lease comes first, so the saved tree can't be collected between
+        // reading its digest and opening it
+        let lease = storage.leased(&key)?;
+        fs::create_dir_all(&dir)?;
+        fs::write(image_path(&dir), &image)?;
+        let layers = read_layers(&dir)?;
+        let mut base = Filesystem::new();
+        if layers.is_empty() {
+            if fs
+                .lookup(&Filesystem::root(), path, &FollowLinks::Follow)
+                .is_err()
+            {
+                fs.writer().write_directory_metadata(
+                    path,
+                    FileStat {
+                        st_mode: abi::S_IFDIR | 0o755,
+                        ..Default::default()
+                    },
+                )?;
+            }
+        } else {
+            for layer in &layers {
+                tar::extract(&mut base, &storage, &StorageKey::Blob(layer.clone()))?;
+            }
+            base.writer()
+                .attach_volume_files(Arc::new(VolumeFiles::new(storage.clone())));
+            fs.writer().graft(path, &base)?;
+        }
+        log::debug!("workspace {:?} at {:?} from {:?}", label, path, dir);
+        Ok(WorkspaceMount {
+            path: path.to_path_buf(),
+            dir,
+            key,
+            storage,
+            lease,
+            layers,
+            base,
+        })
+    }
+
+    /// Remove every workspace belonging to an image, returning how many
+    ///
+    /// Workspaces in use are skipped.
+    pub(crate) fn remove_image(&self, image: &ImageName) -> Result<usize, ImageError> {
+        let image = image_key(image);
+        let entries = match fs::read_dir(&self.path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            result => result?,
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let dir = entry?.path();
+            match fs::read_to_string(image_path(&dir)) {
+                Ok(owner) if owner == image => {}
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => continue,
+            }
+            let key = match dir.file_name().and_then(OsStr::to_str) {
+                Some(hex) => ContentDigest::parse(&format!("sha256:{}", hex))?,
+                None => continue,
+            };
+            let storage = FileStorage::new(dir.clone(), None);
+            let collected = storage.collect_unleased(&key, || {
+                remove_file(&tree_path(&dir))?;
+                remove_file(&image_path(&dir))?;
+                remove_other_trees(&storage, &[])
+            })?;
+            if collected.is_some() {
+                log::debug!("removed workspace {:?}", dir);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl WorkspaceMount {
+    /// Where the workspace is in the container
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Save the tree at this workspace's path, and remove older trees which
+    /// nobody else is using
+    ///
+    /// This blocks on file I/O, so call it where that's allowed, like
+    /// [rt::spawn_blocking()].
+    pub fn save(self, fs: &Filesystem, storage: &FileStorage) -> Result<(), ImageError> {
+        let root = match fs.lookup(&Filesystem::root(), &self.path, &FollowLinks::Follow) {
+            Ok(root) if fs.is_directory(&root)? => Some(root),
+            // A workspace that was removed starts out empty next time
+            _ => None,
+        };
+        let incremental =
+            root.is_some() && !self.layers.is_empty() && self.layers.len() < MAX_LAYERS;
+        let base = if incremental {
+            Some((&self.base, Filesystem::root()))
+        } else {
+            None
+        };
+
+        let mut writer = self.storage.begin_write()?;
+        let mut builder = Builder::new(&mut writer);
+        let changes = match &root {
+            Some(root) => append_tree(&mut builder, fs, storage, root, base)?,
+            None => 0,
+        };
+        builder.finish()?;
+        drop(builder);
+        if incremental && changes == 0 {
+            writer.discard()?;
+            log::debug!("workspace {:?} unchanged", self.dir);
+            return Ok(());
+        }
+        let tree = writer.finalize()?;
+        self.storage
+            .commit_write(writer, &StorageKey::Blob(tree.clone()))?;
+        let mut layers = if incremental {
+            self.layers.clone()
+        } else {
+            Vec::new()
+        };
+        layers.push(tree);
+        write_layers(&self.dir, &layers)?;
+        log::debug!(
+            "workspace {:?} saved with {} changes, {} layers",
+            self.dir,
+            changes,
+            layers.len()
+        );
+
+        let WorkspaceMount {
+            storage,
+            lease,
+            key,
+            ..
+        } = self;
+        drop(lease);
+        storage.collect_unleased(&key, || remove_other_trees(&storage, &layers))?;
+        Ok(())
+    }
+}
+
+/// The name a workspace's image is known by, with its content digest if any
+fn image_key(image: &ImageName) -> String {
+    image
+        .content_digest_str()
+        .unwrap_or_else(|| image.as_str())
+        .to_string()
+}
+
+/// Add everything below a directory to an archive, with paths relative to it,
+/// returning the number of entries added
+///
+/// With a `base` directory from another filesystem, the archive is a layer
+/// holding only the differences from it. Files shared with the base are
+/// unchanged and left out, and files only in the base are whited out.
+fn append_tree<W: io::Write>(
+    builder: &mut Builder<W>,
+    fs: &Filesystem,
+    storage: &FileStorage,
+    root: &VFile,
+    base: Option<(&Filesystem, VFile)>,
+) -> Result<usize, ImageError> {
+    let base_fs = base.as_ref().map(|(base_fs, _)| *base_fs);
+    let mut changes = 0;
+    let mut links = HashMap::new();
+    let mut dirs = vec![(root.clone(), base.map(|(_, dir)| dir), PathBuf::new())];
+    while let Some((dir, base_dir, dir_path)) = dirs.pop() {
+        let mut base_entries = HashMap::new();
+        if let (Some(base_fs), Some(base_dir)) = (base_fs, &base_dir) {
+            for entry in base_fs.read_dir(base_dir)? {
+                let entry = entry?;
+                if entry.name() != "." && entry.name() != ".." {
+                    base_entries.insert(
+                        entry.name().to_os_string(),
+                        (entry.file().clone(), entry.file_type()),
+                    );
+                }
+            }
+        }
+        let mut entries = Vec::new();
+        for entry in fs.read_dir(&dir)? {
+            let entry = entry?;
+            if entry.name() != "." && entry.name() != ".." {
+                entries.push((
+                    entry.name().to_os_string(),
+                    entry.file().clone(),
+                    entry.file_type(),
+                ));
+            }
+        }
+        for (name, file, file_type) in entries {
+            let path = dir_path.join(&name);
+            let base_entry = base_entries.remove(&name);
+            // Whatever the name held before is gone
+            if let Some(reason) = left_out(fs, &file, file_type)? {
+                log::debug!("workspace skipping {} {:?}", reason, path);
+                if base_entry.is_some() {
+                    append_whiteout(builder, &dir_path, &name)?;
+                    changes += 1;
+                }
+                continue;
+            }
+            let base_entry = match (base_fs, base_entry) {
+                (Some(base_fs), Some((base_file, base_type))) => {
+                    if base_type != file_type {
+                        append_whiteout(builder, &dir_path, &name)?;
+                        changes += 1;
+                        None
+                    } else if file_type != FileType::Directory
+                        && fs.is_same_inode(&file, base_fs, &base_file)
+                    {
+                        continue;
+                    } else {
+                        Some((base_fs, base_file))
+                    }
+                }
+                _ => None,
+            };
+            let stat = fs.stat(&file)?;
+            match file_type {
+                FileType::Directory => {
+                    let unchanged = match &base_entry {
+                        Some((base_fs, base_file)) => {
+                            same_metadata(&stat, &base_fs.stat(base_file)?)
+                        }
+                        None => false,
+                    };
+                    if !unchanged {
+                        let mut header = header(&stat, EntryType::Directory);
+                        builder.append_data(&mut header, &path, io::empty())?;
+                        changes += 1;
+                    }
+                    dirs.push((file, base_entry.map(|(_, base_file)| base_file), path));
+                }
+                FileType::File => {
+                    changes += 1;
+                    if let Some(link_to) = links.get(&file.inode) {
+                        let mut header = header(&stat, EntryType::Link);
+                        header.set_link_name(link_to)?;
+                        builder.append_data(&mut header, &path, io::empty())?;
+                        continue;
+                    }
+                    let contents =
+                        rt::handle().block_on(fs.open_storage(storage, &file, libc::O_RDONLY))?;
+                    let contents = File::open(format!("/proc/self/fd/{}", contents.as_raw_fd()))?;
+                    let len = contents.metadata()?.len();
+                    let mut header = header(&stat, EntryType::Regular);
+                    header.set_size(len);
+                    builder.append_data(&mut header, &path, contents.take(len))?;
+                    links.insert(file.inode, path);
+                }
+                FileType::SymbolicLink => {
+                    let target = fs.readlink(&file)?;
+                    let mut header = header(&stat, EntryType::Symlink);
+                    header.set_link_name(OsStr::from_bytes(target.to_bytes()))?;
+                    builder.append_data(&mut header, &path, io::empty())?;
+                    changes += 1;
+                }
+                FileType::Fifo => {
+                    let mut header = header(&stat, EntryType::Fifo);
+                    builder.append_data(&mut header, &path, io::empty())?;
+                    changes += 1;
+                }
+                _ => unreachable!("{:?} should have been left out", file_type),
+            }
+        }
+        // Whatever is left was removed
+        for name in base_entries.keys() {
+            append_whiteout(builder, &dir_path, name)?;
+            changes += 1;
+        }
+    }
+    Ok(changes)
+}
+
+/// Why an entry is left out of a saved tree, if it is
+///
+/// Secrets stay in memory, bind mounts already live on the host, and tmpfs
+/// mounts start out empty every time. Devices and sockets belong to the
+/// container that made them.
+fn left_out(
+    fs: &Filesystem,
+    file: &VFile,
+    file_type: FileType,
+) -> Result<Option<&'static str>, ImageError> {
+    Ok(match file_type {
+        _ if fs.is_private(file) => Some("private file"),
+        FileType::Directory if fs.is_mount_point(file)? => Some("mount"),
+        FileType::Directory | FileType::File | FileType::SymbolicLink | FileType::Fifo => None,
+        FileType::CharDevice | FileType::BlockDevice => Some("device"),
+        FileType::Socket => Some("socket"),
+        FileType::Unknown => Some("unknown file"),
+    })
+}
+
+fn append_whiteout<W: io::Write>(
+    builder: &mut Builder<W>,
+    dir_path: &Path,
+    name: &OsStr,
+) -> Result<(), ImageError> {
+    let mut whiteout = OsString::from(".wh.");
+    whiteout.push(name);
+    let mut header = header(&Default::default(), EntryType::Regular);
+    builder.append_data(&mut header, dir_path.join(whiteout), io::empty())?;
+    Ok(())
+}
+
+fn same_metadata(a: &FileStat, b: &FileStat) -> bool {
+    a.st_mode == b.st_mode
+        && a.st_uid == b.st_uid
+        && a.st_gid == b.st_gid
+        && a.st_mtime == b.st_mtime
+}
+
+fn header(stat: &FileStat, entry_type: EntryType) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(stat.st_mode & 0o7777);
+    header.set_uid(stat.st_uid as u64);
+    header.set_gid(stat.st_gid as u64);
+    header.set_mtime(stat.st_mtime);
+    header.set_size(0);
+    header
+}
+
+/// The file in a workspace's directory listing the layers of its current
+/// tree, one digest per line, bottom first
+fn tree_path(dir: &Path) -> PathBuf {
+    dir.join("tree")
+}
+
+/// The file in a workspace's directory naming the image it belongs to
+fn image_path(dir: &Path) -> PathBuf {
+    dir.join("image")
+}
+
+fn read_layers(dir: &Path) -> Result<Vec<ContentDigest>, ImageError> {
+    match fs::read_to_string(tree_path(dir)) {
+        Ok(layers) => layers.lines().map(|line| line.trim().parse()).collect(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_layers(dir: &Path, layers: &[ContentDigest]) -> Result<(), ImageError> {
+    let temp_path = dir.join(format!(
+        "tree.{}-{}.tmp",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    let mut contents = String::new();
+    for layer in layers {
+        contents.push_str(layer.as_str());
+        contents.push('\n');
+    }
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, tree_path(dir))?;
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> Result<(), ImageError> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn remove_other_trees(storage: &FileStorage, layers: &[ContentDigest]) -> Result<(), ImageError> {
+    let mut keep = Vec::new();
+    for layer in layers {
+        let parts = storage.path_of(&StorageKey::BlobPart(layer.clone(), 0..0));
+        keep.push(storage.path_of(&StorageKey::Blob(layer.clone())));
+        keep.push(parts.parent().unwrap().to_path_buf());
+    }
+    for file in storage.list_data()? {
+        if !keep.iter().any(|path| file.path.starts_with(path)) {
+            storage.try_remove_path(&file.path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{runtime::Runtime, task};
+
+    async fn read(fs: &Filesystem, storage: &FileStorage, path: &str) -> Option<Vec<u8>> {
+        let root = Filesystem::root();
+        let file = fs
+            .lookup(&root, Path::new(path), &FollowLinks::Follow)
+            .ok()?;
+        fs.read_small_file(storage, &file, 4096).await.unwrap()
+    }
+
+    /// Save from a blocking thread, as the runtime does
+    async fn save(mount: WorkspaceMount, fs: &Filesystem, storage: &FileStorage) {
+        let fs = fs.clone();
+        let storage = storage.clone();
+        task::spawn_blocking(move || mount.save(&fs, &storage))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    fn layer_counts(workspaces: &Workspaces) -> Vec<usize> {
+        fs::read_dir(workspaces.path())
+            .unwrap()
+            .map(|entry| read_layers(&entry.unwrap().path()).unwrap().len())
+            .collect()
+    }
+
+    fn blob_count(workspaces: &Workspaces) -> usize {
+        fs::read_dir(workspaces.path())
+            .unwrap()
+            .map(|entry| {
+                let storage = FileStorage::new(entry.unwrap().path(), None);
+                storage.list_data().unwrap().len()
+            })
+            .sum()
+    }
+
+    fn image() -> ImageName {
+        "busybox@sha256:cddb0e8f24f292e9b7baaba4d5f546db08f0a4b900be2048c6bd704bd90c13df"
+            .parse()
+            .unwrap()
+    }
+
+    fn file_stat() -> FileStat {
+        FileStat {
+            st_mode: abi::S_IFREG | 0o644,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn saved_between_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().join("cache"), None);
+        let workspaces = Workspaces::open(&dir.path().join("workspaces"));
+        let image = image();
+        let target = Path::new("/work/target");
+        let stat = file_stat();
+
+        Runtime::new().unwrap().block_on(async {
+            let mut first = Filesystem::new();
+            let mount = workspaces
+                .restore(&image, "build", &mut first, target)
+                .unwrap();
+            let mut writer = first.writer();
+            writer
+                .write_file_bytes(
+                    Path::new("/work/target/debug/app.o"),
+                    stat.clone(),
+                    b"object"[..].into(),
+                )
+                .unwrap();
+            writer
+                .write_hardlink(
+                    Path::new("/work/target/app.o"),
+                    Path::new("/work/target/debug/app.o"),
+                )
+                .unwrap();
+            writer
+                .write_file_bytes(
+                    Path::new("/work/target/debug/lib.o"),
+                    stat.clone(),
+                    b"library"[..].into(),
+                )
+                .unwrap();
+            writer
+                .write_file_bytes(Path::new("/work/outside"), stat, b"gone"[..].into())
+                .unwrap();
+            save(mount, &first, &storage).await;
+
+            let mut second = Filesystem::new();
+            let mount = workspaces
+                .restore(&image, "build", &mut second, target)
+                .unwrap();
+            let object = Some(b"object".to_vec());
+            assert_eq!(
+                read(&second, &storage, "/work/target/debug/app.o").await,
+                object
+            );
+            assert_eq!(read(&second, &storage, "/work/target/app.o").await, object);
+            assert_eq!(read(&second, &storage, "/work/outside").await, None);
+            second
+                .writer()
+                .unlink(Path::new("/work/target/debug/app.o"), false)
+                .unwrap();
+            save(mount, &second, &storage).await;
+
+            // The change is a layer of its own
+            assert_eq!(layer_counts(&workspaces), vec![2]);
+            let mut third = Filesystem::new();
+            let mount = workspaces
+                .restore(&image, "build", &mut third, target)
+                .unwrap();
+            assert_eq!(
+                read(&third, &storage, "/work/target/debug/app.o").await,
+                None
+            );
+            assert_eq!(read(&third, &storage, "/work/target/app.o").await, object);
+            assert_eq!(
+                read(&third, &storage, "/work/target/debug/lib.o").await,
+                Some(b"library".to_vec())
+            );
+
+            // Saving with nothing changed adds nothing
+            save(mount, &third, &storage).await;
+            assert_eq!(layer_counts(&workspaces), vec![2]);
+            assert_eq!(blob_count(&workspaces), 2);
+
+            // Another label has a workspace of its own
+            let mut other = Filesystem::new();
+            workspaces
+                .restore(&image, "test", &mut other, target)
+                .unwrap();
+            let root = Filesystem::root();
+            let dir = other.lookup(&root, target, &FollowLinks::Follow).unwrap();
+            assert!(other.is_directory(&dir).unwrap());
+            assert_eq!(read(&other, &storage, "/work/target/app.o").await, None);
+        });
+    }
+
+    #[test]
+    fn private_files_not_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().join("cache"), None);
+        let workspaces = Workspaces::open(&dir.path().join("workspaces"));
+        let image = image();
+        let target = Path::new("/work");
+
+        Runtime::new().unwrap().block_on(async {
+            let mut fs = Filesystem::new();
+            let mount = workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            let mut writer = fs.writer();
+            writer
+                .write_file_bytes(Path::new("/work/token"), file_stat(), b"s3cret"[..].into())
+                .unwrap();
+            writer
+                .write_hardlink(Path::new("/work/copy"), Path::new("/work/token"))
+                .unwrap();
+            writer
+                .write_file_bytes(Path::new("/work/file"), file_stat(), b"data"[..].into())
+                .unwrap();
+            let root = Filesystem::root();
+            let token = fs
+                .lookup(&root, Path::new("/work/token"), &FollowLinks::NoFollow)
+                .unwrap();
+            fs.set_private(&token);
+            save(mount, &fs, &storage).await;
+
+            // Neither name made it into the layer
+            let entry = fs::read_dir(workspaces.path()).unwrap().next().unwrap();
+            let dir = entry.unwrap().path();
+            let layers = read_layers(&dir).unwrap();
+            assert_eq!(layers.len(), 1);
+            let layer = FileStorage::new(dir, None).path_of(&StorageKey::Blob(layers[0].clone()));
+            let mut archive = ::tar::Archive::new(File::open(layer).unwrap());
+            let names: Vec<PathBuf> = archive
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().into_owned())
+                .collect();
+            assert_eq!(names, vec![PathBuf::from("file")]);
+
+            let mut fs = Filesystem::new();
+            workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            assert_eq!(read(&fs, &storage, "/work/token").await, None);
+            assert_eq!(read(&fs, &storage, "/work/copy").await, None);
+            assert_eq!(
+                read(&fs, &storage, "/work/file").await,
+                Some(b"data".to_vec())
+            );
+        });
+    }
+
+    #[test]
+    fn mounts_and_devices_not_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().join("cache"), None);
+        let workspaces = Workspaces::open(&dir.path().join("workspaces"));
+        let image = image();
+        let target = Path::new("/work");
+        let stat = |st_mode| FileStat {
+            st_mode,
+            ..Default::default()
+        };
+
+        Runtime::new().unwrap().block_on(async {
+            let mut fs = Filesystem::new();
+            let mount = workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            fs.writer()
+                .write_file_bytes(
+                    Path::new("/work/scratch/old"),
+                    file_stat(),
+                    b"old"[..].into(),
+                )
+                .unwrap();
+            save(mount, &fs, &storage).await;
+
+            let mut fs = Filesystem::new();
+            let mount = workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            let mut writer = fs.writer();
+            writer
+                .mount_tmpfs(Path::new("/work/scratch"), 4096, 0o1777)
+                .unwrap();
+            writer
+                .write_file_bytes(
+                    Path::new("/work/scratch/new"),
+                    file_stat(),
+                    b"new"[..].into(),
+                )
+                .unwrap();
+            writer
+                .write_char_device(Path::new("/work/null"), stat(abi::S_IFCHR | 0o666), 1, 3)
+                .unwrap();
+            writer
+                .write_fifo(Path::new("/work/pipe"), stat(abi::S_IFIFO | 0o644))
+                .unwrap();
+            save(mount, &fs, &storage).await;
+
+            // The tmpfs mount hid the directory saved before it
+            let mut fs = Filesystem::new();
+            workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            let root = Filesystem::root();
+            let lookup = |path: &str| fs.lookup(&root, Path::new(path), &FollowLinks::NoFollow);
+            assert!(lookup("/work/scratch").is_err());
+            assert!(lookup("/work/null").is_err());
+            assert!(lookup("/work/pipe").is_ok());
+        });
+    }
+
+    #[test]
+    fn layers_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().join("cache"), None);
+        let workspaces = Workspaces::open(&dir.path().join("workspaces"));
+        let image = image();
+        let target = Path::new("/work");
+
+        Runtime::new().unwrap().block_on(async {
+            for run in 0..MAX_LAYERS + 1 {
+                let mut fs = Filesystem::new();
+                let mount = workspaces
+                    .restore(&image, "build", &mut fs, target)
+                    .unwrap();
+                let path = format!("/work/run-{}", run);
+                fs.writer()
+                    .write_file_bytes(Path::new(&path), file_stat(), b"ran"[..].into())
+                    .unwrap();
+                save(mount, &fs, &storage).await;
+            }
+            // The last save had too many layers below it, and wrote one tree
+            assert_eq!(layer_counts(&workspaces), vec![1]);
+            assert_eq!(blob_count(&workspaces), 1);
+
+            let mut fs = Filesystem::new();
+            workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            for run in 0..MAX_LAYERS + 1 {
+                let path = format!("/work/run-{}", run);
+                assert_eq!(read(&fs, &storage, &path).await, Some(b"ran".to_vec()));
+            }
+        });
+    }
+
+    #[test]
+    fn removed_with_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(dir.path().join("cache"), None);
+        let workspaces = Workspaces::open(&dir.path().join("workspaces"));
+        let image = image();
+        let other_image: ImageName = "busybox:latest".parse().unwrap();
+        let target = Path::new("/work");
+
+        Runtime::new().unwrap().block_on(async {
+            for image in &[&image, &other_image] {
+                let mut fs = Filesystem::new();
+                let mount = workspaces.restore(image, "build", &mut fs, target).unwrap();
+                fs.writer()
+                    .write_file_bytes(Path::new("/work/file"), file_stat(), b"data"[..].into())
+                    .unwrap();
+                save(mount, &fs, &storage).await;
+            }
+
+            // One in use is kept
+            let mut fs = Filesystem::new();
+            let mount = workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            assert_eq!(workspaces.remove_image(&image).unwrap(), 0);
+            drop(mount);
+
+            assert_eq!(workspaces.remove_image(&image).unwrap(), 1);
+            assert_eq!(blob_count(&workspaces), 1);
+            let mut fs = Filesystem::new();
+            workspaces
+                .restore(&image, "build", &mut fs, target)
+                .unwrap();
+            assert_eq!(read(&fs, &storage, "/work/file").await, None);
+            let mut fs = Filesystem::new();
+            workspaces
+                .restore(&other_image, "build", &mut fs, target)
+                .unwrap();
+            assert_eq!(
+                read(&fs, &storage, "/work/file").await,
+                Some(b"data".to_vec())
+            );
+        });
+    }
+}
@@ -0,0 +1,439 @@
+use crate::{
+    errors::ImageError,
+    filesystem::{
+        storage::{FileStorage, StorageKey},
+        workspace::Workspaces,
+    },
+    image::{ContentDigest, ImageName},
+};
+use std::{
+    collections::HashSet,
+    fs, io,
+    io::Write,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Data which no image refers to is only collected once it's this old, so
+/// blobs written by a pull which hasn't been recorded yet are left alone
+const UNREFERENCED_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// The images kept in a cache directory, and garbage collection for them
+///
+/// Blobs are stored by content digest and shared between every image that
+/// uses them. Each pull records which blobs its image refers to and when it
+/// was last used, and [ImageCache::gc()] uses those records to remove the
+/// least recently used images whose data nobody else needs.
+///
+/// Images in use by this or any other process, including ones in the middle
+/// of a pull, hold a lease which protects them from collection. Use
+/// [RegistryClient::image_cache()](crate::RegistryClient::image_cache) to
+/// get the cache for a client.
+#[derive(Clone, Debug)]
+pub struct ImageCache {
+    storage: FileStorage,
+}
+
+/// One image which has been pulled into the cache
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CachedImage {
+    /// Name the image was pulled as, including its content digest
+    pub name: ImageName,
+    /// Content digests of the blobs the image refers to
+    pub blobs: Vec<ContentDigest>,
+    /// When the image was most recently pulled, from the network or cache
+    pub last_used: SystemTime,
+}
+
+/// What happened during [ImageCache::gc()]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheGcReport {
+    /// Images removed, least recently used first
+    pub removed: Vec<ImageName>,
+    /// Disk space freed, in bytes
+    pub bytes_freed: u64,
+    /// Disk space still used by image data, in bytes
+    pub bytes_remaining: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ImageFile {
+    name: String,
+    blobs: Vec<String>,
+    last_used_ms: u64,
+}
+
+impl ImageCache {
+    pub(crate) fn new(storage: &FileStorage) -> Self {
+        ImageCache {
+            storage: storage.clone(),
+        }
+    }
+
+    /// Record that an image was just used, and which blobs it refers to
+    ///
+    /// This must happen while the image is leased and before any of its
+    /// layers are checked. The collector either finishes before the record
+    /// is written, so the check sees what it removed, or it starts after and
+    /// sees the blobs referenced.
+    pub(crate) fn touch(
+        &self,
+        name: &ImageName,
+        blobs: Vec<ContentDigest>,
+    ) -> Result<(), ImageError> {
+        self.storage.with_cache_lock(false, || {
+            self.insert(&CachedImage {
+                name: name.clone(),
+                blobs,
+                last_used: SystemTime::now(),
+            })
+        })
+    }
+
+    fn insert(&self, image: &CachedImage) -> Result<(), ImageError> {
+        let digest = image
+            .name
+            .content_digest()
+            .ok_or_else(|| ImageError::ImageNotPinned(image.name.clone()))?;
+        let file = ImageFile {
+            name: image.name.to_string(),
+            blobs: image.blobs.iter().map(|blob| blob.to_string()).collect(),
+            last_used_ms: image
+                .last_used
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        // Written to a temporary file and renamed, so readers never see a
+        // partial record
+        let mut writer = self.storage.begin_write()?;
+        writer.write_all(&serde_json::to_vec(&file)?)?;
+        self.storage
+            .commit_write(writer, &StorageKey::Image(digest))?;
+        Ok(())
+    }
+
+    /// List the images in the cache, least recently used first
+    ///
+    /// Records that can't be read are skipped.
+    pub fn images(&self) -> Result<Vec<CachedImage>, ImageError> {
+        let mut images = Vec::new();
+        for path in self.storage.list_images()? {
+            let contents = match fs::read(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                result => result?,
+            };
+            match parse_image(&contents) {
+                Ok(image) => images.push(image),
+                Err(err) => log::warn!("skipping unreadable cache record {:?}, {}", path, err),
+            }
+        }
+        images.sort_by_key(|image| image.last_used);
+        Ok(images)
+    }
+
+    /// Count the disk space used by image data, in bytes
+    pub fn usage(&self) -> Result<u64, ImageError> {
+        Ok(self
+            .storage
+            .list_data()?
+            .iter()
+            .map(|file| file.bytes)
+            .sum())
+    }
+
+    /// Remove unused images until the cache fits in `max_bytes`
+    ///
+    /// Data which no image refers to is removed first, then whole images,
+    /// least recently used first. Blobs shared with an image that stays are
+    /// kept. Images which are leased, because a container or pull in any
+    /// process is using them, are skipped, so the cache can stay over the
+    /// limit. Use a limit of zero to remove everything that's not in use.
+    /// Workspaces belonging to a removed image are removed with it, unless
+    /// they're in use.
+    pub fn gc(&self, max_bytes: u64) -> Result<CacheGcReport, ImageError> {
+        self.gc_with_grace(max_bytes, UNREFERENCED_GRACE)
+    }
+
+    fn gc_with_grace(&self, max_bytes: u64, grace: Duration) -> Result<CacheGcReport, ImageError> {
+        let mut report = CacheGcReport::default();
+        let (images, mut total) = self
+            .storage
+            .with_cache_lock(true, || self.collect_unreferenced(grace, &mut report))?;
+
+        for image in images {
+            if total <= max_bytes {
+                break;
+            }
+            let digest = match image.name.content_digest() {
+                Some(digest) => digest,
+                None => continue,
+            };
+            let freed = self.storage.with_cache_lock(true, || {
+                self.storage
+                    .collect_unleased(&digest, || self.collect_image(&image, &digest))
+            })?;
+            match freed {
+                None => log::debug!("{} is in use, keeping it", image.name),
+                Some(freed) => {
+                    log::info!("removed {} from cache, {} bytes freed", image.name, freed);
+                    // Nothing can use its workspaces again without pulling it
+                    match Workspaces::in_storage(&self.storage).remove_image(&image.name) {
+                        Ok(0) => {}
+                        Ok(count) => log::info!("removed {} workspaces of {}", count, image.name),
+                        Err(err) => {
+                            log::warn!("failed to remove workspaces of {}, {}", image.name, err)
+                        }
+                    }
+                    total = total.saturating_sub(freed);
+                    report.bytes_freed += freed;
+                    report.removed.push(image.name);
+                }
+            }
+        }
+
+        report.bytes_remaining = self.usage()?;
+        Ok(report)
+    }
+
+    /// Remove data older than `grace` which no image refers to, returning
+    /// the images and how much data is left
+    ///
+    /// Only call this while holding the cache lock exclusively.
+    fn collect_unreferenced(
+        &self,
+        grace: Duration,
+        report: &mut CacheGcReport,
+    ) -> Result<(Vec<CachedImage>, u64), ImageError> {
+        let now = SystemTime::now();
+        let images = self.images()?;
+        let referenced = self.referenced_paths(&images);
+        let mut total = 0;
+        for file in self.storage.list_data()? {
+            let unreferenced = !referenced.contains(&file.path)
+                && !file
+                    .path
+                    .parent()
+                    .map_or(false, |dir| referenced.contains(dir));
+            let old = now.duration_since(file.modified).unwrap_or_default() >= grace;
+            if unreferenced && old && self.storage.try_remove_path(&file.path)? {
+                log::debug!("removed unreferenced {:?}", file.path);
+                report.bytes_freed += file.bytes;
+            } else {
+                total += file.bytes;
+            }
+        }
+        Ok((images, total))
+    }
+
+    /// Remove one image and the blobs nobody else refers to, returning the
+    /// bytes freed
+    ///
+    /// Only call this while holding the cache lock exclusively and the
+    /// image's lease.
+    fn collect_image(
+        &self,
+        image: &CachedImage,
+        digest: &ContentDigest,
+    ) -> Result<u64, ImageError> {
+        // Read the records again now that this image is locked, in case
+        // another process pulled an image sharing its blobs
+        let others: HashSet<ContentDigest> = self
+            .images()?
+            .into_iter()
+            .filter(|other| other.name.content_digest().as_ref() != Some(digest))
+            .flat_map(|other| other.blobs)
+            .collect();
+        let mut freed = 0;
+        for blob in image.blobs.iter().filter(|blob| !others.contains(blob)) {
+            freed += self.storage.try_remove_parts(blob)?;
+            let key = StorageKey::Blob(blob.clone());
+            let bytes = blob_usage(&self.storage.path_of(&key));
+            if self.storage.try_remove(&key)? {
+                freed += bytes;
+            }
+        }
+        match fs::remove_file(self.storage.path_of(&StorageKey::Image(digest.clone()))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(freed),
+        }
+    }
+
+    /// Paths of every blob and parts directory that an image refers to
+    fn referenced_paths(&self, images: &[CachedImage]) -> HashSet<PathBuf> {
+        let mut paths = HashSet::new();
+        for blob in images.iter().flat_map(|image| &image.blobs) {
+            paths.insert(self.storage.path_of(&StorageKey::Blob(blob.clone())));
+            let part = self
+                .storage
+                .path_of(&StorageKey::BlobPart(blob.clone(), 0..0));
+            if let Some(dir) = part.parent() {
+                paths.insert(dir.to_path_buf());
+            }
+        }
+        paths
+    }
+}
+
+fn blob_usage(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.blocks() * 512)
+}
+
+fn parse_image(contents: &[u8]) -> Result<CachedImage, ImageError> {
+    let file: ImageFile = serde_json::from_slice(contents)?;
+    let mut blobs = Vec::with_capacity(file.blobs.len());
+    for blob in &file.blobs {
+        blobs.push(ContentDigest::parse(blob)?);
+    }
+    Ok(CachedImage {
+        name: file.name.parse()?,
+        blobs,
+        last_used: UNIX_EPOCH + Duration::from_millis(file.last_used_ms),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn store(storage: &FileStorage, data: &[u8]) -> ContentDigest {
+        let digest = ContentDigest::from_content(data);
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(data).unwrap();
+        storage
+            .commit_write(writer, &StorageKey::Blob(digest.clone()))
+            .unwrap();
+        digest
+    }
+
+    fn image(name: &str, blobs: &[&ContentDigest], age_secs: u64) -> CachedImage {
+        let digest = ContentDigest::from_content(name.as_bytes());
+        CachedImage {
+            name: format!("{}@{}", name, digest).parse().unwrap(),
+            blobs: blobs.iter().map(|blob| (*blob).clone()).collect(),
+            last_used: SystemTime::now() - Duration::from_secs(age_secs),
+        }
+    }
+
+    fn names(images: &[CachedImage]) -> Vec<String> {
+        images.iter().map(|image| image.name.to_string()).collect()
+    }
+
+    #[test]
+    fn records_images() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None);
+        let cache = ImageCache::new(&storage);
+        assert_eq!(cache.images().unwrap(), vec![]);
+
+        let layer = store(&storage, &[1; 10000]);
+        let newer = image("busybox:latest", &[&layer], 10);
+        let older = image("alpine:3", &[&layer], 20);
+        cache.insert(&newer).unwrap();
+        cache.insert(&older).unwrap();
+        assert_eq!(
+            names(&cache.images().unwrap()),
+            names(&[older.clone(), newer])
+        );
+
+        cache.touch(&older.name, older.blobs.clone()).unwrap();
+        assert_eq!(cache.images().unwrap().last().unwrap().name, older.name);
+        assert!(cache.usage().unwrap() >= 10000);
+    }
+
+    #[test]
+    fn gc_least_recently_used() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None);
+        let cache = ImageCache::new(&storage);
+        let shared = store(&storage, &[1; 50000]);
+        let old_only = store(&storage, &[2; 50000]);
+        let new_only = store(&storage, &[3; 50000]);
+        let unreferenced = store(&storage, &[4; 50000]);
+        let old = image("old", &[&shared, &old_only], 200);
+        let new = image("new", &[&shared, &new_only], 100);
+        cache.insert(&old).unwrap();
+        cache.insert(&new).unwrap();
+
+        // Recent unreferenced data is left alone
+        let before = cache.usage().unwrap();
+        let report = cache.gc(before).unwrap();
+        assert_eq!(report.removed, vec![]);
+        assert_eq!(report.bytes_remaining, before);
+
+        // Unreferenced data goes first, then the oldest image but not what it
+        // shares with the newer one
+        let report = cache
+            .gc_with_grace(before - 60000, Duration::from_secs(0))
+            .unwrap();
+        assert_eq!(report.removed, vec![old.name.clone()]);
+        assert!(!storage.exists(&StorageKey::Blob(unreferenced)));
+        assert!(!storage.exists(&StorageKey::Blob(old_only)));
+        assert!(storage.exists(&StorageKey::Blob(shared.clone())));
+        assert!(storage.exists(&StorageKey::Blob(new_only.clone())));
+        assert_eq!(names(&cache.images().unwrap()), names(&[new.clone()]));
+        assert_eq!(report.bytes_remaining, cache.usage().unwrap());
+        assert_eq!(report.bytes_freed + report.bytes_remaining, before);
+
+        // Leased images stay no matter the limit
+        let lease = storage.leased(&new.name.content_digest().unwrap()).unwrap();
+        assert_eq!(cache.gc(0).unwrap().removed, vec![]);
+        drop(lease);
+        let report = cache.gc(0).unwrap();
+        assert_eq!(report.removed, vec![new.name]);
+        assert_eq!(report.bytes_remaining, 0);
+        assert!(!storage.exists(&StorageKey::Blob(shared)));
+    }
+
+    #[test]
+    fn gc_waits_for_touch() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None);
+        let cache = ImageCache::new(&storage);
+        let layer = store(&storage, &[5; 50000]);
+        let pulled = image("pulled", &[&layer], 0);
+
+        // A collector starting while a pull is recording its image waits,
+        // then sees the layer as referenced
+        let collector = storage.with_cache_lock(false, || {
+            let cache = cache.clone();
+            let collector =
+                std::thread::spawn(move || cache.gc_with_grace(u64::MAX, Duration::from_secs(0)));
+            std::thread::sleep(Duration::from_millis(200));
+            cache.insert(&pulled)?;
+            Ok(collector)
+        });
+        let report = collector.unwrap().join().unwrap().unwrap();
+        assert_eq!(report.bytes_freed, 0);
+        assert!(storage.exists(&StorageKey::Blob(layer)));
+    }
+
+    #[test]
+    fn gc_keeps_parts_in_use() {
+        let dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(dir.path().to_path_buf(), None);
+        let cache = ImageCache::new(&storage);
+        let layer = store(&storage, b"layer data");
+        let part_key = StorageKey::BlobPart(layer.clone(), 0..5);
+        let other_part_key = StorageKey::BlobPart(layer.clone(), 6..10);
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(b"data").unwrap();
+        storage.commit_write(writer, &other_part_key).unwrap();
+        let mut writer = storage.begin_write().unwrap();
+        writer.write_all(b"layer").unwrap();
+        storage.commit_write(writer, &part_key).unwrap();
+        cache.insert(&image("busybox", &[&layer], 0)).unwrap();
+
+        let open_part = storage.open(&part_key).unwrap().unwrap();
+        let report = cache.gc(0).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert!(storage.exists(&part_key));
+        assert!(!storage.exists(&other_part_key));
+        drop(open_part);
+        cache.gc_with_grace(0, Duration::from_secs(0)).unwrap();
+        assert!(!storage.exists(&part_key));
+        assert_eq!(cache.usage().unwrap(), 0);
+    }
+}
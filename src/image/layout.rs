@@ -0,0 +1,390 @@
+//! Images kept on the host, as an OCI image layout or a `docker save` archive
+
+use crate::{
+    errors::ImageError,
+    filesystem::{
+        storage::{FileStorage, StorageKey},
+        tar,
+        vfs::Filesystem,
+    },
+    image::ContentDigest,
+    manifest::{
+        media_types, ArchiveManifest, ImageIndex, Link, Manifest, Platform, RuntimeConfig, FS_TYPE,
+    },
+    registry::client::{decompress_gzip, MAX_LAYER_SIZE},
+};
+use memmap::{Mmap, MmapOptions};
+use std::{
+    collections::HashMap,
+    fs,
+    fs::File,
+    io::{Cursor, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+/// Symbolic links followed when looking up a file in an archive
+const MAX_ARCHIVE_LINKS: usize = 8;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// The parts of an [Image](super::Image) which come from a local image
+pub(crate) struct LocalImage {
+    pub digest: ContentDigest,
+    pub config: RuntimeConfig,
+    pub filesystem: Filesystem,
+}
+
+/// Load the image from an OCI image layout directory
+///
+/// The layout's index is followed, through any nested indexes, to the first
+/// manifest for this platform. Every blob is checked against its digest.
+pub(crate) fn load_oci_dir(path: &Path, storage: &FileStorage) -> Result<LocalImage, ImageError> {
+    // Local images only ever run on the sandbox's own platform
+    let platform = Platform::host();
+    let mut index = ImageIndex::parse(&fs::read(path.join("index.json"))?)?;
+    loop {
+        let entry = index.select(&platform)?;
+        let blob = oci_blob(path, &entry.link)?;
+        if entry.link.media_type == media_types::OCI_INDEX
+            || entry.link.media_type == media_types::MANIFEST_LIST
+        {
+            index = ImageIndex::parse(&blob)?;
+            continue;
+        }
+        let manifest = Manifest::parse(&blob)?;
+        if manifest.config.media_type != media_types::OCI_CONFIG
+            && manifest.config.media_type != media_types::RUNTIME_CONFIG
+        {
+            return Err(ImageError::UnsupportedRuntimeConfigType(
+                manifest.config.media_type,
+            ));
+        }
+        let config = oci_blob(path, &manifest.config)?;
+        let mut layers = Vec::with_capacity(manifest.layers.len());
+        for link in &manifest.layers {
+            match link.media_type.as_str() {
+                media_types::LAYER_TAR
+                | media_types::LAYER_TAR_GZIP
+                | media_types::OCI_LAYER_TAR
+                | media_types::OCI_LAYER_TAR_GZIP => layers.push(oci_blob(path, link)?),
+                other => return Err(ImageError::UnsupportedLayerType(other.to_string())),
+            }
+        }
+        return assemble(
+            storage,
+            ContentDigest::parse(&entry.link.digest)?,
+            &config,
+            layers.iter().map(|layer| &layer[..]),
+        );
+    }
+}
+
+/// Load the image from an archive written by `docker save`
+///
+/// The archive must hold exactly one image. It's read in place, and only its
+/// layers are copied.
+pub(crate) fn load_docker_tar(
+    path: &Path,
+    storage: &FileStorage,
+) -> Result<LocalImage, ImageError> {
+    let file = File::open(path)?;
+    let archive = unsafe { MmapOptions::new().map(&file) }?;
+    let index = ArchiveIndex::new(&archive)?;
+    let manifest = ArchiveManifest::parse(index.get("manifest.json")?)?;
+    let config = index.get(&manifest.config)?;
+    let layers = manifest
+        .layers
+        .iter()
+        .map(|layer| index.get(layer))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Docker uses the config's digest as the image ID
+    assemble(
+        storage,
+        ContentDigest::from_content(config),
+        config,
+        layers.into_iter(),
+    )
+}
+
+fn oci_blob(layout: &Path, link: &Link) -> Result<Mmap, ImageError> {
+    let expected = ContentDigest::parse(&link.digest)?;
+    let path = layout
+        .join("blobs")
+        .join(expected.format_str())
+        .join(expected.hex_str());
+    let file = File::open(&path)?;
+    let map = unsafe { MmapOptions::new().map(&file) }?;
+    if map.len() as u64 != link.size {
+        return Err(ImageError::UnexpectedContentSize);
+    }
+    let found = ContentDigest::from_content(&map);
+    if found != expected {
+        return Err(ImageError::ContentDigestMismatch { expected, found });
+    }
+    Ok(map)
+}
+
+fn assemble<'a, I: ExactSizeIterator<Item = &'a [u8]>>(
+    storage: &FileStorage,
+    digest: ContentDigest,
+    config: &[u8],
+    layers: I,
+) -> Result<LocalImage, ImageError> {
+    let config = RuntimeConfig::parse(config)?;
+    if config.rootfs.fs_type != FS_TYPE {
+        return Err(ImageError::UnsupportedRootFilesystemType(
+            config.rootfs.fs_type,
+        ));
+    }
+    if config.rootfs.diff_ids.len() != layers.len() {
+        return Err(ImageError::UnexpectedDecompressedLayerContent);
+    }
+    let mut filesystem = Filesystem::new();
+    for (layer, diff_id) in layers.zip(&config.rootfs.diff_ids) {
+        let expected = ContentDigest::parse(diff_id)?;
+        let found = store_layer(storage, layer)?;
+        if found != expected {
+            return Err(ImageError::ContentDigestMismatch { expected, found });
+        }
+        tar::extract(&mut filesystem, storage, &StorageKey::Blob(found))?;
+    }
+    Ok(LocalImage {
+        digest,
+        config,
+        filesystem,
+    })
+}
+
+/// Copy one layer into storage, decompressing it if necessary, and return the
+/// digest of its uncompressed contents
+fn store_layer(storage: &FileStorage, layer: &[u8]) -> Result<ContentDigest, ImageError> {
+    let mut writer = storage.begin_write()?;
+    let result = if layer.starts_with(GZIP_MAGIC) {
+        decompress_gzip(layer, &mut writer, MAX_LAYER_SIZE, |_| {})
+    } else {
+        writer.write_all(layer).map_err(ImageError::from)
+    };
+    if let Err(err) = result {
+        writer.remove_temp()?;
+        return Err(err);
+    }
+    let digest = writer.finalize()?;
+    storage.commit_write(writer, &StorageKey::Blob(digest.clone()))?;
+    Ok(digest)
+}
+
+/// Locations of the files in an uncompressed tar archive
+struct ArchiveIndex<'a> {
+    archive: &'a [u8],
+    entries: HashMap<PathBuf, ArchiveEntry>,
+}
+
+enum ArchiveEntry {
+    File(Range<usize>),
+    Link(PathBuf),
+}
+
+impl<'a> ArchiveIndex<'a> {
+    fn new(archive: &'a [u8]) -> Result<Self, ImageError> {
+        let mut entries = HashMap::new();
+        for entry in ::tar::Archive::new(Cursor::new(archive)).entries()? {
+            let entry = entry?;
+            let path = normalize(&entry.path()?);
+            let header = entry.header();
+            let kind = header.entry_type();
+            if kind.is_file() {
+                let begin = entry.raw_file_position() as usize;
+                let end = begin + header.entry_size()? as usize;
+                if end > archive.len() {
+                    return Err(ImageError::TARFileError);
+                }
+                entries.insert(path, ArchiveEntry::File(begin..end));
+            } else if kind.is_symlink() {
+                let target = header.link_name()?.ok_or(ImageError::TARFileError)?;
+                let parent = path.parent().unwrap_or_else(|| Path::new(""));
+                entries.insert(
+                    path.clone(),
+                    ArchiveEntry::Link(normalize(&parent.join(target))),
+                );
+            } else if kind.is_hard_link() {
+                let target = header.link_name()?.ok_or(ImageError::TARFileError)?;
+                entries.insert(path, ArchiveEntry::Link(normalize(&target)));
+            }
+        }
+        Ok(ArchiveIndex { archive, entries })
+    }
+
+    fn get(&self, name: &str) -> Result<&'a [u8], ImageError> {
+        let mut path = normalize(Path::new(name));
+        for _ in 0..MAX_ARCHIVE_LINKS {
+            match self.entries.get(&path) {
+                Some(ArchiveEntry::File(range)) => return Ok(&self.archive[range.clone()]),
+                Some(ArchiveEntry::Link(target)) => path = target.clone(),
+                None => break,
+            }
+        }
+        Err(ImageError::ArchiveFileMissing(name.to_string()))
+    }
+}
+
+/// A path relative to the archive root, with `.` and `..` resolved
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => result.push(part),
+            Component::ParentDir => {
+                result.pop();
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sand::protocol::FollowLinks;
+    use flate2::{write::GzEncoder, Compression};
+    use std::os::unix::ffi::OsStrExt;
+
+    fn tar_with(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = ::tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn config_json(layer: &[u8]) -> Vec<u8> {
+        format!(
+            r#"{{"architecture": "amd64", "os": "linux", "config": {{"Cmd": ["/hello"]}},
+                "rootfs": {{"type": "layers", "diff_ids": ["{}"]}}}}"#,
+            ContentDigest::from_content(layer)
+        )
+        .into_bytes()
+    }
+
+    fn check_image(image: &LocalImage, storage: &FileStorage) {
+        assert_eq!(image.config.config.cmd, vec!["/hello".to_string()]);
+        let file = image
+            .filesystem
+            .lookup(
+                &Filesystem::root(),
+                Path::new("/hello"),
+                &FollowLinks::Follow,
+            )
+            .unwrap();
+        let contents = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(image.filesystem.read_small_file(storage, &file, 4096))
+            .unwrap();
+        assert_eq!(contents.unwrap(), b"hi\n");
+    }
+
+    fn put_blob(dir: &Path, data: &[u8], media_type: &str) -> String {
+        let digest = ContentDigest::from_content(data);
+        let blobs = dir.join("blobs/sha256");
+        fs::create_dir_all(&blobs).unwrap();
+        fs::write(blobs.join(digest.hex_str()), data).unwrap();
+        format!(
+            r#"{{"mediaType": "{}", "size": {}, "digest": "{}"}}"#,
+            media_type,
+            data.len(),
+            digest
+        )
+    }
+
+    #[test]
+    fn oci_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let layer = tar_with(&[("hello", b"hi\n")]);
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&layer).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let config = put_blob(dir.path(), &config_json(&layer), media_types::OCI_CONFIG);
+        let layer_link = put_blob(dir.path(), &gzip, media_types::OCI_LAYER_TAR_GZIP);
+        let manifest = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "{}", "config": {}, "layers": [{}]}}"#,
+            media_types::OCI_MANIFEST,
+            config,
+            layer_link
+        );
+        let manifest_link = put_blob(dir.path(), manifest.as_bytes(), media_types::OCI_MANIFEST);
+        let other_platform = manifest_link.replace(
+            "}",
+            r#", "platform": {"architecture": "amd64", "os": "windows"}}"#,
+        );
+        fs::write(
+            dir.path().join("index.json"),
+            format!(
+                r#"{{"schemaVersion": 2, "manifests": [{}, {}]}}"#,
+                other_platform, manifest_link
+            ),
+        )
+        .unwrap();
+
+        let image = load_oci_dir(dir.path(), &storage).unwrap();
+        assert_eq!(
+            image.digest,
+            ContentDigest::from_content(manifest.as_bytes())
+        );
+        check_image(&image, &storage);
+
+        fs::write(
+            dir.path()
+                .join("blobs/sha256")
+                .join(ContentDigest::from_content(&gzip).hex_str()),
+            b"x",
+        )
+        .unwrap();
+        assert!(load_oci_dir(dir.path(), &storage).is_err());
+    }
+
+    #[test]
+    fn docker_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(cache.path().to_path_buf(), None);
+        let layer = tar_with(&[("hello", b"hi\n")]);
+        let config = config_json(&layer);
+        let manifest = br#"[{"Config": "abc.json", "RepoTags": ["x:latest"],
+            "Layers": ["2/layer.tar"]}]"#;
+
+        let mut builder = ::tar::Builder::new(Vec::new());
+        for (path, data) in &[
+            ("manifest.json", &manifest[..]),
+            ("abc.json", &config[..]),
+            ("1/layer.tar", &layer[..]),
+        ] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        let mut header = ::tar::Header::new_gnu();
+        header.set_entry_type(::tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_link_name("../1/layer.tar").unwrap();
+        builder
+            .append_data(&mut header, "2/layer.tar", &[][..])
+            .unwrap();
+        let path = dir.path().join("image.tar");
+        fs::write(&path, builder.into_inner().unwrap()).unwrap();
+
+        let image = load_docker_tar(&path, &storage).unwrap();
+        assert_eq!(image.digest, ContentDigest::from_content(&config));
+        check_image(&image, &storage);
+        assert_eq!(
+            normalize(Path::new("./a/../b/./c")).as_os_str().as_bytes(),
+            b"b/c"
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{errors::ImageError, image::ImageName};
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Image names pinned to content digests, for reproducible pulls
+///
+/// A lock maps each image name as it was requested, usually including a tag,
+/// to the specific name and digest it resolved to at the time it was locked.
+/// Looking up a name with [ImageLock::resolve()] gives the pinned name, so a
+/// tag that has since moved to different content on the registry has no
+/// effect. Lockfiles are stored as JSON, sorted by name so they diff cleanly.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImageLock {
+    images: BTreeMap<String, ImageName>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LockFile {
+    images: BTreeMap<String, String>,
+}
+
+impl ImageLock {
+    /// Create an empty lock
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Pin a requested image name to the specific name it resolved to
+    ///
+    /// The resolved name must include a content digest, like the names of
+    /// images returned by [crate::RegistryClient::pull()].
+    pub fn insert(
+        &mut self,
+        requested: &ImageName,
+        resolved: &ImageName,
+    ) -> Result<(), ImageError> {
+        if resolved.content_digest().is_none() {
+            return Err(ImageError::ImageNotPinned(resolved.clone()));
+        }
+        self.images
+            .insert(requested.as_str().to_string(), resolved.clone());
+        Ok(())
+    }
+
+    /// Iterate over each requested name and the name it is pinned to
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ImageName)> {
+        self.images
+            .iter()
+            .map(|(requested, resolved)| (requested.as_str(), resolved))
+    }
+
+    /// Look up the pinned name to pull for a requested image
+    ///
+    /// Names must be locked under exactly the string they were requested
+    /// with. A name which already includes a content digest can't drift, so
+    /// it is allowed even when it's not in the lock, but it must agree with
+    /// the lock if it is.
+    pub fn resolve(&self, name: &ImageName) -> Result<ImageName, ImageError> {
+        match (self.images.get(name.as_str()), name.content_digest()) {
+            (Some(locked), None) => Ok(locked.clone()),
+            (Some(locked), Some(digest)) if locked.content_digest() == Some(digest) => {
+                Ok(locked.clone())
+            }
+            (Some(locked), Some(_)) => Err(ImageError::LockMismatch {
+                requested: name.clone(),
+                locked: locked.clone(),
+            }),
+            (None, Some(_)) => Ok(name.clone()),
+            (None, None) => Err(ImageError::ImageNotLocked(name.clone())),
+        }
+    }
+
+    /// Parse a lock from its JSON representation
+    pub fn parse(json: &[u8]) -> Result<Self, ImageError> {
+        let file: LockFile = serde_json::from_slice(json)?;
+        let mut lock = ImageLock::new();
+        for (requested, resolved) in file.images {
+            lock.insert(&requested.parse()?, &resolved.parse()?)?;
+        }
+        Ok(lock)
+    }
+
+    /// Format this lock as JSON
+    pub fn to_json(&self) -> String {
+        let file = LockFile {
+            images: self
+                .iter()
+                .map(|(requested, resolved)| (requested.to_string(), resolved.to_string()))
+                .collect(),
+        };
+        serde_json::to_string_pretty(&file).expect("lockfile is always valid json") + "\n"
+    }
+
+    /// Read a lockfile from disk
+    pub fn load(path: &Path) -> Result<Self, ImageError> {
+        ImageLock::parse(&fs::read(path)?)
+    }
+
+    /// Write this lock to a file on disk, replacing any existing file
+    pub fn save(&self, path: &Path) -> Result<(), ImageError> {
+        Ok(fs::write(path, self.to_json())?)
+    }
+}
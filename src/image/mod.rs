@@ -2,25 +2,39 @@
 
 #[cfg(test)] mod tests;
 
+mod cache;
 mod digest;
+mod layout;
+mod lock;
 mod name;
 mod registry;
 mod repository;
 mod tag;
+mod test_image;
 mod version;
 
+pub use cache::{CacheGcReport, CachedImage, ImageCache};
 pub use digest::ContentDigest;
+pub use lock::ImageLock;
 pub use name::ImageName;
 pub use registry::Registry;
 pub use repository::{Repository, RepositoryIter};
 pub use tag::Tag;
+pub use test_image::TestImageBuilder;
 pub use version::ImageVersion;
 
 use crate::{
-    filesystem::{storage::FileStorage, vfs::Filesystem},
-    manifest::RuntimeConfig,
+    errors::ImageError,
+    filesystem::{import, storage::FileStorage, vfs::Filesystem},
+    manifest::{Platform, RuntimeConfig},
+    rt,
 };
-use std::fmt;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tempfile::TempDir;
 
 /// Loaded data for a container image
 ///
@@ -38,6 +52,81 @@ pub struct Image {
 }
 
 impl Image {
+    /// Load an image from a root filesystem that's already unpacked on the host
+    ///
+    /// The directory tree is copied into a temporary cache which lasts as long
+    /// as the image, so later changes to the directory do not affect it.
+    /// Symbolic links, device nodes, hard links, and file modes and owners are
+    /// all kept. The image has no configuration, so containers need to be
+    /// given a command to run.
+    ///
+    /// The image is named `local/directory`, with a content digest computed
+    /// from all of the imported files and their metadata.
+    pub async fn from_directory(path: &Path) -> Result<Arc<Image>, ImageError> {
+        let storage = ephemeral_storage()?;
+        let task_storage = storage.clone();
+        let path = PathBuf::from(path);
+        let (filesystem, digest) = rt::spawn_blocking(move || {
+            let mut filesystem = Filesystem::new();
+            let digest = import::import(&mut filesystem, &task_storage, &path)?;
+            Ok::<_, ImageError>((filesystem, digest))
+        })
+        .await??;
+        let platform = Platform::host();
+        Ok(Arc::new(Image {
+            name: ImageName::from_parts(None, "local/directory", None, Some(digest.as_str()))?,
+            config: RuntimeConfig {
+                architecture: platform.architecture,
+                os: platform.os,
+                ..Default::default()
+            },
+            filesystem,
+            storage,
+        }))
+    }
+
+    /// Load an image from an OCI image layout directory on the host
+    ///
+    /// This is the layout written by tools like `skopeo copy` with an `oci:`
+    /// destination. If the layout holds images for several platforms, the
+    /// one for the host's platform is used. Every blob is checked against its
+    /// digest, and layers are copied into a temporary cache which lasts as
+    /// long as the image. No registry is ever contacted.
+    ///
+    /// The image is named `local/oci`, with the digest of its manifest.
+    pub async fn from_oci_dir(path: &Path) -> Result<Arc<Image>, ImageError> {
+        Image::from_layout("local/oci", path, layout::load_oci_dir).await
+    }
+
+    /// Load an image from a tar archive written by `docker save`
+    ///
+    /// The archive must hold exactly one image. Layers are checked against
+    /// the digests in the image's configuration, and copied into a temporary
+    /// cache which lasts as long as the image. No registry is ever contacted.
+    ///
+    /// The image is named `local/archive`, with the digest of its runtime
+    /// configuration, which docker also uses as the image ID.
+    pub async fn from_docker_archive(path: &Path) -> Result<Arc<Image>, ImageError> {
+        Image::from_layout("local/archive", path, layout::load_docker_tar).await
+    }
+
+    async fn from_layout(
+        repository: &str,
+        path: &Path,
+        load: fn(&Path, &FileStorage) -> Result<layout::LocalImage, ImageError>,
+    ) -> Result<Arc<Image>, ImageError> {
+        let storage = ephemeral_storage()?;
+        let task_storage = storage.clone();
+        let path = PathBuf::from(path);
+        let local = rt::spawn_blocking(move || load(&path, &task_storage)).await??;
+        Ok(Arc::new(Image {
+            name: ImageName::from_parts(None, repository, None, Some(local.digest.as_str()))?,
+            config: local.config,
+            filesystem: local.filesystem,
+            storage,
+        }))
+    }
+
     /// Get the digest identifying this image's content and configuration
     pub fn content_digest(&self) -> ContentDigest {
         self.name()
@@ -49,6 +138,18 @@ impl Image {
     pub fn name(&self) -> &ImageName {
         &self.name
     }
+
+    /// Get the virtual filesystem that containers using this image start with
+    pub fn filesystem(&self) -> &Filesystem {
+        &self.filesystem
+    }
+}
+
+/// Storage for an image that isn't from a registry, removed along with the image
+fn ephemeral_storage() -> Result<FileStorage, ImageError> {
+    let temp_dir = TempDir::new()?;
+    let cache_dir = temp_dir.path().join("bandsocks-local");
+    Ok(FileStorage::new(cache_dir, Some(Arc::new(temp_dir))))
 }
 
 impl fmt::Debug for Image {
@@ -0,0 +1,164 @@
+use crate::{
+    errors::ImageError,
+    filesystem::vfs::Filesystem,
+    image::{ContentDigest, Image, ImageName},
+    manifest::RuntimeConfig,
+    sand::protocol::{abi, FileStat},
+};
+use bytes::Bytes;
+use std::{ffi::CString, io::Write, os::unix::ffi::OsStrExt, path::Path, sync::Arc};
+
+/// Builder for small images held entirely in memory
+///
+/// This is meant for tests, which can put together an [Image] out of a few
+/// files without touching the network or a registry cache. Parent directories
+/// are created automatically as needed. The image's name is derived from its
+/// contents, so identical builders produce images with identical names.
+///
+/// ```
+/// # use bandsocks::TestImageBuilder;
+/// let image = TestImageBuilder::new()
+///     .add_file("/etc/motd", b"hello\n", 0o644)
+///     .add_symlink("/etc/issue", "motd")
+///     .set_entrypoint(&["/bin/app"])
+///     .build()
+///     .unwrap();
+/// assert_eq!(image.name().repository_str(), "local/test");
+/// ```
+pub struct TestImageBuilder {
+    filesystem: Filesystem,
+    config: RuntimeConfig,
+    summary: Vec<u8>,
+    error: Result<(), ImageError>,
+}
+
+impl Default for TestImageBuilder {
+    fn default() -> Self {
+        TestImageBuilder::new()
+    }
+}
+
+impl TestImageBuilder {
+    /// Start building an empty image
+    pub fn new() -> Self {
+        TestImageBuilder {
+            filesystem: Filesystem::new(),
+            config: RuntimeConfig {
+                architecture: "amd64".to_string(),
+                os: "linux".to_string(),
+                ..Default::default()
+            },
+            summary: Vec::new(),
+            error: Ok(()),
+        }
+    }
+
+    /// Add a regular file with the given contents and permission bits
+    pub fn add_file<P: AsRef<Path>>(mut self, path: P, contents: &[u8], mode: u32) -> Self {
+        let path = path.as_ref();
+        self.summarize("file", path, mode, contents);
+        let stat = FileStat {
+            st_mode: abi::S_IFREG | (mode & 0o7777),
+            st_size: contents.len() as i64,
+            ..Default::default()
+        };
+        let result =
+            self.filesystem
+                .writer()
+                .write_file_bytes(path, stat, Bytes::copy_from_slice(contents));
+        self.error = self.error.and(result.map_err(ImageError::from));
+        self
+    }
+
+    /// Add a directory with the given permission bits
+    pub fn add_directory<P: AsRef<Path>>(mut self, path: P, mode: u32) -> Self {
+        let path = path.as_ref();
+        self.summarize("dir", path, mode, b"");
+        let stat = FileStat {
+            st_mode: abi::S_IFDIR | (mode & 0o7777),
+            ..Default::default()
+        };
+        let result = self
+            .filesystem
+            .writer()
+            .write_directory_metadata(path, stat);
+        self.error = self.error.and(result.map_err(ImageError::from));
+        self
+    }
+
+    /// Add a symbolic link pointing at `target`
+    pub fn add_symlink<P: AsRef<Path>, T: AsRef<Path>>(mut self, path: P, target: T) -> Self {
+        let path = path.as_ref();
+        let target = target.as_ref().as_os_str().as_bytes();
+        self.summarize("link", path, 0o777, target);
+        let stat = FileStat {
+            st_mode: abi::S_IFLNK | 0o777,
+            st_size: target.len() as i64,
+            ..Default::default()
+        };
+        let filesystem = &mut self.filesystem;
+        let result = CString::new(target)
+            .map_err(ImageError::from)
+            .and_then(|target| Ok(filesystem.writer().write_symlink(path, stat, target)?));
+        self.error = self.error.and(result);
+        self
+    }
+
+    /// Set the entrypoint in the image's configuration
+    ///
+    /// Like in any image, the entrypoint is prepended to the command to form
+    /// the container's full command line.
+    pub fn set_entrypoint<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let args: Vec<String> = args.into_iter().map(|s| s.as_ref().to_string()).collect();
+        self.summarize("entrypoint", Path::new(""), 0, args.join("\0").as_bytes());
+        self.config.config.entrypoint = Some(args);
+        self
+    }
+
+    /// Set the default command in the image's configuration
+    pub fn set_cmd<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let args: Vec<String> = args.into_iter().map(|s| s.as_ref().to_string()).collect();
+        self.summarize("cmd", Path::new(""), 0, args.join("\0").as_bytes());
+        self.config.config.cmd = args;
+        self
+    }
+
+    /// Add an environment variable to the image's configuration
+    pub fn env<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> Self {
+        let var = format!("{}={}", key.as_ref(), value.as_ref());
+        self.summarize("env", Path::new(""), 0, var.as_bytes());
+        self.config.config.env.push(var);
+        self
+    }
+
+    /// Finish the image
+    ///
+    /// Fails if any of the files could not be added, for example because a
+    /// parent path was already used by a regular file.
+    pub fn build(self) -> Result<Arc<Image>, ImageError> {
+        self.error?;
+        let digest = ContentDigest::from_content(&self.summary);
+        Ok(Arc::new(Image {
+            name: ImageName::from_parts(None, "local/test", None, Some(digest.as_str()))?,
+            config: self.config,
+            filesystem: self.filesystem,
+            storage: super::ephemeral_storage()?,
+        }))
+    }
+
+    fn summarize(&mut self, kind: &str, path: &Path, mode: u32, data: &[u8]) {
+        let _ = write!(&mut self.summary, "{} {:o} {} ", kind, mode, data.len());
+        self.summary.extend_from_slice(path.as_os_str().as_bytes());
+        self.summary.push(0);
+        self.summary.extend_from_slice(data);
+        self.summary.push(b'\n');
+    }
+}
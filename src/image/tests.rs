@@ -186,3 +186,82 @@ fn parse_repository_name() {
     assert!(Repository::parse("boring/strings").is_ok());
     assert!(Repository::parse("a").is_ok());
 }
+
+#[test]
+fn image_lock_resolve() {
+    const DIGEST_A: &str =
+        "sha256:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const DIGEST_B: &str =
+        "sha256:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+    let tagged: ImageName = "busybox:1.32".parse().unwrap();
+    let pinned: ImageName = format!("busybox:1.32@{}", DIGEST_A).parse().unwrap();
+    let mut lock = ImageLock::new();
+    assert!(lock.insert(&tagged, &tagged).is_err());
+    lock.insert(&tagged, &pinned).unwrap();
+
+    assert_eq!(lock.resolve(&tagged).unwrap(), pinned);
+    assert_eq!(lock.resolve(&pinned).unwrap(), pinned);
+    assert!(lock.resolve(&"busybox".parse().unwrap()).is_err());
+    assert!(lock.resolve(&"busybox:1.33".parse().unwrap()).is_err());
+    let other = format!("busybox@{}", DIGEST_B).parse().unwrap();
+    assert_eq!(lock.resolve(&other).unwrap(), other);
+    let drifted = format!("busybox:1.32@{}", DIGEST_B).parse().unwrap();
+    assert!(lock.resolve(&drifted).is_err());
+}
+
+#[test]
+fn image_lock_json() {
+    let mut lock = ImageLock::new();
+    lock.insert(
+        &"alpine".parse().unwrap(),
+        &"alpine@sha256:cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc"
+            .parse()
+            .unwrap(),
+    )
+    .unwrap();
+    let json = lock.to_json();
+    assert_eq!(ImageLock::parse(json.as_bytes()).unwrap(), lock);
+    assert!(ImageLock::parse(br#"{"images": {"alpine": "alpine:3"}}"#).is_err());
+    assert!(ImageLock::parse(b"{}").is_err());
+}
+
+#[test]
+fn test_image_builder() {
+    use crate::sand::protocol::FollowLinks;
+    use std::path::Path;
+
+    let build = || {
+        TestImageBuilder::new()
+            .add_file("/bin/app", b"\x7fELF", 0o755)
+            .add_symlink("/usr/bin/app", "../../bin/app")
+            .add_directory("/tmp", 0o1777)
+            .set_entrypoint(&["/usr/bin/app"])
+    };
+    let image = build().build().unwrap();
+    assert_eq!(image.name(), build().build().unwrap().name());
+    assert_ne!(
+        image.name(),
+        build().env("TERM", "dumb").build().unwrap().name()
+    );
+    assert_eq!(
+        image.config.config.entrypoint,
+        Some(vec!["/usr/bin/app".to_string()])
+    );
+
+    let fs = image.filesystem();
+    let root = Filesystem::root();
+    let app = fs
+        .lookup(&root, Path::new("/usr/bin/app"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.stat(&app).unwrap().st_mode, 0o100755);
+    let tmp = fs
+        .lookup(&root, Path::new("/tmp"), &FollowLinks::Follow)
+        .unwrap();
+    assert_eq!(fs.stat(&tmp).unwrap().st_mode, 0o41777);
+
+    assert!(TestImageBuilder::new()
+        .add_file("/bin", b"", 0o644)
+        .add_file("/bin/sh", b"", 0o755)
+        .build()
+        .is_err());
+}
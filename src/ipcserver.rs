@@ -1,39 +1,108 @@
 use crate::{
-    container::ExitStatus,
-    errors::RuntimeError,
-    filesystem::{storage::FileStorage, vfs::Filesystem},
+    container::{
+        events::{op_name, EventCallback},
+        hooks::Hooks,
+        latency::LatencyStats,
+        memory::MemoryAccounting,
+        secrets::SecretAudit,
+        snapshot::ExecSnapshotSlot,
+        ContainerEvent, ExitStatus, HookStage, NetworkGroup, TaggedOutput,
+    },
+    errors::{RuntimeError, VFSError},
+    filesystem::{
+        procfs, procfs::ProcNode, remap::PathRemap, socket::SharedStream, storage::FileStorage,
+        vfs::Filesystem, workspace::WorkspaceMount,
+    },
     process::{Process, ProcessStatus},
-    sand,
+    rt, sand,
     sand::protocol::{
-        buffer, buffer::IPCBuffer, exit::*, Errno, FileStat, FromTask, MessageFromSand,
-        MessageToSand, SysFd, ToTask, TracerSettings, VFile, VPid, MEMFD_TEMP_NAME,
+        abi, buffer, buffer::IPCBuffer, exit::*, Errno, ExecSnapshotHeader, FileContents, FileStat,
+        FollowLinks, FromTask, HardeningReport, InlineBytes, MessageFromSand, MessageToSand,
+        Signal, SysFd, SysPid, ToTask, TracerSettings, VFile, VPid, MEMFD_TEMP_NAME,
     },
     taskcall,
 };
 use fd_queue::{tokio::UnixStream, EnqueueFd};
+use futures_util::future;
 use std::{
     collections::HashMap,
     ffi::{CStr, CString},
     fs::File,
-    io::Write,
+    io,
+    io::{Seek, SeekFrom, Write},
+    mem,
     os::{
         raw::c_int,
-        unix::{io::AsRawFd, prelude::RawFd},
+        unix::{
+            fs::MetadataExt,
+            io::{AsRawFd, FromRawFd},
+            net::UnixStream as StdUnixStream,
+            prelude::RawFd,
+        },
     },
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     process::{Child, Command},
-    task,
+    sync::{mpsc, oneshot},
     task::JoinHandle,
+    time::delay_for,
 };
+use tracing::Instrument;
+
+/// How often a suspended container checks its stdin for new input
+const SUSPENDED_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often the guest's memory use is measured again
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
 
 pub struct IPCServer {
     filesystem: Filesystem,
     storage: FileStorage,
     tracer: Child,
     stream: UnixStream,
+    framed_messages: bool,
     process_table: HashMap<VPid, Process>,
+    auto_suspend: Option<AutoSuspend>,
+    suspended: bool,
+    requests: mpsc::UnboundedReceiver<ControlRequest>,
+    paused: bool,
+    exec_snapshot: Option<ExecSnapshotSlot>,
+    memory: Arc<MemoryAccounting>,
+    next_memory_sample: Instant,
+    latency: Arc<LatencyStats>,
+    tagged_output: Option<TaggedOutput>,
+    path_remap: PathRemap,
+    secrets: Arc<SecretAudit>,
+    network: Option<Arc<NetworkGroup>>,
+    workspaces: Vec<WorkspaceMount>,
+    events: Option<EventCallback>,
+    hooks: Hooks,
+    last_signal: Option<(VPid, i32)>,
+    diagnostics: String,
+    hardening: Option<HardeningReport>,
+}
+
+/// Settings for suspending idle containers, see
+/// [ContainerBuilder::auto_suspend()](crate::ContainerBuilder::auto_suspend)
+pub struct AutoSuspend {
+    pub idle: Duration,
+    pub wake: SharedStream,
+}
+
+/// Requests from a [Container](crate::Container) while it runs
+#[derive(Debug)]
+pub enum ControlRequest {
+    Pause(bool),
+    Kill(Signal),
+    OpenFile {
+        path: PathBuf,
+        write: bool,
+        reply: oneshot::Sender<Result<File, RuntimeError>>,
+    },
 }
 
 struct SysFdStd(SysFd);
@@ -48,8 +117,6 @@ async fn send_message(
     stream: &mut UnixStream,
     message: &MessageToSand,
 ) -> Result<(), RuntimeError> {
-    log::debug!("<{:x?}", message);
-
     let mut buffer = IPCBuffer::new();
     buffer.push_back(message)?;
     for file in buffer.as_slice().files {
@@ -68,22 +135,72 @@ fn memfd_from_bytes(bytes: &[u8]) -> Result<File, RuntimeError> {
     Ok(file)
 }
 
+/// A sand process which has started and is waiting for its Init message
+///
+/// The tracer loads its seccomp policy before reading any messages, so one
+/// of these can be started ahead of time and given a container later.
+pub struct TracerProcess {
+    child: Child,
+    stream: UnixStream,
+}
+
+impl TracerProcess {
+    pub fn spawn() -> Result<Self, RuntimeError> {
+        // Both ends stay close-on-exec here; only the sand process receives a
+        // copy of child_socket, installed at a fixed fd number after fork.
+        let (stream, child_socket) = UnixStream::pair()?;
+        let mut command: Command = sand::command(child_socket.as_raw_fd())?.into();
+        let child = command.spawn()?;
+        Ok(TracerProcess { child, stream })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Another handle on the runtime's end of the IPC socket
+    ///
+    /// Shutting it down disconnects the tracer, which then exits and takes
+    /// the sandboxed processes with it.
+    pub fn control(&self) -> Result<StdUnixStream, RuntimeError> {
+        let fd = unsafe { libc::fcntl(self.stream.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            Err(io::Error::last_os_error())?
+        }
+        Ok(unsafe { StdUnixStream::from_raw_fd(fd) })
+    }
+}
+
 impl IPCServer {
     pub async fn new<T: AsRawFd>(
         filesystem: Filesystem,
         storage: FileStorage,
         args_socket: &T,
         tracer_settings: TracerSettings,
+        auto_suspend: Option<AutoSuspend>,
+        requests: mpsc::UnboundedReceiver<ControlRequest>,
+        tracer: TracerProcess,
+        exec_snapshot: Option<ExecSnapshotSlot>,
+        memory: Arc<MemoryAccounting>,
+        latency: Arc<LatencyStats>,
+        tagged_output: Option<TaggedOutput>,
+        path_remap: PathRemap,
+        secrets: Arc<SecretAudit>,
+        network: Option<Arc<NetworkGroup>>,
+        workspaces: Vec<WorkspaceMount>,
+        events: Option<EventCallback>,
+        hooks: Hooks,
     ) -> Result<Self, RuntimeError> {
-        let (mut server_socket, child_socket) = UnixStream::pair()?;
-        clear_close_on_exec_flag(child_socket.as_raw_fd());
+        let TracerProcess {
+            child: tracer,
+            stream: mut server_socket,
+        } = tracer;
 
         let args_fd = args_socket.as_raw_fd();
         assert_eq!(0, unsafe { libc::fcntl(args_fd, libc::F_SETFL, 0) });
         let args_fd = SysFd(args_fd as u32);
+        let framed_messages = tracer_settings.compress_messages;
 
-        // Queue the init message before running the sand process. It will exit early if
-        // it starts up idle.
         send_message(
             &mut server_socket,
             &MessageToSand::Init {
@@ -93,22 +210,50 @@ impl IPCServer {
         )
         .await?;
 
-        let mut command: Command = sand::command(child_socket.as_raw_fd())?.into();
-        let tracer = command.spawn()?;
-
         Ok(IPCServer {
             filesystem,
             storage,
             tracer,
             stream: server_socket,
+            framed_messages,
             process_table: HashMap::new(),
+            auto_suspend,
+            suspended: false,
+            requests,
+            paused: false,
+            exec_snapshot,
+            memory,
+            next_memory_sample: Instant::now() + MEMORY_SAMPLE_INTERVAL,
+            latency,
+            tagged_output,
+            path_remap,
+            secrets,
+            network,
+            workspaces,
+            events,
+            hooks,
+            last_signal: None,
+            diagnostics: String::new(),
+            hardening: None,
         })
     }
 
     pub fn task(mut self) -> JoinHandle<Result<ExitStatus, RuntimeError>> {
-        task::spawn(async move {
-            let result = self.task_message_loop().await;
+        rt::spawn(async move {
+            let mut result = self.task_message_loop().await;
             log::trace!("task_message_loop -> {:?}", result);
+            let pre_exit = self
+                .hooks
+                .run(HookStage::PreExit, &mut self.filesystem, &self.storage)
+                .await;
+            if let Err(err) = pre_exit {
+                if result.is_ok() {
+                    result = Err(err);
+                } else {
+                    log::warn!("pre-exit hook failed after the container did, {}", err);
+                }
+            }
+            self.save_workspaces().await;
             self.task_finalize().await?;
             result
         })
@@ -118,7 +263,7 @@ impl IPCServer {
         let mut buffer = IPCBuffer::new();
         loop {
             let available = buffer.begin_fill();
-            match self.stream.read(available.bytes).await? {
+            match self.read_or_idle(available.bytes).await? {
                 len if len > 0 => {
                     log::trace!("available={} len={}", available.bytes.len(), len);
                     buffer.commit_fill(len, 0)
@@ -126,7 +271,12 @@ impl IPCServer {
                 _ => return Err(RuntimeError::Disconnected),
             }
             while !buffer.is_empty() {
-                let message = match buffer.pop_front() {
+                let message: Result<MessageFromSand, _> = if self.framed_messages {
+                    buffer.pop_front_framed()
+                } else {
+                    buffer.pop_front()
+                };
+                let message = match message {
                     Ok(message) => message,
                     Err(buffer::Error::UnexpectedEnd) => break,
                     Err(err) => return Err(err.into()),
@@ -143,16 +293,173 @@ impl IPCServer {
         }
     }
 
+    async fn read_or_idle(&mut self, bytes: &mut [u8]) -> Result<usize, RuntimeError> {
+        loop {
+            let interval = match &self.auto_suspend {
+                None => None,
+                Some(_) if self.suspended => Some(SUSPENDED_POLL_INTERVAL),
+                Some(auto_suspend) => Some(auto_suspend.idle),
+            };
+            let idle = async {
+                match interval {
+                    None => future::pending().await,
+                    Some(interval) => delay_for(interval).await,
+                }
+            };
+            let sample = delay_for(
+                self.next_memory_sample
+                    .saturating_duration_since(Instant::now()),
+            );
+            // Requests stop arriving once the Container is dropped
+            let request = tokio::select! {
+                result = self.stream.read(bytes) => return Ok(result?),
+                Some(request) = self.requests.recv() => Some(request),
+                _ = idle => None,
+                _ = sample => {
+                    self.memory.sample(self.process_table.values());
+                    self.next_memory_sample = Instant::now() + MEMORY_SAMPLE_INTERVAL;
+                    continue;
+                }
+            };
+            match request {
+                Some(ControlRequest::Pause(pause)) => self.set_paused(pause).await?,
+                Some(ControlRequest::Kill(signal)) => {
+                    log::debug!("forwarding signal {} to container", signal.0);
+                    self.send_message(&MessageToSand::Kill(signal)).await?
+                }
+                Some(ControlRequest::OpenFile { path, write, reply }) => {
+                    let _ = reply.send(self.open_file(&path, write).await);
+                }
+                None => self.idle_check().await?,
+            }
+        }
+    }
+
+    /// Open a file in the container's filesystem from the host side, either
+    /// to read it or to replace its contents
+    ///
+    /// Writing creates the file if needed, and copies it up like any other
+    /// write to an image file.
+    async fn open_file(&mut self, path: &Path, write: bool) -> Result<File, RuntimeError> {
+        let root = Filesystem::root();
+        let vfile = match self.filesystem.lookup(&root, path, &FollowLinks::Follow) {
+            Ok(vfile) if self.filesystem.is_directory(&vfile)? => Err(VFSError::FileExpected)?,
+            Ok(vfile) if self.filesystem.proc_node(&vfile)?.is_some() => {
+                Err(VFSError::FileExpected)?
+            }
+            Ok(vfile) if write => {
+                self.filesystem.copy_up(&self.storage, &vfile, true).await?;
+                vfile
+            }
+            Ok(vfile) => vfile,
+            Err(VFSError::NotFound) if write => self.filesystem.writer().create_file(
+                &self.storage,
+                path,
+                taskcall::new_file_stat(abi::S_IFREG, 0o666),
+            )?,
+            Err(err) => Err(err)?,
+        };
+        let flags = if write {
+            libc::O_WRONLY | libc::O_TRUNC
+        } else {
+            libc::O_RDONLY
+        };
+        let file = self
+            .filesystem
+            .open_storage(&self.storage, &vfile, flags)
+            .await?;
+        let fd = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            Err(io::Error::last_os_error())?
+        }
+        log::debug!("open_file{:?} -> {:?}", (path, write), vfile);
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    async fn idle_check(&mut self) -> Result<(), RuntimeError> {
+        let has_input = match &self.auto_suspend {
+            None => return Ok(()),
+            Some(auto_suspend) => auto_suspend.wake.has_pending_input(),
+        };
+        if self.suspended {
+            if has_input {
+                log::debug!("resuming on new input");
+                self.suspended = false;
+                if !self.paused {
+                    self.send_message(&MessageToSand::Resume).await?;
+                }
+            }
+        } else if !has_input
+            && !self.process_table.is_empty()
+            && self.process_table.values().all(Process::is_blocked_reading)
+        {
+            log::debug!("suspending idle container");
+            self.suspended = true;
+            if !self.paused {
+                self.send_message(&MessageToSand::Suspend).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a request from [Container::pause()](crate::Container::pause)
+    /// or [Container::resume()](crate::Container::resume)
+    ///
+    /// This shares the tracer's suspended state with auto-suspend, and the
+    /// tasks stay stopped while either one wants them to.
+    async fn set_paused(&mut self, paused: bool) -> Result<(), RuntimeError> {
+        if paused != self.paused {
+            log::debug!("{} container", if paused { "pausing" } else { "resuming" });
+            self.paused = paused;
+            if !self.suspended {
+                self.send_message(if paused {
+                    &MessageToSand::Suspend
+                } else {
+                    &MessageToSand::Resume
+                })
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save each workspace as the container left it
+    ///
+    /// The container has already finished, so a workspace that can't be
+    /// saved is only logged, and starts out older next time. Saving reads and
+    /// writes files, so it happens on a blocking thread.
+    async fn save_workspaces(&mut self) {
+        let workspaces = mem::take(&mut self.workspaces);
+        if workspaces.is_empty() {
+            return;
+        }
+        let filesystem = self.filesystem.clone();
+        let storage = self.storage.clone();
+        let result = rt::spawn_blocking(move || {
+            for workspace in workspaces {
+                let path = workspace.path().to_path_buf();
+                if let Err(err) = workspace.save(&filesystem, &storage) {
+                    log::warn!("failed to save workspace {:?}, {}", path, err);
+                }
+            }
+        })
+        .await;
+        if let Err(err) = result {
+            log::warn!("failed to save workspaces, {}", err);
+        }
+    }
+
     pub async fn task_finalize(self) -> Result<(), RuntimeError> {
         log::trace!("task_finalize begin");
         let output = self.tracer.wait_with_output().await?;
         let stderr = String::from_utf8_lossy(&output.stderr);
+        let diagnostics = self.diagnostics;
         log::trace!("task_finalize ending");
         if output.status.success() {
             assert_eq!(stderr, "");
             Ok(())
         } else {
-            let stderr = stderr.into_owned();
+            let stderr = diagnostics + &stderr;
             let status = output.status;
             if status.code() == Some(EXIT_PANIC as i32) {
                 Err(RuntimeError::SandPanic { stderr })
@@ -162,6 +469,11 @@ impl IPCServer {
                 Err(RuntimeError::SandIOError { stderr })
             } else if status.code() == Some(EXIT_OUT_OF_MEM as i32) {
                 Err(RuntimeError::SandOutOfMem { stderr })
+            } else if status.code() == Some(EXIT_WEAK_HARDENING as i32) {
+                Err(RuntimeError::SandWeakHardening {
+                    report: self.hardening.unwrap_or_default(),
+                    stderr,
+                })
             } else {
                 Err(RuntimeError::SandUnexpectedStatus { status, stderr })
             }
@@ -176,9 +488,63 @@ impl IPCServer {
         &mut self,
         message: &MessageFromSand,
     ) -> Result<Option<ExitStatus>, RuntimeError> {
-        log::debug!(">{:x?}", message);
         match message {
-            MessageFromSand::Task { task, op } => self.handle_task_message(*task, op).await,
+            MessageFromSand::Task { task, op, nr } => {
+                let span = tracing::debug_span!(
+                    "request",
+                    vpid = task.0,
+                    op = op_name(op),
+                    nr = tracing::field::Empty
+                );
+                if let Some(nr) = nr {
+                    span.record("nr", nr);
+                }
+                let started = Instant::now();
+                let result = self
+                    .handle_task_message(*task, op)
+                    .instrument(span.clone())
+                    .await;
+                let elapsed = started.elapsed();
+                span.in_scope(|| tracing::debug!(elapsed_us = elapsed.as_micros() as u64, "done"));
+                // Only replies hold up the sandbox
+                if !matches!(
+                    op,
+                    FromTask::Log(..) | FromTask::SyscallLatency { .. } | FromTask::SyscallStorm(_)
+                ) {
+                    self.latency.taskcall(*task, elapsed);
+                }
+                if let Some(callback) = &self.events {
+                    if let Some(event) = ContainerEvent::from_task(*task, *nr, op, elapsed) {
+                        callback(&event);
+                    }
+                }
+                result
+            }
+            MessageFromSand::Diagnostic(bytes) => {
+                self.diagnostic(bytes.as_slice());
+                Ok(None)
+            }
+            MessageFromSand::Hardening(report) => {
+                if !report.is_complete() {
+                    log::warn!("sandbox runtime is not fully hardened, {:?}", report);
+                }
+                self.hardening = Some(report.clone());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Log each complete line of text from the tracer, and keep all of it in
+    /// case the tracer fails
+    fn diagnostic(&mut self, bytes: &[u8]) {
+        let start = self.diagnostics.rfind('\n').map_or(0, |index| index + 1);
+        self.diagnostics.push_str(&String::from_utf8_lossy(bytes));
+        if let Some(end) = self.diagnostics.rfind('\n') {
+            if end >= start {
+                for line in self.diagnostics[start..end].lines() {
+                    log::warn!("sand: {}", line);
+                }
+            }
         }
     }
 
@@ -212,16 +578,51 @@ impl IPCServer {
         &mut self,
         task: VPid,
         result: Result<VFile, Errno>,
+        flags: i32,
     ) -> Result<Option<ExitStatus>, RuntimeError> {
+        if let Ok(vfile) = &result {
+            if let Ok(Some(node)) = self.filesystem.proc_node(vfile) {
+                let contents = self.generate_proc_file(task, node);
+                return self.task_generated_file_reply(task, vfile, contents).await;
+            }
+            // Small read-only image files travel inside the reply, which is cheaper
+            // than passing a file descriptor. Anything writable needs a real fd, or
+            // the guest would keep reading a stale copy.
+            let fixed = self.filesystem.has_fixed_contents(vfile).unwrap_or(false);
+            if fixed && flags & libc::O_ACCMODE == libc::O_RDONLY {
+                let contents = self
+                    .filesystem
+                    .read_small_file(&self.storage, vfile, InlineBytes::CAPACITY)
+                    .await;
+                if let Ok(Some(contents)) = contents {
+                    let bytes = InlineBytes::new(&contents).expect("inline file size");
+                    self.send_message(&MessageToSand::Task {
+                        task,
+                        op: ToTask::FileReply(Ok((
+                            vfile.clone(),
+                            FileContents::Inline(Box::new(bytes)),
+                        ))),
+                    })
+                    .await?;
+                    return Ok(None);
+                }
+            }
+        }
+
         // SysFd does not own the underlying file, which must remain allocated until the
         // outgoing message has been flushed.
         let (_storage, reply) = match result {
             Err(e) => (None, Err(e)),
-            Ok(vfile) => match self.filesystem.open_storage(&self.storage, &vfile).await {
+            Ok(vfile) => match self
+                .filesystem
+                .open_storage(&self.storage, &vfile, flags)
+                .await
+            {
                 Err(e) => (None, Err(e.into())),
+                Ok(file) if !self.account_fd(&file) => (None, Err(Errno::new(libc::ENOMEM))),
                 Ok(file) => {
                     let sys_fd = SysFd(file.as_raw_fd() as u32);
-                    (Some(file), Ok((vfile, sys_fd)))
+                    (Some(file), Ok((vfile, FileContents::Fd(sys_fd))))
                 }
             },
         };
@@ -233,6 +634,59 @@ impl IPCServer {
         Ok(None)
     }
 
+    /// Reply with contents made just for this open, instead of stored ones
+    async fn task_generated_file_reply(
+        &mut self,
+        task: VPid,
+        vfile: &VFile,
+        contents: Result<Vec<u8>, Errno>,
+    ) -> Result<Option<ExitStatus>, RuntimeError> {
+        let (_file, reply) = match contents {
+            Err(e) => (None, Err(e)),
+            Ok(contents) => match InlineBytes::new(&contents) {
+                Some(bytes) => (
+                    None,
+                    Ok((vfile.clone(), FileContents::Inline(Box::new(bytes)))),
+                ),
+                None => {
+                    let mut file = memfd_from_bytes(&contents)?;
+                    file.seek(SeekFrom::Start(0))?;
+                    if self.account_fd(&file) {
+                        let sys_fd = SysFd(file.as_raw_fd() as u32);
+                        (Some(file), Ok((vfile.clone(), FileContents::Fd(sys_fd))))
+                    } else {
+                        (None, Err(Errno::new(libc::ENOMEM)))
+                    }
+                }
+            },
+        };
+        self.send_message(&MessageToSand::Task {
+            task,
+            op: ToTask::FileReply(reply),
+        })
+        .await?;
+        Ok(None)
+    }
+
+    fn generate_proc_file(&self, task: VPid, node: ProcNode) -> Result<Vec<u8>, Errno> {
+        let process = self
+            .process_table
+            .get(&task)
+            .ok_or(Errno::new(libc::ESRCH))?;
+        self.memory.sample(self.process_table.values());
+        Ok(procfs::generate(node, process, &self.memory.usage())?)
+    }
+
+    /// Count one file the guest is about to receive, against the last sample
+    fn account_fd(&self, file: &File) -> bool {
+        match file.metadata() {
+            Ok(metadata) => self
+                .memory
+                .pass_fd((metadata.dev(), metadata.ino()), metadata.len()),
+            Err(_) => true,
+        }
+    }
+
     async fn task_bytes_reply(
         &mut self,
         task: VPid,
@@ -241,7 +695,8 @@ impl IPCServer {
         let (_storage, reply) = match result {
             Err(e) => (None, Err(e)),
             Ok(bytes) => match memfd_from_bytes(bytes) {
-                Err(_) => (None, Err(Errno(-libc::EFAULT))),
+                Err(_) => (None, Err(Errno::new(libc::EFAULT))),
+                Ok(file) if !self.account_fd(&file) => (None, Err(Errno::new(libc::ENOMEM))),
                 Ok(file) => {
                     let sys_fd = SysFd(file.as_raw_fd() as u32);
                     (Some(file), Ok((sys_fd, bytes.len())))
@@ -256,6 +711,39 @@ impl IPCServer {
         Ok(None)
     }
 
+    /// Reply with a socket, which stays open here until the reply is sent
+    async fn task_socket_reply<T: AsRawFd>(
+        &mut self,
+        task: VPid,
+        result: Result<T, Errno>,
+    ) -> Result<Option<ExitStatus>, RuntimeError> {
+        let reply = match &result {
+            Err(e) => Err(*e),
+            Ok(socket) => Ok(SysFd(socket.as_raw_fd() as u32)),
+        };
+        self.send_message(&MessageToSand::Task {
+            task,
+            op: ToTask::SocketReply(reply),
+        })
+        .await?;
+        Ok(None)
+    }
+
+    /// The container's network group, making a private one the first time
+    /// it's needed if the container didn't join any
+    fn network(&mut self) -> Result<Arc<NetworkGroup>, Errno> {
+        if self.network.is_none() {
+            match NetworkGroup::new("private") {
+                Ok(group) => self.network = Some(group),
+                Err(err) => {
+                    log::warn!("can't set up a loopback network, {}", err);
+                    return Err(Errno::new(libc::ENETDOWN));
+                }
+            }
+        }
+        Ok(self.network.clone().unwrap())
+    }
+
     async fn task_cstring_reply(
         &mut self,
         task: VPid,
@@ -268,6 +756,30 @@ impl IPCServer {
         self.task_bytes_reply(task, result).await
     }
 
+    async fn open_process(
+        &mut self,
+        task: VPid,
+        sys_pid: SysPid,
+        status: ProcessStatus,
+    ) -> Result<Option<ExitStatus>, RuntimeError> {
+        if self.process_table.contains_key(&task) {
+            Err(RuntimeError::WrongProcessState)
+        } else {
+            let process = Process::open(sys_pid, &self.tracer, status)?;
+            let handle = process.to_handle();
+            if let Some(output) = &self.tagged_output {
+                output.process_started(sys_pid, task);
+            }
+            assert!(self.process_table.insert(task, process).is_none());
+            self.send_message(&MessageToSand::Task {
+                task,
+                op: ToTask::OpenProcessReply(handle),
+            })
+            .await?;
+            Ok(None)
+        }
+    }
+
     async fn handle_task_message(
         &mut self,
         task: VPid,
@@ -280,25 +792,25 @@ impl IPCServer {
             }
 
             FromTask::OpenProcess(sys_pid) => {
-                if self.process_table.contains_key(&task) {
-                    Err(RuntimeError::WrongProcessState)
-                } else {
-                    let process = Process::open(
-                        *sys_pid,
-                        &self.tracer,
-                        ProcessStatus {
-                            current_dir: Filesystem::root().clone(),
-                        },
-                    )?;
-                    let handle = process.to_handle();
-                    assert!(self.process_table.insert(task, process).is_none());
-                    self.send_message(&MessageToSand::Task {
-                        task,
-                        op: ToTask::OpenProcessReply(handle),
-                    })
-                    .await?;
-                    Ok(None)
-                }
+                let status = ProcessStatus {
+                    current_dir: Filesystem::root().clone(),
+                    exe: None,
+                };
+                self.open_process(task, *sys_pid, status).await
+            }
+
+            FromTask::OpenChildProcess { sys_pid, parent } => {
+                let status = match self.process_table.get(parent) {
+                    Some(parent) => ProcessStatus {
+                        current_dir: parent.status.current_dir.clone(),
+                        exe: parent.status.exe.clone(),
+                    },
+                    None => ProcessStatus {
+                        current_dir: Filesystem::root().clone(),
+                        exe: None,
+                    },
+                };
+                self.open_process(task, *sys_pid, status).await
             }
 
             FromTask::GetWorkingDir => match self.process_table.get_mut(&task) {
@@ -309,19 +821,25 @@ impl IPCServer {
                 }
             },
 
-            FromTask::ChangeWorkingDir(path) => match self.process_table.get_mut(&task) {
+            FromTask::ChangeWorkingDir { file, path } => match self.process_table.get_mut(&task) {
                 None => Err(RuntimeError::WrongProcessState)?,
                 Some(process) => {
-                    let result =
-                        taskcall::change_working_dir(process, &self.filesystem, path).await;
+                    let result = taskcall::change_working_dir(
+                        process,
+                        &self.filesystem,
+                        &self.path_remap,
+                        file,
+                        path,
+                    )
+                    .await;
                     self.task_reply(task, result).await
                 }
             },
 
-            FromTask::ReadLink(path) => match self.process_table.get_mut(&task) {
+            FromTask::ReadLink { dir, path } => match self.process_table.get_mut(&task) {
                 None => Err(RuntimeError::WrongProcessState)?,
                 Some(process) => {
-                    let result = taskcall::readlink(process, &self.filesystem, path).await;
+                    let result = taskcall::readlink(process, &self.filesystem, dir, path).await;
                     self.task_cstring_reply(task, result).await
                 }
             },
@@ -333,9 +851,15 @@ impl IPCServer {
             } => match self.process_table.get_mut(&task) {
                 None => Err(RuntimeError::WrongProcessState)?,
                 Some(process) => {
-                    let result =
-                        taskcall::file_stat(process, &self.filesystem, file, path, follow_links)
-                            .await;
+                    let result = taskcall::file_stat(
+                        process,
+                        &self.filesystem,
+                        &self.path_remap,
+                        file,
+                        path,
+                        follow_links,
+                    )
+                    .await;
                     self.task_stat_reply(task, result).await
                 }
             },
@@ -343,10 +867,18 @@ impl IPCServer {
             FromTask::FileAccess { dir, path, mode } => match self.process_table.get_mut(&task) {
                 None => Err(RuntimeError::WrongProcessState)?,
                 Some(process) => {
-                    let result =
-                        taskcall::file_open(process, &self.filesystem, dir, path, 0, *mode)
-                            .await
-                            .map(|_| ());
+                    let result = taskcall::file_open(
+                        process,
+                        &mut self.filesystem,
+                        &self.storage,
+                        &self.path_remap,
+                        dir,
+                        path,
+                        0,
+                        *mode,
+                    )
+                    .await
+                    .map(|_| ());
                     self.task_reply(task, result).await
                 }
             },
@@ -356,13 +888,62 @@ impl IPCServer {
                 path,
                 flags,
                 mode,
+            } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result = taskcall::file_open(
+                        process,
+                        &mut self.filesystem,
+                        &self.storage,
+                        &self.path_remap,
+                        dir,
+                        path,
+                        *flags,
+                        *mode,
+                    )
+                    .await;
+                    if let Ok(vfile) = &result {
+                        self.secrets.opened(task, vfile);
+                    }
+                    self.task_file_reply(task, result, *flags).await
+                }
+            },
+
+            FromTask::MakeDir { dir, path, mode } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::make_dir(process, &mut self.filesystem, dir, path, *mode).await;
+                    self.task_reply(task, result).await
+                }
+            },
+
+            FromTask::Unlink {
+                dir,
+                path,
+                remove_dir,
+            } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result =
+                        taskcall::unlink(process, &mut self.filesystem, dir, path, *remove_dir)
+                            .await;
+                    self.task_reply(task, result).await
+                }
+            },
+
+            FromTask::Rename {
+                from_dir,
+                from,
+                to_dir,
+                to,
             } => match self.process_table.get_mut(&task) {
                 None => Err(RuntimeError::WrongProcessState)?,
                 Some(process) => {
                     let result =
-                        taskcall::file_open(process, &self.filesystem, dir, path, *flags, *mode)
+                        taskcall::rename(process, &mut self.filesystem, from_dir, from, to_dir, to)
                             .await;
-                    self.task_file_reply(task, result).await
+                    self.task_reply(task, result).await
                 }
             },
 
@@ -371,15 +952,147 @@ impl IPCServer {
                 Some(_process) => self.task_reply(task, Ok(())).await,
             },
 
-            FromTask::Exited(exit_code) => Ok(Some(ExitStatus { code: *exit_code })),
+            FromTask::ExecSnapshotOpen => {
+                let saved = self.exec_snapshot.as_ref().and_then(ExecSnapshotSlot::get);
+                let reply = match &saved {
+                    None => Err(Errno::new(libc::ENOENT)),
+                    Some(saved) => {
+                        // Restoring the snapshot stands in for loading its program
+                        if let Some(process) = self.process_table.get_mut(&task) {
+                            process.status.exe = saved.exe.clone();
+                        }
+                        Ok((
+                            SysFd(saved.file.as_raw_fd() as u32),
+                            saved.file.metadata()?.len() as usize,
+                        ))
+                    }
+                };
+                self.send_message(&MessageToSand::Task {
+                    task,
+                    op: ToTask::BytesReply(reply),
+                })
+                .await?;
+                Ok(None)
+            }
+
+            FromTask::ExecSnapshotCapture {
+                ip,
+                sp,
+                brk,
+                brk_start,
+            } => match self.process_table.get(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let header = ExecSnapshotHeader {
+                        ip: ip.0,
+                        sp: sp.0,
+                        brk: brk.0,
+                        brk_start: brk_start.0,
+                        random: 0,
+                        region_count: 0,
+                    };
+                    let result = match &self.exec_snapshot {
+                        None => Err(Errno::new(libc::ENOSYS)),
+                        Some(slot) => slot.capture(process, header).map_err(|err| {
+                            log::warn!("exec snapshot not saved, {}", err);
+                            Errno::new(libc::EIO)
+                        }),
+                    };
+                    self.task_reply(task, result).await
+                }
+            },
+
+            FromTask::Exited(exit_code) if task == VPid(1) => {
+                Ok(Some(ExitStatus::exited(*exit_code, self.last_signal)))
+            }
+
+            FromTask::Signaled(signal) if task == VPid(1) => {
+                Ok(Some(ExitStatus::signaled(signal.0 as i32)))
+            }
+
+            FromTask::Exited(_) => {
+                self.process_table.remove(&task);
+                Ok(None)
+            }
+
+            FromTask::Signaled(signal) => {
+                self.process_table.remove(&task);
+                self.last_signal = Some((task, signal.0 as i32));
+                Ok(None)
+            }
+
+            FromTask::ExecLoaded { file, path } => match self.process_table.get_mut(&task) {
+                None => Err(RuntimeError::WrongProcessState)?,
+                Some(process) => {
+                    let result = taskcall::exec_loaded(
+                        process,
+                        &self.filesystem,
+                        &self.path_remap,
+                        file,
+                        path,
+                    )
+                    .await;
+                    if let Err(err) = result {
+                        log::warn!("can't name the program loaded by {:?}, {:?}", task, err);
+                    }
+                    self.hooks
+                        .run(HookStage::PostExec, &mut self.filesystem, &self.storage)
+                        .await?;
+                    Ok(None)
+                }
+            },
+
+            FromTask::NetBind { addr, len } => {
+                let network = self.network();
+                match self.process_table.get_mut(&task) {
+                    None => Err(RuntimeError::WrongProcessState)?,
+                    Some(process) => {
+                        let result = match network {
+                            Err(e) => Err(e),
+                            Ok(network) => taskcall::net_bind(process, &network, *addr, *len).await,
+                        };
+                        self.task_socket_reply(task, result).await
+                    }
+                }
+            }
+
+            FromTask::NetConnect { addr, len } => {
+                let network = self.network();
+                match self.process_table.get_mut(&task) {
+                    None => Err(RuntimeError::WrongProcessState)?,
+                    Some(process) => {
+                        let result = match network {
+                            Err(e) => Err(e),
+                            Ok(network) => {
+                                taskcall::net_connect(process, &network, *addr, *len).await
+                            }
+                        };
+                        self.task_socket_reply(task, result).await
+                    }
+                }
+            }
+
+            FromTask::SyscallLatency {
+                nr,
+                trap,
+                emulate,
+                ipc,
+                resume,
+            } => {
+                self.latency
+                    .syscall(task, *nr, *trap, *emulate, *ipc, *resume);
+                Ok(None)
+            }
+
+            FromTask::SyscallStorm(call) => {
+                log::warn!(
+                    "{:?} is stuck retrying syscall {} which fails with {}",
+                    task,
+                    call.nr,
+                    call.ret
+                );
+                Ok(None)
+            }
         }
     }
 }
-
-fn clear_close_on_exec_flag(fd: RawFd) {
-    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
-    assert!(flags >= 0);
-    let flags = flags & !libc::FD_CLOEXEC;
-    let result = unsafe { libc::fcntl(fd, libc::F_SETFD, flags) };
-    assert_eq!(result, 0);
-}
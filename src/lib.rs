@@ -20,9 +20,11 @@
 //!
 //! Let's make it easy to run somewhat-untrusted computational workloads
 //! like media codecs from inside an existing async rust app. There is
-//! no networking or traditional storage support inside containers. The
-//! container uses a virtual filesystem backed by read-only image contents and
-//! mounted I/O channels.
+//! no external networking or traditional storage support inside containers.
+//! The container uses a virtual filesystem backed by read-only image contents
+//! and mounted I/O channels. Containers may share a loopback-only
+//! [NetworkGroup] for testing multi-service systems, and a [ComposeSpec] can
+//! start several of them together in dependency order.
 //!
 //! Getting Started
 //! ===============
@@ -42,6 +44,15 @@
 //! }
 //! ```
 //!
+//! Bandsocks is built on tokio, and its I/O, timers and tasks always run on
+//! a tokio runtime. It isn't executor-agnostic: an application using
+//! async-std or smol still gets tokio as a dependency, and bandsocks starts a
+//! second executor for itself, a private tokio runtime on a background
+//! thread. Each container's own task runs there in any case. What the
+//! application does get is futures that can be awaited from any executor,
+//! and stdio streams which implement the I/O traits from both tokio and the
+//! `futures` crate.
+//!
 //! Architecture
 //! ============
 //!
@@ -82,13 +93,24 @@ mod ipcserver;
 mod manifest;
 mod process;
 mod registry;
+mod rt;
 mod sand;
 mod taskcall;
 
 pub use crate::{
     container::*,
     errors::*,
-    filesystem::{mount::*, socket::*},
+    filesystem::{
+        bind::BindMount,
+        hostfiles::HostFiles,
+        mount::*,
+        socket::*,
+        tmpfs::Tmpfs,
+        vfs::{DirEntry, FileType, Filesystem, ReadDir},
+        volume::Volume,
+        workspace::Workspaces,
+    },
     image::*,
     registry::*,
+    sand::protocol::{FileStat, FollowLinks, LogLevel, Signal, VFile, VPid},
 };
@@ -1,4 +1,7 @@
-use serde::{Deserialize, Serialize};
+use crate::errors::ImageError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{env, fmt};
 
 /// Partial implementation of the manifest v2 schema2 spec.
 ///
@@ -17,10 +20,57 @@ pub struct Link {
     pub digest: String,
 }
 
+/// One page of results from the registry's tag listing API
+///
+/// Reference: https://docs.docker.com/registry/spec/api/#listing-image-tags
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TagList {
+    pub name: String,
+    pub tags: Option<Vec<String>>,
+}
+
 pub mod media_types {
     pub const MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+    pub const MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+    pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+    pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
     pub const RUNTIME_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+    pub const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
     pub const LAYER_TAR_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+    pub const LAYER_TAR: &str = "application/vnd.docker.image.rootfs.diff.tar";
+    pub const OCI_LAYER_TAR_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+    pub const OCI_LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+}
+
+/// The `index.json` at the top of an OCI image layout, or a nested index
+///
+/// Reference: https://github.com/opencontainers/image-spec/blob/master/image-index.md
+#[derive(Clone, Debug, Default)]
+pub struct ImageIndex {
+    pub manifests: Vec<IndexEntry>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IndexEntry {
+    pub link: Link,
+    pub platform: Option<Platform>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+/// One image from the `manifest.json` written by `docker save`
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveManifest {
+    /// Path of the runtime config within the archive
+    pub config: String,
+    /// Paths of each layer within the archive, bottom layer first
+    pub layers: Vec<String>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -57,3 +107,381 @@ pub struct Filesystem {
     pub fs_type: String,
     pub diff_ids: Vec<String>,
 }
+
+impl Manifest {
+    /// Parse a manifest, reporting the first field that doesn't match the
+    /// schema
+    pub fn parse(json: &[u8]) -> Result<Manifest, ImageError> {
+        let doc = Document::parse("manifest", json)?;
+        match doc.optional::<String>("/mediaType")? {
+            None => (),
+            Some(media_type) if media_type == media_types::MANIFEST => (),
+            Some(media_type) if media_type == media_types::OCI_MANIFEST => (),
+            Some(media_type)
+                if media_type == media_types::MANIFEST_LIST
+                    || media_type == media_types::OCI_INDEX =>
+            {
+                return Err(ImageError::UnsupportedManifestList(media_type))
+            }
+            Some(media_type) => return Err(ImageError::UnsupportedManifestType(media_type)),
+        }
+        if doc.optional::<Vec<Value>>("/manifests")?.is_some() {
+            return Err(ImageError::UnsupportedManifestList(
+                media_types::MANIFEST_LIST.to_string(),
+            ));
+        }
+        match doc.field::<u64>("/schemaVersion")? {
+            2 => (),
+            other => return Err(doc.invalid("/schemaVersion", format!("version {}", other))),
+        }
+        let num_layers = doc.field::<Vec<Value>>("/layers")?.len();
+        Ok(Manifest {
+            config: Link::parse(&doc, "/config")?,
+            layers: (0..num_layers)
+                .map(|index| Link::parse(&doc, &format!("/layers/{}", index)))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl ImageIndex {
+    /// Parse an image index, reporting the first field that doesn't match
+    /// the schema
+    pub fn parse(json: &[u8]) -> Result<ImageIndex, ImageError> {
+        let doc = Document::parse("image index", json)?;
+        match doc.field::<u64>("/schemaVersion")? {
+            2 => (),
+            other => return Err(doc.invalid("/schemaVersion", format!("version {}", other))),
+        }
+        let num_manifests = doc.field::<Vec<Value>>("/manifests")?.len();
+        Ok(ImageIndex {
+            manifests: (0..num_manifests)
+                .map(|index| {
+                    let pointer = format!("/manifests/{}", index);
+                    Ok(IndexEntry {
+                        link: Link::parse(&doc, &pointer)?,
+                        platform: doc.optional(&format!("{}/platform", pointer))?,
+                    })
+                })
+                .collect::<Result<_, ImageError>>()?,
+        })
+    }
+}
+
+impl ImageIndex {
+    /// Does this document list per-platform manifests, rather than describe
+    /// one image?
+    pub fn is_index(json: &[u8]) -> bool {
+        let value: Value = match serde_json::from_slice(json) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        match value.get("mediaType").and_then(Value::as_str) {
+            Some(media_type) => {
+                media_type == media_types::MANIFEST_LIST || media_type == media_types::OCI_INDEX
+            }
+            None => value.get("manifests").is_some(),
+        }
+    }
+
+    /// Choose the first entry for a platform
+    ///
+    /// Entries that don't say which platform they're for are assumed to
+    /// match.
+    pub fn select(self, platform: &Platform) -> Result<IndexEntry, ImageError> {
+        self.manifests
+            .into_iter()
+            .find(|entry| match &entry.platform {
+                None => true,
+                Some(entry_platform) => platform.matches(entry_platform),
+            })
+            .ok_or_else(|| ImageError::NoMatchingPlatform(platform.to_string()))
+    }
+}
+
+impl Platform {
+    /// The platform of the current host, with architectures named the way
+    /// registries name them
+    pub fn host() -> Platform {
+        let architecture = match env::consts::ARCH {
+            "x86_64" => "amd64",
+            "x86" => "386",
+            "aarch64" => "arm64",
+            "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+            "powerpc64" => "ppc64",
+            other => other,
+        };
+        Platform {
+            architecture: architecture.to_string(),
+            os: env::consts::OS.to_string(),
+            variant: None,
+        }
+    }
+
+    /// Parse an `os/architecture[/variant]` string, like `linux/arm64/v8`
+    pub fn parse(s: &str) -> Result<Platform, ImageError> {
+        let parts: Vec<&str> = s.split('/').collect();
+        match &parts[..] {
+            [os, architecture] if !os.is_empty() && !architecture.is_empty() => Ok(Platform {
+                architecture: architecture.to_string(),
+                os: os.to_string(),
+                variant: None,
+            }),
+            [os, architecture, variant]
+                if !os.is_empty() && !architecture.is_empty() && !variant.is_empty() =>
+            {
+                Ok(Platform {
+                    architecture: architecture.to_string(),
+                    os: os.to_string(),
+                    variant: Some(variant.to_string()),
+                })
+            }
+            _ => Err(ImageError::InvalidPlatform(s.to_string())),
+        }
+    }
+
+    /// Can an image for the other platform run here?
+    ///
+    /// A platform with no variant accepts any variant of its architecture.
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+impl ArchiveManifest {
+    /// Parse the first image listed in a `docker save` archive's
+    /// `manifest.json`
+    pub fn parse(json: &[u8]) -> Result<ArchiveManifest, ImageError> {
+        let doc = Document::parse("archive manifest", json)?;
+        let num_images = doc.field::<Vec<Value>>("")?.len();
+        if num_images != 1 {
+            return Err(doc.invalid("", format!("{} images, expected 1", num_images)));
+        }
+        Ok(ArchiveManifest {
+            config: doc.field("/0/Config")?,
+            layers: doc.field("/0/Layers")?,
+        })
+    }
+}
+
+impl Link {
+    fn parse(doc: &Document, pointer: &str) -> Result<Link, ImageError> {
+        Ok(Link {
+            media_type: doc.field(&format!("{}/mediaType", pointer))?,
+            size: doc.field(&format!("{}/size", pointer))?,
+            digest: doc.field(&format!("{}/digest", pointer))?,
+        })
+    }
+}
+
+impl RuntimeConfig {
+    /// Parse a runtime config, reporting the first field that doesn't match
+    /// the schema
+    pub fn parse(json: &[u8]) -> Result<RuntimeConfig, ImageError> {
+        let doc = Document::parse("runtime config", json)?;
+        Ok(RuntimeConfig {
+            architecture: doc.field("/architecture")?,
+            // Images built without docker may leave out any of these
+            config: ImageConfig {
+                user: doc.optional("/config/User")?.unwrap_or_default(),
+                env: doc.optional("/config/Env")?.unwrap_or_default(),
+                cmd: doc.optional("/config/Cmd")?.unwrap_or_default(),
+                image: doc.optional("/config/Image")?.unwrap_or_default(),
+                working_dir: doc.optional("/config/WorkingDir")?.unwrap_or_default(),
+                entrypoint: doc.optional("/config/Entrypoint")?,
+            },
+            created: doc.optional("/created")?.unwrap_or_default(),
+            docker_version: doc.optional("/docker_version")?.unwrap_or_default(),
+            os: doc.field("/os")?,
+            rootfs: Filesystem {
+                fs_type: doc.field("/rootfs/type")?,
+                diff_ids: doc.field("/rootfs/diff_ids")?,
+            },
+        })
+    }
+}
+
+/// A JSON document being validated, with fields addressed by JSON pointer
+struct Document {
+    name: &'static str,
+    value: Value,
+}
+
+impl Document {
+    fn parse(name: &'static str, json: &[u8]) -> Result<Document, ImageError> {
+        let value = serde_json::from_slice(json)?;
+        Ok(Document { name, value })
+    }
+
+    fn invalid(&self, pointer: &str, reason: String) -> ImageError {
+        ImageError::InvalidJSONField {
+            document: self.name,
+            field: pointer.to_string(),
+            reason,
+        }
+    }
+
+    fn field<T: DeserializeOwned>(&self, pointer: &str) -> Result<T, ImageError> {
+        match self.optional(pointer)? {
+            Some(value) => Ok(value),
+            None => Err(self.invalid(pointer, "missing".to_string())),
+        }
+    }
+
+    fn optional<T: DeserializeOwned>(&self, pointer: &str) -> Result<Option<T>, ImageError> {
+        match self.value.pointer(pointer) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => T::deserialize(value)
+                .map(Some)
+                .map_err(|err| self.invalid(pointer, err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAYER: &str = r#"{
+        "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+        "size": 100,
+        "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000000"
+    }"#;
+
+    fn manifest_json(media_type: &str, layer: &str) -> String {
+        format!(
+            r#"{{"schemaVersion": 2, "mediaType": "{}", "config": {}, "layers": [{}, {}]}}"#,
+            media_type, LAYER, LAYER, layer
+        )
+    }
+
+    fn expect_invalid_field<T: std::fmt::Debug>(result: Result<T, ImageError>, expected: &str) {
+        match result {
+            Err(ImageError::InvalidJSONField { field, .. }) => assert_eq!(field, expected),
+            other => panic!("unexpected result, {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = Manifest::parse(manifest_json(media_types::MANIFEST, LAYER).as_bytes());
+        let manifest = manifest.unwrap();
+        assert_eq!(manifest.layers.len(), 2);
+        assert_eq!(manifest.config.size, 100);
+    }
+
+    #[test]
+    fn manifest_bad_layer_field() {
+        let layer = r#"{"mediaType": "x", "size": "big", "digest": "x"}"#;
+        expect_invalid_field(
+            Manifest::parse(manifest_json(media_types::MANIFEST, layer).as_bytes()),
+            "/layers/1/size",
+        );
+        let layer = r#"{"mediaType": "x", "size": 1}"#;
+        expect_invalid_field(
+            Manifest::parse(manifest_json(media_types::MANIFEST, layer).as_bytes()),
+            "/layers/1/digest",
+        );
+    }
+
+    #[test]
+    fn manifest_schema_version() {
+        let json = r#"{"schemaVersion": 1, "layers": []}"#;
+        expect_invalid_field(Manifest::parse(json.as_bytes()), "/schemaVersion");
+    }
+
+    #[test]
+    fn manifest_list_unsupported() {
+        match Manifest::parse(manifest_json(media_types::OCI_INDEX, LAYER).as_bytes()) {
+            Err(ImageError::UnsupportedManifestList(media_type)) => {
+                assert_eq!(media_type, media_types::OCI_INDEX)
+            }
+            other => panic!("unexpected result, {:?}", other),
+        }
+        match Manifest::parse(manifest_json("text/plain", LAYER).as_bytes()) {
+            Err(ImageError::UnsupportedManifestType(media_type)) => {
+                assert_eq!(media_type, "text/plain")
+            }
+            other => panic!("unexpected result, {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_index_and_archive_manifest() {
+        let json = format!(
+            r#"{{"schemaVersion": 2, "manifests": [{}, {}]}}"#,
+            LAYER,
+            LAYER.replace(
+                "}",
+                r#", "platform": {"architecture": "arm64", "os": "linux"}}"#
+            )
+        );
+        let index = ImageIndex::parse(json.as_bytes()).unwrap();
+        assert!(index.manifests[0].platform.is_none());
+        assert_eq!(
+            index.manifests[1].platform.as_ref().unwrap().architecture,
+            "arm64"
+        );
+
+        let json = r#"[{"Config": "abc.json", "RepoTags": null, "Layers": ["1/layer.tar"]}]"#;
+        let archive = ArchiveManifest::parse(json.as_bytes()).unwrap();
+        assert_eq!(archive.config, "abc.json");
+        assert_eq!(archive.layers, vec!["1/layer.tar".to_string()]);
+        expect_invalid_field(ArchiveManifest::parse(b"[]"), "");
+    }
+
+    #[test]
+    fn select_platform() {
+        let entry =
+            |platform: &str| LAYER.replace("}", &format!(r#", "platform": {}}}"#, platform));
+        let json = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "{}", "manifests": [{}, {}, {}]}}"#,
+            media_types::MANIFEST_LIST,
+            entry(r#"{"architecture": "arm", "os": "linux", "variant": "v7"}"#),
+            entry(r#"{"architecture": "arm64", "os": "linux", "variant": "v8"}"#),
+            entry(r#"{"architecture": "amd64", "os": "linux"}"#),
+        );
+        assert!(ImageIndex::is_index(json.as_bytes()));
+        assert!(!ImageIndex::is_index(
+            manifest_json(media_types::MANIFEST, LAYER).as_bytes()
+        ));
+        let index = ImageIndex::parse(json.as_bytes()).unwrap();
+        let select = |platform: &str| {
+            index
+                .clone()
+                .select(&Platform::parse(platform).unwrap())
+                .map(|entry| entry.platform.unwrap().to_string())
+        };
+        assert_eq!(select("linux/amd64").unwrap(), "linux/amd64");
+        assert_eq!(select("linux/arm64").unwrap(), "linux/arm64/v8");
+        assert_eq!(select("linux/arm/v7").unwrap(), "linux/arm/v7");
+        match select("linux/arm/v6") {
+            Err(ImageError::NoMatchingPlatform(platform)) => assert_eq!(platform, "linux/arm/v6"),
+            other => panic!("unexpected result, {:?}", other),
+        }
+        assert!(Platform::parse("linux").is_err());
+        assert!(Platform::parse("linux//v7").is_err());
+    }
+
+    #[test]
+    fn runtime_config_bad_field() {
+        let json = r#"{
+            "architecture": "amd64", "created": "", "docker_version": "", "os": "linux",
+            "rootfs": {"type": "layers", "diff_ids": []},
+            "config": {"User": "", "Env": "PATH=/bin", "Cmd": [], "Image": "", "WorkingDir": ""}
+        }"#;
+        expect_invalid_field(RuntimeConfig::parse(json.as_bytes()), "/config/Env");
+    }
+}
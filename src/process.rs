@@ -5,9 +5,15 @@ use crate::{
 use regex::Regex;
 use std::{
     ffi::{OsStr, OsString},
-    fs::File,
-    io::Read,
-    os::unix::{ffi::OsStrExt, fs::FileExt, io::AsRawFd},
+    fs,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, MetadataExt, OpenOptionsExt},
+        io::{AsRawFd, FromRawFd},
+    },
+    path::PathBuf,
 };
 use tokio::process::Child;
 
@@ -33,6 +39,16 @@ fn page_remaining(vptr: VPtr) -> usize {
 pub struct ProcessStatus {
     // todo: uid, gid, loads of other stuff here.
     pub current_dir: VFile,
+    /// The program this process last loaded with exec, if it has yet
+    pub exe: Option<Executable>,
+}
+
+/// A program file, and the path /proc/self/exe shows for it
+#[derive(Debug, Clone)]
+pub struct Executable {
+    pub file: VFile,
+    /// Absolute, with every symbolic link resolved
+    pub path: PathBuf,
 }
 
 #[derive(Debug)]
@@ -41,10 +57,29 @@ pub struct MemFile(File);
 #[derive(Debug)]
 pub struct MapsFile(File);
 
+/// What a blocked process is waiting in, absent where /proc won't show it
+#[derive(Debug)]
+pub struct SyscallFile(Option<File>);
+
+/// Resident memory totals, absent on kernels without smaps_rollup
+#[derive(Debug)]
+pub struct SmapsFile(Option<File>);
+
+/// The process's open file descriptors, absent if /proc won't show them
+#[derive(Debug)]
+pub struct FdDir(Option<File>);
+
+/// A pidfd keeps referring to the same process even after its pid is reused
+#[derive(Debug)]
+struct PidFd(File);
+
 #[derive(Debug)]
 pub struct Process {
     pub mem: MemFile,
     pub maps: MapsFile,
+    pub syscall: SyscallFile,
+    pub smaps: SmapsFile,
+    pub fds: FdDir,
     pub status: ProcessStatus,
 }
 
@@ -54,12 +89,36 @@ impl Process {
         tracer: &Child,
         status: ProcessStatus,
     ) -> Result<Process, RuntimeError> {
-        // Check before and after opening the file, to prevent PID races
+        // Files opened by pid are only trusted if the process they were meant for
+        // is still alive afterward. With a pidfd that's a direct question; older
+        // kernels fall back on checking the pid before and after opening.
+        let pidfd = PidFd::open(sys_pid)?;
         check_can_open(sys_pid, tracer)?;
         let mem = MemFile::open(sys_pid)?;
         let maps = MapsFile::open(sys_pid)?;
-        check_can_open(sys_pid, tracer)?;
-        Ok(Process { mem, maps, status })
+        let syscall = SyscallFile::open(sys_pid);
+        let smaps = SmapsFile::open(sys_pid);
+        let fds = FdDir::open(sys_pid);
+        match &pidfd {
+            Some(pidfd) => pidfd.check_alive()?,
+            None => check_can_open(sys_pid, tracer)?,
+        }
+        Ok(Process {
+            mem,
+            maps,
+            syscall,
+            smaps,
+            fds,
+            status,
+        })
+    }
+
+    /// Is this process sleeping inside a read, waiting on input?
+    pub fn is_blocked_reading(&self) -> bool {
+        match self.syscall.current() {
+            Some(nr) => nr == libc::SYS_read || nr == libc::SYS_readv || nr == libc::SYS_pread64,
+            None => false,
+        }
     }
 
     pub fn to_handle(&self) -> ProcessHandle {
@@ -77,8 +136,9 @@ impl MemFile {
             .map_err(|_| RuntimeError::MemAccess)
     }
 
-    pub fn read_user_string(&self, vstr: &VString) -> Result<String, Errno> {
-        self.read_string(vstr).map_err(|_| Errno(-libc::EFAULT))
+    pub fn read_user_string(&self, vstr: &VString) -> Result<OsString, Errno> {
+        self.read_string_os(vstr)
+            .map_err(|_| Errno::new(libc::EFAULT))
     }
 
     pub fn read_string(&self, vstr: &VString) -> Result<String, RuntimeError> {
@@ -114,11 +174,177 @@ impl MemFile {
     }
 }
 
+/// One line from a process's memory map
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MapsRegion {
+    pub start: VPtr,
+    pub end: VPtr,
+    /// Protection as PROT_* bits
+    pub prot: usize,
+    /// File path or pseudo-path like `[stack]`, if the mapping has one
+    pub name: Option<String>,
+}
+
+impl MapsRegion {
+    fn parse(line: &str) -> Result<Self, RuntimeError> {
+        let mut fields = line.split_whitespace();
+        let mut next = || fields.next().ok_or(RuntimeError::MapsFormat);
+        let range = next()?;
+        let perms = next()?.as_bytes();
+        let (_offset, _dev, _inode) = (next()?, next()?, next()?);
+        let name = fields.collect::<Vec<_>>().join(" ");
+        let name = if name.is_empty() { None } else { Some(name) };
+        let mut range = range.splitn(2, '-');
+        let mut addr = || {
+            range
+                .next()
+                .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+                .map(VPtr)
+                .ok_or(RuntimeError::MapsFormat)
+        };
+        let (start, end) = (addr()?, addr()?);
+        if perms.len() != 4 {
+            return Err(RuntimeError::MapsFormat);
+        }
+        let bit = |index: usize, flag: char, prot: i32| {
+            if perms[index] == flag as u8 {
+                prot as usize
+            } else {
+                0
+            }
+        };
+        let prot = bit(0, 'r', libc::PROT_READ)
+            | bit(1, 'w', libc::PROT_WRITE)
+            | bit(2, 'x', libc::PROT_EXEC);
+        Ok(MapsRegion {
+            start,
+            end,
+            prot,
+            name,
+        })
+    }
+}
+
 impl MapsFile {
     fn open(sys_pid: SysPid) -> Result<Self, RuntimeError> {
         let path = format!("/proc/{}/maps", sys_pid.0);
         Ok(MapsFile(File::open(path)?))
     }
+
+    /// Read every mapping currently in the process
+    pub fn regions(&self) -> Result<Vec<MapsRegion>, RuntimeError> {
+        let mut text = String::new();
+        (&self.0).seek(SeekFrom::Start(0))?;
+        (&self.0).read_to_string(&mut text)?;
+        text.lines().map(MapsRegion::parse).collect()
+    }
+}
+
+impl SyscallFile {
+    /// Kernels without CONFIG_HAVE_ARCH_TRACEHOOK have no syscall file, and
+    /// their processes never look blocked
+    fn open(sys_pid: SysPid) -> Self {
+        let path = format!("/proc/{}/syscall", sys_pid.0);
+        SyscallFile(File::open(path).ok())
+    }
+
+    /// The syscall a blocked process is sleeping in, or None while it's
+    /// running. The kernel reports -1 for a blocked process outside any
+    /// syscall.
+    pub fn current(&self) -> Option<i64> {
+        let mut buf = [0u8; 256];
+        let len = self.0.as_ref()?.read_at(&mut buf, 0).ok()?;
+        let text = std::str::from_utf8(&buf[..len]).ok()?;
+        text.split_whitespace().next()?.parse().ok()
+    }
+}
+
+impl SmapsFile {
+    fn open(sys_pid: SysPid) -> Self {
+        let path = format!("/proc/{}/smaps_rollup", sys_pid.0);
+        SmapsFile(File::open(path).ok())
+    }
+
+    /// Resident bytes in anonymous and file-backed mappings
+    pub fn resident(&self) -> Option<(u64, u64)> {
+        let mut text = String::new();
+        let mut file = self.0.as_ref()?;
+        file.seek(SeekFrom::Start(0)).ok()?;
+        file.read_to_string(&mut text).ok()?;
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name))
+                .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+                .map(|kb: u64| kb * 1024)
+        };
+        let rss = field("Rss:")?;
+        let anon = field("Anonymous:")?;
+        Some((anon, rss.saturating_sub(anon)))
+    }
+}
+
+impl FdDir {
+    fn open(sys_pid: SysPid) -> Self {
+        let path = format!("/proc/{}/fd", sys_pid.0);
+        FdDir(
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECTORY)
+                .open(path)
+                .ok(),
+        )
+    }
+
+    /// Device and inode numbers of every file the process has open
+    pub fn open_files(&self) -> Option<Vec<(u64, u64)>> {
+        let dir = format!("/proc/self/fd/{}", self.0.as_ref()?.as_raw_fd());
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).ok()? {
+            // Each entry links to the open file, and may be closed by now
+            if let Ok(metadata) = fs::metadata(entry.ok()?.path()) {
+                files.push((metadata.dev(), metadata.ino()));
+            }
+        }
+        Some(files)
+    }
+}
+
+impl PidFd {
+    /// Returns None if the kernel predates pidfd_open (Linux 5.3)
+    fn open(sys_pid: SysPid) -> Result<Option<Self>, RuntimeError> {
+        let flags = 0;
+        let result =
+            unsafe { libc::syscall(libc::SYS_pidfd_open, sys_pid.0 as libc::pid_t, flags) };
+        if result >= 0 {
+            Ok(Some(PidFd(unsafe { File::from_raw_fd(result as i32) })))
+        } else {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ENOSYS) => Ok(None),
+                Some(libc::ESRCH) => Err(RuntimeError::InvalidPid),
+                _ => Err(err.into()),
+            }
+        }
+    }
+
+    /// Signal 0 only checks whether the process can be signaled, which fails
+    /// once it has exited
+    fn check_alive(&self) -> Result<(), RuntimeError> {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.0.as_raw_fd(),
+                0,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(RuntimeError::InvalidPid)
+        }
+    }
 }
 
 fn read_proc_status(sys_pid: SysPid) -> Result<String, RuntimeError> {
@@ -152,6 +378,44 @@ fn check_can_open(sys_pid: SysPid, tracer: &Child) -> Result<(), RuntimeError> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn maps_region_parse() {
+        assert_eq!(
+            MapsRegion::parse(
+                "7f1c2a400000-7f1c2a428000 r-xp 00000000 08:01 1234    /lib/ld musl.so"
+            )
+            .unwrap(),
+            MapsRegion {
+                start: VPtr(0x7f1c2a400000),
+                end: VPtr(0x7f1c2a428000),
+                prot: (libc::PROT_READ | libc::PROT_EXEC) as usize,
+                name: Some("/lib/ld musl.so".to_string()),
+            }
+        );
+        assert_eq!(
+            MapsRegion::parse("1000-3000 ---p 00000000 00:00 0").unwrap(),
+            MapsRegion {
+                start: VPtr(0x1000),
+                end: VPtr(0x3000),
+                prot: 0,
+                name: None,
+            }
+        );
+        assert!(MapsRegion::parse("1000-3000 rw-p").is_err());
+    }
+
+    #[test]
+    fn maps_regions_from_self() {
+        let self_pid = SysPid(unsafe { libc::getpid() as u32 });
+        let maps = MapsFile::open(self_pid).unwrap();
+        let regions = maps.regions().unwrap();
+        assert!(regions
+            .iter()
+            .any(|region| region.name.as_deref() == Some("[stack]")));
+        // Reading again starts over from the beginning
+        assert!(!maps.regions().unwrap().is_empty());
+    }
+
     #[test]
     fn string_read_from_self() {
         let self_pid = SysPid(unsafe { libc::getpid() as u32 });
@@ -230,4 +494,32 @@ mod tests {
 
         unsafe { libc::munmap(map_addr.0 as *mut libc::c_void, map_total_size) };
     }
+
+    #[test]
+    fn non_utf8_string_read_from_self() {
+        let self_pid = SysPid(unsafe { libc::getpid() as u32 });
+        let self_mem = MemFile::open(self_pid).unwrap();
+        let name = b"caf\xe9/\xff\xfe.txt\0";
+        let vstr = VString(VPtr(name.as_ptr() as usize));
+
+        let readback = self_mem.read_user_string(&vstr).unwrap();
+        assert_eq!(readback.as_bytes(), &name[..name.len() - 1]);
+        assert!(self_mem.read_string(&vstr).is_err());
+    }
+
+    #[test]
+    fn pidfd_self_alive() {
+        let self_pid = SysPid(unsafe { libc::getpid() as u32 });
+        if let Some(pidfd) = PidFd::open(self_pid).unwrap() {
+            pidfd.check_alive().unwrap();
+        }
+    }
+
+    #[test]
+    fn syscall_self_reading() {
+        // Reading our own syscall file catches this thread inside that read
+        let self_tid = SysPid(unsafe { libc::syscall(libc::SYS_gettid) as u32 });
+        let syscall = SyscallFile::open(self_tid).unwrap();
+        assert_eq!(syscall.current(), Some(libc::SYS_pread64));
+    }
 }
@@ -1,12 +1,16 @@
 use crate::{errors::ImageError, image::Registry};
 use regex::Regex;
 use reqwest::{header, RequestBuilder, Response, StatusCode, Url};
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
+
+/// Delay used when a rate limited response has no usable `Retry-After`
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct Auth {
     logins: HashMap<Registry, Login>,
     tokens: HashMap<Registry, Token>,
+    rate_limit_wait: Option<Duration>,
 }
 
 #[derive(Clone)]
@@ -20,9 +24,15 @@ impl Auth {
         Auth {
             logins: HashMap::new(),
             tokens: HashMap::new(),
+            rate_limit_wait: None,
         }
     }
 
+    /// Allow waiting out rate limits, up to a total delay per request
+    pub fn wait_for_rate_limits(&mut self, max_wait: Duration) {
+        self.rate_limit_wait = Some(max_wait);
+    }
+
     pub fn login(&mut self, registry: Registry, username: String, password: Option<String>) {
         self.logins.insert(registry, Login { username, password });
     }
@@ -53,14 +63,20 @@ impl Auth {
             Some(login) => req.basic_auth(&login.username, login.password.as_ref()),
             None => req,
         };
-        let response: Token = req.send().await?.error_for_status()?.json().await?;
+        let response = req.send().await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(ImageError::RateLimited {
+                retry_after: retry_after(&response),
+            });
+        }
+        let response: Token = response.error_for_status()?.json().await?;
         log::debug!("received token for {}", registry);
         self.tokens.insert(registry.clone(), response);
         Ok(())
     }
 
-    /// Send a request, with one auth attempt and retry if a 401 error comes
-    /// back the first time.
+    /// Send a request, waiting and retrying while the registry is rate
+    /// limiting us if that's allowed.
     ///
     /// Requires a request that can be cloned (no stream data)
     pub async fn request(
@@ -68,6 +84,40 @@ impl Auth {
         registry: &Registry,
         client: &reqwest::Client,
         req: RequestBuilder,
+    ) -> Result<Response, ImageError> {
+        let mut waited = Duration::from_secs(0);
+        loop {
+            let response = self
+                .request_once(
+                    registry,
+                    client,
+                    req.try_clone()
+                        .expect("not expecting unclonable requests here"),
+                )
+                .await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+            let retry_after = retry_after(&response);
+            let delay = retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+            match self.rate_limit_wait {
+                Some(max_wait) if waited + delay <= max_wait => {
+                    log::warn!("rate limited by {}, retrying in {:?}", registry, delay);
+                    tokio::time::delay_for(delay).await;
+                    waited += delay;
+                }
+                _ => return Err(ImageError::RateLimited { retry_after }),
+            }
+        }
+    }
+
+    /// Send a request, with one auth attempt and retry if a 401 error comes
+    /// back the first time.
+    async fn request_once(
+        &mut self,
+        registry: &Registry,
+        client: &reqwest::Client,
+        req: RequestBuilder,
     ) -> Result<Response, ImageError> {
         let response = self
             .include_token(
@@ -96,6 +146,21 @@ impl Auth {
     }
 }
 
+/// Delay requested by a `Retry-After` header
+///
+/// Only the delay-seconds form is understood, an HTTP date counts as missing.
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
 #[derive(Debug, Clone)]
 struct BearerChallenge {
     realm: Url,
@@ -159,3 +224,21 @@ impl BearerChallenge {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn retry_after_unsupported() {
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after(""), None);
+    }
+}
@@ -4,7 +4,8 @@ use crate::{
     errors::ImageError,
     filesystem::storage::FileStorage,
     image::Registry,
-    registry::{auth::Auth, DefaultRegistry, RegistryClient},
+    manifest::Platform,
+    registry::{auth::Auth, DefaultRegistry, PullPolicy, RegistryClient},
 };
 
 use reqwest::{
@@ -20,6 +21,11 @@ use std::{
 };
 use tempfile::TempDir;
 
+/// Layers downloaded at once unless
+/// [RegistryClientBuilder::max_concurrent_downloads()] says otherwise, the
+/// same as docker's default
+const DEFAULT_CONCURRENT_DOWNLOADS: usize = 3;
+
 enum CacheOption {
     Default,
     Dir(PathBuf),
@@ -34,6 +40,10 @@ pub struct RegistryClientBuilder {
     default_registry: Option<DefaultRegistry>,
     allowed_registries: Option<HashSet<Registry>>,
     allow_http_registries: bool,
+    pull_policy: PullPolicy,
+    verify_cache: bool,
+    platform: Option<String>,
+    max_concurrent_downloads: usize,
 }
 
 impl RegistryClientBuilder {
@@ -46,6 +56,10 @@ impl RegistryClientBuilder {
             auth: Auth::new(),
             allowed_registries: None,
             allow_http_registries: true,
+            pull_policy: PullPolicy::default(),
+            verify_cache: false,
+            platform: None,
+            max_concurrent_downloads: DEFAULT_CONCURRENT_DOWNLOADS,
         }
     }
 
@@ -60,8 +74,24 @@ impl RegistryClientBuilder {
     }
 
     /// Only use images already in the local cache
+    ///
+    /// This sets [PullPolicy::Never], and also refuses requests that aren't
+    /// pulls, like [RegistryClient::list_tags()].
     pub fn offline(mut self) -> Self {
         self.network = None;
+        self.pull_policy = PullPolicy::Never;
+        self
+    }
+
+    /// Choose when the network is consulted about images in the local cache
+    ///
+    /// The default is [PullPolicy::IfNotPresent], which downloads only what
+    /// is missing from the cache. [PullPolicy::Always] checks the registry
+    /// for a newer manifest whenever an image is requested by tag, and
+    /// [PullPolicy::Never] keeps pulls from using the network at all. Only
+    /// pulls are affected, unlike [RegistryClientBuilder::offline()].
+    pub fn pull_policy(mut self, policy: PullPolicy) -> Self {
+        self.pull_policy = policy;
         self
     }
 
@@ -87,6 +117,42 @@ impl RegistryClientBuilder {
         self
     }
 
+    /// Check cached image data against its content digest before use
+    ///
+    /// Containers normally trust the cache directory once an image has been
+    /// pulled. With verification on, the first time this client's images open
+    /// each file, the cached layer it came from is hashed again. This guards
+    /// against disk errors and interrupted writes, at the cost of reading each
+    /// layer once per process. Damaged data is removed from the cache, and
+    /// opening the file fails with an I/O error until the image is pulled
+    /// again.
+    pub fn verify_cache(mut self) -> Self {
+        self.verify_cache = true;
+        self
+    }
+
+    /// Choose which platform to pull when an image supports several
+    ///
+    /// Multi-platform images are published as a list with one manifest per
+    /// platform. By default the entry for the host's own platform is used.
+    /// Platforms are written as `os/architecture[/variant]`, like
+    /// `linux/arm64`, and an invalid one makes
+    /// [RegistryClientBuilder::build()] fail.
+    pub fn platform(mut self, platform: &str) -> Self {
+        self.platform = Some(platform.to_string());
+        self
+    }
+
+    /// Limit how many layers of one image are downloaded at once
+    ///
+    /// Each layer is decompressed as soon as its download finishes, while
+    /// the others keep downloading. The default is 3, and zero is treated as
+    /// one.
+    pub fn max_concurrent_downloads(mut self, limit: usize) -> Self {
+        self.max_concurrent_downloads = limit.max(1);
+        self
+    }
+
     /// Set a temporary cache directory
     ///
     /// This generates a new random temporary cache
@@ -108,6 +174,18 @@ impl RegistryClientBuilder {
         self
     }
 
+    /// Wait out registry rate limits, for up to this long per request
+    ///
+    /// Registries like Docker Hub throttle clients by answering with HTTP 429
+    /// and a `Retry-After` delay. By default that fails the pull right away
+    /// with [ImageError::RateLimited]. With a wait allowed, the client sleeps
+    /// for the requested delay and retries, until the total delay for one
+    /// request would exceed `max_wait`.
+    pub fn rate_limit_wait(mut self, max_wait: Duration) -> Self {
+        self.auth.wait_for_rate_limits(max_wait);
+        self
+    }
+
     /// Set a timeout for only the initial connect phase of each network request
     ///
     /// By default there is no timeout beyond those built into the networking
@@ -186,6 +264,10 @@ impl RegistryClientBuilder {
 
     /// Construct a RegistryClient using the parameters from this Builder
     pub fn build(self) -> Result<RegistryClient, ImageError> {
+        let platform = match &self.platform {
+            Some(platform) => Platform::parse(platform)?,
+            None => Platform::host(),
+        };
         let (cache_dir, temp_dir) = match self.cache_option {
             CacheOption::Dir(dir) => (dir, None),
             CacheOption::Default => (RegistryClient::default_cache_dir()?, None),
@@ -201,8 +283,13 @@ impl RegistryClientBuilder {
             }
         };
         log::debug!("using cache directory {:?}", cache_dir);
+        let storage = FileStorage::new(cache_dir, temp_dir);
         Ok(RegistryClient::from_parts(
-            FileStorage::new(cache_dir, temp_dir),
+            if self.verify_cache {
+                storage.verifying()
+            } else {
+                storage
+            },
             self.auth,
             match self.network {
                 Some(n) => Some(n.build()?),
@@ -212,6 +299,9 @@ impl RegistryClientBuilder {
                 .unwrap_or_else(RegistryClient::default_registry),
             self.allowed_registries,
             self.allow_http_registries,
+            self.pull_policy,
+            platform,
+            self.max_concurrent_downloads,
         ))
     }
 }
@@ -1,21 +1,28 @@
 //! Support for downloading container images from a registry server
 
 use crate::{
+    container::RunHistory,
     errors::ImageError,
     filesystem::{
         storage,
         storage::{FileStorage, StorageKey, StorageWriter},
         tar,
         vfs::Filesystem,
+        workspace::Workspaces,
     },
-    image::{ContentDigest, Image, ImageName, ImageVersion, Registry, Repository},
-    manifest::{media_types, Link, Manifest, RuntimeConfig, FS_TYPE},
-    registry::{auth::Auth, progress::*, DefaultRegistry, RegistryClientBuilder},
+    image::{ContentDigest, Image, ImageCache, ImageName, ImageVersion, Registry, Repository, Tag},
+    manifest::{
+        media_types, ImageIndex, Link, Manifest, Platform, RuntimeConfig, TagList, FS_TYPE,
+    },
+    registry::{auth::Auth, progress::*, DefaultRegistry, PullPolicy, RegistryClientBuilder},
+    rt,
 };
 
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use memmap::Mmap;
-use reqwest::{header, header::HeaderValue, Client, RequestBuilder, Response, Url};
+use reqwest::{
+    header, header::HeaderValue, Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
 use std::{
     collections::HashSet,
     env,
@@ -24,7 +31,13 @@ use std::{
     path::PathBuf,
     sync::Arc,
 };
-use tokio::{sync::mpsc, task};
+use tokio::sync::mpsc;
+
+/// Largest decompressed size accepted for a single image layer
+///
+/// Layers compress very well, but never this well. Anything larger is most
+/// likely a decompression bomb, and stopping here keeps it out of the cache.
+pub(crate) const MAX_LAYER_SIZE: u64 = 32 * 1024 * 1024 * 1024;
 
 /// Registry clients can download and store data from an image registry
 ///
@@ -39,6 +52,9 @@ pub struct RegistryClient {
     default_registry: DefaultRegistry,
     allowed_registries: Option<HashSet<Registry>>,
     allow_http_registries: bool,
+    pull_policy: PullPolicy,
+    platform: Platform,
+    max_concurrent_downloads: usize,
 }
 
 impl RegistryClient {
@@ -59,6 +75,9 @@ impl RegistryClient {
         default_registry: DefaultRegistry,
         allowed_registries: Option<HashSet<Registry>>,
         allow_http_registries: bool,
+        pull_policy: PullPolicy,
+        platform: Platform,
+        max_concurrent_downloads: usize,
     ) -> Self {
         RegistryClient {
             storage,
@@ -67,6 +86,9 @@ impl RegistryClient {
             default_registry,
             allowed_registries,
             allow_http_registries,
+            pull_policy,
+            platform,
+            max_concurrent_downloads,
         }
     }
 
@@ -87,6 +109,21 @@ impl RegistryClient {
         storage::default_cache_dir()
     }
 
+    /// Open the [RunHistory] kept in this client's cache directory
+    pub fn run_history(&self) -> RunHistory {
+        RunHistory::in_storage(&self.storage)
+    }
+
+    /// Open the [Workspaces] kept in this client's cache directory
+    pub fn workspaces(&self) -> Workspaces {
+        Workspaces::in_storage(&self.storage)
+    }
+
+    /// Get the [ImageCache] for this client's cache directory
+    pub fn image_cache(&self) -> ImageCache {
+        ImageCache::new(&self.storage)
+    }
+
     /// Return the default registry server
     ///
     /// This is the server used when nothing else has been specified either in
@@ -119,6 +156,20 @@ impl RegistryClient {
         bucket: &'static str,
         object: T,
     ) -> Result<(&'a Client, &'a mut Auth, RequestBuilder), ImageError>
+    where
+        T: Display,
+    {
+        self.begin_request(Method::GET, registry, repository, bucket, object)
+    }
+
+    fn begin_request<'a, T>(
+        &'a mut self,
+        method: Method,
+        registry: &Registry,
+        repository: &Repository,
+        bucket: &'static str,
+        object: T,
+    ) -> Result<(&'a Client, &'a mut Auth, RequestBuilder), ImageError>
     where
         T: Display,
     {
@@ -140,7 +191,7 @@ impl RegistryClient {
         .parse()
         .expect("url components already validated");
 
-        let req = network.get(url);
+        let req = network.request(method, url);
         Ok((network, &mut self.auth, req))
     }
 
@@ -149,16 +200,22 @@ impl RegistryClient {
         progress: &mut mpsc::Sender<PullProgress>,
         progress_resource: &Arc<ProgressResource>,
         response: Response,
+        mut writer: StorageWriter,
     ) -> Result<(StorageWriter, ContentDigest), ImageError> {
         log::info!("downloading {}", response.url());
-        let mut response = response.error_for_status()?;
-        let storage = self.storage.clone();
+        let mut response = match response.error_for_status() {
+            Ok(response) => response,
+            Err(err) => {
+                rt::spawn_blocking(move || writer.discard()).await??;
+                return Err(err.into());
+            }
+        };
         let mut progress = progress.clone();
         let progress_resource = progress_resource.clone();
 
         // Send blocks from the async reactor to a sync thread pool for hashing
         let (send_channel, recv_channel) = std::sync::mpsc::channel::<bytes::Bytes>();
-        let send_task = task::spawn(async move {
+        let send_task = rt::spawn(async move {
             progress
                 .send(PullProgress::Update(ProgressUpdate {
                     resource: progress_resource.clone(),
@@ -201,8 +258,7 @@ impl RegistryClient {
                 }
             }
         });
-        let recv_task = task::spawn_blocking(move || {
-            let mut writer = storage.begin_write()?;
+        let recv_task = rt::spawn_blocking(move || {
             while let Ok(chunk) = recv_channel.recv() {
                 if let Err(err) = writer.write_all(&chunk) {
                     return Ok::<(StorageWriter, Result<ContentDigest, ImageError>), ImageError>((
@@ -224,7 +280,7 @@ impl RegistryClient {
             }
             (send_result, recv_result) => {
                 let (mut writer, recv_result) = recv_result??;
-                task::spawn_blocking(move || writer.remove_temp()).await??;
+                rt::spawn_blocking(move || writer.discard()).await??;
                 recv_result?;
                 send_result??;
                 unreachable!();
@@ -263,7 +319,7 @@ impl RegistryClient {
                 .request(
                     registry,
                     network,
-                    request.header(header::ACCEPT, media_types::MANIFEST),
+                    request.header(header::ACCEPT, manifest_accept()),
                 )
                 .await;
 
@@ -276,11 +332,71 @@ impl RegistryClient {
                 .await
                 .map_err(|_| ImageError::PullTaskError)?;
 
-            self.download_response(progress, &progress_resource, response?)
+            let writer = self.storage.begin_write()?;
+            self.download_response(progress, &progress_resource, response?, writer)
                 .await
         }
     }
 
+    async fn head_manifest_digest(
+        &mut self,
+        registry: &Registry,
+        repository: &Repository,
+        version: &ImageVersion,
+    ) -> Result<Option<ContentDigest>, ImageError> {
+        let (network, auth, request) =
+            self.begin_request(Method::HEAD, registry, repository, "manifests", version)?;
+        let response = auth
+            .request(
+                registry,
+                network,
+                request.header(header::ACCEPT, manifest_accept()),
+            )
+            .await?
+            .error_for_status()?;
+        Ok(
+            match response
+                .headers()
+                .get("docker-content-digest")
+                .and_then(|value| value.to_str().ok())
+            {
+                Some(digest) => Some(ContentDigest::parse(digest)?),
+                None => None,
+            },
+        )
+    }
+
+    async fn is_cached_manifest_current(
+        &mut self,
+        key: &StorageKey,
+        cached: &[u8],
+    ) -> Result<bool, ImageError> {
+        match (self.pull_policy, key) {
+            (PullPolicy::Always, StorageKey::Manifest(registry, repository, version))
+                if version.is_tag() =>
+            {
+                let cached_digest = ContentDigest::from_content(cached);
+                let remote_digest = self
+                    .head_manifest_digest(registry, repository, version)
+                    .await?;
+                if remote_digest.as_ref() == Some(&cached_digest) {
+                    log::debug!("{} manifest in cache is current", cached_digest);
+                    Ok(true)
+                } else {
+                    log::info!(
+                        "{}/{}:{} has changed from {}, downloading again",
+                        registry,
+                        repository,
+                        version,
+                        cached_digest
+                    );
+                    Ok(false)
+                }
+            }
+            _ => Ok(true),
+        }
+    }
+
     async fn download_blob(
         &mut self,
         progress: &mut mpsc::Sender<PullProgress>,
@@ -290,6 +406,15 @@ impl RegistryClient {
         content_digest: &ContentDigest,
         content_type: &HeaderValue,
     ) -> Result<StorageWriter, ImageError> {
+        let task_storage = self.storage.clone();
+        let task_digest = content_digest.clone();
+        let mut writer =
+            rt::spawn_blocking(move || task_storage.resume_write(&task_digest)).await??;
+        if writer.position() > 0 && writer.current_digest().as_ref() == Some(content_digest) {
+            log::info!("{} was already downloaded", content_digest);
+            return Ok(writer);
+        }
+
         progress
             .send(PullProgress::Update(ProgressUpdate {
                 resource: progress_resource.clone(),
@@ -299,15 +424,43 @@ impl RegistryClient {
             .await
             .map_err(|_| ImageError::PullTaskError)?;
 
-        let (network, auth, request) =
-            self.begin_get(registry, repository, "blobs", content_digest)?;
-        let response = auth
-            .request(
-                registry,
-                network,
-                request.header(header::ACCEPT, content_type),
-            )
-            .await?;
+        let response = loop {
+            let position = writer.position();
+            let (network, auth, request) =
+                self.begin_get(registry, repository, "blobs", content_digest)?;
+            let mut request = request.header(header::ACCEPT, content_type);
+            if position > 0 {
+                request = request.header(header::RANGE, format!("bytes={}-", position));
+            }
+            let response = match auth.request(registry, network, request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    rt::spawn_blocking(move || writer.discard()).await??;
+                    return Err(err);
+                }
+            };
+            match response.status() {
+                _ if position == 0 => break response,
+                StatusCode::PARTIAL_CONTENT if content_range_start(&response) == Some(position) => {
+                    log::info!("resuming {} at byte {}", content_digest, position);
+                    break response;
+                }
+                StatusCode::OK => {
+                    log::info!("registry sent all of {} instead of a range", content_digest);
+                    writer.truncate()?;
+                    break response;
+                }
+                status => {
+                    log::warn!(
+                        "can't resume {} at byte {}, {}, starting over",
+                        content_digest,
+                        position,
+                        status
+                    );
+                    writer.truncate()?;
+                }
+            }
+        };
 
         progress
             .send(PullProgress::Update(ProgressUpdate {
@@ -319,12 +472,12 @@ impl RegistryClient {
             .map_err(|_| ImageError::PullTaskError)?;
 
         let (mut writer, found_digest) = self
-            .download_response(progress, &progress_resource, response)
+            .download_response(progress, &progress_resource, response, writer)
             .await?;
         if &found_digest == content_digest {
             Ok(writer)
         } else {
-            task::spawn_blocking(move || writer.remove_temp()).await??;
+            rt::spawn_blocking(move || writer.remove_temp()).await??;
             Err(ImageError::ContentDigestMismatch {
                 expected: content_digest.clone(),
                 found: found_digest,
@@ -332,14 +485,59 @@ impl RegistryClient {
         }
     }
 
+    /// Pull the manifest for an image, by way of a manifest list if the
+    /// image has one
+    ///
+    /// The name returned is the one that was requested, with the digest of
+    /// the manifest that describes the image. For a list, that's the digest
+    /// of the entry chosen for this platform rather than of the list itself.
     async fn pull_manifest(
         &mut self,
         progress: &mut mpsc::Sender<PullProgress>,
         image: &ImageName,
     ) -> Result<(ImageName, Manifest), ImageError> {
+        let (specific_image, map) = self.pull_manifest_data(progress, image).await?;
+        if !ImageIndex::is_index(&map[..]) {
+            return Ok((specific_image, Manifest::parse(&map[..])?));
+        }
+        let entry = ImageIndex::parse(&map[..])?.select(&self.platform)?;
+        log::debug!(
+            "{} is a manifest list, using {} for {}",
+            specific_image,
+            entry.link.digest,
+            self.platform
+        );
+        let platform_image = ImageName::from_parts(
+            image.registry_str(),
+            image.repository_str(),
+            None,
+            Some(&entry.link.digest),
+        )?;
+        let (_, map) = self.pull_manifest_data(progress, &platform_image).await?;
+        let map = RegistryClient::check_mmap_for_link(&entry.link, map)?;
+        let specific_image = ImageName::from_parts(
+            image.registry_str(),
+            image.repository_str(),
+            image.tag_str(),
+            Some(&entry.link.digest),
+        )?;
+        Ok((specific_image, Manifest::parse(&map[..])?))
+    }
+
+    async fn pull_manifest_data(
+        &mut self,
+        progress: &mut mpsc::Sender<PullProgress>,
+        image: &ImageName,
+    ) -> Result<(ImageName, Mmap), ImageError> {
         let (registry, repository) = self.default_registry.resolve_image_name(image);
         let key = StorageKey::Manifest(registry, repository, image.version());
-        let (specific_image, map) = match self.storage.mmap(&key)? {
+        let mut cached = self.storage.mmap(&key)?;
+        if let Some(map) = &cached {
+            if !self.is_cached_manifest_current(&key, &map[..]).await? {
+                cached = None;
+            }
+        }
+        let (specific_image, map) = match cached {
             Some(map) => {
                 // If the manifest is cached, still verify its content digest and annotate the
                 // ImageName with that digest
@@ -357,7 +555,7 @@ impl RegistryClient {
                     let task_storage = self.storage.clone();
                     let task_image = image.clone();
                     let task_key = key.clone();
-                    let specific_image = task::spawn_blocking(move || {
+                    let specific_image = rt::spawn_blocking(move || {
                         match task_image.with_found_digest(&found_digest) {
                             Ok(specific_image) => {
                                 task_storage.commit_write(writer, &task_key)?;
@@ -393,13 +591,12 @@ impl RegistryClient {
             },
         };
 
-        let slice = &map[..];
         log::trace!(
             "raw json manifest for {}: {:?}",
             specific_image,
-            String::from_utf8_lossy(slice)
+            String::from_utf8_lossy(&map[..])
         );
-        Ok((specific_image, serde_json::from_slice(slice)?))
+        Ok((specific_image, map))
     }
 
     fn check_mmap_for_link(link: &Link, mmap: Mmap) -> Result<Mmap, ImageError> {
@@ -448,7 +645,7 @@ impl RegistryClient {
                             .await?;
 
                         let task_storage = self.storage.clone();
-                        match task::spawn_blocking(move || {
+                        match rt::spawn_blocking(move || {
                             task_storage.commit_write(writer, &key)?;
                             task_storage.mmap(&key)
                         })
@@ -491,17 +688,12 @@ impl RegistryClient {
                         )
                         .await?;
 
-                    let task_storage = self.storage.clone();
-                    match task::spawn_blocking(move || {
-                        let result = task_storage.mmap(&writer.key);
+                    rt::spawn_blocking(move || {
+                        let result = writer.mmap();
                         writer.remove_temp()?;
                         result
                     })
                     .await??
-                    {
-                        Some(map) => map,
-                        None => return Err(ImageError::StorageMissingAfterInsert),
-                    }
                 }
                 _ => unreachable!(),
             },
@@ -522,7 +714,7 @@ impl RegistryClient {
                 "raw json runtime config, {}",
                 String::from_utf8_lossy(slice)
             );
-            Ok(serde_json::from_slice(slice)?)
+            RuntimeConfig::parse(slice)
         } else {
             Err(ImageError::UnsupportedRuntimeConfigType(
                 link.media_type.clone(),
@@ -538,11 +730,16 @@ impl RegistryClient {
     ) -> Result<(), ImageError> {
         let mut tasks = FuturesUnordered::new();
         for link in links {
+            if tasks.len() >= self.max_concurrent_downloads {
+                if let Some(result) = tasks.next().await {
+                    result??;
+                }
+            }
             let mut client = self.clone();
             let mut progress = progress.clone();
             let image = image.clone();
             let link = link.clone();
-            tasks.push(task::spawn(async move {
+            tasks.push(rt::spawn(async move {
                 client.pull_layer(&mut progress, &image, &link).await
             }));
         }
@@ -585,32 +782,20 @@ impl RegistryClient {
             .await
             .map_err(|_| ImageError::PullTaskError)?;
 
-        task::spawn_blocking(move || -> Result<(), ImageError> {
+        rt::spawn_blocking(move || -> Result<(), ImageError> {
             let mut writer = task_storage.begin_write()?;
-            let mut decoder = flate2::bufread::GzDecoder::new(std::io::Cursor::new(&*source));
-            let mut buffer = [0u8; 256 * 1024];
             log::info!("decompressing {} bytes", source.len());
-
-            let result: std::io::Result<()> = loop {
-                match decoder.read(&mut buffer) {
-                    Err(err) => break Err(err),
-                    Ok(size) if size == 0 => break Ok(()),
-                    Ok(size) => match writer.write_all(&buffer[..size]) {
-                        Err(err) => break Err(err),
-                        Ok(()) => {
-                            let _ = task_progress.try_send(PullProgress::Update(ProgressUpdate {
-                                resource: task_progress_resource.clone(),
-                                phase: ProgressPhase::Decompress,
-                                event: ProgressEvent::Progress(decoder.get_ref().position()),
-                            }));
-                        }
-                    },
-                }
-            };
+            let result = decompress_gzip(&source, &mut writer, MAX_LAYER_SIZE, |position| {
+                let _ = task_progress.try_send(PullProgress::Update(ProgressUpdate {
+                    resource: task_progress_resource.clone(),
+                    phase: ProgressPhase::Decompress,
+                    event: ProgressEvent::Progress(position),
+                }));
+            });
             match result {
                 Err(err) => {
                     writer.remove_temp()?;
-                    Err(err.into())
+                    Err(err)
                 }
                 Ok(()) => {
                     let content_digest = writer.finalize()?;
@@ -650,12 +835,28 @@ impl RegistryClient {
         self.pull_progress(image).wait().await
     }
 
+    /// List the tags available in an image repository
+    ///
+    /// The registry and repository are resolved from an [ImageName] the same
+    /// way [RegistryClient::pull()] would, and any tag or digest it includes is
+    /// ignored. Results are collected from every page the registry returns, in
+    /// the order the registry returns them. This always requires the network;
+    /// tag lists are not cached.
+    pub async fn list_tags(&self, image: &ImageName) -> Result<Vec<Tag>, ImageError> {
+        let (registry, repository) = self.default_registry.resolve_image_name(image);
+        let mut client = self.clone();
+        rt::spawn(async move { client.list_tags_all_pages(&registry, &repository).await }).await?
+    }
+
     /// Start to pull an image, and return progress updates
     pub fn pull_progress(&self, image: &ImageName) -> Pull {
         let (mut sender, receiver) = mpsc::channel(128);
         let image = image.clone();
         let mut client = self.clone();
-        let _ = task::spawn(async move {
+        if client.pull_policy == PullPolicy::Never {
+            client.network = None;
+        }
+        let _ = rt::spawn(async move {
             let result = client.pull_with_progress_channel(&mut sender, &image).await;
             let _ = sender.send(PullProgress::Done(result)).await;
         });
@@ -668,9 +869,23 @@ impl RegistryClient {
         image: &ImageName,
     ) -> Result<Arc<Image>, ImageError> {
         let (specific_image, manifest) = self.pull_manifest(progress, image).await?;
+        let storage = self.storage.leased(
+            &specific_image
+                .content_digest()
+                .expect("pulled manifests always have a digest"),
+        )?;
         let config = self
             .pull_runtime_config(progress, image, &manifest.config)
             .await?;
+        let mut blobs = vec![ContentDigest::parse(&manifest.config.digest)?];
+        for digest_str in &config.rootfs.diff_ids {
+            blobs.push(ContentDigest::parse(digest_str)?);
+        }
+        // Touching the cache waits on its lock
+        let task_storage = storage.clone();
+        let task_image = specific_image.clone();
+        rt::spawn_blocking(move || ImageCache::new(&task_storage).touch(&task_image, blobs))
+            .await??;
         let decompressed_layers = match self.check_local_rootfs_layers(&config).await? {
             Some(layers) => layers,
             None => {
@@ -681,9 +896,8 @@ impl RegistryClient {
             }
         };
 
-        let storage = self.storage.clone();
-        let task_storage = self.storage.clone();
-        let filesystem = task::spawn_blocking(move || -> Result<Filesystem, ImageError> {
+        let task_storage = storage.clone();
+        let filesystem = rt::spawn_blocking(move || -> Result<Filesystem, ImageError> {
             let mut filesystem = Filesystem::new();
             for layer in &decompressed_layers {
                 tar::extract(&mut filesystem, &task_storage, layer)?;
@@ -700,6 +914,34 @@ impl RegistryClient {
         }))
     }
 
+    async fn list_tags_all_pages(
+        &mut self,
+        registry: &Registry,
+        repository: &Repository,
+    ) -> Result<Vec<Tag>, ImageError> {
+        const PAGE_SIZE: usize = 100;
+        let mut tags = Vec::new();
+        let mut last: Option<String> = None;
+        loop {
+            let (network, auth, request) = self.begin_get(registry, repository, "tags", "list")?;
+            let mut request = request.query(&[("n", PAGE_SIZE.to_string())]);
+            if let Some(last) = &last {
+                request = request.query(&[("last", last)]);
+            }
+            let response = auth.request(registry, network, request).await?;
+            let response = response.error_for_status()?;
+            let next = next_page_marker(&response);
+            let page: TagList = serde_json::from_slice(&response.bytes().await?)?;
+            for tag in page.tags.unwrap_or_default() {
+                tags.push(Tag::parse(&tag)?);
+            }
+            match next {
+                Some(marker) if Some(&marker) != last.as_ref() => last = Some(marker),
+                _ => return Ok(tags),
+            }
+        }
+    }
+
     async fn check_local_rootfs_layers(
         &mut self,
         config: &RuntimeConfig,
@@ -722,3 +964,307 @@ impl RegistryClient {
         }
     }
 }
+
+/// Every kind of manifest we can use, including lists of them
+fn manifest_accept() -> String {
+    [
+        media_types::MANIFEST,
+        media_types::OCI_MANIFEST,
+        media_types::MANIFEST_LIST,
+        media_types::OCI_INDEX,
+    ]
+    .join(", ")
+}
+
+/// Find where the body of a 206 response starts, from its
+/// `Content-Range: bytes <start>-<end>/<size>` header
+fn content_range_start(response: &Response) -> Option<u64> {
+    let value = response
+        .headers()
+        .get(header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    let range = value.trim().strip_prefix("bytes")?.trim_start();
+    range.split('-').next()?.parse().ok()
+}
+
+/// Find the `last` parameter of the next page, from a `Link: <...>; rel="next"`
+/// header in a paginated registry response
+///
+/// Only the marker is kept; the next request is built from the same registry
+/// and repository rather than following the link to an arbitrary URL.
+fn next_page_marker(response: &Response) -> Option<String> {
+    for value in response.headers().get_all(header::LINK) {
+        let value = value.to_str().ok()?;
+        for link in value.split(',') {
+            let mut parts = link.split(';');
+            let target = parts.next()?.trim();
+            if !parts.any(|param| param.trim().replace(' ', "") == "rel=\"next\"") {
+                continue;
+            }
+            let target = target.strip_prefix('<')?.strip_suffix('>')?;
+            let url = response.url().join(target).ok()?;
+            return url
+                .query_pairs()
+                .find(|(key, _)| key == "last")
+                .map(|(_, value)| value.into_owned());
+        }
+    }
+    None
+}
+
+/// Decompress a gzip stream into `writer`, failing once more than `limit`
+/// bytes come out
+///
+/// The progress callback receives the current position in the compressed
+/// source after each chunk is written.
+pub(crate) fn decompress_gzip<W: Write, F: FnMut(u64)>(
+    source: &[u8],
+    writer: &mut W,
+    limit: u64,
+    mut progress: F,
+) -> Result<(), ImageError> {
+    let mut decoder = flate2::bufread::GzDecoder::new(std::io::Cursor::new(source));
+    let mut buffer = [0u8; 256 * 1024];
+    let mut total: u64 = 0;
+    loop {
+        let size = decoder.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(());
+        }
+        total += size as u64;
+        if total > limit {
+            return Err(ImageError::DecompressedSizeLimit(limit));
+        }
+        writer.write_all(&buffer[..size])?;
+        progress(decoder.get_ref().position());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        sync::Mutex,
+        thread,
+    };
+    use tokio::runtime::Runtime;
+
+    fn gzip_zeros(len: usize) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&vec![0u8; len]).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decompress_within_limit() {
+        let source = gzip_zeros(1000000);
+        let mut output = Vec::new();
+        decompress_gzip(&source, &mut output, 1000000, |_| ()).unwrap();
+        assert_eq!(output.len(), 1000000);
+    }
+
+    #[test]
+    fn decompress_bomb_stops_at_limit() {
+        let source = gzip_zeros(10000000);
+        assert!(source.len() < 20000);
+        let mut output = Vec::new();
+        match decompress_gzip(&source, &mut output, 1000000, |_| ()) {
+            Err(ImageError::DecompressedSizeLimit(1000000)) => (),
+            other => panic!("unexpected result, {:?}", other),
+        }
+        assert!(output.len() <= 1000000);
+    }
+
+    /// Serve HTTP on a local port, answering each request line with a
+    /// digest header and a body, and keeping the request lines it receives
+    fn serve<F>(respond: F) -> (u16, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(&str) -> (ContentDigest, String) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim().to_string();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                let (digest, body) = respond(&line);
+                log.lock().unwrap().push(line);
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nDocker-Content-Digest: {}\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    digest,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (port, requests)
+    }
+
+    /// Serve a registry on a local port which reports `digest` for every
+    /// manifest and a single tag, keeping the request lines it receives
+    fn fake_registry(digest: ContentDigest) -> (ImageName, Arc<Mutex<Vec<String>>>) {
+        let (port, requests) = serve(move |line| {
+            let body = if line.contains("/tags/list") {
+                r#"{"name":"test","tags":["latest"]}"#
+            } else {
+                ""
+            };
+            (digest.clone(), body.to_string())
+        });
+        let name = format!("localhost:{}/test:latest", port).parse().unwrap();
+        (name, requests)
+    }
+
+    fn manifest_key(client: &RegistryClient, image: &ImageName) -> StorageKey {
+        let (registry, repository) = client.default_registry.resolve_image_name(image);
+        StorageKey::Manifest(registry, repository, image.version())
+    }
+
+    #[test]
+    fn always_checks_tags_with_head() {
+        let cached = b"cached manifest";
+        let current = ContentDigest::from_content(cached);
+        let changed = ContentDigest::from_content(b"newer manifest");
+        Runtime::new().unwrap().block_on(async {
+            for (digest, expected) in vec![(current, true), (changed, false)] {
+                let (image, requests) = fake_registry(digest);
+                let mut client = RegistryClient::builder()
+                    .ephemeral_cache()
+                    .pull_policy(PullPolicy::Always)
+                    .build()
+                    .unwrap();
+                let key = manifest_key(&client, &image);
+                let result = client.is_cached_manifest_current(&key, cached).await;
+                assert_eq!(result.unwrap(), expected);
+                assert_eq!(
+                    *requests.lock().unwrap(),
+                    vec!["HEAD /v2/test/manifests/latest HTTP/1.1".to_string()]
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn if_not_present_trusts_cache() {
+        let cached = b"cached manifest";
+        Runtime::new().unwrap().block_on(async {
+            let (image, requests) = fake_registry(ContentDigest::from_content(b"newer"));
+            let mut client = RegistryClient::builder().ephemeral_cache().build().unwrap();
+            let key = manifest_key(&client, &image);
+            assert!(client
+                .is_cached_manifest_current(&key, cached)
+                .await
+                .unwrap());
+            assert!(requests.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn never_only_applies_to_pulls() {
+        Runtime::new().unwrap().block_on(async {
+            let (image, requests) = fake_registry(ContentDigest::from_content(b"manifest"));
+            let client = RegistryClient::builder()
+                .ephemeral_cache()
+                .pull_policy(PullPolicy::Never)
+                .build()
+                .unwrap();
+            match client.pull(&image).await {
+                Err(ImageError::DownloadInOfflineMode) => (),
+                other => panic!("unexpected result, {:?}", other.map(|_| ())),
+            }
+            let tags = client.list_tags(&image).await.unwrap();
+            assert_eq!(
+                tags.iter().map(Tag::as_str).collect::<Vec<_>>(),
+                vec!["latest"]
+            );
+            assert_eq!(requests.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn manifest_list_names_platform_manifest() {
+        let zero = format!("sha256:{}", "0".repeat(64));
+        let manifest = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "{}",
+                "config": {{"mediaType": "{}", "size": 2, "digest": "{}"}},
+                "layers": []}}"#,
+            media_types::MANIFEST,
+            media_types::RUNTIME_CONFIG,
+            zero
+        );
+        let manifest_digest = ContentDigest::from_content(manifest.as_bytes());
+        let entry = |digest: &str, size: usize, architecture: &str| {
+            format!(
+                r#"{{"mediaType": "{}", "size": {}, "digest": "{}",
+                    "platform": {{"architecture": "{}", "os": "linux"}}}}"#,
+                media_types::MANIFEST,
+                size,
+                digest,
+                architecture
+            )
+        };
+        let list = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "{}", "manifests": [{}, {}]}}"#,
+            media_types::MANIFEST_LIST,
+            entry(&zero, 1, "amd64"),
+            entry(manifest_digest.as_str(), manifest.len(), "arm64"),
+        );
+        let list_digest = ContentDigest::from_content(list.as_bytes());
+
+        let task_list = list.clone();
+        let task_list_digest = list_digest.clone();
+        let task_manifest_digest = manifest_digest.clone();
+        let (port, requests) = serve(move |line| {
+            if line.contains(task_list_digest.as_str()) {
+                (task_list_digest.clone(), task_list.clone())
+            } else {
+                (task_manifest_digest.clone(), manifest.clone())
+            }
+        });
+        let image: ImageName = format!("localhost:{}/test@{}", port, list_digest)
+            .parse()
+            .unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            let mut client = RegistryClient::builder()
+                .ephemeral_cache()
+                .platform("linux/arm64")
+                .build()
+                .unwrap();
+            let (mut sender, _receiver) = mpsc::channel(128);
+            let (specific_image, manifest) =
+                client.pull_manifest(&mut sender, &image).await.unwrap();
+            assert_eq!(
+                specific_image.content_digest(),
+                Some(manifest_digest.clone())
+            );
+            assert_eq!(manifest.config.digest, zero);
+            assert_eq!(
+                *requests.lock().unwrap(),
+                vec![
+                    format!("GET /v2/test/manifests/{} HTTP/1.1", list_digest),
+                    format!("GET /v2/test/manifests/{} HTTP/1.1", manifest_digest),
+                ]
+            );
+        });
+    }
+}
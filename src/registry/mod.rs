@@ -2,13 +2,15 @@
 
 mod auth;
 mod builder;
-mod client;
+pub(crate) mod client;
 mod default;
+mod policy;
 mod progress;
 
 pub use builder::RegistryClientBuilder;
 pub use client::RegistryClient;
 pub use default::DefaultRegistry;
+pub use policy::PullPolicy;
 pub use progress::{
     ProgressEvent, ProgressPhase, ProgressResource, ProgressUpdate, Pull, PullProgress,
 };
@@ -0,0 +1,27 @@
+//! Pull policies, deciding when a registry client may use the network
+
+/// Rules for when a [crate::registry::RegistryClient] contacts the network
+///
+/// These match the usual pull policies of other container tools. Images
+/// referenced by content digest are immutable, so any policy which allows a
+/// cached copy will use it without checking the registry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PullPolicy {
+    /// Check the registry for a newer manifest every time an image is
+    /// requested by tag
+    ///
+    /// A cached manifest is only reused if the digest the registry reports
+    /// for the tag still matches it. Layers and configuration are content
+    /// addressed, and are always reused from the cache when available.
+    Always,
+    /// Download only what is missing from the local cache (default)
+    IfNotPresent,
+    /// Never download anything, only use images from the local cache
+    Never,
+}
+
+impl Default for PullPolicy {
+    fn default() -> Self {
+        PullPolicy::IfNotPresent
+    }
+}
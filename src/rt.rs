@@ -0,0 +1,96 @@
+//! The tokio runtime that bandsocks tasks run on
+//!
+//! Every task bandsocks spawns goes through here. From inside a tokio
+//! runtime, that's the current runtime as usual. Applications built on
+//! another executor, like async-std or smol, get a small private runtime
+//! instead, started on first use on a thread of its own. That is a second
+//! executor running alongside theirs; the I/O below is tokio's throughout,
+//! and there's no backend for any other.
+//!
+//! Each container's own task always runs on the private runtime, so a
+//! container keeps going while the runtime it was spawned from is idle, and a
+//! thread can block on it with
+//! [Container::wait_blocking()](crate::Container::wait_blocking).
+//!
+//! The futures bandsocks returns must not touch tokio's timers or I/O
+//! directly, since those only work from inside a tokio runtime. Anything that
+//! waits on one belongs in a task spawned here, and the public futures only
+//! wait on tasks and channels, so any executor can poll them. Use
+//! [delay_for()] instead of tokio's own. The I/O objects handed to
+//! applications, like [ChildStdout](crate::ChildStdout), implement both
+//! tokio's I/O traits and the `futures` crate's.
+
+use futures_util::future;
+use std::{future::Future, thread, time::Duration};
+use tokio::{
+    runtime::{Builder, Handle},
+    task,
+    task::JoinHandle,
+    time,
+};
+
+lazy_static! {
+    static ref PRIVATE_RUNTIME: Handle = {
+        let mut runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .expect("failed to start a tokio runtime");
+        let handle = runtime.handle().clone();
+        thread::Builder::new()
+            .name("bandsocks-runtime".to_string())
+            .spawn(move || runtime.block_on(future::pending::<()>()))
+            .expect("failed to start a tokio runtime thread");
+        handle
+    };
+}
+
+/// The current tokio runtime, or the private one if there is none
+pub fn handle() -> Handle {
+    Handle::try_current().unwrap_or_else(|_| PRIVATE_RUNTIME.clone())
+}
+
+/// Spawn a task on the private runtime, whose own thread keeps it running
+/// even while the caller's runtime sits idle or a thread blocks on it
+pub fn spawn_private<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    PRIVATE_RUNTIME.spawn(future)
+}
+
+/// The private runtime, which runs tasks from [spawn_private()]
+pub fn private_handle() -> Handle {
+    PRIVATE_RUNTIME.clone()
+}
+
+/// Run some code that creates tokio I/O objects or timers
+pub fn enter<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    handle().enter(f)
+}
+
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    handle().spawn(future)
+}
+
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    enter(|| task::spawn_blocking(f))
+}
+
+/// Wait for a while, on a timer that works from any executor
+pub async fn delay_for(duration: Duration) {
+    // The task only fails if the runtime is shutting down
+    let _ = spawn(time::delay_for(duration)).await;
+}
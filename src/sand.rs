@@ -10,12 +10,14 @@ const PROGRAM_DATA: &[u8] = include_bytes!(concat!(
 const PROGRAM_DATA: &[u8] = b"";
 
 use crate::errors::RuntimeError;
-use protocol::{LogLevel, LogMessage, VPid};
+use protocol::{AttachMode, LogLevel, LogMessage, VPid};
 use std::{
+    fs,
     fs::File,
+    io,
     io::Write,
     os::unix::{
-        io::{AsRawFd, RawFd},
+        io::{AsRawFd, FromRawFd, RawFd},
         process::CommandExt,
     },
     process::{Command, Stdio},
@@ -41,9 +43,24 @@ fn create_program_file() -> Result<File, RuntimeError> {
         .cloned()
         .collect(),
     )?;
-    Ok(memfd.into_file())
+    // Keep clear of SOCKET_FD, which the child overwrites before exec
+    let file = memfd.into_file();
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, RESERVED_FDS) } {
+        fd if fd < 0 => Err(io::Error::last_os_error().into()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
 }
 
+/// The sand process always finds its IPC socket at this fd number
+///
+/// Using a fixed number means the socket never needs to be inheritable in the
+/// runtime process, where a concurrent spawn could otherwise pick it up.
+const SOCKET_FD: RawFd = 3;
+
+/// Fds below this are set up by number in the child, so anything it needs to
+/// keep until exec is moved above them first
+const RESERVED_FDS: RawFd = 16;
+
 pub fn command(fd: RawFd) -> Result<Command, RuntimeError> {
     let file = match &*PROGRAM_FILE {
         Err(err) => return Err(RuntimeError::ProgramAllocError(err.to_string())),
@@ -55,10 +72,53 @@ pub fn command(fd: RawFd) -> Result<Command, RuntimeError> {
     cmd.stderr(Stdio::piped());
     cmd.arg0("sand");
     cmd.env_clear();
-    cmd.env("FD", fd.to_string());
+    cmd.env("FD", SOCKET_FD.to_string());
+    unsafe {
+        cmd.pre_exec(move || inherit_fd_as(fd, SOCKET_FD));
+    }
     Ok(cmd)
 }
 
+/// Runs in the forked child before exec, so it must not allocate or lock
+///
+/// The fd is moved above [RESERVED_FDS] first, so it never equals `target`,
+/// where dup2 would do nothing and leave close-on-exec set.
+fn inherit_fd_as(fd: RawFd, target: RawFd) -> io::Result<()> {
+    let result = match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, RESERVED_FDS) } {
+        moved if moved < 0 => moved,
+        moved => unsafe { libc::dup2(moved, target) },
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+const YAMA_PTRACE_SCOPE: &str = "/proc/sys/kernel/yama/ptrace_scope";
+
+/// Check that sand will be able to trace its processes, given the kernel's
+/// ptrace policy, and report how
+///
+/// Sandboxed processes are always direct children of the tracer and ask to
+/// be traced with PTRACE_TRACEME, which yama allows at ptrace_scope 1. Scope
+/// 2 would need CAP_SYS_PTRACE in the tracer, which gives up every capability
+/// before it starts anything, and scope 3 forbids ptrace entirely. No other
+/// attach mode gets further under either, so those are reported here instead
+/// of failing later inside the sand process.
+pub fn attach_mode() -> Result<AttachMode, RuntimeError> {
+    let scope = match fs::read_to_string(YAMA_PTRACE_SCOPE) {
+        // No yama in this kernel
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+        Ok(s) => s.trim().parse().unwrap_or(u32::MAX),
+    };
+    match scope {
+        0 | 1 => Ok(AttachMode::TraceMe),
+        scope => Err(RuntimeError::PtraceRestricted(scope)),
+    }
+}
+
 pub fn max_log_level() -> LogLevel {
     if log::log_enabled!(log::Level::Trace) {
         LogLevel::Trace
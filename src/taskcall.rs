@@ -1,63 +1,245 @@
 use crate::{
-    filesystem::vfs::Filesystem,
-    process::Process,
-    sand::protocol::{Errno, FileStat, FollowLinks, VFile, VString},
+    container::network::{parse_sockaddr, NetworkGroup},
+    errors::VFSError,
+    filesystem::{procfs::ProcNode, remap::PathRemap, storage::FileStorage, vfs::Filesystem},
+    process::{Executable, Process},
+    sand::protocol::{abi, Errno, FileStat, FollowLinks, VFile, VPtr, VString},
+};
+use std::{
+    ffi::CString,
+    net::SocketAddr,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use std::{ffi::CString, path::Path};
 
-pub async fn change_working_dir(
+/// Processes don't track a umask yet, so assume the usual default
+const UMASK: u32 = 0o022;
+
+/// Size of struct sockaddr_storage, the largest address a process can pass
+const SOCKADDR_LIMIT: usize = 128;
+
+pub(crate) fn new_file_stat(file_type: u32, mode: i32) -> FileStat {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    FileStat {
+        st_mode: file_type | (mode as u32 & 0o7777 & !UMASK),
+        st_atime: now.as_secs(),
+        st_atime_nsec: now.subsec_nanos() as u64,
+        st_mtime: now.as_secs(),
+        st_mtime_nsec: now.subsec_nanos() as u64,
+        st_ctime: now.as_secs(),
+        st_ctime_nsec: now.subsec_nanos() as u64,
+        ..Default::default()
+    }
+}
+
+/// Follow /proc/self/exe to the program this process is running, and leave
+/// any other file alone
+fn follow_proc_link(
+    process: &Process,
+    filesystem: &Filesystem,
+    vfile: VFile,
+) -> Result<VFile, Errno> {
+    match filesystem.proc_node(&vfile)? {
+        Some(ProcNode::SelfExe) => match &process.status.exe {
+            Some(exe) => Ok(exe.file.clone()),
+            None => Err(Errno::new(libc::ENOENT)),
+        },
+        _ => Ok(vfile),
+    }
+}
+
+/// Remember the program a process just loaded, under the name it was run by
+pub async fn exec_loaded(
     process: &mut Process,
-    _filesystem: &Filesystem,
+    filesystem: &Filesystem,
+    remap: &PathRemap,
+    file: &VFile,
     path: &VString,
 ) -> Result<(), Errno> {
-    let path = process.mem.read_user_string(path)?;
-    log::warn!("change_working_dir({:?})", path);
+    process.status.exe = None;
+    let path_str = process.mem.read_user_string(path)?;
+    let path = filesystem.canonicalize(&remap.apply(Path::new(&path_str)))?;
+    log::debug!("exec_loaded{:?}", (file, &path));
+    process.status.exe = Some(Executable {
+        file: file.clone(),
+        path,
+    });
+    Ok(())
+}
+
+pub async fn change_working_dir(
+    process: &mut Process,
+    filesystem: &Filesystem,
+    remap: &PathRemap,
+    file: &Option<VFile>,
+    path: &Option<VString>,
+) -> Result<(), Errno> {
+    let path = match path {
+        Some(path) => {
+            let path_str = process.mem.read_user_string(path)?;
+            Some(remap.apply(Path::new(&path_str)).into_owned())
+        }
+        None => None,
+    };
+    let file = match file {
+        Some(file) => &file,
+        None => &process.status.current_dir,
+    };
+    let dir = match &path {
+        None => file.to_owned(),
+        Some(path) => filesystem.lookup(file, path, &FollowLinks::Follow)?,
+    };
+    if !filesystem.is_directory(&dir)? {
+        return Err(VFSError::DirectoryExpected.into());
+    }
+    log::debug!("change_working_dir({:?}) -> {:?}", path, dir);
+    process.status.current_dir = dir;
     Ok(())
 }
 
 pub async fn get_working_dir(
-    _process: &mut Process,
-    _filesystem: &Filesystem,
+    process: &mut Process,
+    filesystem: &Filesystem,
 ) -> Result<CString, Errno> {
-    Ok(CString::new("working dir goes here").unwrap())
+    let path = filesystem.directory_path(&process.status.current_dir)?;
+    CString::new(path.into_os_string().into_vec()).map_err(|_| Errno::new(libc::EINVAL))
 }
 
 pub async fn readlink(
     process: &mut Process,
     filesystem: &Filesystem,
+    dir: &Option<VFile>,
     path: &VString,
 ) -> Result<CString, Errno> {
     let path_str = process.mem.read_user_string(path)?;
     let path = Path::new(&path_str);
-    let dir = &process.status.current_dir;
+    let dir = match dir {
+        Some(dir) => &dir,
+        None => &process.status.current_dir,
+    };
     let vfile = filesystem.lookup(dir, &path, &FollowLinks::NoFollow)?;
+    if let Some(ProcNode::SelfExe) = filesystem.proc_node(&vfile)? {
+        let exe = process
+            .status
+            .exe
+            .as_ref()
+            .ok_or(Errno::new(libc::ENOENT))?;
+        log::debug!("readlink({:?}) -> {:?}", path, exe.path);
+        return CString::new(exe.path.as_os_str().as_bytes()).map_err(|_| Errno::new(libc::EINVAL));
+    }
     let cstr = filesystem.readlink(&vfile)?;
     log::debug!("readlink({:?}) -> {:?}", path, cstr);
-    Ok(cstr.to_owned())
+    Ok(cstr)
 }
 
 pub async fn file_open(
     process: &mut Process,
-    filesystem: &Filesystem,
+    filesystem: &mut Filesystem,
+    storage: &FileStorage,
+    remap: &PathRemap,
     dir: &Option<VFile>,
     path: &VString,
     flags: i32,
     mode: i32,
 ) -> Result<VFile, Errno> {
     let path_str = process.mem.read_user_string(path)?;
-    let path = Path::new(&path_str);
+    let path = remap.apply(Path::new(&path_str));
     let dir = match dir {
         Some(dir) => &dir,
         None => &process.status.current_dir,
     };
-    let vfile = filesystem.lookup(&dir, &path, &FollowLinks::Follow)?;
+    let create = flags & libc::O_CREAT != 0;
+    let exclusive = flags & libc::O_EXCL != 0;
+    let vfile = match filesystem.lookup(&dir, &path, &FollowLinks::Follow) {
+        Ok(_) if create && exclusive => Err(VFSError::AlreadyExists)?,
+        Ok(vfile) => follow_proc_link(process, filesystem, vfile)?,
+        Err(VFSError::NotFound) if create => filesystem.writer_at(dir).create_file(
+            storage,
+            &path,
+            new_file_stat(abi::S_IFREG, mode),
+        )?,
+        Err(err) => Err(err)?,
+    };
+    let truncate = flags & libc::O_TRUNC != 0;
+    if filesystem.proc_node(&vfile)?.is_some()
+        && (truncate || flags & libc::O_ACCMODE != libc::O_RDONLY)
+    {
+        return Err(Errno::new(libc::EACCES));
+    }
+    if truncate || flags & libc::O_ACCMODE != libc::O_RDONLY {
+        filesystem.copy_up(storage, &vfile, truncate).await?;
+    }
     log::debug!("file_open{:?} -> {:?}", (dir, path, flags, mode), vfile);
     Ok(vfile)
 }
 
+pub async fn make_dir(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    dir: &Option<VFile>,
+    path: &VString,
+    mode: i32,
+) -> Result<(), Errno> {
+    let path_str = process.mem.read_user_string(path)?;
+    let path = Path::new(&path_str);
+    let dir = match dir {
+        Some(dir) => &dir,
+        None => &process.status.current_dir,
+    };
+    let vfile = filesystem
+        .writer_at(dir)
+        .create_directory(path, new_file_stat(abi::S_IFDIR, mode))?;
+    log::debug!("make_dir{:?} -> {:?}", (dir, path, mode), vfile);
+    Ok(())
+}
+
+pub async fn unlink(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    dir: &Option<VFile>,
+    path: &VString,
+    remove_dir: bool,
+) -> Result<(), Errno> {
+    let path_str = process.mem.read_user_string(path)?;
+    let path = Path::new(&path_str);
+    let dir = match dir {
+        Some(dir) => &dir,
+        None => &process.status.current_dir,
+    };
+    filesystem.writer_at(dir).unlink(path, remove_dir)?;
+    log::debug!("unlink{:?}", (dir, path, remove_dir));
+    Ok(())
+}
+
+pub async fn rename(
+    process: &mut Process,
+    filesystem: &mut Filesystem,
+    from_dir: &Option<VFile>,
+    from: &VString,
+    to_dir: &Option<VFile>,
+    to: &VString,
+) -> Result<(), Errno> {
+    let from_str = process.mem.read_user_string(from)?;
+    let to_str = process.mem.read_user_string(to)?;
+    let from = Path::new(&from_str);
+    let to = Path::new(&to_str);
+    let from_dir = from_dir.as_ref().unwrap_or(&process.status.current_dir);
+    let to_dir = to_dir.as_ref().unwrap_or(&process.status.current_dir);
+    filesystem.writer_at(from_dir).rename_to(from, to_dir, to)?;
+    log::debug!("rename{:?}", (from_dir, from, to_dir, to));
+    Ok(())
+}
+
 pub async fn file_stat(
     process: &mut Process,
     filesystem: &Filesystem,
+    remap: &PathRemap,
     file: &Option<VFile>,
     path: &Option<VString>,
     follow_links: &FollowLinks,
@@ -65,8 +247,7 @@ pub async fn file_stat(
     let path = match path {
         Some(path) => {
             let path_str = process.mem.read_user_string(path)?;
-            let path = Path::new(&path_str);
-            Some(path.to_owned())
+            Some(remap.apply(Path::new(&path_str)).into_owned())
         }
         None => None,
     };
@@ -76,9 +257,14 @@ pub async fn file_stat(
     };
     let file = match &path {
         None => file.to_owned(),
-        Some(path) => filesystem.lookup(file, path, follow_links)?,
+        Some(path) => match filesystem.lookup(file, path, follow_links)? {
+            vfile if follow_links == &FollowLinks::Follow => {
+                follow_proc_link(process, filesystem, vfile)?
+            }
+            vfile => vfile,
+        },
     };
-    let stat = filesystem.stat(&file)?.to_owned();
+    let stat = filesystem.stat(&file)?;
     log::debug!(
         "file_stat{:?} -> {:?}",
         (path, follow_links),
@@ -86,3 +272,39 @@ pub async fn file_stat(
     );
     Ok((file, stat))
 }
+
+fn read_sockaddr(process: &Process, addr: VPtr, len: usize) -> Result<SocketAddr, Errno> {
+    if len > SOCKADDR_LIMIT {
+        return Err(Errno::new(libc::EINVAL));
+    }
+    let mut bytes = [0u8; SOCKADDR_LIMIT];
+    process
+        .mem
+        .read_bytes(addr, &mut bytes[..len])
+        .map_err(|_| Errno::new(libc::EFAULT))?;
+    parse_sockaddr(&bytes[..len])
+}
+
+pub async fn net_bind(
+    process: &mut Process,
+    network: &NetworkGroup,
+    addr: VPtr,
+    len: usize,
+) -> Result<UnixListener, Errno> {
+    let addr = read_sockaddr(process, addr, len)?;
+    let result = network.bind(&addr);
+    log::debug!("net_bind{:?} -> {:?}", (network, addr), result);
+    result
+}
+
+pub async fn net_connect(
+    process: &mut Process,
+    network: &NetworkGroup,
+    addr: VPtr,
+    len: usize,
+) -> Result<UnixStream, Errno> {
+    let addr = read_sockaddr(process, addr, len)?;
+    let result = network.connect(&addr);
+    log::debug!("net_connect{:?} -> {:?}", (network, addr), result);
+    result
+}
@@ -39,6 +39,14 @@ fn busybox_false() {
     })
 }
 
+#[test]
+fn busybox_wait_blocking() {
+    let runtime = Runtime::new().unwrap();
+    let container = runtime.block_on(async { common().await.arg("/bin/false").spawn().unwrap() });
+    let status = container.wait_blocking().unwrap();
+    assert_eq!(status.code(), Some(1));
+}
+
 #[test]
 fn busybox_sleep_once() {
     Runtime::new().unwrap().block_on(async {
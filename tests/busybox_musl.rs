@@ -1,7 +1,22 @@
-use bandsocks::{Container, ContainerBuilder, RuntimeError};
+use bandsocks::{
+    ComposeSpec, Container, ContainerBuilder, ContainerEvent, ContainerPool, HookStage, LogLevel,
+    NetworkGroup, RegistryClient, RuntimeError, SharedStream, Signal, Stdio, VPid, Workspaces,
+};
 use futures_util::stream::{FuturesUnordered, StreamExt};
-use std::io::{BufRead, Cursor};
-use tokio::{runtime::Runtime, task};
+use std::{
+    ffi::OsStr,
+    io::{BufRead, Cursor},
+    os::unix::ffi::OsStrExt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    runtime::Runtime,
+    task,
+    time::{delay_for, timeout},
+};
 
 const IMAGE: &str =
     "busybox@sha256:e06f93f59fe842fb490ba992bae19fdd5a05373547b52f8184650c2509908114";
@@ -69,6 +84,642 @@ fn busybox_cat_output() {
     })
 }
 
+#[test]
+fn busybox_cat_sysvipc() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["cat", "/proc/sysvipc/sem"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(
+            output.stdout_str(),
+            "       key      semid perms      nsems   uid   gid  cuid  cgid      otime      ctime\n"
+        );
+    })
+}
+
+#[test]
+fn busybox_proc_self() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "readlink /proc/self/exe; grep -c ^MemTotal: /proc/meminfo",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "/bin/readlink\n1\n");
+    })
+}
+
+#[test]
+fn busybox_dev_nodes() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "echo gone > /dev/null; head -c 4 /dev/zero | wc -c; head -c 4 /dev/urandom | wc -c; echo hi > /dev/tty",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "4\n4\n");
+        assert_eq!(output.stderr_str(), "hi\n");
+    })
+}
+
+#[test]
+fn busybox_dev_tty_read_write() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "exec 3<>/dev/tty; echo prompt >&3; head -c 1 <&3 | wc -c; echo done",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "0\ndone\n");
+        assert_eq!(output.stderr_str(), "prompt\n");
+    })
+}
+
+#[test]
+fn busybox_env_merged_with_image() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .arg("/bin/env")
+            .env("B", "2")
+            .env("A", "1")
+            .env("B", "3")
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout_str(),
+            "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin\nB=3\nA=1\n"
+        );
+
+        let output = common()
+            .await
+            .arg("/bin/env")
+            .env_clear()
+            .env("ONLY", "this")
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "ONLY=this\n");
+    })
+}
+
+#[test]
+fn busybox_proc_sys_limits() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .memory_limit(1 << 30)
+            .args(&["cat", "/proc/sys/fs/file-max", "/proc/sys/fs/nr_open"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "104857\n1048576\n");
+    })
+}
+
+#[test]
+fn busybox_deterministic_layout() {
+    Runtime::new().unwrap().block_on(async {
+        let mut stacks = Vec::new();
+        for _ in 0..2 {
+            let output = common()
+                .await
+                .args(&["grep", "stack", "/proc/self/maps"])
+                .deterministic_layout()
+                .output()
+                .await
+                .unwrap();
+            assert!(output.status.success());
+            stacks.push(output.stdout_str().into_owned());
+        }
+        assert!(stacks[0].ends_with("[stack]\n"));
+        assert_eq!(stacks[0], stacks[1]);
+    })
+}
+
+#[test]
+fn busybox_stack_limit() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["sh", "-c", "ulimit -s"])
+            .stack_limit(2 * 1024 * 1024)
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "2048\n");
+    })
+}
+
+#[test]
+fn busybox_date_without_vdso_time() {
+    Runtime::new().unwrap().block_on(async {
+        let clock_gettime = libc::SYS_clock_gettime as isize;
+        for &vdso_time in &[false, true] {
+            let mut container = common()
+                .await
+                .args(&["date", "+%s"])
+                .vdso_time(vdso_time)
+                .syscall_latency_stats()
+                .spawn()
+                .unwrap();
+            let mut stdout = container.stdout.take().unwrap();
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).await.unwrap();
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let reported: u64 = output.trim().parse().unwrap();
+            assert!(reported <= now && reported + 60 > now);
+
+            if vdso_time {
+                assert!(!container.syscall_latency().contains_key(&clock_gettime));
+            } else {
+                // Reported once the call returns, which can be after the output
+                timeout(Duration::from_secs(10), async {
+                    while !container.syscall_latency().contains_key(&clock_gettime) {
+                        delay_for(Duration::from_millis(10)).await;
+                    }
+                })
+                .await
+                .expect("clock read was not traced");
+            }
+            assert!(container.wait().await.unwrap().success());
+        }
+    })
+}
+
+#[test]
+fn busybox_nc_network_group() {
+    Runtime::new().unwrap().block_on(async {
+        let group = NetworkGroup::new("nc").unwrap();
+        let server = common()
+            .await
+            .args(&["sh", "-c", "echo hello | nc -l -p 8080"])
+            .network_group(&group)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let output = common()
+            .await
+            .args(&["sh", "-c", "until nc 127.0.0.1 8080; do sleep 0.1; done"])
+            .network_group(&group)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hello\n");
+        assert!(server.wait().await.unwrap().success());
+    })
+}
+
+#[test]
+fn busybox_kill() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common().await.args(&["sleep", "1000"]).spawn().unwrap();
+        delay_for(Duration::from_millis(100)).await;
+        container.kill(Signal(9)).unwrap();
+        assert_eq!(container.wait().await.unwrap().signal(), Some(9));
+    })
+}
+
+#[test]
+fn busybox_events() {
+    Runtime::new().unwrap().block_on(async {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let collected = events.clone();
+        let status = common()
+            .await
+            .args(&["sh", "-c", "cat /etc/passwd > /dev/null; true"])
+            .on_event(move |event| collected.lock().unwrap().push(event.clone()))
+            .run()
+            .await
+            .unwrap();
+        assert!(status.success());
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events.first(),
+            Some(&ContainerEvent::ProcessStarted {
+                vpid: VPid(1),
+                parent: None
+            })
+        );
+        assert!(events.contains(&ContainerEvent::ProcessStarted {
+            vpid: VPid(2),
+            parent: Some(VPid(1))
+        }));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ContainerEvent::Request {
+                vpid: VPid(2),
+                nr: Some(nr),
+                op: "FileOpen",
+                ..
+            } if *nr == libc::SYS_open as isize
+        )));
+        assert_eq!(
+            events.last(),
+            Some(&ContainerEvent::ProcessExited {
+                vpid: VPid(1),
+                code: 0
+            })
+        );
+    })
+}
+
+#[test]
+fn busybox_syscall_storm() {
+    Runtime::new().unwrap().block_on(async {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let collected = events.clone();
+        // The same chdir fails every time, with nothing emulated in between
+        let script = "i=0; while [ $i -lt 30000 ]; do cd /nonexistent; i=$((i+1)); done";
+        let status = timeout(
+            Duration::from_secs(120),
+            common()
+                .await
+                .args(&["sh", "-c", script])
+                .stderr(Stdio::null())
+                .abort_on_syscall_storm()
+                .on_event(move |event| collected.lock().unwrap().push(event.clone()))
+                .run(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        let events = events.lock().unwrap();
+        assert!(events.contains(&ContainerEvent::SyscallStorm {
+            vpid: VPid(1),
+            nr: libc::SYS_chdir as isize,
+            ret: -libc::ENOENT as isize,
+        }));
+    })
+}
+
+#[test]
+fn busybox_hooks() {
+    Runtime::new().unwrap().block_on(async {
+        let result = Arc::new(Mutex::new(None));
+        let collected = result.clone();
+        let output = common()
+            .await
+            .hook(HookStage::PreSpawn, |ctx| {
+                Box::pin(async move {
+                    ctx.write_file("/etc/greeting", &b"hello\n"[..], 0o644)?;
+                    Ok(())
+                })
+            })
+            .hook(HookStage::PreExit, move |ctx| {
+                let collected = collected.clone();
+                Box::pin(async move {
+                    *collected.lock().unwrap() = ctx.read_file("/tmp/result", 64).await?;
+                    Ok(())
+                })
+            })
+            .args(&["sh", "-c", "cat /etc/greeting; echo done > /tmp/result"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hello\n");
+        assert_eq!(*result.lock().unwrap(), Some(b"done\n".to_vec()));
+    })
+}
+
+#[test]
+fn busybox_kill_trapped() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "trap 'exit 3' TERM; while true; do sleep 0.01; done",
+            ])
+            .spawn()
+            .unwrap();
+        delay_for(Duration::from_millis(100)).await;
+        container.kill(Signal(15)).unwrap();
+        assert_eq!(container.wait().await.unwrap().code(), Some(3));
+    })
+}
+
+#[test]
+fn busybox_pause_resume() {
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common().await.arg("yes").spawn().unwrap();
+        let mut stdout = container.stdout.take().unwrap();
+        let mut buf = [0u8; 4096];
+        assert!(stdout.read(&mut buf).await.unwrap() > 0);
+        container.pause().unwrap();
+        // Drain whatever was written before the pause, then expect silence
+        let mut drained = false;
+        for _ in 0..100 {
+            if timeout(Duration::from_millis(200), stdout.read(&mut buf))
+                .await
+                .is_err()
+            {
+                drained = true;
+                break;
+            }
+        }
+        assert!(drained);
+        container.resume().unwrap();
+        assert!(stdout.read(&mut buf).await.unwrap() > 0);
+        container.kill(Signal(9)).unwrap();
+        assert_eq!(container.wait().await.unwrap().signal(), Some(9));
+    })
+}
+
+#[test]
+fn busybox_pause_resume_quickly() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "for i in 1 2 3 4 5; do sleep 0.1; done; echo done",
+            ])
+            .spawn()
+            .unwrap();
+        // A SIGSTOP still on its way after resuming must not stop anything
+        let handle = container.handle();
+        for _ in 0..20 {
+            handle.pause().unwrap();
+            handle.resume().unwrap();
+        }
+        let output = timeout(Duration::from_secs(10), container.output())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "done\n");
+    })
+}
+
+#[test]
+fn busybox_without_tokio() {
+    // Everything here runs on the private runtime, not the test's executor
+    futures::executor::block_on(async {
+        let mut container = common().await.args(&["echo", "hello"]).spawn().unwrap();
+        let mut stdout = container.stdout.take().unwrap();
+        let mut buf = Vec::new();
+        futures_util::io::AsyncReadExt::read_to_end(&mut stdout, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, b"hello\n");
+        assert!(container.wait().await.unwrap().success());
+    })
+}
+
+#[test]
+fn compose_up_waits_for_health_check() {
+    Runtime::new().unwrap().block_on(async {
+        common().await;
+        let spec = format!(
+            r#"{{
+                "services": {{
+                    "server": {{
+                        "image": "{0}",
+                        "command": ["sh", "-c", "sleep 0.5; exec httpd -f -p 8080 -h /etc"],
+                        "healthcheck": {{
+                            "test": ["wget", "-q", "-O", "/dev/null", "http://127.0.0.1:8080/passwd"],
+                            "interval_ms": 100
+                        }}
+                    }},
+                    "client": {{
+                        "image": "{0}",
+                        "command": ["sh", "-c", "wget -q -O- http://127.0.0.1:8080/passwd | grep -q ^root:"],
+                        "depends_on": ["server"]
+                    }}
+                }}
+            }}"#,
+            IMAGE
+        );
+        let spec = ComposeSpec::parse(spec.as_bytes()).unwrap();
+        let client = RegistryClient::new().unwrap();
+        let compose = spec.up(&client).await.unwrap();
+        assert_eq!(
+            compose.service_names().collect::<Vec<_>>(),
+            vec!["server", "client"]
+        );
+        let (service, status) = compose.wait().await.unwrap();
+        assert_eq!(service, "client");
+        assert!(status.success());
+    })
+}
+
+#[test]
+fn compose_health_check_without_tokio() {
+    futures::executor::block_on(async {
+        common().await;
+        let spec = format!(
+            r#"{{
+                "services": {{
+                    "idle": {{
+                        "image": "{}",
+                        "command": ["sleep", "10"],
+                        "healthcheck": {{ "test": ["false"], "interval_ms": 10, "retries": 3 }}
+                    }}
+                }}
+            }}"#,
+            IMAGE
+        );
+        let spec = ComposeSpec::parse(spec.as_bytes()).unwrap();
+        let client = RegistryClient::new().unwrap();
+        match spec.up(&client).await {
+            Err(RuntimeError::HealthCheckFailed(service)) => assert_eq!(service, "idle"),
+            result => panic!("unexpected result, {:?}", result.map(|_| ())),
+        }
+    })
+}
+
+#[test]
+fn busybox_stdout_null() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["sh", "-c", "echo out; echo err >&2"])
+            .stdout(Stdio::null())
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert!(output.stdout.is_empty());
+        assert_eq!(output.stderr_str(), "err\n");
+    })
+}
+
+#[test]
+fn busybox_read_child_stdout() {
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common()
+            .await
+            .args(&["echo", "piped"])
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut stdout = container.stdout.take().unwrap();
+        let mut buf = String::new();
+        stdout.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "piped\n");
+        assert!(container.stdin.is_none());
+        assert!(container.wait().await.unwrap().success());
+    })
+}
+
+#[test]
+fn busybox_cat_async_stdin() {
+    Runtime::new().unwrap().block_on(async {
+        let stdin = SharedStream::from_async_read(Cursor::new(b"piped in\n".to_vec())).unwrap();
+        let output = common()
+            .await
+            .arg("cat")
+            .stdin(stdin)
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "piped in\n");
+    })
+}
+
+#[test]
+fn busybox_read_auto_suspend() {
+    Runtime::new().unwrap().block_on(async {
+        let (mut input, reader) = UnixStream::pair().unwrap();
+        let stdin = SharedStream::from_async_read(reader).unwrap();
+        task::spawn(async move {
+            delay_for(Duration::from_millis(500)).await;
+            input.write_all(b"late\n").await.unwrap();
+        });
+        let output = common()
+            .await
+            .args(&["sh", "-c", "read line; echo $line"])
+            .stdin(stdin)
+            .auto_suspend(Duration::from_millis(50))
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "late\n");
+    })
+}
+
+#[test]
+fn busybox_echo_tracer_settings() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["echo", "quietly"])
+            .sandbox_log_level(LogLevel::Off)
+            .instruction_pointer_checks(false)
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "quietly\n");
+    })
+}
+
+#[test]
+fn busybox_pool() {
+    Runtime::new().unwrap().block_on(async {
+        common().await;
+        let pool = ContainerPool::pull(&IMAGE.parse().unwrap(), 2)
+            .await
+            .unwrap();
+        let mut tasks = FuturesUnordered::new();
+        // More containers than tracers, so some start cold
+        for n in 0..5 {
+            let builder = pool.container().unwrap().args(&["echo", &n.to_string()]);
+            tasks.push(task::spawn(async move { (n, builder.output().await) }));
+        }
+        while let Some(result) = tasks.next().await {
+            let (n, output) = result.unwrap();
+            let output = output.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout_str(), format!("{}\n", n));
+        }
+    })
+}
+
+#[test]
+fn busybox_exec_snapshots() {
+    Runtime::new().unwrap().block_on(async {
+        common().await;
+        let pool = ContainerPool::pull(&IMAGE.parse().unwrap(), 1)
+            .await
+            .unwrap()
+            .exec_snapshots();
+        // The first run of each command saves a snapshot, the rest restore it
+        for args in &[
+            ["echo", "one"],
+            ["echo", "two"],
+            ["echo", "one"],
+            ["echo", "two"],
+        ] {
+            let output = pool.container().unwrap().args(args).output().await.unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout_str(), format!("{}\n", args[1]));
+        }
+    })
+}
+
+#[test]
+fn busybox_echo_recording() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&["echo", "hello"])
+            .record_session()
+            .spawn()
+            .unwrap();
+        let recording = container.recording().unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "hello\n");
+        let cast = recording.to_asciicast();
+        let mut lines = cast.lines();
+        assert!(lines.next().unwrap().contains("\"version\":2"));
+        assert!(lines.next().unwrap().ends_with(",\"o\",\"hello\\n\"]"));
+        assert_eq!(lines.next(), None);
+    })
+}
+
 #[test]
 fn busybox_sh_c_echo() {
     Runtime::new().unwrap().block_on(async {
@@ -84,6 +735,67 @@ fn busybox_sh_c_echo() {
     })
 }
 
+#[test]
+fn busybox_sh_c_cd() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .working_dir("/usr/sbin")
+            .args(&[
+                "sh",
+                "-c",
+                "pwd -P; cd ../../etc && pwd -P && head -c 5 passwd; cd /bin/sh",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(output.stdout_str(), "/usr/sbin\n/etc\nroot:");
+        assert!(output.stderr_str().contains("can't cd to /bin/sh"));
+    })
+}
+
+#[test]
+fn busybox_sh_c_fd_redirect() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "exec 3</etc/passwd; exec 4<&3 3<&-; head -c 5 <&4; cat <&3",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stdout_str(), "root:");
+        assert!(output.stderr_str().contains("3"));
+    })
+}
+
+#[test]
+fn busybox_sh_c_argv() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "echo $0 $#; exec printf '[%s]' \"$@\"",
+                "zero",
+                "",
+                "one",
+                "two words",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout_str(), "zero 3\n[][one][two words]");
+    })
+}
+
 #[test]
 fn busybox_sh_c_loop() {
     Runtime::new().unwrap().block_on(async {
@@ -100,6 +812,202 @@ fn busybox_sh_c_loop() {
     })
 }
 
+#[test]
+fn busybox_sh_c_subshell() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .args(&["sh", "-c", "(echo child $$; exit 3); echo parent $$ $?"])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "child 1\nparent 1 3\n");
+        assert!(output.status.success());
+    })
+}
+
+#[test]
+fn busybox_sh_c_secrets() {
+    Runtime::new().unwrap().block_on(async {
+        let mut container = common()
+            .await
+            .secret("API_KEY", "hunter2")
+            .secret_file("token", "/run/secrets/token", "s3cret\n")
+            .args(&["sh", "-c", "echo $API_KEY; cat /run/secrets/token"])
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut stdout = container.stdout.take().unwrap();
+        let mut buf = String::new();
+        stdout.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "hunter2\ns3cret\n");
+        // The file was open before anything came out of it
+        let accesses = container.secret_accesses();
+        assert_eq!(accesses.len(), 1);
+        assert_eq!(accesses[0].name, "token");
+        assert!(container.wait().await.unwrap().success());
+    })
+}
+
+#[test]
+fn busybox_secret_file_not_in_workspace() {
+    let dir = tempfile::tempdir().unwrap();
+    let workspaces = Workspaces::open(dir.path());
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .workspace("/work", &workspaces, "secrets")
+            .secret_file("token", "/work/token", "s3cret\n")
+            .args(&["sh", "-c", "cat /work/token; echo kept > /work/file"])
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"s3cret\n");
+
+        let output = common()
+            .await
+            .workspace("/work", &workspaces, "secrets")
+            .args(&["sh", "-c", "ls /work"])
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"file\n");
+    })
+}
+
+#[test]
+fn busybox_sh_c_tmpfs() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .tmpfs("/scratch", 4096)
+            .args(&[
+                "sh",
+                "-c",
+                concat!(
+                    "echo hi > /tmp/a; cat /tmp/a; ",
+                    "dd if=/dev/zero of=/scratch/a bs=4096 count=1 2>/dev/null; ",
+                    "touch /scratch/b 2>/dev/null || echo full",
+                ),
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "hi\nfull\n");
+        assert!(output.status.success());
+    })
+}
+
+#[test]
+fn busybox_non_utf8_names() {
+    Runtime::new().unwrap().block_on(async {
+        let name = OsStr::from_bytes(b"caf\xe9\xff");
+        let args: Vec<&OsStr> = vec![
+            "sh".as_ref(),
+            "-c".as_ref(),
+            concat!(
+                "mkdir /tmp/u; echo hi > \"/tmp/u/$0\"; cat \"/tmp/u/$0\"; ",
+                "[ \"$(ls /tmp/u)\" = \"$0\" ] && echo listed; ",
+                "mv \"/tmp/u/$0\" /tmp/u/plain && ls /tmp/u",
+            )
+            .as_ref(),
+            name,
+        ];
+        let output = common().await.args(&args).output().await.unwrap();
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "hi\nlisted\nplain\n");
+        assert!(output.status.success());
+    })
+}
+
+#[test]
+fn busybox_sh_c_user_notif() {
+    Runtime::new().unwrap().block_on(async {
+        let output = common()
+            .await
+            .seccomp_user_notif()
+            .args(&[
+                "sh",
+                "-c",
+                "head -n 1 /etc/passwd; stat -c '%s %a' /bin/sh; test -r /bin/ls && echo ok",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.stderr.is_empty());
+        assert_eq!(
+            output.stdout_str(),
+            "root:x:0:0:root:/root:/bin/sh\n1165544 755\nok\n"
+        );
+        assert!(output.status.success());
+    })
+}
+
+#[test]
+fn busybox_sh_c_inherited_fds() {
+    Runtime::new().unwrap().block_on(async {
+        // Anything written on inherited descriptors must not reach the
+        // runtime as if the tracer had sent it
+        let output = common()
+            .await
+            .args(&[
+                "sh",
+                "-c",
+                "for fd in 3 4 5 6 7 8 9; do eval \"echo spoof >&$fd\"; done; echo ok",
+            ])
+            .output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout_str(), "ok\n");
+        assert!(output.status.success());
+    })
+}
+
+#[test]
+fn busybox_user_notif_latency() {
+    Runtime::new().unwrap().block_on(async {
+        let access = libc::SYS_access as isize;
+        let mut means = Vec::new();
+        for &user_notif in &[false, true] {
+            let mut builder = common()
+                .await
+                .args(&[
+                    "sh",
+                    "-c",
+                    "for i in 1 2 3 4 5 6 7 8; do test -r /bin/ls; done",
+                ])
+                .syscall_latency_stats();
+            if user_notif {
+                builder = builder.seccomp_user_notif();
+            }
+            let container = builder.spawn().unwrap();
+            // Reported once the call returns, which can be after the exit
+            let latency = timeout(Duration::from_secs(10), async {
+                loop {
+                    match container.syscall_latency().remove(&access) {
+                        Some(latency) if latency.trap.count() >= 8 => break latency,
+                        _ => delay_for(Duration::from_millis(10)).await,
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            assert!(container.wait().await.unwrap().success());
+            let mean = latency.trap.mean()
+                + latency.emulate.mean()
+                + latency.ipc.mean()
+                + latency.resume.mean();
+            means.push((user_notif, mean));
+        }
+        // Timing isn't stable enough to assert on, but show the comparison
+        for (user_notif, mean) in &means {
+            eprintln!("access() latency, user_notif={}: {:?}", user_notif, mean);
+        }
+    })
+}
+
 #[test]
 fn busybox_version() {
     Runtime::new().unwrap().block_on(async {
@@ -224,6 +1132,26 @@ fn busybox_sleep_parallel() {
     })
 }
 
+#[test]
+fn busybox_echo_parallel() {
+    const NUM: usize = 100;
+    Runtime::new().unwrap().block_on(async {
+        let builder = common().await;
+        let mut tasks = FuturesUnordered::new();
+        for i in 0..NUM {
+            let builder = builder.clone().args(&["echo", &i.to_string()]);
+            tasks.push(task::spawn(async move {
+                let output = builder.output().await?;
+                Ok::<(usize, String), RuntimeError>((i, output.stdout_str().into_owned()))
+            }));
+        }
+        for _ in 0..NUM {
+            let (i, stdout) = tasks.next().await.unwrap().unwrap().unwrap();
+            assert_eq!(stdout, format!("{}\n", i));
+        }
+    })
+}
+
 #[test]
 fn busybox_bool_parallel() {
     const NUM: usize = 100;
@@ -48,6 +48,42 @@ fn cli_ephemeral_offline() {
         .stdout(predicate::str::is_empty());
 }
 
+#[test]
+fn cli_ephemeral_pull_policy_never() {
+    Command::new(env!("CARGO"))
+        .arg("run")
+        .arg("--quiet")
+        .arg("-p")
+        .arg("bandsocks-cli")
+        .arg("--")
+        .arg("-0")
+        .arg("--pull-policy")
+        .arg("never")
+        .arg("busybox:musl")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("DownloadInOfflineMode"))
+        .stdout(predicate::str::is_empty());
+}
+
+#[test]
+fn cli_image_tags_offline() {
+    Command::new(env!("CARGO"))
+        .arg("run")
+        .arg("--quiet")
+        .arg("-p")
+        .arg("bandsocks-cli")
+        .arg("--")
+        .arg("--offline")
+        .arg("image")
+        .arg("tags")
+        .arg("busybox")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("DownloadInOfflineMode"))
+        .stdout(predicate::str::is_empty());
+}
+
 #[test]
 fn cli_busybox_echo() {
     Command::new(env!("CARGO"))
@@ -259,3 +295,21 @@ fn cli_ffmpeg_entrypoint_override() {
         )))
         .stdout(predicate::str::is_empty());
 }
+
+#[test]
+fn cli_selftest() {
+    Command::new(env!("CARGO"))
+        .arg("run")
+        .arg("--quiet")
+        .arg("-p")
+        .arg("bandsocks-cli")
+        .arg("--")
+        .arg("-q")
+        .arg("-l")
+        .arg("error")
+        .arg("selftest")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("FAIL").not())
+        .stderr(predicate::str::is_empty());
+}
@@ -1,4 +1,4 @@
-use bandsocks::{Container, ContainerBuilder};
+use bandsocks::{Container, ContainerBuilder, ContainerExit};
 use tokio::runtime::Runtime;
 
 const IMAGE: &str =
@@ -85,6 +85,38 @@ fn debian_readlink_sh() {
     })
 }
 
+#[test]
+fn debian_realpath_sh() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .arg("realpath")
+            .arg("/bin/sh")
+            .spawn()
+            .unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert!(output.stdout_str().ends_with("bin/dash\n"));
+    })
+}
+
+#[test]
+fn debian_find_apt_sources() {
+    Runtime::new().unwrap().block_on(async {
+        // GNU find walks the tree with openat and fstatat on directory fds
+        let container = common()
+            .await
+            .args(&["find", "/etc/apt", "-name", "sources.list", "-type", "f"])
+            .spawn()
+            .unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "/etc/apt/sources.list\n");
+    })
+}
+
 #[test]
 fn super_cow_powers() {
     Runtime::new().unwrap().block_on(async {
@@ -234,3 +266,73 @@ fn debian_cat_etc_shadow() {
         );
     })
 }
+
+#[test]
+fn debian_perl_alarm() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&[
+                "perl",
+                "-e",
+                "$SIG{ALRM} = sub { exit 7 }; alarm 1; sleep 10",
+            ])
+            .spawn()
+            .unwrap();
+        let status = container.wait().await.unwrap();
+        assert_eq!(status.code(), Some(7));
+    })
+}
+
+#[test]
+fn debian_perl_alarm_default() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&["perl", "-e", "alarm 1; sleep 10"])
+            .spawn()
+            .unwrap();
+        let status = container.wait().await.unwrap();
+        assert_eq!(status.signal(), Some(14));
+    })
+}
+
+#[test]
+fn debian_perl_segfault() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&["perl", "-e", "print unpack('p', pack('Q', 8))"])
+            .spawn()
+            .unwrap();
+        let status = container.wait().await.unwrap();
+        assert_eq!(status.exit(), ContainerExit::Signal(11));
+        assert_eq!(status.code(), Some(139));
+    })
+}
+
+#[test]
+fn debian_perl_disallowed_syscall() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&["perl", "-e", "syscall(999)"])
+            .spawn()
+            .unwrap();
+        let status = container.wait().await.unwrap();
+        assert_eq!(status.exit(), ContainerExit::Signal(31));
+    })
+}
+
+#[test]
+fn debian_exit_code() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .args(&["perl", "-e", "exit 42"])
+            .spawn()
+            .unwrap();
+        let status = container.wait().await.unwrap();
+        assert_eq!(status.exit(), ContainerExit::Code(42));
+    })
+}
@@ -1,4 +1,4 @@
-use bandsocks::{Container, ContainerBuilder};
+use bandsocks::{Container, ContainerBuilder, NetworkGroup};
 use tokio::runtime::Runtime;
 
 const IMAGE: &str =
@@ -40,6 +40,32 @@ print(os.__file__)
     })
 }
 
+#[test]
+fn python_absolute_path_ignores_dir_fd() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+r, w = os.pipe()
+path = '/usr/local/lib/python3.10/os.py'
+print(os.path.samestat(os.stat(path, dir_fd=r), os.stat(path)))
+fd = os.open('/etc/passwd', os.O_RDONLY, dir_fd=r)
+print(os.read(fd, 5))
+",
+            )
+            .spawn()
+            .unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "True\nb'root:'\n");
+    })
+}
+
 #[test]
 fn python_os_listdir() {
     Runtime::new().unwrap().block_on(async {
@@ -64,3 +90,101 @@ print(os.listdir('/'))
         ));
     })
 }
+
+#[test]
+fn python_threads() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os, threading
+barrier = threading.Barrier(4)
+ids = []
+def work():
+    barrier.wait()
+    ids.append((os.getpid(), threading.get_native_id()))
+threads = [threading.Thread(target=work) for _ in range(4)]
+for t in threads:
+    t.start()
+for t in threads:
+    t.join()
+print(os.getpid(), threading.get_native_id())
+print(all(pid == os.getpid() for pid, _ in ids), len(set(tid for _, tid in ids)))
+",
+            )
+            .spawn()
+            .unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "1 1\nTrue 4\n");
+    })
+}
+
+#[test]
+fn python_no_spoofing_or_passing_fds() {
+    Runtime::new().unwrap().block_on(async {
+        let group = NetworkGroup::new("fds").unwrap();
+        let container = common()
+            .await
+            .network_group(&group)
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r#"
+import array, os, socket
+for fd in range(3, 64):
+    try:
+        os.write(fd, b"\0" * 64)
+    except OSError:
+        pass
+print(open("/etc/passwd").read(5))
+server = socket.socket()
+server.bind(("127.0.0.1", 8080))
+server.listen(1)
+client = socket.create_connection(("127.0.0.1", 8080))
+conn, _ = server.accept()
+rights = [(socket.SOL_SOCKET, socket.SCM_RIGHTS, array.array("i", [0]))]
+try:
+    client.sendmsg([b"x"], rights)
+except PermissionError:
+    print("refused")
+client.sendmsg([b"hello"])
+data, ancdata, flags, addr = conn.recvmsg(16, socket.CMSG_SPACE(64))
+print(data, ancdata)
+"#,
+            )
+            .spawn()
+            .unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "root:\nrefused\nb'hello' []\n");
+    })
+}
+
+#[test]
+fn python_posix_spawn() {
+    Runtime::new().unwrap().block_on(async {
+        let container = common()
+            .await
+            .arg("python")
+            .arg("-c")
+            .arg(
+                r"
+import os
+pid = os.posix_spawn('/bin/echo', ['echo', 'spawned'], os.environ)
+print(os.waitpid(pid, 0)[1])
+",
+            )
+            .spawn()
+            .unwrap();
+        let output = container.output().await.unwrap();
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.stdout_str(), "spawned\n0\n");
+    })
+}